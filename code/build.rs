@@ -1,4 +1,31 @@
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
 fn main() -> anyhow::Result<()> {
+    // Build info for the firmware (see src/version.rs)
+    println!("cargo:rustc-env=DCPOWER_GIT_HASH={}", git_hash());
+    let epoch = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    println!("cargo:rustc-env=DCPOWER_BUILD_EPOCH={}", epoch);
+    println!("cargo:rerun-if-changed=../.git/HEAD");
+    println!("cargo:rerun-if-changed=../.git/index");
+
     embuild::build::CfgArgs::output_propagated("ESP_IDF")?;
     embuild::build::LinkArgs::output_propagated("ESP_IDF")
 }
+
+// Short hash of HEAD, with "-dirty" if there are uncommitted changes
+fn git_hash() -> String {
+    let hash = Command::new("git").args(["rev-parse", "--short", "HEAD"]).output()
+        .ok()
+        .filter(|out| out.status.success())
+        .map(|out| String::from_utf8_lossy(&out.stdout).trim().to_string());
+    match hash {
+        Some(hash) if !hash.is_empty() => {
+            let clean = Command::new("git").args(["diff", "--quiet", "HEAD"]).status()
+                .map(|status| status.success())
+                .unwrap_or(true);
+            if clean { hash } else { format!("{}-dirty", hash) }
+        },
+        _ => "unknown".to_string(),
+    }
+}
@@ -0,0 +1,110 @@
+// Alert notifications on faults
+// Trips, a full log buffer and a long WiFi outage are POSTed to alert_webhook_url:
+//   json  : {"device": <influxdb_tag>, "event": <event>, "message": <text>}
+//   slack : {"text": "<device>: <text>"} (Slack and compatible incoming webhooks)
+//   ntfy  : the text as the body with Title, Priority and Tags headers (ntfy topic URL)
+// The same event is sent at most once per ALERT_HOLDOFF. Alerts which cannot be sent
+// (e.g. while WiFi is lost) are kept and retried.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use log::*;
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{channel, Sender, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::webhook;
+
+const ALERT_HOLDOFF: Duration = Duration::from_secs(60);
+const ALERT_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+const MAX_PENDING_ALERTS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlertFormat {
+    Json,
+    Slack,
+    Ntfy,
+}
+
+struct Alert {
+    event: &'static str,
+    message: String,
+}
+
+pub struct Alerts {
+    tx: Option<Sender<Alert>>,
+    last_sent: HashMap<&'static str, Instant>,
+}
+
+impl Alerts {
+    // Start the notifier thread; an empty url disables the alerts
+    pub fn start(url: String, format: AlertFormat, device: String) -> anyhow::Result<Alerts> {
+        if url.is_empty() {
+            return Ok(Alerts { tx: None, last_sent: HashMap::new() });
+        }
+        let (tx, rx) = channel::<Alert>();
+        thread::Builder::new().stack_size(8192).spawn(move || {
+            crate::health::register_task("alerts");
+            let mut pending : VecDeque<Alert> = VecDeque::new();
+            loop {
+                match rx.recv_timeout(ALERT_RETRY_INTERVAL) {
+                    Ok(alert) => pending.push_back(alert),
+                    Err(RecvTimeoutError::Timeout) => {},
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+                pending.extend(rx.try_iter());
+                while pending.len() > MAX_PENDING_ALERTS {
+                    pending.pop_front();
+                }
+                while let Some(alert) = pending.front() {
+                    match send(&url, format, &device, alert) {
+                        Ok(()) => {
+                            pending.pop_front();
+                        },
+                        Err(e) => {
+                            info!("Alert {} not sent: {:?}", alert.event, e);
+                            break;
+                        }
+                    }
+                }
+            }
+        })?;
+        info!("Alerts to {} ({:?})", url, format);
+        Ok(Alerts { tx: Some(tx), last_sent: HashMap::new() })
+    }
+
+    pub fn notify(&mut self, event: &'static str, message: String) {
+        warn!("Alert {}: {}", event, message);
+        let Some(tx) = self.tx.as_ref() else {
+            return;
+        };
+        let now = Instant::now();
+        if let Some(last) = self.last_sent.get(event) {
+            if now.duration_since(*last) < ALERT_HOLDOFF {
+                return;
+            }
+        }
+        self.last_sent.insert(event, now);
+        let _ = tx.send(Alert { event: event, message: message });
+    }
+}
+
+fn send(url: &str, format: AlertFormat, device: &str, alert: &Alert) -> anyhow::Result<()> {
+    match format {
+        AlertFormat::Json => {
+            let body = serde_json::json!({ "device": device, "event": alert.event, "message": alert.message }).to_string();
+            webhook::post(url, "application/json", &[], body.as_bytes())
+        },
+        AlertFormat::Slack => {
+            let body = serde_json::json!({ "text": format!("{}: {}", device, alert.message) }).to_string();
+            webhook::post(url, "application/json", &[], body.as_bytes())
+        },
+        AlertFormat::Ntfy => {
+            let title = format!("{} {}", device, alert.event);
+            webhook::post(url, "text/plain", &[("Title", title.as_str()), ("Priority", "high"), ("Tags", "warning")],
+                alert.message.as_bytes())
+        },
+    }
+}
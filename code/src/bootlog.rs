@@ -0,0 +1,70 @@
+// Early boot log capture
+// Records logged before the network is up (sensor and USB PD initialization) are kept in RAM
+// and flushed to syslog, or to InfluxDB if syslog is disabled, once the network is up.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use log::{Level, Record};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const BOOT_LOG_CAPACITY: usize = 200;
+
+pub struct BootRecord {
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+    pub uptime_us: i64,
+}
+
+pub struct BootLog {
+    pub records: Vec<BootRecord>,
+    // Records after the buffer was full
+    pub missed: u32,
+}
+
+impl BootLog {
+    // One line per record: "[uptime ms] LEVEL target: message"
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        for r in &self.records {
+            text.push_str(&format!("[{}] {} {}: {}\n", r.uptime_us / 1000, r.level, r.target, r.message));
+        }
+        text
+    }
+}
+
+static CAPTURING: AtomicBool = AtomicBool::new(true);
+static BOOT_LOG: Mutex<BootLog> = Mutex::new(BootLog { records: Vec::new(), missed: 0 });
+
+pub fn uptime_us() -> i64 {
+    unsafe { esp_idf_sys::esp_timer_get_time() }
+}
+
+// Called by the logger for every record until the boot log is taken
+pub fn capture(record: &Record) {
+    if !CAPTURING.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut lck = BOOT_LOG.lock().unwrap();
+    if lck.records.len() < BOOT_LOG_CAPACITY {
+        lck.records.push(BootRecord {
+            level: record.level(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+            uptime_us: uptime_us(),
+        });
+    }
+    else {
+        lck.missed += 1;
+    }
+}
+
+// Stop capturing and take the captured records
+pub fn take() -> BootLog {
+    CAPTURING.store(false, Ordering::Relaxed);
+    let mut lck = BOOT_LOG.lock().unwrap();
+    std::mem::replace(&mut *lck, BootLog { records: Vec::new(), missed: 0 })
+}
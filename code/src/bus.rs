@@ -0,0 +1,77 @@
+// Inter-thread message bus
+// Producer threads (touchpad, console, HTTP server, sync and share receivers) send commands to the main loop over
+// one channel. The main loop drains it once per iteration, so no thread waits on a lock
+// held by another. Telemetry goes out over the display and transfer threads' own channels.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use std::sync::mpsc::{channel, Receiver, Sender};
+use crate::touchpad::KeyInput;
+use crate::console::ConsoleCommand;
+use crate::hostlink::HostRequest;
+use crate::settings::{PidChange, PidGains};
+use crate::testscripts::ScriptRequest;
+use dcpower_control::session::RunLabel;
+use dcpower_control::unitsync::SyncMessage;
+use dcpower_control::share::ShareMessage;
+use dcpower_control::offsetcal::{CalibrationAction, CalibrationStatus};
+use dcpower_control::testscript::ScriptStatus;
+use dcpower_control::marker::Marker;
+
+#[derive(Debug, Clone)]
+pub enum Command {
+    Key(KeyInput),
+    Console(ConsoleCommand),
+    // Check an uploaded config file (validated by the sender) with the unlock code against the
+    // settings, then store and apply it; reply with why it was rejected
+    ReloadConfig(String, String, Sender<Result<(), String>>),
+    // Import an exported settings document (validated by the sender) with the unlock code,
+    // reply with why it was rejected
+    ImportSettings(String, String, Sender<Result<(), String>>),
+    // Reply with the current settings as exported JSON
+    ExportSettings(Sender<String>),
+    // Read or change the PID gains, reply with the gains in effect or the error
+    Pid(PidChange, Sender<Result<PidGains, String>>),
+    // Set the run label (None to read it), reply with the label in effect
+    Label(Option<RunLabel>, Sender<RunLabel>),
+    // INA228 offset calibration request, reply with its state or why it was refused
+    Calibration(CalibrationAction, Sender<Result<CalibrationStatus, String>>),
+    // Test script request, reply with the state of the run or why it was refused
+    Script(ScriptRequest, Sender<Result<ScriptStatus, String>>),
+    // Marker with its text, reply with the marker or why its text was refused
+    Marker(String, Sender<Result<Marker, String>>),
+    // Start message of the sync group received over UDP
+    Sync(SyncMessage),
+    // Current share message of the master, received by the slave
+    Share(ShareMessage),
+    // Wake the unit from the low-power idle
+    Wake,
+    // Flash the display for the seconds to find the unit in a rack, 0 to stop
+    Identify(u32),
+    // Request of the desktop app over the USB console
+    Host(HostRequest),
+}
+
+pub struct CommandBus {
+    tx: Sender<Command>,
+    rx: Receiver<Command>,
+}
+
+impl CommandBus {
+    pub fn new() -> CommandBus {
+        let (tx, rx) = channel();
+        CommandBus { tx: tx, rx: rx }
+    }
+
+    // A sender for a new producer
+    pub fn sender(&self) -> Sender<Command> {
+        self.tx.clone()
+    }
+
+    // Take all the pending commands without blocking
+    pub fn drain(&self) -> Vec<Command> {
+        self.rx.try_iter().collect()
+    }
+}
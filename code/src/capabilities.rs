@@ -0,0 +1,139 @@
+// Capability discovery
+// GET /capabilities describes what this unit can do, so client software can adapt to
+// different hardware and firmware revisions. The ranges are the PDO limits of the source;
+// the protection limits in the settings may be lower.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use serde::Serialize;
+use crate::settings::Settings;
+use crate::version::{self, BuildInfo};
+use crate::controltimer;
+
+const VOLTAGE_STEP: f32 = 0.01;
+const CURRENT_LIMIT_STEP: f32 = 0.1;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModeInfo {
+    pub mode: &'static str,
+    pub supported: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Range {
+    pub min: f32,
+    pub max: f32,
+    pub step: f32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SamplingRates {
+    pub control_loop_hz: u32,
+    pub log_sample_hz: u32,
+    pub log_buffer_records: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Capabilities {
+    pub firmware: BuildInfo,
+    pub modes: Vec<ModeInfo>,
+    pub voltage: Range,
+    pub current: Range,
+    pub max_power: f32,
+    // Output channels (2 with the second INA228 and PWM)
+    pub channels: usize,
+    pub sampling: SamplingRates,
+    pub features: Vec<&'static str>,
+}
+
+impl Capabilities {
+    pub fn new(pdo_max_voltage: f32, pdo_max_current: f32, channels: usize, settings: &Settings) -> Capabilities {
+        // Over current trips the output (no constant current regulation), and the power
+        // limit is a protection limit, so only CV is supported.
+        let modes = vec![
+            ModeInfo { mode: "CV", supported: true },
+            ModeInfo { mode: "CC", supported: false },
+            ModeInfo { mode: "CP", supported: false },
+        ];
+        let mut features = vec!["usb_pd", "touch_panel", "serial_console", "usb_json", "raw_stream", "http_config", "crash_dump", "log_filter", "pid_tuning", "current_limit", "regulation_stats", "ripple", "cable_test", "pd_probe", "i2c_health", "pwm_offset_learning", "api_v1", "offset_calibration", "identify", "marker", "test_scripts"];
+        if !settings.wifi_ssid.is_empty() {
+            features.push("wifi");
+        }
+        if !settings.influxdb_server.is_empty() {
+            features.push("influxdb");
+        }
+        if settings.syslog_enable {
+            features.push("syslog");
+        }
+        if !settings.alert_webhook_url.is_empty() {
+            features.push("alerts");
+        }
+        if !settings.sync_group.is_empty() {
+            features.push("multi_unit_sync");
+        }
+        if settings.share_mode != "off" {
+            features.push("current_share");
+        }
+        if settings.espnow_enable {
+            features.push("espnow_telemetry");
+        }
+        if settings.kiosk_mode {
+            features.push("kiosk_mode");
+        }
+        if settings.current_limit_mode == "pps" {
+            features.push("soft_current_limit");
+        }
+        if settings.thermal_resistance > 0.0 {
+            features.push("thermal_model");
+        }
+        if settings.safe_mode {
+            features.push("safe_mode");
+        }
+        if !settings.alarms.is_empty() {
+            features.push("alarms");
+        }
+        if settings.scope_trigger_enable {
+            features.push("scope_trigger");
+        }
+        if settings.upload_throttle_rssi != 0 {
+            features.push("upload_throttle");
+        }
+        if settings.idle_sleep_secs > 0 {
+            features.push("idle_sleep");
+        }
+        if settings.interlock_enable {
+            features.push("interlock");
+        }
+        if settings.bleed_enable {
+            features.push("output_bleed");
+        }
+        if settings.pid_trace_rate_hz > 0 {
+            features.push("pid_trace");
+        }
+        if settings.remote_sense_enable {
+            features.push("remote_sense");
+        }
+        if settings.auto_recover_enable {
+            features.push("auto_recover");
+        }
+        if channels > 1 {
+            features.push("dual_channel");
+        }
+        Capabilities {
+            firmware: version::build_info(),
+            modes: modes,
+            voltage: Range { min: 0.0, max: pdo_max_voltage, step: VOLTAGE_STEP },
+            current: Range { min: 0.0, max: pdo_max_current, step: CURRENT_LIMIT_STEP },
+            max_power: pdo_max_voltage * pdo_max_current,
+            channels: channels,
+            sampling: SamplingRates {
+                control_loop_hz: settings.control_rate_hz,
+                log_sample_hz: controltimer::HOUSEKEEPING_RATE_HZ,
+                log_buffer_records: settings.log_buffer_capacity,
+            },
+            features: features,
+        }
+    }
+}
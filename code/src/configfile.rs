@@ -0,0 +1,119 @@
+// Config file on SPIFFS with hot reload
+// /spiffs/config.json is a (partial) settings JSON document applied over the NVS settings.
+// An uploaded config is validated before it replaces the current file, and the previous
+// file is kept as config.good.json to roll back to if the current file cannot be parsed.
+// The main loop checks an upload against the live settings (and the unlock code of the
+// protection limits) before it is stored. The file is served back by GET /config, so it
+// does not hold protection_unlock_code.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use log::*;
+use std::ffi::CString;
+use std::fs;
+use std::sync::mpsc::{channel, Sender};
+use std::time::Duration;
+use serde_json::Value;
+use crate::settings::Settings;
+use crate::bus::Command;
+
+pub const SPIFFS_BASE_PATH: &str = "/spiffs";
+const CONFIG_FILE: &str = "/spiffs/config.json";
+const CONFIG_GOOD_FILE: &str = "/spiffs/config.good.json";
+// The main loop drains the bus every 10ms
+const RELOAD_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub fn mount_spiffs() -> anyhow::Result<()> {
+    let base_path = CString::new(SPIFFS_BASE_PATH)?;
+    let conf = esp_idf_sys::esp_vfs_spiffs_conf_t {
+        base_path: base_path.as_ptr(),
+        partition_label: std::ptr::null(),
+        max_files: 5,
+        format_if_mount_failed: true,
+    };
+    // The VFS keeps a copy of the base path
+    esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_vfs_spiffs_register(&conf) })?;
+    info!("SPIFFS mounted on {}", SPIFFS_BASE_PATH);
+    Ok(())
+}
+
+#[derive(Clone)]
+pub struct ConfigFile {
+    reload: Sender<Command>,
+}
+
+impl ConfigFile {
+    pub fn new(reload: Sender<Command>) -> ConfigFile {
+        ConfigFile { reload: reload }
+    }
+
+    // Apply the config file over the settings at boot. Falls back to the last good file.
+    pub fn load(&self, settings: &Settings) -> Option<Settings> {
+        let json = fs::read_to_string(CONFIG_FILE).ok()?;
+        // The unlock code was checked when the file was uploaded
+        match settings.overlay_json(&json) {
+            Ok(new_settings) => {
+                info!("Config file {} applied", CONFIG_FILE);
+                Some(new_settings)
+            },
+            Err(e) => {
+                warn!("Config file {} is invalid: {}, rolling back to {}", CONFIG_FILE, e, CONFIG_GOOD_FILE);
+                let good = fs::read_to_string(CONFIG_GOOD_FILE).ok()?;
+                match settings.overlay_json(&good) {
+                    Ok(new_settings) => {
+                        if let Err(e) = fs::write(CONFIG_FILE, &good) {
+                            warn!("Failed to restore {}: {}", CONFIG_FILE, e);
+                        }
+                        Some(new_settings)
+                    },
+                    Err(e) => {
+                        warn!("Config file {} is invalid: {}", CONFIG_GOOD_FILE, e);
+                        None
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn read(&self) -> Option<String> {
+        fs::read_to_string(CONFIG_FILE).ok()
+    }
+
+    // Validate an uploaded config file and request the main loop to check it against the live
+    // settings with the unlock code, store and apply it. Returns why it was rejected.
+    pub fn upload(&self, json: &str, code: &str) -> anyhow::Result<()> {
+        // Validate against the schema with the compile-time defaults
+        Settings::from_config().overlay_json(json)?;
+        if serde_json::from_str::<Value>(json)?.get("protection_unlock_code").is_some() {
+            anyhow::bail!("protection_unlock_code is not kept in a config file: change it in the protection settings menu or with 'set'");
+        }
+        let (reply, result) = channel();
+        self.reload.send(Command::ReloadConfig(json.to_string(), code.to_string(), reply))?;
+        result.recv_timeout(RELOAD_TIMEOUT)
+            .map_err(|_| anyhow::anyhow!("main loop busy"))?
+            .map_err(|e| anyhow::anyhow!("{}", e))
+    }
+
+    // Store a config file checked by the main loop, keeping the previous one to roll back to
+    pub fn store(&self, json: &str) -> anyhow::Result<()> {
+        if let Ok(previous) = fs::read_to_string(CONFIG_FILE) {
+            fs::write(CONFIG_GOOD_FILE, previous)?;
+        }
+        fs::write(CONFIG_FILE, json)?;
+        info!("Config file {} uploaded ({} bytes)", CONFIG_FILE, json.len());
+        Ok(())
+    }
+
+    // Remove the config file and its backup (factory reset)
+    pub fn remove(&self) {
+        for file in [CONFIG_FILE, CONFIG_GOOD_FILE] {
+            match fs::remove_file(file) {
+                Ok(()) => info!("{} removed", file),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+                Err(e) => warn!("Failed to remove {}: {}", file, e),
+            }
+        }
+    }
+}
@@ -0,0 +1,479 @@
+// Serial console over the native USB (USB-Serial-JTAG)
+// The console thread parses command lines and sends them to the main loop over the bus.
+// Lines starting with '{' are requests of the desktop app protocol (see hostlink); the
+// prompt is left out after them until the next command line.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use log::*;
+use std::io::{BufRead, Write};
+use std::sync::mpsc::Sender;
+use std::thread;
+use crate::bus::Command;
+use crate::hostlink::{self, HostRequest};
+use crate::logfilter::Sink;
+use crate::settings::{PidChange, PidUpdate};
+use crate::controltask::{CH1, CH2};
+use crate::displayctl::{IDENTIFY_SECS, MAX_IDENTIFY_SECS};
+use crate::testscripts::ScriptRequest;
+use dcpower_control::capture::CaptureTrigger;
+use dcpower_control::session::RunLabel;
+use dcpower_control::unitsync::SyncAction;
+use dcpower_control::offsetcal::CalibrationAction;
+
+const HELP_TEXT: &str = "\
+Commands:
+  help                 Show this help
+  status               Read the measurements and output state
+  get [name]           Show a setting (all settings without name)
+  set <name> <value> [code]
+                       Change a setting and store it in NVS; the protection limits,
+                       protection_unlock_code and safe_mode take the unlock code after the value
+  on | off [ch]        Start/stop the output (channel 1 or 2, default 1)
+  voltage <V> [ch]     Set the output voltage
+  current <A> [ch]     Set the session current limit (not saved, up to the maximum)
+  identify [s]         Flash the display with the IP address for s seconds (10 by default, 0 to stop)
+  marker [text]        Put a numbered marker with the text in the log and InfluxDB
+  calibrate [start | status | apply | discard]
+                       Run the INA228 offset calibration and apply it; start measures the
+                       offsets (outputs off) and keeps them until apply or discard
+  ripple [ch]          Measure the output ripple (peak-to-peak and RMS) with a burst of readings
+  cable                Estimate the USB PD cable and connector resistance (output on, with a load)
+  i2c [scan]           Show the I2C device health (transfers, NACKs, timeouts, bus recoveries);
+                       scan lists the addresses which answer on both sides of the bus
+  capture [current <A> | voltage <V> | fault | off]
+                       Arm a triggered capture of channel 1 (current above, voltage below
+                       or a trip) or disarm it; without arguments show its state
+  schedule             Show the local time and the schedule entries
+                       (change them with 'set schedule <entries>')
+  alarms               Show the alarm rules and the raised alarms
+                       (change them with 'set alarms <rules>')
+  cycle [start [<V> <on s> <off s> <cycles>] | stop]
+                       Run the on/off endurance test on channel 1 (cycle_* settings
+                       without arguments, 0 cycles until stopped); show its progress
+  script [list | show <name> | run <name> | stop | delete <name>]
+                       Run a test script stored on SPIFFS on channel 1; without
+                       arguments show the state and the checks of the run
+  sync [on | off | cycle [run ID] | cancel]
+                       Turn the outputs on/off or start the endurance test on all the
+                       units of the sync group at the same time; without arguments show
+                       the group and the pending start
+  pdprobe [start | stop]
+                       Probe the attached USB PD charger at each advertised PDO (outputs
+                       off); without arguments show the progress or the last report
+  pdcal [start | stop | clear]
+                       Calibrate the USB PD voltage divider against the AP33772S at 5V and
+                       the highest fixed PDO (outputs off, stored in NVS); clear resets it
+  pwmoffset [learn]    Show the PWM offsets learned at each PDO voltage, or learn them
+                       again (outputs off)
+  dut [<id> [note] | clear]
+                       Show or set the DUT identifier and note of the run (InfluxDB
+                       tags and session reports, not saved)
+  raw [on | off]       Stream every sample as binary frames on this port (see the README);
+                       without arguments show the frames sent and dropped
+  latency [reset]      Show the control path latency (p50, p99, max and the cycles over
+                       latency_budget_us) and the key input latency; reset clears them
+                       after showing them
+  dump                 Dump the recorded logs
+  reboot               Restart the unit
+  factory-reset        Erase all settings (confirm on the unit)
+  crash [dump|clear]   Show the reset reason and the stored crash dump
+  errors [reset]       Show the error counters kept across reboots (boots, watchdog resets,
+                       trips, I2C errors, USB PD failures); reset clears them
+  pid [kp ki kd [pwm_offset]] | pid defaults
+                       Show or tune the PID gains (applied live, stored in NVS)
+  settings export       Print all the settings (including calibration) as JSON
+  settings import <json> [code]
+                       Apply exported settings and store them in NVS; the protection
+                       limits take the unlock code after the document
+  log [console|syslog] [filter]
+                       Show or change the log level, e.g. 'log info,usbpd=debug'
+                       (not saved, use 'set log_level|syslog_level <filter>' to keep it)";
+
+#[derive(Debug, Clone)]
+pub enum ConsoleCommand {
+    Status,
+    Get(Option<String>),
+    Set(String, String),
+    // Channel index (controltask::CH1, CH2) and the value
+    Output(usize, bool),
+    Voltage(usize, f32),
+    Current(usize, f32),
+    // Flash the display for the seconds
+    Identify(u32),
+    // Marker of the operator with its text
+    Marker(String),
+    Calibrate,
+    // Offset calibration of a test fixture, applied on request
+    Calibration(CalibrationAction),
+    // End-of-line production test, not listed in the help
+    ProdTest,
+    Ripple(usize),
+    Cable,
+    I2cStatus,
+    I2cScan,
+    // Arm with the trigger, None to disarm
+    Capture(Option<CaptureTrigger>),
+    CaptureStatus,
+    // List the schedule entries
+    Schedule,
+    // List the alarm rules and their state
+    Alarms,
+    // Start the endurance test: voltage, on and off seconds and cycles, None for the settings
+    CycleStart(Option<(f32, u32, u32, u32)>),
+    CycleStop,
+    CycleStatus,
+    // Run or stop a test script, or show the run
+    Script(ScriptRequest),
+    ScriptList,
+    ScriptShow(String),
+    ScriptDelete(String),
+    // Start of the sync group with the run ID, None for a new one
+    SyncStart(SyncAction, Option<String>),
+    SyncCancel,
+    SyncStatus,
+    // USB PD charger probe of the advertised PDOs
+    PdProbeStart,
+    PdProbeStop,
+    PdProbeStatus,
+    // Two-point calibration of the USB PD voltage divider
+    PdCalStart,
+    PdCalStop,
+    PdCalClear,
+    PdCalStatus,
+    // PWM offsets learned by the duty ramp
+    PwmOffsetLearn,
+    PwmOffsetStatus,
+    // Set the run label, None to show it
+    Dut(Option<RunLabel>),
+    // Start or stop the raw sample stream, None to show its counters
+    Raw(Option<bool>),
+    // Show the control latency, and clear it if true
+    Latency(bool),
+    Dump,
+    Reboot,
+    FactoryReset,
+    CrashInfo,
+    CrashDump,
+    CrashClear,
+    // Show the error counters, and clear them if true
+    ErrorCounters(bool),
+    Log(Option<Sink>, Option<String>),
+    Pid(PidChange),
+    SettingsExport,
+    SettingsImport(String, String),
+}
+
+pub struct Console {
+    commands: Sender<Command>,
+}
+
+impl Console {
+    pub fn new(commands: Sender<Command>) -> Console {
+        Console { commands: commands }
+    }
+
+    pub fn start(&mut self)
+    {
+        let commands = self.commands.clone();
+        let _th = thread::spawn(move || {
+            info!("Start Console Thread.");
+            crate::health::register_task("console");
+            unsafe {
+                // Blocking reads on stdin require the USB-Serial-JTAG driver; the transmit
+                // buffer holds a block of the raw stream
+                let mut config = esp_idf_sys::usb_serial_jtag_driver_config_t {
+                    tx_buffer_size: 4096,
+                    rx_buffer_size: 256,
+                };
+                esp_idf_sys::usb_serial_jtag_driver_install(&mut config);
+                esp_idf_sys::esp_vfs_usb_serial_jtag_use_driver();
+            }
+            let stdin = std::io::stdin();
+            let mut line = String::new();
+            let mut host_mode = false;
+            loop {
+                if !host_mode {
+                    print!("> ");
+                    let _ = std::io::stdout().flush();
+                }
+                line.clear();
+                if stdin.lock().read_line(&mut line).is_err() {
+                    continue;
+                }
+                host_mode = line.trim_start().starts_with('{');
+                if host_mode {
+                    match HostRequest::parse(line.trim()) {
+                        Ok(request) => {
+                            let _ = commands.send(Command::Host(request));
+                        },
+                        Err((id, msg)) => hostlink::reply(id, Err(msg)),
+                    }
+                    continue;
+                }
+                match parse_command(line.trim()) {
+                    Ok(Some(cmd)) => {
+                        let _ = commands.send(Command::Console(cmd));
+                    },
+                    Ok(None) => {},
+                    Err(msg) => {
+                        println!("{}", msg);
+                    },
+                }
+            }
+        });
+    }
+}
+
+fn parse_command(line: &str) -> Result<Option<ConsoleCommand>, String> {
+    let mut args = line.split_whitespace();
+    let cmd = match args.next() {
+        Some(cmd) => cmd.to_lowercase(),
+        None => return Ok(None),
+    };
+    match cmd.as_str() {
+        "help" | "?" => {
+            println!("{}", HELP_TEXT);
+            Ok(None)
+        },
+        "status" => Ok(Some(ConsoleCommand::Status)),
+        "get" => Ok(Some(ConsoleCommand::Get(args.next().map(|s| s.to_string())))),
+        "set" => {
+            let name = args.next().ok_or("usage: set <name> <value>")?;
+            let value : Vec<&str> = args.collect();
+            if value.is_empty() {
+                return Err("usage: set <name> <value>".to_string());
+            }
+            Ok(Some(ConsoleCommand::Set(name.to_string(), value.join(" "))))
+        },
+        "on" => Ok(Some(ConsoleCommand::Output(parse_channel(args.next())?, true))),
+        "off" => Ok(Some(ConsoleCommand::Output(parse_channel(args.next())?, false))),
+        "voltage" => {
+            let value = args.next().ok_or("usage: voltage <V> [ch]")?;
+            let voltage = value.parse::<f32>().map_err(|_| format!("invalid voltage: {}", value))?;
+            Ok(Some(ConsoleCommand::Voltage(parse_channel(args.next())?, voltage)))
+        },
+        "current" => {
+            let value = args.next().ok_or("usage: current <A> [ch]")?;
+            let current = value.parse::<f32>().map_err(|_| format!("invalid current: {}", value))?;
+            Ok(Some(ConsoleCommand::Current(parse_channel(args.next())?, current)))
+        },
+        "identify" => {
+            let usage = format!("usage: identify [0 to {} s]", MAX_IDENTIFY_SECS);
+            match args.next() {
+                None => Ok(Some(ConsoleCommand::Identify(IDENTIFY_SECS))),
+                Some(arg) => match arg.parse::<u32>() {
+                    Ok(secs) if secs <= MAX_IDENTIFY_SECS => Ok(Some(ConsoleCommand::Identify(secs))),
+                    _ => Err(usage),
+                },
+            }
+        },
+        "calibrate" => {
+            match args.next() {
+                None => Ok(Some(ConsoleCommand::Calibrate)),
+                Some(action) => CalibrationAction::parse(action)
+                    .map(|action| Some(ConsoleCommand::Calibration(action)))
+                    .ok_or_else(|| "usage: calibrate [start | status | apply | discard]".to_string()),
+            }
+        },
+        "ripple" => Ok(Some(ConsoleCommand::Ripple(parse_channel(args.next())?))),
+        "cable" => Ok(Some(ConsoleCommand::Cable)),
+        "i2c" => {
+            match args.next() {
+                None => Ok(Some(ConsoleCommand::I2cStatus)),
+                Some("scan") => Ok(Some(ConsoleCommand::I2cScan)),
+                Some(_) => Err("usage: i2c [scan]".to_string()),
+            }
+        },
+        "capture" => {
+            let usage = "usage: capture [current <A> | voltage <V> | fault | off]";
+            let level = |arg: Option<&str>| -> Result<f32, String> {
+                let value = arg.ok_or(usage)?;
+                value.parse::<f32>().map_err(|_| format!("invalid level: {}", value))
+            };
+            match args.next() {
+                None => Ok(Some(ConsoleCommand::CaptureStatus)),
+                Some("current") => Ok(Some(ConsoleCommand::Capture(Some(CaptureTrigger::CurrentAbove(level(args.next())?))))),
+                Some("voltage") => Ok(Some(ConsoleCommand::Capture(Some(CaptureTrigger::VoltageBelow(level(args.next())?))))),
+                Some("fault") => Ok(Some(ConsoleCommand::Capture(Some(CaptureTrigger::Fault)))),
+                Some("off") => Ok(Some(ConsoleCommand::Capture(None))),
+                Some(_) => Err(usage.to_string()),
+            }
+        },
+        "schedule" => Ok(Some(ConsoleCommand::Schedule)),
+        "alarms" => Ok(Some(ConsoleCommand::Alarms)),
+        "cycle" => {
+            let usage = "usage: cycle [start [<V> <on s> <off s> <cycles>] | stop]";
+            let values : Vec<&str> = args.collect();
+            match values.as_slice() {
+                [] => Ok(Some(ConsoleCommand::CycleStatus)),
+                ["stop"] => Ok(Some(ConsoleCommand::CycleStop)),
+                ["start"] => Ok(Some(ConsoleCommand::CycleStart(None))),
+                ["start", voltage, on, off, cycles] => {
+                    let voltage = voltage.parse::<f32>().map_err(|_| format!("invalid voltage: {}", voltage))?;
+                    let secs = |v: &str| v.parse::<u32>().ok().filter(|s| *s > 0).ok_or(format!("invalid time: {}", v));
+                    let cycles = cycles.parse::<u32>().map_err(|_| format!("invalid cycles: {}", cycles))?;
+                    Ok(Some(ConsoleCommand::CycleStart(Some((voltage, secs(on)?, secs(off)?, cycles)))))
+                },
+                _ => Err(usage.to_string()),
+            }
+        },
+        "script" => {
+            let usage = "usage: script [list | show <name> | run <name> | stop | delete <name>]";
+            let values : Vec<&str> = args.collect();
+            match values.as_slice() {
+                [] => Ok(Some(ConsoleCommand::Script(ScriptRequest::Status))),
+                ["list"] => Ok(Some(ConsoleCommand::ScriptList)),
+                ["show", name] => Ok(Some(ConsoleCommand::ScriptShow(name.to_string()))),
+                ["run", name] => Ok(Some(ConsoleCommand::Script(ScriptRequest::Run(name.to_string())))),
+                ["stop"] => Ok(Some(ConsoleCommand::Script(ScriptRequest::Stop))),
+                ["delete", name] => Ok(Some(ConsoleCommand::ScriptDelete(name.to_string()))),
+                _ => Err(usage.to_string()),
+            }
+        },
+        "sync" => {
+            let usage = "usage: sync [on | off | cycle [run ID] | cancel]";
+            let values : Vec<&str> = args.collect();
+            match values.as_slice() {
+                [] => Ok(Some(ConsoleCommand::SyncStatus)),
+                ["cancel"] => Ok(Some(ConsoleCommand::SyncCancel)),
+                [action] => Ok(Some(ConsoleCommand::SyncStart(SyncAction::parse(action).ok_or(usage)?, None))),
+                [action, run_id] => Ok(Some(ConsoleCommand::SyncStart(SyncAction::parse(action).ok_or(usage)?, Some(run_id.to_string())))),
+                _ => Err(usage.to_string()),
+            }
+        },
+        "pdprobe" => {
+            match args.next() {
+                None => Ok(Some(ConsoleCommand::PdProbeStatus)),
+                Some("start") => Ok(Some(ConsoleCommand::PdProbeStart)),
+                Some("stop") => Ok(Some(ConsoleCommand::PdProbeStop)),
+                Some(_) => Err("usage: pdprobe [start | stop]".to_string()),
+            }
+        },
+        "pdcal" => {
+            match args.next() {
+                None => Ok(Some(ConsoleCommand::PdCalStatus)),
+                Some("start") => Ok(Some(ConsoleCommand::PdCalStart)),
+                Some("stop") => Ok(Some(ConsoleCommand::PdCalStop)),
+                Some("clear") => Ok(Some(ConsoleCommand::PdCalClear)),
+                Some(_) => Err("usage: pdcal [start | stop | clear]".to_string()),
+            }
+        },
+        "pwmoffset" => {
+            match args.next() {
+                None => Ok(Some(ConsoleCommand::PwmOffsetStatus)),
+                Some("learn") => Ok(Some(ConsoleCommand::PwmOffsetLearn)),
+                Some(_) => Err("usage: pwmoffset [learn]".to_string()),
+            }
+        },
+        "marker" => {
+            let text : Vec<&str> = args.collect();
+            Ok(Some(ConsoleCommand::Marker(text.join(" "))))
+        },
+        "dut" => {
+            let values : Vec<&str> = args.collect();
+            match values.as_slice() {
+                [] => Ok(Some(ConsoleCommand::Dut(None))),
+                ["clear"] => Ok(Some(ConsoleCommand::Dut(Some(RunLabel::default())))),
+                [dut, note @ ..] => Ok(Some(ConsoleCommand::Dut(Some(RunLabel::new(dut, &note.join(" "))?)))),
+            }
+        },
+        "raw" => {
+            match args.next() {
+                None => Ok(Some(ConsoleCommand::Raw(None))),
+                Some("on") => Ok(Some(ConsoleCommand::Raw(Some(true)))),
+                Some("off") => Ok(Some(ConsoleCommand::Raw(Some(false)))),
+                Some(_) => Err("usage: raw [on | off]".to_string()),
+            }
+        },
+        "latency" => {
+            match args.next() {
+                None => Ok(Some(ConsoleCommand::Latency(false))),
+                Some("reset") => Ok(Some(ConsoleCommand::Latency(true))),
+                Some(_) => Err("usage: latency [reset]".to_string()),
+            }
+        },
+        "prodtest" => Ok(Some(ConsoleCommand::ProdTest)),
+        "dump" => Ok(Some(ConsoleCommand::Dump)),
+        "reboot" => Ok(Some(ConsoleCommand::Reboot)),
+        "factory-reset" => Ok(Some(ConsoleCommand::FactoryReset)),
+        "crash" => match args.next() {
+            None => Ok(Some(ConsoleCommand::CrashInfo)),
+            Some("dump") => Ok(Some(ConsoleCommand::CrashDump)),
+            Some("clear") => Ok(Some(ConsoleCommand::CrashClear)),
+            Some(arg) => Err(format!("usage: crash [dump|clear] ({})", arg)),
+        },
+        "errors" => match args.next() {
+            None => Ok(Some(ConsoleCommand::ErrorCounters(false))),
+            Some("reset") => Ok(Some(ConsoleCommand::ErrorCounters(true))),
+            Some(_) => Err("usage: errors [reset]".to_string()),
+        },
+        "pid" => {
+            let values : Vec<&str> = args.collect();
+            match values.as_slice() {
+                [] => Ok(Some(ConsoleCommand::Pid(PidChange::Get))),
+                ["defaults"] => Ok(Some(ConsoleCommand::Pid(PidChange::Defaults))),
+                [kp, ki, kd, rest @ ..] if rest.len() <= 1 => {
+                    let gain = |v: &str| v.parse::<f32>().map_err(|_| format!("invalid gain: {}", v));
+                    let pwm_offset = match rest.first() {
+                        Some(v) => Some(v.parse::<u32>().map_err(|_| format!("invalid pwm_offset: {}", v))?),
+                        None => None,
+                    };
+                    Ok(Some(ConsoleCommand::Pid(PidChange::Set(PidUpdate {
+                        kp: Some(gain(kp)?),
+                        ki: Some(gain(ki)?),
+                        kd: Some(gain(kd)?),
+                        pwm_offset: pwm_offset,
+                    }))))
+                },
+                _ => Err("usage: pid [kp ki kd [pwm_offset]] | pid defaults".to_string()),
+            }
+        },
+        "settings" => match args.next() {
+            Some("export") => Ok(Some(ConsoleCommand::SettingsExport)),
+            Some("import") => {
+                // The JSON document is the rest of the line as is, and the unlock code may follow it
+                let rest = line.split_once("import").map(|(_, rest)| rest.trim()).unwrap_or("");
+                let (json, code) = match rest.rfind('}') {
+                    Some(end) => (&rest[..=end], rest[end + 1..].trim()),
+                    None => (rest, ""),
+                };
+                if json.is_empty() {
+                    return Err("usage: settings import <json> [code]".to_string());
+                }
+                Ok(Some(ConsoleCommand::SettingsImport(json.to_string(), code.to_string())))
+            },
+            _ => Err("usage: settings export|import <json>".to_string()),
+        },
+        "log" => {
+            let mut args = args.peekable();
+            let sink = match args.peek() {
+                Some(&"console") => Some(Sink::Console),
+                Some(&"syslog") => Some(Sink::Syslog),
+                _ => None,
+            };
+            if sink.is_some() {
+                args.next();
+            }
+            let filter : Vec<&str> = args.collect();
+            if filter.is_empty() {
+                Ok(Some(ConsoleCommand::Log(sink, None)))
+            }
+            else {
+                Ok(Some(ConsoleCommand::Log(sink, Some(filter.join("")))))
+            }
+        },
+        _ => Err(format!("unknown command: {} (type 'help')", cmd)),
+    }
+}
+
+// Channel number 1 or 2 (default 1) to the channel index
+fn parse_channel(arg: Option<&str>) -> Result<usize, String> {
+    match arg {
+        None | Some("1") => Ok(CH1),
+        Some("2") => Ok(CH2),
+        Some(ch) => Err(format!("invalid channel: {} (1 or 2)", ch)),
+    }
+}
@@ -0,0 +1,981 @@
+// Fast control task
+// The INA228 measurement, the current/power protection and the PID run in a high-priority
+// task paced by the control timer. The housekeeping loop (keys, display, WiFi, transfer)
+// sends commands over a channel and reads the measurements from a lock-free snapshot,
+// so a slow InfluxDB POST or display update cannot delay the regulation.
+// The task owns the I2C bus; USB PD requests and the calibration are done by it on request.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use log::*;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use esp_idf_hal::cpu::Core;
+use esp_idf_hal::gpio::{Gpio14, Gpio42, Gpio46, Output, PinDriver};
+use esp_idf_hal::i2c::I2cDriver;
+use esp_idf_hal::ledc::LedcDriver;
+use esp_idf_hal::task::thread::ThreadSpawnConfiguration;
+use dcpower_control::regulator::Regulator;
+use dcpower_control::limits::{OverVoltageDetector, ProtectionLimits, ShortCircuitDetector};
+use dcpower_control::recovery::TripCause;
+use dcpower_control::regstats::{RegulationStats, RegulationReport, REGULATION_BAND_V, LOAD_STEP_A, SETTLE_MS};
+use dcpower_control::ripple::RippleReport;
+use dcpower_control::sense::RemoteSense;
+use dcpower_control::stale::{Reading, SampleState, StalePolicy};
+use dcpower_control::consistency::{PowerCheck, PowerCheckEvent};
+use dcpower_control::i2chealth::{DeviceEvent, I2cHealth, I2cOutcome};
+use dcpower_control::pwmoffset::{OffsetRamp, RampStep};
+use dcpower_control::bleed::Bleed;
+use dcpower_control::scopetrigger::{ScopeTrigger, TriggerConfig, TriggerSource};
+use dcpower_control::stepdown::{SetpointRamp, StepDownEvent};
+use dcpower_control::pidtrace::{PidPoint, PidTrace};
+use dcpower_control::power::signed_power;
+use dcpower_control::latency::{LatencyReport, LatencyStats};
+use dcpower_control::offsetcal::ChannelOffsets;
+use crate::CurrentLog;
+use crate::controltimer::{self, ControlTimer};
+use crate::usbpd::AP33772S;
+use crate::ina228;
+use crate::i2cbus;
+use crate::perfcounters;
+use crate::settings::PidGains;
+use crate::rawstream::RawTap;
+
+// Above the main task and the network threads, below the WiFi driver and esp_timer tasks
+const CONTROL_TASK_PRIORITY: u8 = 15;
+const CONTROL_TASK_STACK_SIZE: usize = 8192;
+
+// Voltage readings of a ripple burst (about 50ms, the regulation is paused during the burst)
+const RIPPLE_BURST_SAMPLES: usize = 256;
+
+// Control cycles on a reused sample before a sensor fault, until the StaleLimit command
+const DEFAULT_STALE_LIMIT: u32 = 10;
+// Lower setpoints are applied at once until the StepDownTime command
+const DEFAULT_STEP_DOWN_TIME_MS: u32 = 0;
+// No cycle is over the budget until the LatencyBudget command
+const DEFAULT_LATENCY_BUDGET_US: u32 = 0;
+// The power is not checked until the PowerCheck command
+const DEFAULT_POWER_CHECK_TOLERANCE: f32 = 0.0;
+
+// Latency of the control path at the last publish
+static LATENCY: Mutex<LatencyReport> = Mutex::new(LatencyReport {
+    samples: 0, p50_us: 0, p99_us: 0, max_us: 0, over_budget: 0, budget_us: 0,
+});
+
+pub fn latency_report() -> LatencyReport {
+    *LATENCY.lock().unwrap()
+}
+
+// Output channel index
+pub const CH1: usize = 0;
+pub const CH2: usize = 1;
+
+// Commands from the housekeeping loop
+#[derive(Debug, Clone)]
+pub enum ControlCommand {
+    // Start (the regulator is reset) or stop the output of a channel
+    Output(usize, bool),
+    Setpoint(usize, f32),
+    Limits { channel: usize, current: f32, power: f32 },
+    // Short circuit thresholds of all the channels
+    ShortCircuit { voltage: f32, current: f32 },
+    // Absolute over-voltage limit of a channel and the samples in a row above it which trip
+    OverVoltage { channel: usize, voltage: f32, samples: u32 },
+    // Control cycles of all the channels on the last good sample after a failed read,
+    // before the output is stopped with a sensor fault
+    StaleLimit(u32),
+    // Relative error (%) allowed between the power read and the voltage times the current of
+    // all the channels, 0 to disable the check
+    PowerCheck(f32),
+    // Time limit of a step-down of the setpoint of all the channels (ms), 0 to apply a lower
+    // setpoint at once
+    StepDownTime(u32),
+    // Sampling rate of the PID internals of all the channels (Hz), 0 to disable the trace
+    PidTraceRate(u32),
+    // All the channels
+    Gains(PidGains),
+    // Ramp the duty of the channels with the output off to find their PWM offset
+    LearnPwmOffset,
+    // Learned PWM offset of a channel for the rail voltage, None for the configured one
+    PwmOffset(usize, Option<u32>),
+    // Request a USB PD voltage (0V for 5V with the output off)
+    UsbPd { voltage: f32, current_ma: u16 },
+    // Request a PDO point as is (no configured offset) for the charger probe, the rail
+    // voltage calibration and the PWM offset learning: the fixed PDO of the voltage, or the
+    // voltage from a PPS APDO
+    PdProbe { voltage: f32, current_ma: u16, fixed: bool },
+    // Measure the offsets of all the channels, reported with Calibrated
+    Calibrate,
+    // Offsets of the channels measured by Calibrate
+    Offsets(Vec<ChannelOffsets>),
+    // Burst of voltage readings of a channel for the ripple estimation
+    Ripple(usize),
+    // Read the input current measured by the AP33772S
+    PdCurrent,
+    // Read the rail voltage measured by the AP33772S
+    PdVoltage,
+    // Regulate on the remote sense of the channels which have it, with the maximum wiring
+    // drop (V); None for the local sense
+    RemoteSense(Option<f32>),
+    // Addresses which answer on both sides of the bus
+    I2cScan,
+    // Low-power idle: the channels are measured at the housekeeping rate only. Starting an
+    // output leaves it.
+    Idle(bool),
+    // Every sample of the channels to the raw stream over USB, None to stop it
+    RawStream(Option<RawTap>),
+    // Latency budget of the control path (us), 0 for none
+    LatencyBudget(u32),
+    // Clear the latency statistics
+    LatencyReset,
+    // Polarity, width and events of the scope trigger output
+    TriggerConfig(TriggerConfig),
+    // Pulse the scope trigger output for the event, if it is routed
+    Trigger(TriggerSource),
+}
+
+// Events to the housekeeping loop
+#[derive(Debug, Clone)]
+pub enum ControlEvent {
+    // The output of a channel was stopped by the current or power limit
+    Trip(usize, TripCause, CurrentLog),
+    // Contract voltage after a USB PD request, None if the request failed
+    PdContract(Option<f32>),
+    // Readings of the offset calibration taken of the total
+    CalibrationProgress(u32, u32),
+    Calibrated(Result<Vec<ChannelOffsets>, String>),
+    // A device went offline (the outcome of the last transfer) or back online
+    I2cDevice(DeviceEvent),
+    // Bus recovery after a hang, true if SDA was released
+    I2cRecovery(bool),
+    // Addresses on the INA228 and the USB PD side of the bus
+    I2cScan { ina228: Vec<u8>, usb_pd: Vec<u8> },
+    // Regulation statistics of a running channel, true at the end of the session
+    Regulation(usize, RegulationReport, bool),
+    Ripple(usize, Result<RippleReport, String>),
+    // Input current, None if the read failed
+    PdCurrent(Option<f32>),
+    // Rail voltage, None if the read failed
+    PdVoltage(Option<f32>),
+    // Result of the PWM offset ramp of a channel
+    PwmOffset(usize, Result<u32, String>),
+    // Start and end of a step-down of the setpoint of a channel
+    StepDown(usize, StepDownEvent),
+    // PID internals of a channel sampled in the last second
+    PidTrace(usize, Vec<PidPoint>),
+    // The remote sense of a channel was lost (reason), regulating on the local sense
+    RemoteSenseLost(usize, String),
+    // The power read of a channel diverged from the voltage times the current, or agrees again
+    PowerCheck(usize, PowerCheckEvent),
+    // Control cycles over the latency budget in the last second, and the longest (us)
+    LatencyOverBudget { cycles: u32, max_us: u32 },
+}
+
+// Averages of one housekeeping period (voltage, current, power) and the last duty
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChannelMeasurement {
+    pub voltage: f32,
+    pub current: f32,
+    pub power: f32,
+    pub pwm: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Measurement {
+    pub channels: Vec<ChannelMeasurement>,
+    pub ina228_temperature: Option<f32>,
+    pub ap33772s_temperature: Option<f32>,
+    // Monotonic time of the end of the window (ns, see timebase)
+    pub clock: u128,
+    // Incremented on every publish, to tell a new measurement from the previous one
+    pub sequence: u32,
+}
+
+// Measurement shared without a lock (sequence lock). The control task is the only writer;
+// a reader retries if the measurement was being written.
+struct Snapshot {
+    sequence: AtomicU32,
+    channels: Vec<ChannelSnapshot>,
+    ina228_temperature: AtomicU32,
+    ap33772s_temperature: AtomicU32,
+    // Monotonic time in us, in two halves (no 64-bit atomics)
+    clock_low: AtomicU32,
+    clock_high: AtomicU32,
+}
+
+#[derive(Default)]
+struct ChannelSnapshot {
+    voltage: AtomicU32,
+    current: AtomicU32,
+    power: AtomicU32,
+    pwm: AtomicU32,
+}
+
+impl Snapshot {
+    fn new(channels: usize) -> Snapshot {
+        Snapshot {
+            sequence: AtomicU32::new(0),
+            channels: (0..channels).map(|_| ChannelSnapshot::default()).collect(),
+            ina228_temperature: AtomicU32::new(f32::NAN.to_bits()),
+            ap33772s_temperature: AtomicU32::new(f32::NAN.to_bits()),
+            clock_low: AtomicU32::new(0),
+            clock_high: AtomicU32::new(0),
+        }
+    }
+
+    fn publish(&self, m: &Measurement) {
+        let seq = self.sequence.load(Ordering::Relaxed);
+        self.sequence.store(seq.wrapping_add(1), Ordering::Release);
+        for (snapshot, ch) in self.channels.iter().zip(&m.channels) {
+            snapshot.voltage.store(ch.voltage.to_bits(), Ordering::Release);
+            snapshot.current.store(ch.current.to_bits(), Ordering::Release);
+            snapshot.power.store(ch.power.to_bits(), Ordering::Release);
+            snapshot.pwm.store(ch.pwm, Ordering::Release);
+        }
+        self.ina228_temperature.store(m.ina228_temperature.unwrap_or(f32::NAN).to_bits(), Ordering::Release);
+        self.ap33772s_temperature.store(m.ap33772s_temperature.unwrap_or(f32::NAN).to_bits(), Ordering::Release);
+        let clock_us = (m.clock / 1000) as u64;
+        self.clock_low.store(clock_us as u32, Ordering::Release);
+        self.clock_high.store((clock_us >> 32) as u32, Ordering::Release);
+        self.sequence.store(seq.wrapping_add(2), Ordering::Release);
+    }
+
+    fn load(&self) -> Measurement {
+        loop {
+            let before = self.sequence.load(Ordering::Acquire);
+            if before % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let m = Measurement {
+                channels: self.channels.iter().map(|ch| ChannelMeasurement {
+                    voltage: f32::from_bits(ch.voltage.load(Ordering::Acquire)),
+                    current: f32::from_bits(ch.current.load(Ordering::Acquire)),
+                    power: f32::from_bits(ch.power.load(Ordering::Acquire)),
+                    pwm: ch.pwm.load(Ordering::Acquire),
+                }).collect(),
+                ina228_temperature: Some(f32::from_bits(self.ina228_temperature.load(Ordering::Acquire))).filter(|t| !t.is_nan()),
+                ap33772s_temperature: Some(f32::from_bits(self.ap33772s_temperature.load(Ordering::Acquire))).filter(|t| !t.is_nan()),
+                clock: ((self.clock_high.load(Ordering::Acquire) as u128) << 32 | self.clock_low.load(Ordering::Acquire) as u128) * 1000,
+                sequence: before / 2,
+            };
+            if self.sequence.load(Ordering::Acquire) == before {
+                return m;
+            }
+        }
+    }
+}
+
+// Hardware of one output channel
+pub struct ChannelHardware {
+    pub ina228_addr: u8,
+    // INA228 at the load terminals (remote sense), None without it
+    pub sense_addr: Option<u8>,
+    pub current_lsb: f32,
+    pub pwm_driver: LedcDriver<'static>,
+    pub regulator: Regulator,
+    // Output bleed FET (high = discharging) and its control, None without it
+    pub bleed: Option<(PinDriver<'static, Gpio14, Output>, Bleed)>,
+}
+
+// Hardware owned by the control task
+pub struct ControlHardware {
+    pub i2cdrv: I2cDriver<'static>,
+    pub i2c_sel: PinDriver<'static, Gpio46, Output>,
+    pub ap33772s: AP33772S,
+    pub channels: Vec<ChannelHardware>,
+    pub pd_config_offset: f32,
+    // Scope trigger output (at its idle level) and its configuration, None without it
+    pub trigger: Option<(PinDriver<'static, Gpio42, Output>, TriggerConfig)>,
+}
+
+pub struct ControlTask {
+    commands: Sender<ControlCommand>,
+    events: Receiver<ControlEvent>,
+    snapshot: Arc<Snapshot>,
+}
+
+impl ControlTask {
+    // Spawn the control task. The outputs are off until an Output command; send the
+    // Limits of each channel before it.
+    pub fn start(hw: ControlHardware, rate_hz: u32) -> anyhow::Result<ControlTask> {
+        let (commands, command_rx) = channel();
+        let (event_tx, events) = channel();
+        let snapshot = Arc::new(Snapshot::new(hw.channels.len()));
+        let task_snapshot = snapshot.clone();
+        ThreadSpawnConfiguration {
+            name: Some(b"control\0"),
+            priority: CONTROL_TASK_PRIORITY,
+            // WiFi and lwIP run on core 0
+            pin_to_core: Some(Core::Core1),
+            ..Default::default()
+        }.set()?;
+        let spawned = thread::Builder::new().stack_size(CONTROL_TASK_STACK_SIZE).spawn(move || {
+            crate::health::register_task("control");
+            let ControlHardware { i2cdrv, i2c_sel, ap33772s, channels, pd_config_offset, trigger } = hw;
+            let mut devices = vec![("INA228 CH1", channels[CH1].ina228_addr), ("AP33772S", i2cbus::AP33772S_ADDR)];
+            if let Some(ch) = channels.get(CH2) {
+                devices.push(("INA228 CH2", ch.ina228_addr));
+            }
+            if let Some(sense_addr) = channels[CH1].sense_addr {
+                devices.push(("INA228 sense", sense_addr));
+            }
+            let i2c_health = I2cHealth::new(&devices);
+            i2cbus::publish(&i2c_health);
+            let mut task = Task {
+                i2cdrv: i2cdrv,
+                i2c_sel: i2c_sel,
+                ap33772s: ap33772s,
+                i2c_health: i2c_health,
+                pd_config_offset: pd_config_offset,
+                pwm_offset: channels[CH1].regulator.get_pwm_offset(),
+                channels: channels.into_iter().map(Channel::new).collect(),
+                commands: command_rx,
+                events: event_tx,
+                snapshot: task_snapshot,
+                idle: false,
+                raw: None,
+                latency: LatencyStats::new(DEFAULT_LATENCY_BUDGET_US),
+                trigger: trigger.map(|(pin, config)| (pin, ScopeTrigger::new(config))),
+            };
+            task.run(rate_hz);
+        });
+        // Threads spawned later use the defaults
+        ThreadSpawnConfiguration::default().set()?;
+        spawned?;
+        Ok(ControlTask { commands: commands, events: events, snapshot: snapshot })
+    }
+
+    pub fn send(&self, command: ControlCommand) {
+        let _ = self.commands.send(command);
+    }
+
+    // Take all the pending events without blocking
+    pub fn events(&self) -> Vec<ControlEvent> {
+        self.events.try_iter().collect()
+    }
+
+    pub fn measurement(&self) -> Measurement {
+        self.snapshot.load()
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.snapshot.channels.len()
+    }
+}
+
+// State of one output channel in the control task. Each channel (INA228, PWM and PID) is
+// regulated and protected independently.
+struct Channel {
+    hw: ChannelHardware,
+    output_on: bool,
+    setpoint: f32,
+    limits: ProtectionLimits,
+    short_circuit: ShortCircuitDetector,
+    over_voltage: OverVoltageDetector,
+    voltage_offset: f32,
+    current_offset: f32,
+    remote_sense: Option<RemoteSense>,
+    sense_offset: f32,
+    // Updated at the control rate, reported every second and at the end of the session
+    stats: RegulationStats,
+    stale: StalePolicy,
+    power_check: PowerCheck,
+    step_down: SetpointRamp,
+    // PID internals sampled at the trace rate, sent as a batch every second
+    pid_trace: PidTrace,
+    // PWM offset ramp in progress (open loop, output off), and the offset learned for the
+    // rail voltage, which replaces the configured one until it is cleared
+    ramp: Option<OffsetRamp>,
+    learned_offset: Option<u32>,
+    // Duty of the last control period, and when it was set (us since boot, wrapping)
+    duty: u32,
+    duty_set_us: u32,
+    // Tripped in this control period
+    tripped: bool,
+    // Sums of the current housekeeping period
+    window: ChannelMeasurement,
+}
+
+impl Channel {
+    fn new(hw: ChannelHardware) -> Channel {
+        Channel {
+            hw: hw,
+            output_on: false,
+            setpoint: 0.0,
+            limits: ProtectionLimits::new(0.0, 0.0, 0.0),
+            short_circuit: ShortCircuitDetector::disabled(),
+            over_voltage: OverVoltageDetector::disabled(),
+            voltage_offset: 0.0,
+            current_offset: 0.0,
+            remote_sense: None,
+            sense_offset: 0.0,
+            stats: RegulationStats::new(REGULATION_BAND_V, LOAD_STEP_A, SETTLE_MS),
+            stale: StalePolicy::new(DEFAULT_STALE_LIMIT),
+            power_check: PowerCheck::new(DEFAULT_POWER_CHECK_TOLERANCE),
+            step_down: SetpointRamp::new(DEFAULT_STEP_DOWN_TIME_MS),
+            pid_trace: PidTrace::new(0),
+            ramp: None,
+            learned_offset: None,
+            duty: 0,
+            duty_set_us: 0,
+            tripped: false,
+            window: ChannelMeasurement::default(),
+        }
+    }
+
+    // Measure, check the limits and regulate for one control period. Returns the sample,
+    // and true if it is the last good one reused.
+    fn update(&mut self, index: usize, i2cdrv: &mut I2cDriver<'static>, events: &Sender<ControlEvent>, health: &mut I2cHealth) -> (Reading, bool) {
+        let addr = self.hw.ina228_addr;
+        let mut sample = CurrentLog::default();
+        sample.channel = index as u8 + 1;
+        // The first failed read of the cycle counts
+        let started_us = perfcounters::now_us();
+        let voltage = ina228::voltage_read(i2cdrv, addr);
+        let current = ina228::current_read(i2cdrv, addr, self.hw.current_lsb);
+        let power = ina228::power_read(i2cdrv, addr, self.hw.current_lsb);
+        perfcounters::i2c_transfers(3, started_us);
+        let outcome = [&voltage, &current, &power].into_iter()
+            .find_map(|r| r.as_ref().err())
+            .map_or(I2cOutcome::Ok, i2cbus::error_outcome);
+        record_transfer(health, events, addr, outcome);
+        // A failed read reuses the last good sample (not 0V to the PID) with the PID output
+        // held, for up to the stale limit of cycles in a row; then the output is stopped with
+        // a sensor fault
+        let reading = match (voltage, current, power) {
+            (Ok(voltage), Ok(current), Ok(power)) => {
+                let voltage = voltage - self.voltage_offset;
+                let current = current - self.current_offset;
+                // The POWER register is unsigned, negative for a reverse feed
+                Some(Reading {
+                    voltage: voltage,
+                    current: current,
+                    power: signed_power(voltage, current, power),
+                })
+            },
+            _ => None,
+        };
+        let (reading, state) = self.stale.update(reading);
+        sample.voltage = reading.voltage;
+        sample.current = reading.current;
+        sample.power = reading.power;
+        let fresh = state == SampleState::Fresh;
+        // The power read against the voltage times the current (see dcpower_control::consistency)
+        if fresh {
+            if let Some(event) = self.power_check.check(reading.voltage, reading.current, reading.power) {
+                let _ = events.send(ControlEvent::PowerCheck(index, event));
+            }
+        }
+        // With the remote sense, the output is regulated on the voltage at the load, and on the
+        // local voltage if the sense pair appears disconnected. The short circuit detector
+        // checks the output terminals.
+        let local_voltage = sample.voltage;
+        if let (true, true, Some(sense), Some(sense_addr)) = (fresh, self.output_on, self.remote_sense.as_mut(), self.hw.sense_addr) {
+            let remote = ina228::voltage_read(i2cdrv, sense_addr);
+            record_transfer(health, events, sense_addr, remote.as_ref().map_or_else(i2cbus::error_outcome, |_| I2cOutcome::Ok));
+            let remote = remote.ok().map(|v| v - self.sense_offset);
+            let (voltage, lost) = sense.select(local_voltage, remote);
+            sample.voltage = voltage;
+            if let Some(reason) = lost {
+                warn!("CH{} Remote sense lost ({}): local {:.3}V remote {:?}", sample.channel, reason, local_voltage, remote);
+                let _ = events.send(ControlEvent::RemoteSenseLost(index, reason.to_string()));
+            }
+        }
+        // Setpoint regulated on, ramped down over the step-down time after a lower setpoint with
+        // the PID output scaled along; its completion (or timeout) is reported
+        let setpoint = if self.output_on {
+            let (setpoint, event) = self.step_down.update(self.setpoint, sample.voltage);
+            if let Some(event) = event {
+                self.hw.regulator.set_stepping_down(matches!(event, StepDownEvent::Started { .. }));
+                let _ = events.send(ControlEvent::StepDown(index, event));
+            }
+            setpoint
+        }
+        else {
+            self.setpoint
+        };
+        // Sensor Fault, Short Circuit, Over Voltage, Current and Power Limit
+        if self.output_on {
+            let cause = match state {
+                SampleState::Fault => Some(TripCause::SensorFault),
+                SampleState::Stale(_) => None,
+                SampleState::Fresh => self.short_circuit.check(local_voltage, sample.current)
+                    .or_else(|| self.over_voltage.check(local_voltage))
+                    .or_else(|| self.limits.check_electrical(sample.current, sample.power)),
+            };
+            if let Some(cause) = cause {
+                self.output_on = false;
+                self.tripped = true;
+                match cause {
+                    TripCause::ShortCircuit => {
+                        // Cut the PWM before anything else
+                        self.hw.pwm_driver.set_duty(self.hw.regulator.stop()).expect("Set duty failure");
+                        info!("CH{} Short Circuit: {:.3}V {:.3}A", sample.channel, sample.voltage, sample.current);
+                    },
+                    TripCause::OverVoltage => {
+                        self.hw.pwm_driver.set_duty(self.hw.regulator.stop()).expect("Set duty failure");
+                        warn!("CH{} Over Voltage: {:.3}V above {:.3}V", sample.channel, local_voltage, self.over_voltage.voltage);
+                    },
+                    TripCause::SensorFault => warn!("CH{} Sensor Fault: no measurement for {} cycles", sample.channel, self.stale.get_max_stale() + 1),
+                    TripCause::OverCurrent => info!("CH{} Current Limit Over: {:.3}A (PDO Limited)", sample.channel, sample.current),
+                    _ => info!("CH{} Power Limit Over: {:.1}W", sample.channel, sample.power),
+                }
+                let _ = events.send(ControlEvent::Trip(index, cause, sample.clone()));
+                let _ = events.send(ControlEvent::Regulation(index, self.stats.report(), true));
+            }
+            else if fresh {
+                self.stats.update(setpoint, sample.voltage, sample.current);
+            }
+        }
+        let pwm_duty = if self.output_on && fresh {
+            // PID Control
+            let duty = self.hw.regulator.update(setpoint, sample.voltage, sample.current, self.limits.max_current);
+            self.pid_trace.record(setpoint, sample.voltage, self.hw.regulator.get_terms(), duty);
+            duty
+        }
+        else if self.output_on {
+            // Hold the PID output on a reused sample
+            self.duty
+        }
+        else if let Some(ramp) = self.ramp.as_mut() {
+            // PWM offset learning, the duty is held on a reused sample
+            match (fresh, ramp.update(sample.voltage)) {
+                (false, _) => self.duty,
+                (true, RampStep::Duty(duty)) => duty,
+                (true, RampStep::Done(result)) => {
+                    self.ramp = None;
+                    let _ = events.send(ControlEvent::PwmOffset(index, result));
+                    self.hw.regulator.stop()
+                },
+            }
+        }
+        else {
+            self.hw.regulator.stop()
+        };
+        self.hw.pwm_driver.set_duty(pwm_duty).expect("Set duty failure");
+        if pwm_duty != self.duty {
+            perfcounters::pwm_update();
+        }
+        self.duty = pwm_duty;
+        self.duty_set_us = unsafe { esp_idf_sys::esp_timer_get_time() } as u32;
+        // Discharge the output down to the target (the setpoint, 0V with the output off) with
+        // the bleed FET, so a lower setpoint is reached without a load; kept as is on a reused
+        // sample
+        if let (true, Some((pin, bleed))) = (fresh, self.hw.bleed.as_mut()) {
+            let engaged = bleed.is_engaged();
+            if bleed.update(self.output_on, setpoint, local_voltage) != engaged {
+                if bleed.is_engaged() {
+                    pin.set_high().expect("Bleed pin failure");
+                }
+                else {
+                    pin.set_low().expect("Bleed pin failure");
+                }
+            }
+        }
+
+        self.window.voltage += sample.voltage;
+        self.window.current += sample.current;
+        self.window.power += sample.power;
+        self.window.pwm = pwm_duty;
+        (Reading { voltage: sample.voltage, current: sample.current, power: sample.power }, !fresh)
+    }
+
+    // Averages of the housekeeping period, and start the next period
+    fn take_window(&mut self, samples: u32) -> ChannelMeasurement {
+        let mut m = std::mem::take(&mut self.window);
+        m.voltage /= samples as f32;
+        m.current /= samples as f32;
+        m.power /= samples as f32;
+        m
+    }
+}
+
+// Level of the scope trigger output (true is high)
+fn set_level(pin: &mut PinDriver<'static, Gpio42, Output>, high: bool) {
+    if high {
+        pin.set_high().expect("Scope trigger pin failure");
+    }
+    else {
+        pin.set_low().expect("Scope trigger pin failure");
+    }
+}
+
+// Count a transfer with the device and report a change of its state once (not every failed
+// read)
+fn record_transfer(health: &mut I2cHealth, events: &Sender<ControlEvent>, addr: u8, outcome: I2cOutcome) {
+    match health.record(addr, outcome) {
+        Some(event @ DeviceEvent::Offline(name, addr, outcome)) => {
+            warn!("I2C {} (0x{:02x}) offline: {:?}", name, addr, outcome);
+            let _ = events.send(ControlEvent::I2cDevice(event));
+        },
+        Some(event @ DeviceEvent::Online(name, addr)) => {
+            info!("I2C {} (0x{:02x}) online", name, addr);
+            let _ = events.send(ControlEvent::I2cDevice(event));
+        },
+        None => {},
+    }
+}
+
+struct Task {
+    i2cdrv: I2cDriver<'static>,
+    i2c_sel: PinDriver<'static, Gpio46, Output>,
+    ap33772s: AP33772S,
+    i2c_health: I2cHealth,
+    pd_config_offset: f32,
+    // Configured PWM offset, for the channels without a learned one
+    pwm_offset: u32,
+    channels: Vec<Channel>,
+    commands: Receiver<ControlCommand>,
+    events: Sender<ControlEvent>,
+    snapshot: Arc<Snapshot>,
+    idle: bool,
+    raw: Option<RawTap>,
+    latency: LatencyStats,
+    trigger: Option<(PinDriver<'static, Gpio42, Output>, ScopeTrigger)>,
+}
+
+impl Task {
+    fn run(&mut self, rate_hz: u32) {
+        let mut timer = match ControlTimer::start(rate_hz) {
+            Ok(timer) => timer,
+            Err(e) => {
+                warn!("Control timer: {}, using {}Hz", e, controltimer::HOUSEKEEPING_RATE_HZ);
+                ControlTimer::start(controltimer::HOUSEKEEPING_RATE_HZ).expect("Control timer failure")
+            }
+        };
+        let decimation = timer.decimation();
+        info!("Control rate: {}Hz (housekeeping every {} cycles)", timer.rate_hz(), decimation);
+        let mut count : u32 = 0;
+        let mut window = Measurement::default();
+        let mut samples : u32 = 0;
+        loop {
+            timer.wait();
+            count += 1;
+            let tick_us = timer.tick_us();
+            // A command (calibration, ripple burst, USB PD request) delays the cycle on purpose
+            let commands = self.commands.try_iter().collect::<Vec<_>>();
+            let measure_latency = commands.is_empty();
+            for command in commands {
+                self.handle(command);
+            }
+            // The pulse ends on time in the low-power idle too
+            if let Some((pin, trigger)) = self.trigger.as_mut() {
+                if trigger.poll(unsafe { esp_idf_sys::esp_timer_get_time() } as u64) {
+                    set_level(pin, trigger.level());
+                }
+            }
+
+            if self.idle && count % decimation != 0 {
+                continue;
+            }
+            perfcounters::control_cycle();
+            for (index, channel) in self.channels.iter_mut().enumerate() {
+                let (reading, stale) = channel.update(index, &mut self.i2cdrv, &self.events, &mut self.i2c_health);
+                if let Some(raw) = self.raw.as_mut() {
+                    raw.push(index, channel.output_on, stale, &reading);
+                }
+            }
+            // A trip pulses the scope trigger output in the same cycle; the housekeeping loop
+            // requests the pulses of the other events
+            if self.channels.iter_mut().fold(false, |tripped, ch| std::mem::take(&mut ch.tripped) || tripped) {
+                self.trigger_pulse(TriggerSource::Trip);
+            }
+            // Latency from the timer tick to the last new duty, published every second and
+            // reported when a cycle is over the budget
+            if let (true, Some(last)) = (measure_latency, self.channels.last()) {
+                self.latency.record(last.duty_set_us.wrapping_sub(tick_us));
+            }
+            samples += 1;
+            if count % decimation != 0 {
+                continue;
+            }
+            // Reference temperatures for the plausibility check and the regulation statistics (1s)
+            if count % timer.rate_hz() == 0 {
+                for (index, ch) in self.channels.iter().enumerate().filter(|(_, ch)| ch.output_on) {
+                    let _ = self.events.send(ControlEvent::Regulation(index, ch.stats.report(), false));
+                }
+                for (index, ch) in self.channels.iter_mut().enumerate().filter(|(_, ch)| ch.pid_trace.is_enabled()) {
+                    let points = ch.pid_trace.take();
+                    if !points.is_empty() {
+                        let _ = self.events.send(ControlEvent::PidTrace(index, points));
+                    }
+                }
+                window.ina228_temperature = ina228::temperature_read(&mut self.i2cdrv, ina228::INA228_ADDR).ok();
+                self.i2c_sel.set_high().unwrap(); // Enable USB PD
+                window.ap33772s_temperature = self.ap33772s.get_temperature_c(&mut self.i2cdrv).ok().map(|t| t as f32);
+                self.i2c_sel.set_low().unwrap(); // Select INA228
+                self.check_bus();
+                let (cycles, max_us) = self.latency.take_over_budget();
+                if cycles > 0 {
+                    warn!("Control latency over {}us in {} cycles (max {}us)", self.latency.get_budget(), cycles, max_us);
+                    let _ = self.events.send(ControlEvent::LatencyOverBudget { cycles: cycles, max_us: max_us });
+                }
+                *LATENCY.lock().unwrap() = self.latency.report();
+            }
+            window.channels = self.channels.iter_mut().map(|ch| ch.take_window(samples)).collect();
+            window.clock = crate::timebase::monotonic_ns();
+            self.snapshot.publish(&window);
+            samples = 0;
+        }
+    }
+
+    // Start a pulse of the scope trigger output for a routed event
+    fn trigger_pulse(&mut self, source: TriggerSource) {
+        if let Some((pin, trigger)) = self.trigger.as_mut() {
+            let now_us = unsafe { esp_idf_sys::esp_timer_get_time() } as u64;
+            if trigger.fire(source, now_us) {
+                set_level(pin, trigger.level());
+                info!("Scope trigger: {}", source.as_str());
+            }
+        }
+    }
+
+    // Ping the devices, recover a hung bus and publish the bus health (1s)
+    fn check_bus(&mut self) {
+        let addrs : Vec<u8> = self.i2c_health.get_devices().iter().map(|d| d.addr).collect();
+        for addr in addrs {
+            let usb_pd = addr == i2cbus::AP33772S_ADDR;
+            if usb_pd {
+                self.i2c_sel.set_high().unwrap(); // Enable USB PD
+            }
+            let outcome = i2cbus::ping(&mut self.i2cdrv, addr);
+            if usb_pd {
+                self.i2c_sel.set_low().unwrap(); // Select INA228
+            }
+            record_transfer(&mut self.i2c_health, &self.events, addr, outcome);
+        }
+        if self.i2c_health.is_hung() {
+            // The device holding SDA may be on either side
+            self.i2c_sel.set_high().unwrap(); // Enable USB PD
+            let usb_pd = i2cbus::recover();
+            self.i2c_sel.set_low().unwrap(); // Select INA228
+            let released = i2cbus::recover() && usb_pd;
+            self.i2c_health.recovered(released);
+            if released {
+                warn!("I2C bus hung, recovered");
+            }
+            else {
+                warn!("I2C bus hung, SDA still held low after the recovery");
+            }
+            let _ = self.events.send(ControlEvent::I2cRecovery(released));
+        }
+        i2cbus::publish(&self.i2c_health);
+    }
+
+    fn handle(&mut self, command: ControlCommand) {
+        match command {
+            ControlCommand::Output(index, on) => {
+                if let Some(ch) = self.channels.get_mut(index) {
+                    if on && !ch.output_on {
+                        if ch.ramp.take().is_some() {
+                            let _ = self.events.send(ControlEvent::PwmOffset(index, Err("output started".to_string())));
+                        }
+                        ch.hw.regulator.reset();
+                        ch.hw.regulator.set_stepping_down(false);
+                        ch.step_down.reset(ch.setpoint);
+                        ch.short_circuit.reset();
+                        ch.over_voltage.reset();
+                        ch.stats.reset();
+                        ch.stale.reset();
+                        if let Some(sense) = ch.remote_sense.as_mut() {
+                            sense.reset();
+                        }
+                    }
+                    if !on && ch.output_on {
+                        let _ = self.events.send(ControlEvent::Regulation(index, ch.stats.report(), true));
+                    }
+                    ch.output_on = on;
+                }
+                if on {
+                    self.idle = false;
+                }
+            },
+            ControlCommand::Setpoint(index, voltage) => {
+                if let Some(ch) = self.channels.get_mut(index) {
+                    ch.setpoint = voltage;
+                }
+            },
+            ControlCommand::Limits { channel, current, power } => {
+                if let Some(ch) = self.channels.get_mut(channel) {
+                    ch.limits = ProtectionLimits::new(current, power, 0.0);
+                }
+            },
+            ControlCommand::ShortCircuit { voltage, current } => {
+                for ch in self.channels.iter_mut() {
+                    ch.short_circuit = ShortCircuitDetector::new(voltage, current);
+                }
+            },
+            ControlCommand::OverVoltage { channel, voltage, samples } => {
+                if let Some(ch) = self.channels.get_mut(channel) {
+                    ch.over_voltage = OverVoltageDetector::new(voltage, samples);
+                }
+            },
+            ControlCommand::StaleLimit(limit) => {
+                for ch in self.channels.iter_mut() {
+                    ch.stale.set_max_stale(limit);
+                }
+            },
+            ControlCommand::TriggerConfig(config) => {
+                if let Some((pin, trigger)) = self.trigger.as_mut() {
+                    trigger.set_config(config);
+                    set_level(pin, trigger.level());
+                }
+            },
+            ControlCommand::Trigger(source) => self.trigger_pulse(source),
+            ControlCommand::PowerCheck(tolerance) => {
+                for ch in self.channels.iter_mut() {
+                    ch.power_check.set_tolerance(tolerance);
+                }
+            },
+            ControlCommand::StepDownTime(time_limit_ms) => {
+                for ch in self.channels.iter_mut() {
+                    ch.step_down.set_time_limit(time_limit_ms);
+                }
+            },
+            ControlCommand::PidTraceRate(rate_hz) => {
+                for ch in self.channels.iter_mut() {
+                    ch.pid_trace.set_rate(rate_hz);
+                }
+            },
+            ControlCommand::RemoteSense(max_drop) => {
+                for ch in self.channels.iter_mut().filter(|ch| ch.hw.sense_addr.is_some()) {
+                    ch.remote_sense = max_drop.map(RemoteSense::new);
+                }
+            },
+            ControlCommand::Gains(gains) => {
+                for ch in self.channels.iter_mut() {
+                    ch.hw.regulator.set_gains(gains.kp, gains.ki, gains.kd, ch.learned_offset.unwrap_or(gains.pwm_offset));
+                }
+                self.pwm_offset = gains.pwm_offset;
+                info!("PID Controller: KP={} KI={} KD={} PWM offset={}", gains.kp, gains.ki, gains.kd, gains.pwm_offset);
+            },
+            ControlCommand::LearnPwmOffset => {
+                for (index, ch) in self.channels.iter_mut().enumerate() {
+                    if ch.output_on {
+                        let _ = self.events.send(ControlEvent::PwmOffset(index, Err("output on".to_string())));
+                    }
+                    else {
+                        ch.ramp = Some(OffsetRamp::new(ch.hw.regulator.get_max_duty()));
+                    }
+                }
+            },
+            ControlCommand::PwmOffset(index, offset) => {
+                if let Some(ch) = self.channels.get_mut(index) {
+                    ch.learned_offset = offset;
+                    ch.hw.regulator.set_pwm_offset(offset.unwrap_or(self.pwm_offset));
+                }
+            },
+            ControlCommand::UsbPd { voltage, current_ma } => {
+                let contract = crate::usbpd_control(&mut self.i2c_sel, &mut self.ap33772s, &mut self.i2cdrv,
+                    voltage, self.pd_config_offset, current_ma);
+                let _ = self.events.send(ControlEvent::PdContract(contract));
+            },
+            ControlCommand::PdProbe { voltage, current_ma, fixed } => {
+                let voltage_mv = (voltage * 1000.0).round() as u16;
+                self.i2c_sel.set_high().unwrap(); // Enable USB PD
+                let result = if fixed {
+                    self.ap33772s.request_fixed_voltage(&mut self.i2cdrv, voltage_mv)
+                }
+                else {
+                    self.ap33772s.request_custom_voltage(&mut self.i2cdrv, voltage_mv, current_ma)
+                };
+                self.i2c_sel.set_low().unwrap(); // Select INA228
+                let _ = self.events.send(ControlEvent::PdContract(result.ok().map(|_| voltage)));
+            },
+            ControlCommand::PdCurrent => {
+                self.i2c_sel.set_high().unwrap(); // Enable USB PD
+                let current = self.ap33772s.get_current_a(&mut self.i2cdrv).ok();
+                self.i2c_sel.set_low().unwrap(); // Select INA228
+                let _ = self.events.send(ControlEvent::PdCurrent(current));
+            },
+            ControlCommand::PdVoltage => {
+                self.i2c_sel.set_high().unwrap(); // Enable USB PD
+                let voltage = self.ap33772s.get_voltage_v(&mut self.i2cdrv).ok();
+                self.i2c_sel.set_low().unwrap(); // Select INA228
+                let _ = self.events.send(ControlEvent::PdVoltage(voltage));
+            },
+            ControlCommand::Calibrate => {
+                // The offsets are set by Offsets once they are accepted
+                let devices = self.channels.iter().map(|ch| if ch.hw.sense_addr.is_some() { 2 } else { 1 }).sum::<u32>();
+                let total = devices * ina228::CALIBRATION_SAMPLES;
+                let mut taken = 0;
+                let mut result = Ok(Vec::new());
+                for ch in self.channels.iter() {
+                    let events = &self.events;
+                    let mut progress = |n: u32| {
+                        let _ = events.send(ControlEvent::CalibrationProgress(taken + n, total));
+                    };
+                    let mut offsets = match ina228::calibration(&mut self.i2cdrv, ch.hw.ina228_addr, ch.hw.current_lsb, &mut progress) {
+                        Ok((current_offset, voltage_offset)) => ChannelOffsets { current_a: current_offset, voltage_v: voltage_offset, sense_v: None },
+                        // Keep the previous offsets; a bus error can be retried by the operator
+                        Err(e) => {
+                            result = Err(format!("{}", e));
+                            break;
+                        }
+                    };
+                    taken += ina228::CALIBRATION_SAMPLES;
+                    // Only the voltage offset of the sense INA228 is used
+                    if let Some(sense_addr) = ch.hw.sense_addr {
+                        let mut progress = |n: u32| {
+                            let _ = events.send(ControlEvent::CalibrationProgress(taken + n, total));
+                        };
+                        match ina228::calibration(&mut self.i2cdrv, sense_addr, ch.hw.current_lsb, &mut progress) {
+                            Ok((_, voltage_offset)) => offsets.sense_v = Some(voltage_offset),
+                            Err(e) => {
+                                result = Err(format!("{}", e));
+                                break;
+                            }
+                        }
+                        taken += ina228::CALIBRATION_SAMPLES;
+                    }
+                    if let Ok(list) = result.as_mut() {
+                        list.push(offsets);
+                    }
+                }
+                let _ = self.events.send(ControlEvent::Calibrated(result));
+            },
+            ControlCommand::Offsets(offsets) => {
+                for (ch, offsets) in self.channels.iter_mut().zip(offsets.iter()) {
+                    ch.current_offset = offsets.current_a;
+                    ch.voltage_offset = offsets.voltage_v;
+                    if let Some(sense_offset) = offsets.sense_v {
+                        ch.sense_offset = sense_offset;
+                    }
+                }
+            },
+            ControlCommand::Ripple(index) => {
+                let result = match self.channels.get(index) {
+                    Some(ch) => ina228::voltage_burst(&mut self.i2cdrv, ch.hw.ina228_addr, RIPPLE_BURST_SAMPLES)
+                        .map(|(readings, duration_us)| RippleReport::from_samples(&readings, duration_us))
+                        .map_err(|e| format!("{}", e)),
+                    None => Err(format!("no channel {}", index + 1)),
+                };
+                let _ = self.events.send(ControlEvent::Ripple(index, result));
+            },
+            ControlCommand::RawStream(raw) => {
+                self.raw = raw;
+            },
+            ControlCommand::LatencyBudget(budget_us) => {
+                self.latency.set_budget(budget_us);
+            },
+            ControlCommand::LatencyReset => {
+                self.latency.reset();
+            },
+            ControlCommand::Idle(idle) => {
+                self.idle = idle && !self.channels.iter().any(|ch| ch.output_on);
+            },
+            ControlCommand::I2cScan => {
+                self.i2c_sel.set_high().unwrap(); // Enable USB PD
+                let usb_pd = i2cbus::scan(&mut self.i2cdrv);
+                self.i2c_sel.set_low().unwrap(); // Select INA228
+                let ina228 = i2cbus::scan(&mut self.i2cdrv);
+                let _ = self.events.send(ControlEvent::I2cScan { ina228: ina228, usb_pd: usb_pd });
+            },
+        }
+    }
+}
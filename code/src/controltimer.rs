@@ -0,0 +1,90 @@
+// Control loop timer
+// The measurement and PID cycle is paced by a periodic esp_timer instead of thread::sleep,
+// so the rate does not drift with the loop's own run time. The timer callback only
+// notifies the main task, which does the I2C and PWM work. The time of the last tick is kept
+// for the latency of the control path.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use esp_idf_hal::task::notification::Notification;
+use esp_idf_svc::timer::{EspTaskTimerService, EspTimer};
+
+// Housekeeping (keys, display, logging, network) runs at this rate
+pub const HOUSEKEEPING_RATE_HZ: u32 = 100;
+pub const MIN_RATE_HZ: u32 = 100;
+pub const MAX_RATE_HZ: u32 = 2000;
+// Longest wait for a tick before the loop runs anyway (FreeRTOS ticks)
+const WAIT_TIMEOUT_TICKS: u32 = 100;
+
+// Ticks missed because a cycle took longer than the period
+static OVERRUN_COUNT: AtomicU32 = AtomicU32::new(0);
+
+pub fn overrun_count() -> u32 {
+    OVERRUN_COUNT.load(Ordering::Relaxed)
+}
+
+pub struct ControlTimer {
+    _timer: EspTimer<'static>,
+    notification: Notification,
+    ticks: Arc<AtomicU32>,
+    // Time of the last tick (us since boot, wrapping)
+    tick_us: Arc<AtomicU32>,
+    last_tick: u32,
+    rate_hz: u32,
+}
+
+impl ControlTimer {
+    // Start the timer. Must be called from the task that waits for the ticks.
+    pub fn start(rate_hz: u32) -> anyhow::Result<ControlTimer> {
+        if rate_hz < MIN_RATE_HZ || rate_hz > MAX_RATE_HZ || rate_hz % HOUSEKEEPING_RATE_HZ != 0 {
+            anyhow::bail!("control rate {}Hz must be a multiple of {}Hz from {} to {}Hz",
+                rate_hz, HOUSEKEEPING_RATE_HZ, MIN_RATE_HZ, MAX_RATE_HZ);
+        }
+        let notification = Notification::new();
+        let notifier = notification.notifier();
+        let ticks = Arc::new(AtomicU32::new(0));
+        let timer_ticks = ticks.clone();
+        let tick_us = Arc::new(AtomicU32::new(0));
+        let timer_tick_us = tick_us.clone();
+        let service = EspTaskTimerService::new()?;
+        let timer = service.timer(move || {
+            timer_tick_us.store(unsafe { esp_idf_sys::esp_timer_get_time() } as u32, Ordering::Relaxed);
+            timer_ticks.fetch_add(1, Ordering::Relaxed);
+            // The notification lives as long as the timer (both owned by ControlTimer)
+            unsafe { notifier.notify_and_yield(NonZeroU32::new(1).unwrap()); }
+        })?;
+        timer.every(Duration::from_micros(1_000_000 / rate_hz as u64))?;
+        Ok(ControlTimer { _timer: timer, notification: notification, ticks: ticks, tick_us: tick_us, last_tick: 0, rate_hz: rate_hz })
+    }
+
+    // Block until the next tick
+    pub fn wait(&mut self) {
+        let _ = self.notification.wait(WAIT_TIMEOUT_TICKS);
+        let tick = self.ticks.load(Ordering::Relaxed);
+        let elapsed = tick.wrapping_sub(self.last_tick);
+        if elapsed > 1 && self.last_tick != 0 {
+            OVERRUN_COUNT.fetch_add(elapsed - 1, Ordering::Relaxed);
+        }
+        self.last_tick = tick;
+    }
+
+    // Time of the tick the task was woken by (us since boot, wrapping)
+    pub fn tick_us(&self) -> u32 {
+        self.tick_us.load(Ordering::Relaxed)
+    }
+
+    pub fn rate_hz(&self) -> u32 {
+        self.rate_hz
+    }
+
+    // Control cycles per housekeeping cycle
+    pub fn decimation(&self) -> u32 {
+        self.rate_hz / HOUSEKEEPING_RATE_HZ
+    }
+}
@@ -0,0 +1,143 @@
+// Crash dump (core dump to flash) and reset reason
+// The core dump is written to the coredump partition by ESP-IDF on a panic.
+// It is kept until it is cleared, so it can be retrieved after a reboot in the field.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use log::*;
+use serde::Serialize;
+use base64::Engine;
+
+const READ_CHUNK_SIZE: usize = 1024;
+const BASE64_LINE_LEN: usize = 76;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CrashSummary {
+    pub size: usize,
+    pub task: String,
+    pub pc: u32,
+    pub backtrace: Vec<u32>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CrashInfo {
+    pub reset_reason: String,
+    pub coredump: Option<CrashSummary>,
+}
+
+pub fn reset_reason() -> &'static str {
+    match unsafe { esp_idf_sys::esp_reset_reason() } {
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_POWERON => "power-on",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_EXT => "external pin",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_SW => "software",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_INT_WDT => "interrupt watchdog",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_TASK_WDT => "task watchdog",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_WDT => "other watchdog",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deep sleep",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_SDIO => "sdio",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_USB => "usb",
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_JTAG => "jtag",
+        _ => "unknown",
+    }
+}
+
+// Reset by the interrupt, task or another watchdog (counted in the error counters)
+pub fn is_watchdog_reset() -> bool {
+    matches!(unsafe { esp_idf_sys::esp_reset_reason() },
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_INT_WDT
+        | esp_idf_sys::esp_reset_reason_t_ESP_RST_TASK_WDT
+        | esp_idf_sys::esp_reset_reason_t_ESP_RST_WDT)
+}
+
+// Location of the stored core dump image in flash
+fn image_location() -> Option<(usize, usize)> {
+    let mut addr : usize = 0;
+    let mut size : usize = 0;
+    let ret = unsafe { esp_idf_sys::esp_core_dump_image_get(&mut addr, &mut size) };
+    if ret == esp_idf_sys::ESP_OK && size > 0 {
+        Some((addr, size))
+    }
+    else {
+        None
+    }
+}
+
+pub fn summary() -> Option<CrashSummary> {
+    let (_, size) = image_location()?;
+    let mut summary : esp_idf_sys::esp_core_dump_summary_t = unsafe { std::mem::zeroed() };
+    let ret = unsafe { esp_idf_sys::esp_core_dump_get_summary(&mut summary) };
+    if ret != esp_idf_sys::ESP_OK {
+        // The image exists but cannot be parsed (e.g. corrupted); still report its size
+        return Some(CrashSummary { size: size, task: "".to_string(), pc: 0, backtrace: Vec::new() });
+    }
+    let task = unsafe { std::ffi::CStr::from_ptr(summary.exc_task.as_ptr()) }.to_string_lossy().to_string();
+    let depth = (summary.exc_bt_info.depth as usize).min(summary.exc_bt_info.bt.len());
+    Some(CrashSummary {
+        size: size,
+        task: task,
+        pc: summary.exc_pc,
+        backtrace: summary.exc_bt_info.bt[..depth].to_vec(),
+    })
+}
+
+pub fn info() -> CrashInfo {
+    CrashInfo { reset_reason: reset_reason().to_string(), coredump: summary() }
+}
+
+// Read the core dump image (ELF) in chunks
+pub fn read_image(mut f: impl FnMut(&[u8]) -> anyhow::Result<()>) -> anyhow::Result<bool> {
+    let (addr, size) = match image_location() {
+        Some(loc) => loc,
+        None => return Ok(false),
+    };
+    let mut buf = vec![0u8; READ_CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < size {
+        let len = READ_CHUNK_SIZE.min(size - offset);
+        esp_idf_sys::esp!(unsafe {
+            esp_idf_sys::esp_flash_read(std::ptr::null_mut(), buf.as_mut_ptr() as *mut _, (addr + offset) as u32, len as u32)
+        })?;
+        f(&buf[..len])?;
+        offset += len;
+    }
+    Ok(true)
+}
+
+// Print the image in base64 for `espcoredump.py info_corefile -t b64`
+pub fn print_base64() -> anyhow::Result<()> {
+    let mut image = Vec::new();
+    if !read_image(|chunk| { image.extend_from_slice(chunk); Ok(()) })? {
+        println!("no crash dump");
+        return Ok(());
+    }
+    let encoded = base64::engine::general_purpose::STANDARD.encode(&image);
+    println!("================= CORE DUMP START =================");
+    for line in encoded.as_bytes().chunks(BASE64_LINE_LEN) {
+        println!("{}", std::str::from_utf8(line).unwrap_or(""));
+    }
+    println!("================= CORE DUMP END =================");
+    Ok(())
+}
+
+pub fn clear() -> anyhow::Result<()> {
+    esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_core_dump_image_erase() })?;
+    info!("Crash dump cleared");
+    Ok(())
+}
+
+// Report the reset reason and a pending crash dump at boot
+pub fn report_at_boot() {
+    let reason = reset_reason();
+    match summary() {
+        Some(s) => {
+            warn!("Reset reason: {}, crash dump stored ({} bytes, task {}, PC 0x{:08x})", reason, s.size, s.task, s.pc);
+        },
+        None => {
+            info!("Reset reason: {}", reason);
+        }
+    }
+}
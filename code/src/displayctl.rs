@@ -1,25 +1,72 @@
 // Display control module for SSD1331 OLED display.
+// The live values of the main screen (measurement, setpoint, limit, temperature, logging and
+// WiFi state) are set in the main loop and published once per iteration through a lock-free
+// snapshot (sequence lock) read by the display thread before each frame. The other changes
+// (messages, menus, modes) are sent as updates over a channel.
+// The frames are drawn into a PanelFrame and only the regions changed since the last frame
+// are sent to the panel (see dcpower_control::framediff), at display_refresh_hz.
 // SPDX-License-Identifier: MIT
 // Copyright (c) 2024 Hiroshi Nakajima
 
 #![allow(dead_code)]
 
 use log::*;
-use std::{thread, time::Duration, sync::Arc, sync::Mutex, time::SystemTime};
+use std::{thread, time::Duration, time::Instant, time::SystemTime};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
 use esp_idf_hal::{gpio::*, spi, delay::FreeRtos};
 use ssd1331::{DisplayRotation, Ssd1331};
 use embedded_graphics::{
     mono_font::{ascii::FONT_10X20, ascii::FONT_5X8, ascii::FONT_6X12, MonoTextStyle},
     image::Image,
-    pixelcolor::{Rgb565},
+    pixelcolor::{IntoStorage, Rgb565},
     text::{Text},
     geometry::{Point, Size},
     primitives::{
-        Circle, Line, Triangle, Rectangle, PrimitiveStyle,
+        Circle, Line, Polyline, Triangle, Rectangle, PrimitiveStyle,
     },
     prelude::*,
 };
 use tinybmp::Bmp;
+use esp_idf_sys::EspError;
+use dcpower_control::units::UnitFormat;
+use dcpower_control::framediff::{changed_regions, Region};
+use dcpower_control::currentlogs::{sparkline, TREND_POINTS};
+
+const PANEL_WIDTH: usize = 96;
+const PANEL_HEIGHT: usize = 64;
+// A whole frame is one DMA transfer. The pixel data buffer is below
+// CONFIG_SPIRAM_MALLOC_ALWAYSINTERNAL, so it is allocated in the DMA-capable internal RAM.
+pub const FRAME_BYTES: usize = PANEL_WIDTH * PANEL_HEIGHT * 2;
+// SSD1331 commands of the window the pixel data is written to
+const CMD_COLUMN_ADDRESS: u8 = 0x15;
+const CMD_ROW_ADDRESS: u8 = 0x75;
+// Frames per second until set_refresh_rate
+const DEFAULT_REFRESH_HZ: u32 = 10;
+// Logging mark shown 200ms of every 600ms
+const MARK_PERIOD_MS: u128 = 600;
+const MARK_ON_MS: u128 = 200;
+// A readout near its limit flashes: shown 250ms of every 500ms
+const FLASH_PERIOD_MS: u128 = 500;
+const FLASH_ON_MS: u128 = 250;
+// Each of the values next to the setpoint is shown for this long
+const ROTATE_MS: u128 = 500;
+// Sparklines in place of the setpoint row, after the logging mark and before the WiFi icon
+const TREND_VOLTAGE_X: i32 = 10;
+const TREND_CURRENT_X: i32 = 48;
+const TREND_TOP: i32 = 51;
+const TREND_HEIGHT: u32 = 10;
+// Smallest range of a sparkline (V, A)
+const TREND_MIN_SPAN_V: f32 = 0.05;
+const TREND_MIN_SPAN_A: f32 = 0.01;
+// The setpoint row is shown for this long after the setpoint or the limit changed
+const SETPOINT_SHOW: Duration = Duration::from_secs(3);
+// Identification: the panel flashes for this long by default, up to the maximum (s)
+pub const IDENTIFY_SECS: u32 = 10;
+pub const MAX_IDENTIFY_SECS: u32 = 300;
+// Half period of the flash
+const IDENTIFY_FLASH_MS: u128 = 250;
 
 pub enum LoggingStatus {
     Start,
@@ -31,6 +78,11 @@ pub enum WifiStatus {
     Disconnected,
 }
 
+pub enum InterlockStatus {
+    Closed,
+    Open,
+}
+
 type SPI<'d> = esp_idf_hal::spi::SpiDeviceDriver<'static, spi::SpiDriver<'static>>;
 type DC<'d> = esp_idf_hal::gpio::PinDriver<'static, Gpio15, esp_idf_hal::gpio::Output>;
 type RST<'d> = esp_idf_hal::gpio::PinDriver<'static, Gpio16, esp_idf_hal::gpio::Output>;
@@ -45,64 +97,385 @@ struct DisplayText {
     message_enable: bool,
     message_timer: SystemTime,
     message_timeout: u32,
+    menu_enable: bool,
+    menu_title: String,
+    menu_item: String,
+    menu_value: String,
     battery: f32,
     status: LoggingStatus,
     wifi: WifiStatus,
+    interlock: InterlockStatus,
     buffer_water_mark: u32,
     load_current: f32,
     output_voltage: f32,
     temperature: f32,
     pwm_duty: u32,
     usb_pd_voltage: f32,
+    // Channel shown with two output channels
+    channel: Option<u8>,
+    // Session current limit, and whether the keys adjust it
+    current_limit: f32,
+    current_limit_selected: bool,
+    power_limit: f32,
+    power_limit_selected: bool,
+    // Endurance test cycle and the number of cycles (0 until stopped)
+    cycle: Option<(u32, u32)>,
+    // Unit scaling and resolution of the values
+    format: UnitFormat,
+    // Blanked in the low-power idle
+    sleep: bool,
+    refresh_hz: u32,
+    // The current or power near its limit (pre-warning)
+    current_warning: bool,
+    power_warning: bool,
+    // Voltages and currents of the sparklines (oldest first), None to show the setpoint row
+    trend: Option<(Vec<f32>, Vec<f32>)>,
+    setpoint_shown_until: Option<Instant>,
+    // Color bars of the production test in place of everything else
+    test_pattern: bool,
+    // Flashing identification screen with its text (the IP address) until the time
+    identify: Option<(Instant, String)>,
+}
+
+// Frame drawn by the display thread. The panel is set up (and rotated) by the ssd1331
+// driver; flush sends the regions which differ from the frame on the panel.
+struct PanelFrame {
+    spi: SPI<'static>,
+    dc: DC<'static>,
+    frame: Vec<u16>,
+    // On the panel, not known before the first flush
+    shown: Vec<u16>,
+    shown_valid: bool,
+    data: Vec<u8>,
+}
+
+impl PanelFrame {
+    fn new(spi: SPI<'static>, dc: DC<'static>) -> PanelFrame {
+        PanelFrame {
+            spi: spi,
+            dc: dc,
+            frame: vec![0; PANEL_WIDTH * PANEL_HEIGHT],
+            shown: vec![0; PANEL_WIDTH * PANEL_HEIGHT],
+            shown_valid: false,
+            data: Vec::with_capacity(FRAME_BYTES),
+        }
+    }
+
+    fn clear(&mut self) {
+        self.frame.fill(0);
+    }
+
+    fn flush(&mut self) -> Result<(), EspError> {
+        let regions = if self.shown_valid {
+            changed_regions(&self.shown, &self.frame, PANEL_WIDTH, PANEL_HEIGHT)
+        }
+        else {
+            vec![Region { x: 0, y: 0, width: PANEL_WIDTH, height: PANEL_HEIGHT }]
+        };
+        for region in regions {
+            self.send(region)?;
+        }
+        self.shown.copy_from_slice(&self.frame);
+        self.shown_valid = true;
+        crate::perfcounters::display_frame();
+        Ok(())
+    }
+
+    // Set the window of the region and write its pixels (RGB565, big endian)
+    fn send(&mut self, region: Region) -> Result<(), EspError> {
+        self.dc.set_low()?;
+        self.spi.write(&[
+            CMD_COLUMN_ADDRESS, region.x as u8, (region.x + region.width - 1) as u8,
+            CMD_ROW_ADDRESS, region.y as u8, (region.y + region.height - 1) as u8,
+        ])?;
+        self.data.clear();
+        for row in region.y..region.y + region.height {
+            let start = row * PANEL_WIDTH + region.x;
+            for pixel in &self.frame[start..start + region.width] {
+                self.data.extend_from_slice(&pixel.to_be_bytes());
+            }
+        }
+        self.dc.set_high()?;
+        self.spi.write(&self.data)
+    }
+}
+
+impl DrawTarget for PanelFrame {
+    type Color = Rgb565;
+    type Error = core::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            if (0..PANEL_WIDTH as i32).contains(&point.x) && (0..PANEL_HEIGHT as i32).contains(&point.y) {
+                self.frame[point.y as usize * PANEL_WIDTH + point.x as usize] = color.into_storage();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl OriginDimensions for PanelFrame {
+    fn size(&self) -> Size {
+        Size::new(PANEL_WIDTH as u32, PANEL_HEIGHT as u32)
+    }
+}
+
+// Values of the main screen which change on every main loop iteration
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct LiveValues {
+    voltage: f32,
+    current: f32,
+    power: f32,
+    output_voltage: f32,
+    current_limit: f32,
+    current_limit_selected: bool,
+    power_limit: f32,
+    power_limit_selected: bool,
+    pwm_duty: u32,
+    temperature: f32,
+    usb_pd_voltage: f32,
+    buffer_water_mark: u32,
+    logging: bool,
+    wifi: bool,
+    current_warning: bool,
+    power_warning: bool,
+}
+
+const LIVE_LIMIT_SELECTED: u32 = 0x01;
+const LIVE_LOGGING: u32 = 0x02;
+const LIVE_WIFI: u32 = 0x04;
+const LIVE_CURRENT_WARNING: u32 = 0x08;
+const LIVE_POWER_WARNING: u32 = 0x10;
+const LIVE_POWER_LIMIT_SELECTED: u32 = 0x20;
+
+// Live values shared without a lock (sequence lock). The main loop is the only writer; the
+// display thread retries if they were being written.
+#[derive(Default)]
+struct LiveSnapshot {
+    sequence: AtomicU32,
+    voltage: AtomicU32,
+    current: AtomicU32,
+    power: AtomicU32,
+    output_voltage: AtomicU32,
+    current_limit: AtomicU32,
+    power_limit: AtomicU32,
+    pwm_duty: AtomicU32,
+    temperature: AtomicU32,
+    usb_pd_voltage: AtomicU32,
+    buffer_water_mark: AtomicU32,
+    flags: AtomicU32,
+}
+
+impl LiveSnapshot {
+    fn publish(&self, live: &LiveValues) {
+        let seq = self.sequence.load(Ordering::Relaxed);
+        self.sequence.store(seq.wrapping_add(1), Ordering::Release);
+        self.voltage.store(live.voltage.to_bits(), Ordering::Release);
+        self.current.store(live.current.to_bits(), Ordering::Release);
+        self.power.store(live.power.to_bits(), Ordering::Release);
+        self.output_voltage.store(live.output_voltage.to_bits(), Ordering::Release);
+        self.current_limit.store(live.current_limit.to_bits(), Ordering::Release);
+        self.power_limit.store(live.power_limit.to_bits(), Ordering::Release);
+        self.pwm_duty.store(live.pwm_duty, Ordering::Release);
+        self.temperature.store(live.temperature.to_bits(), Ordering::Release);
+        self.usb_pd_voltage.store(live.usb_pd_voltage.to_bits(), Ordering::Release);
+        self.buffer_water_mark.store(live.buffer_water_mark, Ordering::Release);
+        let flags = if live.current_limit_selected { LIVE_LIMIT_SELECTED } else { 0 }
+            | if live.logging { LIVE_LOGGING } else { 0 }
+            | if live.wifi { LIVE_WIFI } else { 0 }
+            | if live.current_warning { LIVE_CURRENT_WARNING } else { 0 }
+            | if live.power_warning { LIVE_POWER_WARNING } else { 0 }
+            | if live.power_limit_selected { LIVE_POWER_LIMIT_SELECTED } else { 0 };
+        self.flags.store(flags, Ordering::Release);
+        self.sequence.store(seq.wrapping_add(2), Ordering::Release);
+    }
+
+    fn load(&self) -> LiveValues {
+        loop {
+            let before = self.sequence.load(Ordering::Acquire);
+            if before % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let flags = self.flags.load(Ordering::Acquire);
+            let live = LiveValues {
+                voltage: f32::from_bits(self.voltage.load(Ordering::Acquire)),
+                current: f32::from_bits(self.current.load(Ordering::Acquire)),
+                power: f32::from_bits(self.power.load(Ordering::Acquire)),
+                output_voltage: f32::from_bits(self.output_voltage.load(Ordering::Acquire)),
+                current_limit: f32::from_bits(self.current_limit.load(Ordering::Acquire)),
+                current_limit_selected: flags & LIVE_LIMIT_SELECTED != 0,
+                power_limit: f32::from_bits(self.power_limit.load(Ordering::Acquire)),
+                power_limit_selected: flags & LIVE_POWER_LIMIT_SELECTED != 0,
+                pwm_duty: self.pwm_duty.load(Ordering::Acquire),
+                temperature: f32::from_bits(self.temperature.load(Ordering::Acquire)),
+                usb_pd_voltage: f32::from_bits(self.usb_pd_voltage.load(Ordering::Acquire)),
+                buffer_water_mark: self.buffer_water_mark.load(Ordering::Acquire),
+                logging: flags & LIVE_LOGGING != 0,
+                wifi: flags & LIVE_WIFI != 0,
+                current_warning: flags & LIVE_CURRENT_WARNING != 0,
+                power_warning: flags & LIVE_POWER_WARNING != 0,
+            };
+            if self.sequence.load(Ordering::Acquire) == before {
+                return live;
+            }
+        }
+    }
+}
+
+// Updates sent to the display thread, applied before each frame
+enum DisplayUpdate {
+    Enable(bool),
+    Interval(u32),
+    Interlock(InterlockStatus),
+    Message(String, bool, u32, SystemTime),
+    Menu(bool, String, String, String),
+    Battery(f32),
+    LoadCurrent(f32),
+    Channel(Option<u8>),
+    Cycle(Option<(u32, u32)>),
+    Format(UnitFormat),
+    Sleep(bool),
+    RefreshRate(u32),
+    Trend(Option<(Vec<f32>, Vec<f32>)>),
+    TestPattern(bool),
+    Identify(Option<(Instant, String)>),
+}
+
+impl DisplayText {
+    fn apply(&mut self, update: DisplayUpdate) {
+        match update {
+            DisplayUpdate::Enable(enable) => self.display_enable = enable,
+            DisplayUpdate::Interval(interval) => self.interval = interval,
+            DisplayUpdate::Interlock(status) => self.interlock = status,
+            DisplayUpdate::Message(msg, enable, timeout, time) => {
+                self.message = msg;
+                self.message_enable = enable;
+                self.message_timeout = timeout;
+                self.message_timer = time;
+            },
+            DisplayUpdate::Menu(enable, title, item, value) => {
+                self.menu_enable = enable;
+                self.menu_title = title;
+                self.menu_item = item;
+                self.menu_value = value;
+            },
+            DisplayUpdate::Battery(bat) => self.battery = bat,
+            DisplayUpdate::LoadCurrent(load_current) => self.load_current = load_current,
+            DisplayUpdate::Channel(channel) => self.channel = channel,
+            DisplayUpdate::Cycle(cycle) => self.cycle = cycle,
+            DisplayUpdate::Format(format) => self.format = format,
+            DisplayUpdate::Sleep(sleep) => self.sleep = sleep,
+            DisplayUpdate::RefreshRate(hz) => self.refresh_hz = hz,
+            DisplayUpdate::Trend(trend) => self.trend = trend,
+            DisplayUpdate::TestPattern(enable) => self.test_pattern = enable,
+            DisplayUpdate::Identify(identify) => self.identify = identify,
+        }
+    }
+
+    fn apply_live(&mut self, live: &LiveValues) {
+        if live.output_voltage != self.output_voltage || live.current_limit != self.current_limit || live.power_limit != self.power_limit {
+            self.setpoint_shown_until = Some(Instant::now() + SETPOINT_SHOW);
+        }
+        self.voltage = live.voltage;
+        self.current = live.current;
+        self.power = live.power;
+        self.output_voltage = live.output_voltage;
+        self.current_limit = live.current_limit;
+        self.current_limit_selected = live.current_limit_selected;
+        self.power_limit = live.power_limit;
+        self.power_limit_selected = live.power_limit_selected;
+        self.pwm_duty = live.pwm_duty;
+        self.temperature = live.temperature;
+        self.usb_pd_voltage = live.usb_pd_voltage;
+        self.buffer_water_mark = live.buffer_water_mark;
+        self.status = if live.logging { LoggingStatus::Start } else { LoggingStatus::Stop };
+        self.wifi = if live.wifi { WifiStatus::Connected } else { WifiStatus::Disconnected };
+        self.current_warning = live.current_warning;
+        self.power_warning = live.power_warning;
+    }
 }
 
 pub struct DisplayPanel {
-    txt: Arc<Mutex<DisplayText>>
+    tx: Sender<DisplayUpdate>,
+    rx: Option<Receiver<DisplayUpdate>>,
+    // Live values set in this iteration of the main loop, and where they are published
+    live: LiveValues,
+    snapshot: Arc<LiveSnapshot>,
 }
 
 impl DisplayPanel {
 
     pub fn new() -> DisplayPanel {
-        DisplayPanel { txt: Arc::new(Mutex::new(
-            DisplayText {display_enable: false,
+        let (tx, rx) = channel();
+        DisplayPanel { tx: tx, rx: Some(rx), live: LiveValues::default(), snapshot: Arc::new(LiveSnapshot::default()) }
+    }
+
+    pub fn start(&mut self,
+        spi : SPI, dc: DC, mut rst : RST)
+    {
+        let updates = self.rx.take().expect("DisplayPanel already started");
+        let snapshot = self.snapshot.clone();
+        let _th = thread::spawn(move || {
+            info!("Start Display Thread.");
+            crate::health::register_task("display");
+            // The text state is owned by this thread
+            let mut txt = DisplayText {display_enable: false,
                          voltage: 0.0,
                          message: "".to_string(),
                          message_enable: false,
                          message_timer: SystemTime::now(),
                          message_timeout: 0,
+                         menu_enable: false,
+                         menu_title: "".to_string(),
+                         menu_item: "".to_string(),
+                         menu_value: "".to_string(),
                          current: 0.0,
                          power: 0.0,
                          interval: 0,
                          battery: 0.0,
                          status: LoggingStatus::Stop,
                          wifi: WifiStatus::Disconnected,
+                         interlock: InterlockStatus::Closed,
                          buffer_water_mark: 0,
                          load_current: 0.0,
                          output_voltage: 0.0,
                          temperature: 0.0,
                          pwm_duty: 0,
                          usb_pd_voltage: 0.0,
-                     })) }
-    }
-
-    pub fn start(&mut self,
-        spi : SPI, dc: DC, mut rst : RST)
-    {
-        let txt = self.txt.clone();
-        let _th = thread::spawn(move || {
-            info!("Start Display Thread.");
+                         channel: None,
+                         current_limit: 0.0,
+                         current_limit_selected: false,
+                         power_limit: 0.0,
+                         power_limit_selected: false,
+                         cycle: None,
+                         format: UnitFormat::default(),
+                         sleep: false,
+                         refresh_hz: DEFAULT_REFRESH_HZ,
+                         current_warning: false,
+                         power_warning: false,
+                         trend: None,
+                         setpoint_shown_until: None,
+                         test_pattern: false,
+                         identify: None,
+                     };
             let mut delay = FreeRtos;
-            let mut display = Ssd1331::new(spi, dc, DisplayRotation::Rotate180);
-            let _ = display.reset(&mut rst, &mut delay);
-            let _ = display.init();
-            display.clear();
-            let _style = MonoTextStyle::new(&FONT_10X20, Rgb565::WHITE);
+            let mut driver = Ssd1331::new(spi, dc, DisplayRotation::Rotate180);
+            let _ = driver.reset(&mut rst, &mut delay);
+            let _ = driver.init();
+            let (spi, dc) = driver.release();
+            let mut display = PanelFrame::new(spi, dc);
+            let large_style_white = MonoTextStyle::new(&FONT_10X20, Rgb565::WHITE);
             let middle_style_white = MonoTextStyle::new(&FONT_6X12, Rgb565::WHITE);
             let middle_style_red = MonoTextStyle::new(&FONT_6X12, Rgb565::RED);
             let middle_style_yellow = MonoTextStyle::new(&FONT_6X12, Rgb565::YELLOW);
             let middle_style_blue = MonoTextStyle::new(&FONT_6X12, Rgb565::BLUE);
             let red_bg = PrimitiveStyle::with_fill(Rgb565::RED);
             let _small_style_white = MonoTextStyle::new(&FONT_5X8, Rgb565::WHITE);
+            let small_style_red = MonoTextStyle::new(&FONT_5X8, Rgb565::RED);
             let wifibmp = Bmp::from_slice(include_bytes!("./img/wifirev.bmp")).unwrap();
             let wifi_img: Image<Bmp<Rgb565>> = Image::new(&wifibmp, Point::new(86, 47));
             let fill = PrimitiveStyle::with_fill(Rgb565::YELLOW);
@@ -141,26 +514,69 @@ impl DisplayPanel {
             let minus_img: Image<Bmp<Rgb565>> = Image::new(&minus, Point::zero());
             let mut digit_img = n0_img.translate(Point::new(0,0));
 
-            let mut loopcount = 0;
-            let mut mark_count = 0;
+            let started = Instant::now();
+            let mut blanked = false;
             loop {
-                thread::sleep(Duration::from_millis(100));
-                let mut lck = txt.lock().unwrap();
+                thread::sleep(Duration::from_millis(1000 / txt.refresh_hz.max(1) as u64));
+                for update in updates.try_iter() {
+                    txt.apply(update);
+                }
+                txt.apply_live(&snapshot.load());
                 display.clear();
-                if lck.message_enable {
-                    if lck.message_timeout > 0 && lck.message_timer.elapsed().unwrap().as_secs() > lck.message_timeout as u64 {
-                        lck.message_enable = false;
+                if txt.sleep {
+                    // Black pixels of the OLED draw no current, the frame is sent once
+                    if !blanked {
+                        display.flush().unwrap();
+                        blanked = true;
+                    }
+                    continue;
+                }
+                blanked = false;
+                if txt.test_pattern {
+                    draw_test_pattern(&mut display);
+                    display.flush().unwrap();
+                    continue;
+                }
+                if let Some((until, text)) = &txt.identify {
+                    if Instant::now() < *until {
+                        // White and black in turn, seen across the rack
+                        let on = started.elapsed().as_millis() / IDENTIFY_FLASH_MS % 2 == 0;
+                        let style = if on {
+                            Rectangle::new(Point::zero(), Size::new(PANEL_WIDTH as u32, PANEL_HEIGHT as u32))
+                                .into_styled(PrimitiveStyle::with_fill(Rgb565::WHITE))
+                                .draw(&mut display).unwrap();
+                            MonoTextStyle::new(&FONT_6X12, Rgb565::BLACK)
+                        }
+                        else {
+                            middle_style_white
+                        };
+                        Text::new("Identify", Point::new(1, 20), style).draw(&mut display).unwrap();
+                        Text::new(text, Point::new(1, 40), style).draw(&mut display).unwrap();
+                        display.flush().unwrap();
+                        continue;
+                    }
+                    txt.identify = None;
+                }
+                if txt.message_enable {
+                    if txt.message_timeout > 0 && txt.message_timer.elapsed().unwrap().as_secs() > txt.message_timeout as u64 {
+                        txt.message_enable = false;
                     }
                     else {
-                        Text::new(&format!("{}", lck.message), Point::new(1, 20), middle_style_red).draw(&mut display).unwrap();
+                        Text::new(&format!("{}", txt.message), Point::new(1, 20), middle_style_red).draw(&mut display).unwrap();
                         display.flush().unwrap();
                     }
-                    drop(lck);
                     continue;
                 }
-                if lck.display_enable {
-                    // let mut disp_val = lck.current;
-                    let mut disp_val = lck.voltage;
+                if txt.menu_enable {
+                    Text::new(&txt.menu_title, Point::new(1, 10), middle_style_yellow).draw(&mut display).unwrap();
+                    Text::new(&txt.menu_item, Point::new(1, 28), middle_style_white).draw(&mut display).unwrap();
+                    Text::new(&txt.menu_value, Point::new(1, 54), large_style_white).draw(&mut display).unwrap();
+                    display.flush().unwrap();
+                    continue;
+                }
+                if txt.display_enable {
+                    // let mut disp_val = txt.current;
+                    let mut disp_val = txt.voltage;
                     dot_img.draw(&mut display).unwrap();                
                     vv_img.draw(&mut display).unwrap();
                     // amp_img.draw(&mut display).unwrap();
@@ -244,60 +660,68 @@ impl DisplayPanel {
                 else {
                     logo_img.draw(&mut display).unwrap();
                     display.flush().unwrap();
-                    drop(lck);
                     continue;
                 }
 
-                match lck.status {
+                match txt.status {
                     LoggingStatus::Start => {
-                        mark_count += 1;
-                        match mark_count {
-                            0..=2 => {
-                                Circle::new(Point::new(1, 53), 8)
-                                    .into_styled(fill)
-                                    .draw(&mut display).unwrap();
-                            }, 
-                            _ => {},
-                        }
-                        if mark_count == 6 {
-                            mark_count = 0;
+                        if started.elapsed().as_millis() % MARK_PERIOD_MS < MARK_ON_MS {
+                            Circle::new(Point::new(1, 53), 8)
+                                .into_styled(fill)
+                                .draw(&mut display).unwrap();
                         }
                     },
                     LoggingStatus::Stop => {
                     },
                 }
+                // Channel shown
+                if let Some(ch) = txt.channel {
+                    Text::new(&format!("CH{}", ch), Point::new(78, 38), middle_style_yellow).draw(&mut display).unwrap();
+                }
+                // Endurance test progress
+                if let Some((cycle, cycles)) = txt.cycle {
+                    let text = if cycles > 0 { format!("C{}/{}", cycle, cycles) } else { format!("C{}", cycle) };
+                    Text::new(&text, Point::new(1, 38), middle_style_yellow).draw(&mut display).unwrap();
+                }
                 let cur_pos = 50;
+                let flash_off = started.elapsed().as_millis() % FLASH_PERIOD_MS >= FLASH_ON_MS;
                 // Current
-                if lck.current < 0.5 {
-                    Text::new(&format!("{:.0}mA", lck.current * 1000.0), Point::new(10, cur_pos), middle_style_white).draw(&mut display).unwrap();
+                if txt.current_warning && flash_off {
+                    // Flashing, not drawn in this half of the period
                 }
-                else if lck.current >= 0.5 && lck.current < 1.0 {
-                    Text::new(&format!("{:.0}mA", lck.current * 1000.0), Point::new(10, cur_pos), middle_style_yellow).draw(&mut display).unwrap();
+                else if txt.current < 0.5 {
+                    Text::new(&txt.format.current(txt.current), Point::new(10, cur_pos), middle_style_white).draw(&mut display).unwrap();
                 }
-                else if lck.current >= 1.0 {
-                    Text::new(&format!("{:.2}A", lck.current), Point::new(10, cur_pos), middle_style_red).draw(&mut display).unwrap();
+                else if txt.current >= 0.5 && txt.current < 1.0 {
+                    Text::new(&txt.format.current(txt.current), Point::new(10, cur_pos), middle_style_yellow).draw(&mut display).unwrap();
+                }
+                else if txt.current >= 1.0 {
+                    Text::new(&txt.format.current(txt.current), Point::new(10, cur_pos), middle_style_red).draw(&mut display).unwrap();
                 }
 
                 // Power
-                if lck.power < 1.0 {
-                    Text::new(&format!("{:.0}mW", lck.power * 1000.0), Point::new(54, cur_pos), middle_style_white).draw(&mut display).unwrap();
+                if txt.power_warning && flash_off {
+                    // Flashing, not drawn in this half of the period
+                }
+                else if txt.power < 1.0 {
+                    Text::new(&txt.format.power(txt.power), Point::new(54, cur_pos), middle_style_white).draw(&mut display).unwrap();
                 }
-                else if lck.power >= 10.0 && lck.power < 50.0 {
-                    Text::new(&format!("{:.1}W", lck.power), Point::new(54, cur_pos), middle_style_yellow).draw(&mut display).unwrap();
+                else if txt.power >= 10.0 && txt.power < 50.0 {
+                    Text::new(&txt.format.power(txt.power), Point::new(54, cur_pos), middle_style_yellow).draw(&mut display).unwrap();
                 }
-                else if lck.power >= 50.0 {
-                    Text::new(&format!("{:.1}W", lck.power), Point::new(54, cur_pos), middle_style_red).draw(&mut display).unwrap();
+                else if txt.power >= 50.0 {
+                    Text::new(&txt.format.power(txt.power), Point::new(54, cur_pos), middle_style_red).draw(&mut display).unwrap();
                 }
                 else {
-                    Text::new(&format!("{:.2}W", lck.power), Point::new(54, cur_pos), middle_style_white).draw(&mut display).unwrap();
+                    Text::new(&txt.format.power(txt.power), Point::new(54, cur_pos), middle_style_white).draw(&mut display).unwrap();
                 }
 
                 // Water mark of buffer
-                let bar_len = (lck.buffer_water_mark * 95 / 100) as i32;
+                let bar_len = (txt.buffer_water_mark * 95 / 100) as i32;
                 Line::new(Point::new(0,63), Point::new(bar_len, 63)).into_styled(PrimitiveStyle::with_stroke(Rgb565::RED, 1)).draw(&mut display).unwrap();
                 Triangle::new(Point::new(bar_len-2,61), Point::new(bar_len,63), Point::new(bar_len-2,63)).into_styled(PrimitiveStyle::with_stroke(Rgb565::RED, 1)).draw(&mut display).unwrap();
 
-                match lck.wifi {
+                match txt.wifi {
                     WifiStatus::Disconnected => {
                     },
                     WifiStatus::Connected => {
@@ -305,128 +729,248 @@ impl DisplayPanel {
                     },
                 }
 
-                // Output voltage
-                if lck.output_voltage < 10.0 {
-                    Text::new(&format!("{:.2}V", lck.output_voltage), Point::new(10, 60), middle_style_blue).draw(&mut display).unwrap();
-                }
-                else if lck.output_voltage >= 10.0 && lck.output_voltage < 15.0 {
-                    Text::new(&format!("{:.2}V", lck.output_voltage), Point::new(10, 60), middle_style_yellow).draw(&mut display).unwrap();
+                match txt.interlock {
+                    InterlockStatus::Closed => {
+                    },
+                    InterlockStatus::Open => {
+                        Text::new("IL", Point::new(85, 46), small_style_red).draw(&mut display).unwrap();
+                    },
                 }
-                else if lck.output_voltage >= 15.0 {
-                    Text::new(&format!("{:.2}V", lck.output_voltage), Point::new(10, 60), middle_style_red).draw(&mut display).unwrap();
+
+                // Under the readouts: the trends of the last minute, or the setpoint while it is
+                // adjusted (and for a while after it changed)
+                let setpoint_shown = txt.current_limit_selected || txt.power_limit_selected || txt.setpoint_shown_until.map_or(false, |t| Instant::now() < t);
+                if let (false, Some((voltages, currents))) = (setpoint_shown, txt.trend.as_ref()) {
+                    draw_sparkline(&mut display, voltages, TREND_VOLTAGE_X, TREND_MIN_SPAN_V, Rgb565::CYAN);
+                    draw_sparkline(&mut display, currents, TREND_CURRENT_X, TREND_MIN_SPAN_A, Rgb565::YELLOW);
                 }
+                else {
+                    // Output voltage
+                    if txt.output_voltage < 10.0 {
+                        Text::new(&txt.format.voltage(txt.output_voltage), Point::new(10, 60), middle_style_blue).draw(&mut display).unwrap();
+                    }
+                    else if txt.output_voltage >= 10.0 && txt.output_voltage < 15.0 {
+                        Text::new(&txt.format.voltage(txt.output_voltage), Point::new(10, 60), middle_style_yellow).draw(&mut display).unwrap();
+                    }
+                    else if txt.output_voltage >= 15.0 {
+                        Text::new(&txt.format.voltage(txt.output_voltage), Point::new(10, 60), middle_style_red).draw(&mut display).unwrap();
+                    }
 
-                match loopcount {
-                    0..=5 => {
-                        // Temperature
-                        if lck.temperature < 50.0 {
-                            Text::new(&format!("{:.0}C", lck.temperature), Point::new(54, 60), middle_style_white).draw(&mut display).unwrap();
-                        } else if lck.temperature < 60.0 {
-                            Text::new(&format!("{:.0}C", lck.temperature), Point::new(54, 60), middle_style_yellow).draw(&mut display).unwrap();
-                        } else {
-                            // Background rectangle for temperatures over 60C
-                            Rectangle::new(Point::new(54, 52), Size::new(30, 12))
-                                .into_styled(red_bg)
-                                .draw(&mut display).unwrap();
-                            Text::new(&format!("{:.0}C", lck.temperature), Point::new(54, 60), middle_style_white).draw(&mut display).unwrap();
+                    // Next to the setpoint: the current or power limit, shown all the time while it is adjusted
+                    if txt.current_limit_selected {
+                        Text::new(&txt.format.current(txt.current_limit), Point::new(54, 60), middle_style_yellow).draw(&mut display).unwrap();
+                    }
+                    else if txt.power_limit_selected {
+                        Text::new(&txt.format.power(txt.power_limit), Point::new(54, 60), middle_style_yellow).draw(&mut display).unwrap();
+                    }
+                    else {
+                        match (started.elapsed().as_millis() / ROTATE_MS) % 4 {
+                            0 => {
+                                // Current limit
+                                Text::new(&txt.format.current(txt.current_limit), Point::new(54, 60), middle_style_blue).draw(&mut display).unwrap();
+                            },
+                            1 => {
+                                // Temperature
+                                if txt.temperature < 50.0 {
+                                    Text::new(&format!("{:.0}C", txt.temperature), Point::new(54, 60), middle_style_white).draw(&mut display).unwrap();
+                                } else if txt.temperature < 60.0 {
+                                    Text::new(&format!("{:.0}C", txt.temperature), Point::new(54, 60), middle_style_yellow).draw(&mut display).unwrap();
+                                } else {
+                                    // Background rectangle for temperatures over 60C
+                                    Rectangle::new(Point::new(54, 52), Size::new(30, 12))
+                                        .into_styled(red_bg)
+                                        .draw(&mut display).unwrap();
+                                    Text::new(&format!("{:.0}C", txt.temperature), Point::new(54, 60), middle_style_white).draw(&mut display).unwrap();
+                                }
+                            },
+                            2 => {
+                                // USB PD Voltage
+                                Text::new(&txt.format.voltage(txt.usb_pd_voltage), Point::new(54, 60), middle_style_white).draw(&mut display).unwrap();
+                            },
+                            _ => {
+                                // PWM Duty
+                                Text::new(&format!("{}", txt.pwm_duty), Point::new(54, 60), middle_style_white).draw(&mut display).unwrap();
+                            },
                         }
-                    },
-                    6..=10 => {
-                        // USB PD Voltage
-                        Text::new(&format!("{:.1}V", lck.usb_pd_voltage), Point::new(54, 60), middle_style_white).draw(&mut display).unwrap();
-                    },
-                    _ => {
-                        // PWM Duty
-                        Text::new(&format!("{}", lck.pwm_duty), Point::new(54, 60), middle_style_white).draw(&mut display).unwrap();
-                    },
-                }
- 
-                loopcount += 1;
-                if loopcount == 15 {
-                    loopcount = 0;
+                    }
                 }
                 display.flush().unwrap();
-                drop(lck);
             }
         });
     }
 
+    // Sends are ignored if the display thread is not running
+    fn send(&mut self, update: DisplayUpdate)
+    {
+        let _ = self.tx.send(update);
+    }
+
     pub fn enable_display(&mut self, enable: bool)
     {
-        let mut lck = self.txt.lock().unwrap();
-        lck.display_enable = enable;
+        self.send(DisplayUpdate::Enable(enable));
     }
 
     pub fn set_voltage(&mut self, vol: f32, cur: f32, power: f32)
     {
-        let mut lck = self.txt.lock().unwrap();
         // if the voltage is 12.3455V, set 12.346V. if the voltage is 12.3454V, set 12.345V.
         let rvol = (vol * 1000.0).round() / 1000.0;
         // info!("Set voltage: {}V ({}V)", rvol, vol);  
-        lck.voltage = rvol;
-        lck.current = cur;
-        lck.power = power;
+        self.live.voltage = rvol;
+        self.live.current = cur;
+        self.live.power = power;
     }
 
     pub fn set_interval(&mut self, interval : u32)
     {
-        let mut lck = self.txt.lock().unwrap();
-        lck.interval = interval;
+        self.send(DisplayUpdate::Interval(interval));
     }
 
     pub fn set_current_status(&mut self, status: LoggingStatus)
     {
-        let mut lck= self.txt.lock().unwrap();
-        lck.status = status;
+        self.live.logging = matches!(status, LoggingStatus::Start);
     }
 
     pub fn set_wifi_status(&mut self, status: WifiStatus)
     {
-        let mut lck= self.txt.lock().unwrap();
-        lck.wifi = status;
+        self.live.wifi = matches!(status, WifiStatus::Connected);
+    }
+
+    pub fn set_interlock_status(&mut self, status: InterlockStatus)
+    {
+        self.send(DisplayUpdate::Interlock(status));
     }
 
     pub fn set_message(&mut self, msg: String, enable: bool, timeout: u32)
     {
-        let mut lck = self.txt.lock().unwrap();
-        lck.message = msg;
-        lck.message_enable = enable;
-        lck.message_timeout = timeout;
-        lck.message_timer = SystemTime::now();
+        // The timeout starts when the message is set, not when it is drawn
+        self.send(DisplayUpdate::Message(msg, enable, timeout, SystemTime::now()));
+    }
+
+    pub fn set_menu(&mut self, enable: bool, title: String, item: String, value: String)
+    {
+        self.send(DisplayUpdate::Menu(enable, title, item, value));
     }
 
     pub fn set_battery(&mut self, bat: f32){
-        let mut lck = self.txt.lock().unwrap();
-        lck.battery = bat;
+        self.send(DisplayUpdate::Battery(bat));
     }
 
     pub fn set_buffer_watermark(&mut self, wm: u32){
-        let mut lck = self.txt.lock().unwrap();
-        lck.buffer_water_mark = wm;
+        self.live.buffer_water_mark = wm;
     }
 
     pub fn set_load_current(&mut self, load_current: f32){
-        let mut lck = self.txt.lock().unwrap();
-        lck.load_current = load_current;
+        self.send(DisplayUpdate::LoadCurrent(load_current));
     }
 
     pub fn set_output_voltage(&mut self, output_voltage: f32){
-        let mut lck = self.txt.lock().unwrap();
-        lck.output_voltage = output_voltage;
+        self.live.output_voltage = output_voltage;
     }
 
     pub fn set_pwm_duty(&mut self, duty: u32){
-        let mut lck = self.txt.lock().unwrap();
-        lck.pwm_duty = duty;
+        self.live.pwm_duty = duty;
     }
 
     pub fn set_temperature(&mut self, temp: f32){
-        let mut lck = self.txt.lock().unwrap();
-        lck.temperature = temp;
+        self.live.temperature = temp;
     }
 
     pub fn set_usb_pd_voltage(&mut self, voltage: f32){
-        let mut lck = self.txt.lock().unwrap();
-        lck.usb_pd_voltage = voltage;
+        self.live.usb_pd_voltage = voltage;
+    }
+
+    // None with a single output channel
+    pub fn set_channel(&mut self, channel: Option<u8>){
+        self.send(DisplayUpdate::Channel(channel));
+    }
+
+    // selected: the keys adjust the current limit instead of the voltage
+    pub fn set_current_limit(&mut self, limit: f32, selected: bool){
+        self.live.current_limit = limit;
+        self.live.current_limit_selected = selected;
+    }
+
+    pub fn set_power_limit(&mut self, limit: f32, selected: bool){
+        self.live.power_limit = limit;
+        self.live.power_limit_selected = selected;
+    }
+
+    // Endurance test cycle in progress, None without a test
+    pub fn set_cycle(&mut self, cycle: Option<(u32, u32)>){
+        self.send(DisplayUpdate::Cycle(cycle));
+    }
+
+    // Significant digits of the voltages, currents and powers
+    pub fn set_unit_format(&mut self, format: UnitFormat){
+        self.send(DisplayUpdate::Format(format));
+    }
+
+    // Blank the display in the low-power idle
+    pub fn set_sleep(&mut self, sleep: bool){
+        self.send(DisplayUpdate::Sleep(sleep));
+    }
+
+    // The current or power of the channel shown near its limit, the readout flashes
+    pub fn set_limit_warning(&mut self, current: bool, power: bool){
+        self.live.current_warning = current;
+        self.live.power_warning = power;
+    }
+
+    // History of the channel shown for the sparklines (voltages, currents), None to show
+    // the setpoint row all the time
+    pub fn set_trend(&mut self, trend: Option<(Vec<f32>, Vec<f32>)>){
+        self.send(DisplayUpdate::Trend(trend));
+    }
+
+    // Frames per second
+    pub fn set_refresh_rate(&mut self, hz: u32){
+        self.send(DisplayUpdate::RefreshRate(hz));
+    }
+
+    // Flash the panel with the text for the time, 0 to stop
+    pub fn set_identify(&mut self, secs: u32, text: String){
+        let identify = if secs > 0 { Some((Instant::now() + Duration::from_secs(secs as u64), text)) } else { None };
+        self.send(DisplayUpdate::Identify(identify));
+    }
+
+    // Color bars and a frame on the whole panel for the production test
+    pub fn set_test_pattern(&mut self, enable: bool){
+        self.send(DisplayUpdate::TestPattern(enable));
+    }
+
+    // Publish the live values set since the last call, once per main loop iteration
+    pub fn publish(&mut self){
+        self.snapshot.publish(&self.live);
+    }
+}
+
+// Eight color bars, each color channel on and off, inside a white frame: a missing color or
+// a dead row or column of the panel shows
+fn draw_test_pattern(display: &mut PanelFrame) {
+    let colors = [
+        Rgb565::WHITE, Rgb565::YELLOW, Rgb565::CYAN, Rgb565::GREEN,
+        Rgb565::MAGENTA, Rgb565::RED, Rgb565::BLUE, Rgb565::BLACK,
+    ];
+    let width = PANEL_WIDTH as u32 / colors.len() as u32;
+    for (i, color) in colors.iter().enumerate() {
+        Rectangle::new(Point::new(i as i32 * width as i32, 0), Size::new(width, PANEL_HEIGHT as u32))
+            .into_styled(PrimitiveStyle::with_fill(*color))
+            .draw(display).unwrap();
+    }
+    Rectangle::new(Point::zero(), Size::new(PANEL_WIDTH as u32, PANEL_HEIGHT as u32))
+        .into_styled(PrimitiveStyle::with_stroke(Rgb565::WHITE, 1))
+        .draw(display).unwrap();
+}
+
+// Sparkline of the values in the trend box at x
+fn draw_sparkline(display: &mut PanelFrame, values: &[f32], x: i32, min_span: f32, color: Rgb565) {
+    let points : Vec<Point> = sparkline(values, TREND_POINTS as u32, TREND_HEIGHT, min_span).into_iter()
+        .map(|(px, py)| Point::new(x + px, TREND_TOP + py))
+        .collect();
+    let style = PrimitiveStyle::with_stroke(color, 1);
+    match points.len() {
+        0 => {},
+        1 => Pixel(points[0], color).draw(display).unwrap(),
+        _ => Polyline::new(&points).into_styled(style).draw(display).unwrap(),
     }
 }
@@ -0,0 +1,68 @@
+// Error type of the driver modules (ina228, usbpd, transfer)
+// Callers match on the kind to decide whether to retry, trip the output or ignore the error.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use std::fmt;
+use esp_idf_sys::EspError;
+
+#[derive(Debug)]
+pub enum Error {
+    // Bus transfer failed (NACK). Usually transient.
+    I2c(String),
+    // Bus transfer timed out (SCL or SDA held low). Repeated, the bus is hung.
+    I2cTimeout(String),
+    // USB PD source rejected the request or no suitable PDO
+    PdNegotiation(String),
+    // Device responded but the value is invalid
+    Sensor(String),
+    // Invalid parameter or setting
+    Config(String),
+    // HTTP/WiFi failure
+    Network(String),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    // Bus error of a transfer, by its ESP-IDF error code
+    pub fn i2c(e: EspError, msg: &str) -> Error {
+        if e.code() == esp_idf_sys::ESP_ERR_TIMEOUT as i32 {
+            Error::I2cTimeout(msg.to_string())
+        }
+        else {
+            Error::I2c(msg.to_string())
+        }
+    }
+
+    // Errors which may succeed when the operation is retried
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Error::I2c(_) | Error::I2cTimeout(_) | Error::Network(_) => true,
+            Error::PdNegotiation(_) | Error::Sensor(_) | Error::Config(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::I2c(msg) => write!(f, "I2C error: {}", msg),
+            Error::I2cTimeout(msg) => write!(f, "I2C timeout: {}", msg),
+            Error::PdNegotiation(msg) => write!(f, "PD negotiation error: {}", msg),
+            Error::Sensor(msg) => write!(f, "Sensor error: {}", msg),
+            Error::Config(msg) => write!(f, "Config error: {}", msg),
+            Error::Network(msg) => write!(f, "Network error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<EspError> for Error {
+    fn from(e: EspError) -> Self {
+        Error::i2c(e, &format!("{:?}", e))
+    }
+}
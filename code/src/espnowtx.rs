@@ -0,0 +1,44 @@
+// ESP-NOW broadcast of the live measurement frames (see dcpower_control::peertelemetry)
+// ESP-NOW runs on the WiFi radio on its present channel: the channel of the access point
+// while connected, espnow_channel otherwise. No pairing is needed; any receiver on the
+// channel gets the broadcast frames.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use log::*;
+use esp_idf_svc::espnow::{EspNow, PeerInfo, BROADCAST};
+
+pub struct EspNowTx {
+    espnow: EspNow<'static>,
+    failed: u32,
+}
+
+impl EspNowTx {
+    // Call after the WiFi radio is started
+    pub fn start() -> anyhow::Result<EspNowTx> {
+        let espnow = EspNow::take()?;
+        espnow.add_peer(PeerInfo {
+            peer_addr: BROADCAST,
+            channel: 0,
+            ifidx: esp_idf_sys::wifi_interface_t_WIFI_IF_STA,
+            encrypt: false,
+            ..Default::default()
+        })?;
+        info!("ESP-NOW telemetry started");
+        Ok(EspNowTx { espnow: espnow, failed: 0 })
+    }
+
+    // Frames which could not be queued since the start
+    pub fn get_failed(&self) -> u32 {
+        self.failed
+    }
+
+    pub fn send(&mut self, frame: &str) {
+        if let Err(e) = self.espnow.send(BROADCAST, frame.as_bytes()) {
+            self.failed += 1;
+            debug!("ESP-NOW send failed: {:?}", e);
+        }
+    }
+}
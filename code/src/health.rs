@@ -0,0 +1,277 @@
+// Heap, stack and main loop health telemetry
+// Threads register their task at start. The main loop samples the heap and the stack
+// high-water marks periodically and measures the jitter of its own period.
+// The free PSRAM (the log buffer) is reported apart from the internal heap.
+// The I2C devices are reported with their transfer and error counts (see i2cbus).
+// The WiFi state is read from the snapshot of the WifiManager.
+// The control path latency is read from the last publish of the control task.
+// The input latency (touch to the action of a key) is set by the main loop.
+// The error counters kept across reboots (see dcpower_control::errorcounters) too.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use log::*;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use serde::Serialize;
+use crate::wifi::{WifiState, WifiStateHandle};
+use dcpower_control::latency::LatencyReport;
+use dcpower_control::errorcounters::{Counter, ErrorCounters};
+
+const HEAP_WARN_BYTES: u32 = 20 * 1024;
+const STACK_WARN_BYTES: u32 = 1024;
+
+// FreeRTOS task handles of the registered threads
+static TASKS: Mutex<Vec<(&'static str, usize)>> = Mutex::new(Vec::new());
+
+// Free PSRAM and its largest block, 0 without PSRAM
+pub fn psram_free() -> u32 {
+    unsafe { esp_idf_sys::heap_caps_get_free_size(esp_idf_sys::MALLOC_CAP_SPIRAM) as u32 }
+}
+
+pub fn psram_largest_block() -> u32 {
+    unsafe { esp_idf_sys::heap_caps_get_largest_free_block(esp_idf_sys::MALLOC_CAP_SPIRAM) as u32 }
+}
+
+// Call at the start of a thread to include its stack in the report
+pub fn register_task(name: &'static str) {
+    let handle = unsafe { esp_idf_sys::xTaskGetCurrentTaskHandle() } as usize;
+    let mut lck = TASKS.lock().unwrap();
+    lck.retain(|(n, _)| *n != name);
+    lck.push((name, handle));
+}
+
+// Call before the thread ends, its handle is not valid after that
+pub fn unregister_task(name: &'static str) {
+    TASKS.lock().unwrap().retain(|(n, _)| *n != name);
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskStack {
+    pub name: &'static str,
+    pub stack_free_min: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct I2cDeviceReport {
+    pub name: &'static str,
+    pub addr: u8,
+    pub online: bool,
+    pub transfers: u64,
+    pub nacks: u64,
+    pub timeouts: u64,
+    pub error_rate_percent: f32,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ControlLatency {
+    pub samples: u32,
+    pub p50_us: u32,
+    pub p99_us: u32,
+    pub max_us: u32,
+    pub over_budget: u32,
+    pub budget_us: u32,
+}
+
+// Touch to the action of a key (ms), and the part of it to the key scan
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct InputLatency {
+    pub keys: u32,
+    pub p50_ms: f32,
+    pub p99_ms: f32,
+    pub max_ms: f32,
+    pub scan_p99_ms: f32,
+}
+
+// Counts since the counters were last reset, across reboots
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ErrorCountersReport {
+    pub boots: u32,
+    pub watchdog_resets: u32,
+    pub over_current_trips: u32,
+    pub over_temperature_trips: u32,
+    pub i2c_errors: u32,
+    pub pd_failures: u32,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HealthReport {
+    pub uptime_secs: u64,
+    pub free_heap: u32,
+    pub min_free_heap: u32,
+    pub free_internal: u32,
+    pub free_psram: u32,
+    pub tasks: Vec<TaskStack>,
+    pub loop_period_avg_ms: f32,
+    pub loop_period_max_ms: f32,
+    pub loop_jitter_max_ms: f32,
+    pub syslog_sent: u32,
+    pub syslog_dropped: u32,
+    pub records_lost: u32,
+    pub log_batches_resent: u32,
+    pub control_overruns: u32,
+    pub control_latency: ControlLatency,
+    pub input_latency: InputLatency,
+    pub i2c_devices: Vec<I2cDeviceReport>,
+    pub i2c_recoveries: u32,
+    pub i2c_recoveries_failed: u32,
+    pub error_counters: ErrorCountersReport,
+    pub wifi: WifiState,
+}
+
+#[derive(Clone)]
+pub struct HealthMonitor {
+    report: Arc<Mutex<HealthReport>>,
+    loop_period_ms: f32,
+    last_tick: Option<Instant>,
+    loop_count: u32,
+    loop_sum_ms: f32,
+    loop_max_ms: f32,
+    jitter_max_ms: f32,
+    heap_warned: bool,
+    wifi: Option<WifiStateHandle>,
+    input_latency: InputLatency,
+    error_counters: ErrorCountersReport,
+}
+
+impl HealthMonitor {
+    pub fn new(loop_period_ms: f32) -> HealthMonitor {
+        HealthMonitor {
+            report: Arc::new(Mutex::new(HealthReport::default())),
+            loop_period_ms: loop_period_ms,
+            last_tick: None,
+            loop_count: 0,
+            loop_sum_ms: 0.0,
+            loop_max_ms: 0.0,
+            jitter_max_ms: 0.0,
+            heap_warned: false,
+            wifi: None,
+            input_latency: InputLatency::default(),
+            error_counters: ErrorCountersReport::default(),
+        }
+    }
+
+    pub fn set_wifi(&mut self, wifi: WifiStateHandle) {
+        self.wifi = Some(wifi);
+    }
+
+    // Reported with the next sample
+    pub fn set_input_latency(&mut self, total: &LatencyReport, scan: &LatencyReport) {
+        self.input_latency = InputLatency {
+            keys: total.samples,
+            p50_ms: total.p50_us as f32 / 1000.0,
+            p99_ms: total.p99_us as f32 / 1000.0,
+            max_ms: total.max_us as f32 / 1000.0,
+            scan_p99_ms: scan.p99_us as f32 / 1000.0,
+        };
+    }
+
+    // Reported with the next sample
+    pub fn set_error_counters(&mut self, counters: &ErrorCounters) {
+        self.error_counters = ErrorCountersReport {
+            boots: counters.get(Counter::Boot),
+            watchdog_resets: counters.get(Counter::WatchdogReset),
+            over_current_trips: counters.get(Counter::OverCurrentTrip),
+            over_temperature_trips: counters.get(Counter::OverTemperatureTrip),
+            i2c_errors: counters.get(Counter::I2cError),
+            pd_failures: counters.get(Counter::PdFailure),
+        };
+    }
+
+    // Call once per main loop iteration
+    pub fn loop_tick(&mut self) {
+        let now = Instant::now();
+        if let Some(last) = self.last_tick {
+            let period = now.duration_since(last).as_secs_f32() * 1000.0;
+            self.loop_count += 1;
+            self.loop_sum_ms += period;
+            self.loop_max_ms = self.loop_max_ms.max(period);
+            self.jitter_max_ms = self.jitter_max_ms.max((period - self.loop_period_ms).abs());
+        }
+        self.last_tick = Some(now);
+    }
+
+    // Sample the heap and stacks, publish the report and restart the loop timing window
+    pub fn sample(&mut self) {
+        let free_heap = unsafe { esp_idf_sys::esp_get_free_heap_size() };
+        let min_free_heap = unsafe { esp_idf_sys::esp_get_minimum_free_heap_size() };
+        // The heap includes the PSRAM, the warning is for the internal RAM
+        let free_internal = unsafe { esp_idf_sys::heap_caps_get_free_size(esp_idf_sys::MALLOC_CAP_INTERNAL) as u32 };
+        let mut tasks = Vec::new();
+        for (name, handle) in TASKS.lock().unwrap().iter() {
+            // ESP-IDF returns the high-water mark in bytes
+            let stack_free_min = unsafe { esp_idf_sys::uxTaskGetStackHighWaterMark(*handle as esp_idf_sys::TaskHandle_t) };
+            if stack_free_min < STACK_WARN_BYTES {
+                warn!("Task {} stack low: {} bytes free", name, stack_free_min);
+            }
+            tasks.push(TaskStack { name: name, stack_free_min: stack_free_min });
+        }
+        if free_internal < HEAP_WARN_BYTES {
+            if !self.heap_warned {
+                warn!("Free internal RAM low: {} bytes (heap {} bytes, minimum {} bytes)", free_internal, free_heap, min_free_heap);
+            }
+            self.heap_warned = true;
+        }
+        else {
+            self.heap_warned = false;
+        }
+        let avg = if self.loop_count > 0 { self.loop_sum_ms / self.loop_count as f32 } else { 0.0 };
+        if self.loop_max_ms > self.loop_period_ms * 10.0 {
+            warn!("Main loop stalled: {:.1}ms (average {:.1}ms)", self.loop_max_ms, avg);
+        }
+        let report = HealthReport {
+            uptime_secs: (unsafe { esp_idf_sys::esp_timer_get_time() } / 1_000_000) as u64,
+            free_heap: free_heap,
+            min_free_heap: min_free_heap,
+            free_internal: free_internal,
+            free_psram: psram_free(),
+            tasks: tasks,
+            loop_period_avg_ms: avg,
+            loop_period_max_ms: self.loop_max_ms,
+            loop_jitter_max_ms: self.jitter_max_ms,
+            syslog_sent: crate::syslogger::sent_count(),
+            syslog_dropped: crate::syslogger::dropped_count(),
+            records_lost: crate::transfer::lost_count(),
+            log_batches_resent: crate::transfer::resent_count(),
+            control_overruns: crate::controltimer::overrun_count(),
+            control_latency: {
+                let r = crate::controltask::latency_report();
+                ControlLatency {
+                    samples: r.samples,
+                    p50_us: r.p50_us,
+                    p99_us: r.p99_us,
+                    max_us: r.max_us,
+                    over_budget: r.over_budget,
+                    budget_us: r.budget_us,
+                }
+            },
+            input_latency: self.input_latency.clone(),
+            i2c_devices: crate::i2cbus::devices().iter().map(|d| I2cDeviceReport {
+                name: d.name,
+                addr: d.addr,
+                online: d.is_online(),
+                transfers: d.transfers,
+                nacks: d.nacks,
+                timeouts: d.timeouts,
+                error_rate_percent: d.error_rate(),
+            }).collect(),
+            i2c_recoveries: crate::i2cbus::recovery_count(),
+            i2c_recoveries_failed: crate::i2cbus::recovery_failed_count(),
+            error_counters: self.error_counters.clone(),
+            wifi: self.wifi.as_ref().map(|wifi| wifi.lock().unwrap().clone()).unwrap_or_default(),
+        };
+        debug!("Health: heap {} (min {}) bytes, loop {:.1}ms avg {:.1}ms max",
+              report.free_heap, report.min_free_heap, avg, self.loop_max_ms);
+        *self.report.lock().unwrap() = report;
+        self.loop_count = 0;
+        self.loop_sum_ms = 0.0;
+        self.loop_max_ms = 0.0;
+        self.jitter_max_ms = 0.0;
+    }
+
+    pub fn get_report(&self) -> HealthReport {
+        self.report.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,135 @@
+// JSON-lines protocol over the USB console for a desktop app
+// A line received on the console starting with '{' is a request, one JSON object per line:
+//   {"id":1,"cmd":"hello"}                          protocol version, firmware and channels
+//   {"id":2,"cmd":"status"}                         output state and measurements
+//   {"id":3,"cmd":"output","ch":1,"on":true}        start/stop an output ("ch" default 1)
+//   {"id":4,"cmd":"voltage","ch":1,"value":5.0}     setpoint (V)
+//   {"id":5,"cmd":"current","ch":1,"value":0.5}     session current limit (A)
+//   {"id":6,"cmd":"stream","rate_hz":100}           stream the samples, 0 to stop
+//   {"id":7,"cmd":"raw","on":true}                  binary stream of every sample (see rawstream)
+// Each request is answered with {"type":"reply","id":<id>,"ok":true,...} or
+// {"type":"reply","id":<id>,"ok":false,"error":"..."}, and the samples are sent as
+// {"type":"sample","t_ms":...,"ch":1,"output":true,"voltage":...,"current":...,"power":...}.
+// The production test sends its report as {"type":"prodtest","pass":true,"steps":[...]}.
+// The log and the console commands share the port: the app ignores the lines which are
+// not JSON objects.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::controltask::{CH1, CH2};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+// Samples are taken by the main loop (100Hz)
+pub const MAX_STREAM_RATE_HZ: u32 = 100;
+
+fn default_channel() -> u8 {
+    1
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum HostCommand {
+    Hello,
+    Status,
+    Output {
+        #[serde(default = "default_channel")]
+        ch: u8,
+        on: bool,
+    },
+    Voltage {
+        #[serde(default = "default_channel")]
+        ch: u8,
+        value: f32,
+    },
+    Current {
+        #[serde(default = "default_channel")]
+        ch: u8,
+        value: f32,
+    },
+    Stream {
+        rate_hz: u32,
+    },
+    Raw {
+        on: bool,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct HostRequest {
+    // Echoed in the reply, 0 if not given
+    #[serde(default)]
+    pub id: u32,
+    #[serde(flatten)]
+    pub command: HostCommand,
+}
+
+impl HostRequest {
+    // Err with the id (if it could be read) and the message for the error reply
+    pub fn parse(line: &str) -> Result<HostRequest, (u32, String)> {
+        serde_json::from_str::<HostRequest>(line).map_err(|e| {
+            let id = serde_json::from_str::<Value>(line).ok()
+                .and_then(|v| v.get("id").and_then(|id| id.as_u64()))
+                .unwrap_or(0) as u32;
+            (id, format!("invalid request: {}", e))
+        })
+    }
+}
+
+// Channel number 1 or 2 to the channel index
+pub fn channel_index(ch: u8) -> Result<usize, String> {
+    match ch {
+        1 => Ok(CH1),
+        2 => Ok(CH2),
+        _ => Err(format!("invalid channel: {} (1 or 2)", ch)),
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HostSample {
+    pub t_ms: u64,
+    pub ch: u8,
+    pub output: bool,
+    pub voltage: f32,
+    pub current: f32,
+    pub power: f32,
+}
+
+// Fields of the result are added to the reply object
+pub fn reply(id: u32, result: Result<Value, String>) {
+    let mut line = json!({ "type": "reply", "id": id });
+    match result {
+        Ok(Value::Object(fields)) => {
+            line["ok"] = json!(true);
+            for (name, value) in fields {
+                line[name.as_str()] = value;
+            }
+        },
+        Ok(_) => line["ok"] = json!(true),
+        Err(e) => {
+            line["ok"] = json!(false);
+            line["error"] = json!(e);
+        },
+    }
+    println!("{}", line);
+}
+
+pub fn send_sample(sample: &HostSample) {
+    if let Ok(fields) = serde_json::to_value(sample) {
+        send_line("sample", fields);
+    }
+}
+
+// A line of the type with the fields of the object, e.g. the production test report
+pub fn send_line(kind: &str, fields: Value) {
+    let mut line = json!({ "type": kind });
+    if let Value::Object(fields) = fields {
+        for (name, value) in fields {
+            line[name.as_str()] = value;
+        }
+    }
+    println!("{}", line);
+}
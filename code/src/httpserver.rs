@@ -0,0 +1,918 @@
+// HTTP API server
+// GET  /config : Read the config file on SPIFFS
+// POST /config : Upload a config file. It is validated, stored and applied by the main loop.
+//                        A change of the protection limits needs the unlock code in "X-Unlock-Code".
+// GET  /version : Firmware version, git hash and build time
+// GET  /capabilities : Supported modes, voltage and current ranges, sampling rates and features
+// GET  /settings : Export all the settings (including calibration) as JSON
+// POST /settings : Import exported settings. They are validated, applied and stored in NVS by the main loop.
+//                        A change of the protection limits needs the unlock code in "X-Unlock-Code".
+// GET  /pid : PID gains and PWM offset, PUT /pid : Change them (JSON with any of kp, ki, kd, pwm_offset),
+//                        DELETE /pid : Revert to the defaults. Applied live and stored in NVS.
+// POST /wake : Wake the unit from the low-power idle
+// POST /identify : Flash the display with the IP address (JSON with seconds, 10 without a body, 0 to stop)
+// POST /marker : Put a numbered marker in the log and InfluxDB (JSON with text, none without a body)
+// GET  /health : Heap, task stack, main loop timing, I2C bus telemetry and error counters
+// GET  /crash : Reset reason and crash dump summary
+// GET  /crash/dump : Crash dump image (ELF), DELETE /crash/dump : Clear the crash dump
+// GET  /session : The latest session report (text)
+// GET  /dut : Run label, PUT /dut : Set it (JSON with dut and note), DELETE /dut : Clear it.
+//                        Tags the InfluxDB points and the session reports, not saved.
+// GET  /calibration : INA228 offset calibration state, progress and offsets, POST /calibration : Start it
+//                        (outputs off), POST /calibration/apply : Apply the offsets, DELETE /calibration : Discard them.
+//                        The offsets are in effect until a reboot, like the calibration from the panel.
+// GET  /log : Log filters, PUT /log : Change the log filter of the console and syslog
+//                        (e.g. "info,usbpd=debug", not saved), PUT /log/console, PUT /log/syslog : Only one of them
+// GET  /scripts : Names of the test scripts on SPIFFS, POST /scripts : Upload a script (validated and stored)
+// GET  /scripts/run : State and checks of the script run, POST /scripts/run : Run a script (JSON with name),
+//                        DELETE /scripts/run : Stop it
+// All the paths are also served under /api/v1 (e.g. GET /api/v1/health), the versioned API for
+// new clients; the paths without the version stay for the earlier clients. With api_cors_origin
+// set, the responses allow that origin (a browser dashboard hosted elsewhere) and OPTIONS
+// answers the CORS preflight.
+// With api_control_token (and api_read_token) set, a request needs "Authorization: Bearer <token>":
+// the read token for the GETs of the telemetry, the control token for the changes and for
+// /config, /settings and /crash/dump, which hold credentials (see dcpower_control::apiauth).
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use log::*;
+use embedded_svc::http::Method;
+use embedded_svc::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender};
+use std::time::Duration;
+use serde::Deserialize;
+use esp_idf_svc::http::server::{EspHttpServer, EspHttpConnection, Configuration, Request, Response};
+use crate::configfile::ConfigFile;
+use crate::health::HealthMonitor;
+use crate::crashdump;
+use crate::sessionreport;
+use crate::testscripts::{self, ScriptRequest};
+use crate::version;
+use crate::capabilities::Capabilities;
+use crate::settings::{Settings, PidChange, PidUpdate};
+use crate::bus::Command;
+use crate::logfilter::{self, LogFilter, Sink};
+use crate::displayctl::{IDENTIFY_SECS, MAX_IDENTIFY_SECS};
+use dcpower_control::session::RunLabel;
+use dcpower_control::offsetcal::CalibrationAction;
+use dcpower_control::testscript::ScriptState;
+use dcpower_control::apiauth::{Access, AccessTokens, Role};
+
+const MAX_BODY_LEN: usize = 4000;
+// Version of the API, and the paths without it kept for the clients of the earlier firmware
+const API_PREFIXES: [&str; 2] = ["/api/v1", ""];
+// Paths answering a CORS preflight
+const PATHS: [&str; 20] = [
+    "/config", "/version", "/capabilities", "/settings", "/pid", "/wake", "/health", "/crash", "/crash/dump",
+    "/session", "/dut", "/log", "/log/console", "/log/syslog", "/calibration", "/calibration/apply",
+    "/identify", "/marker", "/scripts", "/scripts/run",
+];
+// Unlock code of the protection limits for a config upload or a settings import
+const UNLOCK_CODE_HEADER: &str = "X-Unlock-Code";
+// The main loop drains the bus every 10ms
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn default_identify_secs() -> u32 {
+    IDENTIFY_SECS
+}
+
+#[derive(Deserialize)]
+struct IdentifyRequest {
+    #[serde(default = "default_identify_secs")]
+    seconds: u32,
+}
+
+#[derive(Deserialize)]
+struct MarkerRequest {
+    #[serde(default)]
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct ScriptRunRequest {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct LabelRequest {
+    dut: String,
+    #[serde(default)]
+    note: String,
+}
+
+// Access tokens and CORS origin in effect, updated by the main loop when the settings change
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ApiPolicy {
+    pub tokens: AccessTokens,
+    // Access-Control-Allow-Origin of the responses, empty for none
+    pub cors_origin: String,
+}
+
+pub type ApiHandle = Arc<Mutex<ApiPolicy>>;
+
+pub struct HttpServer {
+    server: Option<EspHttpServer<'static>>,
+    config_file: ConfigFile,
+    health: HealthMonitor,
+    capabilities: Capabilities,
+    commands: Sender<Command>,
+    api: ApiHandle,
+}
+
+impl HttpServer {
+    pub fn new(config_file: ConfigFile, health: HealthMonitor, capabilities: Capabilities, commands: Sender<Command>, api: ApiHandle) -> HttpServer {
+        HttpServer { server: None, config_file: config_file, health: health, capabilities: capabilities, commands: commands, api: api }
+    }
+
+    pub fn start(&mut self) -> anyhow::Result<()> {
+        let conf = Configuration {
+            stack_size: 10240,
+            // The routes twice (versioned and not) with their preflight
+            max_uri_handlers: 110,
+            ..Default::default()
+        };
+        let mut server = EspHttpServer::new(&conf)?;
+
+        // Each route under /api/v1 and at the unversioned path of the earlier firmware
+        for prefix in API_PREFIXES {
+            let config_file = self.config_file.clone();
+            let api = self.api.clone();
+            server.fn_handler::<anyhow::Error, _>(&format!("{}/config", prefix), Method::Get, move |req| {
+                let req = match authorize(req, &api, Role::Control)? {
+                    Some(req) => req,
+                    None => return Ok(()),
+                };
+                match config_file.read() {
+                    Some(json) => {
+                        let mut resp = respond(req, &api, 200, &[("Content-Type", "application/json")])?;
+                        resp.write_all(json.as_bytes())?;
+                    },
+                    None => {
+                        let mut resp = respond(req, &api, 404, &[])?;
+                        resp.write_all(b"no config file\n")?;
+                    }
+                }
+                Ok(())
+            })?;
+
+            let config_file = self.config_file.clone();
+            let api = self.api.clone();
+            server.fn_handler::<anyhow::Error, _>(&format!("{}/config", prefix), Method::Post, move |req| {
+                let mut req = match authorize(req, &api, Role::Control)? {
+                    Some(req) => req,
+                    None => return Ok(()),
+                };
+                let code = req.header(UNLOCK_CODE_HEADER).unwrap_or_default().to_string();
+                let mut body = Vec::new();
+                let mut buf = [0u8; 512];
+                loop {
+                    let len = req.read(&mut buf)?;
+                    if len == 0 {
+                        break;
+                    }
+                    body.extend_from_slice(&buf[..len]);
+                    if body.len() > MAX_BODY_LEN {
+                        let mut resp = respond(req, &api, 413, &[])?;
+                        resp.write_all(b"config file too large\n")?;
+                        return Ok(());
+                    }
+                }
+                let result = std::str::from_utf8(&body)
+                    .map_err(|e| anyhow::anyhow!("{}", e))
+                    .and_then(|json| config_file.upload(json, &code));
+                match result {
+                    Ok(()) => {
+                        let mut resp = respond(req, &api, 200, &[])?;
+                        resp.write_all(b"config applied\n")?;
+                    },
+                    Err(e) => {
+                        warn!("Config upload rejected: {}", e);
+                        let mut resp = respond(req, &api, 400, &[])?;
+                        resp.write_all(format!("invalid config: {}\n", e).as_bytes())?;
+                    }
+                }
+                Ok(())
+            })?;
+
+            let api = self.api.clone();
+            server.fn_handler::<anyhow::Error, _>(&format!("{}/version", prefix), Method::Get, move |req| {
+                let req = match authorize(req, &api, Role::Read)? {
+                    Some(req) => req,
+                    None => return Ok(()),
+                };
+                let json = serde_json::to_string(&version::build_info())?;
+                let mut resp = respond(req, &api, 200, &[("Content-Type", "application/json")])?;
+                resp.write_all(json.as_bytes())?;
+                Ok(())
+            })?;
+
+            let capabilities = serde_json::to_string(&self.capabilities)?;
+            let api = self.api.clone();
+            server.fn_handler::<anyhow::Error, _>(&format!("{}/capabilities", prefix), Method::Get, move |req| {
+                let req = match authorize(req, &api, Role::Read)? {
+                    Some(req) => req,
+                    None => return Ok(()),
+                };
+                let mut resp = respond(req, &api, 200, &[("Content-Type", "application/json")])?;
+                resp.write_all(capabilities.as_bytes())?;
+                Ok(())
+            })?;
+
+            let commands = self.commands.clone();
+            let api = self.api.clone();
+            server.fn_handler::<anyhow::Error, _>(&format!("{}/settings", prefix), Method::Get, move |req| {
+                let req = match authorize(req, &api, Role::Control)? {
+                    Some(req) => req,
+                    None => return Ok(()),
+                };
+                let (reply, settings) = channel();
+                commands.send(Command::ExportSettings(reply))?;
+                match settings.recv_timeout(EXPORT_TIMEOUT) {
+                    Ok(json) => {
+                        let mut resp = respond(req, &api, 200, &[("Content-Type", "application/json")])?;
+                        resp.write_all(json.as_bytes())?;
+                    },
+                    Err(_) => {
+                        let mut resp = respond(req, &api, 503, &[])?;
+                        resp.write_all(b"main loop busy\n")?;
+                    }
+                }
+                Ok(())
+            })?;
+
+            let commands = self.commands.clone();
+            let api = self.api.clone();
+            server.fn_handler::<anyhow::Error, _>(&format!("{}/settings", prefix), Method::Post, move |req| {
+                let mut req = match authorize(req, &api, Role::Control)? {
+                    Some(req) => req,
+                    None => return Ok(()),
+                };
+                let code = req.header(UNLOCK_CODE_HEADER).unwrap_or_default().to_string();
+                let mut body = Vec::new();
+                let mut buf = [0u8; 512];
+                loop {
+                    let len = req.read(&mut buf)?;
+                    if len == 0 {
+                        break;
+                    }
+                    body.extend_from_slice(&buf[..len]);
+                    if body.len() > MAX_BODY_LEN {
+                        let mut resp = respond(req, &api, 413, &[])?;
+                        resp.write_all(b"settings too large\n")?;
+                        return Ok(());
+                    }
+                }
+                // Validate against the schema with the compile-time defaults
+                let result = std::str::from_utf8(&body)
+                    .map_err(|e| anyhow::anyhow!("{}", e))
+                    .and_then(|json| Settings::from_config().import_json(json).map(|_| json.to_string()));
+                // The main loop checks the unlock code against the settings in effect
+                let result = match result {
+                    Ok(json) => {
+                        let (reply, applied) = channel();
+                        commands.send(Command::ImportSettings(json, code, reply))?;
+                        match applied.recv_timeout(EXPORT_TIMEOUT) {
+                            Ok(result) => result.map_err(|e| anyhow::anyhow!("{}", e)),
+                            Err(_) => {
+                                let mut resp = respond(req, &api, 503, &[])?;
+                                resp.write_all(b"main loop busy\n")?;
+                                return Ok(());
+                            }
+                        }
+                    },
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok(()) => {
+                        let mut resp = respond(req, &api, 200, &[])?;
+                        resp.write_all(b"settings imported\n")?;
+                    },
+                    Err(e) => {
+                        warn!("Settings import rejected: {}", e);
+                        let mut resp = respond(req, &api, 400, &[])?;
+                        resp.write_all(format!("invalid settings: {}\n", e).as_bytes())?;
+                    }
+                }
+                Ok(())
+            })?;
+
+            let commands = self.commands.clone();
+            let api = self.api.clone();
+            server.fn_handler::<anyhow::Error, _>(&format!("{}/pid", prefix), Method::Get, move |req| {
+                let req = match authorize(req, &api, Role::Read)? {
+                    Some(req) => req,
+                    None => return Ok(()),
+                };
+                pid_request(req, &api, &commands, PidChange::Get)
+            })?;
+
+            let commands = self.commands.clone();
+            let api = self.api.clone();
+            server.fn_handler::<anyhow::Error, _>(&format!("{}/pid", prefix), Method::Put, move |req| {
+                let mut req = match authorize(req, &api, Role::Control)? {
+                    Some(req) => req,
+                    None => return Ok(()),
+                };
+                let mut buf = [0u8; 256];
+                let mut len = 0;
+                while len < buf.len() {
+                    let n = req.read(&mut buf[len..])?;
+                    if n == 0 {
+                        break;
+                    }
+                    len += n;
+                }
+                if len == buf.len() {
+                    let mut resp = respond(req, &api, 413, &[])?;
+                    resp.write_all(b"PID gains too long\n")?;
+                    return Ok(());
+                }
+                match serde_json::from_slice::<PidUpdate>(&buf[..len]) {
+                    Ok(update) => pid_request(req, &api, &commands, PidChange::Set(update)),
+                    Err(e) => {
+                        let mut resp = respond(req, &api, 400, &[])?;
+                        resp.write_all(format!("invalid PID gains: {}\n", e).as_bytes())?;
+                        Ok(())
+                    }
+                }
+            })?;
+
+            let commands = self.commands.clone();
+            let api = self.api.clone();
+            server.fn_handler::<anyhow::Error, _>(&format!("{}/pid", prefix), Method::Delete, move |req| {
+                let req = match authorize(req, &api, Role::Control)? {
+                    Some(req) => req,
+                    None => return Ok(()),
+                };
+                pid_request(req, &api, &commands, PidChange::Defaults)
+            })?;
+
+            let commands = self.commands.clone();
+            let api = self.api.clone();
+            server.fn_handler::<anyhow::Error, _>(&format!("{}/wake", prefix), Method::Post, move |req| {
+                let req = match authorize(req, &api, Role::Control)? {
+                    Some(req) => req,
+                    None => return Ok(()),
+                };
+                commands.send(Command::Wake)?;
+                let mut resp = respond(req, &api, 200, &[])?;
+                resp.write_all(b"OK\n")?;
+                Ok(())
+            })?;
+
+            let commands = self.commands.clone();
+            let api = self.api.clone();
+            server.fn_handler::<anyhow::Error, _>(&format!("{}/identify", prefix), Method::Post, move |req| {
+                let mut req = match authorize(req, &api, Role::Control)? {
+                    Some(req) => req,
+                    None => return Ok(()),
+                };
+                let mut buf = [0u8; 64];
+                let mut len = 0;
+                while len < buf.len() {
+                    let n = req.read(&mut buf[len..])?;
+                    if n == 0 {
+                        break;
+                    }
+                    len += n;
+                }
+                // No body for the default time
+                let body = std::str::from_utf8(&buf[..len]).unwrap_or("").trim();
+                let request = if body.is_empty() { Ok(IdentifyRequest { seconds: IDENTIFY_SECS }) } else { serde_json::from_str::<IdentifyRequest>(body) };
+                match request {
+                    Ok(request) if request.seconds <= MAX_IDENTIFY_SECS => {
+                        commands.send(Command::Identify(request.seconds))?;
+                        let mut resp = respond(req, &api, 200, &[])?;
+                        resp.write_all(format!("identify {}s\n", request.seconds).as_bytes())?;
+                    },
+                    _ => {
+                        let mut resp = respond(req, &api, 400, &[])?;
+                        resp.write_all(format!("seconds must be 0 to {}\n", MAX_IDENTIFY_SECS).as_bytes())?;
+                    }
+                }
+                Ok(())
+            })?;
+
+            let commands = self.commands.clone();
+            let api = self.api.clone();
+            server.fn_handler::<anyhow::Error, _>(&format!("{}/marker", prefix), Method::Post, move |req| {
+                let mut req = match authorize(req, &api, Role::Control)? {
+                    Some(req) => req,
+                    None => return Ok(()),
+                };
+                let mut buf = [0u8; 512];
+                let mut len = 0;
+                while len < buf.len() {
+                    let n = req.read(&mut buf[len..])?;
+                    if n == 0 {
+                        break;
+                    }
+                    len += n;
+                }
+                // No body for a marker without text
+                let body = std::str::from_utf8(&buf[..len]).unwrap_or("").trim();
+                let request = if body.is_empty() { Ok(MarkerRequest { text: String::new() }) } else { serde_json::from_str::<MarkerRequest>(body) };
+                let text = match request {
+                    Ok(request) => request.text,
+                    Err(e) => {
+                        let mut resp = respond(req, &api, 400, &[])?;
+                        resp.write_all(format!("invalid request: {}\n", e).as_bytes())?;
+                        return Ok(());
+                    }
+                };
+                let (reply, result) = channel();
+                commands.send(Command::Marker(text, reply))?;
+                match result.recv_timeout(EXPORT_TIMEOUT) {
+                    Ok(Ok(marker)) => {
+                        let json = serde_json::json!({
+                            "number": marker.number,
+                            "source": marker.source.as_str(),
+                            "text": marker.text,
+                            "time_ms": marker.clock_ms,
+                        }).to_string();
+                        let mut resp = respond(req, &api, 200, &[("Content-Type", "application/json")])?;
+                        resp.write_all(json.as_bytes())?;
+                    },
+                    Ok(Err(e)) => {
+                        let mut resp = respond(req, &api, 400, &[])?;
+                        resp.write_all(format!("invalid marker: {}\n", e).as_bytes())?;
+                    },
+                    Err(_) => {
+                        let mut resp = respond(req, &api, 503, &[])?;
+                        resp.write_all(b"main loop busy\n")?;
+                    }
+                }
+                Ok(())
+            })?;
+
+            for (path, method, action, role) in [
+                ("/calibration", Method::Get, CalibrationAction::Status, Role::Read),
+                ("/calibration", Method::Post, CalibrationAction::Start, Role::Control),
+                ("/calibration/apply", Method::Post, CalibrationAction::Apply, Role::Control),
+                ("/calibration", Method::Delete, CalibrationAction::Discard, Role::Control),
+            ] {
+                let commands = self.commands.clone();
+                let api = self.api.clone();
+                server.fn_handler::<anyhow::Error, _>(&format!("{}{}", prefix, path), method, move |req| {
+                    let req = match authorize(req, &api, role)? {
+                        Some(req) => req,
+                        None => return Ok(()),
+                    };
+                    calibration_request(req, &api, &commands, action)
+                })?;
+            }
+
+            let health = self.health.clone();
+            let api = self.api.clone();
+            server.fn_handler::<anyhow::Error, _>(&format!("{}/health", prefix), Method::Get, move |req| {
+                let req = match authorize(req, &api, Role::Read)? {
+                    Some(req) => req,
+                    None => return Ok(()),
+                };
+                let json = serde_json::to_string(&health.get_report())?;
+                let mut resp = respond(req, &api, 200, &[("Content-Type", "application/json")])?;
+                resp.write_all(json.as_bytes())?;
+                Ok(())
+            })?;
+
+            let api = self.api.clone();
+            server.fn_handler::<anyhow::Error, _>(&format!("{}/crash", prefix), Method::Get, move |req| {
+                let req = match authorize(req, &api, Role::Read)? {
+                    Some(req) => req,
+                    None => return Ok(()),
+                };
+                let json = serde_json::to_string(&crashdump::info())?;
+                let mut resp = respond(req, &api, 200, &[("Content-Type", "application/json")])?;
+                resp.write_all(json.as_bytes())?;
+                Ok(())
+            })?;
+
+            let api = self.api.clone();
+            server.fn_handler::<anyhow::Error, _>(&format!("{}/crash/dump", prefix), Method::Get, move |req| {
+                let req = match authorize(req, &api, Role::Control)? {
+                    Some(req) => req,
+                    None => return Ok(()),
+                };
+                if crashdump::summary().is_none() {
+                    let mut resp = respond(req, &api, 404, &[])?;
+                    resp.write_all(b"no crash dump\n")?;
+                    return Ok(());
+                }
+                let mut resp = respond(req, &api, 200, &[("Content-Type", "application/octet-stream")])?;
+                crashdump::read_image(|chunk| {
+                    resp.write_all(chunk)?;
+                    Ok(())
+                })?;
+                Ok(())
+            })?;
+
+            let api = self.api.clone();
+            server.fn_handler::<anyhow::Error, _>(&format!("{}/crash/dump", prefix), Method::Delete, move |req| {
+                let req = match authorize(req, &api, Role::Control)? {
+                    Some(req) => req,
+                    None => return Ok(()),
+                };
+                crashdump::clear()?;
+                let mut resp = respond(req, &api, 200, &[])?;
+                resp.write_all(b"crash dump cleared\n")?;
+                Ok(())
+            })?;
+
+            let api = self.api.clone();
+            server.fn_handler::<anyhow::Error, _>(&format!("{}/session", prefix), Method::Get, move |req| {
+                let req = match authorize(req, &api, Role::Read)? {
+                    Some(req) => req,
+                    None => return Ok(()),
+                };
+                match sessionreport::latest() {
+                    Some(text) => {
+                        let mut resp = respond(req, &api, 200, &[("Content-Type", "text/plain")])?;
+                        resp.write_all(text.as_bytes())?;
+                    },
+                    None => {
+                        let mut resp = respond(req, &api, 404, &[])?;
+                        resp.write_all(b"no session report\n")?;
+                    }
+                }
+                Ok(())
+            })?;
+
+            let commands = self.commands.clone();
+            let api = self.api.clone();
+            server.fn_handler::<anyhow::Error, _>(&format!("{}/dut", prefix), Method::Get, move |req| {
+                let req = match authorize(req, &api, Role::Read)? {
+                    Some(req) => req,
+                    None => return Ok(()),
+                };
+                label_request(req, &api, &commands, None)
+            })?;
+
+            let commands = self.commands.clone();
+            let api = self.api.clone();
+            server.fn_handler::<anyhow::Error, _>(&format!("{}/dut", prefix), Method::Put, move |req| {
+                let mut req = match authorize(req, &api, Role::Control)? {
+                    Some(req) => req,
+                    None => return Ok(()),
+                };
+                let mut buf = [0u8; 512];
+                let mut len = 0;
+                while len < buf.len() {
+                    let n = req.read(&mut buf[len..])?;
+                    if n == 0 {
+                        break;
+                    }
+                    len += n;
+                }
+                if len == buf.len() {
+                    let mut resp = respond(req, &api, 413, &[])?;
+                    resp.write_all(b"run label too long\n")?;
+                    return Ok(());
+                }
+                let label = serde_json::from_slice::<LabelRequest>(&buf[..len])
+                    .map_err(|e| e.to_string())
+                    .and_then(|r| RunLabel::new(&r.dut, &r.note));
+                match label {
+                    Ok(label) => label_request(req, &api, &commands, Some(label)),
+                    Err(e) => {
+                        let mut resp = respond(req, &api, 400, &[])?;
+                        resp.write_all(format!("invalid run label: {}\n", e).as_bytes())?;
+                        Ok(())
+                    }
+                }
+            })?;
+
+            let commands = self.commands.clone();
+            let api = self.api.clone();
+            server.fn_handler::<anyhow::Error, _>(&format!("{}/dut", prefix), Method::Delete, move |req| {
+                let req = match authorize(req, &api, Role::Control)? {
+                    Some(req) => req,
+                    None => return Ok(()),
+                };
+                label_request(req, &api, &commands, Some(RunLabel::default()))
+            })?;
+
+            let api = self.api.clone();
+            server.fn_handler::<anyhow::Error, _>(&format!("{}/scripts", prefix), Method::Get, move |req| {
+                let req = match authorize(req, &api, Role::Read)? {
+                    Some(req) => req,
+                    None => return Ok(()),
+                };
+                let json = serde_json::to_string(&testscripts::list())?;
+                let mut resp = respond(req, &api, 200, &[("Content-Type", "application/json")])?;
+                resp.write_all(json.as_bytes())?;
+                Ok(())
+            })?;
+
+            let api = self.api.clone();
+            server.fn_handler::<anyhow::Error, _>(&format!("{}/scripts", prefix), Method::Post, move |req| {
+                let mut req = match authorize(req, &api, Role::Control)? {
+                    Some(req) => req,
+                    None => return Ok(()),
+                };
+                let mut body = Vec::new();
+                let mut buf = [0u8; 512];
+                loop {
+                    let len = req.read(&mut buf)?;
+                    if len == 0 {
+                        break;
+                    }
+                    body.extend_from_slice(&buf[..len]);
+                    if body.len() > MAX_BODY_LEN {
+                        let mut resp = respond(req, &api, 413, &[])?;
+                        resp.write_all(b"script too large\n")?;
+                        return Ok(());
+                    }
+                }
+                let result = std::str::from_utf8(&body)
+                    .map_err(|e| anyhow::anyhow!("{}", e))
+                    .and_then(testscripts::store);
+                match result {
+                    Ok(script) => {
+                        let json = serde_json::json!({ "name": script.get_name(), "steps": script.get_steps().len() }).to_string();
+                        let mut resp = respond(req, &api, 200, &[("Content-Type", "application/json")])?;
+                        resp.write_all(json.as_bytes())?;
+                    },
+                    Err(e) => {
+                        warn!("Script upload rejected: {}", e);
+                        let mut resp = respond(req, &api, 400, &[])?;
+                        resp.write_all(format!("invalid script: {}\n", e).as_bytes())?;
+                    }
+                }
+                Ok(())
+            })?;
+
+            let commands = self.commands.clone();
+            let api = self.api.clone();
+            server.fn_handler::<anyhow::Error, _>(&format!("{}/scripts/run", prefix), Method::Get, move |req| {
+                let req = match authorize(req, &api, Role::Read)? {
+                    Some(req) => req,
+                    None => return Ok(()),
+                };
+                script_request(req, &api, &commands, ScriptRequest::Status)
+            })?;
+
+            let commands = self.commands.clone();
+            let api = self.api.clone();
+            server.fn_handler::<anyhow::Error, _>(&format!("{}/scripts/run", prefix), Method::Post, move |req| {
+                let mut req = match authorize(req, &api, Role::Control)? {
+                    Some(req) => req,
+                    None => return Ok(()),
+                };
+                let mut buf = [0u8; 256];
+                let mut len = 0;
+                while len < buf.len() {
+                    let n = req.read(&mut buf[len..])?;
+                    if n == 0 {
+                        break;
+                    }
+                    len += n;
+                }
+                match serde_json::from_slice::<ScriptRunRequest>(&buf[..len]) {
+                    Ok(r) => script_request(req, &api, &commands, ScriptRequest::Run(r.name)),
+                    Err(e) => {
+                        let mut resp = respond(req, &api, 400, &[])?;
+                        resp.write_all(format!("invalid request: {}\n", e).as_bytes())?;
+                        Ok(())
+                    }
+                }
+            })?;
+
+            let commands = self.commands.clone();
+            let api = self.api.clone();
+            server.fn_handler::<anyhow::Error, _>(&format!("{}/scripts/run", prefix), Method::Delete, move |req| {
+                let req = match authorize(req, &api, Role::Control)? {
+                    Some(req) => req,
+                    None => return Ok(()),
+                };
+                script_request(req, &api, &commands, ScriptRequest::Stop)
+            })?;
+
+            let api = self.api.clone();
+            server.fn_handler::<anyhow::Error, _>(&format!("{}/log", prefix), Method::Get, move |req| {
+                let req = match authorize(req, &api, Role::Read)? {
+                    Some(req) => req,
+                    None => return Ok(()),
+                };
+                let mut resp = respond(req, &api, 200, &[])?;
+                resp.write_all(log_filters(&logfilter::SINKS).as_bytes())?;
+                Ok(())
+            })?;
+
+            let routes : [(&str, &'static [Sink]); 3] = [
+                ("/log", &[Sink::Console, Sink::Syslog]),
+                ("/log/console", &[Sink::Console]),
+                ("/log/syslog", &[Sink::Syslog]),
+            ];
+            for (uri, sinks) in routes {
+                let api = self.api.clone();
+                server.fn_handler::<anyhow::Error, _>(&format!("{}{}", prefix, uri), Method::Put, move |req| {
+                    let mut req = match authorize(req, &api, Role::Control)? {
+                        Some(req) => req,
+                        None => return Ok(()),
+                    };
+                    let mut buf = [0u8; 256];
+                    let mut len = 0;
+                    while len < buf.len() {
+                        let n = req.read(&mut buf[len..])?;
+                        if n == 0 {
+                            break;
+                        }
+                        len += n;
+                    }
+                    if len == buf.len() {
+                        let mut resp = respond(req, &api, 413, &[])?;
+                        resp.write_all(b"log filter too long\n")?;
+                        return Ok(());
+                    }
+                    let result = std::str::from_utf8(&buf[..len])
+                        .map_err(|e| anyhow::anyhow!("{}", e))
+                        .and_then(|spec| LogFilter::parse(spec.trim()));
+                    match result {
+                        Ok(filter) => {
+                            for sink in sinks {
+                                logfilter::set(*sink, filter.clone());
+                            }
+                            let mut resp = respond(req, &api, 200, &[])?;
+                            resp.write_all(log_filters(sinks).as_bytes())?;
+                        },
+                        Err(e) => {
+                            let mut resp = respond(req, &api, 400, &[])?;
+                            resp.write_all(format!("invalid log filter: {}\n", e).as_bytes())?;
+                        }
+                    }
+                    Ok(())
+                })?;
+            }
+
+            // CORS preflight, answered without a token
+            for path in PATHS {
+                let api = self.api.clone();
+                server.fn_handler::<anyhow::Error, _>(&format!("{}{}", prefix, path), Method::Options, move |req| {
+                    respond(req, &api, 204, &[
+                        ("Access-Control-Allow-Methods", "GET, POST, PUT, DELETE, OPTIONS"),
+                        ("Access-Control-Allow-Headers", "Authorization, Content-Type, X-Unlock-Code"),
+                        ("Access-Control-Max-Age", "600"),
+                    ])?;
+                    Ok(())
+                })?;
+            }
+        }
+
+        info!("HTTP server started");
+        self.server = Some(server);
+        Ok(())
+    }
+}
+
+// A response with the CORS header of the policy
+fn respond<'a, 'b>(req: Request<&'a mut EspHttpConnection<'b>>, api: &ApiHandle, status: u16, headers: &[(&str, &str)]) -> anyhow::Result<Response<&'a mut EspHttpConnection<'b>>> {
+    let origin = api.lock().unwrap().cors_origin.clone();
+    let mut all = headers.to_vec();
+    if !origin.is_empty() {
+        all.push(("Access-Control-Allow-Origin", origin.as_str()));
+        if origin != "*" {
+            all.push(("Vary", "Origin"));
+        }
+    }
+    Ok(req.into_response(status, None, &all)?)
+}
+
+// The request if its token has the role, otherwise it is answered with 401 or 403
+fn authorize<'a, 'b>(req: Request<&'a mut EspHttpConnection<'b>>, api: &ApiHandle, role: Role) -> anyhow::Result<Option<Request<&'a mut EspHttpConnection<'b>>>> {
+    let result = api.lock().unwrap().tokens.check(req.header("Authorization"), role);
+    match result {
+        Access::Granted => Ok(Some(req)),
+        Access::Unauthorized => {
+            info!("HTTP {}: no valid token for {}", req.uri(), role.as_str());
+            let mut resp = respond(req, api, 401, &[("WWW-Authenticate", "Bearer")])?;
+            resp.write_all(format!("{} token required\n", role.as_str()).as_bytes())?;
+            Ok(None)
+        },
+        Access::Forbidden => {
+            info!("HTTP {}: read token used for control", req.uri());
+            let mut resp = respond(req, api, 403, &[])?;
+            resp.write_all(b"control token required\n")?;
+            Ok(None)
+        },
+    }
+}
+
+// Pass a PID change to the main loop and respond with the resulting gains
+fn pid_request(req: Request<&mut EspHttpConnection>, api: &ApiHandle, commands: &Sender<Command>, change: PidChange) -> anyhow::Result<()> {
+    let (reply, result) = channel();
+    commands.send(Command::Pid(change, reply))?;
+    match result.recv_timeout(EXPORT_TIMEOUT) {
+        Ok(Ok(gains)) => {
+            let json = serde_json::to_string(&gains)?;
+            let mut resp = respond(req, api, 200, &[("Content-Type", "application/json")])?;
+            resp.write_all(json.as_bytes())?;
+        },
+        Ok(Err(e)) => {
+            let mut resp = respond(req, api, 400, &[])?;
+            resp.write_all(format!("invalid PID gains: {}\n", e).as_bytes())?;
+        },
+        Err(_) => {
+            let mut resp = respond(req, api, 503, &[])?;
+            resp.write_all(b"main loop busy\n")?;
+        }
+    }
+    Ok(())
+}
+
+// Pass an offset calibration request to the main loop and respond with the calibration state
+fn calibration_request(req: Request<&mut EspHttpConnection>, api: &ApiHandle, commands: &Sender<Command>, action: CalibrationAction) -> anyhow::Result<()> {
+    let (reply, result) = channel();
+    commands.send(Command::Calibration(action, reply))?;
+    match result.recv_timeout(EXPORT_TIMEOUT) {
+        Ok(Ok(status)) => {
+            let offsets: Vec<_> = status.offsets().iter().enumerate().map(|(index, offsets)| serde_json::json!({
+                "channel": index + 1,
+                "current_offset": offsets.current_a,
+                "voltage_offset": offsets.voltage_v,
+                "sense_offset": offsets.sense_v,
+            })).collect();
+            let json = serde_json::json!({
+                "state": status.state.name(),
+                "progress": status.percent,
+                "offsets": offsets,
+                "error": status.error(),
+            }).to_string();
+            let mut resp = respond(req, api, 200, &[("Content-Type", "application/json")])?;
+            resp.write_all(json.as_bytes())?;
+        },
+        Ok(Err(e)) => {
+            let mut resp = respond(req, api, 409, &[])?;
+            resp.write_all(format!("{}\n", e).as_bytes())?;
+        },
+        Err(_) => {
+            let mut resp = respond(req, api, 503, &[])?;
+            resp.write_all(b"main loop busy\n")?;
+        }
+    }
+    Ok(())
+}
+
+// Pass a test script request to the main loop and respond with the state of the run
+fn script_request(req: Request<&mut EspHttpConnection>, api: &ApiHandle, commands: &Sender<Command>, request: ScriptRequest) -> anyhow::Result<()> {
+    let (reply, result) = channel();
+    commands.send(Command::Script(request, reply))?;
+    match result.recv_timeout(EXPORT_TIMEOUT) {
+        Ok(Ok(status)) => {
+            let checks: Vec<_> = status.checks.iter().map(|check| serde_json::json!({
+                "step": check.step + 1,
+                "check": check.text,
+                "pass": check.pass,
+                "value": check.value,
+            })).collect();
+            let reason = match &status.state {
+                ScriptState::Aborted(reason) => Some(reason.as_str()),
+                _ => None,
+            };
+            let json = serde_json::json!({
+                "name": status.name,
+                "state": status.state.name(),
+                "step": status.step,
+                "steps": status.steps,
+                "checks": checks,
+                "reason": reason,
+            }).to_string();
+            let mut resp = respond(req, api, 200, &[("Content-Type", "application/json")])?;
+            resp.write_all(json.as_bytes())?;
+        },
+        Ok(Err(e)) => {
+            let mut resp = respond(req, api, 409, &[])?;
+            resp.write_all(format!("{}\n", e).as_bytes())?;
+        },
+        Err(_) => {
+            let mut resp = respond(req, api, 503, &[])?;
+            resp.write_all(b"main loop busy\n")?;
+        }
+    }
+    Ok(())
+}
+
+// Pass a run label change to the main loop and respond with the label in effect
+fn label_request(req: Request<&mut EspHttpConnection>, api: &ApiHandle, commands: &Sender<Command>, label: Option<RunLabel>) -> anyhow::Result<()> {
+    let (reply, result) = channel();
+    commands.send(Command::Label(label, reply))?;
+    match result.recv_timeout(EXPORT_TIMEOUT) {
+        Ok(label) => {
+            let json = serde_json::json!({ "dut": label.dut, "note": label.note }).to_string();
+            let mut resp = respond(req, api, 200, &[("Content-Type", "application/json")])?;
+            resp.write_all(json.as_bytes())?;
+        },
+        Err(_) => {
+            let mut resp = respond(req, api, 503, &[])?;
+            resp.write_all(b"main loop busy\n")?;
+        }
+    }
+    Ok(())
+}
+
+fn log_filters(sinks: &[Sink]) -> String {
+    sinks.iter().map(|sink| format!("{}={}\n", sink.name(), logfilter::get(*sink))).collect()
+}
@@ -0,0 +1,125 @@
+// I2C bus health check, scan and recovery
+// The control task pings the INA228s and the AP33772S once per second and counts the
+// outcome of every transfer (see dcpower_control::i2chealth). When the bus hangs (a device
+// holds SDA low after an interrupted transfer), the pins are taken from the I2C peripheral
+// and SCL is pulsed until SDA is released, followed by a STOP condition.
+// The state of the devices is published for the health telemetry (/health).
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering};
+use esp_idf_hal::delay::TickType;
+use esp_idf_hal::i2c::I2cDriver;
+use esp_idf_sys::*;
+use dcpower_control::i2chealth::{DeviceHealth, I2cHealth, I2cOutcome};
+use crate::error::Error;
+
+// AP33772S USB PD sink controller (7-bit address)
+pub const AP33772S_ADDR: u8 = 0x52;
+
+// I2C0 pins (main.rs)
+const I2C_PORT: i2c_port_t = 0;
+const I2C_SCL_GPIO: i32 = 47;
+const I2C_SDA_GPIO: i32 = 21;
+
+// A device answers a ping within a few transfers at 400kHz
+const PING_TIMEOUT_MS: u64 = 10;
+// A device holding SDA releases it within 9 clocks (8 data bits and the ACK)
+const RECOVERY_PULSES: u32 = 9;
+// 100kHz during the recovery
+const RECOVERY_HALF_PERIOD_US: u32 = 5;
+// Scanned addresses, without the reserved ones
+const SCAN_FIRST_ADDR: u8 = 0x08;
+const SCAN_LAST_ADDR: u8 = 0x77;
+
+static DEVICES: Mutex<Vec<DeviceHealth>> = Mutex::new(Vec::new());
+static RECOVERY_COUNT: AtomicU32 = AtomicU32::new(0);
+static RECOVERY_FAILED_COUNT: AtomicU32 = AtomicU32::new(0);
+
+// State of the devices at the last publish
+pub fn devices() -> Vec<DeviceHealth> {
+    DEVICES.lock().unwrap().clone()
+}
+
+pub fn recovery_count() -> u32 {
+    RECOVERY_COUNT.load(Ordering::Relaxed)
+}
+
+pub fn recovery_failed_count() -> u32 {
+    RECOVERY_FAILED_COUNT.load(Ordering::Relaxed)
+}
+
+pub fn publish(health: &I2cHealth) {
+    *DEVICES.lock().unwrap() = health.get_devices().to_vec();
+    RECOVERY_COUNT.store(health.get_recoveries(), Ordering::Relaxed);
+    RECOVERY_FAILED_COUNT.store(health.get_failed_recoveries(), Ordering::Relaxed);
+}
+
+// Outcome of a transfer: a NACK fails with ESP_FAIL, a hung bus with ESP_ERR_TIMEOUT
+pub fn outcome<T>(result: &Result<T, EspError>) -> I2cOutcome {
+    match result {
+        Ok(_) => I2cOutcome::Ok,
+        Err(e) if e.code() == ESP_ERR_TIMEOUT as i32 => I2cOutcome::Timeout,
+        Err(_) => I2cOutcome::Nack,
+    }
+}
+
+// Outcome of a driver operation (ina228, usbpd)
+pub fn error_outcome(e: &Error) -> I2cOutcome {
+    match e {
+        Error::I2cTimeout(_) => I2cOutcome::Timeout,
+        _ => I2cOutcome::Nack,
+    }
+}
+
+// Read one byte from the device (the register pointer is not changed)
+pub fn ping(i2cdrv: &mut I2cDriver, addr: u8) -> I2cOutcome {
+    let mut data = [0u8; 1];
+    outcome(&i2cdrv.read(addr, &mut data, TickType::new_millis(PING_TIMEOUT_MS).ticks()))
+}
+
+// Addresses which acknowledge a ping on the selected side of the bus
+pub fn scan(i2cdrv: &mut I2cDriver) -> Vec<u8> {
+    (SCAN_FIRST_ADDR..=SCAN_LAST_ADDR).filter(|addr| ping(i2cdrv, *addr) == I2cOutcome::Ok).collect()
+}
+
+// Clock out a device holding SDA and send a STOP. The pins are given back to the I2C
+// peripheral afterwards. Returns true if SDA is released.
+pub fn recover() -> bool {
+    let released;
+    unsafe {
+        for pin in [I2C_SCL_GPIO, I2C_SDA_GPIO] {
+            gpio_reset_pin(pin);
+            gpio_set_direction(pin, gpio_mode_t_GPIO_MODE_INPUT_OUTPUT_OD);
+            gpio_set_pull_mode(pin, gpio_pull_mode_t_GPIO_PULLUP_ONLY);
+            gpio_set_level(pin, 1);
+        }
+        esp_rom_delay_us(RECOVERY_HALF_PERIOD_US);
+        for _ in 0..RECOVERY_PULSES {
+            if gpio_get_level(I2C_SDA_GPIO) != 0 {
+                break;
+            }
+            gpio_set_level(I2C_SCL_GPIO, 0);
+            esp_rom_delay_us(RECOVERY_HALF_PERIOD_US);
+            gpio_set_level(I2C_SCL_GPIO, 1);
+            esp_rom_delay_us(RECOVERY_HALF_PERIOD_US);
+        }
+        // STOP: SDA rises while SCL is high
+        gpio_set_level(I2C_SCL_GPIO, 0);
+        esp_rom_delay_us(RECOVERY_HALF_PERIOD_US);
+        gpio_set_level(I2C_SDA_GPIO, 0);
+        esp_rom_delay_us(RECOVERY_HALF_PERIOD_US);
+        gpio_set_level(I2C_SCL_GPIO, 1);
+        esp_rom_delay_us(RECOVERY_HALF_PERIOD_US);
+        gpio_set_level(I2C_SDA_GPIO, 1);
+        esp_rom_delay_us(RECOVERY_HALF_PERIOD_US);
+        released = gpio_get_level(I2C_SDA_GPIO) != 0;
+        i2c_set_pin(I2C_PORT, I2C_SDA_GPIO, I2C_SCL_GPIO, true, true, i2c_mode_t_I2C_MODE_MASTER);
+        i2c_reset_tx_fifo(I2C_PORT);
+        i2c_reset_rx_fifo(I2C_PORT);
+    }
+    released
+}
@@ -0,0 +1,136 @@
+// INA228 current/voltage/power monitor
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use log::*;
+use std::thread;
+use std::time::Duration;
+use esp_idf_hal::i2c;
+use esp_idf_hal::delay::BLOCK;
+use crate::error::{Error, Result};
+
+// Output channel 1 (A0 = GND) and channel 2 (A0 = VS)
+pub const INA228_ADDR: u8 = 0x40;
+pub const INA228_CH2_ADDR: u8 = 0x41;
+// Remote sense of channel 1 (A1 = VS, A0 = GND), voltage only
+pub const INA228_SENSE_ADDR: u8 = 0x44;
+
+pub fn current_read(i2cdrv: &mut i2c::I2cDriver, addr: u8, current_lsb: f32) -> Result<f32> {
+    let mut curt_buf  = [0u8; 3];
+    i2cdrv.write(addr, &[0x07u8; 1], BLOCK)?;
+    match i2cdrv.read(addr, &mut curt_buf, BLOCK) {
+        Ok(_v) => {
+            let current_reg : f32;
+            if curt_buf[0] & 0x80 == 0x80 {
+                current_reg = (0x100000 - (((curt_buf[0] as u32) << 16 | (curt_buf[1] as u32) << 8 | (curt_buf[2] as u32)) >> 4)) as f32 * -1.0;
+            }
+            else {
+                current_reg = (((curt_buf[0] as u32) << 16 | (curt_buf[1] as u32) << 8 | (curt_buf[2] as u32)) >> 4) as f32;
+            }
+            return Ok(current_lsb * current_reg);
+        },
+        Err(e) => {
+            debug!("{:?}", e);
+            return Err(Error::i2c(e, "Current Read Error"));
+        }
+    }
+}
+
+pub fn voltage_read(i2cdrv: &mut i2c::I2cDriver, addr: u8) -> Result<f32> {
+    let mut vbus_buf  = [0u8; 3];
+    i2cdrv.write(addr, &[0x05u8; 1], BLOCK)?;
+    match i2cdrv.read(addr, &mut vbus_buf, BLOCK){
+        Ok(_v) => {
+            let vbus = ((((vbus_buf[0] as u32) << 16 | (vbus_buf[1] as u32) << 8 | (vbus_buf[2] as u32)) >> 4) as f32 * 195.3125) / 1000_000.0;
+            // info!("vbus_buf={:?} vbus={:?}", vbus_buf, vbus);
+            return Ok(vbus);
+        },
+        Err(e) => {
+            debug!("{:?}", e);
+            return Err(Error::i2c(e, "Voltage Read Error"));
+        }
+    }
+}
+
+pub fn power_read(i2cdrv: &mut i2c::I2cDriver, addr: u8, current_lsb: f32) -> Result<f32> {
+    let mut power_buf = [0u8; 3];
+    i2cdrv.write(addr, &[0x08u8; 1], BLOCK)?;
+    match i2cdrv.read(addr, &mut power_buf, BLOCK) {
+        Ok(_v) => {
+            let power_reg = ((power_buf[0] as u32) << 16 | (power_buf[1] as u32) << 8 | (power_buf[2] as u32)) as f32;
+            let power = 3.2 * current_lsb * power_reg;
+            return Ok(power);
+        },
+        Err(e) => {
+            debug!("{:?}", e);
+            return Err(Error::i2c(e, "Power Read Error"));
+        }
+    }
+}
+
+pub fn temperature_read(i2cdrv: &mut i2c::I2cDriver, addr: u8) -> Result<f32> {
+    // DIETEMP: 16-bit two's complement, 7.8125 m°C/LSB
+    let dietemp = read_reg16(i2cdrv, addr, 0x06)? as i16;
+    let temp = dietemp as f32 * 7.8125 / 1000.0;
+    if !(-40.0..=150.0).contains(&temp) {
+        return Err(Error::Sensor(format!("DIETEMP out of range: {:.1}", temp)));
+    }
+    Ok(temp)
+}
+
+// Burst of bus voltage readings back to back for the ripple estimation: continuous bus voltage
+// only, 50us conversion, no averaging. The ADC configuration is restored afterwards.
+// Returns the readings and the duration of the burst in us.
+pub fn voltage_burst(i2cdrv: &mut i2c::I2cDriver, addr: u8, count: usize) -> Result<(Vec<f32>, u64)> {
+    let adc_config = read_reg16(i2cdrv, addr, 0x01)?;
+    write_reg16(i2cdrv, addr, 0x01, 0x9000)?; // MODE=9h: Continuous bus voltage only, VBUSCT=50us, AVG=1
+    let start = unsafe { esp_idf_sys::esp_timer_get_time() };
+    let readings = (0..count).map(|_| voltage_read(i2cdrv, addr)).collect::<Result<Vec<f32>>>();
+    let duration = unsafe { esp_idf_sys::esp_timer_get_time() } - start;
+    write_reg16(i2cdrv, addr, 0x01, adc_config)?;
+    Ok((readings?, duration as u64))
+}
+
+pub fn write_reg16(i2cdrv: &mut i2c::I2cDriver, addr: u8, reg: u8, value: u16) -> Result<()> {
+    let mut config = [0u8; 3];
+    config[0] = reg;
+    config[1] = (value >> 8) as u8;
+    config[2] = value as u8;
+    i2cdrv.write(addr, &config, BLOCK)?;
+    Ok(())
+}
+
+pub fn read_reg16(i2cdrv: &mut i2c::I2cDriver, addr: u8, reg: u8) -> Result<u16> {
+    let mut data = [0u8; 2];
+    i2cdrv.write(addr, &[reg; 1], BLOCK)?;
+    i2cdrv.read(addr, &mut data, BLOCK)?;
+    // info!("INA228 Reg {:02x} Read: {:02x} {:02x}", reg, data[0], data[1]);
+    Ok(((data[0] as u16) << 8) | (data[1] as u16))
+}
+
+// Readings averaged by the offset calibration, 10ms apart
+pub const CALIBRATION_SAMPLES: u32 = 300;
+
+// The progress is called with the readings taken, every 30 readings
+pub fn calibration(i2cdrv: &mut i2c::I2cDriver, addr: u8, current_lsb: f32, progress: &mut dyn FnMut(u32)) -> Result<(f32, f32)> {
+    // INA228 Calibration
+    // calibration read
+    let mut average_current_offset = 0.0;
+    let mut voltage_offset = 0.0;
+    for i in 0..CALIBRATION_SAMPLES {
+        let read_current = current_read(i2cdrv, addr, current_lsb)?;
+        average_current_offset += read_current;
+        let read_voltage = voltage_read(i2cdrv, addr)?;
+        voltage_offset += read_voltage;
+        thread::sleep(Duration::from_millis(10));
+        if (i + 1) % 30 == 0 {
+            progress(i + 1);
+        }
+    }
+    average_current_offset /= CALIBRATION_SAMPLES as f32;
+    voltage_offset /= CALIBRATION_SAMPLES as f32;
+    info!("Average Current Offset: {:.3}A Voltage Offset: {:.3}V", average_current_offset, voltage_offset);
+    Ok((average_current_offset, voltage_offset))
+}
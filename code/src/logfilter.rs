@@ -0,0 +1,142 @@
+// Runtime log level and per-module filtering
+// A filter is a comma separated list of a default level and module=level directives,
+// e.g. "info,usbpd=debug". A module name without "::" is a module of this firmware.
+// The console and syslog have independent filters. The console filter is also applied
+// to the ESP logger (per target), which checks the ESP-IDF tag levels.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use log::{LevelFilter, Metadata};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+const CRATE_NAME: &str = env!("CARGO_CRATE_NAME");
+
+static CONSOLE_FILTER: RwLock<LogFilter> = RwLock::new(LogFilter::new(LevelFilter::Info));
+static SYSLOG_FILTER: RwLock<LogFilter> = RwLock::new(LogFilter::new(LevelFilter::Info));
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sink {
+    Console,
+    Syslog,
+}
+
+impl Sink {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Sink::Console => "console",
+            Sink::Syslog => "syslog",
+        }
+    }
+
+    fn filter(&self) -> &'static RwLock<LogFilter> {
+        match self {
+            Sink::Console => &CONSOLE_FILTER,
+            Sink::Syslog => &SYSLOG_FILTER,
+        }
+    }
+}
+
+pub const SINKS: [Sink; 2] = [Sink::Console, Sink::Syslog];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogFilter {
+    pub default: LevelFilter,
+    pub modules: Vec<(String, LevelFilter)>,
+}
+
+impl LogFilter {
+    pub const fn new(default: LevelFilter) -> LogFilter {
+        LogFilter { default: default, modules: Vec::new() }
+    }
+
+    pub fn parse(spec: &str) -> anyhow::Result<LogFilter> {
+        let mut filter = LogFilter::new(LevelFilter::Info);
+        for directive in spec.split(',').map(|d| d.trim()).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((module, level)) => {
+                    let module = module.trim();
+                    if module.is_empty() {
+                        anyhow::bail!("missing module name: {}", directive);
+                    }
+                    let target = module_target(module);
+                    let level = parse_level(level.trim())?;
+                    filter.modules.retain(|(t, _)| *t != target);
+                    filter.modules.push((target, level));
+                },
+                None => {
+                    filter.default = parse_level(directive)?;
+                }
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules.iter()
+            .find(|(t, _)| t == target)
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default)
+    }
+
+    // Highest level of all directives, for the global max level of the log crate
+    pub fn max_level(&self) -> LevelFilter {
+        self.modules.iter().map(|(_, level)| *level).fold(self.default, |a, b| a.max(b))
+    }
+}
+
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.default.as_str().to_lowercase())?;
+        for (target, level) in &self.modules {
+            write!(f, ",{}={}", target, level.as_str().to_lowercase())?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_level(level: &str) -> anyhow::Result<LevelFilter> {
+    LevelFilter::from_str(level).map_err(|_| anyhow::anyhow!("invalid log level: {} (off, error, warn, info, debug, trace)", level))
+}
+
+fn module_target(module: &str) -> String {
+    if module.contains("::") || module == CRATE_NAME {
+        module.to_string()
+    }
+    else {
+        format!("{}::{}", CRATE_NAME, module)
+    }
+}
+
+// Filter from a setting, info if the setting is invalid
+pub fn from_setting(name: &str, spec: &str) -> LogFilter {
+    LogFilter::parse(spec).unwrap_or_else(|e| {
+        println!("Invalid {}: {}, using info", name, e);
+        LogFilter::new(LevelFilter::Info)
+    })
+}
+
+pub fn get(sink: Sink) -> LogFilter {
+    sink.filter().read().unwrap().clone()
+}
+
+// Replace the filter of a sink and update the max level of the log crate
+pub fn set(sink: Sink, filter: LogFilter) {
+    if sink == Sink::Console {
+        // "*" resets the levels of all the tags, so the module levels are set after it.
+        // The default level also applies to the ESP-IDF components.
+        let _ = esp_idf_svc::log::set_target_level("*", filter.default);
+        for (target, level) in &filter.modules {
+            let _ = esp_idf_svc::log::set_target_level(target, *level);
+        }
+    }
+    *sink.filter().write().unwrap() = filter;
+    log::set_max_level(get(Sink::Console).max_level().max(get(Sink::Syslog).max_level()));
+}
+
+pub fn enabled(sink: Sink, metadata: &Metadata) -> bool {
+    metadata.level() <= sink.filter().read().unwrap().level_for(metadata.target())
+}
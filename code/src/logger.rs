@@ -0,0 +1,48 @@
+// Composite logger: every record goes to the serial console (ESP logger) and, once it is
+// started, to the syslog server. Each output has its own level filter (see logfilter.rs).
+// Until the network is up, the records for syslog are kept in the boot log (bootlog.rs).
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use log::{Log, Record, Metadata};
+use esp_idf_svc::log::EspLogger;
+use crate::logfilter::{self, Sink};
+use crate::syslogger;
+use crate::bootlog;
+
+static CONSOLE_LOGGER: EspLogger = EspLogger::new();
+static LOGGER: DualLogger = DualLogger;
+
+pub struct DualLogger;
+
+impl Log for DualLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        logfilter::enabled(Sink::Console, metadata) || logfilter::enabled(Sink::Syslog, metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if logfilter::enabled(Sink::Console, record.metadata()) {
+            CONSOLE_LOGGER.log(record);
+        }
+        if logfilter::enabled(Sink::Syslog, record.metadata()) {
+            bootlog::capture(record);
+        }
+        syslogger::STATIC_LOGGER.log(record);
+    }
+
+    fn flush(&self) {
+        CONSOLE_LOGGER.flush();
+        syslogger::STATIC_LOGGER.flush();
+    }
+}
+
+// Install the logger with the console and syslog filters. Call once at boot.
+pub fn init(console_filter: logfilter::LogFilter, syslog_filter: logfilter::LogFilter) {
+    if let Err(e) = log::set_logger(&LOGGER) {
+        println!("Failed to set the logger: {:?}", e);
+    }
+    logfilter::set(Sink::Console, console_filter);
+    logfilter::set(Sink::Syslog, syslog_filter);
+}
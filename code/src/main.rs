@@ -4,11 +4,11 @@
 
 use std::{thread, time::Duration};
 use esp_idf_hal::{gpio::*, prelude::*, spi, i2c};
-use esp_idf_hal::delay::BLOCK;
 use esp_idf_hal::peripherals::Peripherals;
 use embedded_hal::spi::MODE_0;
 use log::*;
 use std::time::SystemTime;
+use std::sync::{Arc, Mutex};
 use esp_idf_hal::adc::oneshot::config::AdcChannelConfig as AdcConfig;
 use esp_idf_hal::adc::oneshot::config::Calibration;
 use esp_idf_hal::adc::oneshot::*;
@@ -17,28 +17,163 @@ use esp_idf_hal::ledc::config::TimerConfig;
 use esp_idf_hal::ledc::LedcTimerDriver;
 use esp_idf_hal::ledc::LedcDriver;
 use esp_idf_svc::sntp::{EspSntp, SyncStatus, SntpConf, OperatingMode, SyncMode};
-use esp_idf_svc::wifi::EspWifi;
 use esp_idf_svc::nvs::*;
 use chrono::{DateTime, Utc};
 
 mod displayctl;
-mod currentlogs;
 mod wifi;
 mod transfer;
 mod touchpad;
-mod pidcont;
 mod usbpd;
 mod syslogger;  // Add the syslogger module
+mod tempmon;
+mod settings;
+mod menu;
+mod console;
+mod error;
+mod ina228;
+mod configfile;
+mod httpserver;
+mod health;
+mod crashdump;
+mod bus;
+mod logfilter;
+mod logger;
+mod bootlog;
+mod version;
+mod capabilities;
+mod controltimer;
+mod controltask;
+mod i2cbus;
+mod sessionreport;
+mod webhook;
+mod alerts;
+mod syncnet;
+mod sharenet;
+mod espnowtx;
+mod timebase;
+mod hostlink;
+mod rawstream;
+mod perfcounters;
+mod testscripts;
 
-use displayctl::{DisplayPanel, LoggingStatus, WifiStatus};
-use currentlogs::{CurrentRecord, CurrentLog};
-use transfer::{Transfer, ServerInfo};
-use touchpad::{TouchPad, KeyEvent, Key};
-use pidcont::PIDController;
+use displayctl::{DisplayPanel, LoggingStatus, WifiStatus, InterlockStatus};
+use dcpower_control::currentlogs::{CurrentRecord, CurrentLog, BufferPolicy, RegulationMode, TrendHistory, TREND_POINTS, TREND_SECS};
+use transfer::{Transfer, TransferAck, ServerInfo};
+use sessionreport::SessionReports;
+use alerts::Alerts;
+use syncnet::SyncNet;
+use sharenet::ShareNet;
+use espnowtx::EspNowTx;
+use wifi::{WifiManager, WifiState};
+use hostlink::{HostCommand, HostRequest, HostSample};
+use rawstream::RawTap;
+use serde_json::{json, Value};
+use touchpad::{TouchPad, KeyEvent, KeyInput, Key};
+use dcpower_control::regulator::Regulator;
+use dcpower_control::latency::LatencyStats;
+use dcpower_control::gesture::{ButtonDebounce, StartStopGesture, Tap, TapDetector, DOUBLE_TAP_MS};
+use dcpower_control::limits::{LimitKind, LimitPreWarning, LimitWarning, ProtectionLimits, SafeMode, SAFE_MODE_MAX_CURRENT, SAFE_MODE_MAX_VOLTAGE};
 use usbpd::{AP33772S, PDVoltage};
+use tempmon::{TempMonitor, TempSensorFault};
+use dcpower_control::recovery::{RecoveryPolicy, RecoveryAction, TripCause};
+use dcpower_control::regstats::RegulationReport;
+use dcpower_control::cable::CableTest;
+use dcpower_control::capture::{Capture, CaptureTrigger};
+use dcpower_control::summary::SummaryLog;
+use dcpower_control::session::{SessionTracker, RunLabel};
+use dcpower_control::i2chealth::DeviceEvent;
+use dcpower_control::schedule::{Schedule, ScheduleAction};
+use dcpower_control::alarms::{AlarmEvent, AlarmValues, Alarms};
+use dcpower_control::consistency::PowerCheckEvent;
+use dcpower_control::marker::{Marker, MarkerSource, Markers};
+use dcpower_control::scopetrigger::{TriggerConfig, TriggerSource};
+use dcpower_control::cycle::{CycleTest, CyclePhase};
+use dcpower_control::testscript::{ScriptCommand, ScriptRun, ScriptState, ScriptStatus};
+use testscripts::ScriptRequest;
+use dcpower_control::pdprobe::{PdProbe, PdoPoint};
+use dcpower_control::efficiency::EfficiencyMeter;
+use dcpower_control::pdcal::PdVoltageCalibration;
+use dcpower_control::offsetcal::{CalibrationAction, CalibrationStatus, OffsetCalibration};
+use dcpower_control::prodtest::{ProductionTest, TestStep};
+use dcpower_control::errorcounters::{Counter, ErrorCounters};
+use dcpower_control::softlimit::{CurrentLimitMode, SoftCurrentLimit, SoftLimitAction};
+use dcpower_control::thermal::ThermalModel;
+use dcpower_control::pwmoffset::{OffsetLearning, OffsetTable};
+use dcpower_control::bleed::Bleed;
+use dcpower_control::stepdown::{StepDownEvent, STEP_DOWN_MIN_V};
+use dcpower_control::adcfilter::AdcFilter;
+use dcpower_control::unitsync::{SyncAction, SyncMessage, SyncScheduler};
+use dcpower_control::share::{Droop, ShareMessage, ShareMode, ShareSlave, ShareUpdate, SHARE_TIMEOUT_MS};
+use dcpower_control::peertelemetry::{PeerChannel, PeerTelemetry};
+use dcpower_control::limitlog::LimitLog;
+use dcpower_control::uploadthrottle::UploadThrottle;
+use dcpower_control::idlesleep::IdleSleep;
+use settings::{Settings, PowerOnMode, PidGains, PidChange, PidUpdate, PROTECTED_FIELDS};
+use menu::{SettingsMenu, MenuItem, MenuAction};
+use console::{Console, ConsoleCommand};
+use configfile::ConfigFile;
+use httpserver::{ApiPolicy, HttpServer};
+use health::HealthMonitor;
+use capabilities::Capabilities;
+use controltask::{ControlTask, ControlHardware, ChannelHardware, ControlCommand, ControlEvent, CH1, CH2};
+use bus::{CommandBus, Command};
+use logfilter::{LogFilter, Sink};
 
 const ADCRANGE : bool = true; // true: 40.96mV, false: 163.84mV
 
+// USB PD rail sag handling (counts of 10ms loop)
+const PD_SAG_HOLDOFF_COUNT : u32 = 50;        // settling time after a PD request
+const PD_SAG_WARN_COUNT : u32 = 10;           // sag duration to reduce the current limit
+const PD_SAG_RENEGOTIATE_COUNT : u32 = 300;   // sag duration to renegotiate a lower power point
+const PD_SAG_CURRENT_DERATE : f32 = 0.8;
+const PD_MIN_REQUEST_CURRENT_MA : u16 = 1000;
+const PD_MAX_REQUEST_CURRENT_MA : u16 = 5000;
+
+// Lowest session current limit set from the front panel
+const SESSION_CURRENT_LIMIT_MIN : f32 = 0.01;
+// and power limit
+const SESSION_POWER_LIMIT_MIN : f32 = 0.1;
+
+// Cable resistance test: hold time of each setpoint before and while averaging,
+// and the input current read interval (10ms/loop)
+const CABLE_TEST_SETTLE_MS : u32 = 1000;
+const CABLE_TEST_MEASURE_MS : u32 = 2000;
+const CABLE_TEST_SAMPLE_COUNT : u32 = 10;
+// Input current reading for the efficiency (1s)
+const EFFICIENCY_SAMPLE_COUNT : u32 = 100;
+// Rail voltage read from the AP33772S during the divider calibration, every 100ms
+const PD_CAL_SAMPLE_COUNT : u32 = 10;
+
+// Log buffer: PSRAM left for the WiFi/TLS buffers, and the records without PSRAM
+const LOG_BUFFER_PSRAM_RESERVE : usize = 512 * 1024;
+const LOG_BUFFER_INTERNAL_RECORDS : usize = 4095;
+
+// Full-rate logging after a trip with the output off (10ms/loop)
+const FAULT_FULL_RATE_COUNT : u32 = 6000;
+
+// The wall clock is not set before this (2023-11-14), so the schedule waits for NTP
+const WALL_CLOCK_VALID_SECS : u64 = 1_700_000_000;
+
+// Loops per second of the main loop (10ms/loop)
+const LOOPS_PER_SEC : u32 = 100;
+// The panel unlocked in the kiosk mode locks again without a key for this long
+const KIOSK_RELOCK_SECS : u32 = 300;
+// Steps of the setpoint with Up/Down (V or A), Center+Up/Down selects one
+const ADJUST_STEPS : [f32; 3] = [1.0, 0.1, 0.01];
+const ADJUST_STEP_DEFAULT : usize = 1;
+
+// Bucket of the latency from a touch to the action of the key (us)
+const INPUT_LATENCY_BUCKET_US : u32 = 1000;
+
+// Health telemetry sampling interval (10ms/loop)
+const HEALTH_SAMPLE_COUNT : u32 = 1000;
+
+// Factory reset: Up+Down within this many loops after boot (10ms/loop)
+const FACTORY_RESET_BOOT_WINDOW_COUNT : u32 = 500;
+
+// Compile-time defaults from cfg.toml. The fields are typed: numbers and booleans are
+// written without quotes (floats with a decimal point), so a malformed value fails the build.
 #[toml_cfg::toml_config]
 pub struct Config {
     #[default("")]
@@ -47,26 +182,66 @@ pub struct Config {
     wifi_psk: &'static str,
     #[default("")]
     influxdb_server: &'static str,
-    #[default("0.00001")]
-    pid_kp: &'static str,
-    #[default("0.05")]
-    pid_ki: &'static str,
-    #[default("0.000001")]
-    pid_kd: &'static str,
-    #[default("4500")]
-    pwm_offset: &'static str,
-    #[default("0.0")]
-    pd_config_offset: &'static str,
-    #[default("0.0")]
-    shunt_resistance: &'static str,
-    #[default("50")]
-    shunt_temp_coefficient: &'static str,
-    #[default("11.0")]
-    max_current_limit: &'static str,
-    #[default("110.0")]
-    max_power_limit: &'static str,
-    #[default("75.0")]
-    max_temperature: &'static str,
+    #[default(0.00001)]
+    pid_kp: f32,
+    #[default(0.05)]
+    pid_ki: f32,
+    #[default(0.000001)]
+    pid_kd: f32,
+    #[default(4500)]
+    pwm_offset: u32,
+    #[default(false)]
+    pwm_offset_auto: bool,
+    #[default(1000)]
+    control_rate_hz: u32,
+    #[default(4000)]
+    pwm_frequency_hz: u32,
+    #[default(14)]
+    pwm_resolution_bits: u32,
+    #[default(false)]
+    pwm_dither_enable: bool,
+    #[default(0)]
+    pid_trace_rate_hz: u32,
+    #[default(10)]
+    perf_metrics_secs: u32,
+    #[default(0.0)]
+    pd_config_offset: f32,
+    #[default(1.0)]
+    pd_voltage_gain: f32,
+    #[default(0.0)]
+    pd_voltage_offset: f32,
+    #[default(0.0)]
+    shunt_resistance: f32,
+    #[default(50)]
+    shunt_temp_coefficient: u16,
+    #[default(11.0)]
+    max_current_limit: f32,
+    #[default(110.0)]
+    max_power_limit: f32,
+    #[default(75.0)]
+    max_temperature: f32,
+    #[default(0.0)]
+    thermal_resistance: f32,
+    #[default(120.0)]
+    thermal_time_constant: f32,
+    #[default(0.5)]
+    short_circuit_voltage: f32,
+    #[default(1.0)]
+    short_circuit_current: f32,
+    #[default(0.0)]
+    ovp_voltage: f32,
+    #[default(0.0)]
+    ch2_ovp_voltage: f32,
+    #[default(3)]
+    ovp_samples: u32,
+    #[default(10)]
+    stale_sample_limit: u32,
+    #[default(5.0)]
+    power_check_tolerance: f32,
+    #[default(500)]
+    step_down_time_ms: u32,
+    #[default(800)]
+    latency_budget_us: u32,
     #[default("")]
     influxdb_api_key: &'static str,
     #[default("")]
@@ -77,13 +252,163 @@ pub struct Config {
     influxdb_tag: &'static str,
     #[default("")]
     syslog_server: &'static str,
+    #[default(false)]
+    syslog_enable: bool,
+    #[default("")]
+    session_webhook_url: &'static str,
+    #[default("")]
+    alert_webhook_url: &'static str,
+    #[default("json")]
+    alert_format: &'static str,
+    #[default(300)]
+    wifi_lost_alert_secs: u32,
+    #[default(-80)]
+    upload_throttle_rssi: i32,
+    #[default(600)]
+    idle_sleep_secs: u32,
+    #[default("")]
+    schedule: &'static str,
+    #[default("")]
+    alarms: &'static str,
+    #[default(0)]
+    utc_offset_minutes: i32,
+    #[default("")]
+    sync_group: &'static str,
+    #[default(50505)]
+    sync_port: u32,
+    #[default("")]
+    sync_key: &'static str,
+    #[default(2000)]
+    sync_lead_ms: u32,
+    #[default("off")]
+    share_mode: &'static str,
     #[default("")]
-    syslog_enable: &'static str,
+    share_peer: &'static str,
+    #[default(50506)]
+    share_port: u32,
+    #[default(0.05)]
+    share_droop_ohm: f32,
+    #[default(0.5)]
+    share_trim_max: f32,
+    #[default(false)]
+    espnow_enable: bool,
+    #[default(100)]
+    espnow_interval_ms: u32,
+    #[default(1)]
+    espnow_channel: u32,
+    #[default(false)]
+    interlock_enable: bool,
+    #[default("center_long")]
+    start_stop_gesture: &'static str,
+    #[default(false)]
+    bleed_enable: bool,
+    #[default(2000)]
+    bleed_max_on_ms: u32,
+    #[default(false)]
+    scope_trigger_enable: bool,
+    #[default("high")]
+    scope_trigger_polarity: &'static str,
+    #[default(1)]
+    scope_trigger_width_ms: u32,
+    #[default("trip,alarm")]
+    scope_trigger_events: &'static str,
+    #[default(10.0)]
+    pd_sag_percent: f32,
+    #[default("trip")]
+    current_limit_mode: &'static str,
+    #[default(0.2)]
+    cable_resistance_warn: f32,
+    #[default(false)]
+    remote_sense_enable: bool,
+    #[default(1.0)]
+    remote_sense_max_drop: f32,
+    #[default(false)]
+    auto_recover_enable: bool,
+    #[default(5)]
+    auto_recover_cooldown: u32,
+    #[default(3)]
+    auto_recover_max_retries: u32,
+    #[default("0000")]
+    protection_unlock_code: &'static str,
+    #[default(false)]
+    kiosk_mode: bool,
+    #[default(false)]
+    safe_mode: bool,
+    #[default("")]
+    api_read_token: &'static str,
+    #[default("")]
+    api_control_token: &'static str,
+    #[default("")]
+    api_cors_origin: &'static str,
+    #[default("off")]
+    power_on_mode: &'static str,
+    #[default(4095)]
+    log_buffer_capacity: u32,
+    #[default(10)]
+    summary_interval: u32,
+    #[default(200)]
+    capture_pre_samples: u32,
+    #[default(800)]
+    capture_post_samples: u32,
+    #[default(5.0)]
+    cycle_voltage: f32,
+    #[default(10)]
+    cycle_on_secs: u32,
+    #[default(10)]
+    cycle_off_secs: u32,
+    #[default(100)]
+    cycle_count: u32,
+    #[default("stop")]
+    log_buffer_policy: &'static str,
+    #[default(60)]
+    efficiency_interval: u32,
+    #[default(70.0)]
+    efficiency_warn: f32,
+    #[default(5.0)]
+    pd_probe_tolerance: f32,
+    #[default(5)]
+    adc_filter_window: u32,
+    #[default(0.2)]
+    temp_filter_alpha: f32,
+    #[default(5.0)]
+    temp_outlier: f32,
+    #[default(0.5)]
+    pd_voltage_filter_alpha: f32,
+    #[default(2.0)]
+    pd_voltage_outlier: f32,
+    #[default(4)]
+    display_voltage_digits: u32,
+    #[default(3)]
+    display_current_digits: u32,
+    #[default(3)]
+    display_power_digits: u32,
+    #[default(10)]
+    display_refresh_hz: u32,
+    #[default(6)]
+    display_spi_mhz: u32,
+    #[default(false)]
+    display_trend: bool,
+    #[default(90.0)]
+    limit_warning_percent: f32,
+    #[default("info")]
+    log_level: &'static str,
+    #[default("info")]
+    syslog_level: &'static str,
+    #[default(false)]
+    ch2_enable: bool,
+    #[default(0.005)]
+    ch2_shunt_resistance: f32,
+    #[default(5.0)]
+    ch2_max_current_limit: f32,
+    #[default(50.0)]
+    ch2_max_power_limit: f32,
 }
 
 // NVS key for storing the last voltage setting
 const NVS_NAMESPACE: &str = "dcpowerunit";
 const VOLTAGE_KEY: &str = "last_voltage";
+const OUTPUT_STATE_KEY: &str = "output_on";
+const ERROR_COUNTERS_KEY: &str = "error_counters";
 
 // Function to save voltage setting to NVS
 fn save_voltage_to_nvs(voltage: f32) -> anyhow::Result<()> {
@@ -97,6 +422,20 @@ fn save_voltage_to_nvs(voltage: f32) -> anyhow::Result<()> {
     Ok(())
 }
 
+// Output state for the power-on resume
+fn save_output_state_to_nvs(on: bool) -> anyhow::Result<()> {
+    let nvs_default_partition = EspDefaultNvsPartition::take()?;
+    let mut nvs = EspNvs::new(nvs_default_partition, NVS_NAMESPACE, true)?;
+    nvs.set_u8(OUTPUT_STATE_KEY, on as u8)?;
+    Ok(())
+}
+
+fn load_output_state_from_nvs() -> anyhow::Result<bool> {
+    let nvs_default_partition = EspDefaultNvsPartition::take()?;
+    let nvs = EspNvs::new(nvs_default_partition, NVS_NAMESPACE, false)?;
+    Ok(nvs.get_u8(OUTPUT_STATE_KEY)?.unwrap_or(0) != 0)
+}
+
 // Function to load voltage setting from NVS
 fn load_voltage_from_nvs() -> anyhow::Result<f32> {
     let nvs_default_partition = EspDefaultNvsPartition::take()?;
@@ -120,39 +459,88 @@ fn load_voltage_from_nvs() -> anyhow::Result<f32> {
     }
 }
 
+// Error counters of the unit's lifetime, empty on a new unit or an unreadable blob
+fn load_error_counters_from_nvs() -> anyhow::Result<ErrorCounters> {
+    let nvs_default_partition = EspDefaultNvsPartition::take()?;
+    let nvs = EspNvs::new(nvs_default_partition, NVS_NAMESPACE, false)?;
+    let mut blob = [0u8; 64];
+    match nvs.get_blob(ERROR_COUNTERS_KEY, &mut blob)? {
+        Some(data) => Ok(ErrorCounters::decode(data).unwrap_or_else(|| {
+            warn!("Error counters in NVS not readable, starting from 0");
+            ErrorCounters::new()
+        })),
+        None => Ok(ErrorCounters::new()),
+    }
+}
+
+fn save_error_counters_to_nvs(counters: &ErrorCounters) -> anyhow::Result<()> {
+    let nvs_default_partition = EspDefaultNvsPartition::take()?;
+    let mut nvs = EspNvs::new(nvs_default_partition, NVS_NAMESPACE, true)?;
+    nvs.set_blob(ERROR_COUNTERS_KEY, &counters.encode())?;
+    Ok(())
+}
+
 fn main() -> anyhow::Result<()> {
     esp_idf_sys::link_patches();
-    
-    // Initialize the default ESP logger only if syslog is disabled
-    // If syslog is enabled, we'll initialize the syslog logger later
-    if CONFIG.syslog_enable != "true" {
-        esp_idf_svc::log::EspLogger::initialize_default();
-        // Set log level to INFO to ensure info!() messages are displayed
-        log::set_max_level(log::LevelFilter::Info);
-    }
-    
-    // Peripherals Initialize
-    let peripherals = Peripherals::take().unwrap();
+
     // Initialize nvs
     unsafe {
         esp_idf_sys::nvs_flash_init();
     }
+    // Load Settings (cfg.toml values are the defaults on the first boot)
+    let mut settings = Settings::load(Settings::from_config());
+    // Commands from the touchpad, console and HTTP server to the main loop
+    let bus = CommandBus::new();
+    // Config file on SPIFFS overrides the stored settings
+    let config_file = ConfigFile::new(bus.sender());
+    match configfile::mount_spiffs() {
+        Ok(()) => {
+            if let Some(new_settings) = config_file.load(&settings) {
+                settings = new_settings;
+                if let Err(e) = settings.save() {
+                    println!("Failed to save settings: {:?}", e);
+                }
+            }
+        },
+        Err(e) => {
+            println!("Failed to mount SPIFFS: {:?}", e);
+        }
+    }
+    
+    // Log to the console, and also to syslog once it is initialized.
+    // The log levels and module filters can be changed at runtime with the console or HTTP API.
+    logger::init(logfilter::from_setting("log_level", &settings.log_level),
+                 logfilter::from_setting("syslog_level", &settings.syslog_level));
+    
+    // Peripherals Initialize
+    let peripherals = Peripherals::take().unwrap();
 
     // Log startup message
     println!("DCPowerUnit2 application started (println)");
     info!("DCPowerUnit2 application started (info)");
+    info!("Firmware {} built {}", version::version_string(), version::build_time());
+    crashdump::report_at_boot();
+    let mut error_counters = load_error_counters_from_nvs().unwrap_or_else(|e| {
+        info!("Failed to read error counters from NVS: {:?}", e);
+        ErrorCounters::new()
+    });
+    error_counters.add(Counter::Boot, 1);
+    if crashdump::is_watchdog_reset() {
+        error_counters.add(Counter::WatchdogReset, 1);
+    }
+    info!("Settings loaded (schema v{})", settings.schema_version);
     
     // Load Config
-    let max_current_limit = CONFIG.max_current_limit.parse::<f32>().unwrap();
-    let max_power_limit = CONFIG.max_power_limit.parse::<f32>().unwrap();
-    let max_temperature = CONFIG.max_temperature.parse::<f32>().unwrap();
+    let mut max_current_limit = settings.max_current_limit;
+    let mut max_power_limit = settings.max_power_limit;
+    let mut max_temperature = settings.max_temperature;
     println!("[Config Limit] Current: {}A  Power: {}W  Temperature: {}°C", max_current_limit, max_power_limit, max_temperature);
     info!("[Config Limit] Current: {}A  Power: {}W  Temperature: {}°C", max_current_limit, max_power_limit, max_temperature);
-    let server_info = ServerInfo::new(CONFIG.influxdb_server.to_string(), 
-        CONFIG.influxdb_api_key.to_string(),
-        CONFIG.influxdb_api.to_string(),
-        CONFIG.influxdb_measurement.to_string(),
-        CONFIG.influxdb_tag.to_string());
+    let server_info = ServerInfo::new(settings.influxdb_server.clone(), 
+        settings.influxdb_api_key.clone(),
+        settings.influxdb_api.clone(),
+        settings.influxdb_measurement.clone(),
+        settings.influxdb_tag.clone());
 
     // Display SPI
     let spi = peripherals.spi2;
@@ -162,8 +550,10 @@ fn main() -> anyhow::Result<()> {
     let cs_not_used : Option<Gpio2> = None;
     let dc = PinDriver::output(peripherals.pins.gpio15)?;
     let rst = PinDriver::output(peripherals.pins.gpio16)?;
-    let spi_config = spi::SpiConfig::new().baudrate(1.MHz().into()).data_mode(MODE_0);
-    let spi_driver_config = spi::config::DriverConfig::new();
+    // The frames are sent by DMA: the display thread blocks on the transfer instead of
+    // feeding the FIFO, and the CPU is free for the other threads
+    let spi_config = spi::SpiConfig::new().baudrate(settings.display_spi_mhz.MHz().into()).data_mode(MODE_0);
+    let spi_driver_config = spi::config::DriverConfig::new().dma(spi::Dma::Auto(displayctl::FRAME_BYTES));
 
     let spi_driver = spi::SpiDriver::new(
         spi,
@@ -176,6 +566,9 @@ fn main() -> anyhow::Result<()> {
     let spi_device = spi::SpiDeviceDriver::new(spi_driver, cs_not_used, &spi_config)?;
     let mut dp = DisplayPanel::new();
     dp.start(spi_device, dc, rst);
+    dp.set_unit_format(settings.get_unit_format());
+    let mut display_refresh_hz = settings.display_refresh_hz;
+    dp.set_refresh_rate(display_refresh_hz);
 
     // Current/Voltage
     let i2c = peripherals.i2c0;
@@ -229,112 +622,166 @@ fn main() -> anyhow::Result<()> {
     i2c_sel.set_high().unwrap(); // Enable USB PD for PDO query
     let (pdo_max_voltage, pdo_max_current) = ap33772s.get_pdo_limits();
     info!("PDO Limits: Max Voltage = {:.2}V, Max Current = {:.3}A", pdo_max_voltage, pdo_max_current);
+    // Advertised PDOs for the charger probe (the AP33772S moves to the control task)
+    let pdo_points : Vec<PdoPoint> = ap33772s.get_pdo_list().iter().map(|pdo| PdoPoint {
+        index: pdo.pdo_index as u8,
+        voltage: pdo.voltage_mv as f32 / 1000.0,
+        current: pdo.current_ma as f32 / 1000.0,
+        fixed: pdo.is_fixed,
+    }).collect();
     
     // Apply the more restrictive limit between config and PDO
-    let effective_max_current = if pdo_max_current < max_current_limit { pdo_max_current } else { max_current_limit };
+    let mut effective_max_current = if pdo_max_current < max_current_limit { pdo_max_current } else { max_current_limit };
     info!("Effective Current Limit: {:.3}A (Config: {:.3}A, PDO: {:.3}A)", 
           effective_max_current, max_current_limit, pdo_max_current);
     println!("[Effective Limits] Voltage: {:.2}V  Current: {:.3}A", pdo_max_voltage, effective_max_current);
+    let pd_sag_percent = settings.pd_sag_percent;
+    info!("PD Rail Sag Threshold: {:.1}%", pd_sag_percent);
 
     // Select INA228
     i2c_sel.set_low().unwrap(); // Select INA228
 
     // Initialize INA228 sensor
-    match ADCRANGE {
-        true => write_ina228_reg16(&mut i2cdrv, 0x00, 0x0030)?, // Bit4: ADCRANGE=1(40.96mV), Bit5 Enables temperature compensation
-        false => write_ina228_reg16(&mut i2cdrv, 0x00, 0x0020)?, // Bit4: ADCRANGE=0(163.84mV), Bit5 Enables temperature compensation
-    }
-    let read_value = read_ina228_reg16(&mut i2cdrv, 0x00)?;
-    info!("INA228 Config Set to: {:04x}", read_value);
-
-    // INA228 ADC Config
-    let read_adc_config = read_ina228_reg16(&mut i2cdrv, 0x01)?;
-    info!("INA228 ADC Config Read: {:04x}", read_adc_config);
-    let write_adc_config : u16 = (read_adc_config & 0xFFF8) | 0x04; // Clear bits 0-2, 0x00: 1avg, 0x02: 16avg, 0x03: 64avg
-    write_ina228_reg16(&mut i2cdrv, 0x01, write_adc_config)?;
-    let read_adc_config = read_ina228_reg16(&mut i2cdrv, 0x01)?;
-    info!("INA228 ADC Config Set to: {:04x}", read_adc_config);
-
-
-    // SHUNT_CAL
-    let shunt_resistance = CONFIG.shunt_resistance.parse::<f32>().unwrap();
-    let current_lsb = match ADCRANGE {
-        true => {
-            // 40.96mV range
-            40.96 / 524_288.0
-        },
-        false => {
-            // 163.84mV range
-            163.84 / 524_288.0
+    let shunt_temp_coefficient = settings.shunt_temp_coefficient;
+    let current_lsb = init_ina228(&mut i2cdrv, ina228::INA228_ADDR, settings.shunt_resistance, shunt_temp_coefficient)?;
+    // Second output channel (optional INA228 at 0x41)
+    let ch2_current_lsb = if settings.ch2_enable {
+        match init_ina228(&mut i2cdrv, ina228::INA228_CH2_ADDR, settings.ch2_shunt_resistance, shunt_temp_coefficient) {
+            Ok(lsb) => Some(lsb),
+            Err(e) => {
+                warn!("Channel 2 INA228 not found, channel 2 disabled: {:?}", e);
+                None
+            }
         }
+    }
+    else {
+        None
     };
-    let shunt_cal_val = match ADCRANGE {
-        true => 13107.2 * current_lsb * 1000_000.0 * shunt_resistance * 4.0, // 40.96mV range
-        false => 13107.2 * current_lsb * 1000_000.0 * shunt_resistance, // 163.84mV range
+    // Remote sense of channel 1 (optional INA228 at 0x44, only the bus voltage is used)
+    let sense_addr = if settings.remote_sense_enable {
+        match init_ina228(&mut i2cdrv, ina228::INA228_SENSE_ADDR, settings.shunt_resistance, shunt_temp_coefficient) {
+            Ok(_) => Some(ina228::INA228_SENSE_ADDR),
+            Err(e) => {
+                warn!("Remote sense INA228 not found, regulating on the local sense: {:?}", e);
+                None
+            }
+        }
+    }
+    else {
+        None
     };
-    let shunt_cal = shunt_cal_val as u16;
-    info!("current_lsb={:?} shunt_cal_val={:?} shunt_cal={:?}", current_lsb, shunt_cal_val, shunt_cal);
-    write_ina228_reg16(&mut i2cdrv, 0x02, shunt_cal)?;
-    let read_shunt_cal = read_ina228_reg16(&mut i2cdrv, 0x02)?;
-    info!("INA228 SHUNT_CAL Set to: {:04x}", read_shunt_cal);
-    // Shunt Temperature Coefficient
-    let shunt_temp_coefficient = CONFIG.shunt_temp_coefficient.parse::<u16>().unwrap();
-    info!("Shunt Temperature Coefficient: {:?}", shunt_temp_coefficient);
-    write_ina228_reg16(&mut i2cdrv, 0x03, shunt_temp_coefficient)?;
-    let read_shunt_temp_coefficient = read_ina228_reg16(&mut i2cdrv, 0x03)?;
-    info!("INA228 SHUNT_TEMP_COEFFICIENT Set to: {:04x}", read_shunt_temp_coefficient);
 
     // Temperature Measurement
-    let temperature: f32 = read_ina228_reg16(&mut i2cdrv, 0x06)? as f32 * 7.8125;
-    info!("Initial Temperature Read: {:.2}°C", temperature / 1000.0);
-
-    // calibration read
-    let mut average_current_offset :f32 = 0.0;
-    let mut average_voltage_offset :f32 = 0.0;
-    // let (current_offset, voltage_offset) = calibration(&mut i2cdrv, current_lsb)?;
-    // average_current_offset = current_offset;
-
-    // PWM
-    let timer_config_out_current = TimerConfig::default().frequency(4.kHz().into())
-        .resolution(esp_idf_hal::ledc::config::Resolution::Bits14);
+    let temperature = ina228::temperature_read(&mut i2cdrv, ina228::INA228_ADDR)?;
+    info!("Initial Temperature Read: {:.2}°C", temperature);
+    let mut tempmon = TempMonitor::new();
+    tempmon.set_ina228_temperature(Some(temperature));
+
+    // calibration read (offsets are kept by the control task)
+    // let (current_offset, voltage_offset) = ina228::calibration(&mut i2cdrv, ina228::INA228_ADDR, current_lsb)?;
+
+    // PWM (the PID output and pwm_offset are scaled to max_duty by the regulator)
+    let timer_config_out_current = TimerConfig::default().frequency(settings.pwm_frequency_hz.Hz().into())
+        .resolution(pwm_resolution(settings.pwm_resolution_bits));
     let timer_driver_0 = LedcTimerDriver::new(peripherals.ledc.timer0, &timer_config_out_current).unwrap();
     let mut pwm_driver = LedcDriver::new(peripherals.ledc.channel0, &timer_driver_0, peripherals.pins.gpio38).unwrap();
     pwm_driver.set_duty(0).expect("Set duty failure");
+    // Channel 2 PWM GPIO48 on the same timer
+    let ch2_pwm_driver = match ch2_current_lsb {
+        Some(_) => {
+            let mut driver = LedcDriver::new(peripherals.ledc.channel1, &timer_driver_0, peripherals.pins.gpio48).unwrap();
+            driver.set_duty(0).expect("Set duty failure");
+            Some(driver)
+        },
+        None => None,
+    };
     let max_duty = pwm_driver.get_max_duty();
-    info!("Max duty: {}", max_duty);
+    info!("PWM: {}Hz {}bits Max duty: {}", settings.pwm_frequency_hz, settings.pwm_resolution_bits, max_duty);
+
+    let pd_config_offset = settings.pd_config_offset;
+
+    // Interlock input GPIO39 (loop closed = low, open = high by pull-up)
+    let interlock_enable = settings.interlock_enable;
+    let mut interlock_pin = PinDriver::input(peripherals.pins.gpio39)?;
+    interlock_pin.set_pull(Pull::Up)?;
+    info!("Interlock: {}", if interlock_enable { "enabled" } else { "disabled" });
+
+    // Start/stop push button GPIO40 (pressed = low by pull-up), used with start_stop_gesture = "input"
+    let mut start_stop_pin = PinDriver::input(peripherals.pins.gpio40)?;
+    start_stop_pin.set_pull(Pull::Up)?;
+    let mut start_stop_button = ButtonDebounce::new();
+    // Center key taps with start_stop_gesture = "center_double"
+    let mut center_taps = TapDetector::new(DOUBLE_TAP_MS);
+    info!("Start/stop gesture: {}", settings.start_stop_gesture);
+
+    // Production test strap GPIO41, tied to GND by the test fixture (pull-up otherwise)
+    let mut prod_test_pin = PinDriver::input(peripherals.pins.gpio41)?;
+    prod_test_pin.set_pull(Pull::Up)?;
+    thread::sleep(Duration::from_millis(1));
+    let prod_test_strap = prod_test_pin.is_low();
+    if prod_test_strap {
+        info!("Production test strap set");
+    }
+
+    // Output bleed FET GPIO14 of channel 1 (high = discharging)
+    let bleed = if settings.bleed_enable {
+        let mut bleed_pin = PinDriver::output(peripherals.pins.gpio14)?;
+        bleed_pin.set_low()?;
+        Some((bleed_pin, Bleed::new(settings.bleed_max_on_ms)))
+    }
+    else {
+        None
+    };
+    info!("Output bleed: {}", if settings.bleed_enable { "enabled" } else { "disabled" });
 
-    let pd_config_offset = CONFIG.pd_config_offset.parse::<f32>().unwrap();    
+    // Scope trigger output GPIO42, at its idle level until an event
+    let trigger = if settings.scope_trigger_enable {
+        let config = settings.get_trigger_config();
+        let mut trigger_pin = PinDriver::output(peripherals.pins.gpio42)?;
+        if config.polarity.level(false) {
+            trigger_pin.set_high()?;
+        }
+        else {
+            trigger_pin.set_low()?;
+        }
+        Some((trigger_pin, config))
+    }
+    else {
+        None
+    };
+    info!("Scope trigger: {}", if settings.scope_trigger_enable { settings.scope_trigger_events.as_str() } else { "disabled" });
 
     // Temperature Logs
-    let mut clogs = CurrentRecord::new();
+    let mut clogs = new_log_buffer(settings.log_buffer_capacity as usize, settings.get_log_buffer_policy());
 
     // Initialize logging for early debugging
-    let mut wifi_enable : bool;
-    let mut wifi_dev = wifi::wifi_connect(peripherals.modem, CONFIG.wifi_ssid, CONFIG.wifi_psk);
+    // Without an SSID, ESP-NOW runs on the radio alone, and the production test scans with it
+    let radio_only = settings.wifi_ssid.is_empty() && (settings.espnow_enable || prod_test_strap);
+    let mut wifi = if radio_only {
+        WifiManager::radio_only(peripherals.modem, settings.espnow_channel as u8)
+    }
+    else {
+        WifiManager::connect(peripherals.modem, &settings.wifi_ssid, &settings.wifi_psk)
+    };
 
-    if CONFIG.syslog_enable == "true" {
-        // Initialize syslog logger to replace the default ESP logger
+    if settings.syslog_enable {
+        // Initialize syslog logger in addition to the console logger
         println!("Initializing syslog logger...");
         thread::sleep(Duration::from_secs(5));
         
-        match syslogger::init_logger(CONFIG.syslog_server, CONFIG.syslog_enable) {
+        match syslogger::init_logger(&settings.syslog_server, settings.syslog_enable) {
             Ok(_) => {
-                // Set log level for syslog
-                log::set_max_level(log::LevelFilter::Info);
                 println!("Syslog logger initialized successfully");
                 info!("Syslog logger initialized successfully");
             },
             Err(e) => {
-                // Fallback to ESP logger if syslog fails
-                println!("Failed to initialize syslog logger: {:?}, using ESP logger instead", e);
-                esp_idf_svc::log::EspLogger::initialize_default();
-                log::set_max_level(log::LevelFilter::Info);
-                info!("Failed to initialize syslog logger: {:?}, using ESP logger instead", e);
+                // Console only if syslog fails
+                info!("Failed to initialize syslog logger: {:?}, using the console only", e);
             }
         }
     } else {
-        // syslog_enable is false, continue using default ESP console logger
-        info!("Using default ESP console logger (syslog disabled)");
+        // syslog_enable is false, continue using the console only
+        info!("Using the console logger only (syslog disabled)");
     }
     
     // NTP Server
@@ -370,10 +817,87 @@ fn main() -> anyhow::Result<()> {
         
     let mut txd =  Transfer::new(server_info);
     txd.start()?;
+    let session_reports = SessionReports::start(settings.session_webhook_url.clone())?;
+    let mut alerts = Alerts::start(settings.alert_webhook_url.clone(), settings.get_alert_format(),
+        settings.influxdb_tag.clone())?;
+    // Without syslog, the boot log is sent to InfluxDB as one event
+    if !settings.syslog_enable {
+        let boot_log = bootlog::take();
+        if !boot_log.records.is_empty() {
+            txd.push_event("boot_log", &format!("log=\"{}\",missed={}i",
+                Transfer::escape_string_field(&boot_log.to_text()), boot_log.missed));
+        }
+    }
 
     // TouchPad
-    let mut touchpad = TouchPad::new();
+    let mut touchpad = TouchPad::new(bus.sender());
     touchpad.start();
+
+    // USB Serial Console
+    let mut console = Console::new(bus.sender());
+    console.start();
+
+    // Health Telemetry
+    health::register_task("main");
+    let mut health = HealthMonitor::new(10.0);
+    health.set_wifi(wifi.state_handle());
+
+    // HTTP API Server
+    let capabilities = Capabilities::new(pdo_max_voltage, pdo_max_current, if ch2_current_lsb.is_some() { 2 } else { 1 }, &settings);
+    let api_access = Arc::new(Mutex::new(ApiPolicy {
+        tokens: settings.get_access_tokens(),
+        cors_origin: settings.api_cors_origin.clone(),
+    }));
+    info!("HTTP API: {}", api_access_summary(&settings));
+    let mut http_server = HttpServer::new(config_file.clone(), health.clone(), capabilities, bus.sender(), api_access.clone());
+    if let Err(e) = http_server.start() {
+        warn!("Failed to start HTTP server: {:?}", e);
+    }
+
+    // Multi-unit sync over UDP
+    let sync_net = if settings.sync_group.is_empty() {
+        None
+    }
+    else {
+        if settings.sync_key.is_empty() {
+            warn!("Sync messages are not authenticated, set sync_key on the units of the group");
+        }
+        match SyncNet::start(settings.sync_port as u16, &settings.sync_key, bus.sender()) {
+            Ok(net) => Some(net),
+            Err(e) => {
+                warn!("Failed to start the sync receiver: {:?}", e);
+                None
+            },
+        }
+    };
+
+    // Current sharing of channel 1 with a paired unit over UDP
+    let share_mode = settings.get_share_mode();
+    let share_net = match share_mode {
+        ShareMode::Off => Ok(None),
+        ShareMode::Master => settings.share_peer.parse::<std::net::Ipv4Addr>().map_err(anyhow::Error::from)
+            .and_then(|peer| ShareNet::master(peer, settings.share_port as u16)).map(Some),
+        ShareMode::Slave => ShareNet::slave(settings.share_port as u16, bus.sender()).map(Some),
+    }.unwrap_or_else(|e| {
+        warn!("Failed to start the current share: {:?}", e);
+        None
+    });
+    info!("Current share: {:?}", if share_net.is_some() { share_mode } else { ShareMode::Off });
+
+    // Live measurements over ESP-NOW for a receiver dongle
+    let mut espnow_tx = if settings.espnow_enable && wifi.has_radio() {
+        match EspNowTx::start() {
+            Ok(tx) => Some(tx),
+            Err(e) => {
+                warn!("Failed to start ESP-NOW: {:?}", e);
+                None
+            },
+        }
+    }
+    else {
+        None
+    };
+    let mut peer_telemetry = PeerTelemetry::new(&settings.influxdb_tag, settings.espnow_interval_ms);
     
     // ADC2-CH7 GPIO18 for Temperature
     let mut adc_temp = AdcDriver::new(peripherals.adc2)?;
@@ -392,14 +916,43 @@ fn main() -> anyhow::Result<()> {
         .. AdcConfig::default()
     };
     let mut usb_pd_pin = AdcChannelDriver::new(&mut adc_pd_voltage, peripherals.pins.gpio9, &mut adc_pd_voltage_config)?;
+    // Median and IIR filters of the single-shot readings, against spikes (spurious over-temperature trips)
+    let mut temp_filter = AdcFilter::new(settings.adc_filter_window as usize, settings.temp_filter_alpha, settings.temp_outlier);
+    let mut pd_voltage_filter = AdcFilter::new(settings.adc_filter_window as usize, settings.pd_voltage_filter_alpha, settings.pd_voltage_outlier);
     
     // PID Controller
-    let pid_kp = CONFIG.pid_kp.parse::<f32>().unwrap();
-    let pid_ki = CONFIG.pid_ki.parse::<f32>().unwrap();
-    let pid_kd = CONFIG.pid_kd.parse::<f32>().unwrap();
-    let pwm_offset = CONFIG.pwm_offset.parse::<u32>().unwrap();
+    let pid_kp = settings.pid_kp;
+    let pid_ki = settings.pid_ki;
+    let pid_kd = settings.pid_kd;
+    let pwm_offset = settings.pwm_offset;
     info!("PID Controller: KP={} KI={} KD={}", pid_kp, pid_ki, pid_kd);
-    let mut pid = PIDController::new(pid_kp, pid_ki, pid_kd, 0.0);
+    let mut regulator = Regulator::new(pid_kp, pid_ki, pid_kd, max_duty, pwm_offset);
+    regulator.set_dither(settings.pwm_dither_enable);
+    info!("PWM dithering: {}", if settings.pwm_dither_enable { "enabled" } else { "disabled" });
+    let mut channels = vec![ChannelHardware {
+        ina228_addr: ina228::INA228_ADDR,
+        sense_addr: sense_addr,
+        current_lsb: current_lsb,
+        pwm_driver: pwm_driver,
+        regulator: regulator,
+        bleed: bleed,
+    }];
+    // Channel 2 has its own PID with the same gains
+    if let (Some(ch2_current_lsb), Some(ch2_pwm_driver)) = (ch2_current_lsb, ch2_pwm_driver) {
+        let mut ch2_regulator = Regulator::new(pid_kp, pid_ki, pid_kd, max_duty, pwm_offset);
+        ch2_regulator.set_dither(settings.pwm_dither_enable);
+        channels.push(ChannelHardware {
+            ina228_addr: ina228::INA228_CH2_ADDR,
+            sense_addr: None,
+            current_lsb: ch2_current_lsb,
+            pwm_driver: ch2_pwm_driver,
+            regulator: ch2_regulator,
+            bleed: None,
+        });
+    }
+    // The channels are moved to the control task
+    let channel_count = channels.len();
+    info!("Output channels: {}", channel_count);
 
     // Start Display
     dp.enable_display(true);
@@ -408,15 +961,65 @@ fn main() -> anyhow::Result<()> {
     touchpad.set_press_threshold(Key::Center, 1000, false);
     touchpad.set_press_threshold(Key::Up, 300, true);
     touchpad.set_press_threshold(Key::Down, 300, true);
+    touchpad.set_press_threshold(Key::Left, 1000, false);
+    touchpad.set_press_threshold(Key::Right, 1000, false);
+
+    // Restart-after-fault policy
+    let mut recovery = RecoveryPolicy::new(settings.auto_recover_enable,
+        settings.auto_recover_cooldown,
+        settings.auto_recover_max_retries);
+    info!("Auto-recover: {} (cooldown {}s, max retries {})", settings.auto_recover_enable,
+        recovery.get_cooldown_secs(), recovery.get_max_retries());
 
     // loop
     let mut measurement_count : u32 = 0;
     let mut logging_start = false;
     let mut load_start = false;
     let mut calibration_start = false;
+    // INA228 offset calibration, applied at once from the panel or on request of a test fixture
+    let mut offset_cal = OffsetCalibration::new();
+    // Transfer errors of the I2C devices already counted in the error counters
+    let mut i2c_errors_counted : u64 = 0;
+    // Performance counters at the last perf point
+    let mut perf_counters = perfcounters::snapshot();
+    let mut perf_counters_ms = monotonic_ms();
+    // End-of-line production test, from the strap at boot or the prodtest command, and the
+    // PWM sweep results of its channels
+    let mut prod_test : Option<ProductionTest> = if prod_test_strap { Some(ProductionTest::new()) } else { None };
+    let mut prod_test_pwm : Vec<(usize, Result<u32, String>)> = Vec::new();
+    // Cable resistance test of channel 1, which steps its setpoint
+    let mut cable_start = false;
+    let mut cable_test : Option<CableTest> = None;
+    // Duty-cycle endurance test of channel 1, and the result of the last one
+    let mut cycle_test : Option<CycleTest> = None;
+    let mut cycle_result = "no test run".to_string();
+    // Test script run on channel 1, kept after it ends for its report
+    let mut script_run : Option<ScriptRun> = None;
+    // Markers put by the operator (key, console, API), numbered since boot
+    let mut markers = Markers::new();
+    // USB PD charger probe (outputs off), and the report of the last one
+    let mut pd_probe : Option<PdProbe> = None;
+    let mut pd_probe_report = "no probe run".to_string();
+    // USB PD voltage divider calibration (outputs off)
+    let mut pd_cal : Option<PdVoltageCalibration> = None;
+    // PWM offset learning at each fixed PDO voltage (outputs off), and the learned offsets
+    // of each channel; empty for the configured pwm_offset
+    let pwm_offset_voltages : Vec<f32> = pdo_points.iter().filter(|p| p.fixed).map(|p| p.voltage).collect();
+    let mut pwm_offset_learning : Option<OffsetLearning> = None;
+    let mut pwm_offset_tables : Vec<OffsetTable> = Vec::new();
+    // DUT identifier and note of the run (console or HTTP, not saved)
+    let mut run_label = RunLabel::default();
+    let mut cable_current : Option<f32> = None;
+    // Input current for the efficiency, and the efficiency of the last interval
+    let mut input_current : Option<f32> = None;
+    let mut last_efficiency : Option<f32> = None;
+    let mut last_data = CurrentLog::default();
     
+    // Power-on output state policy
+    let power_on_mode = settings.get_power_on_mode();
+    info!("Power-on mode: {:?}", power_on_mode);
     // Load last voltage setting from NVS
-    let mut set_output_voltage = match load_voltage_from_nvs() {
+    let mut set_output_voltage = if power_on_mode == PowerOnMode::Off { 0.0 } else { match load_voltage_from_nvs() {
         Ok(voltage) => {
             // Ensure voltage is within PDO limits
             if voltage > pdo_max_voltage {
@@ -430,96 +1033,1658 @@ fn main() -> anyhow::Result<()> {
             info!("Failed to load voltage from NVS: {:?}, using 0.0V", e);
             0.0
         }
-    };
+    }};
+    // Resume the output if it was on when the power was lost
+    let mut resume_output = power_on_mode == PowerOnMode::Resume && load_output_state_from_nvs().unwrap_or(false);
     
     info!("Initial voltage setting: {:.3}V", set_output_voltage);
     let mut previous_set_output_voltage = 0.0;
+
+    // USB PD contract and rail sag state
+    let mut pd_contract_voltage : f32 = 5.0;
+    // Session current limit (not saved), set from the front panel up to the effective maximum.
+    // current_limit is this limit, reduced while the USB PD rail sags.
+    let mut session_current_limit = effective_max_current;
+    let mut pd_request_current_ma = pd_operating_current_ma(session_current_limit);
+    let mut applied_session_current_limit = session_current_limit;
+    let mut current_limit = session_current_limit;
+    // Soft current limit (current_limit_mode = "pps") of channel 1
+    let mut soft_limit = SoftCurrentLimit::new(current_limit, pd_request_current_ma);
+    // Thermal model of the heatsink, and the current limit of channel 1 while it derates
+    let mut thermal_settings = (settings.thermal_resistance, settings.thermal_time_constant);
+    let mut thermal = ThermalModel::new(thermal_settings.0, thermal_settings.1);
+    let mut thermal_current_limit : Option<f32> = None;
+    // Session power limit, up to max_power_limit and not saved
+    let mut session_power_limit = max_power_limit;
+    let mut pd_sag_count : u32 = 0;
+    let mut pd_sag_holdoff : u32 = 0;
+
+    // Protection settings menu
+    let mut protection_menu : Option<SettingsMenu> = None;
+    // PID gains menu
+    let mut pid_menu : Option<SettingsMenu> = None;
+    // Kiosk mode: the unlock code prompt, and the panel unlocked with the loops since the last key
+    let mut kiosk_menu : Option<SettingsMenu> = None;
+    let mut kiosk_unlocked = false;
+    let mut kiosk_idle_count : u32 = 0;
+    // Safe mode applied (announced at boot when set)
+    let mut safe_mode_on = false;
+    // Factory reset confirmation on the display
+    let mut factory_reset_confirm = false;
+    // About page (firmware version) on the display
+    let mut about_page = false;
+    // Upload queue and the last upload on the display (Right on the about page)
+    let mut network_page = false;
+    // Regulation statistics page of the channel shown
+    let mut stats_page = false;
+    // Ripple measurement result on the display
+    let mut ripple_page = false;
+    // Cable resistance test progress and result on the display
+    let mut cable_page = false;
+    // Test scripts on the display (Right on the network page) and the one selected
+    let mut script_page : Option<(Vec<String>, usize)> = None;
+    let mut efficiency = EfficiencyMeter::new(settings.efficiency_interval, settings.efficiency_warn);
+    // Summary records of each channel while the full-rate logging is off (0s disables them)
+    let mut summaries : Vec<SummaryLog> = (0..channel_count).map(|_| SummaryLog::new(settings.summary_interval)).collect();
+    let mut fault_full_rate : u32 = 0;
+    // Session report of each channel, from output ON to OFF
+    let mut sessions : Vec<SessionTracker> = (0..channel_count).map(|_| SessionTracker::new()).collect();
+    // Triggered capture of channel 1 (armed from the console)
+    let mut capture = Capture::new(settings.capture_pre_samples as usize, settings.capture_post_samples as usize);
     
     // Set initial voltage display
     dp.set_output_voltage(set_output_voltage);
     
-    let mut pwm_duty : u32;
+    let mut pending_keys : Vec<KeyInput> = Vec::new();
+    // Touch to the action of a key, and the part of it to the key scan
+    let mut input_latency = LatencyStats::with_bucket(0, INPUT_LATENCY_BUCKET_US);
+    let mut scan_latency = LatencyStats::with_bucket(0, INPUT_LATENCY_BUCKET_US);
+    let mut trip : Option<TripCause> = None;
+    let mut trip_data = CurrentLog::default();
+
+    // Fast control task: measurement, current/power limits and PID at control_rate_hz.
+    // This loop (housekeeping) runs every 10ms and sends the changes of the output state,
+    // the setpoint and the limits to it.
+    let control = ControlTask::start(ControlHardware {
+            i2cdrv: i2cdrv,
+            i2c_sel: i2c_sel,
+            ap33772s: ap33772s,
+            channels: channels,
+            pd_config_offset: pd_config_offset,
+            trigger: trigger,
+        }, settings.control_rate_hz)?;
+    let mut control_output = false;
+    let mut control_setpoint = set_output_voltage;
+    let mut control_limits = (current_limit, session_power_limit);
+    let mut control_gains = settings.pid_gains();
+    // Sent on the first loop
+    let mut control_short_circuit = (f32::NAN, f32::NAN);
+    let mut control_over_voltage = vec![(f32::NAN, 0); control.channel_count()];
+    let mut control_stale_limit : Option<u32> = None;
+    let mut control_power_check : Option<f32> = None;
+    let mut control_trigger : Option<TriggerConfig> = None;
+    let mut control_latency_budget : Option<u32> = None;
+    let mut control_step_down_time : Option<u32> = None;
+    let mut control_pid_trace_rate : Option<u32> = None;
+    let mut control_remote_sense : Option<f32> = None;
+    // Remote sense lost in the running session of channel 1
+    let mut remote_sense_lost = false;
+    // Setpoint and limit changes sent as events, with what changed them
+    let mut limit_log = LimitLog::new();
+    // Fewer uploads and summarized records on a weak WiFi signal
+    let mut upload_throttle = UploadThrottle::new(settings.upload_throttle_rssi);
+    // Low-power idle with the outputs off and no activity
+    let mut idle_sleep = IdleSleep::new(settings.idle_sleep_secs);
+    // Main loop iterations per sample streamed to the desktop app, 0 not streaming
+    let mut host_stream_divider : u32 = 0;
+    // Every sample streamed over USB as binary frames
+    let mut raw_stream = false;
+    control.send(ControlCommand::Setpoint(CH1, set_output_voltage));
+    control.send(ControlCommand::Limits { channel: CH1, current: current_limit, power: max_power_limit });
+    // Channel 2 (not saved, 0V at boot). Trips are latched, auto-recover is for channel 1.
+    let ch2_present = control.channel_count() > 1;
+    let mut ch2_output = false;
+    let mut ch2_setpoint : f32 = 0.0;
+    let mut control_ch2_output = false;
+    let mut control_ch2_setpoint = ch2_setpoint;
+    let mut control_ch2_limits = (0.0, 0.0);
+    // Current and power near the limits of each channel
+    let mut limit_warnings = vec![LimitPreWarning::new(settings.limit_warning_percent); control.channel_count()];
+    let mut ch2_session_current_limit = settings.ch2_max_current_limit.min(pdo_max_current);
+    let mut ch2_session_power_limit = settings.ch2_max_power_limit;
+    // Channel shown on the display and adjusted by the keys
+    let mut selected_channel = CH1;
+    // Voltage and current history of each channel for the sparklines, and the channel shown
+    let mut trends : Vec<TrendHistory> = (0..control.channel_count())
+        .map(|_| TrendHistory::new(TREND_POINTS, TREND_SECS * LOOPS_PER_SEC / TREND_POINTS as u32)).collect();
+    let mut trend_shown : Option<usize> = None;
+    // Keys adjust the current limit instead of the voltage
+    let mut adjust = Adjust::Voltage;
+    let mut adjust_step = ADJUST_STEP_DEFAULT;
+    // A short press of Center is taken at the release, unless Center was held as a modifier
+    let mut center_short = false;
+    dp.set_channel(if ch2_present { Some(1) } else { None });
+    if settings.pwm_offset_auto {
+        info!("PWM offset learning at {:?}V", pwm_offset_voltages);
+        pwm_offset_learning = Some(OffsetLearning::new(&pwm_offset_voltages, control.channel_count()));
+    }
+    // Channels stepping down to a lower setpoint; the USB PD rail is lowered after them
+    let mut stepping_down = vec![false; control.channel_count()];
+    // Regulation statistics of the running session of each channel
+    let mut regulation = vec![RegulationReport::default(); control.channel_count()];
+    let mut last_sequence : u32 = 0;
+    // WiFi outage length in loops, alerted once it exceeds wifi_lost_alert_secs
+    let mut wifi_lost_count : u32 = 0;
+    let mut wifi_lost_alerted = false;
+    let mut buffer_full_alerted = false;
+    let mut schedule = new_schedule(&settings);
+    let mut alarm_rules = new_alarms(&settings);
+    let mut sync = SyncScheduler::new(&settings.sync_group);
+    let mut share_droop = Droop::new(settings.share_droop_ohm);
+    let mut share_slave = ShareSlave::new(settings.share_droop_ohm, settings.share_trim_max);
+    // Channel 1 setpoint of the slave, None without the master
+    let mut share_setpoint : Option<f32> = None;
+    let mut share_sequence : u32 = 0;
     loop {
         thread::sleep(Duration::from_millis(10));
 
+        // Measurements and events from the control task
+        let measurement = control.measurement();
+        let mut data = CurrentLog::default();
+        // Timestamp of the control task (monotonic, converted to the wall clock when sent)
+        data.clock = measurement.clock;
+        // Wall clock for the schedule
+        let wall_clock_ns = wall_clock_ms() as u128 * 1_000_000;
+        data.voltage = measurement.channels[CH1].voltage;
+        data.current = measurement.channels[CH1].current;
+        data.power = measurement.channels[CH1].power;
+        data.pwm = measurement.channels[CH1].pwm;
+        let new_measurement = measurement.sequence != last_sequence;
+        last_sequence = measurement.sequence;
+        for event in control.events() {
+            match event {
+                ControlEvent::Trip(CH1, cause, sample) => {
+                    match cause {
+                        TripCause::ShortCircuit => dp.set_message("Short Circuit".to_string(), true, 3000),
+                        TripCause::OverCurrent => dp.set_message(format!("Current OV {:.3}A", sample.current), true, 3000),
+                        TripCause::SensorFault => dp.set_message("Sensor Fault".to_string(), true, 3000),
+                        TripCause::OverVoltage => dp.set_message(format!("Voltage OV {:.2}V", sample.voltage), true, 3000),
+                        _ => dp.set_message(format!("Power OV {:.1}W", sample.power), true, 3000),
+                    }
+                    load_start = false;
+                    control_output = false;
+                    trip = Some(cause);
+                    trip_data = sample;
+                    capture.fault();
+                },
+                ControlEvent::Trip(_, cause, sample) => {
+                    match cause {
+                        TripCause::ShortCircuit => dp.set_message("CH2 Short Circuit".to_string(), true, 3000),
+                        TripCause::OverCurrent => dp.set_message(format!("CH2 Current OV {:.3}A", sample.current), true, 3000),
+                        TripCause::SensorFault => dp.set_message("CH2 Sensor Fault".to_string(), true, 3000),
+                        TripCause::OverVoltage => dp.set_message(format!("CH2 Voltage OV {:.2}V", sample.voltage), true, 3000),
+                        _ => dp.set_message(format!("CH2 Power OV {:.1}W", sample.power), true, 3000),
+                    }
+                    warn!(cause:? = cause, voltage = sample.voltage, current = sample.current, power = sample.power;
+                          "CH2 {:?} trip latched", cause);
+                    txd.push_event(trip_event(cause), &format!("cause=\"{:?}\",channel=2i,retry=0i,latched=true", cause));
+                    alerts.notify(alert_event(cause), format!("CH2 {:?} trip latched at {:.3}V {:.3}A {:.1}W",
+                        cause, sample.voltage, sample.current, sample.power));
+                    ch2_output = false;
+                    control_ch2_output = false;
+                    count_trip(&mut error_counters, cause);
+                    capture.fault();
+                    fault_full_rate = FAULT_FULL_RATE_COUNT;
+                    if let Some(session) = sessions.get_mut(CH2) {
+                        session.trip(&format!("{:?}", cause));
+                    }
+                },
+                ControlEvent::PdContract(contract) => {
+                    // A PDO refused by the charger under probe is not a failure of the unit
+                    if contract.is_none() && pd_probe.is_none() {
+                        error_counters.add(Counter::PdFailure, 1);
+                    }
+                    if let Some(v) = contract {
+                        pd_contract_voltage = v;
+                        // The learned PWM offset for the new rail voltage
+                        for (index, table) in pwm_offset_tables.iter().enumerate() {
+                            control.send(ControlCommand::PwmOffset(index, table.offset_at(v)));
+                        }
+                    }
+                    if let Some(probe) = pd_probe.as_mut() {
+                        probe.contract(contract);
+                    }
+                    if let Some(cal) = pd_cal.as_mut() {
+                        cal.contract(contract);
+                    }
+                    if let Some(learning) = pwm_offset_learning.as_mut() {
+                        learning.contract(contract);
+                    }
+                },
+                ControlEvent::CalibrationProgress(done, total) => offset_cal.progress(done, total),
+                ControlEvent::Calibrated(result) => {
+                    if let Some(offsets) = offset_cal.finish(result) {
+                        control.send(ControlCommand::Offsets(offsets));
+                    }
+                    let status = offset_cal.status();
+                    match status.error() {
+                        None => {
+                            info!("Calibration: {}", calibration_text(&status));
+                            dp.set_message("".to_string(), false, 0);
+                        },
+                        Some(e) => {
+                            warn!("Calibration failed: {}", e);
+                            dp.set_message("Calibration Error".to_string(), true, 3);
+                        }
+                    }
+                },
+                ControlEvent::I2cDevice(DeviceEvent::Offline(name, addr, outcome)) => {
+                    txd.push_event("i2c_device", &format!("device=\"{}\",addr={}i,online=false,outcome=\"{:?}\"", name, addr, outcome));
+                    dp.set_message(format!("{} Error", name), true, 3000);
+                },
+                ControlEvent::I2cDevice(DeviceEvent::Online(name, addr)) => {
+                    txd.push_event("i2c_device", &format!("device=\"{}\",addr={}i,online=true", name, addr));
+                },
+                ControlEvent::I2cRecovery(released) => {
+                    txd.push_event("i2c_recovery", &format!("released={},total={}i", released, i2cbus::recovery_count()));
+                    if !released {
+                        dp.set_message("I2C Bus Hung".to_string(), true, 3000);
+                    }
+                },
+                ControlEvent::I2cScan { ina228, usb_pd } => {
+                    let list = |addrs: &[u8]| addrs.iter().map(|a| format!("0x{:02x}", a)).collect::<Vec<_>>().join(" ");
+                    match prod_test.as_mut() {
+                        Some(test) if test.current() == Some(TestStep::I2c) => {
+                            // Every device of this unit answers
+                            let mut expected = vec![ina228::INA228_ADDR];
+                            if control.channel_count() > 1 {
+                                expected.push(ina228::INA228_CH2_ADDR);
+                            }
+                            if let Some(addr) = sense_addr {
+                                expected.push(addr);
+                            }
+                            let mut missing : Vec<String> = expected.iter().filter(|a| !ina228.contains(a)).map(|a| format!("INA228 0x{:02x}", a)).collect();
+                            if !usb_pd.contains(&i2cbus::AP33772S_ADDR) {
+                                missing.push(format!("AP33772S 0x{:02x}", i2cbus::AP33772S_ADDR));
+                            }
+                            let detail = if missing.is_empty() {
+                                format!("ina228 side [{}], usb pd side [{}]", list(&ina228), list(&usb_pd))
+                            }
+                            else {
+                                format!("missing {}", missing.join(", "))
+                            };
+                            test.result(TestStep::I2c, monotonic_ms(), missing.is_empty(), &detail);
+                        },
+                        _ => println!("i2c scan: INA228 side [{}], USB PD side [{}]", list(&ina228), list(&usb_pd)),
+                    }
+                },
+                ControlEvent::Regulation(index, report, session_end) => {
+                    if session_end && report.samples > 0 {
+                        info!("CH{} regulation: error {:+.4}V, peak {:.4}V, {} load steps, recovery {:.0}ms (max {:.0}ms)",
+                              index + 1, report.steady_state_error, report.peak_deviation, report.load_steps,
+                              report.recovery_time_ms, report.max_recovery_time_ms);
+                        txd.push_event("regulation", &format!("channel={}i,steady_state_error={:.5},peak_deviation={:.5},load_steps={}i,recovery_time_ms={:.1},max_recovery_time_ms={:.1},samples={}i",
+                            index + 1, report.steady_state_error, report.peak_deviation, report.load_steps,
+                            report.recovery_time_ms, report.max_recovery_time_ms, report.samples));
+                    }
+                    if let Some(r) = regulation.get_mut(index) {
+                        *r = report;
+                    }
+                    if stats_page && index == selected_channel {
+                        show_regulation(&mut dp, index, &report);
+                    }
+                },
+                ControlEvent::PidTrace(index, points) => {
+                    txd.push_pid_trace(index + 1, &points);
+                },
+                ControlEvent::PdCurrent(current) => {
+                    cable_current = current;
+                    input_current = current;
+                },
+                ControlEvent::PdVoltage(voltage) => {
+                    if let (Some(cal), Some(voltage)) = (pd_cal.as_mut(), voltage) {
+                        cal.add_reference(voltage);
+                    }
+                },
+                ControlEvent::StepDown(index, StepDownEvent::Started { from, to }) => {
+                    debug!("CH{} stepping down from {:.2}V to {:.2}V", index + 1, from, to);
+                    stepping_down[index] = true;
+                },
+                ControlEvent::StepDown(index, StepDownEvent::Completed(time_ms)) => {
+                    info!("CH{} step down completed in {:.0}ms", index + 1, time_ms);
+                    txd.push_event("step_down", &format!("channel={}i,completed=true,time_ms={:.0}", index + 1, time_ms));
+                    stepping_down[index] = false;
+                },
+                ControlEvent::StepDown(index, StepDownEvent::TimedOut(voltage)) => {
+                    warn!("CH{} step down not completed in {}ms: {:.3}V", index + 1, settings.step_down_time_ms, voltage);
+                    txd.push_event("step_down", &format!("channel={}i,completed=false,time_ms={}i,voltage={:.3}",
+                        index + 1, settings.step_down_time_ms, voltage));
+                    dp.set_message("Step Down Slow".to_string(), true, 3);
+                    stepping_down[index] = false;
+                },
+                ControlEvent::PwmOffset(index, result) => {
+                    if let Some(learning) = pwm_offset_learning.as_mut() {
+                        learning.ramp_result(index, result);
+                    }
+                    else if let Some(test) = prod_test.as_mut() {
+                        // The sweep of each channel raises its output at a duty below the limit
+                        prod_test_pwm.push((index, result));
+                        if prod_test_pwm.len() == control.channel_count() {
+                            let pass = prod_test_pwm.iter().all(|(_, r)| r.is_ok());
+                            let detail = prod_test_pwm.iter().map(|(index, r)| match r {
+                                Ok(offset) => format!("ch{} offset {}", index + 1, offset),
+                                Err(e) => format!("ch{} {}", index + 1, e),
+                            }).collect::<Vec<_>>().join(", ");
+                            test.result(TestStep::Pwm, monotonic_ms(), pass, &detail);
+                        }
+                    }
+                },
+                ControlEvent::RemoteSenseLost(index, reason) => {
+                    warn!("CH{} remote sense lost ({}), regulating on the local sense", index + 1, reason);
+                    txd.push_event("remote_sense_lost", &format!("channel={}i,reason=\"{}\"", index + 1, reason));
+                    dp.set_message("Remote Sense Lost".to_string(), true, 3000);
+                    remote_sense_lost = true;
+                },
+                ControlEvent::PowerCheck(index, PowerCheckEvent::Inconsistent { power, expected, error }) => {
+                    warn!("CH{} sensor inconsistent: power read {:.3}W, V x I {:.3}W ({:.1}% off)", index + 1, power, expected, error);
+                    txd.push_event("sensor_inconsistent", &format!("channel={}i,power={:.4},expected={:.4},error={:.2}", index + 1, power, expected, error));
+                    dp.set_message(format!("CH{} Sensor Check", index + 1), true, 3000);
+                    alerts.notify("sensor_inconsistent", format!("CH{} power read {:.3}W but V x I is {:.3}W ({:.1}% off), check the INA228 calibration",
+                        index + 1, power, expected, error));
+                },
+                ControlEvent::PowerCheck(index, PowerCheckEvent::Consistent) => {
+                    info!("CH{} sensor consistent again", index + 1);
+                    txd.push_event("sensor_consistent", &format!("channel={}i", index + 1));
+                },
+                ControlEvent::LatencyOverBudget { cycles, max_us } => {
+                    txd.push_event("control_latency", &format!("cycles={}i,max_us={}i,budget_us={}i", cycles, max_us, settings.latency_budget_us));
+                },
+                ControlEvent::Ripple(index, result) => {
+                    match result {
+                        Ok(r) => {
+                            info!("CH{} ripple: {:.2}mVpp {:.2}mVrms at {:.3}V ({} samples, {:.0}S/s)",
+                                  index + 1, r.peak_to_peak * 1000.0, r.rms * 1000.0, r.mean, r.samples, r.sample_rate_hz);
+                            println!("ch{} ripple p-p={:.2}mV rms={:.2}mV mean={:.4}V samples={} rate={:.0}S/s",
+                                     index + 1, r.peak_to_peak * 1000.0, r.rms * 1000.0, r.mean, r.samples, r.sample_rate_hz);
+                            ripple_page = true;
+                            dp.set_menu(true, format!("Ripple CH{}", index + 1), format!("P-P {:.1}mV", r.peak_to_peak * 1000.0), format!("{:.2}mV", r.rms * 1000.0));
+                        },
+                        Err(e) => {
+                            warn!("CH{} ripple measurement failed: {}", index + 1, e);
+                            println!("ripple measurement failed: {}", e);
+                            if ripple_page {
+                                ripple_page = false;
+                                dp.set_menu(false, "".to_string(), "".to_string(), "".to_string());
+                            }
+                            dp.set_message("Ripple Error".to_string(), true, 3);
+                        }
+                    }
+                },
+            }
+        }
+
+        // Commands from the other threads
+        let mut console_commands = Vec::new();
+        let mut host_requests : Vec<HostRequest> = Vec::new();
+        let mut settings_update = None;
+        // Source of a setpoint or limit change in this iteration
+        let mut change_source = "auto";
+        for cmd in bus.drain() {
+            // Keys, console commands and API requests wake the unit from the low-power idle
+            let woken = match cmd {
+                Command::Share(_) | Command::Sync(_) => false,
+                _ => idle_sleep.activity(),
+            };
+            match cmd {
+                Command::Key(key) => {
+                    // A key which wakes the unit is not taken as a key press
+                    if !woken {
+                        pending_keys.push(key);
+                    }
+                },
+                Command::Console(cmd) => {
+                    console_commands.push(cmd);
+                    change_source = "console";
+                },
+                Command::ReloadConfig(json, code, reply) => {
+                    // Checked against the live settings before the file replaces the current one
+                    let result = settings.overlay_json(&json)
+                        .and_then(|new| settings.unlock(new, &code))
+                        .and_then(|new| config_file.store(&json).map(|_| new));
+                    let _ = reply.send(result.as_ref().map(|_| ()).map_err(|e| e.to_string()));
+                    settings_update = Some(("Config file reload", "Config Reloaded", result));
+                    change_source = "api";
+                },
+                Command::ImportSettings(json, code, reply) => {
+                    let result = settings.import_json(&json).and_then(|new| settings.unlock(new, &code));
+                    let _ = reply.send(result.as_ref().map(|_| ()).map_err(|e| e.to_string()));
+                    settings_update = Some(("Settings import", "Settings Imported", result));
+                    change_source = "api";
+                },
+                Command::ExportSettings(reply) => {
+                    let _ = reply.send(settings.export_json());
+                },
+                Command::Pid(change, reply) => {
+                    let _ = reply.send(change_pid(&mut settings, change).map_err(|e| e.to_string()));
+                },
+                Command::Label(label, reply) => {
+                    if let Some(label) = label {
+                        run_label = label;
+                        apply_run_label(&run_label, &mut sessions, &mut txd);
+                    }
+                    let _ = reply.send(run_label.clone());
+                },
+                Command::Calibration(action, reply) => {
+                    let result = calibration_request(&mut offset_cal, action, load_start || ch2_output, &control);
+                    if result.is_ok() && action == CalibrationAction::Start {
+                        dp.set_message("Calibration..".to_string(), true, 0);
+                    }
+                    let _ = reply.send(result.map(|_| offset_cal.status()));
+                },
+                Command::Script(request, reply) => {
+                    let busy = cycle_test.is_some() || cable_test.is_some() || pd_probe.is_some() || pd_cal.is_some() || prod_test.is_some();
+                    let _ = reply.send(script_request(&mut script_run, request, busy, &mut txd));
+                    change_source = "api";
+                },
+                Command::Marker(text, reply) => {
+                    let _ = reply.send(add_marker(&mut markers, MarkerSource::Api, &text, &mut txd, &mut dp, &control));
+                },
+                Command::Share(message) => share_slave.receive(message),
+                Command::Wake => {},
+                Command::Identify(secs) => identify(&mut dp, &wifi, secs),
+                Command::Host(request) => {
+                    host_requests.push(request);
+                    change_source = "console";
+                },
+                Command::Sync(message) => {
+                    let run_id = message.run_id.clone();
+                    match sync.accept(message, wall_clock_ms()) {
+                        Ok(true) => info!("Sync: run {} accepted", run_id),
+                        Ok(false) => {},
+                        Err(e) => warn!("Sync: run {} rejected: {}", run_id, e),
+                    }
+                },
+            }
+        }
+        // Scheduled operations run as console commands
+        if let Some((weekday, minute_of_day)) = local_day_minute(wall_clock_ns, settings.utc_offset_minutes) {
+            for entry in schedule.poll(weekday, minute_of_day) {
+                info!("Schedule: {}", entry.to_text());
+                txd.push_event("schedule", &format!("entry=\"{}\"", entry.to_text()));
+                change_source = "schedule";
+                match entry.action {
+                    ScheduleAction::Output(on) => console_commands.push(ConsoleCommand::Output(entry.channel, on)),
+                    ScheduleAction::Voltage(voltage) => console_commands.push(ConsoleCommand::Voltage(entry.channel, voltage)),
+                    ScheduleAction::Cycle => console_commands.push(ConsoleCommand::CycleStart(None)),
+                }
+            }
+        }
+        // The start of the sync group, run as console commands on all the channels
+        if sync.get_group() != settings.sync_group {
+            sync.set_group(&settings.sync_group);
+        }
+        let now_ms = wall_clock_ms();
+        if let Some(message) = sync.poll(now_ms) {
+            let late_ms = now_ms - message.start_ms;
+            info!("Sync start: run {} {} ({}ms late)", message.run_id, message.action.as_str(), late_ms);
+            txd.set_run_id(&message.run_id);
+            txd.push_event("sync_start", &format!("group=\"{}\",run=\"{}\",action=\"{}\",late_ms={}i",
+                message.group, message.run_id, message.action.as_str(), late_ms));
+            change_source = "sync";
+            match message.action {
+                SyncAction::On | SyncAction::Off => {
+                    for channel in 0..control.channel_count() {
+                        console_commands.push(ConsoleCommand::Output(channel, message.action == SyncAction::On));
+                    }
+                },
+                SyncAction::Cycle => console_commands.push(ConsoleCommand::CycleStart(None)),
+            }
+        }
+
+        // API tokens and CORS origin, applied from the next request
+        {
+            let mut policy = api_access.lock().unwrap();
+            if policy.tokens.read != settings.api_read_token || policy.tokens.control != settings.api_control_token {
+                policy.tokens = settings.get_access_tokens();
+                info!("HTTP API: {}", api_access_summary(&settings));
+            }
+            if policy.cors_origin != settings.api_cors_origin {
+                policy.cors_origin = settings.api_cors_origin.clone();
+                info!("HTTP API: CORS origin \"{}\"", policy.cors_origin);
+            }
+        }
+        // Low-power idle: display blanked, WiFi modem sleep and slow measurements
+        if idle_sleep.get_timeout() != settings.idle_sleep_secs {
+            idle_sleep.set_timeout(settings.idle_sleep_secs);
+        }
+        let busy = load_start || ch2_output || cycle_test.is_some() || cable_test.is_some() || pd_probe.is_some() || pwm_offset_learning.is_some() || offset_cal.is_running() || prod_test.is_some() || raw_stream
+            || script_run.as_ref().map_or(false, |r| r.is_running());
+        if let Some(sleep) = idle_sleep.update(busy) {
+            info!("{}", if sleep { "Idle, entering the low-power mode" } else { "Woken up from the low-power mode" });
+            txd.push_event("idle_sleep", &format!("sleep={}", sleep));
+            dp.set_sleep(sleep);
+            wifi.set_power_save(sleep);
+            control.send(ControlCommand::Idle(sleep));
+        }
+
         let mut start_stop_btn = false;
+        let mut ch2_start_stop = false;
         measurement_count += 1;
-        if measurement_count % 10 == 0 {
-            let key_event = touchpad.get_key_event_and_clear();
-            for key in &key_event {
-                match key {
-                    KeyEvent::CenterKeyDown => {
-                        // Clear error messages when center key is pressed
-                        dp.set_message("".to_string(), false, 0);
-                        info!("Error message cleared by center key press");
+        perfcounters::main_loop();
+        // Loop performance counters to the <measurement>_perf points
+        if settings.perf_metrics_secs > 0 && measurement_count % (settings.perf_metrics_secs * LOOPS_PER_SEC) == 0 {
+            let counters = perfcounters::snapshot();
+            let now_ms = monotonic_ms();
+            let rates = counters.rates(&perf_counters, now_ms.saturating_sub(perf_counters_ms));
+            debug!("Perf: control {:.0}/s overruns {:.1}/s i2c {:.0}/s ({:.0}us) pwm {:.0}/s frames {:.1}/s loops {:.1}/s",
+                rates.control_cycles, rates.overruns, rates.i2c_transfers, rates.i2c_transfer_us,
+                rates.pwm_updates, rates.display_frames, rates.main_loops);
+            txd.push_perf(&rates);
+            perf_counters = counters;
+            perf_counters_ms = now_ms;
+        }
+        health.loop_tick();
+        if measurement_count % HEALTH_SAMPLE_COUNT == 0 {
+            let i2c_errors : u64 = i2cbus::devices().iter().map(|d| d.nacks + d.timeouts).sum();
+            error_counters.add(Counter::I2cError, i2c_errors.saturating_sub(i2c_errors_counted) as u32);
+            i2c_errors_counted = i2c_errors;
+            health.set_error_counters(&error_counters);
+            health.sample();
+        }
+        if error_counters.is_save_due(monotonic_ms()) {
+            if let Err(e) = save_error_counters_to_nvs(&error_counters) {
+                info!("Failed to save error counters to NVS: {:?}", e);
+            }
+            error_counters.saved(monotonic_ms());
+        }
+        // Keys are taken in the loop they arrive (10ms), the measurements keep their own cadence
+        let key_event = std::mem::take(&mut pending_keys);
+        if !key_event.is_empty() {
+            change_source = "panel";
+        }
+        // Kiosk mode: the panel locks again when idle, or when the mode is turned off
+        if !settings.kiosk_mode || !key_event.is_empty() {
+            kiosk_idle_count = 0;
+        }
+        else if kiosk_unlocked {
+            kiosk_idle_count += 1;
+            if kiosk_idle_count >= KIOSK_RELOCK_SECS * LOOPS_PER_SEC {
+                info!("Kiosk mode: panel locked again");
+                kiosk_unlocked = false;
+            }
+        }
+        if !settings.kiosk_mode {
+            kiosk_unlocked = false;
+            if kiosk_menu.take().is_some() {
+                dp.set_menu(false, "".to_string(), "".to_string(), "".to_string());
+            }
+        }
+        let gesture = settings.get_start_stop_gesture();
+        let key_ms = (timebase::monotonic_ns() / 1_000_000) as u64;
+        if !key_event.is_empty() {
+            let action_us = touchpad::now_us();
+            for input in &key_event {
+                input_latency.record(action_us.wrapping_sub(input.t_us));
+                scan_latency.record(input.sent_us.wrapping_sub(input.t_us));
+            }
+            health.set_input_latency(&input_latency.report(), &scan_latency.report());
+        }
+        for input in &key_event {
+            let key = &input.event;
+            if let Some(test) = prod_test.as_mut() {
+                // The keys are checked by the production test, and do nothing else while it runs
+                let index = match key {
+                    KeyEvent::UpKeyDown => Some(0),
+                    KeyEvent::DownKeyDown => Some(1),
+                    KeyEvent::LeftKeyDown => Some(2),
+                    KeyEvent::RightKeyDown => Some(3),
+                    KeyEvent::CenterKeyDown => Some(4),
+                    _ => None,
+                };
+                if let Some(index) = index {
+                    test.key(index, key_ms);
+                    if test.current() == Some(TestStep::Touch) {
+                        dp.set_menu(true, "Production Test".to_string(), "Touch keys".to_string(), test.missing_keys().len().to_string());
+                    }
+                }
+                continue;
+            }
+            if settings.kiosk_mode && !kiosk_unlocked {
+                // Only the unlock code is taken; without a code the panel stays locked
+                if settings.protection_unlock_code.is_empty() {
+                    dp.set_message("Kiosk Mode".to_string(), false, 2000);
+                    continue;
+                }
+                let menu = match kiosk_menu.as_mut() {
+                    Some(menu) => menu,
+                    None => {
+                        // The first key opens the prompt
+                        let menu = SettingsMenu::new("Kiosk Mode", Vec::new(), &settings.protection_unlock_code);
+                        menu.update_display(&mut dp);
+                        kiosk_menu = Some(menu);
+                        continue;
                     },
+                };
+                if menu.handle_key(key) == MenuAction::Exit {
+                    kiosk_menu = None;
+                    dp.set_menu(false, "".to_string(), "".to_string(), "".to_string());
+                }
+                else if menu.is_unlocked() {
+                    info!("Kiosk mode: panel unlocked");
+                    txd.push_event("kiosk_unlock", &format!("relock_secs={}i", KIOSK_RELOCK_SECS));
+                    kiosk_unlocked = true;
+                    kiosk_menu = None;
+                    dp.set_menu(false, "".to_string(), "".to_string(), "".to_string());
+                }
+                else {
+                    menu.update_display(&mut dp);
+                }
+                continue;
+            }
+            if factory_reset_confirm {
+                match key {
                     KeyEvent::CenterKeyDownLong => {
-                        if start_stop_btn == false {
-                            start_stop_btn = true;
-                        }
-                        else {
-                            start_stop_btn = false;
-                        } 
+                        factory_reset(&config_file);
                     },
-                    KeyEvent::UpKeyDown => {
-                        set_output_voltage += 0.1;
-                        if set_output_voltage > pdo_max_voltage {
-                            set_output_voltage = pdo_max_voltage;
-                        }
-                        dp.set_output_voltage(set_output_voltage);
+                    KeyEvent::UpKeyDown | KeyEvent::DownKeyDown | KeyEvent::LeftKeyDown | KeyEvent::RightKeyDown => {
+                        info!("Factory reset cancelled");
+                        factory_reset_confirm = false;
+                        dp.set_menu(false, "".to_string(), "".to_string(), "".to_string());
                     },
-                    KeyEvent::RightKeyDown => {
-                        set_output_voltage += 0.01;
-                        if set_output_voltage > pdo_max_voltage {
-                            set_output_voltage = pdo_max_voltage;
-                        }
-                        dp.set_output_voltage(set_output_voltage);
+                    _ => {},
+                }
+                continue;
+            }
+            if stats_page && matches!(key, KeyEvent::CenterKeyDownLong) {
+                // Ripple measurement of the channel shown, the result replaces the statistics
+                stats_page = false;
+                ripple_page = true;
+                dp.set_menu(true, format!("Ripple CH{}", selected_channel + 1), "Measuring..".to_string(), "".to_string());
+                control.send(ControlCommand::Ripple(selected_channel));
+                continue;
+            }
+            if stats_page && matches!(key, KeyEvent::RightKeyDownLong) {
+                stats_page = false;
+                cable_start = true;
+                continue;
+            }
+            if about_page && matches!(key, KeyEvent::RightKeyDown) {
+                about_page = false;
+                network_page = true;
+                show_network(&mut dp, &wifi.get_state(), txd.unsent_points(clogs.get_size()), txd.last_upload_age_secs());
+                continue;
+            }
+            if network_page && matches!(key, KeyEvent::RightKeyDown) {
+                network_page = false;
+                let names = testscripts::list();
+                show_script(&mut dp, &names, 0);
+                script_page = Some((names, 0));
+                continue;
+            }
+            if let Some((names, index)) = script_page.as_mut() {
+                // Up/Down select the script, a long press of Center runs it, other keys close the page
+                match key {
+                    KeyEvent::UpKeyDown | KeyEvent::DownKeyDown if !names.is_empty() => {
+                        let step = if matches!(key, KeyEvent::UpKeyDown) { names.len() - 1 } else { 1 };
+                        *index = (*index + step) % names.len();
+                        show_script(&mut dp, names, *index);
                     },
-                    KeyEvent::UpKeyDownLong => {
-                        set_output_voltage = ((set_output_voltage + 1.0) as u32) as f32;
-                        if set_output_voltage > pdo_max_voltage {
-                            set_output_voltage = pdo_max_voltage;
+                    KeyEvent::CenterKeyDownLong if !names.is_empty() => {
+                        let busy = cycle_test.is_some() || cable_test.is_some() || pd_probe.is_some() || pd_cal.is_some();
+                        match script_request(&mut script_run, ScriptRequest::Run(names[*index].clone()), busy, &mut txd) {
+                            Ok(_) => dp.set_message(format!("Script {}", names[*index]), true, 3),
+                            Err(e) => {
+                                warn!("Test script: {}", e);
+                                dp.set_message("Script Busy".to_string(), true, 3);
+                            },
                         }
-                        dp.set_output_voltage(set_output_voltage);
+                        script_page = None;
+                        dp.set_menu(false, "".to_string(), "".to_string(), "".to_string());
                     },
-                    KeyEvent::DownKeyDown => {
-                        set_output_voltage -= 0.1;
-                        if set_output_voltage < 0.0 {
-                            set_output_voltage = 0.0;
-                        }
-                        dp.set_output_voltage(set_output_voltage);
+                    KeyEvent::UpKeyDown | KeyEvent::DownKeyDown | KeyEvent::LeftKeyDown | KeyEvent::RightKeyDown | KeyEvent::CenterKeyDown => {
+                        script_page = None;
+                        dp.set_menu(false, "".to_string(), "".to_string(), "".to_string());
+                    },
+                    _ => {},
+                }
+                continue;
+            }
+            if about_page || network_page || stats_page || ripple_page || cable_page {
+                // Any key closes the about and network pages, the statistics and the ripple result
+                match key {
+                    KeyEvent::UpKeyDown | KeyEvent::DownKeyDown | KeyEvent::LeftKeyDown | KeyEvent::RightKeyDown | KeyEvent::CenterKeyDown => {
+                        about_page = false;
+                        network_page = false;
+                        stats_page = false;
+                        ripple_page = false;
+                        cable_page = false;
+                        dp.set_menu(false, "".to_string(), "".to_string(), "".to_string());
                     },
-                    KeyEvent::LeftKeyDown => {
-                        set_output_voltage -= 0.01;
-                        if set_output_voltage < 0.0 {
-                            set_output_voltage = 0.0;
+                    _ => {},
+                }
+                continue;
+            }
+            if let Some(menu) = protection_menu.as_mut() {
+                let action = menu.handle_key(key);
+                match action {
+                    MenuAction::Save => {
+                        settings.max_current_limit = menu.get_value("Current Limit").unwrap_or(max_current_limit);
+                        settings.max_power_limit = menu.get_value("Power Limit").unwrap_or(max_power_limit);
+                        settings.max_temperature = menu.get_value("Temp Limit").unwrap_or(max_temperature);
+                        if let Err(e) = settings.save() {
+                            info!("Failed to save protection settings to NVS: {:?}", e);
                         }
-                        dp.set_output_voltage(set_output_voltage);
+                        max_current_limit = settings.max_current_limit;
+                        max_power_limit = settings.max_power_limit;
+                        max_temperature = settings.max_temperature;
+                        effective_max_current = if pdo_max_current < max_current_limit { pdo_max_current } else { max_current_limit };
+                        session_current_limit = session_current_limit.min(effective_max_current);
+                        current_limit = session_current_limit;
+                        info!("[Protection Limit] Current: {:.3}A (Effective {:.3}A)  Power: {:.1}W  Temperature: {:.0}°C",
+                              max_current_limit, effective_max_current, max_power_limit, max_temperature);
+                    },
+                    MenuAction::Exit => {
+                        info!("Protection settings menu closed without saving");
                     },
-                    KeyEvent::DownKeyDownLong => {
-                        set_output_voltage = ((set_output_voltage - 1.0) as u32) as f32;
-                        if set_output_voltage < 0.0 {
-                            set_output_voltage = 0.0;
+                    MenuAction::None => {
+                        menu.update_display(&mut dp);
+                    },
+                }
+                if action != MenuAction::None {
+                    protection_menu = None;
+                    dp.set_menu(false, "".to_string(), "".to_string(), "".to_string());
+                }
+                continue;
+            }
+            if let Some(menu) = pid_menu.as_mut() {
+                let action = menu.handle_key(key);
+                match action {
+                    MenuAction::Save => {
+                        let update = PidUpdate {
+                            kp: menu.get_value("Kp").map(|v| v * 1e-7),
+                            ki: menu.get_value("Ki").map(|v| v * 1e-6),
+                            kd: menu.get_value("Kd"),
+                            pwm_offset: menu.get_value("PWM Offset").map(|v| v as u32),
+                        };
+                        if let Err(e) = change_pid(&mut settings, PidChange::Set(update)) {
+                            warn!("Failed to change the PID gains: {}", e);
                         }
-                        dp.set_output_voltage(set_output_voltage);
                     },
-                    KeyEvent::UpDownKeyCombinationDown => {
+                    MenuAction::Exit => {
+                        info!("PID settings menu closed without saving");
+                    },
+                    MenuAction::None => {
+                        menu.update_display(&mut dp);
+                    },
+                }
+                if action != MenuAction::None {
+                    pid_menu = None;
+                    dp.set_menu(false, "".to_string(), "".to_string(), "".to_string());
+                }
+                continue;
+            }
+            // Setpoint of the channel shown and its range
+            let ch2_max_current = settings.ch2_max_current_limit.min(pdo_max_current);
+            let (setpoint, lower, upper) = match (selected_channel == CH2, adjust) {
+                (false, Adjust::Voltage) => (&mut set_output_voltage, 0.0, pdo_max_voltage),
+                (true, Adjust::Voltage) => (&mut ch2_setpoint, 0.0, pdo_max_voltage),
+                (false, Adjust::CurrentLimit) => (&mut session_current_limit, SESSION_CURRENT_LIMIT_MIN, effective_max_current),
+                (true, Adjust::CurrentLimit) => (&mut ch2_session_current_limit, SESSION_CURRENT_LIMIT_MIN, ch2_max_current),
+                (false, Adjust::PowerLimit) => (&mut session_power_limit, SESSION_POWER_LIMIT_MIN, max_power_limit),
+                (true, Adjust::PowerLimit) => (&mut ch2_session_power_limit, SESSION_POWER_LIMIT_MIN, settings.ch2_max_power_limit),
+            };
+            match key {
+                KeyEvent::CenterKeyDown => {
+                    if gesture != StartStopGesture::CenterDouble {
+                        center_short = true;
+                    }
+                    // The single tap is taken once the double-tap window passed
+                    else if center_taps.press(key_ms) == Some(Tap::Double) {
+                        if selected_channel == CH2 {
+                            ch2_start_stop = true;
+                        }
+                        else {
+                            start_stop_btn = true;
+                        }
+                    }
+                },
+                KeyEvent::CenterKeyUp => {
+                    if center_short {
+                        center_short = false;
+                        select_next_setpoint(&mut dp, &mut adjust, &mut selected_channel, ch2_present);
+                    }
+                },
+                KeyEvent::CenterUpKeyCombinationDown | KeyEvent::CenterDownKeyCombinationDown => {
+                    center_short = false;
+                    center_taps.clear();
+                    adjust_step = if matches!(key, KeyEvent::CenterUpKeyCombinationDown) {
+                        adjust_step.saturating_sub(1)
+                    }
+                    else {
+                        (adjust_step + 1).min(ADJUST_STEPS.len() - 1)
+                    };
+                    dp.set_message(format!("Step {}{}", ADJUST_STEPS[adjust_step], adjust.unit()), true, 1);
+                },
+                KeyEvent::CenterKeyDownLong if gesture == StartStopGesture::CenterLong => {
+                    center_short = false;
+                    if selected_channel == CH2 {
+                        ch2_start_stop = !ch2_start_stop;
+                    }
+                    else if start_stop_btn == false {
+                        start_stop_btn = true;
+                    }
+                    else {
+                        start_stop_btn = false;
+                    } 
+                },
+                // By the step selected, repeated while the key is held
+                KeyEvent::UpKeyDown | KeyEvent::UpKeyDownLong => {
+                    *setpoint += ADJUST_STEPS[adjust_step];
+                    if *setpoint > upper {
+                        *setpoint = upper;
+                    }
+                },
+                KeyEvent::DownKeyDown | KeyEvent::DownKeyDownLong => {
+                    *setpoint -= ADJUST_STEPS[adjust_step];
+                    if *setpoint < lower {
+                        *setpoint = lower;
+                    }
+                },
+                KeyEvent::UpDownKeyCombinationDown => {
+                    if measurement_count < FACTORY_RESET_BOOT_WINDOW_COUNT && load_start == false {
+                        // Up+Down right after boot: factory reset
+                        factory_reset_confirm = true;
+                        dp.set_menu(true, "Factory Reset".to_string(), "Erase all?".to_string(), "Hold C".to_string());
+                    }
+                    else {
                         // Calibration
                         calibration_start = true;
-                    },
-                    _ => {},
+                    }
+                },
+                KeyEvent::LeftKeyDownLong => {
+                    // About page while the output is off, regulation statistics while it is on
+                    if load_start == false && ch2_output == false {
+                        about_page = true;
+                        dp.set_menu(true, "About".to_string(), format!("v{}", version::VERSION), version::GIT_HASH.to_string());
+                    }
+                    else {
+                        stats_page = true;
+                        show_regulation(&mut dp, selected_channel, &regulation[selected_channel]);
+                    }
+                },
+                KeyEvent::RightKeyDownLong => {
+                    // PID gains, applied live
+                    let menu = pid_settings_menu(&settings);
+                    menu.update_display(&mut dp);
+                    pid_menu = Some(menu);
+                },
+                KeyEvent::RightKeyDown => {
+                    // Marker key: annotate the data at this moment
+                    let _ = add_marker(&mut markers, MarkerSource::Key, "", &mut txd, &mut dp, &control);
+                },
+                KeyEvent::LeftRightKeyCombinationDown => {
+                    // Protection settings can only be changed while the output is off
+                    if load_start == false {
+                        let menu = protection_settings_menu(&settings);
+                        menu.update_display(&mut dp);
+                        protection_menu = Some(menu);
+                    }
+                },
+                _ => {},
+            }
+        }
+        // if key_event.len() > 0 {
+        //     dp.set_message("".to_string(), false);
+        // }
+        if center_taps.poll(key_ms) == Some(Tap::Single) {
+            // Dropped if a page or a menu was opened meanwhile
+            let pages = about_page || network_page || stats_page || ripple_page || cable_page || script_page.is_some() || factory_reset_confirm;
+            if !pages && protection_menu.is_none() && pid_menu.is_none() {
+                select_next_setpoint(&mut dp, &mut adjust, &mut selected_channel, ch2_present);
+            }
+        }
+        // Start/stop push button, taken like the panel keys
+        let button_ms = (timebase::monotonic_ns() / 1_000_000) as u64;
+        if start_stop_button.update(start_stop_pin.is_low(), button_ms) && settings.get_start_stop_gesture() == StartStopGesture::Input {
+            if settings.kiosk_mode && !kiosk_unlocked {
+                dp.set_message("Kiosk Mode".to_string(), false, 2000);
+            }
+            else {
+                info!("Start/stop input pressed: CH{}", selected_channel + 1);
+                change_source = "panel";
+                if selected_channel == CH2 {
+                    ch2_start_stop = true;
+                }
+                else {
+                    start_stop_btn = true;
+                }
+            }
+        }
+        // Requests of the desktop app; outputs and setpoints are run as console commands
+        let host_channel = |ch: u8| hostlink::channel_index(ch).and_then(|index| {
+            if index == CH2 && !ch2_present { Err("channel 2 is not enabled (ch2_enable)".to_string()) } else { Ok(index) }
+        });
+        for request in host_requests {
+            let result = match request.command {
+                HostCommand::Hello => {
+                    Ok(json!({ "protocol": hostlink::PROTOCOL_VERSION, "version": version::VERSION, "channels": control.channel_count() }))
+                },
+                HostCommand::Status => {
+                    let outputs = [load_start, ch2_output];
+                    let setpoints = [set_output_voltage, ch2_setpoint];
+                    let limits = [current_limit, control_ch2_limits.0];
+                    let channels : Vec<Value> = measurement.channels.iter().enumerate().map(|(index, ch)| json!({
+                        "ch": index + 1, "output": outputs[index], "setpoint": setpoints[index], "limit": limits[index],
+                        "voltage": ch.voltage, "current": ch.current, "power": ch.power,
+                    })).collect();
+                    Ok(json!({ "channels": channels, "temp": last_data.temp, "pd_voltage": pd_contract_voltage, "records": clogs.get_size() }))
+                },
+                HostCommand::Output { ch, on } => host_channel(ch).map(|index| {
+                    console_commands.push(ConsoleCommand::Output(index, on));
+                    json!({})
+                }),
+                HostCommand::Voltage { ch, value } => host_channel(ch).map(|index| {
+                    console_commands.push(ConsoleCommand::Voltage(index, value));
+                    json!({})
+                }),
+                HostCommand::Current { ch, value } => host_channel(ch).map(|index| {
+                    console_commands.push(ConsoleCommand::Current(index, value));
+                    json!({})
+                }),
+                HostCommand::Stream { rate_hz } if rate_hz > hostlink::MAX_STREAM_RATE_HZ => {
+                    Err(format!("rate_hz must be 0 to {}", hostlink::MAX_STREAM_RATE_HZ))
+                },
+                HostCommand::Stream { rate_hz } => {
+                    host_stream_divider = if rate_hz == 0 { 0 } else { LOOPS_PER_SEC / rate_hz };
+                    Ok(json!({ "rate_hz": if rate_hz == 0 { 0 } else { LOOPS_PER_SEC / host_stream_divider } }))
+                },
+                HostCommand::Raw { on } => {
+                    console_commands.push(ConsoleCommand::Raw(Some(on)));
+                    Ok(json!({}))
+                },
+            };
+            hostlink::reply(request.id, result);
+        }
+        if host_stream_divider > 0 && new_measurement && measurement_count % host_stream_divider == 0 {
+            let outputs = [load_start, ch2_output];
+            for (index, ch) in measurement.channels.iter().enumerate() {
+                hostlink::send_sample(&HostSample {
+                    t_ms: (measurement.clock / 1_000_000) as u64,
+                    ch: index as u8 + 1,
+                    output: outputs[index],
+                    voltage: ch.voltage,
+                    current: ch.current,
+                    power: ch.power,
+                });
+            }
+        }
+        // Console Commands
+        for cmd in console_commands {
+            match cmd {
+                ConsoleCommand::Status => {
+                    println!("output={} setpoint={:.3}V voltage={:.5}V current={:.5}A power={:.5}W temp={:.1}C pwm={} pd={:.2}V limit={:.3}A records={}",
+                        if load_start { "on" } else { "off" }, set_output_voltage,
+                        last_data.voltage, last_data.current, last_data.power, last_data.temp, last_data.pwm,
+                        pd_contract_voltage, current_limit, clogs.get_size());
+                    println!("records lost={} resent_batches={} upload={}", transfer::lost_count(), transfer::resent_count(),
+                        if upload_throttle.is_throttled() { "throttled" } else { "full" });
+                    println!("unsent records={} events={} last_upload={}", clogs.get_size(), txd.pending_events(),
+                        txd.last_upload_age_secs().map_or("never".to_string(), |secs| format!("{}s ago", secs)));
+                    println!("input_energy={:.4}Wh output_energy={:.4}Wh efficiency={}", efficiency.get_input_energy_wh(), efficiency.get_output_energy_wh(),
+                        last_efficiency.map_or("--".to_string(), |e| format!("{:.1}%{}", e, if efficiency.is_low() { " (low)" } else { "" })));
+                    for (index, r) in regulation.iter().enumerate() {
+                        println!("ch{} regulation error={:+.5}V peak={:.5}V load_steps={} recovery={:.0}ms max_recovery={:.0}ms samples={}",
+                            index + 1, r.steady_state_error, r.peak_deviation, r.load_steps, r.recovery_time_ms, r.max_recovery_time_ms, r.samples);
+                    }
+                    if control_remote_sense.is_some() {
+                        println!("remote_sense={}", if remote_sense_lost { "lost" } else { "active" });
+                    }
+                    if settings.kiosk_mode {
+                        println!("kiosk={}", if kiosk_unlocked { "unlocked" } else { "locked" });
+                    }
+                    if settings.get_current_limit_mode() == CurrentLimitMode::Pps {
+                        println!("current_limit_mode=pps operating={}mA{}", soft_limit.get_operating_ma(),
+                            if soft_limit.is_limiting() { " (limiting)" } else { "" });
+                    }
+                    if thermal.is_enabled() {
+                        println!("thermal model={:.1}C ambient={:.1}C rise={:.1}C{}", thermal.estimate(), thermal.get_ambient(), thermal.get_rise(),
+                            thermal_current_limit.map_or("".to_string(), |c| format!(" derate={:.2}A", c)));
+                    }
+                    if settings.safe_mode {
+                        println!("safe_mode=on max_voltage={:.1}V max_current={:.1}A", SAFE_MODE_MAX_VOLTAGE, SAFE_MODE_MAX_CURRENT);
+                    }
+                    if !run_label.is_empty() {
+                        println!("dut={} note={}", run_label.dut, run_label.note);
+                    }
+                    if let Some(ch2) = measurement.channels.get(CH2) {
+                        println!("ch2 output={} setpoint={:.3}V voltage={:.5}V current={:.5}A power={:.5}W pwm={} limit={:.3}A",
+                            if ch2_output { "on" } else { "off" }, ch2_setpoint,
+                            ch2.voltage, ch2.current, ch2.power, ch2.pwm, control_ch2_limits.0);
+                    }
+                },
+                ConsoleCommand::Get(Some(name)) => {
+                    match settings.get_field(&name) {
+                        Some(value) => println!("{}={}", name, value),
+                        None => println!("unknown setting: {}", name),
+                    }
+                },
+                ConsoleCommand::Get(None) => {
+                    for name in settings.field_names() {
+                        println!("{}={}", name, settings.get_field(&name).unwrap_or_default());
+                    }
+                },
+                ConsoleCommand::Set(name, value) => {
+                    // A protected setting takes the unlock code after the value
+                    let (value, code) = match value.split_once(' ') {
+                        Some((value, code)) if PROTECTED_FIELDS.contains(&name.as_str()) => (value.to_string(), code.trim().to_string()),
+                        _ => (value.clone(), String::new()),
+                    };
+                    let mut new_settings = settings.clone();
+                    match new_settings.set_field(&name, &value).and_then(|_| settings.unlock(new_settings, &code)) {
+                        Ok(new_settings) => {
+                            settings = new_settings;
+                            if let Err(e) = settings.save() {
+                                println!("Failed to save settings: {:?}", e);
+                            }
+                            // Protection limits, the log level and the PID gains are applied immediately, other settings after reboot
+                            max_current_limit = settings.max_current_limit;
+                            max_power_limit = settings.max_power_limit;
+                            max_temperature = settings.max_temperature;
+                            effective_max_current = if pdo_max_current < max_current_limit { pdo_max_current } else { max_current_limit };
+                            session_current_limit = session_current_limit.min(effective_max_current);
+                            current_limit = session_current_limit;
+                            if name == "log_level" {
+                                logfilter::set(Sink::Console, logfilter::from_setting("log_level", &settings.log_level));
+                            }
+                            if name == "syslog_level" {
+                                logfilter::set(Sink::Syslog, logfilter::from_setting("syslog_level", &settings.syslog_level));
+                            }
+                            if name == "schedule" {
+                                schedule = new_schedule(&settings);
+                            }
+                            if name == "alarms" {
+                                alarm_rules = new_alarms(&settings);
+                            }
+                            dp.set_unit_format(settings.get_unit_format());
+                            println!("{}={} (saved)", name, settings.get_field(&name).unwrap_or_default());
+                        },
+                        Err(e) => println!("{}", e),
+                    }
+                },
+                ConsoleCommand::Output(CH1, on) => {
+                    if on != load_start {
+                        start_stop_btn = true;
+                    }
+                },
+                ConsoleCommand::Voltage(CH1, voltage) => {
+                    set_output_voltage = voltage.clamp(0.0, pdo_max_voltage);
+                    println!("setpoint={:.3}V", set_output_voltage);
+                },
+                ConsoleCommand::Current(CH1, current) => {
+                    session_current_limit = current.min(effective_max_current).max(SESSION_CURRENT_LIMIT_MIN);
+                    println!("limit={:.3}A", session_current_limit);
+                },
+                ConsoleCommand::Output(_, _) | ConsoleCommand::Voltage(_, _) | ConsoleCommand::Current(_, _) if !ch2_present => {
+                    println!("channel 2 is not enabled (ch2_enable)");
+                },
+                ConsoleCommand::Output(_, on) => {
+                    if on != ch2_output {
+                        ch2_start_stop = true;
+                    }
+                },
+                ConsoleCommand::Voltage(_, voltage) => {
+                    ch2_setpoint = voltage.clamp(0.0, pdo_max_voltage);
+                    println!("ch2 setpoint={:.3}V", ch2_setpoint);
+                },
+                ConsoleCommand::Current(_, current) => {
+                    ch2_session_current_limit = current.min(settings.ch2_max_current_limit.min(pdo_max_current)).max(SESSION_CURRENT_LIMIT_MIN);
+                    println!("ch2 limit={:.3}A", ch2_session_current_limit);
+                },
+                ConsoleCommand::Calibrate => {
+                    calibration_start = true;
+                },
+                ConsoleCommand::Identify(secs) => {
+                    identify(&mut dp, &wifi, secs);
+                    println!("identify {}s", secs);
+                },
+                ConsoleCommand::Marker(text) => {
+                    match add_marker(&mut markers, MarkerSource::Console, &text, &mut txd, &mut dp, &control) {
+                        Ok(marker) => println!("{}", marker.to_text()),
+                        Err(e) => println!("{}", e),
+                    }
+                },
+                ConsoleCommand::ProdTest => {
+                    if prod_test.is_some() {
+                        println!("production test already running");
+                    }
+                    else if load_start || ch2_output {
+                        println!("production test needs the outputs off");
+                    }
+                    else {
+                        info!("Production test started");
+                        prod_test = Some(ProductionTest::new());
+                    }
+                },
+                ConsoleCommand::Calibration(action) => {
+                    match calibration_request(&mut offset_cal, action, load_start || ch2_output, &control) {
+                        Ok(()) => {
+                            if action == CalibrationAction::Start {
+                                dp.set_message("Calibration..".to_string(), true, 0);
+                            }
+                            println!("calibration {}", calibration_text(&offset_cal.status()));
+                        },
+                        Err(e) => println!("{}", e),
+                    }
+                },
+                ConsoleCommand::Ripple(index) => {
+                    if index < control.channel_count() {
+                        control.send(ControlCommand::Ripple(index));
+                    }
+                    else {
+                        println!("channel 2 is not enabled (ch2_enable)");
+                    }
+                },
+                ConsoleCommand::Cable => {
+                    cable_start = true;
+                },
+                ConsoleCommand::I2cStatus => {
+                    for device in i2cbus::devices() {
+                        println!("{}", device.to_text());
+                    }
+                    println!("bus recoveries: {} (SDA still held: {})", i2cbus::recovery_count(), i2cbus::recovery_failed_count());
+                },
+                ConsoleCommand::I2cScan => {
+                    control.send(ControlCommand::I2cScan);
+                },
+                ConsoleCommand::Capture(Some(trigger)) => {
+                    // The window size of the settings when armed
+                    capture = Capture::new(settings.capture_pre_samples as usize, settings.capture_post_samples as usize);
+                    capture.arm(trigger);
+                    info!("Capture armed: {:?}, {} pre / {} post samples", trigger, settings.capture_pre_samples, settings.capture_post_samples);
+                    println!("capture armed: {:?}", trigger);
+                },
+                ConsoleCommand::Capture(None) => {
+                    capture.disarm();
+                    println!("capture off");
+                },
+                ConsoleCommand::Schedule => {
+                    match local_day_minute(wall_clock_ns, settings.utc_offset_minutes) {
+                        Some((weekday, minute)) => println!("local time: day {} (0 is Monday) {:02}:{:02} (UTC{:+}min)",
+                            weekday, minute / 60, minute % 60, settings.utc_offset_minutes),
+                        None => println!("local time: not set (waiting for NTP)"),
+                    }
+                    for entry in schedule.get_entries() {
+                        println!("{}", entry.to_text());
+                    }
+                    if schedule.is_empty() {
+                        println!("no schedule (set schedule \"weekdays 08:00 on; weekdays 18:00 off\")");
+                    }
+                },
+                ConsoleCommand::Alarms => {
+                    for (index, rule) in alarm_rules.get_rules().iter().enumerate() {
+                        println!("{} {}", if alarm_rules.is_raised(index) { "RAISED" } else { "ok    " }, rule.to_text());
+                    }
+                    if alarm_rules.is_empty() {
+                        println!("no alarm rules (set alarms \"current > 2A for 5s; voltage < 4.5V\")");
+                    }
+                },
+                ConsoleCommand::CycleStart(_) if cycle_test.is_some() => {
+                    println!("cycle test already running (cycle stop)");
+                },
+                ConsoleCommand::CycleStart(params) => {
+                    let (voltage, on_secs, off_secs, cycles) = params.unwrap_or((settings.cycle_voltage,
+                        settings.cycle_on_secs, settings.cycle_off_secs, settings.cycle_count));
+                    set_output_voltage = voltage.clamp(0.0, pdo_max_voltage);
+                    // The first on period starts the output as the start key does
+                    if !load_start {
+                        start_stop_btn = true;
+                    }
+                    cycle_test = Some(CycleTest::new(set_output_voltage, on_secs * 1000, off_secs * 1000, cycles));
+                    info!("Cycle test: {:.3}V on {}s off {}s, {} cycles", set_output_voltage, on_secs, off_secs, cycles);
+                    txd.push_event("cycle_start", &format!("voltage={:.3},on={}i,off={}i,cycles={}i", set_output_voltage, on_secs, off_secs, cycles));
+                    println!("cycle test started: {:.3}V on {}s off {}s, {} cycles", set_output_voltage, on_secs, off_secs, cycles);
+                },
+                ConsoleCommand::SyncStart(_, _) if sync_net.is_none() => {
+                    println!("sync is off (set sync_group and reboot)");
+                },
+                ConsoleCommand::SyncStart(action, run_id) => {
+                    let start_ms = wall_clock_ms() + settings.sync_lead_ms as u64;
+                    let run_id = run_id.unwrap_or_else(|| format!("run-{}", start_ms / 1000));
+                    let message = match SyncMessage::new(&settings.sync_group, &run_id, action, start_ms) {
+                        Ok(message) => message,
+                        Err(e) => {
+                            println!("{}", e);
+                            continue;
+                        },
+                    };
+                    // Scheduled here as on the other units; the echo of the broadcast is a repeat
+                    match sync.accept(message.clone(), wall_clock_ms()) {
+                        Ok(_) => {
+                            if let Some(Err(e)) = sync_net.as_ref().map(|net| net.broadcast(&message)) {
+                                warn!("Sync broadcast failed: {:?}", e);
+                            }
+                            println!("sync {} run {} in {}ms", action.as_str(), run_id, settings.sync_lead_ms);
+                        },
+                        Err(e) => println!("sync failed: {}", e),
+                    }
+                },
+                ConsoleCommand::SyncCancel => {
+                    match sync.cancel() {
+                        Some(message) => println!("sync run {} cancelled on this unit", message.run_id),
+                        None => println!("no sync start pending"),
+                    }
+                },
+                ConsoleCommand::SyncStatus => {
+                    if sync_net.is_none() {
+                        println!("sync: off");
+                    }
+                    else {
+                        println!("sync group={} port={} lead={}ms last run={}", sync.get_group(), settings.sync_port,
+                            settings.sync_lead_ms, sync.get_last_run_id().unwrap_or("none"));
+                    }
+                    if let Some(message) = sync.get_pending() {
+                        println!("pending: {} run {} in {}ms", message.action.as_str(), message.run_id,
+                            message.start_ms.saturating_sub(wall_clock_ms()));
+                    }
+                },
+                ConsoleCommand::CycleStop => {
+                    match cycle_test.as_mut() {
+                        Some(test) => test.abort("stopped"),
+                        None => println!("no cycle test running"),
+                    }
+                },
+                ConsoleCommand::Script(request) => {
+                    let busy = cycle_test.is_some() || cable_test.is_some() || pd_probe.is_some() || pd_cal.is_some() || prod_test.is_some();
+                    match script_request(&mut script_run, request, busy, &mut txd) {
+                        Ok(status) => {
+                            println!("script {}", script_text(&status));
+                            for check in &status.checks {
+                                println!("  step {} {} {} ({:.4})", check.step + 1, check.text, if check.pass { "pass" } else { "FAIL" }, check.value);
+                            }
+                        },
+                        Err(e) => println!("{}", e),
+                    }
+                },
+                ConsoleCommand::ScriptList => {
+                    let names = testscripts::list();
+                    if names.is_empty() {
+                        println!("no scripts (upload them with POST /scripts)");
+                    }
+                    for name in names {
+                        println!("{}", name);
+                    }
+                },
+                ConsoleCommand::ScriptShow(name) => {
+                    match testscripts::load(&name) {
+                        Ok(script) => {
+                            for (index, step) in script.get_steps().iter().enumerate() {
+                                println!("{:2} {}", index + 1, step.to_text());
+                            }
+                        },
+                        Err(e) => println!("{}", e),
+                    }
+                },
+                ConsoleCommand::ScriptDelete(name) => {
+                    match testscripts::remove(&name) {
+                        Ok(()) => println!("script {} deleted", name),
+                        Err(e) => println!("{}", e),
+                    }
+                },
+                ConsoleCommand::CycleStatus => {
+                    match cycle_test.as_ref() {
+                        Some(test) => println!("cycle test: {:?} completed={}/{} setpoint={:.3}V",
+                            test.phase(), test.get_completed(), test.get_cycles(), test.get_setpoint()),
+                        None => println!("cycle test: {}", cycle_result),
+                    }
+                },
+                ConsoleCommand::PdProbeStart if pd_probe.is_some() => {
+                    println!("pd probe already running (pdprobe stop)");
+                },
+                ConsoleCommand::PdProbeStart if pd_cal.is_some() || pwm_offset_learning.is_some() => {
+                    println!("pd calibration or PWM offset learning running");
+                },
+                ConsoleCommand::PdProbeStart if load_start || ch2_output || cycle_test.is_some() => {
+                    println!("pd probe needs the outputs off");
+                },
+                ConsoleCommand::PdProbeStart => {
+                    let probe = PdProbe::new(&pdo_points, settings.pd_probe_tolerance);
+                    info!("USB PD probe: {} PDOs, {} points", pdo_points.len(), probe.progress().1);
+                    println!("pd probe started: {} PDOs, {} points", pdo_points.len(), probe.progress().1);
+                    pd_probe = Some(probe);
+                },
+                ConsoleCommand::PdProbeStop => {
+                    match pd_probe.as_mut() {
+                        Some(probe) => probe.abort("stopped"),
+                        None => println!("no pd probe running"),
+                    }
+                },
+                ConsoleCommand::PdProbeStatus => {
+                    match pd_probe.as_ref() {
+                        Some(probe) => println!("pd probe: point {}/{}", probe.progress().0 + 1, probe.progress().1),
+                        None => println!("{}", pd_probe_report),
+                    }
+                },
+                ConsoleCommand::PdCalStart if pd_cal.is_some() || pd_probe.is_some() || pwm_offset_learning.is_some() => {
+                    println!("pd calibration, probe or PWM offset learning already running");
+                },
+                ConsoleCommand::PdCalStart if load_start || ch2_output || cycle_test.is_some() => {
+                    println!("pd calibration needs the outputs off");
+                },
+                ConsoleCommand::PdCalStart => {
+                    // 5V and the highest fixed PDO
+                    let high = pdo_points.iter().filter(|p| p.fixed).map(|p| p.voltage).fold(0.0, f32::max);
+                    match PdVoltageCalibration::new(5.0, high) {
+                        Ok(cal) => {
+                            info!("USB PD voltage calibration: 5.00V and {:.2}V", high);
+                            println!("pd calibration started: 5.00V and {:.2}V", high);
+                            pd_cal = Some(cal);
+                        },
+                        Err(e) => println!("pd calibration not possible with this charger: {}", e),
+                    }
+                },
+                ConsoleCommand::PdCalStop => {
+                    match pd_cal.as_mut() {
+                        Some(cal) => cal.abort("stopped"),
+                        None => println!("no pd calibration running"),
+                    }
+                },
+                ConsoleCommand::PdCalClear => {
+                    settings.pd_voltage_gain = 1.0;
+                    settings.pd_voltage_offset = 0.0;
+                    match settings.save() {
+                        Ok(()) => println!("pd voltage correction cleared"),
+                        Err(e) => println!("failed to save settings: {:?}", e),
+                    }
+                },
+                ConsoleCommand::PdCalStatus => {
+                    if let Some(cal) = pd_cal.as_ref() {
+                        println!("pd calibration: point {}/2", (cal.get_points().len() + 1).min(2));
+                    }
+                    println!("pd voltage correction: gain={:.5} offset={:+.4}V", settings.pd_voltage_gain, settings.pd_voltage_offset);
+                },
+                ConsoleCommand::PwmOffsetLearn if pwm_offset_learning.is_some() || pd_probe.is_some() || pd_cal.is_some() => {
+                    println!("PWM offset learning, pd probe or calibration already running");
+                },
+                ConsoleCommand::PwmOffsetLearn if load_start || ch2_output || cycle_test.is_some() => {
+                    println!("PWM offset learning needs the outputs off");
+                },
+                ConsoleCommand::PwmOffsetLearn => {
+                    pwm_offset_learning = Some(OffsetLearning::new(&pwm_offset_voltages, control.channel_count()));
+                    println!("PWM offset learning started");
+                },
+                ConsoleCommand::PwmOffsetStatus => {
+                    if let Some(learning) = pwm_offset_learning.as_ref() {
+                        println!("PWM offset learning: voltage {}/{}", learning.progress().0 + 1, learning.progress().1);
+                    }
+                    if pwm_offset_tables.is_empty() {
+                        println!("PWM offset: {} (configured)", settings.pwm_offset);
+                    }
+                    for (index, table) in pwm_offset_tables.iter().enumerate() {
+                        println!("CH{} PWM offset: {} (now {:?})", index + 1, table.to_text(), table.offset_at(pd_contract_voltage));
+                    }
+                },
+                ConsoleCommand::Dut(label) => {
+                    if let Some(label) = label {
+                        run_label = label;
+                        apply_run_label(&run_label, &mut sessions, &mut txd);
+                    }
+                    if run_label.is_empty() {
+                        println!("dut: none");
+                    }
+                    else {
+                        println!("dut={} note={}", run_label.dut, run_label.note);
+                    }
+                },
+                ConsoleCommand::CaptureStatus => {
+                    println!("capture state={:?} trigger={:?} samples={}", capture.state(), capture.trigger(), capture.get_size());
+                },
+                ConsoleCommand::Raw(Some(true)) if !raw_stream => {
+                    match RawTap::start() {
+                        Ok(tap) => {
+                            info!("Raw stream started");
+                            control.send(ControlCommand::RawStream(Some(tap)));
+                            raw_stream = true;
+                        },
+                        Err(e) => println!("Raw stream not started: {:?}", e),
+                    }
+                },
+                ConsoleCommand::Raw(Some(true)) => {},
+                ConsoleCommand::Raw(Some(false)) => {
+                    if raw_stream {
+                        control.send(ControlCommand::RawStream(None));
+                        raw_stream = false;
+                    }
+                },
+                ConsoleCommand::Raw(None) => {
+                    println!("raw={} frames_sent={} frames_dropped={}", if raw_stream { "on" } else { "off" },
+                        rawstream::sent_count(), rawstream::dropped_count());
+                },
+                ConsoleCommand::Latency(reset) => {
+                    let r = controltask::latency_report();
+                    println!("control latency p50={}us p99={}us max={}us over_budget={} (budget {}us) cycles={}",
+                        r.p50_us, r.p99_us, r.max_us, r.over_budget, r.budget_us, r.samples);
+                    let (r, scan) = (input_latency.report(), scan_latency.report());
+                    println!("input latency p50={}ms p99={}ms max={}ms scan_p99={}ms keys={}",
+                        r.p50_us / 1000, r.p99_us / 1000, r.max_us / 1000, scan.p99_us / 1000, r.samples);
+                    if reset {
+                        control.send(ControlCommand::LatencyReset);
+                        input_latency.reset();
+                        scan_latency.reset();
+                        health.set_input_latency(&input_latency.report(), &scan_latency.report());
+                    }
+                },
+                ConsoleCommand::Dump => {
+                    clogs.dump();
+                },
+                ConsoleCommand::Reboot => {
+                    println!("Rebooting..");
+                    unsafe {
+                        esp_idf_sys::esp_restart();
+                    }
+                },
+                ConsoleCommand::CrashInfo => {
+                    let info = crashdump::info();
+                    println!("reset_reason={}", info.reset_reason);
+                    match info.coredump {
+                        Some(s) => {
+                            println!("crash dump: {} bytes, task={} pc=0x{:08x}", s.size, s.task, s.pc);
+                            let bt : Vec<String> = s.backtrace.iter().map(|a| format!("0x{:08x}", a)).collect();
+                            println!("backtrace: {}", bt.join(" "));
+                        },
+                        None => println!("no crash dump"),
+                    }
+                },
+                ConsoleCommand::CrashDump => {
+                    if let Err(e) = crashdump::print_base64() {
+                        println!("Failed to read the crash dump: {:?}", e);
+                    }
+                },
+                ConsoleCommand::CrashClear => {
+                    if let Err(e) = crashdump::clear() {
+                        println!("Failed to clear the crash dump: {:?}", e);
+                    }
+                },
+                ConsoleCommand::ErrorCounters(reset) => {
+                    for (name, count) in error_counters.entries() {
+                        println!("{}={}", name, count);
+                    }
+                    if reset {
+                        error_counters.reset();
+                        health.set_error_counters(&error_counters);
+                        info!("Error counters reset");
+                        println!("error counters reset");
+                    }
+                },
+                ConsoleCommand::Log(sink, spec) => {
+                    let sinks = match sink {
+                        Some(sink) => vec![sink],
+                        None => logfilter::SINKS.to_vec(),
+                    };
+                    let result = match spec {
+                        Some(spec) => LogFilter::parse(&spec).map(|filter| {
+                            for sink in &sinks {
+                                logfilter::set(*sink, filter.clone());
+                            }
+                        }),
+                        None => Ok(()),
+                    };
+                    match result {
+                        Ok(()) => {
+                            for sink in &sinks {
+                                println!("{}={}", sink.name(), logfilter::get(*sink));
+                            }
+                        },
+                        Err(e) => println!("{}", e),
+                    }
+                },
+                ConsoleCommand::FactoryReset => {
+                    if load_start == true {
+                        println!("Stop the output before the factory reset");
+                    }
+                    else {
+                        println!("Long press Center on the unit to erase all settings, any other key to cancel");
+                        factory_reset_confirm = true;
+                        dp.set_menu(true, "Factory Reset".to_string(), "Erase all?".to_string(), "Hold C".to_string());
+                    }
+                },
+                ConsoleCommand::SettingsExport => {
+                    println!("{}", settings.export_json());
+                },
+                ConsoleCommand::Pid(change) => {
+                    match change_pid(&mut settings, change) {
+                        Ok(gains) => println!("kp={} ki={} kd={} pwm_offset={}", gains.kp, gains.ki, gains.kd, gains.pwm_offset),
+                        Err(e) => println!("{}", e),
+                    }
+                },
+                ConsoleCommand::SettingsImport(json, code) => {
+                    // Applied below like a config file reload
+                    let result = settings.import_json(&json).and_then(|new| settings.unlock(new, &code));
+                    settings_update = Some(("Settings import", "Settings Imported", result));
+                },
+            }
+        }
+        // Config file hot reload and settings import
+        if let Some((what, message, result)) = settings_update {
+            match result {
+                Ok(new_settings) => {
+                    let log_level_changed = new_settings.log_level != settings.log_level;
+                    let syslog_level_changed = new_settings.syslog_level != settings.syslog_level;
+                    let alarms_changed = new_settings.alarms != settings.alarms;
+                    settings = new_settings;
+                    if let Err(e) = settings.save() {
+                        warn!("Failed to save settings: {:?}", e);
+                    }
+                    // Protection limits, the log level and the PID gains are applied immediately, other settings after reboot
+                    max_current_limit = settings.max_current_limit;
+                    max_power_limit = settings.max_power_limit;
+                    max_temperature = settings.max_temperature;
+                    effective_max_current = if pdo_max_current < max_current_limit { pdo_max_current } else { max_current_limit };
+                    session_current_limit = session_current_limit.min(effective_max_current);
+                    current_limit = session_current_limit;
+                    if log_level_changed {
+                        logfilter::set(Sink::Console, logfilter::from_setting("log_level", &settings.log_level));
+                    }
+                    if syslog_level_changed {
+                        logfilter::set(Sink::Syslog, logfilter::from_setting("syslog_level", &settings.syslog_level));
+                    }
+                    if alarms_changed {
+                        alarm_rules = new_alarms(&settings);
+                    }
+                    dp.set_unit_format(settings.get_unit_format());
+                    info!("{} applied", what);
+                    dp.set_message(message.to_string(), true, 3);
+                },
+                Err(e) => {
+                    warn!("{} failed: {}", what, e);
                 }
             }
-            // if key_event.len() > 0 {
-            //     dp.set_message("".to_string(), false);
-            // }
         }
+        // The slave follows the output state and the setpoint of the master
+        if share_mode == ShareMode::Slave && share_net.is_some() {
+            match share_slave.update(data.current) {
+                ShareUpdate::Follow { output_on, setpoint } => {
+                    if let Some(master) = share_slave.get_master() {
+                        let master_setpoint = master.setpoint.clamp(0.0, pdo_max_voltage);
+                        if master_setpoint != set_output_voltage {
+                            set_output_voltage = master_setpoint;
+                            change_source = "share";
+                        }
+                    }
+                    share_setpoint = Some(setpoint.clamp(0.0, pdo_max_voltage));
+                    if output_on != load_start {
+                        start_stop_btn = true;
+                    }
+                },
+                ShareUpdate::Lost => {
+                    share_setpoint = None;
+                    warn!("Current share lost: no message from the master for {}ms", SHARE_TIMEOUT_MS);
+                    dp.set_message("Share Lost".to_string(), true, 3000);
+                    txd.push_event("share_lost", &format!("timeout_ms={}i,output={}", SHARE_TIMEOUT_MS, load_start));
+                    if load_start {
+                        start_stop_btn = true;
+                    }
+                },
+                ShareUpdate::Waiting => share_setpoint = None,
+            }
+        }
+        // Power-on resume (checked by the interlock like a manual start)
+        if resume_output {
+            resume_output = false;
+            if load_start == false {
+                info!("Power-on: Resuming the output at {:.3}V", set_output_voltage);
+                txd.push_event("resume", &format!("voltage={:.3}", set_output_voltage));
+                start_stop_btn = true;
+            }
+        }
+        // Interlock
+        let interlock_open = interlock_enable && interlock_pin.is_high();
+        if interlock_open {
+            dp.set_interlock_status(InterlockStatus::Open);
+            if load_start == true {
+                // Trip the output if the interlock is opened mid-run
+                info!("Interlock opened: Output disabled");
+                dp.set_message("Interlock OPEN".to_string(), true, 3000);
+                load_start = false;
+                start_stop_btn = false;
+                trip = Some(TripCause::Interlock);
+                trip_data = data.clone();
+            }
+            else if start_stop_btn == true {
+                // Inhibit enabling the output while the interlock is open
+                info!("Interlock open: Output start inhibited");
+                dp.set_message("Interlock OPEN".to_string(), true, 3);
+                start_stop_btn = false;
+            }
+            if ch2_output {
+                info!("Interlock opened: CH2 output disabled");
+                dp.set_message("Interlock OPEN".to_string(), true, 3000);
+                ch2_output = false;
+                ch2_start_stop = false;
+            }
+            else if ch2_start_stop {
+                info!("Interlock open: CH2 output start inhibited");
+                dp.set_message("Interlock OPEN".to_string(), true, 3);
+                ch2_start_stop = false;
+            }
+        }
+        else {
+            dp.set_interlock_status(InterlockStatus::Closed);
+        }
+
         if start_stop_btn == true {
             if load_start == true {
                 // to Stop
-                logging_start = false;
                 load_start = false;
-                usbpd_control(&mut i2c_sel, &mut ap33772s, &mut i2cdrv, 0.0, pd_config_offset);
+                recovery.reset();
+                if let Err(e) = save_output_state_to_nvs(false) {
+                    info!("Failed to save output state to NVS: {:?}", e);
+                }
+                // Channel 2 keeps the logging and the USB PD voltage
+                if !ch2_output {
+                    logging_start = false;
+                    control.send(ControlCommand::UsbPd { voltage: 0.0, current_ma: pd_request_current_ma });
+                    pd_sag_holdoff = PD_SAG_HOLDOFF_COUNT;
+                }
                 // clogs.dump();
                 // clogs.clear();
             }
@@ -535,22 +2700,76 @@ fn main() -> anyhow::Result<()> {
                 if let Err(e) = save_voltage_to_nvs(set_output_voltage) {
                     info!("Failed to save voltage to NVS: {:?}", e);
                 }
+                if let Err(e) = save_output_state_to_nvs(true) {
+                    info!("Failed to save output state to NVS: {:?}", e);
+                }
                 
-                pid.reset();
                 clogs.clear();
                 dp.enable_display(true);
+                // Restore the limits reduced by a previous rail sag
+                current_limit = session_current_limit;
+                pd_request_current_ma = pd_operating_current_ma(session_current_limit);
+                pd_sag_count = 0;
+                recovery.reset();
             }
         }
-
-        let rssi = wifi::get_rssi();
-        if rssi == 0 {
-            wifi_enable = false;
-            if measurement_count % 1000 == 0 {
-                wifi_reconnect(&mut wifi_dev.as_mut().unwrap());
+        if ch2_start_stop {
+            if ch2_output {
+                info!("CH2 output off");
+                ch2_output = false;
+                if !load_start {
+                    logging_start = false;
+                    control.send(ControlCommand::UsbPd { voltage: 0.0, current_ma: pd_request_current_ma });
+                    pd_sag_holdoff = PD_SAG_HOLDOFF_COUNT;
+                }
+            }
+            else {
+                info!("CH2 output on at {:.3}V", ch2_setpoint);
+                ch2_output = true;
+                logging_start = true;
+                // Request the USB PD voltage for the new setpoints
+                previous_set_output_voltage = 0.0;
+            }
+        }
+
+        let wifi_state = wifi.update();
+        let wifi_enable = wifi_state.connected;
+        if network_page && measurement_count % LOOPS_PER_SEC == 0 {
+            show_network(&mut dp, &wifi_state, txd.unsent_points(clogs.get_size()), txd.last_upload_age_secs());
+        }
+        if upload_throttle.get_threshold() != settings.upload_throttle_rssi {
+            upload_throttle.set_threshold(settings.upload_throttle_rssi);
+        }
+        if let Some(throttled) = upload_throttle.update(wifi_state.rssi) {
+            if throttled {
+                warn!("Weak WiFi signal ({}dBm), uploads throttled", wifi_state.rssi);
+            }
+            else {
+                info!("WiFi signal recovered ({}dBm), uploads at full rate", wifi_state.rssi);
+            }
+            txd.push_event("upload_throttle", &format!("throttled={},rssi={}i", throttled, wifi_state.rssi));
+        }
+        if !wifi_enable && radio_only {
+            // Not connecting to an access point
+        }
+        else if !wifi_enable {
+            if measurement_count % 1000 == 0 {
+                wifi.reconnect();
+            }
+            wifi_lost_count = wifi_lost_count.saturating_add(1);
+            // Queued by the notifier and sent once WiFi is back
+            if settings.wifi_lost_alert_secs > 0 && !wifi_lost_alerted
+                && wifi_lost_count >= settings.wifi_lost_alert_secs * LOOPS_PER_SEC {
+                alerts.notify("wifi_lost", format!("WiFi lost for more than {}s", settings.wifi_lost_alert_secs));
+                wifi_lost_alerted = true;
             }
         }
         else {
-            wifi_enable = true;
+            if wifi_lost_alerted {
+                alerts.notify("wifi_restored", format!("WiFi restored after {}s", wifi_lost_count / LOOPS_PER_SEC));
+                wifi_lost_alerted = false;
+            }
+            wifi_lost_count = 0;
         }
 
         if wifi_enable == false {
@@ -560,228 +2779,1142 @@ fn main() -> anyhow::Result<()> {
             dp.set_wifi_status(WifiStatus::Connected);
         }
 
+        if cable_start {
+            cable_start = false;
+            if load_start == false {
+                println!("Start the output with a load connected for the cable test");
+                dp.set_message("Output OFF".to_string(), true, 3);
+            }
+            else if cable_test.is_none() {
+                // Half and full setpoint at the same USB PD contract
+                info!("Cable test: {:.2}V and {:.2}V", set_output_voltage * 0.5, set_output_voltage);
+                cable_test = Some(CableTest::new(set_output_voltage * 0.5, set_output_voltage, CABLE_TEST_SETTLE_MS, CABLE_TEST_MEASURE_MS));
+                cable_current = None;
+                cable_page = true;
+                dp.set_menu(true, "Cable Test".to_string(), "Measuring..".to_string(), "".to_string());
+            }
+        }
+        if cable_test.is_some() && load_start == false {
+            cable_test = None;
+            warn!("Cable test aborted: output stopped");
+            if cable_page {
+                cable_page = false;
+                dp.set_menu(false, "".to_string(), "".to_string(), "".to_string());
+            }
+        }
+        if cable_test.is_some() && measurement_count % CABLE_TEST_SAMPLE_COUNT == 0 {
+            control.send(ControlCommand::PdCurrent);
+        }
+        else if (load_start || ch2_output) && settings.efficiency_interval > 0 && measurement_count % EFFICIENCY_SAMPLE_COUNT == 0 {
+            control.send(ControlCommand::PdCurrent);
+        }
+
         if calibration_start == true {
-            dp.set_message("Calibration..".to_string(), true, 0);
-            let (current_offset, voltage_offset) = calibration(&mut i2cdrv, current_lsb)?;
-            average_current_offset = current_offset;
-            average_voltage_offset = voltage_offset;
-            dp.set_message("".to_string(), false, 0);
+            // The result comes back as a Calibrated event and is applied at once
+            match offset_cal.start(true) {
+                Ok(()) => {
+                    dp.set_message("Calibration..".to_string(), true, 0);
+                    control.send(ControlCommand::Calibrate);
+                },
+                Err(e) => info!("Calibration: {}", e),
+            }
             calibration_start = false;
         }
 
-        if load_start == true {
-            pid.set_setpoint(set_output_voltage);
-            let diff_setpoint = set_output_voltage - previous_set_output_voltage;
-            if diff_setpoint >= 0.1 || diff_setpoint <= -0.1 {
-                // Set USB PD Voltage
-                info!("Changing USB PD Voltage to {:.2}V from {:.2}V", set_output_voltage, previous_set_output_voltage);
-                usbpd_control(&mut i2c_sel, &mut ap33772s, &mut i2cdrv, set_output_voltage, pd_config_offset);
-                previous_set_output_voltage = set_output_voltage;
+        // Production test: start each step in turn, and send the report at the end
+        if let Some(test) = prod_test.as_mut() {
+            let now_ms = monotonic_ms();
+            let before = test.current();
+            if let Some(step) = test.poll(now_ms) {
+                info!("Production test: {}", step.name());
+                match step {
+                    TestStep::I2c => control.send(ControlCommand::I2cScan),
+                    TestStep::Pwm => {
+                        prod_test_pwm.clear();
+                        control.send(ControlCommand::LearnPwmOffset);
+                    },
+                    TestStep::Display => dp.set_test_pattern(true),
+                    TestStep::Touch => {
+                        dp.set_menu(true, "Production Test".to_string(), "Touch keys".to_string(), test.missing_keys().len().to_string());
+                    },
+                    TestStep::Wifi => {
+                        // Blocks the loop for the scan
+                        let (pass, detail) = match wifi.scan() {
+                            Ok(networks) if !networks.is_empty() => (true, format!("{} networks, best {}dBm", networks.len(), networks[0].1)),
+                            Ok(_) => (false, "no network found".to_string()),
+                            Err(e) => (false, format!("{}", e)),
+                        };
+                        test.result(TestStep::Wifi, monotonic_ms(), pass, &detail);
+                    },
+                }
+            }
+            if before == Some(TestStep::Display) && test.current() != before {
+                dp.set_test_pattern(false);
+            }
+            if test.is_done() {
+                let pass = test.passed();
+                let steps : Vec<_> = test.results().iter().map(|r| json!({
+                    "name": r.step.name(), "pass": r.pass, "detail": r.detail, "duration_ms": r.duration_ms,
+                })).collect();
+                for r in test.results() {
+                    info!("Production test: {} {} ({})", r.step.name(), if r.pass { "pass" } else { "FAIL" }, r.detail);
+                }
+                hostlink::send_line("prodtest", json!({ "pass": pass, "version": version::VERSION, "git_hash": version::GIT_HASH, "steps": steps }));
+                // Shown until the center key clears it
+                dp.set_menu(false, "".to_string(), "".to_string(), "".to_string());
+                dp.set_message(if pass { "Test PASS" } else { "Test FAIL" }.to_string(), true, 0);
+                prod_test = None;
             }
-            dp.set_current_status(LoggingStatus::Start);
-        }
-        else {
-            dp.set_current_status(LoggingStatus::Stop);
         }
 
-        // Read Current/Voltage
-        let mut data = CurrentLog::default();
-        // Timestamp
-        let now = SystemTime::now();
-        // set clock in ns
-        data.clock = now.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos();
-        // Voltage
-        match voltage_read(&mut i2cdrv) {
-            Ok(vbus) => {
-                data.voltage = vbus - average_voltage_offset;
-                // info!("vbus={:?} {:?}V", vbus_buf, data.voltage);
-            },
-            Err(e) => {
-                info!("{:?}", e);
-                dp.set_message(format!("{:?}", e), true, 1000);
+        // Safe mode: the setpoints and current limits are capped whatever set them
+        let safe_mode = SafeMode::new(settings.safe_mode);
+        if settings.safe_mode != safe_mode_on {
+            safe_mode_on = settings.safe_mode;
+            info!("Safe mode {}", if safe_mode_on { "on" } else { "off" });
+            txd.push_event("safe_mode", &format!("enabled={},max_voltage={:.1},max_current={:.1}",
+                safe_mode_on, SAFE_MODE_MAX_VOLTAGE, SAFE_MODE_MAX_CURRENT));
+            if safe_mode_on {
+                dp.set_message(format!("Safe Mode {:.0}V {:.0}A", SAFE_MODE_MAX_VOLTAGE, SAFE_MODE_MAX_CURRENT), true, 3);
             }
-        }
-        // Current
-        match current_read(&mut i2cdrv, current_lsb) {
-            Ok(current) => {
-                data.current = current - average_current_offset;
-            },
-            Err(e) => {
-                info!("{:?}", e);
-                dp.set_message(format!("{:?}", e), true, 1000);
+            else {
+                dp.set_message("Safe Mode Off".to_string(), true, 3);
             }
         }
-        // Power
-        match power_read(&mut i2cdrv, current_lsb) {
-            Ok(power) => {
-                data.power = power;
-            },
-            Err(e) => {
-                info!("{:?}", e);
-                dp.set_message(format!("{:?}", e), true, 1000);
+        set_output_voltage = safe_mode.voltage(set_output_voltage);
+        ch2_setpoint = safe_mode.voltage(ch2_setpoint);
+        session_current_limit = safe_mode.current(session_current_limit);
+        ch2_session_current_limit = safe_mode.current(ch2_session_current_limit);
+        current_limit = safe_mode.current(current_limit);
+
+        if load_start == true || ch2_output {
+            // The USB PD rail feeds both channels: the highest setpoint of the running channels
+            let mut pd_setpoint : f32 = 0.0;
+            if load_start {
+                pd_setpoint = pd_setpoint.max(set_output_voltage);
+            }
+            if ch2_output {
+                pd_setpoint = pd_setpoint.max(ch2_setpoint);
+            }
+            let diff_setpoint = pd_setpoint - previous_set_output_voltage;
+            // A lower rail waits until the running channels have stepped down (including a
+            // lower setpoint not sent yet), so the rail stays above the outputs
+            let step_down_pending = settings.step_down_time_ms > 0
+                && ((load_start && set_output_voltage < control_setpoint - STEP_DOWN_MIN_V)
+                    || (ch2_output && ch2_setpoint < control_ch2_setpoint - STEP_DOWN_MIN_V));
+            let lowering_held = stepping_down.iter().any(|s| *s) || step_down_pending;
+            if diff_setpoint >= 0.1 || (diff_setpoint <= -0.1 && !lowering_held) {
+                // Set USB PD Voltage
+                info!("Changing USB PD Voltage to {:.2}V from {:.2}V", pd_setpoint, previous_set_output_voltage);
+                control.send(ControlCommand::UsbPd { voltage: pd_setpoint, current_ma: pd_request_current_ma });
+                pd_sag_holdoff = PD_SAG_HOLDOFF_COUNT;
+                previous_set_output_voltage = pd_setpoint;
             }
         }
-        // Current and Power Limit
-        if data.current > effective_max_current && load_start == true {
-            info!("Current Limit Over: {:.3}A (PDO Limited)", data.current);
-            dp.set_message(format!("Current OV {:.3}A", data.current), true, 3000);
-            load_start = false;
+        if load_start == true {
+            dp.set_current_status(LoggingStatus::Start);
         }
-        if data.power > max_power_limit && load_start == true {
-            info!("Power Limit Over: {:.1}W", data.power);
-            dp.set_message(format!("Power OV {:.1}W", data.power), true, 3000);
-            load_start = false;
+        else {
+            dp.set_current_status(LoggingStatus::Stop);
         }
 
         // Temperature
-        let temp = temp_pin.read().unwrap() as f32 * 0.05;
+        if measurement_count % 100 == 0 {
+            // Reference temperatures for the plausibility check (read by the control task)
+            tempmon.set_ina228_temperature(measurement.ina228_temperature);
+            tempmon.set_ap33772s_temperature(measurement.ap33772s_temperature);
+        }
+        let previous_temp_fault = tempmon.get_fault();
+        let analog_temp = temp_filter.update(temp_pin.read().unwrap() as f32 * 0.05);
+        let temp = tempmon.update(analog_temp);
+        if tempmon.get_fault() != previous_temp_fault && tempmon.get_fault() != TempSensorFault::None {
+            dp.set_message("Temp Sensor Fault".to_string(), true, 3);
+        }
         data.temp = temp;
+        data.set_source_temps(Some(analog_temp), measurement.ina228_temperature, measurement.ap33772s_temperature);
+        // Thermal model: channel 1 derated before the slow sensor reaches the limit
+        if (settings.thermal_resistance, settings.thermal_time_constant) != thermal_settings {
+            thermal_settings = (settings.thermal_resistance, settings.thermal_time_constant);
+            thermal = ThermalModel::new(thermal_settings.0, thermal_settings.1);
+            thermal_current_limit = None;
+        }
+        if thermal.is_enabled() && measurement_count % LOOPS_PER_SEC == 0 {
+            let ch2_power = if ch2_output { measurement.channels.get(CH2).map_or(0.0, |ch| ch.power) } else { 0.0 };
+            let power = data.power + ch2_power;
+            thermal.update(1.0, power, temp);
+            // The power left to channel 1, as a current at its setpoint in 50mA steps
+            let limit = thermal.power_limit(max_temperature, power)
+                .map(|p| ((p - ch2_power).max(0.0) / set_output_voltage.max(1.0) * 20.0).floor() / 20.0);
+            if limit.is_some() != thermal_current_limit.is_some() {
+                match limit {
+                    Some(current) => {
+                        warn!("Thermal derate: model {:.1}°C (sensor {:.1}°C) at {:.1}W, channel 1 limited to {:.2}A",
+                              thermal.estimate(), temp, power, current);
+                        dp.set_message(format!("Thermal {:.2}A", current), true, 3);
+                    },
+                    None => info!("Thermal derate released: model {:.1}°C (sensor {:.1}°C)", thermal.estimate(), temp),
+                }
+                txd.push_event("thermal_derate", &format!("derating={},current_limit={:.3},model_temp={:.1},sensor_temp={:.1},power={:.1}",
+                    limit.is_some(), limit.unwrap_or(current_limit), thermal.estimate(), temp, power));
+            }
+            thermal_current_limit = limit;
+        }
         // Temperature Safety Check
-        if temp > max_temperature && load_start == true {
+        let limits = ProtectionLimits::new(current_limit, max_power_limit, max_temperature);
+        if limits.check_temperature(temp).is_some() && load_start == true {
             info!("Temperature Limit Over: {:.1}°C", temp);
             dp.set_message(format!("Temp OV {:.1}°C", temp), true, 3000);
             load_start = false;
+            trip = Some(TripCause::OverTemperature);
+            trip_data = data.clone();
+        }
+        if limits.check_temperature(temp).is_some() && ch2_output {
+            info!("Temperature Limit Over: {:.1}°C, CH2 output off", temp);
+            dp.set_message(format!("Temp OV {:.1}°C", temp), true, 3000);
+            ch2_output = false;
+            alerts.notify("over_temperature", format!("CH2 output off at {:.1}°C", temp));
+            count_trip(&mut error_counters, TripCause::OverTemperature);
+            control.send(ControlCommand::Trigger(TriggerSource::Trip));
+        }
+        // Restart-after-fault policy
+        if let Some(cause) = trip.take() {
+            // A trip of the control task has pulsed already, within the hold-off
+            control.send(ControlCommand::Trigger(TriggerSource::Trip));
+            fault_full_rate = FAULT_FULL_RATE_COUNT;
+            count_trip(&mut error_counters, cause);
+            sessions[CH1].trip(&format!("{:?}", cause));
+            if let Some(test) = cycle_test.as_mut() {
+                test.abort(&format!("{:?} trip", cause));
+            }
+            if let Some(run) = script_run.as_mut() {
+                run.abort(&format!("{:?} trip", cause));
+            }
+            // A tripped output is not resumed at power-on
+            if let Err(e) = save_output_state_to_nvs(false) {
+                info!("Failed to save output state to NVS: {:?}", e);
+            }
+            match recovery.on_trip(cause) {
+                RecoveryAction::Retry(retry) => {
+                    warn!(cause:? = cause, voltage = trip_data.voltage, current = trip_data.current, power = trip_data.power, temp = temp;
+                          "Auto-recover: {:?} trip, retry {}/{} in {}s", cause, retry,
+                          recovery.get_max_retries(), recovery.get_cooldown_secs());
+                    txd.push_event(trip_event(cause), &format!("cause=\"{:?}\",retry={}i,latched=false", cause, retry));
+                    alerts.notify(alert_event(cause), format!("CH1 {:?} trip at {:.3}V {:.3}A {:.1}W {:.1}°C, restart {}/{} in {}s",
+                        cause, trip_data.voltage, trip_data.current, trip_data.power, temp, retry,
+                        recovery.get_max_retries(), recovery.get_cooldown_secs()));
+                },
+                RecoveryAction::Latch => {
+                    warn!(cause:? = cause, voltage = trip_data.voltage, current = trip_data.current, power = trip_data.power, temp = temp;
+                          "{:?} trip latched after {} retries", cause, recovery.get_retries());
+                    txd.push_event(trip_event(cause), &format!("cause=\"{:?}\",retry={}i,latched=true", cause, recovery.get_retries()));
+                    alerts.notify(alert_event(cause), format!("CH1 {:?} trip latched at {:.3}V {:.3}A {:.1}W {:.1}°C",
+                        cause, trip_data.voltage, trip_data.current, trip_data.power, temp));
+                },
+            }
+        }
+        else if load_start == false && recovery.poll() {
+            info!("Auto-recover: Restarting output (retry {}/{})", recovery.get_retries(), recovery.get_max_retries());
+            txd.push_event("restart", &format!("retry={}i", recovery.get_retries()));
+            dp.set_message("".to_string(), false, 0);
+            load_start = true;
+            if let Err(e) = save_output_state_to_nvs(true) {
+                info!("Failed to save output state to NVS: {:?}", e);
+            }
+        }
+        // Duty-cycle endurance test: the output is switched without the start key, so the
+        // logs, the USB PD contract and the session are kept over the cycles
+        if let Some(test) = cycle_test.as_mut() {
+            if test.output() && !load_start {
+                // Stopped by the key, the console or the interlock (trips abort in the trip handling)
+                test.abort("output off");
+            }
+            match test.poll() {
+                Some(CyclePhase::On) => {
+                    load_start = true;
+                },
+                Some(CyclePhase::Off) | Some(CyclePhase::Done) => {
+                    load_start = false;
+                    info!("Cycle {} completed", test.get_completed());
+                    txd.push_event("cycle", &format!("completed={}i,cycles={}i", test.get_completed(), test.get_cycles()));
+                },
+                _ => {},
+            }
+            if test.is_running() {
+                dp.set_cycle(Some((test.get_completed() + 1, test.get_cycles())));
+            }
+            else {
+                cycle_result = match test.phase() {
+                    CyclePhase::Aborted(reason) => format!("aborted ({}) after {} cycles", reason, test.get_completed()),
+                    _ => format!("done, {} cycles", test.get_completed()),
+                };
+                info!("Cycle test {}", cycle_result);
+                txd.push_event("cycle_end", &format!("completed={}i,cycles={}i,result=\"{}\"", test.get_completed(), test.get_cycles(), cycle_result));
+                dp.set_message(format!("Cycles {}", test.get_completed()), true, 3);
+                dp.set_cycle(None);
+                load_start = false;
+                if !ch2_output {
+                    logging_start = false;
+                    control.send(ControlCommand::UsbPd { voltage: 0.0, current_ma: pd_request_current_ma });
+                    pd_sag_holdoff = PD_SAG_HOLDOFF_COUNT;
+                }
+                if let Err(e) = save_output_state_to_nvs(false) {
+                    info!("Failed to save output state to NVS: {:?}", e);
+                }
+                cycle_test = None;
+            }
+        }
+        // Test script: one step per loop, on channel 1 as the console commands do
+        if let Some(run) = script_run.as_mut().filter(|run| run.is_running()) {
+            match run.poll(monotonic_ms(), data.voltage, data.current) {
+                Some(ScriptCommand::Voltage(voltage)) => {
+                    set_output_voltage = voltage.clamp(0.0, pdo_max_voltage);
+                    change_source = "script";
+                },
+                Some(ScriptCommand::CurrentLimit(current)) => {
+                    session_current_limit = current.min(effective_max_current).max(SESSION_CURRENT_LIMIT_MIN);
+                    change_source = "script";
+                },
+                Some(ScriptCommand::Output(on)) => {
+                    start_stop_btn = on != load_start;
+                },
+                Some(ScriptCommand::Marker(text)) => {
+                    info!("Test script {}: {}", run.get_name(), text);
+                    txd.push_event("script_marker", &format!("script=\"{}\",text=\"{}\"", run.get_name(), Transfer::escape_string_field(&text)));
+                },
+                Some(ScriptCommand::Checked(step, pass, value)) => {
+                    let text = run.status().checks.last().map(|check| check.text.clone()).unwrap_or_default();
+                    info!("Test script {}: step {} {} {} ({:.4})", run.get_name(), step + 1, text, if pass { "pass" } else { "FAIL" }, value);
+                    txd.push_event("script_check", &format!("script=\"{}\",step={}i,check=\"{}\",pass={},value={:.5}",
+                        run.get_name(), step + 1, text, pass, value));
+                },
+                None => {},
+            }
+            if !run.is_running() {
+                let status = run.status();
+                info!("Test script {}", script_text(&status));
+                txd.push_event("script_end", &format!("script=\"{}\",result=\"{}\",steps={}i,checks={}i,failed={}i",
+                    status.name, status.state.name(), status.step, status.checks.len(), status.checks.iter().filter(|c| !c.pass).count()));
+                dp.set_message(format!("{} {}", status.name, status.state.name().to_uppercase()), true, 10);
+                // Channel 1 is left off at the end, also when a step to turn it on is pending
+                start_stop_btn = load_start;
+            }
+        }
+        // Session reports at the start and the end of each output (a cycle test is one session)
+        let cycle_running = cycle_test.as_ref().map_or(false, |t| t.is_running());
+        for (index, on, setpoint) in [(CH1, load_start || cycle_running, set_output_voltage), (CH2, ch2_output, ch2_setpoint)] {
+            if let Some(session) = sessions.get_mut(index) {
+                if on && !session.is_active() {
+                    session.start(index as u8 + 1, setpoint, data.clock);
+                }
+                else if !on {
+                    if let Some(mut report) = session.finish(data.clock) {
+                        // The report shows the times on the wall clock
+                        let offset = timebase::wall_offset_ns();
+                        report.start_clock += offset;
+                        report.end_clock += offset;
+                        session_reports.send(report);
+                    }
+                }
+            }
         }
         // info!("Temperature: {:.2}°C", temp);
         dp.set_temperature(temp);
         // USB PD Voltage
-        let pd_voltage = usb_pd_pin.read().unwrap() as f32 * 0.01125; // (47K + 4.7K) / 4.7K / 1000
+        let pd_voltage_adc = usb_pd_pin.read().unwrap() as f32 * 0.01125; // (47K + 4.7K) / 4.7K / 1000
+        let pd_voltage = pd_voltage_filter.update(settings.pd_voltage_correction().apply(pd_voltage_adc));
         dp.set_usb_pd_voltage(pd_voltage);
+        // Input power from the rail voltage and the input current, against the output power
+        if !(load_start || ch2_output) {
+            efficiency.reset();
+            input_current = None;
+        }
+        else if let Some(current) = input_current.take() {
+            let output_power : f32 = measurement.channels.iter().map(|ch| ch.power).sum();
+            if let Some(report) = efficiency.add(data.clock, pd_voltage * current, output_power) {
+                last_efficiency = report.efficiency;
+                txd.push_event("efficiency", &format!("input_power={:.4},output_power={:.4},efficiency={:.2},input_energy={:.6},output_energy={:.6}",
+                    report.input_power, report.output_power, report.efficiency.unwrap_or(0.0), report.input_energy_wh, report.output_energy_wh));
+                match report.warning {
+                    Some(true) => {
+                        let efficiency = report.efficiency.unwrap_or(0.0);
+                        warn!("Efficiency low: {:.1}% ({:.2}W in, {:.2}W out)", efficiency, report.input_power, report.output_power);
+                        dp.set_message(format!("Eff Low {:.0}%", efficiency), true, 5);
+                        alerts.notify("efficiency_low", format!("Efficiency {:.1}% below {:.1}% ({:.2}W in, {:.2}W out)",
+                            efficiency, settings.efficiency_warn, report.input_power, report.output_power));
+                    },
+                    Some(false) => info!("Efficiency recovered: {:.1}%", report.efficiency.unwrap_or(0.0)),
+                    None => {},
+                }
+            }
+        }
+        // Alarm rules on the measurements, the values of a channel while its output is on
+        if !alarm_rules.is_empty() {
+            let mut values = AlarmValues { temperature: temp, pd_voltage: pd_voltage, ..Default::default() };
+            for (index, on) in [(CH1, load_start || cycle_running), (CH2, ch2_output)] {
+                if let Some(ch) = measurement.channels.get(index).filter(|_| on) {
+                    values.voltage[index] = Some(ch.voltage);
+                    values.current[index] = Some(ch.current);
+                    values.power[index] = Some(ch.power);
+                }
+            }
+            for event in alarm_rules.update(monotonic_ms(), &values) {
+                match event {
+                    AlarmEvent::Raised(index, value) => {
+                        let rule = alarm_rules.get_rules()[index];
+                        let text = rule.to_text();
+                        dp.set_message(format!("Alarm {:.2}{}", value, rule.quantity.unit()), true, 5);
+                        control.send(ControlCommand::Trigger(TriggerSource::Alarm));
+                        txd.push_event("alarm", &format!("rule=\"{}\",state=\"raised\",value={:.4}", text, value));
+                        alerts.notify("alarm", format!("Alarm {} ({:.3}{})", text, value, rule.quantity.unit()));
+                    },
+                    AlarmEvent::Cleared(index) => {
+                        let text = alarm_rules.get_rules()[index].to_text();
+                        info!("Alarm cleared: {}", text);
+                        txd.push_event("alarm", &format!("rule=\"{}\",state=\"cleared\"", text));
+                    },
+                }
+            }
+        }
+        // USB PD charger probe: each PDO point in turn, measured on the rail without load
+        if let Some(probe) = pd_probe.as_mut() {
+            if load_start || ch2_output {
+                probe.abort("output started");
+            }
+            if let Some(step) = probe.next_request() {
+                info!("USB PD probe: PDO {} {:.2}V", step.pdo.index, step.voltage);
+                dp.set_message(format!("PD Probe {}/{}", probe.progress().0 + 1, probe.progress().1), true, 3);
+                control.send(ControlCommand::PdProbe { voltage: step.voltage, current_ma: (step.pdo.current * 1000.0) as u16, fixed: step.pdo.fixed });
+            }
+            probe.add_sample(pd_voltage);
+            if let Some(report) = probe.poll() {
+                for result in &report.results {
+                    info!("USB PD probe: {}", result.to_text());
+                    txd.push_event("pd_probe", &format!("pdo={}i,fixed={},request={:.3},measured={:.3},min={:.3},max={:.3},result=\"{:?}\"",
+                        result.step.pdo.index, result.step.pdo.fixed, result.step.voltage, result.mean, result.min, result.max, result.outcome));
+                }
+                pd_probe_report = report.to_text();
+                println!("{}", pd_probe_report);
+                txd.push_event("pd_probe_end", &format!("passed={}i,points={}i,compatible={}",
+                    report.passed(), report.results.len(), report.is_compatible()));
+                let compatible = if report.is_compatible() { "OK" } else { "NG" };
+                dp.set_message(format!("PD {} {}/{}", compatible, report.passed(), report.results.len()), true, 5);
+                // Back to 5V, unless an output was started and has requested its voltage
+                if !load_start && !ch2_output {
+                    control.send(ControlCommand::UsbPd { voltage: 0.0, current_ma: pd_request_current_ma });
+                }
+                pd_probe = None;
+            }
+        }
+        // USB PD voltage divider calibration: the ADC voltage against the AP33772S at two points
+        if let Some(cal) = pd_cal.as_mut() {
+            if load_start || ch2_output {
+                cal.abort("output started");
+            }
+            if let Some(voltage) = cal.next_request() {
+                info!("USB PD voltage calibration: {:.2}V", voltage);
+                dp.set_message(format!("PD Cal {:.0}V", voltage), true, 3);
+                control.send(ControlCommand::PdProbe { voltage: voltage, current_ma: pd_request_current_ma, fixed: true });
+            }
+            cal.add_adc(pd_voltage_adc);
+            if measurement_count % PD_CAL_SAMPLE_COUNT == 0 {
+                control.send(ControlCommand::PdVoltage);
+            }
+            if let Some(result) = cal.poll() {
+                for point in cal.get_points() {
+                    info!("USB PD voltage calibration: {:.2}V ADC {:.3}V AP33772S {:.3}V", point.requested, point.adc, point.reference);
+                }
+                match result {
+                    Ok(correction) => {
+                        info!("USB PD voltage correction: gain {:.5} offset {:+.4}V", correction.gain, correction.offset);
+                        println!("pd calibration done: gain={:.5} offset={:+.4}V", correction.gain, correction.offset);
+                        txd.push_event("pd_voltage_cal", &format!("gain={:.5},offset={:.4}", correction.gain, correction.offset));
+                        settings.pd_voltage_gain = correction.gain;
+                        settings.pd_voltage_offset = correction.offset;
+                        if let Err(e) = settings.save() {
+                            warn!("Failed to save the USB PD voltage correction: {:?}", e);
+                        }
+                        dp.set_message("PD Cal OK".to_string(), true, 3);
+                    },
+                    Err(e) => {
+                        warn!("USB PD voltage calibration failed: {}", e);
+                        println!("pd calibration failed: {}", e);
+                        dp.set_message("PD Cal Error".to_string(), true, 3);
+                    },
+                }
+                // Back to 5V, unless an output was started and has requested its voltage
+                if !load_start && !ch2_output {
+                    control.send(ControlCommand::UsbPd { voltage: 0.0, current_ma: pd_request_current_ma });
+                }
+                pd_cal = None;
+            }
+        }
+        // PWM offset learning: the duty ramp of the channels at each fixed PDO voltage
+        if let Some(learning) = pwm_offset_learning.as_mut() {
+            if load_start || ch2_output {
+                learning.abort("output started");
+            }
+            if let Some(voltage) = learning.next_request() {
+                info!("PWM offset learning: {:.2}V", voltage);
+                dp.set_message(format!("PWM Offset {}/{}", learning.progress().0 + 1, learning.progress().1), true, 3);
+                control.send(ControlCommand::PdProbe { voltage: voltage, current_ma: pd_request_current_ma, fixed: true });
+            }
+            if learning.start_ramp() {
+                control.send(ControlCommand::LearnPwmOffset);
+            }
+            if let Some(result) = learning.poll() {
+                for e in learning.get_errors() {
+                    warn!("PWM offset learning: {}", e);
+                }
+                match result {
+                    Ok(tables) => {
+                        for (index, table) in tables.iter().enumerate() {
+                            info!("CH{} PWM offset: {}", index + 1, table.to_text());
+                            for (voltage, offset) in table.get_points() {
+                                txd.push_event("pwm_offset", &format!("channel={}i,voltage={:.2},offset={}i", index + 1, voltage, offset));
+                            }
+                        }
+                        pwm_offset_tables = tables;
+                        dp.set_message("PWM Offset OK".to_string(), true, 3);
+                    },
+                    Err(e) => {
+                        warn!("PWM offset learning failed, using pwm_offset {}: {}", settings.pwm_offset, e);
+                        dp.set_message("PWM Offset Error".to_string(), true, 3);
+                    },
+                }
+                // Back to 5V (the learned offsets are applied with the contract), unless an
+                // output was started and has requested its voltage
+                if !load_start && !ch2_output {
+                    control.send(ControlCommand::UsbPd { voltage: 0.0, current_ma: pd_request_current_ma });
+                }
+                pwm_offset_learning = None;
+            }
+        }
+        // Cable test: the rail voltage with the input current just read
+        if let Some(test) = cable_test.as_mut() {
+            if let Some(current) = cable_current.take() {
+                test.add_sample(pd_voltage, current);
+            }
+            if let Some(result) = test.poll() {
+                cable_test = None;
+                match result {
+                    Ok(estimate) => {
+                        let milliohm = estimate.resistance * 1000.0;
+                        info!("Cable resistance: {:.0}mOhm ({:.2}V at {:.3}A, {:.2}V at {:.3}A)", milliohm,
+                              estimate.low.voltage, estimate.low.current, estimate.high.voltage, estimate.high.current);
+                        println!("cable resistance={:.0}mOhm low={:.3}V,{:.3}A high={:.3}V,{:.3}A", milliohm,
+                                 estimate.low.voltage, estimate.low.current, estimate.high.voltage, estimate.high.current);
+                        let high = estimate.resistance > settings.cable_resistance_warn;
+                        if high {
+                            warn!("Cable resistance {:.0}mOhm exceeds {:.0}mOhm", milliohm, settings.cable_resistance_warn * 1000.0);
+                        }
+                        cable_page = true;
+                        dp.set_menu(true, "Cable Test".to_string(),
+                                    if high { "HIGH, check cable".to_string() } else { format!("dI {:.2}A", estimate.high.current - estimate.low.current) },
+                                    format!("{:.0}mO", milliohm));
+                    },
+                    Err(e) => {
+                        warn!("Cable test failed: {}", e);
+                        println!("cable test failed: {}", e);
+                        cable_page = true;
+                        dp.set_menu(true, "Cable Test".to_string(), "Failed".to_string(), "Load?".to_string());
+                    },
+                }
+            }
+        }
         // info!("USB PD Voltage: {:.2}V", pd_voltage);
-        dp.set_voltage(data.voltage, data.current, data.power);
-        if load_start == false {
-            pid.reset();
-            pwm_duty = 0;
+        // USB PD rail sag
+        if pd_sag_holdoff > 0 {
+            pd_sag_holdoff -= 1;
+        }
+        else if load_start == true && !soft_limit.is_limiting() && pd_voltage < pd_contract_voltage * (1.0 - pd_sag_percent / 100.0) {
+            pd_sag_count += 1;
+            if pd_sag_count == PD_SAG_WARN_COUNT {
+                // Reduce the current limit while the source is sagging
+                current_limit = current_limit * PD_SAG_CURRENT_DERATE;
+                warn!("Source sagging: PD rail {:.2}V (contract {:.2}V), current limit reduced to {:.3}A",
+                      pd_voltage, pd_contract_voltage, current_limit);
+                dp.set_message(format!("PD Sag {:.1}V", pd_voltage), true, 3);
+            }
+            if pd_sag_count >= PD_SAG_RENEGOTIATE_COUNT {
+                // Sag persists, renegotiate a lower power point
+                pd_request_current_ma = pd_operating_current_ma(current_limit);
+                // at the voltage requested for the running channels
+                warn!("Source sagging persists: Renegotiating {:.2}V at {}mA", previous_set_output_voltage, pd_request_current_ma);
+                control.send(ControlCommand::UsbPd { voltage: previous_set_output_voltage, current_ma: pd_request_current_ma });
+                pd_sag_holdoff = PD_SAG_HOLDOFF_COUNT;
+                pd_sag_count = 0;
+            }
+        }
+        else {
+            pd_sag_count = 0;
+        }
+        // Session current limit to the limit check and the USB PD operating current
+        if session_current_limit != applied_session_current_limit {
+            applied_session_current_limit = session_current_limit;
+            current_limit = session_current_limit;
+            pd_request_current_ma = pd_operating_current_ma(session_current_limit);
+            info!("Session current limit: {:.2}A (USB PD {}mA)", session_current_limit, pd_request_current_ma);
+            if load_start == true || ch2_output {
+                control.send(ControlCommand::UsbPd { voltage: previous_set_output_voltage, current_ma: pd_request_current_ma });
+                pd_sag_holdoff = PD_SAG_HOLDOFF_COUNT;
+            }
+        }
+        // Soft current limit: the source holds a lower operating current instead of a trip
+        let soft_limiting = settings.get_current_limit_mode() == CurrentLimitMode::Pps;
+        // Lowered while the thermal model derates
+        let ch1_current_limit = thermal_current_limit.map_or(current_limit, |c| current_limit.min(c.max(SESSION_CURRENT_LIMIT_MIN)));
+        soft_limit.set_limit(ch1_current_limit, pd_operating_current_ma(ch1_current_limit));
+        if !soft_limiting || !load_start {
+            if soft_limit.is_limiting() {
+                // Requested again at the operating current of the limit with the next contract
+                pd_request_current_ma = pd_operating_current_ma(current_limit);
+                info!("Soft current limit released (USB PD {}mA)", pd_request_current_ma);
+            }
+            soft_limit.reset();
+        }
+        else if new_measurement {
+            match soft_limit.update(monotonic_ms(), data.current) {
+                Some(SoftLimitAction::Reduce(current_ma)) | Some(SoftLimitAction::Restore(current_ma)) => {
+                    info!("Soft current limit: {:.3}A (limit {:.2}A), USB PD operating current {}mA",
+                          data.current, ch1_current_limit, current_ma);
+                    txd.push_event("soft_limit", &format!("current={:.3},limit={:.3},operating_ma={}i,limiting={}",
+                        data.current, ch1_current_limit, current_ma, soft_limit.is_limiting()));
+                    if soft_limit.is_limiting() {
+                        dp.set_message(format!("I Limit {:.2}A", current_ma as f32 / 1000.0), true, 3);
+                    }
+                    pd_request_current_ma = current_ma;
+                    control.send(ControlCommand::UsbPd { voltage: previous_set_output_voltage, current_ma: current_ma });
+                    pd_sag_holdoff = PD_SAG_HOLDOFF_COUNT;
+                },
+                Some(SoftLimitAction::Trip) => {
+                    warn!("Soft current limit not held by the source: {:.3}A above {:.2}A", data.current, ch1_current_limit);
+                    dp.set_message(format!("Current OV {:.3}A", data.current), true, 3000);
+                    load_start = false;
+                    trip = Some(TripCause::OverCurrent);
+                    trip_data = data.clone();
+                },
+                None => {},
+            }
+        }
+        // Output state, setpoint and limits to the control task
+        if load_start != control_output {
+            control.send(ControlCommand::Output(CH1, load_start));
+            control_output = load_start;
+            if load_start {
+                // The control task tries the remote sense again
+                remote_sense_lost = false;
+            }
+            stepping_down[CH1] = false;
+        }
+        // The cable test steps the setpoint at the same USB PD contract
+        let ch1_setpoint = cable_test.as_ref().map(|t| t.setpoint()).unwrap_or(set_output_voltage);
+        // Paired current sharing: the droop on the master, the trimmed setpoint on the slave
+        let ch1_setpoint = match share_mode {
+            ShareMode::Master if load_start => share_droop.update(ch1_setpoint, data.current),
+            ShareMode::Slave => share_setpoint.unwrap_or(ch1_setpoint),
+            _ => ch1_setpoint,
+        };
+        let ch1_setpoint = safe_mode.voltage(ch1_setpoint);
+        if !load_start {
+            share_droop.reset();
+        }
+        if let (ShareMode::Master, Some(net)) = (share_mode, share_net.as_ref()) {
+            share_sequence = share_sequence.wrapping_add(1);
+            if share_sequence % 2 == 0 {
+                net.send(&ShareMessage { sequence: share_sequence, output_on: load_start, setpoint: set_output_voltage, current: data.current });
+            }
+        }
+        if ch1_setpoint != control_setpoint {
+            control.send(ControlCommand::Setpoint(CH1, ch1_setpoint));
+            control_setpoint = ch1_setpoint;
+        }
+        // The session power limit follows a lower protection limit
+        session_power_limit = session_power_limit.min(max_power_limit);
+        // The soft current limit leaves only a backstop to the over-current trip
+        let trip_current = if soft_limiting { soft_limit.trip_current() } else { ch1_current_limit };
+        if (trip_current, session_power_limit) != control_limits {
+            control.send(ControlCommand::Limits { channel: CH1, current: trip_current, power: session_power_limit });
+            control_limits = (trip_current, session_power_limit);
+        }
+        if ch2_present {
+            if ch2_output != control_ch2_output {
+                control.send(ControlCommand::Output(CH2, ch2_output));
+                control_ch2_output = ch2_output;
+                stepping_down[CH2] = false;
+            }
+            if ch2_setpoint != control_ch2_setpoint {
+                control.send(ControlCommand::Setpoint(CH2, ch2_setpoint));
+                control_ch2_setpoint = ch2_setpoint;
+            }
+            // Limited by the USB PD source like channel 1
+            ch2_session_current_limit = ch2_session_current_limit.min(settings.ch2_max_current_limit.min(pdo_max_current));
+            ch2_session_power_limit = ch2_session_power_limit.min(settings.ch2_max_power_limit);
+            let ch2_limits = (ch2_session_current_limit, ch2_session_power_limit);
+            if ch2_limits != control_ch2_limits {
+                control.send(ControlCommand::Limits { channel: CH2, current: ch2_limits.0, power: ch2_limits.1 });
+                control_ch2_limits = ch2_limits;
+            }
+        }
+        // Short circuit thresholds, 0V disables the detector
+        let short_circuit = if settings.short_circuit_voltage > 0.0 {
+            (settings.short_circuit_voltage, settings.short_circuit_current)
+        }
+        else {
+            (0.0, f32::INFINITY)
+        };
+        if short_circuit != control_short_circuit {
+            control.send(ControlCommand::ShortCircuit { voltage: short_circuit.0, current: short_circuit.1 });
+            control_short_circuit = short_circuit;
+        }
+        // Over-voltage limit of each channel, 0V disables it
+        for (index, limit) in [settings.ovp_voltage, settings.ch2_ovp_voltage].into_iter().enumerate().take(control.channel_count()) {
+            let over_voltage = (if limit > 0.0 { limit } else { f32::INFINITY }, settings.ovp_samples);
+            if over_voltage != control_over_voltage[index] {
+                control.send(ControlCommand::OverVoltage { channel: index, voltage: over_voltage.0, samples: over_voltage.1 });
+                control_over_voltage[index] = over_voltage;
+            }
+        }
+        if display_refresh_hz != settings.display_refresh_hz {
+            display_refresh_hz = settings.display_refresh_hz;
+            dp.set_refresh_rate(display_refresh_hz);
+        }
+        if control_latency_budget != Some(settings.latency_budget_us) {
+            control.send(ControlCommand::LatencyBudget(settings.latency_budget_us));
+            control_latency_budget = Some(settings.latency_budget_us);
+        }
+        if control_stale_limit != Some(settings.stale_sample_limit) {
+            control.send(ControlCommand::StaleLimit(settings.stale_sample_limit));
+            control_stale_limit = Some(settings.stale_sample_limit);
+        }
+        if control_power_check != Some(settings.power_check_tolerance) {
+            control.send(ControlCommand::PowerCheck(settings.power_check_tolerance));
+            control_power_check = Some(settings.power_check_tolerance);
+        }
+        if control_trigger != Some(settings.get_trigger_config()) {
+            control.send(ControlCommand::TriggerConfig(settings.get_trigger_config()));
+            control_trigger = Some(settings.get_trigger_config());
         }
-        else if data.current > effective_max_current {
-            // no voltage, over current
-            info!("Voltage Off due to over current or load stop {}", data.current);
-            pid.reset();
-            pwm_duty = 0;
+        if control_pid_trace_rate != Some(settings.pid_trace_rate_hz) {
+            control.send(ControlCommand::PidTraceRate(settings.pid_trace_rate_hz));
+            control_pid_trace_rate = Some(settings.pid_trace_rate_hz);
+        }
+        if control_step_down_time != Some(settings.step_down_time_ms) {
+            control.send(ControlCommand::StepDownTime(settings.step_down_time_ms));
+            control_step_down_time = Some(settings.step_down_time_ms);
+        }
+        // Remote sense, if the sense INA228 was found at boot
+        let remote_sense = if settings.remote_sense_enable && sense_addr.is_some() {
+            Some(settings.remote_sense_max_drop)
         }
         else {
-            // Check voltage overshoot (>110% of setpoint)
-            let voltage_overshoot_threshold = set_output_voltage * 1.10;
-            if data.voltage > voltage_overshoot_threshold && set_output_voltage > 0.0 {
-                info!("Voltage overshoot detected: {:.3}V > {:.3}V (110% of {:.3}V) - Resetting PID", 
-                      data.voltage, voltage_overshoot_threshold, set_output_voltage);
-                pid.reset();
-                // Continue with PID control after reset
-            }
-            
-            // PID Control
-            let pid_out = pid.update(data.voltage);
-            pwm_duty = (pid_out * (max_duty as f32)) as u32 + pwm_offset;
-            if pwm_duty > max_duty {
-                pwm_duty = max_duty;
-            }
-        }
-        pwm_driver.set_duty(pwm_duty).expect("Set duty failure");
-        // info!("Duty: {} Setpoint: {:.6}V Current Voltage: {:.6}V Diff: {:.6}V", pwm_duty, set_output_voltage, data.voltage, set_output_voltage - data.voltage);
-        // PID Control
-        dp.set_pwm_duty(pwm_duty);
-        data.pwm = pwm_duty;
-        if logging_start {
-            clogs.record(data);
+            None
+        };
+        if remote_sense != control_remote_sense {
+            control.send(ControlCommand::RemoteSense(remote_sense));
+            control_remote_sense = remote_sense;
+        }
+        if settings.pid_gains() != control_gains {
+            control_gains = settings.pid_gains();
+            control.send(ControlCommand::Gains(control_gains));
+        }
+
+        if selected_channel == CH2 {
+            let ch2 = measurement.channels[CH2];
+            dp.set_voltage(ch2.voltage, ch2.current, ch2.power);
+            dp.set_pwm_duty(ch2.pwm);
+            dp.set_output_voltage(ch2_setpoint);
+            dp.set_current_limit(ch2_session_current_limit, adjust == Adjust::CurrentLimit);
+            dp.set_power_limit(ch2_session_power_limit, adjust == Adjust::PowerLimit);
+        }
+        else {
+            dp.set_voltage(data.voltage, data.current, data.power);
+            dp.set_pwm_duty(data.pwm);
+            dp.set_output_voltage(set_output_voltage);
+            dp.set_current_limit(session_current_limit, adjust == Adjust::CurrentLimit);
+            dp.set_power_limit(session_power_limit, adjust == Adjust::PowerLimit);
+        }
+        // Pre-warning of the current and power limits of the outputs which are on
+        let channel_limits = [(load_start, control_limits), (ch2_output, control_ch2_limits)];
+        if new_measurement {
+            for (index, (warning, ch)) in limit_warnings.iter_mut().zip(&measurement.channels).enumerate() {
+                let (on, (current_max, power_max)) = channel_limits[index];
+                warning.percent = settings.limit_warning_percent;
+                if !on {
+                    warning.reset();
+                    continue;
+                }
+                for event in warning.check(&ProtectionLimits::new(current_max, power_max, 0.0), ch.current, ch.power) {
+                    match event {
+                        LimitWarning::Above(kind, value, limit) => {
+                            warn!("CH{} {} near the limit: {:.3} of {:.3} (above {:.0}%)", index + 1, kind.as_str(), value, limit, warning.percent);
+                            txd.push_event("limit_warning", &format!("channel={}i,limit=\"{}\",value={:.4},limit_value={:.4},percent={:.0}",
+                                index + 1, kind.as_str(), value, limit, warning.percent));
+                        },
+                        LimitWarning::Cleared(kind) => {
+                            debug!("CH{} {} back below {:.0}% of the limit", index + 1, kind.as_str(), warning.percent);
+                        },
+                    }
+                }
+            }
+        }
+        let warning = &limit_warnings[selected_channel];
+        dp.set_limit_warning(warning.is_warning(LimitKind::Current), warning.is_warning(LimitKind::Power));
+        let mut trend_point = false;
+        if new_measurement {
+            for (index, (trend, ch)) in trends.iter_mut().zip(&measurement.channels).enumerate() {
+                if trend.add(ch.voltage, ch.current) && index == selected_channel {
+                    trend_point = true;
+                }
+            }
+        }
+        let shown = if settings.display_trend { Some(selected_channel) } else { None };
+        if trend_point || shown != trend_shown {
+            dp.set_trend(shown.map(|index| (trends[index].get_voltages(), trends[index].get_currents())));
+            trend_shown = shown;
+        }
+        last_data = data.clone();
+        // Setpoint and limit changes, for the dashboard annotations
+        limit_log.watch("ch1_setpoint", set_output_voltage, change_source);
+        limit_log.watch("ch1_current_limit", session_current_limit, change_source);
+        limit_log.watch("ch1_power_limit", session_power_limit, change_source);
+        limit_log.watch("ch1_ovp_voltage", settings.ovp_voltage, change_source);
+        if ch2_present {
+            limit_log.watch("ch2_setpoint", ch2_setpoint, change_source);
+            limit_log.watch("ch2_current_limit", ch2_session_current_limit, change_source);
+            limit_log.watch("ch2_power_limit", ch2_session_power_limit, change_source);
+            limit_log.watch("ch2_ovp_voltage", settings.ch2_ovp_voltage, change_source);
+        }
+        for change in limit_log.take() {
+            info!("{}: {:.3} -> {:.3} ({})", change.name, change.old, change.new, change.source);
+            txd.push_event("limit_change", &format!("name=\"{}\",old={:.4},new={:.4},source=\"{}\"",
+                change.name, change.old, change.new, change.source));
+        }
+        if let Some(tx) = espnow_tx.as_mut() {
+            let mut channels = vec![PeerChannel {
+                channel: 1,
+                output_on: load_start,
+                voltage: data.voltage,
+                current: data.current,
+                power: data.power,
+            }];
+            if ch2_present {
+                let ch2 = measurement.channels[CH2];
+                channels.push(PeerChannel {
+                    channel: 2,
+                    output_on: ch2_output,
+                    voltage: ch2.voltage,
+                    current: ch2.current,
+                    power: ch2.power,
+                });
+            }
+            if let Some(frame) = peer_telemetry.poll(&channels, data.temp) {
+                tx.send(&frame);
+            }
+        }
+        // The capture runs apart from the logging
+        if new_measurement && capture.push(&data) {
+            if let Some(window) = capture.take() {
+                let trigger_sample = &window.samples[window.trigger_index.min(window.samples.len() - 1)];
+                let id = timebase::to_wall_ns(trigger_sample.clock) / 1_000_000;
+                info!("Capture {} triggered by {:?}: {} samples ({} before the trigger)", id, window.trigger, window.samples.len(), window.trigger_index);
+                println!("capture {} {:?}: {} samples, trigger at {:.3}V {:.3}A", id, window.trigger, window.samples.len(), trigger_sample.voltage, trigger_sample.current);
+                dp.set_message("Captured".to_string(), false, 3000);
+                txd.push_event("capture", &format!("capture={}i,trigger=\"{}\",level={:.4},pre={}i,post={}i,voltage={:.5},current={:.5}",
+                    id, capture_trigger_name(window.trigger), capture_trigger_level(window.trigger), window.trigger_index,
+                    window.samples.len() - window.trigger_index, trigger_sample.voltage, trigger_sample.current));
+                txd.push_capture(id, &window);
+            }
+        }
+        // Full rate while an output is on and for a while after a trip, summaries otherwise.
+        // On a weak WiFi signal the outputs are summarized too (the trips still at full rate).
+        fault_full_rate = fault_full_rate.saturating_sub(1);
+        let summarize_output = upload_throttle.is_throttled() && settings.summary_interval > 0;
+        let full_rate = (logging_start && !summarize_output) || fault_full_rate > 0;
+        if new_measurement && (full_rate || settings.summary_interval > 0) {
+            // The USB PD request and the regulation mode, to tell the source from the converter
+            data.set_pd_request(pd_contract_voltage, pd_request_current_ma);
+            data.mode = regulation_mode(load_start, stepping_down[CH1], control_remote_sense.is_some() && !remote_sense_lost);
+            let mut records = vec![data];
+            if let Some(ch2) = measurement.channels.get(CH2) {
+                let mut ch2_data = records[0].clone();
+                ch2_data.voltage = ch2.voltage;
+                ch2_data.current = ch2.current;
+                ch2_data.power = ch2.power;
+                ch2_data.pwm = ch2.pwm;
+                ch2_data.mode = regulation_mode(ch2_output, stepping_down[CH2], false);
+                ch2_data.channel = 2;
+                records.insert(0, ch2_data);
+            }
+            for record in records {
+                if let Some(session) = sessions.get_mut(record.channel as usize - 1) {
+                    session.add(&record);
+                }
+                let summary = &mut summaries[record.channel as usize - 1];
+                if full_rate {
+                    summary.reset();
+                    transfer::count_lost(clogs.record(record));
+                }
+                else if let Some(s) = summary.add(&record) {
+                    txd.push_summary(&s);
+                }
+            }
         }
         let current_record = clogs.get_size();
-        if current_record >= 4095 {
+        if clogs.is_full() && !buffer_full_alerted {
+            if clogs.get_policy() == BufferPolicy::Stop {
+                alerts.notify("buffer_full", format!("Log buffer full ({} records), logging stopped", clogs.get_capacity()));
+            }
+            else {
+                alerts.notify("buffer_full", format!("Log buffer full ({} records), overwriting the oldest records", clogs.get_capacity()));
+            }
+            buffer_full_alerted = true;
+        }
+        else if !clogs.is_full() {
+            buffer_full_alerted = false;
+        }
+        if clogs.is_full() && clogs.get_policy() == BufferPolicy::Stop {
             logging_start = false;  // Auto stop logging if buffer is full.
         }
-        dp.set_buffer_watermark((current_record as u32) * 100 / 4095);
+        dp.set_buffer_watermark((current_record * 100 / clogs.get_capacity()) as u32);
+        dp.publish();
 
-        if wifi_enable == true && current_record > 0 {
-            let logs = clogs.get_all_data();
-            let txcount = txd.set_transfer_data(logs);
-            if txcount > 0 {
-                clogs.remove_data(txcount);
+        if wifi_enable == true {
+            match txd.poll_ack() {
+                Some(TransferAck::Stored(sequence)) => {
+                    clogs.acknowledge(sequence);
+                },
+                Some(TransferAck::Dropped(sequence)) => {
+                    let count = clogs.discard(sequence);
+                    transfer::count_lost(count);
+                    warn!("{} records up to #{} dropped after failed transfers", count, sequence);
+                    txd.push_event("records_lost", &format!("count={}i,sequence={}i,total={}i", count, sequence, transfer::lost_count()));
+                },
+                None => {},
+            }
+            if clogs.get_size() > 0 && upload_throttle.batch_due() {
+                if txd.set_transfer_data(clogs.get_all_data()) > 0 {
+                    upload_throttle.batch_sent();
+                }
             }
         }
     }
 }
 
-fn current_read(i2cdrv: &mut i2c::I2cDriver, current_lsb: f32) -> anyhow::Result<f32> {
-    let mut curt_buf  = [0u8; 3];
-    i2cdrv.write(0x40, &[0x07u8; 1], BLOCK)?;
-    match i2cdrv.read(0x40, &mut curt_buf, BLOCK) {
-        Ok(_v) => {
-            let current_reg : f32;
-            if curt_buf[0] & 0x80 == 0x80 {
-                current_reg = (0x100000 - (((curt_buf[0] as u32) << 16 | (curt_buf[1] as u32) << 8 | (curt_buf[2] as u32)) >> 4)) as f32 * -1.0;
-            }
-            else {
-                current_reg = (((curt_buf[0] as u32) << 16 | (curt_buf[1] as u32) << 8 | (curt_buf[2] as u32)) >> 4) as f32;
-            }
-            return Ok(current_lsb * current_reg);
-        },
-        Err(e) => {
-            info!("{:?}", e);
-            return Err(anyhow::anyhow!("Current Read Error"));
-        }
+// Allocate the log buffer in PSRAM (allocations above CONFIG_SPIRAM_MALLOC_ALWAYSINTERNAL
+// come from PSRAM). The capacity is reduced to what fits in the largest free block, keeping
+// LOG_BUFFER_PSRAM_RESERVE for the other users; without PSRAM the default buffer is used.
+fn new_log_buffer(capacity: usize, policy: BufferPolicy) -> CurrentRecord {
+    let record_size = std::mem::size_of::<CurrentLog>();
+    let largest = health::psram_largest_block() as usize;
+    let capacity = if largest == 0 {
+        warn!("No PSRAM, log buffer in the internal RAM");
+        capacity.min(LOG_BUFFER_INTERNAL_RECORDS)
     }
+    else {
+        let fit = largest.saturating_sub(LOG_BUFFER_PSRAM_RESERVE) / record_size;
+        if fit < capacity {
+            warn!("Log buffer reduced to {} records ({} bytes of PSRAM free)", fit, largest);
+        }
+        capacity.min(fit)
+    };
+    let psram_before = health::psram_free();
+    let clogs = CurrentRecord::with_capacity(capacity, policy);
+    let psram_used = psram_before.saturating_sub(health::psram_free());
+    info!("Log buffer: {} records ({} bytes, {} bytes from PSRAM), {:?} when full",
+          capacity, capacity * record_size, psram_used, policy);
+    clogs
 }
 
-fn voltage_read(i2cdrv: &mut i2c::I2cDriver) -> anyhow::Result<f32> {
-    let mut vbus_buf  = [0u8; 3];
-    i2cdrv.write(0x40, &[0x05u8; 1], BLOCK)?;
-    match i2cdrv.read(0x40, &mut vbus_buf, BLOCK){
-        Ok(_v) => {
-            let vbus = ((((vbus_buf[0] as u32) << 16 | (vbus_buf[1] as u32) << 8 | (vbus_buf[2] as u32)) >> 4) as f32 * 195.3125) / 1000_000.0;
-            // info!("vbus_buf={:?} vbus={:?}", vbus_buf, vbus);
-            return Ok(vbus);
+fn protection_settings_menu(settings: &Settings) -> SettingsMenu {
+    SettingsMenu::new("PROTECT", vec![
+        MenuItem::new("Current Limit", "A", settings.max_current_limit, 0.1, 0.1, 11.0, 1),
+        MenuItem::new("Power Limit", "W", settings.max_power_limit, 1.0, 1.0, 140.0, 0),
+        MenuItem::new("Temp Limit", "C", settings.max_temperature, 1.0, 30.0, 100.0, 0),
+    ], &settings.protection_unlock_code)
+}
+
+// Configure an INA228 (ADC range, averaging, shunt calibration and temperature
+// coefficient) and return its current LSB
+fn init_ina228(i2cdrv: &mut i2c::I2cDriver, addr: u8, shunt_resistance: f32, shunt_temp_coefficient: u16) -> anyhow::Result<f32> {
+    match ADCRANGE {
+        true => ina228::write_reg16(i2cdrv, addr, 0x00, 0x0030)?, // Bit4: ADCRANGE=1(40.96mV), Bit5 Enables temperature compensation
+        false => ina228::write_reg16(i2cdrv, addr, 0x00, 0x0020)?, // Bit4: ADCRANGE=0(163.84mV), Bit5 Enables temperature compensation
+    }
+    let read_value = ina228::read_reg16(i2cdrv, addr, 0x00)?;
+    info!("INA228 {:02x} Config Set to: {:04x}", addr, read_value);
+
+    // INA228 ADC Config
+    let read_adc_config = ina228::read_reg16(i2cdrv, addr, 0x01)?;
+    info!("INA228 {:02x} ADC Config Read: {:04x}", addr, read_adc_config);
+    let write_adc_config : u16 = (read_adc_config & 0xFFF8) | 0x04; // Clear bits 0-2, 0x00: 1avg, 0x02: 16avg, 0x03: 64avg
+    ina228::write_reg16(i2cdrv, addr, 0x01, write_adc_config)?;
+    let read_adc_config = ina228::read_reg16(i2cdrv, addr, 0x01)?;
+    info!("INA228 {:02x} ADC Config Set to: {:04x}", addr, read_adc_config);
+
+    // SHUNT_CAL
+    let current_lsb = match ADCRANGE {
+        true => {
+            // 40.96mV range
+            40.96 / 524_288.0
         },
-        Err(e) => {
-            info!("{:?}", e);
-            return Err(anyhow::anyhow!("Voltage Read Error"));
+        false => {
+            // 163.84mV range
+            163.84 / 524_288.0
         }
+    };
+    let shunt_cal_val = match ADCRANGE {
+        true => 13107.2 * current_lsb * 1000_000.0 * shunt_resistance * 4.0, // 40.96mV range
+        false => 13107.2 * current_lsb * 1000_000.0 * shunt_resistance, // 163.84mV range
+    };
+    let shunt_cal = shunt_cal_val as u16;
+    info!("current_lsb={:?} shunt_cal_val={:?} shunt_cal={:?}", current_lsb, shunt_cal_val, shunt_cal);
+    ina228::write_reg16(i2cdrv, addr, 0x02, shunt_cal)?;
+    let read_shunt_cal = ina228::read_reg16(i2cdrv, addr, 0x02)?;
+    info!("INA228 {:02x} SHUNT_CAL Set to: {:04x}", addr, read_shunt_cal);
+    // Shunt Temperature Coefficient
+    info!("Shunt Temperature Coefficient: {:?}", shunt_temp_coefficient);
+    ina228::write_reg16(i2cdrv, addr, 0x03, shunt_temp_coefficient)?;
+    let read_shunt_temp_coefficient = ina228::read_reg16(i2cdrv, addr, 0x03)?;
+    info!("INA228 {:02x} SHUNT_TEMP_COEFFICIENT Set to: {:04x}", addr, read_shunt_temp_coefficient);
+    Ok(current_lsb)
+}
+
+fn pwm_resolution(bits: u32) -> esp_idf_hal::ledc::config::Resolution {
+    use esp_idf_hal::ledc::config::Resolution;
+    match bits {
+        8 => Resolution::Bits8,
+        9 => Resolution::Bits9,
+        10 => Resolution::Bits10,
+        11 => Resolution::Bits11,
+        12 => Resolution::Bits12,
+        13 => Resolution::Bits13,
+        _ => Resolution::Bits14,
+    }
+}
+
+// The gains are scaled to fit the display: Kp in 1e-7, Ki in 1e-6
+fn pid_settings_menu(settings: &Settings) -> SettingsMenu {
+    SettingsMenu::new("PID", vec![
+        MenuItem::new("Kp", "e-7", settings.pid_kp * 1e7, 0.5, 0.0, 100.0, 1),
+        MenuItem::new("Ki", "e-6", settings.pid_ki * 1e6, 1.0, 0.0, 1000.0, 0),
+        MenuItem::new("Kd", "", settings.pid_kd, 0.01, 0.0, 10.0, 2),
+        MenuItem::new("PWM Offset", "", settings.pwm_offset as f32, 50.0, 0.0, 10000.0, 0),
+    ], &settings.protection_unlock_code)
+}
+
+// Get, change or revert the PID gains. Changes are stored in NVS and picked up
+// by the control task from the settings.
+fn change_pid(settings: &mut Settings, change: PidChange) -> anyhow::Result<PidGains> {
+    let store = !matches!(change, PidChange::Get);
+    let gains = settings.change_pid(change)?;
+    if store {
+        settings.save()?;
     }
+    Ok(gains)
 }
 
-fn power_read(i2cdrv: &mut i2c::I2cDriver, current_lsb: f32) -> anyhow::Result<f32> {
-    let mut power_buf = [0u8; 3];
-    i2cdrv.write(0x40, &[0x08u8; 1], BLOCK)?;
-    match i2cdrv.read(0x40, &mut power_buf, BLOCK) {
-        Ok(_v) => {
-            let power_reg = ((power_buf[0] as u32) << 16 | (power_buf[1] as u32) << 8 | (power_buf[2] as u32)) as f32;
-            let power = 3.2 * current_lsb * power_reg;
-            return Ok(power);
+// Flash the display with the IP address, to find the unit among identical ones
+fn identify(dp: &mut DisplayPanel, wifi: &WifiManager, secs: u32) {
+    let text = match wifi.get_state().ip {
+        Some(ip) => ip.to_string(),
+        None => "No WiFi".to_string(),
+    };
+    info!("Identify: {}s ({})", secs, text);
+    dp.set_identify(secs, text);
+}
+
+// Offset calibration requested over the console or the HTTP API. The offsets measured are
+// held until they are applied or discarded.
+fn calibration_request(cal: &mut OffsetCalibration, action: CalibrationAction, outputs_on: bool, control: &ControlTask) -> Result<(), String> {
+    match action {
+        CalibrationAction::Status => Ok(()),
+        CalibrationAction::Start => {
+            if outputs_on {
+                return Err("calibration needs the outputs off".to_string());
+            }
+            cal.start(false)?;
+            info!("Calibration: started remotely");
+            control.send(ControlCommand::Calibrate);
+            Ok(())
+        },
+        CalibrationAction::Apply => {
+            let offsets = cal.apply()?;
+            control.send(ControlCommand::Offsets(offsets));
+            info!("Calibration: offsets applied");
+            Ok(())
+        },
+        CalibrationAction::Discard => {
+            cal.discard()?;
+            info!("Calibration: offsets discarded");
+            Ok(())
         },
-        Err(e) => {
-            info!("{:?}", e);
-            return Err(anyhow::anyhow!("Power Read Error"));
-        }
     }
 }
 
-fn write_ina228_reg16(i2cdrv: &mut i2c::I2cDriver, reg: u8, value: u16) -> anyhow::Result<()> {
-    let mut config = [0u8; 3];
-    config[0] = reg;
-    config[1] = (value >> 8) as u8;
-    config[2] = value as u8;
-    i2cdrv.write(0x40, &config, BLOCK)?;
-    Ok(())
+fn script_request(run: &mut Option<ScriptRun>, request: ScriptRequest, busy: bool, txd: &mut Transfer) -> Result<ScriptStatus, String> {
+    match request {
+        ScriptRequest::Status => {},
+        ScriptRequest::Run(_) if run.as_ref().map_or(false, |r| r.is_running()) => {
+            return Err(format!("script {} running", run.as_ref().map(|r| r.get_name()).unwrap_or_default()));
+        },
+        ScriptRequest::Run(_) if busy => {
+            return Err("a test is running on channel 1".to_string());
+        },
+        ScriptRequest::Run(name) => {
+            let script = testscripts::load(&name).map_err(|e| e.to_string())?;
+            info!("Test script {}: {} steps", name, script.get_steps().len());
+            txd.push_event("script_start", &format!("script=\"{}\",steps={}i", name, script.get_steps().len()));
+            *run = Some(ScriptRun::new(script));
+        },
+        ScriptRequest::Stop => {
+            match run.as_mut().filter(|r| r.is_running()) {
+                Some(r) => r.abort("stopped"),
+                None => return Err("no script running".to_string()),
+            }
+        },
+    }
+    run.as_ref().map(|r| r.status()).ok_or_else(|| "no script run".to_string())
 }
 
-fn read_ina228_reg16(i2cdrv: &mut i2c::I2cDriver, reg: u8) -> anyhow::Result<u16> {
-    let mut data = [0u8; 2];
-    i2cdrv.write(0x40, &[reg; 1], BLOCK)?;
-    i2cdrv.read(0x40, &mut data, BLOCK)?;
-    // info!("INA228 Reg {:02x} Read: {:02x} {:02x}", reg, data[0], data[1]);
-    Ok(((data[0] as u16) << 8) | (data[1] as u16))
+// Name, state, progress and the failed checks of a script run on one line
+fn script_text(status: &ScriptStatus) -> String {
+    let mut text = format!("{} {} step {}/{}", status.name, status.state.name(), status.step, status.steps);
+    if let ScriptState::Aborted(reason) = &status.state {
+        text += &format!(" ({})", reason);
+    }
+    for check in status.checks.iter().filter(|c| !c.pass) {
+        text += &format!(", step {} {} failed at {:.4}", check.step + 1, check.text, check.value);
+    }
+    text
 }
 
+// State, progress and offsets of the calibration on one line
+fn calibration_text(status: &CalibrationStatus) -> String {
+    let mut text = format!("{} {}%", status.state.name(), status.percent);
+    for (index, offsets) in status.offsets().iter().enumerate() {
+        text += &format!(" CH{} current={:+.4}A voltage={:+.4}V", index + 1, offsets.current_a, offsets.voltage_v);
+        if let Some(sense) = offsets.sense_v {
+            text += &format!(" sense={:+.4}V", sense);
+        }
+    }
+    if let Some(e) = status.error() {
+        text += &format!(": {}", e);
+    }
+    text
+}
 
 fn usbpd_control(i2c_sel: &mut PinDriver<Gpio46, Output>,
     ap33772s: &mut AP33772S,
     i2cdrv: &mut i2c::I2cDriver,
     voltage: f32,
-    pd_config_offset: f32) {
+    pd_config_offset: f32,
+    max_current_ma: u16) -> Option<f32> {
 
     i2c_sel.set_high().unwrap(); // Enable USB PD
     // USB PD Control
-    ap33772_usbpd_control(ap33772s, i2cdrv, voltage, pd_config_offset);
+    let contract_voltage = ap33772_usbpd_control(ap33772s, i2cdrv, voltage, pd_config_offset, max_current_ma);
     i2c_sel.set_low().unwrap(); // Disable USB PD
-
+    contract_voltage
 } 
 
 // if output_control is used, USB current will be unstable. 
@@ -817,17 +3950,20 @@ fn usbpd_control(i2c_sel: &mut PinDriver<Gpio46, Output>,
 //     i2c_sel.set_low().unwrap(); // Disable USB PD
 // }
 
-fn ap33772_usbpd_control(ap33772s: &mut AP33772S, i2cdrv: &mut i2c::I2cDriver, voltage: f32, pd_config_offset: f32) {
+// Returns the negotiated contract voltage in volts, or None if the request failed.
+fn ap33772_usbpd_control(ap33772s: &mut AP33772S, i2cdrv: &mut i2c::I2cDriver, voltage: f32, pd_config_offset: f32, max_current_ma: u16) -> Option<f32> {
     // USB PD Control
     // Set voltage
     if voltage <= 0.0 {
         // Disable Output
-        let _ = ap33772s.request_voltage(i2cdrv, PDVoltage::V5);
         // ap33772s.force_vout_off(i2cdrv).unwrap();
-        return;
+        return match ap33772s.request_voltage(i2cdrv, PDVoltage::V5) {
+            Ok(()) => Some(5.0),
+            Err(_) => None,
+        };
     }
     // ap33772s.set_vout_auto_control(i2cdrv).unwrap();
-    let mut max_current_limit = 5000; // 5A
+    let mut max_current_limit = max_current_ma;
     let mut req_voltage = voltage + pd_config_offset;
     let available_voltage = ap33772s.get_max_voltage() as f32 / 1000.0;
     if req_voltage > available_voltage {
@@ -839,21 +3975,29 @@ fn ap33772_usbpd_control(ap33772s: &mut AP33772S, i2cdrv: &mut i2c::I2cDriver, v
         // Try to request custom voltage PPS APDO
         match ap33772s.request_custom_voltage(i2cdrv, pd_voltage, max_current_limit) {
             Ok(()) => {
-                return;
+                return Some(req_voltage);
             },
             Err(e) => {
-                info!("Failed to request voltage: {:?}", e);
+                info!("Failed to request voltage: {}", e);
+                if e.is_transient() {
+                    // Bus error: the request did not reach the source, retry it as is
+                    if ap33772s.request_custom_voltage(i2cdrv, pd_voltage, max_current_limit).is_ok() {
+                        return Some(req_voltage);
+                    }
+                }
             }
         }
-        // try to request maximum current to be 3A
-        max_current_limit = 3000;
-        // try to request custom voltage PPS APDO
-        match ap33772s.request_custom_voltage(i2cdrv, pd_voltage, max_current_limit) {
-            Ok(()) => {
-                return;
-            },
-            Err(e) => {
-                info!("Failed to request voltage: {:?}", e);
+        if max_current_limit > 3000 {
+            // try to request maximum current to be 3A
+            max_current_limit = 3000;
+            // try to request custom voltage PPS APDO
+            match ap33772s.request_custom_voltage(i2cdrv, pd_voltage, max_current_limit) {
+                Ok(()) => {
+                    return Some(req_voltage);
+                },
+                Err(e) => {
+                    info!("Failed to request voltage: {:?}", e);
+                }
             }
         }
     }
@@ -862,39 +4006,241 @@ fn ap33772_usbpd_control(ap33772s: &mut AP33772S, i2cdrv: &mut i2c::I2cDriver, v
         // This unit needs to power on 5V.
         match ap33772s.request_voltage(i2cdrv, PDVoltage::V5) {
             Ok(()) => {
-                return;
+                return Some(5.0);
             },
             Err(e) => {
                 info!("Failed to request 5V: {:?}", e);
             }
         }
     }
+    None
 }
 
-fn wifi_reconnect(wifi_dev: &mut EspWifi) -> bool{
-    unsafe {
-        esp_idf_sys::esp_wifi_start();
+// Access level of the HTTP API for the log
+fn api_access_summary(settings: &Settings) -> &'static str {
+    match (settings.api_read_token.is_empty(), settings.api_control_token.is_empty()) {
+        (_, true) => "open",
+        (true, false) => "control token required for changes",
+        (false, false) => "read and control tokens required",
     }
-    match wifi_dev.connect() {
-        Ok(_) => { info!("Wifi connecting requested."); true},
-        Err(ref e) => { info!("{:?}", e); false }
+}
+
+// Regulation statistics page: mean error and peak deviation in mV, the longest load step recovery
+
+// Setpoint adjusted by the Up/Down keys
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Adjust {
+    Voltage,
+    CurrentLimit,
+    PowerLimit,
+}
+
+impl Adjust {
+    fn unit(&self) -> &'static str {
+        match self {
+            Adjust::Voltage => "V",
+            Adjust::CurrentLimit => "A",
+            Adjust::PowerLimit => "W",
+        }
     }
 }
 
-fn calibration(i2cdrv: &mut i2c::I2cDriver, current_lsb: f32) -> anyhow::Result<(f32, f32)> {
-    // INA228 Calibration
-    // calibration read
-    let mut average_current_offset = 0.0;
-    let mut voltage_offset = 0.0;
-    for _ in 0..300 {
-        let read_current = current_read(i2cdrv, current_lsb)?;
-        average_current_offset += read_current;
-        let read_voltage = voltage_read(i2cdrv)?;
-        voltage_offset += read_voltage;
-        thread::sleep(Duration::from_millis(10));
+// Center key: clear the error message and select the next setpoint, the voltage, the current
+// limit, the power limit, then the other channel
+fn select_next_setpoint(dp: &mut DisplayPanel, adjust: &mut Adjust, selected_channel: &mut usize, ch2_present: bool) {
+    dp.set_message("".to_string(), false, 0);
+    info!("Error message cleared by center key press");
+    *adjust = match *adjust {
+        Adjust::Voltage => Adjust::CurrentLimit,
+        Adjust::CurrentLimit => Adjust::PowerLimit,
+        Adjust::PowerLimit => Adjust::Voltage,
+    };
+    if *adjust == Adjust::Voltage && ch2_present {
+        *selected_channel = if *selected_channel == CH1 { CH2 } else { CH1 };
+        dp.set_channel(Some(*selected_channel as u8 + 1));
+    }
+}
+
+fn show_regulation(dp: &mut DisplayPanel, channel: usize, report: &RegulationReport) {
+    let item = if report.samples > 0 {
+        format!("E{:+.1} P{:.0}mV", report.steady_state_error * 1000.0, report.peak_deviation * 1000.0)
+    }
+    else {
+        "Settling..".to_string()
+    };
+    let value = if report.load_steps > 0 { format!("{:.0}ms", report.max_recovery_time_ms) } else { "--".to_string() };
+    dp.set_menu(true, format!("Regulation CH{}", channel + 1), item, value);
+}
+
+// Test script page: the name of the script selected
+fn show_script(dp: &mut DisplayPanel, names: &[String], index: usize) {
+    match names.get(index) {
+        Some(name) => dp.set_menu(true, "Test Script".to_string(), name.clone(), format!("{}/{}", index + 1, names.len())),
+        None => dp.set_menu(true, "Test Script".to_string(), "No scripts".to_string(), "".to_string()),
+    }
+}
+
+// Network page: the RSSI, the points not stored by the server yet (nothing is lost at power
+// off with 0) and the time since the last upload
+fn show_network(dp: &mut DisplayPanel, state: &WifiState, unsent: usize, last_upload_secs: Option<u32>) {
+    let title = if state.connected { format!("Network {}dBm", state.rssi) } else { "Network offline".to_string() };
+    let value = match last_upload_secs {
+        None => "--".to_string(),
+        Some(secs) if secs < 60 => format!("{}s ago", secs),
+        Some(secs) if secs < 3600 => format!("{}m ago", secs / 60),
+        Some(secs) => format!("{}h ago", secs / 3600),
+    };
+    dp.set_menu(true, title, format!("Unsent {}", unsent), value);
+}
+
+// InfluxDB event of a trip: a short circuit has its own event
+fn capture_trigger_name(trigger: CaptureTrigger) -> &'static str {
+    match trigger {
+        CaptureTrigger::CurrentAbove(_) => "current_above",
+        CaptureTrigger::VoltageBelow(_) => "voltage_below",
+        CaptureTrigger::Fault => "fault",
+    }
+}
+
+fn capture_trigger_level(trigger: CaptureTrigger) -> f32 {
+    match trigger {
+        CaptureTrigger::CurrentAbove(level) | CaptureTrigger::VoltageBelow(level) => level,
+        CaptureTrigger::Fault => 0.0,
+    }
+}
+
+fn trip_event(cause: TripCause) -> &'static str {
+    match cause {
+        TripCause::ShortCircuit => "short_circuit",
+        _ => "trip",
+    }
+}
+
+// Number a marker of the operator, log it and send it to InfluxDB as a marker event
+fn add_marker(markers: &mut Markers, source: MarkerSource, text: &str, txd: &mut Transfer, dp: &mut DisplayPanel, control: &ControlTask) -> Result<Marker, String> {
+    let marker = markers.add(source, text, wall_clock_ms())?;
+    info!("{}", marker.to_text());
+    control.send(ControlCommand::Trigger(TriggerSource::Marker));
+    txd.push_event("marker", &format!("number={}i,source=\"{}\",text=\"{}\"",
+        marker.number, source.as_str(), Transfer::escape_string_field(&marker.text)));
+    dp.set_message(format!("Marker {}", marker.number), true, 2000);
+    Ok(marker)
+}
+
+// The label tags the points formatted from now on and the running sessions
+fn apply_run_label(label: &RunLabel, sessions: &mut [SessionTracker], txd: &mut Transfer) {
+    for session in sessions.iter_mut() {
+        session.set_label(label);
+    }
+    txd.set_run_label(label);
+    info!("Run label: dut=\"{}\" note=\"{}\"", label.dut, label.note);
+    txd.push_event("dut", &format!("dut=\"{}\",note=\"{}\"",
+        Transfer::escape_string_field(&label.dut), Transfer::escape_string_field(&label.note)));
+}
+
+fn new_schedule(settings: &Settings) -> Schedule {
+    match Schedule::parse(&settings.schedule) {
+        Ok(schedule) => {
+            for entry in schedule.get_entries() {
+                info!("Schedule entry: {}", entry.to_text());
+            }
+            schedule
+        },
+        Err(e) => {
+            warn!("Schedule ignored: {}", e);
+            Schedule::new(Vec::new())
+        },
+    }
+}
+
+fn new_alarms(settings: &Settings) -> Alarms {
+    match Alarms::parse(&settings.alarms) {
+        Ok(alarms) => {
+            for rule in alarms.get_rules() {
+                info!("Alarm rule: {}", rule.to_text());
+            }
+            alarms
+        },
+        Err(e) => {
+            warn!("Alarm rules ignored: {}", e);
+            Alarms::new(Vec::new())
+        },
+    }
+}
+
+// Over-current (a short circuit too) and over-temperature trips are kept in the error counters
+fn count_trip(counters: &mut ErrorCounters, cause: TripCause) {
+    match cause {
+        TripCause::OverCurrent | TripCause::ShortCircuit => counters.add(Counter::OverCurrentTrip, 1),
+        TripCause::OverTemperature => counters.add(Counter::OverTemperatureTrip, 1),
+        _ => {},
+    }
+}
+
+// Time since boot in ms, the time base of the production test
+fn monotonic_ms() -> u64 {
+    (timebase::monotonic_ns() / 1_000_000) as u64
+}
+
+// Local weekday (0 is Monday) and minute of the day of a clock (ns), None before NTP sync
+// Wall clock (SNTP) in ms since the epoch, the time base of the sync group
+fn wall_clock_ms() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+fn local_day_minute(clock: u128, utc_offset_minutes: i32) -> Option<(u8, u16)> {
+    let secs = (clock / 1_000_000_000) as u64;
+    if secs < WALL_CLOCK_VALID_SECS {
+        return None;
+    }
+    let local = secs.checked_add_signed(utc_offset_minutes as i64 * 60)?;
+    let days = local / 86400;
+    // 1970-01-01 was a Thursday
+    Some((((days + 3) % 7) as u8, ((local % 86400) / 60) as u16))
+}
+
+fn alert_event(cause: TripCause) -> &'static str {
+    match cause {
+        TripCause::OverCurrent | TripCause::ShortCircuit => "over_current",
+        TripCause::OverPower => "over_power",
+        TripCause::OverTemperature => "over_temperature",
+        TripCause::Interlock => "interlock",
+        TripCause::SensorFault => "sensor_fault",
+        TripCause::OverVoltage => "over_voltage",
+    }
+}
+
+// Regulation mode of a channel for the log records
+fn regulation_mode(output: bool, stepping_down: bool, remote_sense: bool) -> RegulationMode {
+    if !output {
+        RegulationMode::Off
+    }
+    else if stepping_down {
+        RegulationMode::StepDown
     }
-    average_current_offset /= 300.0;
-    voltage_offset /= 300.0;
-    info!("Average Current Offset: {:.3}A Voltage Offset: {:.3}V", average_current_offset, voltage_offset); 
-    Ok((average_current_offset, voltage_offset))
-}
\ No newline at end of file
+    else if remote_sense {
+        RegulationMode::RemoteSense
+    }
+    else {
+        RegulationMode::Cv
+    }
+}
+
+// USB PD operating current requested for a current limit
+fn pd_operating_current_ma(current_limit: f32) -> u16 {
+    ((current_limit * 1000.0) as u16).clamp(PD_MIN_REQUEST_CURRENT_MA, PD_MAX_REQUEST_CURRENT_MA)
+}
+
+// Erase all settings and restart with the compile-time defaults
+fn factory_reset(config_file: &ConfigFile) {
+    warn!("Factory reset");
+    config_file.remove();
+    if let Err(e) = settings::factory_reset() {
+        warn!("Factory reset failed: {:?}", e);
+    }
+    println!("Factory reset completed. Rebooting..");
+    thread::sleep(Duration::from_millis(100));
+    unsafe {
+        esp_idf_sys::esp_restart();
+    }
+}
@@ -0,0 +1,148 @@
+// Settings menu on the front panel
+// The menu is guarded by a numeric unlock code entered with the touch keys.
+// Locked:   Up/Down change the digit, Right/Center accept the digit.
+// Unlocked: Left/Right select the item, Up/Down change the value (long press x10),
+//           Center long press saves, Left+Right exits without saving.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use log::*;
+use crate::displayctl::DisplayPanel;
+use crate::touchpad::KeyEvent;
+
+pub struct MenuItem {
+    pub name: &'static str,
+    pub unit: &'static str,
+    pub value: f32,
+    pub step: f32,
+    pub min: f32,
+    pub max: f32,
+    pub decimals: usize,
+}
+
+impl MenuItem {
+    pub fn new(name: &'static str, unit: &'static str, value: f32, step: f32, min: f32, max: f32, decimals: usize) -> Self {
+        MenuItem { name, unit, value, step, min, max, decimals }
+    }
+
+    fn adjust(&mut self, delta: f32) {
+        self.value = (self.value + delta).clamp(self.min, self.max);
+        // Suppress accumulated rounding errors of the step
+        let scale = 10f32.powi(self.decimals as i32);
+        self.value = (self.value * scale).round() / scale;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MenuAction {
+    None,
+    Save,
+    Exit,
+}
+
+pub struct SettingsMenu {
+    title: &'static str,
+    items: Vec<MenuItem>,
+    index: usize,
+    unlock_code: Vec<u8>,
+    unlocked: bool,
+    entered_code: Vec<u8>,
+    digit: u8,
+}
+
+impl SettingsMenu {
+    // unlock_code: digits ("1234"). An empty code leaves the menu unlocked.
+    pub fn new(title: &'static str, items: Vec<MenuItem>, unlock_code: &str) -> SettingsMenu {
+        let code : Vec<u8> = unlock_code.chars().filter_map(|c| c.to_digit(10)).map(|d| d as u8).collect();
+        SettingsMenu {
+            title: title,
+            items: items,
+            index: 0,
+            unlocked: code.is_empty(),
+            unlock_code: code,
+            entered_code: Vec::new(),
+            digit: 0,
+        }
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        self.unlocked
+    }
+
+    pub fn get_value(&self, name: &str) -> Option<f32> {
+        self.items.iter().find(|it| it.name == name).map(|it| it.value)
+    }
+
+    pub fn handle_key(&mut self, key: &KeyEvent) -> MenuAction {
+        if let KeyEvent::LeftRightKeyCombinationDown = key {
+            return MenuAction::Exit;
+        }
+        if !self.unlocked {
+            match key {
+                KeyEvent::UpKeyDown => {
+                    self.digit = (self.digit + 1) % 10;
+                },
+                KeyEvent::DownKeyDown => {
+                    self.digit = (self.digit + 9) % 10;
+                },
+                KeyEvent::RightKeyDown | KeyEvent::CenterKeyDown => {
+                    self.entered_code.push(self.digit);
+                    self.digit = 0;
+                    if self.entered_code.len() >= self.unlock_code.len() {
+                        if self.entered_code == self.unlock_code {
+                            info!("{} menu unlocked", self.title);
+                            self.unlocked = true;
+                        }
+                        else {
+                            warn!("{} menu: wrong unlock code", self.title);
+                        }
+                        self.entered_code.clear();
+                    }
+                },
+                _ => {},
+            }
+            return MenuAction::None;
+        }
+        if self.items.is_empty() {
+            return MenuAction::None;
+        }
+        let item = &mut self.items[self.index];
+        match key {
+            KeyEvent::UpKeyDown => item.adjust(item.step),
+            KeyEvent::DownKeyDown => item.adjust(-item.step),
+            KeyEvent::UpKeyDownLong => item.adjust(item.step * 10.0),
+            KeyEvent::DownKeyDownLong => item.adjust(-item.step * 10.0),
+            KeyEvent::RightKeyDown => {
+                self.index = (self.index + 1) % self.items.len();
+            },
+            KeyEvent::LeftKeyDown => {
+                self.index = (self.index + self.items.len() - 1) % self.items.len();
+            },
+            KeyEvent::CenterKeyDownLong => {
+                return MenuAction::Save;
+            },
+            _ => {},
+        }
+        MenuAction::None
+    }
+
+    pub fn update_display(&self, dp: &mut DisplayPanel) {
+        if !self.unlocked {
+            let mut code = String::new();
+            for _ in 0..self.entered_code.len() {
+                code.push('*');
+            }
+            code.push_str(&format!("{}", self.digit));
+            dp.set_menu(true, self.title.to_string(), "Unlock Code".to_string(), code);
+            return;
+        }
+        if let Some(item) = self.items.get(self.index) {
+            dp.set_menu(true,
+                format!("{} {}/{}", self.title, self.index + 1, self.items.len()),
+                item.name.to_string(),
+                format!("{:.*}{}", item.decimals, item.value, item.unit));
+        }
+    }
+}
@@ -0,0 +1,58 @@
+// Loop performance counters
+// Free running counters of the work done by the control task (cycles, I2C reads, PWM duty
+// changes), the display thread (frames) and the main loop (iterations). The main loop reads
+// them with the missed control ticks (controltimer) and sends the rates per second as the
+// <measurement>_perf points (see dcpower_control::perfstats).
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use dcpower_control::perfstats::PerfCounters;
+use crate::controltimer;
+
+static CONTROL_CYCLES: AtomicU32 = AtomicU32::new(0);
+static I2C_TRANSFERS: AtomicU32 = AtomicU32::new(0);
+static I2C_BUSY_US: AtomicU32 = AtomicU32::new(0);
+static PWM_UPDATES: AtomicU32 = AtomicU32::new(0);
+static DISPLAY_FRAMES: AtomicU32 = AtomicU32::new(0);
+static MAIN_LOOPS: AtomicU32 = AtomicU32::new(0);
+
+pub fn now_us() -> u32 {
+    unsafe { esp_idf_sys::esp_timer_get_time() as u32 }
+}
+
+pub fn control_cycle() {
+    CONTROL_CYCLES.fetch_add(1, Ordering::Relaxed);
+}
+
+// Transfers on the bus since started_us (now_us)
+pub fn i2c_transfers(count: u32, started_us: u32) {
+    I2C_TRANSFERS.fetch_add(count, Ordering::Relaxed);
+    I2C_BUSY_US.fetch_add(now_us().wrapping_sub(started_us), Ordering::Relaxed);
+}
+
+pub fn pwm_update() {
+    PWM_UPDATES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn display_frame() {
+    DISPLAY_FRAMES.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn main_loop() {
+    MAIN_LOOPS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn snapshot() -> PerfCounters {
+    PerfCounters {
+        control_cycles: CONTROL_CYCLES.load(Ordering::Relaxed),
+        overruns: controltimer::overrun_count(),
+        i2c_transfers: I2C_TRANSFERS.load(Ordering::Relaxed),
+        i2c_busy_us: I2C_BUSY_US.load(Ordering::Relaxed),
+        pwm_updates: PWM_UPDATES.load(Ordering::Relaxed),
+        display_frames: DISPLAY_FRAMES.load(Ordering::Relaxed),
+        main_loops: MAIN_LOOPS.load(Ordering::Relaxed),
+    }
+}
@@ -0,0 +1,96 @@
+// Raw sample streaming over the USB console (frames in dcpower_control::rawframe)
+// The control task hands every sample to a RawTap, which queues it without blocking; a
+// writer thread sends the queued frames to the USB-Serial-JTAG driver in blocks. When the
+// host does not read fast enough, the queue or the driver buffer fills up and the frames
+// which do not fit are dropped (and counted), so the control cycle is never held up.
+// Dropping the tap ends the writer thread.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use log::*;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread;
+use dcpower_control::rawframe::{RawSample, FRAME_LEN};
+use dcpower_control::stale::Reading;
+use crate::timebase;
+
+// Samples queued for the writer (0.25s of two channels at 2kHz)
+const QUEUE_LEN: usize = 1024;
+// Frames per write to the driver
+const BLOCK_FRAMES: usize = 64;
+// Longest wait for room in the driver buffer (ticks, 10ms)
+const WRITE_TIMEOUT_TICKS: u32 = 1;
+
+// Counters since boot
+static SENT_COUNT: AtomicU32 = AtomicU32::new(0);
+static DROPPED_COUNT: AtomicU32 = AtomicU32::new(0);
+
+pub fn sent_count() -> u32 {
+    SENT_COUNT.load(Ordering::Relaxed)
+}
+
+pub fn dropped_count() -> u32 {
+    DROPPED_COUNT.load(Ordering::Relaxed)
+}
+
+// Sample source in the control task
+#[derive(Debug, Clone)]
+pub struct RawTap {
+    tx: SyncSender<RawSample>,
+    sequence: u32,
+}
+
+impl RawTap {
+    // Start the writer thread
+    pub fn start() -> anyhow::Result<RawTap> {
+        let (tx, rx) = sync_channel(QUEUE_LEN);
+        thread::Builder::new().stack_size(4096).spawn(move || {
+            info!("Start raw stream thread.");
+            crate::health::register_task("rawstream");
+            write_frames(rx);
+            crate::health::unregister_task("rawstream");
+            info!("Raw stream stopped.");
+        })?;
+        Ok(RawTap { tx: tx, sequence: 0 })
+    }
+
+    // The sample of a channel in this control cycle; the sequence counts a dropped one too
+    pub fn push(&mut self, index: usize, output_on: bool, stale: bool, reading: &Reading) {
+        let sample = RawSample {
+            sequence: self.sequence,
+            t_us: (timebase::monotonic_ns() / 1000) as u32,
+            channel: index as u8 + 1,
+            output_on: output_on,
+            stale: stale,
+            voltage: reading.voltage,
+            current: reading.current,
+        };
+        self.sequence = self.sequence.wrapping_add(1);
+        if self.tx.try_send(sample).is_err() {
+            DROPPED_COUNT.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn write_frames(rx: Receiver<RawSample>) {
+    let mut block : Vec<u8> = Vec::with_capacity(BLOCK_FRAMES * FRAME_LEN);
+    // Ends when the tap is dropped
+    while let Ok(first) = rx.recv() {
+        block.clear();
+        block.extend_from_slice(&first.encode());
+        for sample in rx.try_iter().take(BLOCK_FRAMES - 1) {
+            block.extend_from_slice(&sample.encode());
+        }
+        let written = unsafe {
+            esp_idf_sys::usb_serial_jtag_write_bytes(block.as_ptr() as *const core::ffi::c_void, block.len(), WRITE_TIMEOUT_TICKS)
+        }.max(0) as usize;
+        // A frame written in part is rejected by the CRC on the host
+        let frames = block.len() / FRAME_LEN;
+        let complete = written / FRAME_LEN;
+        SENT_COUNT.fetch_add(complete as u32, Ordering::Relaxed);
+        DROPPED_COUNT.fetch_add((frames - complete) as u32, Ordering::Relaxed);
+    }
+}
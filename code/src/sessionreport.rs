@@ -0,0 +1,113 @@
+// Session reports on SPIFFS and to a webhook
+// At the end of each output session a human-readable report is written to
+// /spiffs/session-<start time>.txt (the last MAX_REPORT_FILES are kept) and, if
+// session_webhook_url is set, POSTed as JSON. Both are done by a thread, so a slow flash
+// write or network does not delay the main loop.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use log::*;
+use std::fs;
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use chrono::{DateTime, Utc};
+use dcpower_control::session::SessionReport;
+use crate::configfile::SPIFFS_BASE_PATH;
+use crate::webhook;
+
+const MAX_REPORT_FILES: usize = 8;
+const REPORT_FILE_PREFIX: &str = "session-";
+
+pub struct SessionReports {
+    tx: Sender<SessionReport>,
+}
+
+impl SessionReports {
+    // Start the report thread; an empty webhook_url disables the POST
+    pub fn start(webhook_url: String) -> anyhow::Result<SessionReports> {
+        let (tx, rx) = channel::<SessionReport>();
+        thread::Builder::new().stack_size(8192).spawn(move || {
+            crate::health::register_task("session");
+            for report in rx {
+                let start_time = format_clock(report.start_clock);
+                let text = report.to_text(&start_time);
+                info!("Session report:\n{}", text);
+                if let Err(e) = store(&report, &text) {
+                    warn!("Failed to store the session report: {:?}", e);
+                }
+                if !webhook_url.is_empty() {
+                    if let Err(e) = post(&webhook_url, &report, &start_time, &text) {
+                        warn!("Failed to post the session report: {:?}", e);
+                    }
+                }
+            }
+        })?;
+        Ok(SessionReports { tx: tx })
+    }
+
+    pub fn send(&self, report: SessionReport) {
+        let _ = self.tx.send(report);
+    }
+}
+
+// The latest stored report
+pub fn latest() -> Option<String> {
+    let name = report_files().pop()?;
+    fs::read_to_string(format!("{}/{}", SPIFFS_BASE_PATH, name)).ok()
+}
+
+// Names of the stored reports, oldest first (the start time is in the name)
+fn report_files() -> Vec<String> {
+    let mut names: Vec<String> = match fs::read_dir(SPIFFS_BASE_PATH) {
+        Ok(entries) => entries.filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .filter(|name| name.starts_with(REPORT_FILE_PREFIX))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    names.sort();
+    names
+}
+
+fn format_clock(clock: u128) -> String {
+    let secs = (clock / 1_000_000_000) as i64;
+    match DateTime::<Utc>::from_timestamp(secs, 0) {
+        Some(dt) => dt.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+        None => format!("{}s", secs),
+    }
+}
+
+fn store(report: &SessionReport, text: &str) -> anyhow::Result<()> {
+    let name = format!("{}{:010}-ch{}.txt", REPORT_FILE_PREFIX, report.start_clock / 1_000_000_000, report.channel);
+    fs::write(format!("{}/{}", SPIFFS_BASE_PATH, name), text)?;
+    let names = report_files();
+    if names.len() > MAX_REPORT_FILES {
+        for old in &names[..names.len() - MAX_REPORT_FILES] {
+            let _ = fs::remove_file(format!("{}/{}", SPIFFS_BASE_PATH, old));
+        }
+    }
+    Ok(())
+}
+
+fn post(url: &str, report: &SessionReport, start_time: &str, text: &str) -> anyhow::Result<()> {
+    let body = serde_json::json!({
+        "channel": report.channel,
+        "dut": report.label.dut,
+        "note": report.label.note,
+        "start": start_time,
+        "duration_secs": report.duration_secs(),
+        "setpoint": report.setpoint,
+        "energy_wh": report.energy_wh,
+        "charge_ah": report.charge_ah,
+        "voltage_min": report.voltage_min,
+        "voltage_max": report.voltage_max,
+        "current_min": report.current_min,
+        "current_max": report.current_max,
+        "trips": report.trips,
+        "end": report.end_reason,
+        "text": text,
+    }).to_string();
+    webhook::post(url, "application/json", &[], body.as_bytes())
+}
@@ -0,0 +1,899 @@
+// Runtime settings stored in NVS
+// The compile-time cfg.toml values are only used as defaults on the first boot. They are
+// typed in the Config, so they are checked by the build and not parsed at boot.
+// Settings are stored as a JSON document with a schema version. Older schemas are
+// migrated on load and fields added by newer firmware take their compile-time defaults.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use log::*;
+use esp_idf_svc::nvs::*;
+use serde::{Serialize, Deserialize};
+use serde_json::{Value, Map};
+use crate::{CONFIG, NVS_NAMESPACE};
+use crate::controltimer;
+use dcpower_control::currentlogs::BufferPolicy;
+use crate::alerts::AlertFormat;
+use dcpower_control::schedule::Schedule;
+use dcpower_control::alarms::Alarms;
+use dcpower_control::units::{self, UnitFormat};
+use dcpower_control::adcfilter;
+use dcpower_control::pdcal::PdVoltageCorrection;
+use dcpower_control::pidtrace::MAX_TRACE_RATE_HZ;
+use dcpower_control::unitsync::{self, MAX_LEAD_MS};
+use dcpower_control::share::ShareMode;
+use dcpower_control::gesture::StartStopGesture;
+use dcpower_control::scopetrigger::{TriggerConfig, TriggerPolarity, MAX_WIDTH_MS};
+use dcpower_control::softlimit::CurrentLimitMode;
+use dcpower_control::apiauth::AccessTokens;
+
+pub const SETTINGS_SCHEMA_VERSION: u32 = 2;
+const SETTINGS_KEY: &str = "settings";
+const SETTINGS_MAX_LEN: usize = 4000;
+// LEDC timer: the clock (APB 80MHz) limits frequency * 2^resolution
+const LEDC_CLOCK_HZ: u64 = 80_000_000;
+const MIN_PWM_FREQUENCY_HZ: u32 = 1000;
+const MIN_PWM_RESOLUTION_BITS: u32 = 8;
+const MAX_PWM_RESOLUTION_BITS: u32 = 14;
+
+// Schema version 1: protection limits stored as individual f32 blobs
+const V1_MAX_CURRENT_KEY: &str = "max_current";
+const V1_MAX_POWER_KEY: &str = "max_power";
+const V1_MAX_TEMP_KEY: &str = "max_temp";
+
+// Records of the log buffer (64 bytes each, in PSRAM). 100000 records take 6.4MB of the 8MB.
+pub const MIN_LOG_BUFFER_CAPACITY: u32 = 256;
+pub const MAX_LOG_BUFFER_CAPACITY: u32 = 100000;
+// Pre + post trigger samples of a capture
+pub const MAX_CAPTURE_SAMPLES: u32 = 10000;
+// The schedule is stored with the other settings in one NVS entry
+const MAX_SCHEDULE_LEN: usize = 512;
+const MAX_ALARMS_LEN: usize = 256;
+const MAX_UTC_OFFSET_MINUTES: i32 = 14 * 60;
+// Changed in the protection settings menu, or with the unlock code from the console, a config
+// upload or a settings import. The safe mode is only changed with the unlock code.
+pub const PROTECTED_FIELDS: [&str; 5] = ["max_current_limit", "max_power_limit", "max_temperature", "protection_unlock_code", "safe_mode"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PowerOnMode {
+    Off,        // 0V, output off
+    Restore,    // last setpoint, output off
+    Resume,     // last setpoint and output state
+}
+
+// PID gains and PWM offset, tunable at runtime
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PidGains {
+    pub kp: f32,
+    pub ki: f32,
+    pub kd: f32,
+    pub pwm_offset: u32,
+}
+
+// Partial change of the PID gains (from the console or the HTTP API)
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PidUpdate {
+    pub kp: Option<f32>,
+    pub ki: Option<f32>,
+    pub kd: Option<f32>,
+    pub pwm_offset: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub enum PidChange {
+    Get,
+    Set(PidUpdate),
+    // Revert to the compile-time defaults (cfg.toml)
+    Defaults,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Settings {
+    pub schema_version: u32,
+    // Network
+    pub wifi_ssid: String,
+    pub wifi_psk: String,
+    pub influxdb_server: String,
+    pub influxdb_api_key: String,
+    pub influxdb_api: String,
+    pub influxdb_measurement: String,
+    pub influxdb_tag: String,
+    pub syslog_server: String,
+    pub syslog_enable: bool,
+    // Session reports are POSTed here, "" to only store them (applied at boot)
+    pub session_webhook_url: String,
+    // Alert notifications are POSTed here, "" to disable them (applied at boot)
+    pub alert_webhook_url: String,
+    // "json", "slack" or "ntfy"
+    pub alert_format: String,
+    // Alert when WiFi has been lost this long, 0 to disable
+    pub wifi_lost_alert_secs: u32,
+    // Below this RSSI (dBm) the uploads are throttled, 0 to disable
+    pub upload_throttle_rssi: i32,
+    // Low-power idle after this long without activity with the outputs off, 0 to disable
+    pub idle_sleep_secs: u32,
+    // Scheduled output on/off and setpoints, e.g. "weekdays 08:00 on; weekdays 18:00 off"
+    pub schedule: String,
+    // Local time of the schedule = UTC + utc_offset_minutes
+    pub utc_offset_minutes: i32,
+    // Alarm rules on the measurements, e.g. "current > 2A for 5s; voltage < 4.5V"
+    pub alarms: String,
+    // Sync group of the units started together, "" to disable the sync (applied at boot),
+    // its UDP port, the key the messages are signed with ("" for none), and the lead time of
+    // a start sent from this unit
+    pub sync_group: String,
+    pub sync_port: u32,
+    pub sync_key: String,
+    pub sync_lead_ms: u32,
+    // Current sharing of channel 1 with a paired unit: "off", "master" or "slave", the
+    // address of the slave (master only), the UDP port, the droop and the largest trim of
+    // the slave setpoint (applied at boot)
+    pub share_mode: String,
+    pub share_peer: String,
+    pub share_port: u32,
+    pub share_droop_ohm: f32,
+    pub share_trim_max: f32,
+    // ESP-NOW broadcast of the measurements, the interval and the radio channel used without
+    // an access point (applied at boot)
+    pub espnow_enable: bool,
+    pub espnow_interval_ms: u32,
+    pub espnow_channel: u32,
+    // Control
+    pub pid_kp: f32,
+    pub pid_ki: f32,
+    pub pid_kd: f32,
+    pub pwm_offset: u32,
+    // Learn the PWM offset at each fixed PDO voltage at boot, in place of pwm_offset
+    pub pwm_offset_auto: bool,
+    pub control_rate_hz: u32,
+    pub pwm_frequency_hz: u32,
+    pub pwm_resolution_bits: u32,
+    pub pwm_dither_enable: bool,
+    // Sampling rate of the PID internals sent to InfluxDB (Hz), 0 to disable the trace
+    pub pid_trace_rate_hz: u32,
+    // Interval of the loop performance points sent to InfluxDB (s), 0 to disable them
+    pub perf_metrics_secs: u32,
+    pub pd_config_offset: f32,
+    // Correction of the USB PD rail voltage divider (GPIO9): gain * ADC voltage + offset
+    pub pd_voltage_gain: f32,
+    pub pd_voltage_offset: f32,
+    pub shunt_resistance: f32,
+    pub shunt_temp_coefficient: u16,
+    // Protection
+    pub max_current_limit: f32,
+    pub max_power_limit: f32,
+    pub max_temperature: f32,
+    // Thermal model of the heatsink: rise per W of output power (°C/W, 0 disables it) and
+    // its time constant (s)
+    pub thermal_resistance: f32,
+    pub thermal_time_constant: f32,
+    pub short_circuit_voltage: f32,
+    pub short_circuit_current: f32,
+    // Absolute output over-voltage limit of each channel (0 disables it), and the samples
+    // in a row above it which trip the output
+    pub ovp_voltage: f32,
+    pub ch2_ovp_voltage: f32,
+    pub ovp_samples: u32,
+    // Control cycles on the last good sample after failed reads before a sensor fault
+    pub stale_sample_limit: u32,
+    // Relative error (%) allowed between the power read from the INA228 and V x I, 0 to disable the check
+    pub power_check_tolerance: f32,
+    // Time limit of a step-down of the setpoint (ramped down over half of it), 0 to apply a
+    // lower setpoint at once
+    pub step_down_time_ms: u32,
+    // Share of the current and power limits above which the readout flashes and a warning is
+    // sent (%), 0 to disable
+    pub limit_warning_percent: f32,
+    // Latency budget of the control path (timer tick to the new duty), 0 for none
+    pub latency_budget_us: u32,
+    pub interlock_enable: bool,
+    // Start/stop of the output from the panel: center_long, center_double or input (GPIO40)
+    pub start_stop_gesture: String,
+    // Output bleed FET on GPIO14 (channel 1), and its longest continuous discharge
+    pub bleed_enable: bool,
+    pub bleed_max_on_ms: u32,
+    // Scope trigger output on GPIO42: polarity (high or low), pulse width and the events
+    // pulsed (trip, alarm, marker)
+    pub scope_trigger_enable: bool,
+    pub scope_trigger_polarity: String,
+    pub scope_trigger_width_ms: u32,
+    pub scope_trigger_events: String,
+    pub pd_sag_percent: f32,
+    // Over the current limit: trip the output, or lower the USB PD operating current (pps)
+    pub current_limit_mode: String,
+    pub cable_resistance_warn: f32,
+    pub remote_sense_enable: bool,
+    pub remote_sense_max_drop: f32,
+    pub auto_recover_enable: bool,
+    pub auto_recover_cooldown: u32,
+    pub auto_recover_max_retries: u32,
+    pub protection_unlock_code: String,
+    // Touch keys ignored except for the unlock code (remote control only)
+    pub kiosk_mode: bool,
+    // Setpoints capped at 5V and current limits at 1A on all the channels
+    pub safe_mode: bool,
+    // Bearer tokens of the HTTP API: telemetry, and changes (empty for open access)
+    pub api_read_token: String,
+    pub api_control_token: String,
+    // Origin allowed by the CORS headers of the HTTP API ("" none, "*" any)
+    pub api_cors_origin: String,
+    pub power_on_mode: String,
+    // Log buffer (applied at boot)
+    pub log_buffer_capacity: u32,
+    pub log_buffer_policy: String,
+    // Summary record interval while the output is off (s, 0 disables), applied at boot
+    pub summary_interval: u32,
+    // Triggered capture window (records at 100/s)
+    pub capture_pre_samples: u32,
+    pub capture_post_samples: u32,
+    // Duty-cycle endurance test of channel 1 (cycle_count 0 repeats until stopped)
+    pub cycle_voltage: f32,
+    pub cycle_on_secs: u32,
+    pub cycle_off_secs: u32,
+    pub cycle_count: u32,
+    // Input energy metering: efficiency interval (s, 0 disables) and warning level (%)
+    pub efficiency_interval: u32,
+    pub efficiency_warn: f32,
+    // USB PD charger probe: tolerance of the rail voltage (%)
+    pub pd_probe_tolerance: f32,
+    // ADC filters: median window (readings), IIR coefficient and outlier limit (°C, V)
+    pub adc_filter_window: u32,
+    pub temp_filter_alpha: f32,
+    pub temp_outlier: f32,
+    pub pd_voltage_filter_alpha: f32,
+    pub pd_voltage_outlier: f32,
+    // Significant digits of the voltages, currents and powers on the display
+    pub display_voltage_digits: u32,
+    pub display_current_digits: u32,
+    pub display_power_digits: u32,
+    // Frames per second of the display, and its SPI clock
+    pub display_refresh_hz: u32,
+    pub display_spi_mhz: u32,
+    // Sparklines of the last minute in place of the setpoint row
+    pub display_trend: bool,
+    // Diagnostics
+    pub log_level: String,
+    pub syslog_level: String,
+    // Second output channel
+    pub ch2_enable: bool,
+    pub ch2_shunt_resistance: f32,
+    pub ch2_max_current_limit: f32,
+    pub ch2_max_power_limit: f32,
+}
+
+impl Settings {
+    // Defaults from the compile-time configuration (cfg.toml)
+    pub fn from_config() -> Settings {
+        Settings {
+            schema_version: SETTINGS_SCHEMA_VERSION,
+            wifi_ssid: CONFIG.wifi_ssid.to_string(),
+            wifi_psk: CONFIG.wifi_psk.to_string(),
+            influxdb_server: CONFIG.influxdb_server.to_string(),
+            influxdb_api_key: CONFIG.influxdb_api_key.to_string(),
+            influxdb_api: CONFIG.influxdb_api.to_string(),
+            influxdb_measurement: CONFIG.influxdb_measurement.to_string(),
+            influxdb_tag: CONFIG.influxdb_tag.to_string(),
+            syslog_server: CONFIG.syslog_server.to_string(),
+            syslog_enable: CONFIG.syslog_enable,
+            session_webhook_url: CONFIG.session_webhook_url.to_string(),
+            alert_webhook_url: CONFIG.alert_webhook_url.to_string(),
+            alert_format: CONFIG.alert_format.to_string(),
+            wifi_lost_alert_secs: CONFIG.wifi_lost_alert_secs,
+            upload_throttle_rssi: CONFIG.upload_throttle_rssi,
+            idle_sleep_secs: CONFIG.idle_sleep_secs,
+            schedule: CONFIG.schedule.to_string(),
+            utc_offset_minutes: CONFIG.utc_offset_minutes,
+            alarms: CONFIG.alarms.to_string(),
+            sync_group: CONFIG.sync_group.to_string(),
+            sync_port: CONFIG.sync_port,
+            sync_key: CONFIG.sync_key.to_string(),
+            sync_lead_ms: CONFIG.sync_lead_ms,
+            share_mode: CONFIG.share_mode.to_string(),
+            share_peer: CONFIG.share_peer.to_string(),
+            share_port: CONFIG.share_port,
+            share_droop_ohm: CONFIG.share_droop_ohm,
+            share_trim_max: CONFIG.share_trim_max,
+            espnow_enable: CONFIG.espnow_enable,
+            espnow_interval_ms: CONFIG.espnow_interval_ms,
+            espnow_channel: CONFIG.espnow_channel,
+            pid_kp: CONFIG.pid_kp,
+            pid_ki: CONFIG.pid_ki,
+            pid_kd: CONFIG.pid_kd,
+            pwm_offset: CONFIG.pwm_offset,
+            pwm_offset_auto: CONFIG.pwm_offset_auto,
+            control_rate_hz: CONFIG.control_rate_hz,
+            pwm_frequency_hz: CONFIG.pwm_frequency_hz,
+            pwm_resolution_bits: CONFIG.pwm_resolution_bits,
+            pwm_dither_enable: CONFIG.pwm_dither_enable,
+            pid_trace_rate_hz: CONFIG.pid_trace_rate_hz,
+            perf_metrics_secs: CONFIG.perf_metrics_secs,
+            pd_config_offset: CONFIG.pd_config_offset,
+            pd_voltage_gain: CONFIG.pd_voltage_gain,
+            pd_voltage_offset: CONFIG.pd_voltage_offset,
+            shunt_resistance: CONFIG.shunt_resistance,
+            shunt_temp_coefficient: CONFIG.shunt_temp_coefficient,
+            max_current_limit: CONFIG.max_current_limit,
+            max_power_limit: CONFIG.max_power_limit,
+            max_temperature: CONFIG.max_temperature,
+            thermal_resistance: CONFIG.thermal_resistance,
+            thermal_time_constant: CONFIG.thermal_time_constant,
+            short_circuit_voltage: CONFIG.short_circuit_voltage,
+            short_circuit_current: CONFIG.short_circuit_current,
+            ovp_voltage: CONFIG.ovp_voltage,
+            ch2_ovp_voltage: CONFIG.ch2_ovp_voltage,
+            ovp_samples: CONFIG.ovp_samples,
+            stale_sample_limit: CONFIG.stale_sample_limit,
+            power_check_tolerance: CONFIG.power_check_tolerance,
+            step_down_time_ms: CONFIG.step_down_time_ms,
+            latency_budget_us: CONFIG.latency_budget_us,
+            limit_warning_percent: CONFIG.limit_warning_percent,
+            interlock_enable: CONFIG.interlock_enable,
+            start_stop_gesture: CONFIG.start_stop_gesture.to_string(),
+            bleed_enable: CONFIG.bleed_enable,
+            bleed_max_on_ms: CONFIG.bleed_max_on_ms,
+            scope_trigger_enable: CONFIG.scope_trigger_enable,
+            scope_trigger_polarity: CONFIG.scope_trigger_polarity.to_string(),
+            scope_trigger_width_ms: CONFIG.scope_trigger_width_ms,
+            scope_trigger_events: CONFIG.scope_trigger_events.to_string(),
+            pd_sag_percent: CONFIG.pd_sag_percent,
+            current_limit_mode: CONFIG.current_limit_mode.to_string(),
+            cable_resistance_warn: CONFIG.cable_resistance_warn,
+            remote_sense_enable: CONFIG.remote_sense_enable,
+            remote_sense_max_drop: CONFIG.remote_sense_max_drop,
+            auto_recover_enable: CONFIG.auto_recover_enable,
+            auto_recover_cooldown: CONFIG.auto_recover_cooldown,
+            auto_recover_max_retries: CONFIG.auto_recover_max_retries,
+            protection_unlock_code: CONFIG.protection_unlock_code.to_string(),
+            kiosk_mode: CONFIG.kiosk_mode,
+            safe_mode: CONFIG.safe_mode,
+            api_read_token: CONFIG.api_read_token.to_string(),
+            api_control_token: CONFIG.api_control_token.to_string(),
+            api_cors_origin: CONFIG.api_cors_origin.to_string(),
+            power_on_mode: CONFIG.power_on_mode.to_string(),
+            log_buffer_capacity: CONFIG.log_buffer_capacity,
+            log_buffer_policy: CONFIG.log_buffer_policy.to_string(),
+            summary_interval: CONFIG.summary_interval,
+            capture_pre_samples: CONFIG.capture_pre_samples,
+            capture_post_samples: CONFIG.capture_post_samples,
+            cycle_voltage: CONFIG.cycle_voltage,
+            cycle_on_secs: CONFIG.cycle_on_secs,
+            cycle_off_secs: CONFIG.cycle_off_secs,
+            cycle_count: CONFIG.cycle_count,
+            efficiency_interval: CONFIG.efficiency_interval,
+            efficiency_warn: CONFIG.efficiency_warn,
+            pd_probe_tolerance: CONFIG.pd_probe_tolerance,
+            adc_filter_window: CONFIG.adc_filter_window,
+            temp_filter_alpha: CONFIG.temp_filter_alpha,
+            temp_outlier: CONFIG.temp_outlier,
+            pd_voltage_filter_alpha: CONFIG.pd_voltage_filter_alpha,
+            pd_voltage_outlier: CONFIG.pd_voltage_outlier,
+            display_voltage_digits: CONFIG.display_voltage_digits,
+            display_current_digits: CONFIG.display_current_digits,
+            display_power_digits: CONFIG.display_power_digits,
+            display_refresh_hz: CONFIG.display_refresh_hz,
+            display_spi_mhz: CONFIG.display_spi_mhz,
+            display_trend: CONFIG.display_trend,
+            log_level: CONFIG.log_level.to_string(),
+            syslog_level: CONFIG.syslog_level.to_string(),
+            ch2_enable: CONFIG.ch2_enable,
+            ch2_shunt_resistance: CONFIG.ch2_shunt_resistance,
+            ch2_max_current_limit: CONFIG.ch2_max_current_limit,
+            ch2_max_power_limit: CONFIG.ch2_max_power_limit,
+        }
+    }
+
+    // Load the settings from NVS. On the first boot the defaults are stored.
+    pub fn load(defaults: Settings) -> Settings {
+        let mut nvs = match EspDefaultNvsPartition::take()
+            .and_then(|partition| EspNvs::new(partition, NVS_NAMESPACE, true)) {
+            Ok(nvs) => nvs,
+            Err(e) => {
+                println!("Failed to open NVS: {:?}, using default settings", e);
+                return defaults;
+            }
+        };
+        let mut buf = vec![0u8; SETTINGS_MAX_LEN];
+        let stored = match nvs.get_str(SETTINGS_KEY, &mut buf) {
+            Ok(Some(json)) => serde_json::from_str::<Value>(json).ok(),
+            Ok(None) => None,
+            Err(e) => {
+                println!("Failed to read settings from NVS: {:?}", e);
+                None
+            }
+        };
+        let stored = match stored {
+            Some(value) => value,
+            None => {
+                match migrate_v1(&nvs) {
+                    Some(value) => value,
+                    None => {
+                        println!("No settings in NVS, storing compile-time defaults");
+                        if let Err(e) = store(&mut nvs, &defaults) {
+                            println!("Failed to store default settings: {:?}", e);
+                        }
+                        return defaults;
+                    }
+                }
+            }
+        };
+        let version = stored.get("schema_version").and_then(|v| v.as_u64()).unwrap_or(1) as u32;
+        let settings = match merge(&defaults, migrate(stored, version)) {
+            Some(settings) => settings,
+            None => {
+                println!("Stored settings are invalid, using default settings");
+                return defaults;
+            }
+        };
+        if version != SETTINGS_SCHEMA_VERSION {
+            println!("Settings migrated from schema v{} to v{}", version, SETTINGS_SCHEMA_VERSION);
+            if let Err(e) = store(&mut nvs, &settings) {
+                println!("Failed to store migrated settings: {:?}", e);
+            }
+        }
+        settings
+    }
+
+    // Get a setting by its field name as a string
+    pub fn get_field(&self, name: &str) -> Option<String> {
+        let value = serde_json::to_value(self).ok()?;
+        match value.get(name)? {
+            Value::String(s) => Some(s.clone()),
+            other => Some(other.to_string()),
+        }
+    }
+
+    pub fn field_names(&self) -> Vec<String> {
+        match serde_json::to_value(self) {
+            Ok(Value::Object(obj)) => obj.keys().cloned().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // Set a setting by its field name. The value is parsed according to the field type.
+    pub fn set_field(&mut self, name: &str, value: &str) -> anyhow::Result<()> {
+        if name == "schema_version" {
+            anyhow::bail!("schema_version is read-only");
+        }
+        let mut current = serde_json::to_value(&*self)?;
+        let obj = current.as_object_mut().ok_or(anyhow::anyhow!("invalid settings"))?;
+        let new_value = match obj.get(name) {
+            Some(Value::String(_)) => Value::from(value),
+            Some(Value::Bool(_)) => Value::from(value.parse::<bool>()?),
+            Some(Value::Number(_)) => serde_json::from_str::<Value>(value)
+                .ok()
+                .filter(|v| v.is_number())
+                .ok_or(anyhow::anyhow!("{} is not a number", value))?,
+            _ => anyhow::bail!("unknown setting: {}", name),
+        };
+        obj.insert(name.to_string(), new_value);
+        let settings : Settings = serde_json::from_value(current)
+            .map_err(|e| anyhow::anyhow!("invalid value for {}: {}", name, e))?;
+        settings.validate()?;
+        *self = settings;
+        Ok(())
+    }
+
+    // New settings which change a protected field need the unlock code (none without one)
+    pub fn unlock(&self, new: Settings, code: &str) -> anyhow::Result<Settings> {
+        if self.protection_unlock_code.is_empty() || code == self.protection_unlock_code {
+            return Ok(new);
+        }
+        let (old_value, new_value) = (serde_json::to_value(self)?, serde_json::to_value(&new)?);
+        if let Some(name) = PROTECTED_FIELDS.iter().find(|name| old_value.get(**name) != new_value.get(**name)) {
+            anyhow::bail!("{} is locked: give the unlock code (protection_unlock_code)", name);
+        }
+        Ok(new)
+    }
+
+    // Apply a (partial) settings JSON document on top of these settings
+    pub fn overlay_json(&self, json: &str) -> anyhow::Result<Settings> {
+        let overlay : Value = serde_json::from_str(json)?;
+        let overlay = match overlay {
+            Value::Object(obj) => obj,
+            _ => anyhow::bail!("settings must be a JSON object"),
+        };
+        let mut value = serde_json::to_value(self)?;
+        let base = value.as_object_mut().ok_or(anyhow::anyhow!("invalid settings"))?;
+        for (key, val) in overlay {
+            if key == "schema_version" {
+                continue;
+            }
+            if !base.contains_key(&key) {
+                anyhow::bail!("unknown setting: {}", key);
+            }
+            base.insert(key, val);
+        }
+        let settings : Settings = serde_json::from_value(value)?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    // All the settings, including the calibration values (shunt, offsets, PID gains), as a JSON
+    // document to import on another unit. The unlock code is left out, so an import keeps the
+    // code of the unit.
+    pub fn export_json(&self) -> String {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        if let Some(obj) = value.as_object_mut() {
+            obj.remove("protection_unlock_code");
+        }
+        value.to_string()
+    }
+
+    // Apply an exported settings document. Documents from an older schema are migrated,
+    // and fields missing in the document keep the current values.
+    pub fn import_json(&self, json: &str) -> anyhow::Result<Settings> {
+        let value : Value = serde_json::from_str(json)?;
+        let version = value.get("schema_version").and_then(|v| v.as_u64())
+            .ok_or(anyhow::anyhow!("missing schema_version, not an exported settings document"))? as u32;
+        if version > SETTINGS_SCHEMA_VERSION {
+            anyhow::bail!("settings schema v{} is newer than this firmware (v{})", version, SETTINGS_SCHEMA_VERSION);
+        }
+        self.overlay_json(&migrate(value, version).to_string())
+    }
+
+    pub fn pd_voltage_correction(&self) -> PdVoltageCorrection {
+        PdVoltageCorrection { gain: self.pd_voltage_gain, offset: self.pd_voltage_offset }
+    }
+
+    pub fn pid_gains(&self) -> PidGains {
+        PidGains { kp: self.pid_kp, ki: self.pid_ki, kd: self.pid_kd, pwm_offset: self.pwm_offset }
+    }
+
+    // Apply a PID change. The settings are not saved.
+    pub fn change_pid(&mut self, change: PidChange) -> anyhow::Result<PidGains> {
+        let gains = match change {
+            PidChange::Get => return Ok(self.pid_gains()),
+            PidChange::Set(update) => {
+                let current = self.pid_gains();
+                PidGains {
+                    kp: update.kp.unwrap_or(current.kp),
+                    ki: update.ki.unwrap_or(current.ki),
+                    kd: update.kd.unwrap_or(current.kd),
+                    pwm_offset: update.pwm_offset.unwrap_or(current.pwm_offset),
+                }
+            },
+            PidChange::Defaults => Settings::from_config().pid_gains(),
+        };
+        let mut settings = self.clone();
+        settings.pid_kp = gains.kp;
+        settings.pid_ki = gains.ki;
+        settings.pid_kd = gains.kd;
+        settings.pwm_offset = gains.pwm_offset;
+        settings.validate()?;
+        *self = settings;
+        Ok(gains)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(self.max_current_limit > 0.0) || !(self.max_power_limit > 0.0) || !(self.max_temperature > 0.0) {
+            anyhow::bail!("protection limits must be positive");
+        }
+        if !(self.shunt_resistance > 0.0) || !(self.ch2_shunt_resistance > 0.0) {
+            anyhow::bail!("shunt_resistance must be positive");
+        }
+        if !(self.ch2_max_current_limit > 0.0) || !(self.ch2_max_power_limit > 0.0) {
+            anyhow::bail!("channel 2 protection limits must be positive");
+        }
+        if !(self.short_circuit_voltage >= 0.0) || !(self.short_circuit_current > 0.0) {
+            anyhow::bail!("short_circuit_voltage must be 0 or more and short_circuit_current positive");
+        }
+        if !(self.ovp_voltage >= 0.0) || !(self.ch2_ovp_voltage >= 0.0) || !(1..=100).contains(&self.ovp_samples) {
+            anyhow::bail!("ovp_voltage and ch2_ovp_voltage must be 0 or more and ovp_samples 1 to 100");
+        }
+        if self.stale_sample_limit > 1000 {
+            anyhow::bail!("stale_sample_limit must be 0 to 1000 cycles");
+        }
+        if !(0.0..=50.0).contains(&self.power_check_tolerance) {
+            anyhow::bail!("power_check_tolerance must be 0 to 50%");
+        }
+        if self.step_down_time_ms > 10000 {
+            anyhow::bail!("step_down_time_ms must be 0 to 10000ms");
+        }
+        if self.limit_warning_percent != 0.0 && !(50.0..=99.0).contains(&self.limit_warning_percent) {
+            anyhow::bail!("limit_warning_percent must be 0 or 50 to 99");
+        }
+        if self.latency_budget_us != 0 && !(50..=10000).contains(&self.latency_budget_us) {
+            anyhow::bail!("latency_budget_us must be 0 or 50 to 10000us");
+        }
+        if !(100..=60000).contains(&self.bleed_max_on_ms) {
+            anyhow::bail!("bleed_max_on_ms must be 100 to 60000ms");
+        }
+        let Some(polarity) = TriggerPolarity::parse(&self.scope_trigger_polarity) else {
+            anyhow::bail!("scope_trigger_polarity must be high or low");
+        };
+        if !(1..=MAX_WIDTH_MS).contains(&self.scope_trigger_width_ms) {
+            anyhow::bail!("scope_trigger_width_ms must be 1 to {}ms", MAX_WIDTH_MS);
+        }
+        if let Err(e) = TriggerConfig::new(polarity, self.scope_trigger_width_ms, &self.scope_trigger_events) {
+            anyhow::bail!("scope_trigger_events: {}", e);
+        }
+        if !(self.pd_sag_percent >= 0.0 && self.pd_sag_percent < 100.0) {
+            anyhow::bail!("pd_sag_percent must be 0 to 100");
+        }
+        if !(self.cable_resistance_warn > 0.0) {
+            anyhow::bail!("cable_resistance_warn must be positive");
+        }
+        if !(self.remote_sense_max_drop > 0.0) {
+            anyhow::bail!("remote_sense_max_drop must be positive");
+        }
+        if !["off", "restore", "resume"].contains(&self.power_on_mode.as_str()) {
+            anyhow::bail!("power_on_mode must be off, restore or resume");
+        }
+        if !(MIN_LOG_BUFFER_CAPACITY..=MAX_LOG_BUFFER_CAPACITY).contains(&self.log_buffer_capacity) {
+            anyhow::bail!("log_buffer_capacity must be {} to {}", MIN_LOG_BUFFER_CAPACITY, MAX_LOG_BUFFER_CAPACITY);
+        }
+        if !["stop", "overwrite"].contains(&self.log_buffer_policy.as_str()) {
+            anyhow::bail!("log_buffer_policy must be stop or overwrite");
+        }
+        if !["json", "slack", "ntfy"].contains(&self.alert_format.as_str()) {
+            anyhow::bail!("alert_format must be json, slack or ntfy");
+        }
+        if self.schedule.len() > MAX_SCHEDULE_LEN {
+            anyhow::bail!("schedule must be {} characters or less", MAX_SCHEDULE_LEN);
+        }
+        if let Err(e) = Schedule::parse(&self.schedule) {
+            anyhow::bail!("schedule: {}", e);
+        }
+        if self.alarms.len() > MAX_ALARMS_LEN {
+            anyhow::bail!("alarms must be {} characters or less", MAX_ALARMS_LEN);
+        }
+        if let Err(e) = Alarms::parse(&self.alarms) {
+            anyhow::bail!("alarms: {}", e);
+        }
+        if self.upload_throttle_rssi != 0 && !(-100..=-40).contains(&self.upload_throttle_rssi) {
+            anyhow::bail!("upload_throttle_rssi must be -100 to -40dBm, or 0 to disable");
+        }
+        if self.idle_sleep_secs != 0 && !(30..=86400).contains(&self.idle_sleep_secs) {
+            anyhow::bail!("idle_sleep_secs must be 30 to 86400, or 0 to disable");
+        }
+        if self.utc_offset_minutes.abs() > MAX_UTC_OFFSET_MINUTES {
+            anyhow::bail!("utc_offset_minutes must be -{} to {}", MAX_UTC_OFFSET_MINUTES, MAX_UTC_OFFSET_MINUTES);
+        }
+        if !self.sync_group.is_empty() && !unitsync::valid_id(&self.sync_group) {
+            anyhow::bail!("sync_group must be up to {} letters, digits, '-', '_' or '.'", unitsync::MAX_ID_LEN);
+        }
+        if !(1024..=65535).contains(&self.sync_port) {
+            anyhow::bail!("sync_port must be 1024 to 65535");
+        }
+        if self.sync_key.len() > unitsync::MAX_KEY_LEN {
+            anyhow::bail!("sync_key must be up to {} characters", unitsync::MAX_KEY_LEN);
+        }
+        if !(100..=MAX_LEAD_MS as u32).contains(&self.sync_lead_ms) {
+            anyhow::bail!("sync_lead_ms must be 100 to {}ms", MAX_LEAD_MS);
+        }
+        for token in [&self.api_read_token, &self.api_control_token] {
+            if token.len() > 64 || token.chars().any(|c| !c.is_ascii_graphic()) {
+                anyhow::bail!("api_read_token and api_control_token must be up to 64 printable characters without spaces");
+            }
+        }
+        if !self.api_read_token.is_empty() && (self.api_control_token.is_empty() || self.api_read_token == self.api_control_token) {
+            anyhow::bail!("api_read_token needs a different api_control_token");
+        }
+        let origin = &self.api_cors_origin;
+        if !(origin.is_empty() || origin == "*"
+            || ((origin.starts_with("http://") || origin.starts_with("https://")) && !origin.ends_with('/')
+                && origin.len() <= 128 && origin.chars().all(|c| c.is_ascii_graphic()))) {
+            anyhow::bail!("api_cors_origin must be \"\", \"*\" or an origin like \"http://dashboard.local:3000\"");
+        }
+        if !(0.0..=10.0).contains(&self.thermal_resistance) {
+            anyhow::bail!("thermal_resistance must be 0 to 10 C/W");
+        }
+        if !(1.0..=3600.0).contains(&self.thermal_time_constant) {
+            anyhow::bail!("thermal_time_constant must be 1 to 3600 s");
+        }
+        if CurrentLimitMode::parse(&self.current_limit_mode).is_none() {
+            anyhow::bail!("current_limit_mode must be trip or pps");
+        }
+        if StartStopGesture::parse(&self.start_stop_gesture).is_none() {
+            anyhow::bail!("start_stop_gesture must be center_long, center_double or input");
+        }
+        match ShareMode::parse(&self.share_mode) {
+            None => anyhow::bail!("share_mode must be off, master or slave"),
+            Some(ShareMode::Master) if self.share_peer.parse::<std::net::Ipv4Addr>().is_err() => {
+                anyhow::bail!("share_peer must be the IPv4 address of the slave");
+            },
+            _ => {},
+        }
+        if !(1024..=65535).contains(&self.share_port) {
+            anyhow::bail!("share_port must be 1024 to 65535");
+        }
+        if !(0.0..=0.5).contains(&self.share_droop_ohm) || !(0.0..=2.0).contains(&self.share_trim_max) {
+            anyhow::bail!("share_droop_ohm must be 0 to 0.5 and share_trim_max 0 to 2V");
+        }
+        if !(20..=10000).contains(&self.espnow_interval_ms) {
+            anyhow::bail!("espnow_interval_ms must be 20 to 10000ms");
+        }
+        if !(1..=13).contains(&self.espnow_channel) {
+            anyhow::bail!("espnow_channel must be 1 to 13");
+        }
+        if self.capture_post_samples == 0 || self.capture_pre_samples + self.capture_post_samples > MAX_CAPTURE_SAMPLES {
+            anyhow::bail!("capture_post_samples must be positive and the capture {} samples or less", MAX_CAPTURE_SAMPLES);
+        }
+        if !(self.cycle_voltage > 0.0) {
+            anyhow::bail!("cycle_voltage must be positive");
+        }
+        if self.cycle_on_secs == 0 || self.cycle_off_secs == 0 {
+            anyhow::bail!("cycle_on_secs and cycle_off_secs must be positive");
+        }
+        if !(self.efficiency_warn >= 0.0 && self.efficiency_warn < 100.0) {
+            anyhow::bail!("efficiency_warn must be 0 to 100%");
+        }
+        if !(self.pd_probe_tolerance > 0.0 && self.pd_probe_tolerance <= 50.0) {
+            anyhow::bail!("pd_probe_tolerance must be between 0 and 50%");
+        }
+        if !(self.pd_voltage_gain >= 0.9 && self.pd_voltage_gain <= 1.1) || !(self.pd_voltage_offset.abs() <= 1.0) {
+            anyhow::bail!("pd_voltage_gain must be 0.9 to 1.1 and pd_voltage_offset -1.0 to 1.0V");
+        }
+        if !(1..=adcfilter::MAX_WINDOW as u32).contains(&self.adc_filter_window) {
+            anyhow::bail!("adc_filter_window must be 1 to {} readings", adcfilter::MAX_WINDOW);
+        }
+        for alpha in [self.temp_filter_alpha, self.pd_voltage_filter_alpha] {
+            if !(alpha > 0.0 && alpha <= 1.0) {
+                anyhow::bail!("temp_filter_alpha and pd_voltage_filter_alpha must be more than 0 and up to 1");
+            }
+        }
+        if !(self.temp_outlier >= 0.0) || !(self.pd_voltage_outlier >= 0.0) {
+            anyhow::bail!("temp_outlier and pd_voltage_outlier must be 0 or more");
+        }
+        for digits in [self.display_voltage_digits, self.display_current_digits, self.display_power_digits] {
+            if !(units::MIN_DIGITS..=units::MAX_DIGITS).contains(&digits) {
+                anyhow::bail!("display_*_digits must be {} to {}", units::MIN_DIGITS, units::MAX_DIGITS);
+            }
+        }
+        if !(1..=50).contains(&self.display_refresh_hz) {
+            anyhow::bail!("display_refresh_hz must be 1 to 50");
+        }
+        if !(1..=40).contains(&self.display_spi_mhz) {
+            anyhow::bail!("display_spi_mhz must be 1 to 40");
+        }
+        if !self.pid_kp.is_finite() || !self.pid_ki.is_finite() || !self.pid_kd.is_finite() {
+            anyhow::bail!("PID gains must be finite");
+        }
+        if self.pid_kp < 0.0 || self.pid_ki < 0.0 || self.pid_kd < 0.0 {
+            anyhow::bail!("PID gains must not be negative");
+        }
+        let rate = self.control_rate_hz;
+        if rate < controltimer::MIN_RATE_HZ || rate > controltimer::MAX_RATE_HZ || rate % controltimer::HOUSEKEEPING_RATE_HZ != 0 {
+            anyhow::bail!("control_rate_hz must be a multiple of {} from {} to {}",
+                controltimer::HOUSEKEEPING_RATE_HZ, controltimer::MIN_RATE_HZ, controltimer::MAX_RATE_HZ);
+        }
+        if self.perf_metrics_secs > 3600 {
+            anyhow::bail!("perf_metrics_secs must be 0 to 3600");
+        }
+        if self.pid_trace_rate_hz > rate.min(MAX_TRACE_RATE_HZ) {
+            anyhow::bail!("pid_trace_rate_hz must be 0 to control_rate_hz (at most {})", MAX_TRACE_RATE_HZ);
+        }
+        if self.pwm_resolution_bits < MIN_PWM_RESOLUTION_BITS || self.pwm_resolution_bits > MAX_PWM_RESOLUTION_BITS {
+            anyhow::bail!("pwm_resolution_bits must be {} to {}", MIN_PWM_RESOLUTION_BITS, MAX_PWM_RESOLUTION_BITS);
+        }
+        if self.pwm_frequency_hz < MIN_PWM_FREQUENCY_HZ
+            || self.pwm_frequency_hz as u64 * (1u64 << self.pwm_resolution_bits) > LEDC_CLOCK_HZ {
+            anyhow::bail!("pwm_frequency_hz must be at least {} and pwm_frequency_hz * 2^pwm_resolution_bits at most {} (e.g. 20000Hz with 11 bits)",
+                MIN_PWM_FREQUENCY_HZ, LEDC_CLOCK_HZ);
+        }
+        crate::logfilter::LogFilter::parse(&self.log_level)?;
+        crate::logfilter::LogFilter::parse(&self.syslog_level)?;
+        Ok(())
+    }
+
+    pub fn get_power_on_mode(&self) -> PowerOnMode {
+        match self.power_on_mode.as_str() {
+            "restore" => PowerOnMode::Restore,
+            "resume" => PowerOnMode::Resume,
+            _ => PowerOnMode::Off,
+        }
+    }
+
+    pub fn get_log_buffer_policy(&self) -> BufferPolicy {
+        match self.log_buffer_policy.as_str() {
+            "overwrite" => BufferPolicy::Overwrite,
+            _ => BufferPolicy::Stop,
+        }
+    }
+
+    pub fn get_unit_format(&self) -> UnitFormat {
+        UnitFormat {
+            voltage_digits: self.display_voltage_digits,
+            current_digits: self.display_current_digits,
+            power_digits: self.display_power_digits,
+        }
+    }
+
+    pub fn get_access_tokens(&self) -> AccessTokens {
+        AccessTokens::new(&self.api_read_token, &self.api_control_token)
+    }
+
+    pub fn get_start_stop_gesture(&self) -> StartStopGesture {
+        StartStopGesture::parse(&self.start_stop_gesture).unwrap_or(StartStopGesture::CenterLong)
+    }
+
+    pub fn get_trigger_config(&self) -> TriggerConfig {
+        let polarity = TriggerPolarity::parse(&self.scope_trigger_polarity).unwrap_or(TriggerPolarity::High);
+        TriggerConfig::new(polarity, self.scope_trigger_width_ms, &self.scope_trigger_events)
+            .unwrap_or_else(|_| TriggerConfig::new(polarity, 1, "").unwrap())
+    }
+
+    pub fn get_current_limit_mode(&self) -> CurrentLimitMode {
+        CurrentLimitMode::parse(&self.current_limit_mode).unwrap_or(CurrentLimitMode::Trip)
+    }
+
+    pub fn get_share_mode(&self) -> ShareMode {
+        ShareMode::parse(&self.share_mode).unwrap_or(ShareMode::Off)
+    }
+
+    pub fn get_alert_format(&self) -> AlertFormat {
+        match self.alert_format.as_str() {
+            "slack" => AlertFormat::Slack,
+            "ntfy" => AlertFormat::Ntfy,
+            _ => AlertFormat::Json,
+        }
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        let partition = EspDefaultNvsPartition::take()?;
+        let mut nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+        store(&mut nvs, self)?;
+        info!("Settings saved to NVS (schema v{})", self.schema_version);
+        Ok(())
+    }
+}
+
+// Erase the whole NVS partition: settings, the last output voltage and the WiFi driver data.
+// The unit must be restarted afterwards; the defaults are stored again on the next boot.
+pub fn factory_reset() -> anyhow::Result<()> {
+    esp_idf_sys::esp!(unsafe { esp_idf_sys::nvs_flash_erase() })?;
+    warn!("NVS erased (factory reset)");
+    Ok(())
+}
+
+fn store(nvs: &mut EspNvs<NvsDefault>, settings: &Settings) -> anyhow::Result<()> {
+    let json = serde_json::to_string(settings)?;
+    nvs.set_str(SETTINGS_KEY, &json)?;
+    Ok(())
+}
+
+// Overlay the stored fields onto the defaults, so fields unknown to the stored schema keep their defaults.
+fn merge(defaults: &Settings, stored: Value) -> Option<Settings> {
+    let mut value = serde_json::to_value(defaults).ok()?;
+    if let (Some(base), Value::Object(overlay)) = (value.as_object_mut(), stored) {
+        for (key, val) in overlay {
+            if base.contains_key(&key) {
+                base.insert(key, val);
+            }
+        }
+        base.insert("schema_version".to_string(), Value::from(SETTINGS_SCHEMA_VERSION));
+    }
+    serde_json::from_value(value).ok()
+}
+
+// Migration path between the schema versions
+fn migrate(mut value: Value, from: u32) -> Value {
+    if from < 2 {
+        // v1 -> v2: the protection limits moved into the settings document (see migrate_v1)
+        if let Some(obj) = value.as_object_mut() {
+            obj.insert("schema_version".to_string(), Value::from(2));
+        }
+    }
+    value
+}
+
+// Schema version 1 stored only the protection limits as individual blobs
+fn migrate_v1(nvs: &EspNvs<NvsDefault>) -> Option<Value> {
+    let mut obj = Map::new();
+    for (key, field) in [(V1_MAX_CURRENT_KEY, "max_current_limit"),
+                         (V1_MAX_POWER_KEY, "max_power_limit"),
+                         (V1_MAX_TEMP_KEY, "max_temperature")] {
+        let mut bytes = [0u8; 4];
+        if let Ok(Some(_)) = nvs.get_blob(key, &mut bytes) {
+            obj.insert(field.to_string(), Value::from(f32::from_le_bytes(bytes)));
+        }
+    }
+    if obj.is_empty() {
+        return None;
+    }
+    obj.insert("schema_version".to_string(), Value::from(1));
+    Some(Value::Object(obj))
+}
@@ -0,0 +1,69 @@
+// UDP transport of the current share messages (see dcpower_control::share)
+// The master sends a message to the slave every other main loop. The slave receives them
+// in a thread and sends them to the main loop over the bus.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use log::*;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+use crate::bus::Command;
+use dcpower_control::share::ShareMessage;
+
+const MAX_DATAGRAM: usize = 128;
+
+pub struct ShareNet {
+    socket: UdpSocket,
+    // Slave address of the master, None on the slave
+    peer: Option<SocketAddrV4>,
+}
+
+impl ShareNet {
+    // Master: the messages go to the slave at the address
+    pub fn master(peer: Ipv4Addr, port: u16) -> anyhow::Result<ShareNet> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_nonblocking(true)?;
+        Ok(ShareNet { socket: socket, peer: Some(SocketAddrV4::new(peer, port)) })
+    }
+
+    // Slave: bind the share port and start the receive thread
+    pub fn slave(port: u16, commands: Sender<Command>) -> anyhow::Result<ShareNet> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+        let rx_socket = socket.try_clone()?;
+        let _th = thread::spawn(move || {
+            info!("Start current share thread on UDP port {}.", port);
+            crate::health::register_task("share");
+            let mut buf = [0u8; MAX_DATAGRAM];
+            loop {
+                let (len, from) = match rx_socket.recv_from(&mut buf) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        warn!("Share receive failed: {:?}", e);
+                        thread::sleep(Duration::from_secs(1));
+                        continue;
+                    },
+                };
+                match ShareMessage::parse(&String::from_utf8_lossy(&buf[..len])) {
+                    Ok(message) => {
+                        let _ = commands.send(Command::Share(message));
+                    },
+                    Err(e) => debug!("Ignored datagram from {}: {}", from, e),
+                }
+            }
+        });
+        Ok(ShareNet { socket: socket, peer: None })
+    }
+
+    // Send the message to the slave (master only); a full socket buffer drops it
+    pub fn send(&self, message: &ShareMessage) {
+        if let Some(peer) = self.peer {
+            if let Err(e) = self.socket.send_to(message.to_text().as_bytes(), peer) {
+                debug!("Share send failed: {:?}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,104 @@
+// UDP transport of the multi-unit sync messages (see dcpower_control::unitsync)
+// A thread receives the datagrams on the sync port and sends the sync messages to the main
+// loop over the bus. A start is broadcast to the local network a few times, as UDP may
+// drop a datagram; the receivers ignore the repeats by the run ID.
+// With sync_key set, the messages are signed with it and the ones without a valid MAC are
+// dropped.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use log::*;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use crate::bus::Command;
+use dcpower_control::unitsync::{self, SyncMessage};
+
+const SEND_REPEATS: u32 = 3;
+const SEND_INTERVAL: Duration = Duration::from_millis(20);
+const MAX_DATAGRAM: usize = 256;
+
+pub struct SyncNet {
+    socket: UdpSocket,
+    port: u16,
+    key: String,
+}
+
+fn mac(key: &str) -> Hmac<Sha256> {
+    // Any key length is accepted
+    Hmac::<Sha256>::new_from_slice(key.as_bytes()).unwrap()
+}
+
+// The message with its MAC, as is without a key
+fn sign(key: &str, text: &str) -> String {
+    if key.is_empty() {
+        return text.to_string();
+    }
+    let mut mac = mac(key);
+    mac.update(text.as_bytes());
+    format!("{} {}", text, unitsync::to_hex(&mac.finalize().into_bytes()))
+}
+
+// The message if its MAC is valid (any message without a key)
+fn verify<'a>(key: &str, text: &'a str) -> Result<&'a str, String> {
+    let (message, tag) = unitsync::split_mac(text);
+    if key.is_empty() {
+        return Ok(message);
+    }
+    let tag = tag.and_then(unitsync::from_hex).ok_or("no MAC")?;
+    let mut mac = mac(key);
+    mac.update(message.as_bytes());
+    mac.verify_slice(&tag).map_err(|_| "invalid MAC".to_string())?;
+    Ok(message)
+}
+
+impl SyncNet {
+    // Bind the sync port and start the receive thread
+    pub fn start(port: u16, key: &str, commands: Sender<Command>) -> anyhow::Result<SyncNet> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port))?;
+        socket.set_broadcast(true)?;
+        let rx_socket = socket.try_clone()?;
+        let rx_key = key.to_string();
+        let _th = thread::spawn(move || {
+            info!("Start sync thread on UDP port {}.", port);
+            crate::health::register_task("sync");
+            let mut buf = [0u8; MAX_DATAGRAM];
+            loop {
+                let (len, from) = match rx_socket.recv_from(&mut buf) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        warn!("Sync receive failed: {:?}", e);
+                        thread::sleep(Duration::from_secs(1));
+                        continue;
+                    },
+                };
+                let text = String::from_utf8_lossy(&buf[..len]);
+                match verify(&rx_key, &text).and_then(SyncMessage::parse) {
+                    Ok(message) => {
+                        debug!("Sync message from {}: {}", from, text.trim());
+                        let _ = commands.send(Command::Sync(message));
+                    },
+                    Err(e) => debug!("Ignored datagram from {}: {}", from, e),
+                }
+            }
+        });
+        Ok(SyncNet { socket: socket, port: port, key: key.to_string() })
+    }
+
+    // Broadcast the message to the sync port of the local network
+    pub fn broadcast(&self, message: &SyncMessage) -> anyhow::Result<()> {
+        let text = sign(&self.key, &message.to_text());
+        for i in 0..SEND_REPEATS {
+            if i > 0 {
+                thread::sleep(SEND_INTERVAL);
+            }
+            self.socket.send_to(text.as_bytes(), (Ipv4Addr::BROADCAST, self.port))?;
+        }
+        Ok(())
+    }
+}
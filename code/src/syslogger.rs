@@ -2,12 +2,25 @@
 // Copyright (c) 2024 Hiroshi Nakajima
 //
 // Custom syslog implementation for ESP32-S3 platform
+// log! calls only queue the message. A dedicated thread sends the queued messages, so a
+// slow network never stalls the caller. When the queue is full the oldest message is
+// dropped, and the number of dropped messages is reported periodically.
+// Every message carries the unit ID and the firmware version as RFC 5424 structured data.
+// Key-values of a log record (e.g. the measured values of a fault) are added as a second
+// SD-ELEMENT: warn!(cause:? = cause, current = 5.2; "...").
 
 #![allow(dead_code)]
 
-use log::{Log, Record, Level, Metadata, SetLoggerError, LevelFilter};
-use std::sync::Mutex;
+use log::{Log, Record, Level, Metadata};
+use log::kv::{self, Key, Value, VisitSource};
+use crate::bootlog::{self, BootLog};
+use crate::version;
+use std::sync::{Mutex, Condvar};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::collections::VecDeque;
 use std::net::UdpSocket;
+use std::thread;
+use std::time::{Duration, Instant};
 use std::fmt::Write;
 use std::time::SystemTime;
 use chrono::{DateTime, Utc};
@@ -16,11 +29,26 @@ use std::io;
 // Remote syslog server address
 const SYSLOG_SERVER: &str = "192.168.2.140:514";
 const APP_NAME: &str = "dcpowerunit";
+// SD-IDs use the documentation enterprise number (RFC 5612)
+const SD_ID_UNIT: &str = "unit@32473";
+const SD_ID_VALUES: &str = "values@32473";
 
-// Global logger instance protected by a mutex
-static SYSLOGGER: Mutex<Option<SysLogger>> = Mutex::new(None);
-// Static reference for the log system
-static STATIC_LOGGER: StaticLoggerWrapper = StaticLoggerWrapper;
+const QUEUE_CAPACITY: usize = 64;
+const DROP_NOTICE_INTERVAL: Duration = Duration::from_secs(10);
+// The boot log is sent when the clock is set by SNTP, or after this time without it
+const BOOT_LOG_CLOCK_WAIT: Duration = Duration::from_secs(60);
+const CLOCK_VALID_SECS: u64 = 1_700_000_000;
+const BOOT_LOG_BURST: usize = 8;
+
+// Messages waiting for the sender thread. The lock is only held to push or take messages.
+static QUEUE: Mutex<VecDeque<QueuedMessage>> = Mutex::new(VecDeque::new());
+static QUEUE_READY: Condvar = Condvar::new();
+static LOGGER_ENABLED: AtomicBool = AtomicBool::new(false);
+// Counters since boot
+static SENT_COUNT: AtomicU32 = AtomicU32::new(0);
+static DROPPED_COUNT: AtomicU32 = AtomicU32::new(0);
+// Syslog output of the composite logger (logger.rs)
+pub static STATIC_LOGGER: StaticLoggerWrapper = StaticLoggerWrapper;
 
 // Enumeration for syslog facilities (RFC 5424)
 #[derive(Debug, Clone, Copy)]
@@ -66,11 +94,18 @@ enum Severity {
     Debug = 7,
 }
 
-// Our custom logger that forwards logs to remote syslog server
+struct QueuedMessage {
+    severity: Severity,
+    timestamp: SystemTime,
+    structured_data: String,
+    message: String,
+}
+
+// Our custom logger that forwards logs to remote syslog server (owned by the sender thread)
 pub struct SysLogger {
     socket: UdpSocket,
-    level_filter: LevelFilter,
     server_addr: String,
+    unit_sd: String,
 }
 
 impl SysLogger {
@@ -82,6 +117,7 @@ impl SysLogger {
         timestamp: SystemTime,
         hostname: &str,
         app_name: &str,
+        structured_data: &str,
         message: &str,
     ) -> String {
         let mut buffer = String::new();
@@ -107,16 +143,16 @@ impl SysLogger {
         // PROCID and MSGID (using - as nil value)
         let _ = write!(&mut buffer, "- - ");
         
+        // STRUCTURED-DATA
+        let _ = write!(&mut buffer, "{}{} ", self.unit_sd, structured_data);
+        
         // MSG
         let _ = write!(&mut buffer, "{}", message);
         
         buffer
     }
 
-    fn send_message(&self, level: Severity, message: &str) {
-        // Get current timestamp
-        let timestamp = SystemTime::now();
-
+    fn send_message(&self, level: Severity, timestamp: SystemTime, structured_data: &str, message: &str) {
         // Format the message according to RFC 5424
         let formatted_message = self.format_syslog_message(
             Facility::User,
@@ -124,46 +160,151 @@ impl SysLogger {
             timestamp,
             "esp32-s3", // Using a static hostname
             APP_NAME,
+            structured_data,
             message,
         );
 
         // Send the message to the syslog server - using sendto instead of send
         // to avoid connection issues
+        // The socket is non-blocking; a message that cannot be sent is counted as dropped
         match self.socket.send_to(formatted_message.as_bytes(), &self.server_addr) {
-            Ok(_) => {},
-            Err(e) => {
-                // Use eprintln for errors since we can't use the logger itself
-                eprintln!("Failed to send log to syslog server: {}", e);
+            Ok(_) => {
+                SENT_COUNT.fetch_add(1, Ordering::Relaxed);
+            },
+            Err(_) => {
+                DROPPED_COUNT.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // Sender thread
+    fn run(self, boot_log: BootLog) {
+        let mut reported_dropped = 0;
+        let mut last_notice = Instant::now();
+        let started = Instant::now();
+        let mut boot_log = Some(boot_log);
+        loop {
+            if boot_log.is_some() && (clock_is_set() || started.elapsed() >= BOOT_LOG_CLOCK_WAIT) {
+                if let Some(log) = boot_log.take() {
+                    self.send_boot_log(log);
+                }
+            }
+            let messages : Vec<QueuedMessage> = {
+                let mut queue = QUEUE.lock().unwrap();
+                if queue.is_empty() {
+                    queue = QUEUE_READY.wait_timeout(queue, Duration::from_secs(1)).unwrap().0;
+                }
+                queue.drain(..).collect()
+            };
+            for msg in messages {
+                self.send_message(msg.severity, msg.timestamp, &msg.structured_data, &msg.message);
+            }
+            if last_notice.elapsed() >= DROP_NOTICE_INTERVAL {
+                last_notice = Instant::now();
+                let dropped = DROPPED_COUNT.load(Ordering::Relaxed);
+                if dropped != reported_dropped {
+                    let notice = format!("{} messages dropped ({} since boot)", dropped.wrapping_sub(reported_dropped), dropped);
+                    self.send_message(Severity::Warning, SystemTime::now(), "", &notice);
+                    reported_dropped = dropped;
+                }
+            }
+        }
+    }
+
+    // Send the records captured before syslog was started with their original time
+    fn send_boot_log(&self, log: BootLog) {
+        let now = SystemTime::now();
+        let uptime_now = bootlog::uptime_us();
+        for (i, r) in log.records.iter().enumerate() {
+            let age = Duration::from_micros((uptime_now - r.uptime_us).max(0) as u64);
+            let timestamp = now.checked_sub(age).unwrap_or(now);
+            self.send_message(severity(r.level), timestamp, "", &format!("[{}] {}", r.target, r.message));
+            // Pace the burst to keep the socket buffers from overflowing
+            if i % BOOT_LOG_BURST == BOOT_LOG_BURST - 1 {
+                thread::sleep(Duration::from_millis(10));
             }
         }
+        if log.missed > 0 {
+            self.send_message(Severity::Warning, now, "", &format!("{} boot log messages were not captured", log.missed));
+        }
     }
 }
 
-impl Log for SysLogger {
-    fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= self.level_filter
+fn clock_is_set() -> bool {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs() > CLOCK_VALID_SECS).unwrap_or(false)
+}
+
+// Map log levels to syslog severity
+fn severity(level: Level) -> Severity {
+    match level {
+        Level::Error => Severity::Error,
+        Level::Warn => Severity::Warning,
+        Level::Info => Severity::Informational,
+        Level::Debug => Severity::Debug,
+        Level::Trace => Severity::Debug,
+    }
+}
+
+// Queue a message for the sender thread, dropping the oldest one if the queue is full
+fn enqueue(severity: Severity, structured_data: String, message: String) {
+    let mut queue = QUEUE.lock().unwrap();
+    if queue.len() >= QUEUE_CAPACITY {
+        queue.pop_front();
+        DROPPED_COUNT.fetch_add(1, Ordering::Relaxed);
     }
+    queue.push_back(QueuedMessage { severity: severity, timestamp: SystemTime::now(), structured_data: structured_data, message: message });
+    drop(queue);
+    QUEUE_READY.notify_one();
+}
 
-    fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
-            // Map log levels to syslog severity
-            let level = match record.level() {
-                Level::Error => Severity::Error,
-                Level::Warn => Severity::Warning,
-                Level::Info => Severity::Informational,
-                Level::Debug => Severity::Debug,
-                Level::Trace => Severity::Debug,
-            };
-            
-            // Format and send the message
-            let message = format!("[{}] {}", record.target(), record.args());
-            self.send_message(level, &message);
+// PARAM-VALUE escaping (RFC 5424 6.3.3)
+fn escape_param_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == '"' || c == '\\' || c == ']' {
+            escaped.push('\\');
         }
+        escaped.push(c);
     }
+    escaped
+}
 
-    fn flush(&self) {
-        // UDP doesn't require explicit flushing
+// Collects the key-values of a log record into an SD-ELEMENT
+struct StructuredDataVisitor {
+    params: String,
+}
+
+impl<'kvs> VisitSource<'kvs> for StructuredDataVisitor {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let _ = write!(&mut self.params, " {}=\"{}\"", key, escape_param_value(&value.to_string()));
+        Ok(())
+    }
+}
+
+fn record_structured_data(record: &Record) -> String {
+    let mut visitor = StructuredDataVisitor { params: String::new() };
+    let _ = record.key_values().visit(&mut visitor);
+    if visitor.params.is_empty() {
+        return String::new();
+    }
+    format!("[{}{}]", SD_ID_VALUES, visitor.params)
+}
+
+// Unit ID from the factory MAC address
+pub fn unit_id() -> String {
+    let mut mac = [0u8; 6];
+    unsafe {
+        esp_idf_sys::esp_efuse_mac_get_default(mac.as_mut_ptr());
     }
+    mac.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn sent_count() -> u32 {
+    SENT_COUNT.load(Ordering::Relaxed)
+}
+
+pub fn dropped_count() -> u32 {
+    DROPPED_COUNT.load(Ordering::Relaxed)
 }
 
 // Static logger wrapper that lives for the entire program
@@ -171,28 +312,19 @@ pub struct StaticLoggerWrapper;
 
 impl Log for StaticLoggerWrapper {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        if let Ok(guard) = SYSLOGGER.lock() {
-            if let Some(logger) = guard.as_ref() {
-                return logger.enabled(metadata);
-            }
-        }
-        false
+        LOGGER_ENABLED.load(Ordering::Relaxed) && crate::logfilter::enabled(crate::logfilter::Sink::Syslog, metadata)
     }
     
     fn log(&self, record: &Record) {
-        if let Ok(guard) = SYSLOGGER.lock() {
-            if let Some(logger) = guard.as_ref() {
-                logger.log(record);
-            }
+        if self.enabled(record.metadata()) {
+            // Format and queue the message
+            let message = format!("[{}] {}", record.target(), record.args());
+            enqueue(severity(record.level()), record_structured_data(record), message);
         }
     }
     
     fn flush(&self) {
-        if let Ok(guard) = SYSLOGGER.lock() {
-            if let Some(logger) = guard.as_ref() {
-                logger.flush();
-            }
-        }
+        // UDP doesn't require explicit flushing
     }
 }
 
@@ -200,8 +332,6 @@ impl Log for StaticLoggerWrapper {
 #[derive(Debug)]
 pub enum LoggerError {
     SocketError(io::Error),
-    LockError,
-    SetLoggerError(SetLoggerError),
 }
 
 impl From<io::Error> for LoggerError {
@@ -210,25 +340,17 @@ impl From<io::Error> for LoggerError {
     }
 }
 
-impl From<SetLoggerError> for LoggerError {
-    fn from(error: SetLoggerError) -> Self {
-        LoggerError::SetLoggerError(error)
-    }
-}
-
 impl std::fmt::Display for LoggerError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             LoggerError::SocketError(e) => write!(f, "Socket error: {}", e),
-            LoggerError::LockError => write!(f, "Failed to acquire lock on logger"),
-            LoggerError::SetLoggerError(_) => write!(f, "Failed to set global logger"),
         }
     }
 }
 
 // Initialize the syslogger with improved error handling
-pub fn init_logger(syslog_server: &str, syslog_enable: &str) -> Result<(), LoggerError> {
-    if syslog_enable != "true" {
+pub fn init_logger(syslog_server: &str, syslog_enable: bool) -> Result<(), LoggerError> {
+    if !syslog_enable {
         // syslog無効時は何もしない
         return Ok(());
     }
@@ -245,21 +367,18 @@ pub fn init_logger(syslog_server: &str, syslog_enable: &str) -> Result<(), Logge
     }
     let sys_logger = SysLogger {
         socket,
-        level_filter: LevelFilter::Info,
         server_addr: syslog_server.to_string(),
+        unit_sd: format!("[{} id=\"{}\" fw=\"{}\" git=\"{}\" built=\"{}\"]", SD_ID_UNIT, unit_id(),
+            escape_param_value(version::VERSION), escape_param_value(version::GIT_HASH), version::build_time()),
     };
-    let test_message = format!("Syslog logger initialized for {}", APP_NAME);
-    sys_logger.send_message(Severity::Informational, &test_message);
-    let mut guard = SYSLOGGER.lock().map_err(|_| {
-        eprintln!("Failed to acquire lock on logger mutex");
-        LoggerError::LockError
-    })?;
-    *guard = Some(sys_logger);
-    drop(guard);
-    log::set_logger(&STATIC_LOGGER)
-        .map(|()| log::set_max_level(LevelFilter::Info))
-        .map_err(|e| {
-            eprintln!("Failed to set global logger: {:?}", e);
-            LoggerError::SetLoggerError(e)
-        })
+    // Records from before this point are sent from the boot log
+    let boot_log = bootlog::take();
+    let _th = thread::spawn(move || {
+        crate::health::register_task("syslog");
+        sys_logger.run(boot_log);
+    });
+    enqueue(Severity::Informational, String::new(), format!("Syslog logger initialized for {}", APP_NAME));
+    // The composite logger starts forwarding the records from here
+    LOGGER_ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
 }
\ No newline at end of file
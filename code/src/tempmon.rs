@@ -0,0 +1,122 @@
+// Temperature plausibility monitor
+// Cross-checks the GPIO18 analog temperature against the INA228 die temperature
+// and the AP33772S temperature, and selects the conservative source on a sensor fault.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use log::*;
+
+// Readings outside of this range are treated as an open/shorted sensor
+const TEMP_PLAUSIBLE_MIN : f32 = -20.0;
+const TEMP_PLAUSIBLE_MAX : f32 = 125.0;
+// The heatsink sensor runs hotter than the other dies under load, so only wild divergence is a fault
+const TEMP_DIVERGENCE_LIMIT : f32 = 40.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TempSource {
+    Analog,
+    Ina228,
+    Ap33772s,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TempSensorFault {
+    None,
+    Implausible(TempSource),
+    Diverged(TempSource),
+}
+
+pub struct TempMonitor {
+    analog: f32,
+    ina228: Option<f32>,
+    ap33772s: Option<f32>,
+    fault: TempSensorFault,
+}
+
+impl TempMonitor {
+    pub fn new() -> TempMonitor {
+        TempMonitor {
+            analog: 0.0,
+            ina228: None,
+            ap33772s: None,
+            fault: TempSensorFault::None,
+        }
+    }
+
+    pub fn set_ina228_temperature(&mut self, temp: Option<f32>) {
+        self.ina228 = temp;
+    }
+
+    pub fn set_ap33772s_temperature(&mut self, temp: Option<f32>) {
+        self.ap33772s = temp;
+    }
+
+    pub fn get_ina228_temperature(&self) -> Option<f32> {
+        self.ina228
+    }
+
+    pub fn get_ap33772s_temperature(&self) -> Option<f32> {
+        self.ap33772s
+    }
+
+    pub fn get_fault(&self) -> TempSensorFault {
+        self.fault
+    }
+
+    // Update with the analog reading and return the temperature to be used for protection.
+    pub fn update(&mut self, analog: f32) -> f32 {
+        self.analog = analog;
+        let analog_ok = is_plausible(analog);
+        let mut references : Vec<f32> = Vec::new();
+        let mut fault = TempSensorFault::None;
+        for (source, temp) in [(TempSource::Ina228, self.ina228), (TempSource::Ap33772s, self.ap33772s)] {
+            if let Some(t) = temp {
+                if is_plausible(t) {
+                    references.push(t);
+                }
+                else {
+                    fault = TempSensorFault::Implausible(source);
+                }
+            }
+        }
+        if !analog_ok {
+            fault = TempSensorFault::Implausible(TempSource::Analog);
+        }
+        else if references.len() > 0 && references.iter().all(|t| (analog - t).abs() > TEMP_DIVERGENCE_LIMIT) {
+            fault = TempSensorFault::Diverged(TempSource::Analog);
+        }
+
+        let temperature = match fault {
+            TempSensorFault::None => analog,
+            _ => {
+                // Fall back to the hottest plausible source
+                let mut conservative = if analog_ok { Some(analog) } else { None };
+                for t in &references {
+                    conservative = Some(conservative.map_or(*t, |c: f32| c.max(*t)));
+                }
+                // No plausible source at all, report the upper bound so the output trips
+                conservative.unwrap_or(TEMP_PLAUSIBLE_MAX)
+            }
+        };
+
+        if fault != self.fault {
+            match fault {
+                TempSensorFault::None => {
+                    info!("Temperature sensors recovered: Analog={:.1}°C INA228={:?} AP33772S={:?}", analog, self.ina228, self.ap33772s);
+                },
+                _ => {
+                    warn!("Temperature sensor fault {:?}: Analog={:.1}°C INA228={:?} AP33772S={:?}, using {:.1}°C",
+                        fault, analog, self.ina228, self.ap33772s, temperature);
+                },
+            }
+            self.fault = fault;
+        }
+        temperature
+    }
+}
+
+fn is_plausible(temp: f32) -> bool {
+    temp.is_finite() && temp >= TEMP_PLAUSIBLE_MIN && temp <= TEMP_PLAUSIBLE_MAX
+}
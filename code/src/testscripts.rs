@@ -0,0 +1,117 @@
+// Test scripts stored on SPIFFS
+// A script is a JSON document stored as /spiffs/script-<name>.json:
+//   {"name": "usb-fan", "steps": [{"voltage": 5.0}, {"current_limit": 1.0}, {"output": true},
+//    {"wait": 2.0}, {"assert_current": {"min": 0.1, "max": 0.3}}, {"marker": "fan at 5V"},
+//    {"assert_voltage": {"min": 4.9, "max": 5.1}}, {"output": false}]}
+// The wait is in seconds. A script is validated before it is stored, and run by the main
+// loop (see dcpower_control::testscript).
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use log::*;
+use std::fs;
+use serde::Deserialize;
+use dcpower_control::testscript::{is_valid_name, ScriptStep, TestScript};
+use crate::configfile::SPIFFS_BASE_PATH;
+
+const SCRIPT_FILE_PREFIX: &str = "script-";
+const SCRIPT_FILE_SUFFIX: &str = ".json";
+pub const MAX_SCRIPT_LEN: usize = 4000;
+
+// Request to the main loop from the console, the panel and the HTTP API
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptRequest {
+    Run(String),
+    Stop,
+    Status,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScriptFile {
+    name: String,
+    steps: Vec<StepFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RangeFile {
+    min: f32,
+    max: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+enum StepFile {
+    Voltage(f32),
+    CurrentLimit(f32),
+    Output(bool),
+    Wait(f32),
+    AssertVoltage(RangeFile),
+    AssertCurrent(RangeFile),
+    Marker(String),
+}
+
+// Parse and validate a script document
+pub fn parse(json: &str) -> anyhow::Result<TestScript> {
+    let file: ScriptFile = serde_json::from_str(json)?;
+    let steps = file.steps.into_iter().map(|step| match step {
+        StepFile::Voltage(v) => ScriptStep::Voltage(v),
+        StepFile::CurrentLimit(a) => ScriptStep::CurrentLimit(a),
+        StepFile::Output(on) => ScriptStep::Output(on),
+        StepFile::Wait(secs) => ScriptStep::Wait((secs.max(0.0) * 1000.0) as u64),
+        StepFile::AssertVoltage(range) => ScriptStep::AssertVoltage(range.min, range.max),
+        StepFile::AssertCurrent(range) => ScriptStep::AssertCurrent(range.min, range.max),
+        StepFile::Marker(text) => ScriptStep::Marker(text),
+    }).collect();
+    TestScript::new(&file.name, steps).map_err(|e| anyhow::anyhow!(e))
+}
+
+// Validate and store a script, replacing the one of the same name
+pub fn store(json: &str) -> anyhow::Result<TestScript> {
+    if json.len() > MAX_SCRIPT_LEN {
+        anyhow::bail!("a script must be {} bytes or less", MAX_SCRIPT_LEN);
+    }
+    let script = parse(json)?;
+    fs::write(script_path(script.get_name()), json)?;
+    info!("Test script {} stored ({} steps)", script.get_name(), script.get_steps().len());
+    Ok(script)
+}
+
+pub fn load(name: &str) -> anyhow::Result<TestScript> {
+    if !is_valid_name(name) {
+        anyhow::bail!("invalid script name: {}", name);
+    }
+    let json = fs::read_to_string(script_path(name)).map_err(|_| anyhow::anyhow!("no script {}", name))?;
+    parse(&json)
+}
+
+pub fn remove(name: &str) -> anyhow::Result<()> {
+    if !is_valid_name(name) {
+        anyhow::bail!("invalid script name: {}", name);
+    }
+    fs::remove_file(script_path(name)).map_err(|_| anyhow::anyhow!("no script {}", name))?;
+    info!("Test script {} removed", name);
+    Ok(())
+}
+
+// Names of the stored scripts in alphabetical order
+pub fn list() -> Vec<String> {
+    let mut names: Vec<String> = match fs::read_dir(SPIFFS_BASE_PATH) {
+        Ok(entries) => entries.filter_map(|e| e.ok())
+            .filter_map(|e| {
+                let file = e.file_name().to_string_lossy().to_string();
+                file.strip_prefix(SCRIPT_FILE_PREFIX)?.strip_suffix(SCRIPT_FILE_SUFFIX).map(|name| name.to_string())
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    names.sort();
+    names
+}
+
+fn script_path(name: &str) -> String {
+    format!("{}/{}{}{}", SPIFFS_BASE_PATH, SCRIPT_FILE_PREFIX, name, SCRIPT_FILE_SUFFIX)
+}
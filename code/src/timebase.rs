@@ -0,0 +1,29 @@
+// Timestamps of the measurements
+// The control task stamps each measurement with the esp_timer (microseconds since boot),
+// which does not jitter with the scheduling of the main loop and is not stepped by SNTP.
+// The records keep that monotonic time, and it is converted to the wall clock when the
+// points are formatted for upload, with the offset of the wall clock at that time. So the
+// spacing of the samples is as measured, and records taken before SNTP got the time are
+// still sent with a valid wall-clock time.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use std::time::SystemTime;
+
+// Monotonic time since boot (ns)
+pub fn monotonic_ns() -> u128 {
+    unsafe { esp_idf_sys::esp_timer_get_time() }.max(0) as u128 * 1000
+}
+
+// Wall clock (ns since the epoch) minus the monotonic time, now
+pub fn wall_offset_ns() -> u128 {
+    let wall = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    wall.saturating_sub(monotonic_ns())
+}
+
+// Wall-clock time (ns since the epoch) of a monotonic time
+pub fn to_wall_ns(clock: u128) -> u128 {
+    clock + wall_offset_ns()
+}
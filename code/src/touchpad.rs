@@ -1,14 +1,20 @@
-use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
 use std::thread;
 use std::time::{Duration, SystemTime};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::ffi::c_void;
 use log::*;
+use crate::bus::Command;
+use dcpower_control::touchbaseline::TouchBaseline;
 
 const MAX_TOUCHPADS: usize = 14;
 const THRESHOLD_PERCENT: f32 = 0.011;
+// Period of the key scan (ms)
+const SCAN_PERIOD_MS: u64 = 20;
 
 static TOUCH_ACTIVE_FLAG: AtomicBool = AtomicBool::new(false);
+// Time of the last touch interrupt (us since boot, wraps in 71 minutes)
+static TOUCH_TIME_US: AtomicU32 = AtomicU32::new(0);
 
 #[allow(dead_code)]
 pub enum Key {
@@ -37,6 +43,31 @@ pub enum KeyEvent {
     CenterKeyUp,
     CenterKeyDownLong,
     UpDownKeyCombinationDown,
+    LeftRightKeyCombinationDown,
+    // Up or Down while Center is held
+    CenterUpKeyCombinationDown,
+    CenterDownKeyCombinationDown,
+}
+
+// A key event with its timestamps (us since boot, wraps in 71 minutes): the touch interrupt
+// for a press or a release and the detection for a long press, and the send to the main loop
+#[derive(Debug, Clone, Copy)]
+pub struct KeyInput {
+    pub event: KeyEvent,
+    pub t_us: u32,
+    pub sent_us: u32,
+}
+
+pub fn now_us() -> u32 {
+    (crate::timebase::monotonic_ns() / 1000) as u32
+}
+
+fn now_ms() -> u64 {
+    (crate::timebase::monotonic_ns() / 1_000_000) as u64
+}
+
+fn send_key(events: &Sender<Command>, event: KeyEvent, t_us: u32) {
+    let _ = events.send(Command::Key(KeyInput { event: event, t_us: t_us, sent_us: now_us() }));
 }
 
 #[derive(Debug, Clone)]
@@ -58,7 +89,6 @@ struct KeyState {
     left: KeyInfo,
     right: KeyInfo,
     center: KeyInfo,
-    key_event: Vec<KeyEvent>,
 }
 
 #[derive(Debug)]
@@ -95,13 +125,30 @@ const LEFT_KEY : usize = 4;
 const RIGHT_KEY : usize = 2;
 const CENTER_KEY : usize = 5;
 
+// Pads of USE_TOUCH_PAD_CHANNEL, in the order of smooth_value
+const TOUCH_PAD_NUMS : [esp_idf_sys::touch_pad_t; 5] = [
+    esp_idf_sys::touch_pad_t_TOUCH_PAD_NUM1,
+    esp_idf_sys::touch_pad_t_TOUCH_PAD_NUM2,
+    esp_idf_sys::touch_pad_t_TOUCH_PAD_NUM3,
+    esp_idf_sys::touch_pad_t_TOUCH_PAD_NUM4,
+    esp_idf_sys::touch_pad_t_TOUCH_PAD_NUM5,
+];
+
 struct TouchState {
     smooth_value: [u32; MAX_TOUCHPADS],
 }
 
+// Long press setting sent to the touchpad thread
+struct PressThreshold {
+    key: Key,
+    threshold: u32,
+    allow_repeat: bool,
+}
+
 pub struct TouchPad {
-    touch_state: Arc<Mutex<TouchState>>,
-    key_state: Arc<Mutex<KeyState>>,
+    events: Sender<Command>,
+    threshold_tx: Sender<PressThreshold>,
+    threshold_rx: Option<Receiver<PressThreshold>>,
 }
 
 unsafe extern "C" fn touch_key_interrupt_handler(_arg: *mut c_void) {
@@ -109,35 +156,34 @@ unsafe extern "C" fn touch_key_interrupt_handler(_arg: *mut c_void) {
     if (intr & (esp_idf_sys::touch_pad_intr_mask_t_TOUCH_PAD_INTR_MASK_ACTIVE as u32 |
                 esp_idf_sys::touch_pad_intr_mask_t_TOUCH_PAD_INTR_MASK_INACTIVE as u32)
     ) != 0 {
+        TOUCH_TIME_US.store(esp_idf_sys::esp_timer_get_time() as u32, Ordering::Relaxed);
         TOUCH_ACTIVE_FLAG.store(true, Ordering::Relaxed);
     }
 }
 
 #[allow(dead_code)]
 impl TouchPad {
-    pub fn new() -> TouchPad {
-        TouchPad { touch_state: Arc::new(Mutex::new(
-            TouchState {
-                            smooth_value: [0; MAX_TOUCHPADS],
-            })),
-            key_state: Arc::new(Mutex::new(
-                KeyState {
-                    up: KeyInfo { active: false, press_time: SystemTime::now(), release_time: SystemTime::now(), press_duration: 0, release_duration: 0, press_threshold: 0, press: false, allow_repeat: false, repeat_count: 0 },
-                    down: KeyInfo { active: false, press_time: SystemTime::now(), release_time: SystemTime::now(), press_duration: 0, release_duration: 0, press_threshold: 0, press: false, allow_repeat: false, repeat_count: 0 },
-                    left: KeyInfo { active: false, press_time: SystemTime::now(), release_time: SystemTime::now(), press_duration: 0, release_duration: 0, press_threshold: 0, press: false, allow_repeat: false, repeat_count: 0 },
-                    right: KeyInfo { active: false, press_time: SystemTime::now(), release_time: SystemTime::now(), press_duration: 0, release_duration: 0, press_threshold: 0, press: false, allow_repeat: false, repeat_count: 0 },
-                    center: KeyInfo { active: false, press_time: SystemTime::now(), release_time: SystemTime::now(), press_duration: 0, release_duration: 0, press_threshold: 0, press: false, allow_repeat: false, repeat_count: 0 },
-                    key_event: Vec::new(),                         
-                })),
-        }
+    pub fn new(events: Sender<Command>) -> TouchPad {
+        let (threshold_tx, threshold_rx) = channel();
+        TouchPad { events: events, threshold_tx: threshold_tx, threshold_rx: Some(threshold_rx) }
     }
 
     pub fn start(&mut self)
     {
-        let touch_state = self.touch_state.clone();
-        let key_state = self.key_state.clone();
+        let events = self.events.clone();
+        let thresholds = self.threshold_rx.take().expect("TouchPad already started");
         let _th = thread::spawn(move || {
             info!("Start TouchPad Read Thread.");
+            crate::health::register_task("touchpad");
+            // Touch and key state are owned by this thread
+            let mut touch = TouchState { smooth_value: [0; MAX_TOUCHPADS] };
+            let mut keys = KeyState {
+                up: KeyInfo { active: false, press_time: SystemTime::now(), release_time: SystemTime::now(), press_duration: 0, release_duration: 0, press_threshold: 0, press: false, allow_repeat: false, repeat_count: 0 },
+                down: KeyInfo { active: false, press_time: SystemTime::now(), release_time: SystemTime::now(), press_duration: 0, release_duration: 0, press_threshold: 0, press: false, allow_repeat: false, repeat_count: 0 },
+                left: KeyInfo { active: false, press_time: SystemTime::now(), release_time: SystemTime::now(), press_duration: 0, release_duration: 0, press_threshold: 0, press: false, allow_repeat: false, repeat_count: 0 },
+                right: KeyInfo { active: false, press_time: SystemTime::now(), release_time: SystemTime::now(), press_duration: 0, release_duration: 0, press_threshold: 0, press: false, allow_repeat: false, repeat_count: 0 },
+                center: KeyInfo { active: false, press_time: SystemTime::now(), release_time: SystemTime::now(), press_duration: 0, release_duration: 0, press_threshold: 0, press: false, allow_repeat: false, repeat_count: 0 },
+            };
             unsafe {
                 esp_idf_sys::touch_pad_init();
                 for i in USE_TOUCH_PAD_CHANNEL.iter() {
@@ -169,41 +215,52 @@ impl TouchPad {
                 esp_idf_sys::touch_pad_set_fsm_mode(esp_idf_sys::touch_fsm_mode_t_TOUCH_FSM_MODE_TIMER);
                 esp_idf_sys::touch_pad_fsm_start();
                 thread::sleep(Duration::from_millis(100));
-                let mut lck = touch_state.lock().unwrap();
                 for i in USE_TOUCH_PAD_CHANNEL.iter() {
                     match i {
                         TouchPadChannel::TouchPad1 => {
-                            esp_idf_sys::touch_pad_filter_read_smooth(esp_idf_sys::touch_pad_t_TOUCH_PAD_NUM1, &mut lck.smooth_value[0]);
-                            esp_idf_sys::touch_pad_set_thresh(esp_idf_sys::touch_pad_t_TOUCH_PAD_NUM1, (lck.smooth_value[0] as f32 * THRESHOLD_PERCENT) as u32);
-                            info!("TouchPad1 threshold: {}", (lck.smooth_value[0] as f32 * THRESHOLD_PERCENT) as u32);
+                            esp_idf_sys::touch_pad_filter_read_smooth(esp_idf_sys::touch_pad_t_TOUCH_PAD_NUM1, &mut touch.smooth_value[0]);
+                            esp_idf_sys::touch_pad_set_thresh(esp_idf_sys::touch_pad_t_TOUCH_PAD_NUM1, (touch.smooth_value[0] as f32 * THRESHOLD_PERCENT) as u32);
+                            info!("TouchPad1 threshold: {}", (touch.smooth_value[0] as f32 * THRESHOLD_PERCENT) as u32);
                         },
                         TouchPadChannel::TouchPad2 => {
-                            esp_idf_sys::touch_pad_filter_read_smooth(esp_idf_sys::touch_pad_t_TOUCH_PAD_NUM2, &mut lck.smooth_value[1]);
-                            esp_idf_sys::touch_pad_set_thresh(esp_idf_sys::touch_pad_t_TOUCH_PAD_NUM2, (lck.smooth_value[1] as f32 * THRESHOLD_PERCENT) as u32);
-                            info!("TouchPad2 threshold: {}", (lck.smooth_value[1] as f32 * THRESHOLD_PERCENT) as u32);
+                            esp_idf_sys::touch_pad_filter_read_smooth(esp_idf_sys::touch_pad_t_TOUCH_PAD_NUM2, &mut touch.smooth_value[1]);
+                            esp_idf_sys::touch_pad_set_thresh(esp_idf_sys::touch_pad_t_TOUCH_PAD_NUM2, (touch.smooth_value[1] as f32 * THRESHOLD_PERCENT) as u32);
+                            info!("TouchPad2 threshold: {}", (touch.smooth_value[1] as f32 * THRESHOLD_PERCENT) as u32);
                         },
                         TouchPadChannel::TouchPad3 => {
-                            esp_idf_sys::touch_pad_filter_read_smooth(esp_idf_sys::touch_pad_t_TOUCH_PAD_NUM3, &mut lck.smooth_value[2]);
-                            esp_idf_sys::touch_pad_set_thresh(esp_idf_sys::touch_pad_t_TOUCH_PAD_NUM3, (lck.smooth_value[2] as f32 * THRESHOLD_PERCENT) as u32);
-                            info!("TouchPad5 threshold: {}", (lck.smooth_value[2] as f32 * THRESHOLD_PERCENT) as u32);
+                            esp_idf_sys::touch_pad_filter_read_smooth(esp_idf_sys::touch_pad_t_TOUCH_PAD_NUM3, &mut touch.smooth_value[2]);
+                            esp_idf_sys::touch_pad_set_thresh(esp_idf_sys::touch_pad_t_TOUCH_PAD_NUM3, (touch.smooth_value[2] as f32 * THRESHOLD_PERCENT) as u32);
+                            info!("TouchPad5 threshold: {}", (touch.smooth_value[2] as f32 * THRESHOLD_PERCENT) as u32);
                         },
                         TouchPadChannel::TouchPad4 => {
-                            esp_idf_sys::touch_pad_filter_read_smooth(esp_idf_sys::touch_pad_t_TOUCH_PAD_NUM4, &mut lck.smooth_value[3]);
-                            esp_idf_sys::touch_pad_set_thresh(esp_idf_sys::touch_pad_t_TOUCH_PAD_NUM4, (lck.smooth_value[3] as f32 * THRESHOLD_PERCENT) as u32);
-                            info!("TouchPad6 threshold: {}", (lck.smooth_value[3] as f32 * THRESHOLD_PERCENT) as u32);
+                            esp_idf_sys::touch_pad_filter_read_smooth(esp_idf_sys::touch_pad_t_TOUCH_PAD_NUM4, &mut touch.smooth_value[3]);
+                            esp_idf_sys::touch_pad_set_thresh(esp_idf_sys::touch_pad_t_TOUCH_PAD_NUM4, (touch.smooth_value[3] as f32 * THRESHOLD_PERCENT) as u32);
+                            info!("TouchPad6 threshold: {}", (touch.smooth_value[3] as f32 * THRESHOLD_PERCENT) as u32);
                         },
                         TouchPadChannel::TouchPad5 => {
-                            esp_idf_sys::touch_pad_filter_read_smooth(esp_idf_sys::touch_pad_t_TOUCH_PAD_NUM5, &mut lck.smooth_value[4]);
-                            esp_idf_sys::touch_pad_set_thresh(esp_idf_sys::touch_pad_t_TOUCH_PAD_NUM5, (lck.smooth_value[4] as f32 * THRESHOLD_PERCENT) as u32);
-                            info!("TouchPad7 threshold: {}", (lck.smooth_value[4] as f32 * THRESHOLD_PERCENT) as u32);
+                            esp_idf_sys::touch_pad_filter_read_smooth(esp_idf_sys::touch_pad_t_TOUCH_PAD_NUM5, &mut touch.smooth_value[4]);
+                            esp_idf_sys::touch_pad_set_thresh(esp_idf_sys::touch_pad_t_TOUCH_PAD_NUM5, (touch.smooth_value[4] as f32 * THRESHOLD_PERCENT) as u32);
+                            info!("TouchPad7 threshold: {}", (touch.smooth_value[4] as f32 * THRESHOLD_PERCENT) as u32);
                         },
                         _ => {},
                     }
                 }
             }
+            let mut baseline = TouchBaseline::new(&touch.smooth_value[..TOUCH_PAD_NUMS.len()], THRESHOLD_PERCENT, now_ms());
 
             loop {
-                thread::sleep(Duration::from_millis(100));
+                thread::sleep(Duration::from_millis(SCAN_PERIOD_MS));
+                for t in thresholds.try_iter() {
+                    let info = match t.key {
+                        Key::Up => &mut keys.up,
+                        Key::Down => &mut keys.down,
+                        Key::Left => &mut keys.left,
+                        Key::Right => &mut keys.right,
+                        Key::Center => &mut keys.center,
+                    };
+                    info.press_threshold = t.threshold;
+                    info.allow_repeat = t.allow_repeat;
+                }
                 // raw data from touch pad
                 // unsafe {
                     // let mut value = 0;
@@ -220,7 +277,7 @@ impl TouchPad {
                 // }
 
                 if TOUCH_ACTIVE_FLAG.load(Ordering::Relaxed) {
-                    let mut keylck = key_state.lock().unwrap();
+                    let touch_us = TOUCH_TIME_US.load(Ordering::Relaxed);
                     unsafe {
                         let touch_status = esp_idf_sys::touch_pad_get_status();
                         for i in 0..MAX_TOUCHPADS {
@@ -228,19 +285,19 @@ impl TouchPad {
                                 info!("TouchPad{} touched.", i);
                                 match i {
                                     UP_KEY => {
-                                        keylck.up.active = true;
+                                        keys.up.active = true;
                                     },
                                     DOWN_KEY => {
-                                        keylck.down.active = true;
+                                        keys.down.active = true;
                                     },
                                     LEFT_KEY => {
-                                        keylck.left.active = true;
+                                        keys.left.active = true;
                                     },
                                     RIGHT_KEY => {
-                                        keylck.right.active = true;
+                                        keys.right.active = true;
                                     },
                                     CENTER_KEY => {
-                                        keylck.center.active = true;
+                                        keys.center.active = true;
                                     },
                                     _ => {},
                                 }
@@ -248,19 +305,19 @@ impl TouchPad {
                             else {
                                 match i {
                                     UP_KEY => {
-                                        keylck.up.active = false;
+                                        keys.up.active = false;
                                     },
                                     DOWN_KEY => {
-                                        keylck.down.active = false;
+                                        keys.down.active = false;
                                     },
                                     LEFT_KEY => {
-                                        keylck.left.active = false;
+                                        keys.left.active = false;
                                     },
                                     RIGHT_KEY => {
-                                        keylck.right.active = false;
+                                        keys.right.active = false;
                                     },
                                     CENTER_KEY => {
-                                        keylck.center.active = false;
+                                        keys.center.active = false;
                                     },
                                     _ => {},
                                 }
@@ -270,267 +327,230 @@ impl TouchPad {
                     TOUCH_ACTIVE_FLAG.store(false, Ordering::Relaxed);
 
                     // check combination of touch pad
-                    if keylck.up.active && keylck.down.active {
-                        keylck.key_event.push(KeyEvent::UpDownKeyCombinationDown);
+                    if keys.up.active && keys.down.active {
+                        send_key(&events, KeyEvent::UpDownKeyCombinationDown, touch_us);
                         info!("UpDownKeyCombinationDown");
                     }
+                    else if keys.left.active && keys.right.active {
+                        send_key(&events, KeyEvent::LeftRightKeyCombinationDown, touch_us);
+                        info!("LeftRightKeyCombinationDown");
+                    }
+                    else if keys.center.active && (keys.up.active || keys.down.active) {
+                        // Center held as a modifier: no long press of Center in this press
+                        if ! keys.center.press {
+                            keys.center.press = true;
+                            keys.center.press_time = SystemTime::now();
+                        }
+                        keys.center.repeat_count = 1;
+                        // The key is taken as pressed, so it does not adjust when Center is released first
+                        if keys.up.active && ! keys.up.press {
+                            keys.up.press = true;
+                            keys.up.press_time = SystemTime::now();
+                            send_key(&events, KeyEvent::CenterUpKeyCombinationDown, touch_us);
+                            info!("CenterUpKeyCombinationDown");
+                        }
+                        if keys.down.active && ! keys.down.press {
+                            keys.down.press = true;
+                            keys.down.press_time = SystemTime::now();
+                            send_key(&events, KeyEvent::CenterDownKeyCombinationDown, touch_us);
+                            info!("CenterDownKeyCombinationDown");
+                        }
+                    }
                     else {
-                        if keylck.up.active {
-                            if ! keylck.up.press {
-                                keylck.up.press = true;
-                                keylck.up.press_time = SystemTime::now();
-                                keylck.up.press_duration = 0;
-                                keylck.up.release_duration = keylck.up.release_time.elapsed().unwrap().as_millis() as u32;
-                                keylck.key_event.push(KeyEvent::UpKeyDown);
+                        if keys.up.active {
+                            if ! keys.up.press {
+                                keys.up.press = true;
+                                keys.up.press_time = SystemTime::now();
+                                keys.up.press_duration = 0;
+                                keys.up.release_duration = keys.up.release_time.elapsed().unwrap().as_millis() as u32;
+                                send_key(&events, KeyEvent::UpKeyDown, touch_us);
                                 info!("UpKeyDown");
                             }
                         }
                         else {
-                            if keylck.up.press {
-                                keylck.up.press = false;
-                                keylck.up.press_duration = keylck.up.press_time.elapsed().unwrap().as_millis() as u32;
-                                keylck.up.release_time = SystemTime::now();
-                                keylck.up.release_duration = 0;
-                                keylck.up.repeat_count = 0;
-                                keylck.key_event.push(KeyEvent::UpKeyUp);
+                            if keys.up.press {
+                                keys.up.press = false;
+                                keys.up.press_duration = keys.up.press_time.elapsed().unwrap().as_millis() as u32;
+                                keys.up.release_time = SystemTime::now();
+                                keys.up.release_duration = 0;
+                                keys.up.repeat_count = 0;
+                                send_key(&events, KeyEvent::UpKeyUp, touch_us);
                                 info!("UpKeyUp");
                             }
                         }
-                        if keylck.down.active {
-                            if ! keylck.down.press {
-                                keylck.down.press = true;
-                                keylck.down.press_time = SystemTime::now();
-                                keylck.down.press_duration = 0;
-                                keylck.down.release_duration = keylck.down.release_time.elapsed().unwrap().as_millis() as u32;
-                                keylck.key_event.push(KeyEvent::DownKeyDown);
+                        if keys.down.active {
+                            if ! keys.down.press {
+                                keys.down.press = true;
+                                keys.down.press_time = SystemTime::now();
+                                keys.down.press_duration = 0;
+                                keys.down.release_duration = keys.down.release_time.elapsed().unwrap().as_millis() as u32;
+                                send_key(&events, KeyEvent::DownKeyDown, touch_us);
                                 info!("DownKeyDown");
                             }
                         }
                         else {
-                            if keylck.down.press {
-                                keylck.down.press = false;
-                                keylck.down.press_duration = keylck.down.press_time.elapsed().unwrap().as_millis() as u32;
-                                keylck.down.release_time = SystemTime::now();
-                                keylck.down.release_duration = 0;
-                                keylck.down.repeat_count = 0;
-                                keylck.key_event.push(KeyEvent::DownKeyUp);
+                            if keys.down.press {
+                                keys.down.press = false;
+                                keys.down.press_duration = keys.down.press_time.elapsed().unwrap().as_millis() as u32;
+                                keys.down.release_time = SystemTime::now();
+                                keys.down.release_duration = 0;
+                                keys.down.repeat_count = 0;
+                                send_key(&events, KeyEvent::DownKeyUp, touch_us);
                                 info!("DownKeyUp");
                             }
                         }
-                        if keylck.left.active {
-                            if ! keylck.left.press {
-                                keylck.left.press = true;
-                                keylck.left.press_time = SystemTime::now();
-                                keylck.left.press_duration = 0;
-                                keylck.left.release_duration = keylck.left.release_time.elapsed().unwrap().as_millis() as u32;
-                                keylck.key_event.push(KeyEvent::LeftKeyDown);
+                        if keys.left.active {
+                            if ! keys.left.press {
+                                keys.left.press = true;
+                                keys.left.press_time = SystemTime::now();
+                                keys.left.press_duration = 0;
+                                keys.left.release_duration = keys.left.release_time.elapsed().unwrap().as_millis() as u32;
+                                send_key(&events, KeyEvent::LeftKeyDown, touch_us);
                                 info!("LeftKeyDown");
                             }
                         }
                         else {
-                            if keylck.left.press {
-                                keylck.left.press = false;
-                                keylck.left.press_duration = keylck.left.press_time.elapsed().unwrap().as_millis() as u32;
-                                keylck.left.release_time = SystemTime::now();
-                                keylck.left.release_duration = 0;
-                                keylck.left.repeat_count = 0;
-                                keylck.key_event.push(KeyEvent::LeftKeyUp);
+                            if keys.left.press {
+                                keys.left.press = false;
+                                keys.left.press_duration = keys.left.press_time.elapsed().unwrap().as_millis() as u32;
+                                keys.left.release_time = SystemTime::now();
+                                keys.left.release_duration = 0;
+                                keys.left.repeat_count = 0;
+                                send_key(&events, KeyEvent::LeftKeyUp, touch_us);
                                 info!("LeftUpKeyUp");
                             }
                         }
-                        if keylck.right.active {
-                            if ! keylck.right.press {
-                                keylck.right.press = true;
-                                keylck.right.press_time = SystemTime::now();
-                                keylck.right.press_duration = 0;
-                                keylck.right.release_duration = keylck.right.release_time.elapsed().unwrap().as_millis() as u32;
-                                keylck.key_event.push(KeyEvent::RightKeyDown);
+                        if keys.right.active {
+                            if ! keys.right.press {
+                                keys.right.press = true;
+                                keys.right.press_time = SystemTime::now();
+                                keys.right.press_duration = 0;
+                                keys.right.release_duration = keys.right.release_time.elapsed().unwrap().as_millis() as u32;
+                                send_key(&events, KeyEvent::RightKeyDown, touch_us);
                                 info!("RightKeyDown");
                             }
                         }
                         else {
-                            if keylck.right.press {
-                                keylck.right.press = false;
-                                keylck.right.press_duration = keylck.right.press_time.elapsed().unwrap().as_millis() as u32;
-                                keylck.right.release_time = SystemTime::now();
-                                keylck.right.release_duration = 0;
-                                keylck.right.repeat_count = 0;
-                                keylck.key_event.push(KeyEvent::RightKeyUp);
+                            if keys.right.press {
+                                keys.right.press = false;
+                                keys.right.press_duration = keys.right.press_time.elapsed().unwrap().as_millis() as u32;
+                                keys.right.release_time = SystemTime::now();
+                                keys.right.release_duration = 0;
+                                keys.right.repeat_count = 0;
+                                send_key(&events, KeyEvent::RightKeyUp, touch_us);
                                 info!("RightKeyUp");
                             }
                         }
-                        if keylck.center.active {
-                            if ! keylck.center.press {
-                                keylck.center.press = true;
-                                keylck.center.press_time = SystemTime::now();
-                                keylck.center.press_duration = 0;
-                                keylck.center.release_duration = keylck.center.release_time.elapsed().unwrap().as_millis() as u32;
-                                keylck.key_event.push(KeyEvent::CenterKeyDown);
+                        if keys.center.active {
+                            if ! keys.center.press {
+                                keys.center.press = true;
+                                keys.center.press_time = SystemTime::now();
+                                keys.center.press_duration = 0;
+                                keys.center.release_duration = keys.center.release_time.elapsed().unwrap().as_millis() as u32;
+                                send_key(&events, KeyEvent::CenterKeyDown, touch_us);
                                 info!("CenterKeyDown");
                             }
                         }
                         else {
-                            if keylck.center.press {
-                                keylck.center.press = false;
-                                keylck.center.press_duration = keylck.center.press_time.elapsed().unwrap().as_millis() as u32;
-                                keylck.center.release_time = SystemTime::now();
-                                keylck.center.release_duration = 0;
-                                keylck.center.repeat_count = 0;
-                                keylck.key_event.push(KeyEvent::CenterKeyUp);
+                            if keys.center.press {
+                                keys.center.press = false;
+                                keys.center.press_duration = keys.center.press_time.elapsed().unwrap().as_millis() as u32;
+                                keys.center.release_time = SystemTime::now();
+                                keys.center.release_duration = 0;
+                                keys.center.repeat_count = 0;
+                                send_key(&events, KeyEvent::CenterKeyUp, touch_us);
                                 info!("CenterKeyUp");
                             }
                         }
                     }
                 }
+                // The smooth values drift as the enclosure warms up: read them again while no key
+                // is pressed and derive the threshold again of a pad which drifted
+                let now = now_ms();
+                if keys.up.active || keys.down.active || keys.left.active || keys.right.active || keys.center.active {
+                    baseline.touched(now);
+                }
+                else if baseline.is_due(now) {
+                    for (i, pad) in TOUCH_PAD_NUMS.iter().enumerate() {
+                        unsafe {
+                            esp_idf_sys::touch_pad_filter_read_smooth(*pad, &mut touch.smooth_value[i]);
+                        }
+                    }
+                    for r in baseline.update(now, &touch.smooth_value[..TOUCH_PAD_NUMS.len()]) {
+                        unsafe {
+                            esp_idf_sys::touch_pad_set_thresh(TOUCH_PAD_NUMS[r.pad], r.threshold);
+                        }
+                        info!("TouchPad{} re-baselined: smooth {} -> {}, threshold {} ({} since boot)", r.pad + 1, r.from, r.to, r.threshold, baseline.count());
+                    }
+                }
+
                 // check press time and generate long press event
-                let mut keylck = key_state.lock().unwrap();
-                if keylck.up.press_threshold > 0 {
-                    if keylck.up.press &&
-                        (keylck.up.repeat_count == 0 || (keylck.up.allow_repeat && keylck.up.repeat_count > 0)) {                        
-                        let duration = keylck.up.press_time.elapsed().unwrap().as_millis() as u32;
-                        if duration > keylck.up.press_threshold {
-                            keylck.key_event.push(KeyEvent::UpKeyDownLong);
-                            keylck.up.press_time = SystemTime::now();
-                            keylck.up.repeat_count += 1;
+                if keys.up.press_threshold > 0 {
+                    if keys.up.press && ! keys.center.press &&
+                        (keys.up.repeat_count == 0 || (keys.up.allow_repeat && keys.up.repeat_count > 0)) {                        
+                        let duration = keys.up.press_time.elapsed().unwrap().as_millis() as u32;
+                        if duration > keys.up.press_threshold {
+                            send_key(&events, KeyEvent::UpKeyDownLong, now_us());
+                            keys.up.press_time = SystemTime::now();
+                            keys.up.repeat_count += 1;
                             info!("UpKeyDownLong");
                         }
                     }
                 }
-                if keylck.down.press_threshold > 0 {
-                    if keylck.down.press &&
-                        (keylck.down.repeat_count == 0 || (keylck.down.allow_repeat && keylck.down.repeat_count > 0)) {
-                        let duration = keylck.down.press_time.elapsed().unwrap().as_millis() as u32;
-                        if duration > keylck.down.press_threshold {
-                            keylck.key_event.push(KeyEvent::DownKeyDownLong);
-                            keylck.down.press_time = SystemTime::now();
-                            keylck.down.repeat_count += 1;
+                if keys.down.press_threshold > 0 {
+                    if keys.down.press && ! keys.center.press &&
+                        (keys.down.repeat_count == 0 || (keys.down.allow_repeat && keys.down.repeat_count > 0)) {
+                        let duration = keys.down.press_time.elapsed().unwrap().as_millis() as u32;
+                        if duration > keys.down.press_threshold {
+                            send_key(&events, KeyEvent::DownKeyDownLong, now_us());
+                            keys.down.press_time = SystemTime::now();
+                            keys.down.repeat_count += 1;
                             info!("DownKeyDownLong");
                         }
                     }
                 }
-                if keylck.left.press_threshold > 0 {
-                    if keylck.left.press &&
-                        (keylck.left.repeat_count == 0 || (keylck.left.allow_repeat && keylck.left.repeat_count > 0)) {
-                        let duration = keylck.left.press_time.elapsed().unwrap().as_millis() as u32;
-                        if duration > keylck.left.press_threshold {
-                            keylck.key_event.push(KeyEvent::LeftKeyDownLong);
-                            keylck.left.press_time = SystemTime::now();
-                            keylck.left.repeat_count += 1;
+                if keys.left.press_threshold > 0 {
+                    if keys.left.press &&
+                        (keys.left.repeat_count == 0 || (keys.left.allow_repeat && keys.left.repeat_count > 0)) {
+                        let duration = keys.left.press_time.elapsed().unwrap().as_millis() as u32;
+                        if duration > keys.left.press_threshold {
+                            send_key(&events, KeyEvent::LeftKeyDownLong, now_us());
+                            keys.left.press_time = SystemTime::now();
+                            keys.left.repeat_count += 1;
                             info!("LeftKeyDownLong");
                         }
                     }
                 }
-                if keylck.right.press_threshold > 0 {
-                    if keylck.right.press &&
-                        (keylck.right.repeat_count == 0 || (keylck.right.allow_repeat && keylck.right.repeat_count > 0)) {
-                        let duration = keylck.right.press_time.elapsed().unwrap().as_millis() as u32;
-                        if duration > keylck.right.press_threshold {
-                            keylck.key_event.push(KeyEvent::RightKeyDownLong);
-                            keylck.right.press_time = SystemTime::now();
-                            keylck.right.repeat_count += 1;
+                if keys.right.press_threshold > 0 {
+                    if keys.right.press &&
+                        (keys.right.repeat_count == 0 || (keys.right.allow_repeat && keys.right.repeat_count > 0)) {
+                        let duration = keys.right.press_time.elapsed().unwrap().as_millis() as u32;
+                        if duration > keys.right.press_threshold {
+                            send_key(&events, KeyEvent::RightKeyDownLong, now_us());
+                            keys.right.press_time = SystemTime::now();
+                            keys.right.repeat_count += 1;
                             info!("RightKeyDownLong");
                         }
                     }
                 }
-                if keylck.center.press_threshold > 0 {
-                    if keylck.center.press &&
-                        (keylck.center.repeat_count == 0 || (keylck.center.allow_repeat && keylck.center.repeat_count > 0)) {
-                        let duration = keylck.center.press_time.elapsed().unwrap().as_millis() as u32;
-                        if duration > keylck.center.press_threshold {
-                            keylck.key_event.push(KeyEvent::CenterKeyDownLong);
-                            keylck.center.press_time = SystemTime::now();
-                            keylck.center.repeat_count += 1;
+                if keys.center.press_threshold > 0 {
+                    if keys.center.press &&
+                        (keys.center.repeat_count == 0 || (keys.center.allow_repeat && keys.center.repeat_count > 0)) {
+                        let duration = keys.center.press_time.elapsed().unwrap().as_millis() as u32;
+                        if duration > keys.center.press_threshold {
+                            send_key(&events, KeyEvent::CenterKeyDownLong, now_us());
+                            keys.center.press_time = SystemTime::now();
+                            keys.center.repeat_count += 1;
                             info!("CenterKeyDownLong");
                         }
                     }
                 }
-                drop(keylck);
             }
         });
     }
 
-    pub fn get_touchpad_status(&mut self, key: Key) -> bool
-    {
-        let lck = self.key_state.lock().unwrap();
-        match key {
-            Key::Up => {
-                lck.up.press
-            },
-            Key::Down => {
-                lck.down.press
-            },
-            Key::Left => {
-                lck.left.press
-            },
-            Key::Right => {
-                lck.right.press
-            },
-            Key::Center => {
-                lck.center.press
-            },
-        }
-    }
-    
-    pub fn get_button_press_time(&mut self, key: Key) -> u32
-    {
-        let lck = self.key_state.lock().unwrap();
-        match key {
-            Key::Up => {
-                lck.up.press_duration
-            },
-            Key::Down => {
-                lck.down.press_duration
-            },
-            Key::Left => {
-                lck.left.press_duration
-            },
-            Key::Right => {
-                lck.right.press_duration
-            },
-            Key::Center => {
-                lck.center.press_duration
-            },
-        }
-    }
-
-    pub fn clear_all_button_event(&mut self)
-    {
-        let mut lck = self.key_state.lock().unwrap();
-        lck.key_event.clear();
-    }
-
-    pub fn get_key_event_and_clear(&mut self) -> Vec<KeyEvent>
-    {
-        let mut lck = self.key_state.lock().unwrap();
-        let ret = lck.key_event.clone();
-        lck.key_event.clear();
-        ret
-    }
-
     pub fn set_press_threshold(&mut self, key: Key, threshold: u32, allow_repeat: bool)
     {
-        let mut lck = self.key_state.lock().unwrap();
-        match key {
-            Key::Up => {
-                lck.up.press_threshold = threshold;
-                lck.up.allow_repeat = allow_repeat;
-            },
-            Key::Down => {
-                lck.down.press_threshold = threshold;
-                lck.down.allow_repeat = allow_repeat;
-            },
-            Key::Left => {
-                lck.left.press_threshold = threshold;
-                lck.left.allow_repeat = allow_repeat;
-            },
-            Key::Right => {
-                lck.right.press_threshold = threshold;
-                lck.right.allow_repeat = allow_repeat;
-            },
-            Key::Center => {
-                lck.center.press_threshold = threshold;
-                lck.center.allow_repeat = allow_repeat;
-            },
-        }
+        let _ = self.threshold_tx.send(PressThreshold { key: key, threshold: threshold, allow_repeat: allow_repeat });
     }
-}
\ No newline at end of file
+}
@@ -1,22 +1,75 @@
 // Transfer data to the InfluxDB server
+// A batch of logs stays in the buffer until the server acknowledges it (HTTP 204), and is
+// sent again after a failure. It is dropped (and counted as lost) after MAX_BATCH_ATTEMPTS.
 // SPDX-License-Identifier: MIT
 // Copyright (c) 2024 Hiroshi Nakajima
 
 use log::*;
-use std::{thread, sync::Arc, sync::Mutex};
-use esp_idf_hal::task;
-use std::io::Error;
+use std::collections::VecDeque;
+use std::thread;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender, RecvTimeoutError};
 use std::time::Duration;
+use std::time::SystemTime;
 use embedded_svc::http::client::Client;
 use embedded_svc::http::Method;
 use esp_idf_svc::http::client::{EspHttpConnection, Configuration};
 
-use anyhow::Result;
+use crate::error::{Error, Result};
 use crate::CurrentLog;
+use dcpower_control::capture::CaptureWindow;
+use dcpower_control::summary::LogSummary;
+use dcpower_control::session::RunLabel;
+use dcpower_control::pidtrace::PidPoint;
+use dcpower_control::perfstats::PerfRates;
+use crate::version;
+use crate::timebase;
 
-struct TransferData {
-    body: String,
-    txreq: bool,
+const MAX_PENDING_EVENTS: usize = 64;
+const MAX_BATCH_ATTEMPTS: u32 = 3;
+// Points of a capture per event message
+const CAPTURE_CHUNK_RECORDS: usize = 256;
+
+// Counters since boot: batches sent again, records dropped by the firmware
+static RESENT_COUNT: AtomicU32 = AtomicU32::new(0);
+static LOST_COUNT: AtomicU32 = AtomicU32::new(0);
+// Kept by the transfer thread: events waiting to be sent, and the time of the last POST
+// stored by the server (seconds since boot + 1, 0 before the first one)
+static PENDING_EVENTS: AtomicU32 = AtomicU32::new(0);
+static LAST_UPLOAD_SECS: AtomicU32 = AtomicU32::new(0);
+
+pub fn resent_count() -> u32 {
+    RESENT_COUNT.load(Ordering::Relaxed)
+}
+
+pub fn lost_count() -> u32 {
+    LOST_COUNT.load(Ordering::Relaxed)
+}
+
+// Records removed from the log buffer without being stored
+pub fn count_lost(records: usize) {
+    LOST_COUNT.fetch_add(records as u32, Ordering::Relaxed);
+}
+
+// Messages to the transfer thread
+enum TransferMessage {
+    // Line protocol and the sequence of the last record
+    Logs(String, u64),
+    Event(String),
+}
+
+// Sent back by the transfer thread when it can take the next batch
+enum BatchResult {
+    Ready,
+    Stored(u64),
+    Failed(u64),
+}
+
+// Batch result for the main loop: remove (Stored) or drop (Dropped) the records up to the sequence
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransferAck {
+    Stored(u64),
+    Dropped(u64),
 }
 
 #[derive(Clone)]
@@ -40,60 +93,123 @@ impl ServerInfo {
     }
 }
 
+// The transfer thread sends a ready token (with the result of the last batch) when it can
+// take the next chunk of logs, so the main loop never formats logs that cannot be sent yet.
 pub struct Transfer {
-    data: Arc<Mutex<TransferData>>,
+    tx: Sender<TransferMessage>,
+    ready: Receiver<BatchResult>,
+    thread_channels: Option<(Receiver<TransferMessage>, Sender<BatchResult>)>,
     server: ServerInfo,
+    // Firmware version tag of every point
+    fw_tag: String,
+    // dut, note and run tags of every point, "" without a run label or a run ID
+    run_tags: String,
+    label: RunLabel,
+    // Run ID of the last sync start, "" without one
+    run_id: String,
+    idle: bool,
+    // Failed attempts of the batch at the head of the buffer
+    attempts: u32,
 }
 
 impl Transfer {
     pub fn new(server: ServerInfo) -> Self {
-        Transfer { data: Arc::new(Mutex::new(
-            TransferData { body: "".to_string(), txreq: false })),
-            server: server}
+        let (tx, rx) = channel();
+        let (ready_tx, ready) = channel();
+        Transfer {
+            tx: tx,
+            ready: ready,
+            thread_channels: Some((rx, ready_tx)),
+            server: server,
+            fw_tag: version::version_tag(),
+            run_tags: String::new(),
+            label: RunLabel::default(),
+            run_id: String::new(),
+            idle: false,
+            attempts: 0,
+        }
     }
 
-    pub fn start(&mut self) -> Result<(), Error>
+    pub fn start(&mut self) -> Result<()>
     {
-        let data = self.data.clone();
+        let (rx, ready_tx) = self.thread_channels.take()
+            .ok_or(Error::Network("transfer already started".to_string()))?;
         let server_info = self.server.clone();
-        let _th = thread::spawn(move || -> anyhow::Result<()> {
+        let _th = thread::spawn(move || -> Result<()> {
             info!("Start transfer thread.");    
+            crate::health::register_task("transfer");
 
+            let mut events : Vec<String> = Vec::new();
+            let _ = ready_tx.send(BatchResult::Ready);
             loop {
-                task::wait_notification(100);
+                let mut body = None;
+                match rx.recv_timeout(Duration::from_millis(100)) {
+                    Ok(msg) => Self::queue(msg, &mut body, &mut events),
+                    Err(RecvTimeoutError::Timeout) => {},
+                    Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                }
+                for msg in rx.try_iter() {
+                    Self::queue(msg, &mut body, &mut events);
+                }
+                if body.is_none() && events.is_empty() {
+                    continue;
+                }
                 let http = EspHttpConnection::new(
                     &Configuration {
                         use_global_ca_store: true,
                         crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
                         timeout: Some(Duration::from_secs(10 as u64)),
                         ..Default::default()
-                    })?;
+                    }).map_err(|e| Error::Network(format!("{:?}", e)))?;
     
                 let mut client = Client::wrap(http);
     
-                let mut lck = data.lock().unwrap();
-                if lck.txreq == false {
-                    drop(lck);
-                    continue;
+                let last_sequence = body.as_ref().map(|(_, sequence)| *sequence);
+                let mut request = body.map(|(logs, _)| logs).unwrap_or_default();
+                let event_count = events.len();
+                for ev in &events {
+                    request.push_str(ev);
                 }
-                let request = format!("{}", lck.body);
-                drop(lck);
                 // info!("Transfer data: {}", request);                
-                let ret = Self::transfer(&mut client, &server_info, request);
-                lck = data.lock().unwrap();
-                match ret {
-                    Ok(()) => { lck.txreq = false; },
-                    Err(e) => { info!("{}", e) },
+                let stored = match Self::transfer(&mut client, &server_info, request) {
+                    Ok(()) => {
+                        events.drain(0..event_count);
+                        PENDING_EVENTS.store(events.len() as u32, Ordering::Relaxed);
+                        LAST_UPLOAD_SECS.store((timebase::monotonic_ns() / 1_000_000_000) as u32 + 1, Ordering::Relaxed);
+                        true
+                    },
+                    Err(e) => {
+                        info!("{}", e);
+                        false
+                    },
+                };
+                // The main loop keeps the logs until they are stored, events are kept here
+                if let Some(sequence) = last_sequence {
+                    let _ = ready_tx.send(if stored { BatchResult::Stored(sequence) } else { BatchResult::Failed(sequence) });
                 }
-                lck.body.clear();
-                drop(lck);
             }
         });
 
         Ok(())
     }
 
-    fn transfer(client: &mut Client<EspHttpConnection>, server_info: &ServerInfo, body_data: String) -> anyhow::Result<()>
+    fn queue(msg: TransferMessage, body: &mut Option<(String, u64)>, events: &mut Vec<String>)
+    {
+        match msg {
+            TransferMessage::Logs(logs, sequence) => {
+                *body = Some((logs, sequence));
+            },
+            TransferMessage::Event(event) => {
+                if events.len() >= MAX_PENDING_EVENTS {
+                    events.remove(0);
+                }
+                events.push(event);
+                PENDING_EVENTS.store(events.len() as u32, Ordering::Relaxed);
+            },
+        }
+    }
+
+    fn transfer(client: &mut Client<EspHttpConnection>, server_info: &ServerInfo, body_data: String) -> Result<()>
     {
         let authorization = &format!("Token {}", server_info.influxdb_api_key);
         let headers : [(&str, &str); 2] = [
@@ -104,10 +220,10 @@ impl Transfer {
         // info!("URL: {}", url);
         let mut request = client.request(Method::Post, 
                url.as_str(),
-                &headers)?;
+                &headers).map_err(|e| Error::Network(format!("{:?}", e)))?;
         let body = body_data.as_bytes();
-        request.write(body)?;
-        let mut response = request.submit()?;
+        request.write(body).map_err(|e| Error::Network(format!("{:?}", e)))?;
+        let mut response = request.submit().map_err(|e| Error::Network(format!("{:?}", e)))?;
         let res_status = response.status();
         // info!("Response status: {:?}", res_status);
         match res_status {
@@ -116,47 +232,251 @@ impl Transfer {
             },
             _ => {
                 let mut response_buf = [0u8; 4096];
-                response.read(&mut response_buf)?;
+                response.read(&mut response_buf).map_err(|e| Error::Network(format!("{:?}", e)))?;
                 let res_str = std::str::from_utf8(&response_buf).unwrap_or("<invalid UTF-8>");        
                 info!("Response: {}", res_str);
-                return Err(anyhow::anyhow!("Failed to transfer data."));
+                return Err(Error::Network(format!("Failed to transfer data. (HTTP {})", res_status)));
+            }
+        }
+    }
+
+
+    // Queue an event point (fields in line protocol) to be sent with the next transfer
+    pub fn push_event(&mut self, event: &str, fields: &str)
+    {
+        let now = SystemTime::now();
+        let clock = now.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+        let _ = self.tx.send(TransferMessage::Event(format!("{}_event,tag={},fw={}{},event={} {} {}\n",
+            self.server.influxdb_measurement,
+            self.server.influxdb_tag,
+            self.fw_tag,
+            self.run_tags,
+            event,
+            fields,
+            clock)));
+    }
+
+    // Queue the points of a capture (measurement <measurement>_capture, tagged with the
+    // capture id). offset is the record index from the trigger.
+    pub fn push_capture(&mut self, id: u128, window: &CaptureWindow)
+    {
+        let wall_offset = timebase::wall_offset_ns();
+        for (chunk_index, chunk) in window.samples.chunks(CAPTURE_CHUNK_RECORDS).enumerate() {
+            let mut body = String::new();
+            for (i, it) in chunk.iter().enumerate() {
+                let offset = (chunk_index * CAPTURE_CHUNK_RECORDS + i) as i64 - window.trigger_index as i64;
+                body.push_str(&format!("{}_capture,tag={},fw={}{},channel={},capture={} current={:.5},voltage={:.5},power={:.5},pwm={},offset={}i {}\n",
+                    self.server.influxdb_measurement,
+                    self.server.influxdb_tag,
+                    self.fw_tag,
+                    self.run_tags,
+                    it.channel,
+                    id,
+                    it.current,
+                    it.voltage,
+                    it.power,
+                    it.pwm,
+                    offset,
+                    it.clock + wall_offset));
+            }
+            let _ = self.tx.send(TransferMessage::Event(body));
+        }
+    }
+
+    // Queue the PID trace points of a channel (measurement <measurement>_pid)
+    pub fn push_pid_trace(&mut self, channel: usize, points: &[PidPoint])
+    {
+        for chunk in points.chunks(CAPTURE_CHUNK_RECORDS) {
+            let mut body = String::new();
+            for p in chunk {
+                body.push_str(&format!("{}_pid,tag={},fw={}{},channel={} setpoint={:.5},voltage={:.5},error={:.5},p={:.6},i={:.6},d={:.6},output={:.6},duty={}i {}\n",
+                    self.server.influxdb_measurement,
+                    self.server.influxdb_tag,
+                    self.fw_tag,
+                    self.run_tags,
+                    channel,
+                    p.setpoint,
+                    p.voltage,
+                    p.terms.error,
+                    p.terms.proportional,
+                    p.terms.integral,
+                    p.terms.derivative,
+                    p.terms.output,
+                    p.duty,
+                    p.clock));
             }
+            let _ = self.tx.send(TransferMessage::Event(body));
         }
     }
 
+    // Queue the loop performance rates (measurement <measurement>_perf), tagged with the firmware
+    pub fn push_perf(&mut self, r: &PerfRates)
+    {
+        let now = SystemTime::now();
+        let clock = now.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_nanos();
+        let _ = self.tx.send(TransferMessage::Event(format!("{}_perf,tag={},fw={}{} control_cycles={:.1},overruns={:.2},i2c_transfers={:.1},i2c_busy_us={:.0},i2c_transfer_us={:.1},pwm_updates={:.1},display_frames={:.2},main_loops={:.2} {}\n",
+            self.server.influxdb_measurement,
+            self.server.influxdb_tag,
+            self.fw_tag,
+            self.run_tags,
+            r.control_cycles, r.overruns, r.i2c_transfers, r.i2c_busy_us, r.i2c_transfer_us,
+            r.pwm_updates, r.display_frames, r.main_loops,
+            clock)));
+    }
+
+    // Queue a summary record (measurement <measurement>_summary) at the time of its last record
+    pub fn push_summary(&mut self, s: &LogSummary)
+    {
+        let _ = self.tx.send(TransferMessage::Event(format!("{}_summary,tag={},fw={}{},channel={} voltage={:.5},voltage_min={:.5},voltage_max={:.5},current={:.5},current_min={:.5},current_max={:.5},power={:.5},power_min={:.5},power_max={:.5},temp={:.1},temp_max={:.1},samples={}i {}\n",
+            self.server.influxdb_measurement,
+            self.server.influxdb_tag,
+            self.fw_tag,
+            self.run_tags,
+            s.channel,
+            s.voltage.mean, s.voltage.min, s.voltage.max,
+            s.current.mean, s.current.min, s.current.max,
+            s.power.mean, s.power.min, s.power.max,
+            s.temp.mean, s.temp.max,
+            s.samples,
+            timebase::to_wall_ns(s.end_clock))));
+    }
+
+    // Tag the points formatted from now on with the run label
+    pub fn set_run_label(&mut self, label: &RunLabel)
+    {
+        self.label = label.clone();
+        self.update_run_tags();
+    }
+
+    // Tag the points formatted from now on with the run ID shared by the sync group
+    pub fn set_run_id(&mut self, run_id: &str)
+    {
+        self.run_id = run_id.to_string();
+        self.update_run_tags();
+    }
 
-    pub fn set_transfer_data(&mut self, data: &Vec<CurrentLog>) -> usize
+    fn update_run_tags(&mut self)
+    {
+        self.run_tags = String::new();
+        if !self.label.dut.is_empty() {
+            self.run_tags.push_str(&format!(",dut={}", Transfer::escape_tag(&self.label.dut)));
+        }
+        if !self.label.note.is_empty() {
+            self.run_tags.push_str(&format!(",note={}", Transfer::escape_tag(&self.label.note)));
+        }
+        if !self.run_id.is_empty() {
+            self.run_tags.push_str(&format!(",run={}", Transfer::escape_tag(&self.run_id)));
+        }
+    }
+
+    // Escape a tag value of the line protocol
+    pub fn escape_tag(value: &str) -> String {
+        value.replace('\\', "\\\\").replace(',', "\\,").replace('=', "\\=").replace(' ', "\\ ")
+    }
+
+    // Escape a string field value of the line protocol
+    pub fn escape_string_field(value: &str) -> String {
+        value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+    }
+
+    // Events queued in the transfer thread, not stored by the server yet
+    pub fn pending_events(&self) -> u32 {
+        PENDING_EVENTS.load(Ordering::Relaxed)
+    }
+
+    // Points not stored by the server yet: the records in the log buffer and the events
+    pub fn unsent_points(&self, buffered_records: usize) -> usize {
+        buffered_records + self.pending_events() as usize
+    }
+
+    // Seconds since the server last stored a POST, None before the first one
+    pub fn last_upload_age_secs(&self) -> Option<u32> {
+        match LAST_UPLOAD_SECS.load(Ordering::Relaxed) {
+            0 => None,
+            secs => Some(((timebase::monotonic_ns() / 1_000_000_000) as u32 + 1).saturating_sub(secs)),
+        }
+    }
+
+    // Result of the last batch, call before set_transfer_data
+    pub fn poll_ack(&mut self) -> Option<TransferAck>
+    {
+        match self.ready.try_recv() {
+            Ok(BatchResult::Ready) => {
+                self.idle = true;
+                None
+            },
+            Ok(BatchResult::Stored(sequence)) => {
+                self.idle = true;
+                self.attempts = 0;
+                Some(TransferAck::Stored(sequence))
+            },
+            Ok(BatchResult::Failed(sequence)) => {
+                self.idle = true;
+                self.attempts += 1;
+                if self.attempts >= MAX_BATCH_ATTEMPTS {
+                    self.attempts = 0;
+                    return Some(TransferAck::Dropped(sequence));
+                }
+                RESENT_COUNT.fetch_add(1, Ordering::Relaxed);
+                None
+            },
+            Err(_) => None,
+        }
+    }
+
+    // Send a batch from the head of the buffer; it stays there until poll_ack
+    pub fn set_transfer_data(&mut self, data: &VecDeque<CurrentLog>) -> usize
     {
         if data.len() == 0 {
             return 0;
         }
-        let mut lck = self.data.lock().unwrap();
-        if lck.txreq == true {
+        if !self.idle {
             // info!("Transfer request is already pending.");
             return 0;
         }
+        self.idle = false;
+        // The records are stamped with the monotonic clock
+        let offset = timebase::wall_offset_ns();
+        let mut body = String::new();
         let mut count = 0;
+        let mut last_sequence = 0;
         for it in data {
-            lck.body.push_str(
-                &format!("{},tag={} current={:.5},voltage={:.5},power={:.5},bat={:.2},temp={:.1},rpm={},pwm={} {}\n",
+            // Each temperature source with a reading
+            let mut temps = String::new();
+            for (name, temp) in ["temp_sensor", "temp_ina228", "temp_ap33772s"].iter().zip(it.get_source_temps()) {
+                if let Some(t) = temp {
+                    temps.push_str(&format!(",{}={:.1}", name, t));
+                }
+            }
+            body.push_str(
+                &format!("{},tag={},fw={}{},channel={} current={:.5},voltage={:.5},power={:.5},bat={:.2},temp={:.1}{},pwm={},pd_voltage={:.3},pd_current={:.3},mode=\"{}\",reverse={},seq={}i {}\n",
                     self.server.influxdb_measurement,
                     self.server.influxdb_tag,
+                    self.fw_tag,
+                    self.run_tags,
+                    it.channel,
                     it.current,
                     it.voltage,
                     it.power,
                     it.battery,
                     it.temp,
-                    it.rpm,
+                    temps,
                     it.pwm,
-                    it.clock,
+                    it.pd_voltage_mv as f32 / 1000.0,
+                    it.pd_current_ma as f32 / 1000.0,
+                    it.mode.as_str(),
+                    it.is_reverse(),
+                    it.sequence,
+                    it.clock + offset,
             ));
+            last_sequence = it.sequence;
             count += 1;
             if count == 128 {
                 info!("Chunk data");
                 break;
             }
         }
-        lck.txreq = true;
+        let _ = self.tx.send(TransferMessage::Logs(body, last_sequence));
         count as usize
     }
 }
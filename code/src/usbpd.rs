@@ -15,6 +15,7 @@ use esp_idf_hal::delay::BLOCK;
 use embedded_hal::i2c::{I2c, ErrorType};
 use embedded_hal::delay::DelayNs;
 use esp_idf_sys::EspError;
+use crate::error::{Error, Result};
 
 // Import the driver and types from the ap33772s-driver crate (note: hyphens become underscores)
 use ap33772s_driver::AP33772S as GenericAP33772S;
@@ -104,7 +105,7 @@ impl AP33772S {
     }
 
     /// Initialize the AP33772S controller
-    pub fn init(&mut self, i2cdrv: &mut i2c::I2cDriver) -> anyhow::Result<()> {
+    pub fn init(&mut self, i2cdrv: &mut i2c::I2cDriver) -> Result<()> {
         info!("Initializing AP33772S...");
         
         // Create wrapper for the I2C driver
@@ -133,13 +134,13 @@ impl AP33772S {
             },
             Err(e) => {
                 error!("Failed to initialize AP33772S: {:?}", e);
-                Err(anyhow::anyhow!("AP33772S initialization failed"))
+                Err(Error::I2c(format!("AP33772S initialization failed: {:?}", e)))
             }
         }
     }
     
     /// Perform a hard reset of the PD connection
-    pub fn hard_reset(&self, i2cdrv: &mut i2c::I2cDriver) -> anyhow::Result<()> {
+    pub fn hard_reset(&self, i2cdrv: &mut i2c::I2cDriver) -> Result<()> {
         info!("Performing hard reset on AP33772S...");
         let mut i2c_wrapper = I2cWrapper::new(i2cdrv);
         
@@ -152,13 +153,13 @@ impl AP33772S {
             },
             Err(e) => {
                 error!("Hard reset failed: {:?}", e);
-                Err(anyhow::anyhow!("Hard reset failed"))
+                Err(Error::PdNegotiation(format!("Hard reset failed: {:?}", e)))
             }
         }
     }
 
     /// Request specific voltage from the USB PD source using predefined PDO index
-    pub fn request_voltage(&self, i2cdrv: &mut i2c::I2cDriver, voltage: PDVoltage) -> anyhow::Result<()> {
+    pub fn request_voltage(&self, i2cdrv: &mut i2c::I2cDriver, voltage: PDVoltage) -> Result<()> {
         info!("Requesting voltage: {:?}", voltage);
         let mut i2c_wrapper = I2cWrapper::new(i2cdrv);
         let mut delay = StdDelay;
@@ -170,14 +171,14 @@ impl AP33772S {
             },
             Err(e) => {
                 error!("Voltage request failed: {:?}", e);
-                Err(anyhow::anyhow!("Voltage request failed"))
+                Err(Error::PdNegotiation(format!("Voltage request failed: {:?}", e)))
             }
         }
     }
 
     /// Request custom voltage and current from the USB PD source
     /// This maps to the nearest standard PDVoltage since the generic driver doesn't support arbitrary voltages
-    pub fn request_custom_voltage(&self, i2cdrv: &mut i2c::I2cDriver, voltage_mv: u16, _current_ma: u16) -> anyhow::Result<()> {
+    pub fn request_custom_voltage(&self, i2cdrv: &mut i2c::I2cDriver, voltage_mv: u16, _current_ma: u16) -> Result<()> {
         info!("Requesting custom voltage: {}mV (mapping to nearest standard voltage)", voltage_mv);
         
         // First, check available PDOs to see if the requested voltage is actually available
@@ -265,75 +266,80 @@ impl AP33772S {
                     },
                     Err(e) => {
                         error!("Custom voltage request failed: {:?}", e);
-                        Err(anyhow::anyhow!("Custom voltage request failed"))
+                        Err(Error::PdNegotiation(format!("Custom voltage request failed: {:?}", e)))
                     }
                 }
             } else {
                 // For Fixed PDO, map to nearest standard PDVoltage
                 info!("Using Fixed PDO - mapping to standard voltage");
-                let pd_voltage = if best_pdo.voltage_mv <= 6500 {
-                    PDVoltage::V5
-                } else if best_pdo.voltage_mv <= 10500 {
-                    PDVoltage::V9
-                } else if best_pdo.voltage_mv <= 13500 {
-                    PDVoltage::V12
-                } else if best_pdo.voltage_mv <= 17500 {
-                    PDVoltage::V15
-                } else if best_pdo.voltage_mv <= 24000 {
-                    PDVoltage::V20
-                } else {
-                    PDVoltage::V28
-                };
-                
-                info!("Mapped {}mV to {:?}", best_pdo.voltage_mv, pd_voltage);
-                self.request_voltage(i2cdrv, pd_voltage)
+                self.request_fixed_voltage(i2cdrv, best_pdo.voltage_mv)
             }
         } else {
             error!("No suitable PDO found for voltage {}mV", voltage_mv);
-            Err(anyhow::anyhow!("No suitable PDO found for requested voltage"))
+            Err(Error::PdNegotiation(format!("No suitable PDO found for {}mV", voltage_mv)))
         }
     }
 
+    /// Request the fixed PDO of the voltage, mapped to the nearest standard PDVoltage
+    pub fn request_fixed_voltage(&self, i2cdrv: &mut i2c::I2cDriver, voltage_mv: u16) -> Result<()> {
+        let pd_voltage = if voltage_mv <= 6500 {
+            PDVoltage::V5
+        } else if voltage_mv <= 10500 {
+            PDVoltage::V9
+        } else if voltage_mv <= 13500 {
+            PDVoltage::V12
+        } else if voltage_mv <= 17500 {
+            PDVoltage::V15
+        } else if voltage_mv <= 24000 {
+            PDVoltage::V20
+        } else {
+            PDVoltage::V28
+        };
+
+        info!("Mapped {}mV to {:?}", voltage_mv, pd_voltage);
+        self.request_voltage(i2cdrv, pd_voltage)
+    }
+
     /// Read the current status of the PD controller
-    pub fn get_status(&self, i2cdrv: &mut i2c::I2cDriver) -> anyhow::Result<PDStatus> {
+    pub fn get_status(&self, i2cdrv: &mut i2c::I2cDriver) -> Result<PDStatus> {
         let mut i2c_wrapper = I2cWrapper::new(i2cdrv);
         
         match self.driver.get_status(&mut i2c_wrapper) {
             Ok(status) => Ok(status),
             Err(e) => {
                 error!("Get status failed: {:?}", e);
-                Err(anyhow::anyhow!("Get status failed"))
+                Err(Error::I2c(format!("Get status failed: {:?}", e)))
             }
         }
     }
 
     /// Get current voltage in volts (convenience method)
-    pub fn get_voltage_v(&self, i2cdrv: &mut i2c::I2cDriver) -> anyhow::Result<f32> {
+    pub fn get_voltage_v(&self, i2cdrv: &mut i2c::I2cDriver) -> Result<f32> {
         let mut i2c_wrapper = I2cWrapper::new(i2cdrv);
         match self.driver.get_status(&mut i2c_wrapper) {
             Ok(status) => Ok(status.voltage_mv as f32 / 1000.0),
-            Err(_) => Err(anyhow::anyhow!("Failed to get voltage"))
+            Err(e) => Err(Error::I2c(format!("Failed to get voltage: {:?}", e)))
         }
     }
 
     /// Get current in amperes (convenience method)
-    pub fn get_current_a(&self, i2cdrv: &mut i2c::I2cDriver) -> anyhow::Result<f32> {
+    pub fn get_current_a(&self, i2cdrv: &mut i2c::I2cDriver) -> Result<f32> {
         let mut i2c_wrapper = I2cWrapper::new(i2cdrv);
         match self.driver.get_status(&mut i2c_wrapper) {
             Ok(status) => Ok(status.current_ma as f32 / 1000.0),
-            Err(_) => Err(anyhow::anyhow!("Failed to get current"))
+            Err(e) => Err(Error::I2c(format!("Failed to get current: {:?}", e)))
         }
     }
 
     /// Get power in watts (convenience method)
-    pub fn get_power_w(&self, i2cdrv: &mut i2c::I2cDriver) -> anyhow::Result<f32> {
+    pub fn get_power_w(&self, i2cdrv: &mut i2c::I2cDriver) -> Result<f32> {
         let voltage = self.get_voltage_v(i2cdrv)?;
         let current = self.get_current_a(i2cdrv)?;
         Ok(voltage * current)
     }
 
     /// Set voltage using float value in volts (convenience method)
-    pub fn set_voltage_v(&self, i2cdrv: &mut i2c::I2cDriver, voltage: f32) -> anyhow::Result<()> {
+    pub fn set_voltage_v(&self, i2cdrv: &mut i2c::I2cDriver, voltage: f32) -> Result<()> {
         let pd_voltage = match voltage {
             v if v <= 5.5 => PDVoltage::V5,
             v if v <= 9.5 => PDVoltage::V9,
@@ -341,7 +347,7 @@ impl AP33772S {
             v if v <= 15.5 => PDVoltage::V15,
             v if v <= 20.5 => PDVoltage::V20,
             v if v <= 28.5 => PDVoltage::V28,
-            _ => return Err(anyhow::anyhow!("Voltage {} V out of range", voltage)),
+            _ => return Err(Error::Config(format!("Voltage {} V out of range", voltage))),
         };
         
         self.request_voltage(i2cdrv, pd_voltage)
@@ -353,7 +359,7 @@ impl AP33772S {
     }
 
     /// Set custom voltage and current using float values (convenience method)
-    pub fn set_custom_voltage_v(&self, i2cdrv: &mut i2c::I2cDriver, voltage: f32, current: f32) -> anyhow::Result<()> {
+    pub fn set_custom_voltage_v(&self, i2cdrv: &mut i2c::I2cDriver, voltage: f32, current: f32) -> Result<()> {
         let voltage_mv = (voltage * 1000.0) as u16;
         let current_ma = (current * 1000.0) as u16;
         self.request_custom_voltage(i2cdrv, voltage_mv, current_ma)
@@ -365,11 +371,11 @@ impl AP33772S {
     }
 
     /// Get temperature in degrees Celsius
-    pub fn get_temperature_c(&self, i2cdrv: &mut i2c::I2cDriver) -> anyhow::Result<i8> {
+    pub fn get_temperature_c(&self, i2cdrv: &mut i2c::I2cDriver) -> Result<i8> {
         let mut i2c_wrapper = I2cWrapper::new(i2cdrv);
         match self.driver.get_status(&mut i2c_wrapper) {
             Ok(status) => Ok(status.temperature),
-            Err(_) => Err(anyhow::anyhow!("Failed to get temperature"))
+            Err(e) => Err(Error::I2c(format!("Failed to get temperature: {:?}", e)))
         }
     }
 
@@ -382,7 +388,7 @@ impl AP33772S {
         enable_ocp: bool,
         enable_otp: bool,
         enable_dr: bool,
-    ) -> anyhow::Result<()> {
+    ) -> Result<()> {
         info!("Configuring protections: UVP={}, OVP={}, OCP={}, OTP={}, DR={}", 
             enable_uvp, enable_ovp, enable_ocp, enable_otp, enable_dr);
         
@@ -402,13 +408,13 @@ impl AP33772S {
             },
             Err(e) => {
                 error!("Configure protections failed: {:?}", e);
-                Err(anyhow::anyhow!("Configure protections failed"))
+                Err(Error::I2c(format!("Configure protections failed: {:?}", e)))
             }
         }
     }
 
     /// Set VOUT to auto control
-    pub fn set_vout_auto_control(&self, i2cdrv: &mut i2c::I2cDriver) -> anyhow::Result<()> {
+    pub fn set_vout_auto_control(&self, i2cdrv: &mut i2c::I2cDriver) -> Result<()> {
         let mut i2c_wrapper = I2cWrapper::new(i2cdrv);
         
         match self.driver.set_vout_auto_control(&mut i2c_wrapper) {
@@ -418,13 +424,13 @@ impl AP33772S {
             },
             Err(e) => {
                 error!("Set auto control failed: {:?}", e);
-                Err(anyhow::anyhow!("Set auto control failed"))
+                Err(Error::I2c(format!("Set auto control failed: {:?}", e)))
             }
         }
     }
     
     /// Force VOUT OFF
-    pub fn force_vout_off(&self, i2cdrv: &mut i2c::I2cDriver) -> anyhow::Result<()> {
+    pub fn force_vout_off(&self, i2cdrv: &mut i2c::I2cDriver) -> Result<()> {
         let mut i2c_wrapper = I2cWrapper::new(i2cdrv);
         
         match self.driver.force_vout_off(&mut i2c_wrapper) {
@@ -434,13 +440,13 @@ impl AP33772S {
             },
             Err(e) => {
                 error!("Force VOUT OFF failed: {:?}", e);
-                Err(anyhow::anyhow!("Force VOUT OFF failed"))
+                Err(Error::I2c(format!("Force VOUT OFF failed: {:?}", e)))
             }
         }
     }
     
     /// Force VOUT ON
-    pub fn force_vout_on(&self, i2cdrv: &mut i2c::I2cDriver) -> anyhow::Result<()> {
+    pub fn force_vout_on(&self, i2cdrv: &mut i2c::I2cDriver) -> Result<()> {
         let mut i2c_wrapper = I2cWrapper::new(i2cdrv);
         
         match self.driver.force_vout_on(&mut i2c_wrapper) {
@@ -450,7 +456,7 @@ impl AP33772S {
             },
             Err(e) => {
                 error!("Force VOUT ON failed: {:?}", e);
-                Err(anyhow::anyhow!("Force VOUT ON failed"))
+                Err(Error::I2c(format!("Force VOUT ON failed: {:?}", e)))
             }
         }
     }
@@ -479,7 +485,7 @@ impl AP33772S {
     }
 
     /// Dump register values for debugging
-    pub fn dump_registers(&self, i2cdrv: &mut i2c::I2cDriver) -> anyhow::Result<()> {
+    pub fn dump_registers(&self, i2cdrv: &mut i2c::I2cDriver) -> Result<()> {
         info!("Register dump functionality moved to generic driver");
         // The generic driver doesn't expose individual register access
         // as it's abstracted away. For debugging, use get_status() instead.
@@ -492,7 +498,7 @@ impl AP33772S {
                     status.fault_type, status.is_attached, status.is_busy);
                 Ok(())
             },
-            Err(_) => Err(anyhow::anyhow!("Failed to dump registers"))
+            Err(e) => Err(Error::I2c(format!("Failed to dump registers: {:?}", e)))
         }
     }
 }
@@ -0,0 +1,49 @@
+// Firmware version and build info
+// The git hash and the build time are embedded by build.rs at compile time.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use serde::Serialize;
+use chrono::{DateTime, Utc};
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const GIT_HASH: &str = env!("DCPOWER_GIT_HASH");
+const BUILD_EPOCH: &str = env!("DCPOWER_BUILD_EPOCH");
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildInfo {
+    pub version: String,
+    pub git_hash: String,
+    pub build_time: String,
+    pub idf_version: String,
+}
+
+// Build time in RFC 3339 (UTC)
+pub fn build_time() -> String {
+    BUILD_EPOCH.parse::<i64>().ok()
+        .and_then(|epoch| DateTime::<Utc>::from_timestamp(epoch, 0))
+        .map(|t| t.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+// e.g. "0.1.2 (1a2b3c4)"
+pub fn version_string() -> String {
+    format!("{} ({})", VERSION, GIT_HASH)
+}
+
+// Semver build metadata form without spaces, for tags: "0.1.2+1a2b3c4"
+pub fn version_tag() -> String {
+    format!("{}+{}", VERSION, GIT_HASH)
+}
+
+pub fn build_info() -> BuildInfo {
+    let idf_version = unsafe { std::ffi::CStr::from_ptr(esp_idf_sys::esp_get_idf_version()) };
+    BuildInfo {
+        version: VERSION.to_string(),
+        git_hash: GIT_HASH.to_string(),
+        build_time: build_time(),
+        idf_version: idf_version.to_string_lossy().to_string(),
+    }
+}
@@ -0,0 +1,35 @@
+// HTTP POST to webhooks (session reports, alert notifications)
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use std::time::Duration;
+use embedded_svc::http::client::Client;
+use embedded_svc::http::Method;
+use embedded_svc::io::Write;
+use esp_idf_svc::http::client::{EspHttpConnection, Configuration};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// POST the body to an http or https URL; a status other than 2xx is an error
+pub fn post(url: &str, content_type: &str, headers: &[(&str, &str)], body: &[u8]) -> anyhow::Result<()> {
+    let http = EspHttpConnection::new(&Configuration {
+        use_global_ca_store: true,
+        crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+        timeout: Some(WEBHOOK_TIMEOUT),
+        ..Default::default()
+    })?;
+    let mut client = Client::wrap(http);
+    let content_length = body.len().to_string();
+    let mut all_headers = vec![("Content-Type", content_type), ("Content-Length", content_length.as_str())];
+    all_headers.extend_from_slice(headers);
+    let mut request = client.request(Method::Post, url, &all_headers)?;
+    request.write_all(body)?;
+    let response = request.submit()?;
+    let status = response.status();
+    if !(200..300).contains(&status) {
+        anyhow::bail!("HTTP {}", status);
+    }
+    Ok(())
+}
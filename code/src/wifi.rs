@@ -1,51 +1,205 @@
 // Wi-Fi connection and RSSI measurement
+// WifiManager owns the driver for the whole WiFi lifecycle: the connection at boot, the
+// status polled by the main loop and the reconnection. The status is kept as a snapshot
+// (connected, IP address, RSSI, reconnect count) which other threads read through a handle
+// without touching the driver.
+// Without an SSID the radio can be started alone for ESP-NOW.
+// In the low-power idle the modem sleeps between the beacons of the access point.
 // SPDX-License-Identifier: MIT
 // Copyright (c) 2024 Hiroshi Nakajima
 
 #![allow(dead_code)]
 
+use log::*;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use std::thread;
 
 use esp_idf_hal::peripheral;
 use esp_idf_svc::{eventloop::EspSystemEventLoop, wifi::EspWifi};
 use esp_idf_sys;
+use serde::Serialize;
 
 use embedded_svc::wifi::{ClientConfiguration, Configuration};
 use anyhow::bail;
 use anyhow::Result;
 use std::str::FromStr;
 
-pub fn wifi_connect<'d> (
+// Seconds to wait for the connection at boot
+const CONNECT_TIMEOUT_SECS: u32 = 10;
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct WifiState {
+    pub connected: bool,
+    // IPv4 address while connected
+    pub ip: Option<Ipv4Addr>,
+    // dBm, 0 while not connected
+    pub rssi: i32,
+    // Reconnections requested since boot
+    pub reconnects: u32,
+    // Radio started for ESP-NOW without an access point
+    pub radio_only: bool,
+}
+
+// Snapshot of the state, shared with the other threads
+pub type WifiStateHandle = Arc<Mutex<WifiState>>;
+
+pub struct WifiManager {
+    // None if the driver could not be started
+    wifi: Option<Box<EspWifi<'static>>>,
+    state: WifiStateHandle,
+}
+
+impl WifiManager {
+    // Connect to the access point, waiting up to 10s. A failed start is logged and leaves
+    // the manager without a driver (not connected).
+    pub fn connect(
+        modem: impl peripheral::Peripheral<P = esp_idf_hal::modem::Modem> + 'static,
+        ssid: &str,
+        pass: &str,
+    ) -> WifiManager {
+        let wifi = match wifi_connect(modem, ssid, pass) {
+            Ok(wifi) => Some(wifi),
+            Err(e) => {
+                warn!("WiFi not started: {:?}", e);
+                None
+            },
+        };
+        WifiManager::with_driver(wifi, false)
+    }
+
+    // Start the radio on the channel without joining a network (ESP-NOW only)
+    pub fn radio_only(
+        modem: impl peripheral::Peripheral<P = esp_idf_hal::modem::Modem> + 'static,
+        channel: u8,
+    ) -> WifiManager {
+        let wifi = match wifi_radio_only(modem, channel) {
+            Ok(wifi) => Some(wifi),
+            Err(e) => {
+                warn!("WiFi radio not started: {:?}", e);
+                None
+            },
+        };
+        WifiManager::with_driver(wifi, true)
+    }
+
+    fn with_driver(wifi: Option<Box<EspWifi<'static>>>, radio_only: bool) -> WifiManager {
+        let manager = WifiManager {
+            wifi: wifi,
+            state: Arc::new(Mutex::new(WifiState { radio_only: radio_only, ..Default::default() })),
+        };
+        manager.update();
+        manager
+    }
+
+    // The radio is running (for ESP-NOW)
+    pub fn has_radio(&self) -> bool {
+        self.wifi.is_some()
+    }
+
+    pub fn state_handle(&self) -> WifiStateHandle {
+        self.state.clone()
+    }
+
+    pub fn get_state(&self) -> WifiState {
+        self.state.lock().unwrap().clone()
+    }
+
+    // Poll the connection and update the snapshot, returns it
+    pub fn update(&self) -> WifiState {
+        let mut state = self.state.lock().unwrap();
+        let rssi = if self.wifi.is_some() { read_rssi() } else { 0 };
+        let connected = rssi != 0 && !state.radio_only;
+        if connected && !state.connected {
+            // The address is read once per connection
+            state.ip = self.wifi.as_ref()
+                .and_then(|wifi| wifi.sta_netif().get_ip_info().ok())
+                .map(|info| info.ip);
+            info!("WiFi connected: {:?}", state.ip);
+        }
+        else if !connected {
+            state.ip = None;
+        }
+        state.connected = connected;
+        state.rssi = rssi;
+        state.clone()
+    }
+
+    // Networks in range (SSID, dBm), strongest first. Blocks for the scan of all the
+    // channels, about 2 seconds.
+    pub fn scan(&mut self) -> Result<Vec<(String, i8)>> {
+        let wifi = match self.wifi.as_mut() {
+            Some(wifi) => wifi,
+            None => bail!("WiFi not started"),
+        };
+        let mut networks: Vec<(String, i8)> = wifi.scan()?.into_iter()
+            .map(|ap| (ap.ssid.to_string(), ap.signal_strength))
+            .collect();
+        networks.sort_by(|a, b| b.1.cmp(&a.1));
+        Ok(networks)
+    }
+
+    // Modem sleep between the beacons in the low-power idle, the default power save otherwise
+    pub fn set_power_save(&self, enable: bool) {
+        if self.wifi.is_none() || self.get_state().radio_only {
+            // ESP-NOW needs the radio on
+            return;
+        }
+        let mode = if enable { esp_idf_sys::wifi_ps_type_t_WIFI_PS_MAX_MODEM } else { esp_idf_sys::wifi_ps_type_t_WIFI_PS_MIN_MODEM };
+        if let Err(e) = esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_wifi_set_ps(mode) }) {
+            warn!("WiFi power save: {:?}", e);
+        }
+    }
+
+    // Start the driver again if needed and request a connection
+    pub fn reconnect(&mut self) -> bool {
+        let wifi = match self.wifi.as_mut() {
+            Some(wifi) => wifi,
+            None => return false,
+        };
+        self.state.lock().unwrap().reconnects += 1;
+        if !wifi.is_started().unwrap_or(false) {
+            if let Err(e) = wifi.start() {
+                info!("{:?}", e);
+                return false;
+            }
+        }
+        match wifi.connect() {
+            Ok(_) => { info!("Wifi connecting requested."); true },
+            Err(ref e) => { info!("{:?}", e); false }
+        }
+    }
+}
+
+fn wifi_connect(
     modem: impl peripheral::Peripheral<P = esp_idf_hal::modem::Modem> + 'static,
-    ssid: &'d str,
-    pass: &'d str,
-) -> Result<Box<EspWifi<'d>>> {
+    ssid: &str,
+    pass: &str,
+) -> Result<Box<EspWifi<'static>>> {
 
     if ssid.is_empty() || pass.is_empty() {
         bail!("SSID or password is empty");
     }
-    let sys_event_loop = EspSystemEventLoop::take().unwrap();
-    let mut wifi = Box::new(EspWifi::new(modem, sys_event_loop.clone(), None).unwrap());
+    let sys_event_loop = EspSystemEventLoop::take()?;
+    let mut wifi = Box::new(EspWifi::new(modem, sys_event_loop.clone(), None)?);
 
     wifi.set_configuration(&Configuration::Client(ClientConfiguration {
-        ssid: heapless::String::<32>::from_str(ssid).unwrap(),
-        password: heapless::String::<64>::from_str(pass).unwrap(),
+        ssid: heapless::String::<32>::from_str(ssid).map_err(|_| anyhow::anyhow!("SSID too long"))?,
+        password: heapless::String::<64>::from_str(pass).map_err(|_| anyhow::anyhow!("password too long"))?,
         ..Default::default()
-    })).unwrap();
+    }))?;
 
-    wifi.start().unwrap();
+    wifi.start()?;
     wifi.connect()?;
     let mut timeout = 0;
     loop {
-        if wifi.is_connected().unwrap(){
-            // info!("Wifi connected");
+        if wifi.is_connected()? {
             break;
         }
         thread::sleep(Duration::from_secs(1));
         timeout += 1;
-        if timeout > 10 {
-            // info!("Wifi could not be connected.");
+        if timeout > CONNECT_TIMEOUT_SECS {
             // wifi could not be connected, but we can use the wifi object to reconnect
             break;
         }
@@ -53,10 +207,25 @@ pub fn wifi_connect<'d> (
     Ok(wifi)
 }
 
-pub fn get_rssi() -> i32 {
+fn wifi_radio_only(
+    modem: impl peripheral::Peripheral<P = esp_idf_hal::modem::Modem> + 'static,
+    channel: u8,
+) -> Result<Box<EspWifi<'static>>> {
+    let sys_event_loop = EspSystemEventLoop::take()?;
+    let mut wifi = Box::new(EspWifi::new(modem, sys_event_loop, None)?);
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
+    wifi.start()?;
+    esp_idf_sys::esp!(unsafe {
+        esp_idf_sys::esp_wifi_set_channel(channel, esp_idf_sys::wifi_second_chan_t_WIFI_SECOND_CHAN_NONE)
+    })?;
+    Ok(wifi)
+}
+
+// RSSI of the access point (dBm), 0 while not connected
+fn read_rssi() -> i32 {
+    let mut rssi : i32 = 0;
     unsafe {
-        let mut rssi : i32 = 0;
         esp_idf_sys::esp_wifi_sta_get_rssi(&mut rssi);
-        rssi
     }
-}
\ No newline at end of file
+    rssi
+}
@@ -0,0 +1,96 @@
+// Median and IIR filter of the single-shot ADC readings
+// The analog temperature (GPIO18) and the USB PD rail voltage (GPIO9) are read once per
+// main loop and are noisy, with an occasional spike. A reading further than the outlier
+// limit from the median of the recent readings is rejected; if the readings stay there for
+// a full window, the signal really moved and they are taken. The median of the window is
+// then smoothed by a first order IIR filter (alpha 1 is no smoothing).
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+
+pub const MAX_WINDOW: usize = 15;
+
+pub struct AdcFilter {
+    window: usize,
+    alpha: f32,
+    // Maximum deviation from the median (units of the reading), 0 disables the rejection
+    outlier: f32,
+    readings: VecDeque<f32>,
+    value: Option<f32>,
+    rejected_run: usize,
+    rejected: u32,
+}
+
+impl AdcFilter {
+    pub fn new(window: usize, alpha: f32, outlier: f32) -> AdcFilter {
+        let window = window.clamp(1, MAX_WINDOW);
+        AdcFilter {
+            window: window,
+            alpha: alpha.clamp(0.01, 1.0),
+            outlier: outlier.max(0.0),
+            readings: VecDeque::with_capacity(window),
+            value: None,
+            rejected_run: 0,
+            rejected: 0,
+        }
+    }
+
+    // Filtered value, None before the first reading
+    pub fn get_value(&self) -> Option<f32> {
+        self.value
+    }
+
+    // Readings rejected as outliers since the start
+    pub fn get_rejected(&self) -> u32 {
+        self.rejected
+    }
+
+    // Add a reading, returns the filtered value
+    pub fn update(&mut self, reading: f32) -> f32 {
+        if !reading.is_finite() {
+            self.rejected += 1;
+            return self.value.unwrap_or(0.0);
+        }
+        if let (Some(value), Some(median)) = (self.value, self.median()) {
+            if self.outlier > 0.0 && self.readings.len() == self.window && (reading - median).abs() > self.outlier {
+                self.rejected_run += 1;
+                if self.rejected_run < self.window {
+                    self.rejected += 1;
+                    return value;
+                }
+            }
+            else {
+                self.rejected_run = 0;
+            }
+        }
+        if self.readings.len() == self.window {
+            self.readings.pop_front();
+        }
+        self.readings.push_back(reading);
+        let median = self.median().unwrap_or(reading);
+        let value = match self.value {
+            Some(value) => value + self.alpha * (median - value),
+            None => median,
+        };
+        self.value = Some(value);
+        value
+    }
+
+    fn median(&self) -> Option<f32> {
+        if self.readings.is_empty() {
+            return None;
+        }
+        let mut sorted : Vec<f32> = self.readings.iter().copied().collect();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let middle = sorted.len() / 2;
+        if sorted.len() % 2 == 1 {
+            Some(sorted[middle])
+        }
+        else {
+            Some((sorted[middle - 1] + sorted[middle]) / 2.0)
+        }
+    }
+}
@@ -0,0 +1,287 @@
+// Alarm rules on the measurements
+// The rules are a list separated by ';', each "[ch1|ch2] <quantity> <op> <value>[unit] [for <time>]":
+//   quantity : voltage (V), current (A), power (W) of the channel (ch1 by default),
+//              temperature (C, the heatsink) or pd_voltage (V, the USB PD rail)
+//   op       : >, >=, <, <=
+//   time     : the condition holds this long before the alarm is raised, 5s or 500ms
+// e.g. "current > 2A for 5s; ch2 voltage < 4.5V; temperature > 60C for 30s"
+// The values of a channel are only watched while its output is on. An alarm is cleared
+// when its condition has been false for CLEAR_MS, so a value at the threshold does not
+// raise it again and again.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+pub const MAX_RULES: usize = 8;
+// Time the condition is false before a raised alarm is cleared (ms)
+pub const CLEAR_MS: u64 = 1000;
+// Longest hold time of a rule (ms)
+pub const MAX_HOLD_MS: u64 = 3_600_000;
+const MAX_CHANNELS: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quantity {
+    Voltage,
+    Current,
+    Power,
+    Temperature,
+    PdVoltage,
+}
+
+impl Quantity {
+    fn parse(text: &str) -> Option<Quantity> {
+        match text.to_ascii_lowercase().as_str() {
+            "voltage" => Some(Quantity::Voltage),
+            "current" => Some(Quantity::Current),
+            "power" => Some(Quantity::Power),
+            "temperature" => Some(Quantity::Temperature),
+            "pd_voltage" => Some(Quantity::PdVoltage),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Quantity::Voltage => "voltage",
+            Quantity::Current => "current",
+            Quantity::Power => "power",
+            Quantity::Temperature => "temperature",
+            Quantity::PdVoltage => "pd_voltage",
+        }
+    }
+
+    pub fn unit(&self) -> &'static str {
+        match self {
+            Quantity::Voltage | Quantity::PdVoltage => "V",
+            Quantity::Current => "A",
+            Quantity::Power => "W",
+            Quantity::Temperature => "C",
+        }
+    }
+
+    // Values of a channel, the others are of the unit
+    fn is_channel(&self) -> bool {
+        matches!(self, Quantity::Voltage | Quantity::Current | Quantity::Power)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compare {
+    Above,
+    AtLeast,
+    Below,
+    AtMost,
+}
+
+impl Compare {
+    fn parse(text: &str) -> Option<Compare> {
+        match text {
+            ">" => Some(Compare::Above),
+            ">=" => Some(Compare::AtLeast),
+            "<" => Some(Compare::Below),
+            "<=" => Some(Compare::AtMost),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Compare::Above => ">",
+            Compare::AtLeast => ">=",
+            Compare::Below => "<",
+            Compare::AtMost => "<=",
+        }
+    }
+
+    fn is_met(&self, value: f32, threshold: f32) -> bool {
+        match self {
+            Compare::Above => value > threshold,
+            Compare::AtLeast => value >= threshold,
+            Compare::Below => value < threshold,
+            Compare::AtMost => value <= threshold,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AlarmRule {
+    // Channel index (0 is CH1) of a channel value
+    pub channel: usize,
+    pub quantity: Quantity,
+    pub compare: Compare,
+    pub threshold: f32,
+    pub hold_ms: u64,
+}
+
+impl AlarmRule {
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        if self.quantity.is_channel() {
+            text.push_str(&format!("ch{} ", self.channel + 1));
+        }
+        text.push_str(&format!("{} {} {}{}", self.quantity.as_str(), self.compare.as_str(), self.threshold, self.quantity.unit()));
+        if self.hold_ms > 0 {
+            text.push_str(&format!(" for {}s", self.hold_ms as f32 / 1000.0));
+        }
+        text
+    }
+
+    // The value watched by the rule, None while it is not watched
+    pub fn value(&self, values: &AlarmValues) -> Option<f32> {
+        match self.quantity {
+            Quantity::Voltage => values.voltage.get(self.channel).copied().flatten(),
+            Quantity::Current => values.current.get(self.channel).copied().flatten(),
+            Quantity::Power => values.power.get(self.channel).copied().flatten(),
+            Quantity::Temperature => Some(values.temperature),
+            Quantity::PdVoltage => Some(values.pd_voltage),
+        }
+    }
+}
+
+// The values of a channel are None while its output is off
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AlarmValues {
+    pub voltage: [Option<f32>; MAX_CHANNELS],
+    pub current: [Option<f32>; MAX_CHANNELS],
+    pub power: [Option<f32>; MAX_CHANNELS],
+    pub temperature: f32,
+    pub pd_voltage: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlarmEvent {
+    // Index of the rule and the value
+    Raised(usize, f32),
+    Cleared(usize),
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct RuleState {
+    met_since_ms: Option<u64>,
+    clear_since_ms: Option<u64>,
+    raised: bool,
+}
+
+pub struct Alarms {
+    rules: Vec<AlarmRule>,
+    states: Vec<RuleState>,
+}
+
+impl Alarms {
+    pub fn new(rules: Vec<AlarmRule>) -> Alarms {
+        let states = vec![RuleState::default(); rules.len()];
+        Alarms { rules: rules, states: states }
+    }
+
+    pub fn parse(text: &str) -> Result<Alarms, String> {
+        let mut rules = Vec::new();
+        for item in text.split(';').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            rules.push(parse_rule(item)?);
+        }
+        if rules.len() > MAX_RULES {
+            return Err(format!("{} rules at most", MAX_RULES));
+        }
+        Ok(Alarms::new(rules))
+    }
+
+    pub fn get_rules(&self) -> &[AlarmRule] {
+        &self.rules
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn is_raised(&self, index: usize) -> bool {
+        self.states.get(index).is_some_and(|s| s.raised)
+    }
+
+    // Number of the raised alarms
+    pub fn raised_count(&self) -> usize {
+        self.states.iter().filter(|s| s.raised).count()
+    }
+
+    // Alarms raised and cleared by the values now
+    pub fn update(&mut self, now_ms: u64, values: &AlarmValues) -> Vec<AlarmEvent> {
+        let mut events = Vec::new();
+        for (index, (rule, state)) in self.rules.iter().zip(self.states.iter_mut()).enumerate() {
+            let value = rule.value(values);
+            let met = match value {
+                Some(v) => rule.compare.is_met(v, rule.threshold),
+                None => false,
+            };
+            if met {
+                state.clear_since_ms = None;
+                let since = *state.met_since_ms.get_or_insert(now_ms);
+                if !state.raised && now_ms.saturating_sub(since) >= rule.hold_ms {
+                    state.raised = true;
+                    events.push(AlarmEvent::Raised(index, value.unwrap_or(0.0)));
+                }
+            }
+            else {
+                state.met_since_ms = None;
+                if state.raised {
+                    let since = *state.clear_since_ms.get_or_insert(now_ms);
+                    if now_ms.saturating_sub(since) >= CLEAR_MS {
+                        state.raised = false;
+                        state.clear_since_ms = None;
+                        events.push(AlarmEvent::Cleared(index));
+                    }
+                }
+            }
+        }
+        events
+    }
+}
+
+fn parse_rule(item: &str) -> Result<AlarmRule, String> {
+    let tokens: Vec<&str> = item.split_whitespace().collect();
+    let mut rest = &tokens[..];
+    let mut channel = 0;
+    if let Some(ch) = rest.first().and_then(|t| t.strip_prefix("ch")) {
+        channel = match ch.parse::<usize>() {
+            Ok(n) if (1..=MAX_CHANNELS).contains(&n) => n - 1,
+            _ => return Err(format!("'{}': channel must be ch1 or ch2", item)),
+        };
+        rest = &rest[1..];
+    }
+    let (quantity, compare, value) = match rest {
+        [q, c, v, ..] => (*q, *c, *v),
+        _ => return Err(format!("'{}': <quantity> <op> <value> expected", item)),
+    };
+    rest = &rest[3..];
+    let quantity = Quantity::parse(quantity)
+        .ok_or_else(|| format!("'{}': quantity must be voltage, current, power, temperature or pd_voltage", item))?;
+    if !quantity.is_channel() && channel != 0 {
+        return Err(format!("'{}': {} is not a value of a channel", item, quantity.as_str()));
+    }
+    let compare = Compare::parse(compare).ok_or_else(|| format!("'{}': op must be >, >=, < or <=", item))?;
+    let value = value.strip_suffix("°C").unwrap_or(value);
+    let value = value.strip_suffix(quantity.unit()).unwrap_or(value);
+    let threshold = match value.parse::<f32>() {
+        Ok(v) if v.is_finite() => v,
+        _ => return Err(format!("'{}': value must be a number in {}", item, quantity.unit())),
+    };
+    let hold_ms = match rest {
+        [] => 0,
+        ["for", time] => parse_time_ms(time)
+            .filter(|ms| *ms <= MAX_HOLD_MS)
+            .ok_or_else(|| format!("'{}': time must be like 5s or 500ms, up to {}s", item, MAX_HOLD_MS / 1000))?,
+        _ => return Err(format!("'{}': only 'for <time>' may follow the value", item)),
+    };
+    Ok(AlarmRule { channel: channel, quantity: quantity, compare: compare, threshold: threshold, hold_ms: hold_ms })
+}
+
+fn parse_time_ms(text: &str) -> Option<u64> {
+    if let Some(ms) = text.strip_suffix("ms") {
+        return ms.parse::<u64>().ok();
+    }
+    let secs = text.strip_suffix('s').unwrap_or(text).parse::<f32>().ok()?;
+    if secs.is_finite() && secs >= 0.0 {
+        Some((secs * 1000.0) as u64)
+    }
+    else {
+        None
+    }
+}
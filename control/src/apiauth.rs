@@ -0,0 +1,84 @@
+// Access levels of the HTTP API
+// A request presents a token as "Authorization: Bearer <token>". The read token gives the
+// telemetry (health, version, session report, ...), the control token gives everything,
+// including the setpoints, the settings and the documents which hold credentials. Without
+// a control token the API is open as before; without a read token the telemetry is open.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Role {
+    Read,
+    Control,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Read => "read",
+            Role::Control => "control",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Granted,
+    // No token or an unknown one (401)
+    Unauthorized,
+    // A valid token of a lower role (403)
+    Forbidden,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AccessTokens {
+    // Empty for no token
+    pub read: String,
+    pub control: String,
+}
+
+impl AccessTokens {
+    pub fn new(read: &str, control: &str) -> AccessTokens {
+        AccessTokens { read: read.to_string(), control: control.to_string() }
+    }
+
+    // Role given by the Authorization header, None for no or an unknown token
+    pub fn role_of(&self, authorization: Option<&str>) -> Option<Role> {
+        let token = authorization?.trim().strip_prefix("Bearer ")?.trim();
+        if !self.control.is_empty() && same_token(token, &self.control) {
+            Some(Role::Control)
+        }
+        else if !self.read.is_empty() && same_token(token, &self.read) {
+            Some(Role::Read)
+        }
+        else {
+            None
+        }
+    }
+
+    pub fn check(&self, authorization: Option<&str>, required: Role) -> Access {
+        let open = match required {
+            Role::Read => self.read.is_empty() || self.control.is_empty(),
+            Role::Control => self.control.is_empty(),
+        };
+        if open {
+            return Access::Granted;
+        }
+        match self.role_of(authorization) {
+            Some(role) if role >= required => Access::Granted,
+            Some(_) => Access::Forbidden,
+            None => Access::Unauthorized,
+        }
+    }
+}
+
+// Compared in a time independent of where the tokens differ
+fn same_token(presented: &str, token: &str) -> bool {
+    let (a, b) = (presented.as_bytes(), token.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
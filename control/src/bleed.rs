@@ -0,0 +1,67 @@
+// Output discharge (bleed) control
+// At light load the output capacitor holds its charge long after the output is turned off
+// or the setpoint is lowered; the buck stage can only source current. A bleed FET across
+// the output discharges it: engaged while the output is above the target (the setpoint, or
+// 0V with the output off) by the margin, released near the target. The bleed resistor is
+// rated for short pulses, so a continuous discharge is cut after max_on and rests as long.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use crate::hal::{Clock, SystemClock};
+
+// Engaged above the target by this much (V), released at half of it
+pub const BLEED_MARGIN_V: f32 = 0.2;
+
+pub struct Bleed<C: Clock = SystemClock> {
+    max_on_ms: u32,
+    engaged: bool,
+    since: u128,
+    // Rest after a discharge cut by max_on, until this time
+    rest_until: u128,
+    clock: C,
+}
+
+impl Bleed<SystemClock> {
+    pub fn new(max_on_ms: u32) -> Bleed {
+        Bleed::with_clock(max_on_ms, SystemClock)
+    }
+}
+
+impl<C: Clock> Bleed<C> {
+    pub fn with_clock(max_on_ms: u32, clock: C) -> Bleed<C> {
+        Bleed {
+            max_on_ms: max_on_ms,
+            engaged: false,
+            since: 0,
+            rest_until: 0,
+            clock: clock,
+        }
+    }
+
+    pub fn is_engaged(&self) -> bool {
+        self.engaged
+    }
+
+    // Output voltage of the control period, returns whether the bleed is to be engaged
+    pub fn update(&mut self, output_on: bool, setpoint: f32, voltage: f32) -> bool {
+        let now = self.clock.now_ns();
+        let target = if output_on { setpoint } else { 0.0 };
+        let max_on_ns = self.max_on_ms as u128 * 1_000_000;
+        if self.engaged {
+            if voltage <= target + BLEED_MARGIN_V / 2.0 {
+                self.engaged = false;
+            }
+            else if now - self.since >= max_on_ns {
+                self.engaged = false;
+                self.rest_until = now + max_on_ns;
+            }
+        }
+        else if voltage > target + BLEED_MARGIN_V && now >= self.rest_until {
+            self.engaged = true;
+            self.since = now;
+        }
+        self.engaged
+    }
+}
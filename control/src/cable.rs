@@ -0,0 +1,121 @@
+// Cable and connector resistance estimation
+// The output is stepped between two setpoints at the same USB PD contract, which steps the
+// input current. The drop of the sink side rail voltage over the input current step (dV/dI)
+// is the series resistance between the source and the unit; the offsets of the
+// measurements cancel out.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use crate::hal::{Clock, SystemClock};
+
+// Smallest input current step for an estimate
+pub const MIN_CURRENT_STEP_A: f32 = 0.1;
+
+// Average of the rail voltage and the input current at one setpoint
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CablePoint {
+    pub voltage: f32,
+    pub current: f32,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CableEstimate {
+    pub resistance: f32,
+    pub low: CablePoint,
+    pub high: CablePoint,
+}
+
+impl CableEstimate {
+    pub fn from_points(low: CablePoint, high: CablePoint) -> Result<CableEstimate, String> {
+        let current_step = high.current - low.current;
+        if current_step < MIN_CURRENT_STEP_A {
+            return Err(format!("input current step {:.3}A too small, connect a load", current_step));
+        }
+        Ok(CableEstimate {
+            resistance: (low.voltage - high.voltage) / current_step,
+            low: low,
+            high: high,
+        })
+    }
+}
+
+// Sequence of the estimation: each setpoint is held for settle_ms, then the samples of
+// measure_ms are averaged. The low setpoint is first.
+pub struct CableTest<C: Clock = SystemClock> {
+    setpoints: [f32; 2],
+    settle_ns: u128,
+    measure_ns: u128,
+    phase: usize,
+    phase_start: u128,
+    sum: CablePoint,
+    samples: u32,
+    points: [CablePoint; 2],
+    clock: C,
+}
+
+impl CableTest<SystemClock> {
+    pub fn new(low: f32, high: f32, settle_ms: u32, measure_ms: u32) -> CableTest {
+        CableTest::with_clock(low, high, settle_ms, measure_ms, SystemClock)
+    }
+}
+
+impl<C: Clock> CableTest<C> {
+    pub fn with_clock(low: f32, high: f32, settle_ms: u32, measure_ms: u32, clock: C) -> CableTest<C> {
+        CableTest {
+            setpoints: [low, high],
+            settle_ns: settle_ms as u128 * 1_000_000,
+            measure_ns: measure_ms as u128 * 1_000_000,
+            phase: 0,
+            phase_start: clock.now_ns(),
+            sum: CablePoint::default(),
+            samples: 0,
+            points: [CablePoint::default(); 2],
+            clock: clock,
+        }
+    }
+
+    // Setpoint of the output for the current phase
+    pub fn setpoint(&self) -> f32 {
+        self.setpoints[self.phase.min(1)]
+    }
+
+    // Whether the samples are averaged now (after the settle time)
+    pub fn is_measuring(&self) -> bool {
+        self.clock.now_ns() - self.phase_start >= self.settle_ns
+    }
+
+    // Rail voltage and input current; ignored while settling
+    pub fn add_sample(&mut self, voltage: f32, current: f32) {
+        if !self.is_measuring() {
+            return;
+        }
+        self.sum.voltage += voltage;
+        self.sum.current += current;
+        self.samples += 1;
+    }
+
+    // Advance the sequence. Returns the result once both setpoints are measured.
+    pub fn poll(&mut self) -> Option<Result<CableEstimate, String>> {
+        let now = self.clock.now_ns();
+        if now - self.phase_start < self.settle_ns + self.measure_ns {
+            return None;
+        }
+        if self.samples == 0 {
+            return Some(Err("no samples".to_string()));
+        }
+        self.points[self.phase] = CablePoint {
+            voltage: self.sum.voltage / self.samples as f32,
+            current: self.sum.current / self.samples as f32,
+        };
+        self.sum = CablePoint::default();
+        self.samples = 0;
+        self.phase_start = now;
+        self.phase += 1;
+        if self.phase < 2 {
+            return None;
+        }
+        Some(CableEstimate::from_points(self.points[0], self.points[1]))
+    }
+}
@@ -0,0 +1,158 @@
+// Triggered capture (pre/post trigger)
+// The records are kept in a circular buffer of pre_samples while armed. When the trigger
+// condition becomes true (edge), post_samples more are recorded and the window is frozen
+// until it is taken. A level trigger only fires once the pre-trigger part is full; a fault
+// fires at once with what has been recorded.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+use crate::currentlogs::CurrentLog;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CaptureTrigger {
+    CurrentAbove(f32),
+    VoltageBelow(f32),
+    // Trip of the output
+    Fault,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CaptureState {
+    Idle,
+    Armed,
+    Triggered,
+    Frozen,
+}
+
+#[derive(Debug, Clone)]
+pub struct CaptureWindow {
+    pub trigger: CaptureTrigger,
+    // Index of the trigger record in samples
+    pub trigger_index: usize,
+    pub samples: Vec<CurrentLog>,
+}
+
+pub struct Capture {
+    pre_samples: usize,
+    post_samples: usize,
+    trigger: Option<CaptureTrigger>,
+    state: CaptureState,
+    buffer: VecDeque<CurrentLog>,
+    trigger_index: usize,
+    remaining: usize,
+    last_condition: bool,
+}
+
+impl Capture {
+    pub fn new(pre_samples: usize, post_samples: usize) -> Capture {
+        Capture {
+            pre_samples: pre_samples,
+            post_samples: post_samples,
+            trigger: None,
+            state: CaptureState::Idle,
+            buffer: VecDeque::with_capacity(pre_samples + post_samples),
+            trigger_index: 0,
+            remaining: 0,
+            last_condition: false,
+        }
+    }
+
+    // Start filling the buffer for a new capture (a frozen window is discarded)
+    pub fn arm(&mut self, trigger: CaptureTrigger) {
+        self.trigger = Some(trigger);
+        self.state = CaptureState::Armed;
+        self.buffer.clear();
+        self.last_condition = true;
+    }
+
+    pub fn disarm(&mut self) {
+        self.trigger = None;
+        self.state = CaptureState::Idle;
+        self.buffer.clear();
+    }
+
+    pub fn state(&self) -> CaptureState {
+        self.state
+    }
+
+    pub fn trigger(&self) -> Option<CaptureTrigger> {
+        self.trigger
+    }
+
+    // Records in the buffer
+    pub fn get_size(&self) -> usize {
+        self.buffer.len()
+    }
+
+    // One record. Returns true when the window is frozen by it.
+    pub fn push(&mut self, sample: &CurrentLog) -> bool {
+        match self.state {
+            CaptureState::Armed => {
+                let condition = match self.trigger {
+                    Some(CaptureTrigger::CurrentAbove(current)) => sample.current > current,
+                    Some(CaptureTrigger::VoltageBelow(voltage)) => sample.voltage < voltage,
+                    _ => false,
+                };
+                let edge = condition && !self.last_condition;
+                self.last_condition = condition;
+                if edge && self.buffer.len() >= self.pre_samples {
+                    self.start_post();
+                    return self.record(sample);
+                }
+                if self.buffer.len() >= self.pre_samples {
+                    self.buffer.pop_front();
+                }
+                if self.pre_samples > 0 {
+                    self.buffer.push_back(sample.clone());
+                }
+                false
+            },
+            CaptureState::Triggered => self.record(sample),
+            _ => false,
+        }
+    }
+
+    // Trip of the output; fires an armed Fault trigger
+    pub fn fault(&mut self) {
+        if self.state == CaptureState::Armed && self.trigger == Some(CaptureTrigger::Fault) {
+            self.start_post();
+        }
+    }
+
+    // The frozen window; the capture is idle afterwards
+    pub fn take(&mut self) -> Option<CaptureWindow> {
+        if self.state != CaptureState::Frozen {
+            return None;
+        }
+        let window = CaptureWindow {
+            trigger: self.trigger.unwrap_or(CaptureTrigger::Fault),
+            trigger_index: self.trigger_index,
+            samples: self.buffer.drain(..).collect(),
+        };
+        self.trigger = None;
+        self.state = CaptureState::Idle;
+        Some(window)
+    }
+
+    fn start_post(&mut self) {
+        self.state = CaptureState::Triggered;
+        self.trigger_index = self.buffer.len();
+        self.remaining = self.post_samples;
+        if self.remaining == 0 {
+            self.state = CaptureState::Frozen;
+        }
+    }
+
+    fn record(&mut self, sample: &CurrentLog) -> bool {
+        self.buffer.push_back(sample.clone());
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            self.state = CaptureState::Frozen;
+            return true;
+        }
+        false
+    }
+}
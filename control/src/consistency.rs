@@ -0,0 +1,86 @@
+// Power consistency check of the INA228
+// The INA228 computes its POWER register from its own current and bus voltage, and the
+// firmware reads it apart from VBUS and CURRENT. The power read is compared with the voltage
+// times the current as logged (with the offsets applied): when they diverge beyond the
+// tolerance, one of the readings or their conversion is wrong, e.g. a SHUNT_CAL or ADC range
+// which does not match the current LSB, a corrupted offset calibration or a register read
+// from the wrong device. The check is made on each sample above MIN_POWER_W, where the
+// resolution does not dominate the error; CHECK_SAMPLES in a row over the tolerance raise
+// the warning and as many within it clear it, so a load step between the reads does not.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+// Below this power the samples are not checked (W)
+pub const MIN_POWER_W: f32 = 0.5;
+// Samples in a row which change the state
+pub const CHECK_SAMPLES: u32 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PowerCheckEvent {
+    // The power read, the voltage times the current and the relative error (%)
+    Inconsistent { power: f32, expected: f32, error: f32 },
+    Consistent,
+}
+
+pub struct PowerCheck {
+    // Relative error allowed (%), 0 disables the check
+    tolerance: f32,
+    run: u32,
+    inconsistent: bool,
+}
+
+impl PowerCheck {
+    pub fn new(tolerance: f32) -> PowerCheck {
+        PowerCheck { tolerance: tolerance, run: 0, inconsistent: false }
+    }
+
+    pub fn set_tolerance(&mut self, tolerance: f32) {
+        if tolerance != self.tolerance {
+            *self = PowerCheck::new(tolerance);
+        }
+    }
+
+    pub fn is_inconsistent(&self) -> bool {
+        self.inconsistent
+    }
+
+    // Relative error (%) of the power read against the voltage times the current
+    pub fn error(voltage: f32, current: f32, power: f32) -> f32 {
+        let expected = (voltage * current).abs();
+        if expected > 0.0 {
+            (power.abs() - expected).abs() / expected * 100.0
+        }
+        else {
+            0.0
+        }
+    }
+
+    // A fresh sample; the state changes are returned
+    pub fn check(&mut self, voltage: f32, current: f32, power: f32) -> Option<PowerCheckEvent> {
+        let expected = (voltage * current).abs();
+        if self.tolerance <= 0.0 || expected.max(power.abs()) < MIN_POWER_W {
+            return None;
+        }
+        let error = PowerCheck::error(voltage, current, power);
+        // A sample on the other side of the tolerance counts towards a change
+        if (error > self.tolerance) != self.inconsistent {
+            self.run += 1;
+        }
+        else {
+            self.run = 0;
+        }
+        if self.run < CHECK_SAMPLES {
+            return None;
+        }
+        self.run = 0;
+        self.inconsistent = !self.inconsistent;
+        if self.inconsistent {
+            Some(PowerCheckEvent::Inconsistent { power: power.abs(), expected: expected, error: error })
+        }
+        else {
+            Some(PowerCheckEvent::Consistent)
+        }
+    }
+}
@@ -0,0 +1,329 @@
+// CurrentLogs
+// CurrentLogs is a module to record the current, voltage, power, battery, temperature, and pwm.
+// It is used to record the data for the electric load.
+// Each record gets a sequence number. Records are removed when the server acknowledges them
+// (by sequence), or dropped by the firmware and counted as lost.
+// The buffer holds up to its capacity; when full it either refuses new records (Stop) or
+// drops the oldest one (Overwrite).
+// Besides the temperature used for the protection, a record keeps each temperature source
+// (GPIO18 sensor, INA228 die, AP33772S) in 0.1°C, and the USB PD request and the regulation
+// mode in place of the fan rpm (not fitted), so a record still takes 64 bytes.
+// TrendHistory keeps the averages of the voltage and current of a channel over the last
+// TREND_SECS, decimated to TREND_POINTS, for the sparklines on the main screen; it is fed
+// with every measurement whether or not the records are logged.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2024 Hiroshi Nakajima
+
+use log::*;
+use std::collections::VecDeque;
+
+// A temperature source without a reading
+pub const TEMP_NONE: i16 = i16::MIN;
+
+// Time shown by the sparklines and their points (one per pixel)
+pub const TREND_SECS: u32 = 60;
+pub const TREND_POINTS: usize = 36;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BufferPolicy {
+    Stop,
+    Overwrite,
+}
+
+// Regulation of the channel when the record was taken
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegulationMode {
+    Off,
+    // Constant voltage at the output terminals
+    Cv,
+    // Constant voltage at the load terminals (remote sense)
+    RemoteSense,
+    // Setpoint ramping down to a lower one
+    StepDown,
+}
+
+impl RegulationMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RegulationMode::Off => "off",
+            RegulationMode::Cv => "cv",
+            RegulationMode::RemoteSense => "sense",
+            RegulationMode::StepDown => "step_down",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CurrentLog {
+    pub voltage: f32,
+    pub current: f32,
+    pub power: f32,
+    pub clock: u128,
+    pub battery: f32,
+    pub temp: f32,
+    // Temperature sources in 0.1°C (TEMP_NONE without a reading)
+    pub temp_sensor: i16,
+    pub temp_ina228: i16,
+    pub temp_ap33772s: i16,
+    pub pwm: u32,
+    // USB PD contract requested (mV, mA), shared by the channels
+    pub pd_voltage_mv: u16,
+    pub pd_current_ma: u16,
+    pub mode: RegulationMode,
+    // Output channel (1 or 2)
+    pub channel: u8,
+    // Set by CurrentRecord::record, counts the records of all the channels
+    pub sequence: u64,
+}
+
+impl CurrentLog {
+    pub fn default() -> Self {
+        CurrentLog { 
+            voltage: 0.0,
+            current: 0.0,
+            power: 0.0,
+            clock: 0,
+            battery: 0.0,
+            temp: 0.0,
+            temp_sensor: TEMP_NONE,
+            temp_ina228: TEMP_NONE,
+            temp_ap33772s: TEMP_NONE,
+            pwm: 0,
+            pd_voltage_mv: 0,
+            pd_current_ma: 0,
+            mode: RegulationMode::Off,
+            channel: 1,
+            sequence: 0,
+         }
+    }
+
+    // GPIO18 sensor, INA228 die and AP33772S temperatures (°C)
+    pub fn set_source_temps(&mut self, sensor: Option<f32>, ina228: Option<f32>, ap33772s: Option<f32>) {
+        self.temp_sensor = pack_temp(sensor);
+        self.temp_ina228 = pack_temp(ina228);
+        self.temp_ap33772s = pack_temp(ap33772s);
+    }
+
+    pub fn get_source_temps(&self) -> [Option<f32>; 3] {
+        [unpack_temp(self.temp_sensor), unpack_temp(self.temp_ina228), unpack_temp(self.temp_ap33772s)]
+    }
+
+    // Power fed back into the output (negative power, see power)
+    pub fn is_reverse(&self) -> bool {
+        crate::power::is_reverse(self.power)
+    }
+
+    // USB PD request (V, mA)
+    pub fn set_pd_request(&mut self, voltage: f32, current_ma: u16) {
+        self.pd_voltage_mv = (voltage * 1000.0).round().clamp(0.0, u16::MAX as f32) as u16;
+        self.pd_current_ma = current_ma;
+    }
+}
+
+fn pack_temp(temp: Option<f32>) -> i16 {
+    match temp {
+        Some(t) if t.is_finite() => (t * 10.0).round().clamp(i16::MIN as f32 + 1.0, i16::MAX as f32) as i16,
+        _ => TEMP_NONE,
+    }
+}
+
+fn unpack_temp(temp: i16) -> Option<f32> {
+    if temp == TEMP_NONE {
+        None
+    }
+    else {
+        Some(temp as f32 / 10.0)
+    }
+}
+
+
+pub struct CurrentRecord {
+    rec: VecDeque<CurrentLog>,
+    capacity: usize,
+    policy: BufferPolicy,
+    next_sequence: u64,
+    lost: u64,
+}
+
+#[allow(dead_code)]
+impl CurrentRecord {
+    // Unbounded
+    pub fn new() -> CurrentRecord {
+        CurrentRecord { rec: VecDeque::new(), capacity: usize::MAX, policy: BufferPolicy::Stop, next_sequence: 0, lost: 0 }
+    }
+
+    // The buffer is allocated at once (in PSRAM on the target)
+    pub fn with_capacity(capacity: usize, policy: BufferPolicy) -> CurrentRecord {
+        CurrentRecord { rec: VecDeque::with_capacity(capacity), capacity: capacity, policy: policy, next_sequence: 0, lost: 0 }
+    }
+
+    // Returns the number of the records dropped for it (Overwrite), a refused record is not
+    // counted (Stop)
+    pub fn record(&mut self, mut data: CurrentLog) -> usize
+    {
+        let mut dropped = 0;
+        if self.is_full() {
+            if self.policy == BufferPolicy::Stop {
+                return 0;
+            }
+            self.remove_data(1);
+            dropped = 1;
+        }
+        data.sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.rec.push_back(data);
+        dropped
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.rec.len() >= self.capacity
+    }
+
+    pub fn get_capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn get_policy(&self) -> BufferPolicy {
+        self.policy
+    }
+
+    pub fn dump(&self)
+    {
+        info!("time,voltage,current,power,battery,temp,temp_sensor,temp_ina228,temp_ap33772s,pwm,pd_voltage_mv,pd_current_ma,mode,channel");
+        for it in &self.rec {
+           let temps = it.get_source_temps().map(|t| t.map_or(String::new(), |t| format!("{:.1}", t)));
+           info!("{},{},{},{},{},{},{},{},{},{},{},{},{},{}", it.clock, it.voltage, it.current, it.power, it.battery, it.temp,
+               temps[0], temps[1], temps[2], it.pwm, it.pd_voltage_mv, it.pd_current_ma, it.mode.as_str(), it.channel);
+        } 
+    }
+
+    pub fn clear(&mut self)
+    {
+        self.rec.clear()
+    }
+
+    pub fn get_size(&self) -> usize {
+        self.rec.len()    
+    }
+
+    pub fn get_all_data(&self) -> &VecDeque<CurrentLog> {
+        &self.rec
+    }
+
+    // Drop the oldest records without sending them (counted as lost)
+    pub fn remove_data(&mut self, size : usize){
+        let mut num = size;
+        if self.rec.len() < size {
+            num = self.rec.len();
+        }       
+        let _ = &self.rec.drain(0..num);
+        self.lost += num as u64;
+    }
+
+    // Remove the records up to the sequence, which the server has stored.
+    // Returns the number of the removed records.
+    pub fn acknowledge(&mut self, sequence: u64) -> usize
+    {
+        let num = self.rec.iter().take_while(|it| it.sequence <= sequence).count();
+        let _ = &self.rec.drain(0..num);
+        num
+    }
+
+    // Drop the records up to the sequence (counted as lost)
+    pub fn discard(&mut self, sequence: u64) -> usize
+    {
+        let num = self.rec.iter().take_while(|it| it.sequence <= sequence).count();
+        self.remove_data(num);
+        num
+    }
+
+    // Records dropped by remove_data
+    pub fn get_lost(&self) -> u64 {
+        self.lost
+    }
+
+}
+
+// Decimated history of the voltage and current of a channel
+pub struct TrendHistory {
+    points: usize,
+    samples_per_point: u32,
+    voltages: VecDeque<f32>,
+    currents: VecDeque<f32>,
+    // Sums of the point being taken
+    voltage_sum: f32,
+    current_sum: f32,
+    samples: u32,
+}
+
+impl TrendHistory {
+    pub fn new(points: usize, samples_per_point: u32) -> TrendHistory {
+        TrendHistory {
+            points: points,
+            samples_per_point: samples_per_point.max(1),
+            voltages: VecDeque::with_capacity(points),
+            currents: VecDeque::with_capacity(points),
+            voltage_sum: 0.0,
+            current_sum: 0.0,
+            samples: 0,
+        }
+    }
+
+    // Returns true when a point was completed
+    pub fn add(&mut self, voltage: f32, current: f32) -> bool {
+        self.voltage_sum += voltage;
+        self.current_sum += current;
+        self.samples += 1;
+        if self.samples < self.samples_per_point {
+            return false;
+        }
+        if self.voltages.len() >= self.points {
+            self.voltages.pop_front();
+            self.currents.pop_front();
+        }
+        self.voltages.push_back(self.voltage_sum / self.samples as f32);
+        self.currents.push_back(self.current_sum / self.samples as f32);
+        self.voltage_sum = 0.0;
+        self.current_sum = 0.0;
+        self.samples = 0;
+        true
+    }
+
+    // Oldest first
+    pub fn get_voltages(&self) -> Vec<f32> {
+        self.voltages.iter().copied().collect()
+    }
+
+    pub fn get_currents(&self) -> Vec<f32> {
+        self.currents.iter().copied().collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.voltages.clear();
+        self.currents.clear();
+        self.voltage_sum = 0.0;
+        self.current_sum = 0.0;
+        self.samples = 0;
+    }
+}
+
+// Pixel offsets (x, y from the top left) of a sparkline of the values in a width x height
+// box. The newest value is at the right edge, one pixel per point, so a history which is
+// not full yet starts further right. The values are scaled to their range, at least
+// min_span (so the noise of a steady output stays a flat line), around their middle.
+pub fn sparkline(values: &[f32], width: u32, height: u32, min_span: f32) -> Vec<(i32, i32)> {
+    let values = &values[values.len().saturating_sub(width as usize)..];
+    if values.is_empty() || height == 0 {
+        return Vec::new();
+    }
+    let min = values.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let span = (max - min).max(min_span);
+    let low = (min + max) / 2.0 - span / 2.0;
+    let start = width as i32 - values.len() as i32;
+    values.iter().enumerate().map(|(i, v)| {
+        let level = if span > 0.0 { (v - low) / span } else { 0.5 };
+        let y = (height - 1) as i32 - (level * (height - 1) as f32).round() as i32;
+        (start + i as i32, y)
+    }).collect()
+}
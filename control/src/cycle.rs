@@ -0,0 +1,101 @@
+// Duty-cycle endurance test
+// The output is turned on at the setpoint for on_ms and off for off_ms, repeated for the
+// number of cycles (0 to repeat until stopped). A cycle is completed at the end of its on
+// period. The test is aborted by any fault, and the completed cycles are kept.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use crate::hal::{Clock, SystemClock};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CyclePhase {
+    On,
+    Off,
+    Done,
+    Aborted(String),
+}
+
+pub struct CycleTest<C: Clock = SystemClock> {
+    setpoint: f32,
+    on_ns: u128,
+    off_ns: u128,
+    cycles: u32,
+    completed: u32,
+    phase: CyclePhase,
+    phase_start: u128,
+    clock: C,
+}
+
+impl CycleTest<SystemClock> {
+    pub fn new(setpoint: f32, on_ms: u32, off_ms: u32, cycles: u32) -> CycleTest {
+        CycleTest::with_clock(setpoint, on_ms, off_ms, cycles, SystemClock)
+    }
+}
+
+impl<C: Clock> CycleTest<C> {
+    // Starts with the on period of the first cycle
+    pub fn with_clock(setpoint: f32, on_ms: u32, off_ms: u32, cycles: u32, clock: C) -> CycleTest<C> {
+        CycleTest {
+            setpoint: setpoint,
+            on_ns: on_ms as u128 * 1_000_000,
+            off_ns: off_ms as u128 * 1_000_000,
+            cycles: cycles,
+            completed: 0,
+            phase: CyclePhase::On,
+            phase_start: clock.now_ns(),
+            clock: clock,
+        }
+    }
+
+    pub fn get_setpoint(&self) -> f32 {
+        self.setpoint
+    }
+
+    pub fn get_cycles(&self) -> u32 {
+        self.cycles
+    }
+
+    pub fn get_completed(&self) -> u32 {
+        self.completed
+    }
+
+    pub fn phase(&self) -> &CyclePhase {
+        &self.phase
+    }
+
+    // Whether the output should be on now
+    pub fn output(&self) -> bool {
+        self.phase == CyclePhase::On
+    }
+
+    pub fn is_running(&self) -> bool {
+        matches!(self.phase, CyclePhase::On | CyclePhase::Off)
+    }
+
+    // Stop the test by a fault or by the operator
+    pub fn abort(&mut self, reason: &str) {
+        if self.is_running() {
+            self.phase = CyclePhase::Aborted(reason.to_string());
+        }
+    }
+
+    // Advance the test. Returns the new phase when it changes.
+    pub fn poll(&mut self) -> Option<CyclePhase> {
+        let now = self.clock.now_ns();
+        let elapsed = now - self.phase_start;
+        match self.phase {
+            CyclePhase::On if elapsed >= self.on_ns => {
+                self.completed += 1;
+                self.phase = if self.cycles > 0 && self.completed >= self.cycles { CyclePhase::Done } else { CyclePhase::Off };
+            },
+            CyclePhase::Off if elapsed >= self.off_ns => {
+                self.phase = CyclePhase::On;
+            },
+            _ => return None,
+        }
+        self.phase_start = now;
+        Some(self.phase.clone())
+    }
+}
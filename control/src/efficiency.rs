@@ -0,0 +1,141 @@
+// Input energy metering and conversion efficiency
+// The input power (USB PD rail voltage times the input current) and the output power of
+// the channels are integrated into the input and output energy (Wh) by the clock of the
+// samples. Each interval gives the mean powers and the efficiency (output over input
+// energy). The efficiency is low when it falls below the warning level at a meaningful
+// output power, and recovers with a margin so it does not flap around the level.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+// A gap longer than this between two samples is not integrated (ns)
+const MAX_SAMPLE_GAP_NS: u128 = 5_000_000_000;
+// Below this output power the efficiency is dominated by the unit's own consumption (W)
+pub const MIN_OUTPUT_POWER: f32 = 0.5;
+// Recovery margin above the warning level (percentage points)
+const RECOVERY_MARGIN_PERCENT: f32 = 2.0;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct EfficiencyReport {
+    // Clock of the first and the last sample of the interval (ns)
+    pub start_clock: u128,
+    pub end_clock: u128,
+    // Mean powers of the interval (W)
+    pub input_power: f32,
+    pub output_power: f32,
+    // Output over input energy of the interval (%), None at a low output power
+    pub efficiency: Option<f32>,
+    // Totals since the start (Wh)
+    pub input_energy_wh: f64,
+    pub output_energy_wh: f64,
+    // Some(true) when the efficiency became low in this interval, Some(false) when it recovered
+    pub warning: Option<bool>,
+}
+
+pub struct EfficiencyMeter {
+    interval_ns: u128,
+    warn_percent: f32,
+    // Clock, input and output power of the last sample
+    last: Option<(u128, f32, f32)>,
+    start_clock: u128,
+    // Energies of the interval and the totals (Ws)
+    interval_input: f64,
+    interval_output: f64,
+    input_energy: f64,
+    output_energy: f64,
+    low: bool,
+}
+
+impl EfficiencyMeter {
+    pub fn new(interval_secs: u32, warn_percent: f32) -> EfficiencyMeter {
+        EfficiencyMeter {
+            interval_ns: interval_secs as u128 * 1_000_000_000,
+            warn_percent: warn_percent,
+            last: None,
+            start_clock: 0,
+            interval_input: 0.0,
+            interval_output: 0.0,
+            input_energy: 0.0,
+            output_energy: 0.0,
+            low: false,
+        }
+    }
+
+    pub fn get_input_energy_wh(&self) -> f64 {
+        self.input_energy / 3600.0
+    }
+
+    pub fn get_output_energy_wh(&self) -> f64 {
+        self.output_energy / 3600.0
+    }
+
+    pub fn is_low(&self) -> bool {
+        self.low
+    }
+
+    // Discard the partial interval (the outputs are off); the totals are kept
+    pub fn reset(&mut self) {
+        self.last = None;
+        self.interval_input = 0.0;
+        self.interval_output = 0.0;
+    }
+
+    // One sample of the input and output power (W). The powers are integrated with the
+    // trapezoidal rule. Returns the report at the end of each interval.
+    pub fn add(&mut self, clock: u128, input_power: f32, output_power: f32) -> Option<EfficiencyReport> {
+        let Some((last_clock, last_input, last_output)) = self.last else {
+            self.last = Some((clock, input_power, output_power));
+            self.start_clock = clock;
+            return None;
+        };
+        let dt = clock.saturating_sub(last_clock);
+        if dt <= MAX_SAMPLE_GAP_NS {
+            let secs = dt as f64 / 1_000_000_000.0;
+            let input = (last_input + input_power) as f64 / 2.0 * secs;
+            let output = (last_output + output_power) as f64 / 2.0 * secs;
+            self.interval_input += input;
+            self.interval_output += output;
+            self.input_energy += input;
+            self.output_energy += output;
+        }
+        self.last = Some((clock, input_power, output_power));
+        let elapsed = clock.saturating_sub(self.start_clock);
+        if self.interval_ns == 0 || elapsed < self.interval_ns {
+            return None;
+        }
+        let secs = elapsed as f64 / 1_000_000_000.0;
+        let output_power = (self.interval_output / secs) as f32;
+        let efficiency = if output_power >= MIN_OUTPUT_POWER && self.interval_input > 0.0 {
+            Some((self.interval_output / self.interval_input * 100.0) as f32)
+        }
+        else {
+            None
+        };
+        let mut warning = None;
+        if let Some(efficiency) = efficiency {
+            if !self.low && efficiency < self.warn_percent {
+                self.low = true;
+                warning = Some(true);
+            }
+            else if self.low && efficiency >= self.warn_percent + RECOVERY_MARGIN_PERCENT {
+                self.low = false;
+                warning = Some(false);
+            }
+        }
+        let report = EfficiencyReport {
+            start_clock: self.start_clock,
+            end_clock: clock,
+            input_power: (self.interval_input / secs) as f32,
+            output_power: output_power,
+            efficiency: efficiency,
+            input_energy_wh: self.get_input_energy_wh(),
+            output_energy_wh: self.get_output_energy_wh(),
+            warning: warning,
+        };
+        self.start_clock = clock;
+        self.interval_input = 0.0;
+        self.interval_output = 0.0;
+        Some(report)
+    }
+}
@@ -0,0 +1,126 @@
+// Error counters kept across reboots
+// The unit counts the boots, watchdog resets, over-current and over-temperature trips, I2C
+// errors and failed USB PD requests over its lifetime, so a unit which keeps failing the
+// same way shows it in its history. The counters are stored in NVS as a versioned blob; a
+// change is written at most once per SAVE_PERIOD_MS to spare the flash.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+// Least time between two writes to NVS (ms)
+pub const SAVE_PERIOD_MS: u64 = 60_000;
+// Version of the stored blob, the counters follow as u32 (little endian)
+const BLOB_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Counter {
+    Boot,
+    WatchdogReset,
+    OverCurrentTrip,
+    OverTemperatureTrip,
+    I2cError,
+    PdFailure,
+}
+
+// In the order of the blob; new counters are added at the end
+pub const COUNTERS: [Counter; 6] = [
+    Counter::Boot,
+    Counter::WatchdogReset,
+    Counter::OverCurrentTrip,
+    Counter::OverTemperatureTrip,
+    Counter::I2cError,
+    Counter::PdFailure,
+];
+
+impl Counter {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Counter::Boot => "boots",
+            Counter::WatchdogReset => "watchdog_resets",
+            Counter::OverCurrentTrip => "over_current_trips",
+            Counter::OverTemperatureTrip => "over_temperature_trips",
+            Counter::I2cError => "i2c_errors",
+            Counter::PdFailure => "pd_failures",
+        }
+    }
+
+    fn index(&self) -> usize {
+        COUNTERS.iter().position(|c| c == self).unwrap_or(0)
+    }
+}
+
+pub struct ErrorCounters {
+    counts: [u32; COUNTERS.len()],
+    // Changed since the last write, and when it was written
+    dirty: bool,
+    saved_ms: Option<u64>,
+}
+
+impl ErrorCounters {
+    pub fn new() -> ErrorCounters {
+        ErrorCounters { counts: [0; COUNTERS.len()], dirty: false, saved_ms: None }
+    }
+
+    // Counters from the stored blob; a blob of an earlier version with fewer counters leaves
+    // the new ones at 0, a blob of an unknown version is not read
+    pub fn decode(blob: &[u8]) -> Option<ErrorCounters> {
+        let mut words = blob.chunks_exact(4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+        if words.next()? != BLOB_VERSION {
+            return None;
+        }
+        let mut counters = ErrorCounters::new();
+        for (count, value) in counters.counts.iter_mut().zip(words) {
+            *count = value;
+        }
+        Some(counters)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut blob = BLOB_VERSION.to_le_bytes().to_vec();
+        for count in &self.counts {
+            blob.extend_from_slice(&count.to_le_bytes());
+        }
+        blob
+    }
+
+    pub fn add(&mut self, counter: Counter, count: u32) {
+        if count > 0 {
+            let value = &mut self.counts[counter.index()];
+            *value = value.saturating_add(count);
+            self.dirty = true;
+        }
+    }
+
+    pub fn get(&self, counter: Counter) -> u32 {
+        self.counts[counter.index()]
+    }
+
+    // Name and count of each counter
+    pub fn entries(&self) -> Vec<(&'static str, u32)> {
+        COUNTERS.iter().map(|c| (c.name(), self.get(*c))).collect()
+    }
+
+    pub fn reset(&mut self) {
+        self.counts = [0; COUNTERS.len()];
+        self.dirty = true;
+        self.saved_ms = None;
+    }
+
+    // True when a change should be written now: the first change at once, then at most once per period
+    pub fn is_save_due(&self, now_ms: u64) -> bool {
+        match self.saved_ms {
+            Some(saved) => self.dirty && now_ms.saturating_sub(saved) >= SAVE_PERIOD_MS,
+            None => self.dirty,
+        }
+    }
+
+    pub fn saved(&mut self, now_ms: u64) {
+        self.dirty = false;
+        self.saved_ms = Some(now_ms);
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+}
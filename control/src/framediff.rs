@@ -0,0 +1,54 @@
+// Changed regions of a display frame
+// The frame is compared with the one on the panel in tiles; the changed tiles next to each
+// other in a row of tiles are merged into one region, so a changed digit is sent as a few
+// small windows instead of the whole frame. When most of the frame changed, the whole frame
+// is one region (a window costs a few command bytes).
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+pub const TILE_WIDTH: usize = 16;
+pub const TILE_HEIGHT: usize = 8;
+// Above this share of the pixels in the changed tiles the whole frame is sent (%)
+pub const FULL_FRAME_PERCENT: usize = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+// Regions of the frame (width x height pixels, row by row) which differ from the previous one
+pub fn changed_regions(previous: &[u16], frame: &[u16], width: usize, height: usize) -> Vec<Region> {
+    let mut regions : Vec<Region> = Vec::new();
+    let mut changed_pixels = 0;
+    for y in (0..height).step_by(TILE_HEIGHT) {
+        let tile_height = TILE_HEIGHT.min(height - y);
+        let mut run : Option<Region> = None;
+        for x in (0..width).step_by(TILE_WIDTH) {
+            let tile_width = TILE_WIDTH.min(width - x);
+            let changed = (y..y + tile_height).any(|row| {
+                let start = row * width + x;
+                previous[start..start + tile_width] != frame[start..start + tile_width]
+            });
+            if changed {
+                changed_pixels += tile_width * tile_height;
+                match run.as_mut() {
+                    Some(region) => region.width += tile_width,
+                    None => run = Some(Region { x: x, y: y, width: tile_width, height: tile_height }),
+                }
+            }
+            else if let Some(region) = run.take() {
+                regions.push(region);
+            }
+        }
+        regions.extend(run);
+    }
+    if changed_pixels * 100 > width * height * FULL_FRAME_PERCENT {
+        return vec![Region { x: 0, y: 0, width: width, height: height }];
+    }
+    regions
+}
@@ -0,0 +1,119 @@
+// Start/stop gesture of the front panel
+// A long press of the center key toggles the output by default. It can also be a double tap
+// of the center key, whose single tap (clear the message and select the next setpoint) is
+// then taken once the double-tap window has passed without a second tap, or a push button
+// wired to an external input, debounced here, for a unit mounted in a rack.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+// Longest time between the two taps of a double tap (ms)
+pub const DOUBLE_TAP_MS: u64 = 400;
+// Time the external input has to be stable (ms)
+pub const DEBOUNCE_MS: u64 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StartStopGesture {
+    CenterLong,
+    CenterDouble,
+    Input,
+}
+
+impl StartStopGesture {
+    pub fn parse(text: &str) -> Option<StartStopGesture> {
+        match text {
+            "center_long" => Some(StartStopGesture::CenterLong),
+            "center_double" => Some(StartStopGesture::CenterDouble),
+            "input" => Some(StartStopGesture::Input),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StartStopGesture::CenterLong => "center_long",
+            StartStopGesture::CenterDouble => "center_double",
+            StartStopGesture::Input => "input",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tap {
+    Single,
+    Double,
+}
+
+pub struct TapDetector {
+    window_ms: u64,
+    // Time of a tap waiting for a second one
+    pending: Option<u64>,
+}
+
+impl TapDetector {
+    pub fn new(window_ms: u64) -> TapDetector {
+        TapDetector { window_ms: window_ms, pending: None }
+    }
+
+    // A tap; Double if it is the second one within the window
+    pub fn press(&mut self, now_ms: u64) -> Option<Tap> {
+        match self.pending.take() {
+            Some(first) if now_ms.saturating_sub(first) <= self.window_ms => Some(Tap::Double),
+            _ => {
+                self.pending = Some(now_ms);
+                None
+            },
+        }
+    }
+
+    // Single once the window of a tap has passed without a second one
+    pub fn poll(&mut self, now_ms: u64) -> Option<Tap> {
+        match self.pending {
+            Some(first) if now_ms.saturating_sub(first) > self.window_ms => {
+                self.pending = None;
+                Some(Tap::Single)
+            },
+            _ => None,
+        }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.pending = None;
+    }
+}
+
+pub struct ButtonDebounce {
+    pressed: bool,
+    // Level read last and since when
+    level: bool,
+    since_ms: u64,
+}
+
+impl ButtonDebounce {
+    pub fn new() -> ButtonDebounce {
+        ButtonDebounce { pressed: false, level: false, since_ms: 0 }
+    }
+
+    // The level read (true = pressed); true once per press, when it has been stable for DEBOUNCE_MS
+    pub fn update(&mut self, level: bool, now_ms: u64) -> bool {
+        if level != self.level {
+            self.level = level;
+            self.since_ms = now_ms;
+            return false;
+        }
+        if level != self.pressed && now_ms.saturating_sub(self.since_ms) >= DEBOUNCE_MS {
+            self.pressed = level;
+            return level;
+        }
+        false
+    }
+
+    pub fn is_pressed(&self) -> bool {
+        self.pressed
+    }
+}
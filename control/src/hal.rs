@@ -0,0 +1,41 @@
+// Hardware abstraction of the control loop
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use std::sync::OnceLock;
+use std::time::Instant;
+
+// Monotonic time source in nanoseconds
+pub trait Clock {
+    fn now_ns(&self) -> u128;
+}
+
+// Time since the first reading in the process, which a change of the wall clock (NTP) does not
+// move back
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+static START: OnceLock<Instant> = OnceLock::new();
+
+impl Clock for SystemClock {
+    fn now_ns(&self) -> u128 {
+        START.get_or_init(Instant::now).elapsed().as_nanos()
+    }
+}
+
+// PWM which sets the output of the buck stage (LEDC on the unit)
+pub trait PowerStage {
+    fn max_duty(&self) -> u32;
+    fn set_duty(&mut self, duty: u32);
+}
+
+// Output measurement (INA228 on the unit)
+pub trait OutputSensor {
+    fn read_voltage(&mut self) -> f32;
+    fn read_current(&mut self) -> f32;
+    fn read_power(&mut self) -> f32;
+    // Voltage at the DUT terminals (remote sense), None without it
+    fn read_remote_voltage(&mut self) -> Option<f32> {
+        None
+    }
+}
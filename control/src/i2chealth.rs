@@ -0,0 +1,154 @@
+// I2C bus health
+// Every transfer with a device (the regular reads and the periodic pings) is counted as
+// an acknowledge, a NACK or a timeout. A device is offline after a run of failures, and
+// online again with the next good transfer; only these changes are reported, so a flaky
+// cable does not flood the log. The bus is taken as hung, and is to be recovered, when
+// all the devices are failing or a device keeps timing out (SDA held low).
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+// Consecutive failures before a device is offline
+pub const OFFLINE_FAILURES: u32 = 10;
+// Consecutive timeouts of a device which mean a hung bus
+const HUNG_TIMEOUTS: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum I2cOutcome {
+    Ok,
+    Nack,
+    Timeout,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceHealth {
+    pub name: &'static str,
+    pub addr: u8,
+    pub transfers: u64,
+    pub nacks: u64,
+    pub timeouts: u64,
+    pub consecutive_failures: u32,
+    // Failures since the last recovery or good transfer
+    failures_since_recovery: u32,
+    consecutive_timeouts: u32,
+}
+
+impl DeviceHealth {
+    // Failed transfers (%)
+    pub fn error_rate(&self) -> f32 {
+        if self.transfers == 0 {
+            0.0
+        }
+        else {
+            (self.nacks + self.timeouts) as f32 / self.transfers as f32 * 100.0
+        }
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.consecutive_failures < OFFLINE_FAILURES
+    }
+
+    pub fn to_text(&self) -> String {
+        format!("{} (0x{:02x}): {}, {} transfers, {} NACK, {} timeout ({:.2}%)", self.name, self.addr,
+            if self.is_online() { "online" } else { "offline" }, self.transfers, self.nacks, self.timeouts, self.error_rate())
+    }
+}
+
+// A change of the state of a device
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeviceEvent {
+    Offline(&'static str, u8, I2cOutcome),
+    Online(&'static str, u8),
+}
+
+pub struct I2cHealth {
+    devices: Vec<DeviceHealth>,
+    recoveries: u32,
+    failed_recoveries: u32,
+}
+
+impl I2cHealth {
+    pub fn new(devices: &[(&'static str, u8)]) -> I2cHealth {
+        I2cHealth {
+            devices: devices.iter().map(|(name, addr)| DeviceHealth {
+                name: name,
+                addr: *addr,
+                transfers: 0,
+                nacks: 0,
+                timeouts: 0,
+                consecutive_failures: 0,
+                failures_since_recovery: 0,
+                consecutive_timeouts: 0,
+            }).collect(),
+            recoveries: 0,
+            failed_recoveries: 0,
+        }
+    }
+
+    pub fn get_devices(&self) -> &[DeviceHealth] {
+        &self.devices
+    }
+
+    pub fn get_recoveries(&self) -> u32 {
+        self.recoveries
+    }
+
+    pub fn get_failed_recoveries(&self) -> u32 {
+        self.failed_recoveries
+    }
+
+    // Count a transfer with the device (unknown addresses are ignored). Returns the change
+    // of its state.
+    pub fn record(&mut self, addr: u8, outcome: I2cOutcome) -> Option<DeviceEvent> {
+        let device = self.devices.iter_mut().find(|d| d.addr == addr)?;
+        let was_online = device.is_online();
+        device.transfers += 1;
+        match outcome {
+            I2cOutcome::Ok => {
+                device.consecutive_failures = 0;
+                device.failures_since_recovery = 0;
+                device.consecutive_timeouts = 0;
+            },
+            I2cOutcome::Nack => {
+                device.nacks += 1;
+                device.consecutive_failures += 1;
+                device.failures_since_recovery += 1;
+                device.consecutive_timeouts = 0;
+            },
+            I2cOutcome::Timeout => {
+                device.timeouts += 1;
+                device.consecutive_failures += 1;
+                device.failures_since_recovery += 1;
+                device.consecutive_timeouts += 1;
+            },
+        }
+        match (was_online, device.is_online()) {
+            (true, false) => Some(DeviceEvent::Offline(device.name, device.addr, outcome)),
+            (false, true) => Some(DeviceEvent::Online(device.name, device.addr)),
+            _ => None,
+        }
+    }
+
+    // All the devices are failing, or one keeps timing out
+    pub fn is_hung(&self) -> bool {
+        !self.devices.is_empty()
+            && (self.devices.iter().all(|d| d.failures_since_recovery >= OFFLINE_FAILURES)
+                || self.devices.iter().any(|d| d.consecutive_timeouts >= HUNG_TIMEOUTS))
+    }
+
+    // Result of a bus recovery (SDA released). The failure runs start again, so a
+    // recovery which did not help is tried again after the next run of failures.
+    pub fn recovered(&mut self, released: bool) {
+        if released {
+            self.recoveries += 1;
+        }
+        else {
+            self.failed_recoveries += 1;
+        }
+        for device in self.devices.iter_mut() {
+            device.failures_since_recovery = 0;
+            device.consecutive_timeouts = 0;
+        }
+    }
+}
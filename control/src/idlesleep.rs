@@ -0,0 +1,89 @@
+// Low-power idle of the unit
+// A unit left on the bench with the outputs off draws its idle current from the charger it
+// is testing. After idle_sleep_secs without a key, a console command or an API request, and
+// with nothing running (outputs, tests), the unit sleeps: the display is blanked, WiFi goes
+// to modem sleep and the measurements are taken at the housekeeping rate. Any activity wakes
+// it at once; a key which wakes the unit is not taken as a key press.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use crate::hal::{Clock, SystemClock};
+
+pub struct IdleSleep<C: Clock = SystemClock> {
+    // 0 to never sleep
+    timeout_secs: u32,
+    last_activity: u128,
+    sleeping: bool,
+    // Woken by an activity, reported by the next update
+    woken: bool,
+    clock: C,
+}
+
+impl IdleSleep<SystemClock> {
+    pub fn new(timeout_secs: u32) -> IdleSleep {
+        IdleSleep::with_clock(timeout_secs, SystemClock)
+    }
+}
+
+impl<C: Clock> IdleSleep<C> {
+    pub fn with_clock(timeout_secs: u32, clock: C) -> IdleSleep<C> {
+        let now = clock.now_ns();
+        IdleSleep {
+            timeout_secs: timeout_secs,
+            last_activity: now,
+            sleeping: false,
+            woken: false,
+            clock: clock,
+        }
+    }
+
+    // A new timeout counts from now
+    pub fn set_timeout(&mut self, timeout_secs: u32) {
+        self.timeout_secs = timeout_secs;
+        self.last_activity = self.clock.now_ns();
+    }
+
+    pub fn get_timeout(&self) -> u32 {
+        self.timeout_secs
+    }
+
+    pub fn is_sleeping(&self) -> bool {
+        self.sleeping
+    }
+
+    // A key, a command or a request. Returns true if it wakes the unit.
+    pub fn activity(&mut self) -> bool {
+        self.last_activity = self.clock.now_ns();
+        if self.sleeping {
+            self.sleeping = false;
+            self.woken = true;
+            return true;
+        }
+        false
+    }
+
+    // busy: an output is on or a test is running. Returns Some(true) when the unit falls
+    // asleep and Some(false) when it wakes up.
+    pub fn update(&mut self, busy: bool) -> Option<bool> {
+        let now = self.clock.now_ns();
+        if busy || self.timeout_secs == 0 {
+            self.last_activity = now;
+            if self.sleeping {
+                self.sleeping = false;
+                self.woken = true;
+            }
+        }
+        if self.woken {
+            self.woken = false;
+            return Some(false);
+        }
+        if !busy && self.timeout_secs > 0 && !self.sleeping
+            && now - self.last_activity >= self.timeout_secs as u128 * 1_000_000_000 {
+            self.sleeping = true;
+            return Some(true);
+        }
+        None
+    }
+}
@@ -0,0 +1,126 @@
+// Latency of the control path
+// The time from the start of an acquisition (the control timer tick: the INA228 converts
+// continuously and the last conversion is read at the tick) to the new PWM duty is recorded
+// in a histogram of BUCKET_US wide buckets, from which the percentiles are estimated without
+// keeping the samples. A cycle longer than the budget is counted, so a stall of the control
+// task (e.g. by a lock held on the I2C bus or a higher-priority task) can be seen. With
+// wider buckets it also takes the slower paths, e.g. from a touch to the action of the key.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+// Width of a bucket of the control path (us)
+pub const BUCKET_US: u32 = 10;
+// 0 to 5ms with BUCKET_US, the longer ones are counted in the last bucket
+pub const BUCKETS: usize = 500;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LatencyReport {
+    pub samples: u32,
+    // Upper edge of the bucket of the percentile, at most the maximum (us)
+    pub p50_us: u32,
+    pub p99_us: u32,
+    pub max_us: u32,
+    // Cycles over the budget, and the budget (us, 0 without one)
+    pub over_budget: u32,
+    pub budget_us: u32,
+}
+
+pub struct LatencyStats {
+    // 0 to count no cycle over the budget
+    budget_us: u32,
+    bucket_us: u32,
+    buckets: Vec<u32>,
+    samples: u32,
+    max_us: u32,
+    over_budget: u32,
+    // Since the last take_over_budget
+    window_over_budget: u32,
+    window_max_us: u32,
+}
+
+impl LatencyStats {
+    pub fn new(budget_us: u32) -> LatencyStats {
+        LatencyStats::with_bucket(budget_us, BUCKET_US)
+    }
+
+    // Buckets of bucket_us, covering BUCKETS times that
+    pub fn with_bucket(budget_us: u32, bucket_us: u32) -> LatencyStats {
+        LatencyStats {
+            budget_us: budget_us,
+            bucket_us: bucket_us.max(1),
+            buckets: vec![0; BUCKETS],
+            samples: 0,
+            max_us: 0,
+            over_budget: 0,
+            window_over_budget: 0,
+            window_max_us: 0,
+        }
+    }
+
+    pub fn set_budget(&mut self, budget_us: u32) {
+        self.budget_us = budget_us;
+    }
+
+    pub fn get_budget(&self) -> u32 {
+        self.budget_us
+    }
+
+    // Latency of a control cycle (us)
+    pub fn record(&mut self, latency_us: u32) {
+        let bucket = ((latency_us / self.bucket_us) as usize).min(BUCKETS - 1);
+        self.buckets[bucket] = self.buckets[bucket].saturating_add(1);
+        self.samples = self.samples.saturating_add(1);
+        self.max_us = self.max_us.max(latency_us);
+        self.window_max_us = self.window_max_us.max(latency_us);
+        if self.budget_us > 0 && latency_us > self.budget_us {
+            self.over_budget += 1;
+            self.window_over_budget += 1;
+        }
+    }
+
+    // Cycles over the budget and the longest latency since the last call
+    pub fn take_over_budget(&mut self) -> (u32, u32) {
+        let window = (self.window_over_budget, self.window_max_us);
+        self.window_over_budget = 0;
+        self.window_max_us = 0;
+        window
+    }
+
+    // Latency of the fraction of the cycles (0.0 to 1.0), 0 without a sample
+    pub fn percentile(&self, fraction: f32) -> u32 {
+        if self.samples == 0 {
+            return 0;
+        }
+        let rank = ((self.samples as f32 * fraction).ceil() as u32).clamp(1, self.samples);
+        let mut count = 0;
+        for (index, n) in self.buckets.iter().enumerate() {
+            count += n;
+            if count >= rank {
+                return if index == BUCKETS - 1 { self.max_us } else { ((index as u32 + 1) * self.bucket_us).min(self.max_us) };
+            }
+        }
+        self.max_us
+    }
+
+    pub fn report(&self) -> LatencyReport {
+        LatencyReport {
+            samples: self.samples,
+            p50_us: self.percentile(0.5),
+            p99_us: self.percentile(0.99),
+            max_us: self.max_us,
+            over_budget: self.over_budget,
+            budget_us: self.budget_us,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.buckets.iter_mut().for_each(|n| *n = 0);
+        self.samples = 0;
+        self.max_us = 0;
+        self.over_budget = 0;
+        self.window_over_budget = 0;
+        self.window_max_us = 0;
+    }
+}
@@ -0,0 +1,61 @@
+// Control logic of the DC power unit
+// PID regulation, protection limits, restart-after-fault sequencing and measurement logs.
+// The hardware is accessed through the traits in hal, so the logic can be built and
+// tested on the host against the simulated plant in sim.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+// Keep the coding style of the firmware modules
+#![allow(clippy::redundant_field_names, clippy::new_without_default, clippy::should_implement_trait)]
+
+pub mod hal;
+pub mod pidcont;
+pub mod regulator;
+pub mod limits;
+pub mod softlimit;
+pub mod thermal;
+pub mod recovery;
+pub mod regstats;
+pub mod ripple;
+pub mod cable;
+pub mod capture;
+pub mod summary;
+pub mod session;
+pub mod schedule;
+pub mod alarms;
+pub mod cycle;
+pub mod testscript;
+pub mod pdprobe;
+pub mod pdcal;
+pub mod offsetcal;
+pub mod prodtest;
+pub mod errorcounters;
+pub mod pwmoffset;
+pub mod bleed;
+pub mod stepdown;
+pub mod pidtrace;
+pub mod unitsync;
+pub mod share;
+pub mod peertelemetry;
+pub mod limitlog;
+pub mod power;
+pub mod uploadthrottle;
+pub mod idlesleep;
+pub mod rawframe;
+pub mod latency;
+pub mod perfstats;
+pub mod framediff;
+pub mod gesture;
+pub mod touchbaseline;
+pub mod apiauth;
+pub mod efficiency;
+pub mod i2chealth;
+pub mod units;
+pub mod sense;
+pub mod adcfilter;
+pub mod stale;
+pub mod consistency;
+pub mod marker;
+pub mod scopetrigger;
+pub mod currentlogs;
+pub mod sim;
@@ -0,0 +1,96 @@
+// Annotations of the setpoint and limit changes
+// The main loop watches the setpoints and limits of the channels, whatever changed them (the
+// touch keys, the console, the HTTP API, a schedule, ...), and sends each change as an
+// event with the old and the new value and the source, for the dashboards to annotate the
+// waveform with. A value adjusted in steps (a key held down) is reported once it has not
+// changed for the settle time, from the value before the first step, so one adjustment is
+// one annotation.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use crate::hal::{Clock, SystemClock};
+
+// A change is reported after the value is unchanged for this long (ms)
+pub const SETTLE_MS: u128 = 1000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LimitChange {
+    pub name: &'static str,
+    pub old: f32,
+    pub new: f32,
+    // Source of the last step of the change
+    pub source: &'static str,
+}
+
+struct Watched {
+    name: &'static str,
+    // Value last reported (or first seen)
+    reported: f32,
+    value: f32,
+    source: &'static str,
+    changed: Option<u128>,
+}
+
+pub struct LimitLog<C: Clock = SystemClock> {
+    watched: Vec<Watched>,
+    clock: C,
+}
+
+impl LimitLog<SystemClock> {
+    pub fn new() -> LimitLog {
+        LimitLog::with_clock(SystemClock)
+    }
+}
+
+impl<C: Clock> LimitLog<C> {
+    pub fn with_clock(clock: C) -> LimitLog<C> {
+        LimitLog {
+            watched: Vec::new(),
+            clock: clock,
+        }
+    }
+
+    // The value of a setpoint or limit, and what changed it if it differs from the last
+    // value. The first value of a name is taken as it is, without a change.
+    pub fn watch(&mut self, name: &'static str, value: f32, source: &'static str) {
+        let now = self.clock.now_ns();
+        match self.watched.iter_mut().find(|w| w.name == name) {
+            Some(w) => {
+                if value != w.value {
+                    w.value = value;
+                    w.source = source;
+                    w.changed = Some(now);
+                }
+            },
+            None => self.watched.push(Watched {
+                name: name,
+                reported: value,
+                value: value,
+                source: source,
+                changed: None,
+            }),
+        }
+    }
+
+    // The changes settled since the last call. A value set back to the reported one is
+    // not a change.
+    pub fn take(&mut self) -> Vec<LimitChange> {
+        let now = self.clock.now_ns();
+        let mut changes = Vec::new();
+        for w in self.watched.iter_mut() {
+            match w.changed {
+                Some(changed) if now - changed >= SETTLE_MS * 1_000_000 => {
+                    w.changed = None;
+                    if w.value != w.reported {
+                        changes.push(LimitChange { name: w.name, old: w.reported, new: w.value, source: w.source });
+                        w.reported = w.value;
+                    }
+                },
+                _ => {},
+            }
+        }
+        changes
+    }
+}
@@ -0,0 +1,221 @@
+// Protection limits of the output
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use crate::recovery::TripCause;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProtectionLimits {
+    pub max_current: f32,
+    pub max_power: f32,
+    pub max_temperature: f32,
+}
+
+impl ProtectionLimits {
+    pub fn new(max_current: f32, max_power: f32, max_temperature: f32) -> ProtectionLimits {
+        ProtectionLimits { max_current, max_power, max_temperature }
+    }
+
+    // Over-current has priority over over-power
+    pub fn check_electrical(&self, current: f32, power: f32) -> Option<TripCause> {
+        if current > self.max_current {
+            Some(TripCause::OverCurrent)
+        }
+        else if power > self.max_power {
+            Some(TripCause::OverPower)
+        }
+        else {
+            None
+        }
+    }
+
+    pub fn check_temperature(&self, temp: f32) -> Option<TripCause> {
+        if temp > self.max_temperature {
+            Some(TripCause::OverTemperature)
+        }
+        else {
+            None
+        }
+    }
+}
+
+// Short circuit: the output voltage collapsed below the threshold voltage, after it had reached
+// it since the start, with the current above the threshold current in the same sample.
+// Not armed before the output reaches the threshold, so the inrush of a start and a setpoint
+// below the threshold are not a short circuit.
+#[derive(Debug, Clone, Copy)]
+pub struct ShortCircuitDetector {
+    pub voltage: f32,
+    pub current: f32,
+    armed: bool,
+}
+
+impl ShortCircuitDetector {
+    pub fn new(voltage: f32, current: f32) -> ShortCircuitDetector {
+        ShortCircuitDetector { voltage, current, armed: false }
+    }
+
+    pub fn disabled() -> ShortCircuitDetector {
+        ShortCircuitDetector::new(0.0, f32::INFINITY)
+    }
+
+    // Output started
+    pub fn reset(&mut self) {
+        self.armed = false;
+    }
+
+    pub fn check(&mut self, voltage: f32, current: f32) -> Option<TripCause> {
+        if voltage >= self.voltage {
+            self.armed = true;
+            None
+        }
+        else if self.armed && current > self.current {
+            Some(TripCause::ShortCircuit)
+        }
+        else {
+            None
+        }
+    }
+}
+
+// Over-voltage: the output above the absolute limit for a number of samples in a row,
+// whatever the setpoint, to protect a low voltage DUT from a wrong setpoint or a failed
+// regulation. A single noisy sample does not trip it.
+#[derive(Debug, Clone, Copy)]
+pub struct OverVoltageDetector {
+    pub voltage: f32,
+    pub samples: u32,
+    count: u32,
+}
+
+impl OverVoltageDetector {
+    pub fn new(voltage: f32, samples: u32) -> OverVoltageDetector {
+        OverVoltageDetector { voltage, samples: samples.max(1), count: 0 }
+    }
+
+    pub fn disabled() -> OverVoltageDetector {
+        OverVoltageDetector::new(f32::INFINITY, 1)
+    }
+
+    // Output started
+    pub fn reset(&mut self) {
+        self.count = 0;
+    }
+
+    pub fn check(&mut self, voltage: f32) -> Option<TripCause> {
+        if voltage > self.voltage {
+            self.count += 1;
+        }
+        else {
+            self.count = 0;
+        }
+        if self.count >= self.samples {
+            Some(TripCause::OverVoltage)
+        }
+        else {
+            None
+        }
+    }
+}
+
+// Safe mode: the setpoints and current limits of all the channels are capped whatever the
+// other settings, the panel or a remote client set, for a unit lent to students.
+pub const SAFE_MODE_MAX_VOLTAGE: f32 = 5.0;
+pub const SAFE_MODE_MAX_CURRENT: f32 = 1.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SafeMode {
+    pub enabled: bool,
+}
+
+impl SafeMode {
+    pub fn new(enabled: bool) -> SafeMode {
+        SafeMode { enabled: enabled }
+    }
+
+    pub fn voltage(&self, voltage: f32) -> f32 {
+        if self.enabled { voltage.min(SAFE_MODE_MAX_VOLTAGE) } else { voltage }
+    }
+
+    pub fn current(&self, current: f32) -> f32 {
+        if self.enabled { current.min(SAFE_MODE_MAX_CURRENT) } else { current }
+    }
+}
+
+// Pre-warning: the current or the power above a share of its limit, before the limit trips
+// the output. Each is reported once when it goes above the share, and again only after it
+// was back below the share less WARN_HYSTERESIS_PERCENT, so a reading around the share does
+// not repeat the warning.
+pub const WARN_HYSTERESIS_PERCENT: f32 = 5.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LimitKind {
+    Current,
+    Power,
+}
+
+impl LimitKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LimitKind::Current => "current",
+            LimitKind::Power => "power",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LimitWarning {
+    // The reading and the limit
+    Above(LimitKind, f32, f32),
+    Cleared(LimitKind),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LimitPreWarning {
+    // Share of the limit (%), 0 to disable
+    pub percent: f32,
+    current: bool,
+    power: bool,
+}
+
+impl LimitPreWarning {
+    pub fn new(percent: f32) -> LimitPreWarning {
+        LimitPreWarning { percent: percent, current: false, power: false }
+    }
+
+    pub fn is_warning(&self, kind: LimitKind) -> bool {
+        match kind {
+            LimitKind::Current => self.current,
+            LimitKind::Power => self.power,
+        }
+    }
+
+    // Output off: no warning, without reporting it
+    pub fn reset(&mut self) {
+        self.current = false;
+        self.power = false;
+    }
+
+    pub fn check(&mut self, limits: &ProtectionLimits, current: f32, power: f32) -> Vec<LimitWarning> {
+        let mut warnings = Vec::new();
+        if self.percent <= 0.0 {
+            self.reset();
+            return warnings;
+        }
+        for (kind, value, limit) in [(LimitKind::Current, current, limits.max_current), (LimitKind::Power, power, limits.max_power)] {
+            let warning = match kind {
+                LimitKind::Current => &mut self.current,
+                LimitKind::Power => &mut self.power,
+            };
+            if !*warning && value > limit * self.percent / 100.0 {
+                *warning = true;
+                warnings.push(LimitWarning::Above(kind, value, limit));
+            }
+            else if *warning && value < limit * (self.percent - WARN_HYSTERESIS_PERCENT) / 100.0 {
+                *warning = false;
+                warnings.push(LimitWarning::Cleared(kind));
+            }
+        }
+        warnings
+    }
+}
@@ -0,0 +1,89 @@
+// Timestamped markers
+// A marker is put in the log and the InfluxDB events by the operator at the moment of an
+// observation during a test ("DUT started whining here"), so it lines up with the
+// measurements afterwards. It comes from the marker key on the panel, the console or the
+// HTTP API, with an optional text. The markers are numbered from 1 since boot, so the ones
+// of a test can be told apart on a chart.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+pub const MAX_MARKER_TEXT_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MarkerSource {
+    Key,
+    Console,
+    Api,
+}
+
+impl MarkerSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MarkerSource::Key => "key",
+            MarkerSource::Console => "console",
+            MarkerSource::Api => "api",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Marker {
+    pub number: u32,
+    pub source: MarkerSource,
+    pub text: String,
+    // Wall clock of the marker (ms since the epoch)
+    pub clock_ms: u64,
+}
+
+impl Marker {
+    pub fn to_text(&self) -> String {
+        let mut text = format!("marker {} ({})", self.number, self.source.as_str());
+        if !self.text.is_empty() {
+            text += &format!(": {}", self.text);
+        }
+        text
+    }
+}
+
+// The text of a marker, trimmed
+pub fn marker_text(text: &str) -> Result<String, String> {
+    let text = text.trim();
+    if text.chars().count() > MAX_MARKER_TEXT_LEN {
+        return Err(format!("marker text up to {} characters", MAX_MARKER_TEXT_LEN));
+    }
+    if text.chars().any(|c| c.is_control()) {
+        return Err("no control characters".to_string());
+    }
+    Ok(text.to_string())
+}
+
+#[derive(Debug, Default)]
+pub struct Markers {
+    count: u32,
+    last: Option<Marker>,
+}
+
+impl Markers {
+    pub fn new() -> Markers {
+        Markers { count: 0, last: None }
+    }
+
+    // Number the next marker, or why its text is refused
+    pub fn add(&mut self, source: MarkerSource, text: &str, clock_ms: u64) -> Result<Marker, String> {
+        let text = marker_text(text)?;
+        self.count += 1;
+        let marker = Marker { number: self.count, source: source, text: text, clock_ms: clock_ms };
+        self.last = Some(marker.clone());
+        Ok(marker)
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn last(&self) -> Option<&Marker> {
+        self.last.as_ref()
+    }
+}
@@ -0,0 +1,191 @@
+// INA228 offset calibration sequence
+// The control task averages the readings of each INA228 with the outputs off. From the front
+// panel the offsets are applied at once, as before. A production test fixture drives it over the
+// console or the HTTP API instead: it starts the calibration, polls the progress, checks the
+// offsets measured and then applies or discards them.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+// An offset beyond these is a load left connected or a faulty channel, not an offset
+pub const MAX_CURRENT_OFFSET_A: f32 = 0.05;
+pub const MAX_VOLTAGE_OFFSET_V: f32 = 0.1;
+
+// Offsets measured on a channel; the voltage offset of its sense INA228 if it has one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelOffsets {
+    pub current_a: f32,
+    pub voltage_v: f32,
+    pub sense_v: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CalibrationAction {
+    Status,
+    Start,
+    Apply,
+    Discard,
+}
+
+impl CalibrationAction {
+    pub fn parse(text: &str) -> Option<CalibrationAction> {
+        match text {
+            "status" => Some(CalibrationAction::Status),
+            "start" => Some(CalibrationAction::Start),
+            "apply" => Some(CalibrationAction::Apply),
+            "discard" => Some(CalibrationAction::Discard),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CalibrationState {
+    Idle,
+    // Samples taken of the total
+    Running { done: u32, total: u32 },
+    // Measured, waiting to be applied or discarded
+    Ready(Vec<ChannelOffsets>),
+    Applied(Vec<ChannelOffsets>),
+    Failed(String),
+}
+
+impl CalibrationState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CalibrationState::Idle => "idle",
+            CalibrationState::Running { .. } => "running",
+            CalibrationState::Ready(_) => "ready",
+            CalibrationState::Applied(_) => "applied",
+            CalibrationState::Failed(_) => "failed",
+        }
+    }
+}
+
+// State and progress reported to the console and the HTTP API
+#[derive(Debug, Clone, PartialEq)]
+pub struct CalibrationStatus {
+    pub state: CalibrationState,
+    pub percent: u32,
+}
+
+impl CalibrationStatus {
+    // Offsets measured or applied, empty otherwise
+    pub fn offsets(&self) -> &[ChannelOffsets] {
+        match &self.state {
+            CalibrationState::Ready(offsets) | CalibrationState::Applied(offsets) => offsets,
+            _ => &[],
+        }
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        match &self.state {
+            CalibrationState::Failed(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+pub struct OffsetCalibration {
+    state: CalibrationState,
+    // Apply without a confirmation (front panel)
+    auto_apply: bool,
+}
+
+impl OffsetCalibration {
+    pub fn new() -> OffsetCalibration {
+        OffsetCalibration { state: CalibrationState::Idle, auto_apply: false }
+    }
+
+    pub fn start(&mut self, auto_apply: bool) -> Result<(), String> {
+        if self.is_running() {
+            return Err("calibration already running".to_string());
+        }
+        self.state = CalibrationState::Running { done: 0, total: 0 };
+        self.auto_apply = auto_apply;
+        Ok(())
+    }
+
+    pub fn progress(&mut self, done: u32, total: u32) {
+        if self.is_running() {
+            self.state = CalibrationState::Running { done: done, total: total };
+        }
+    }
+
+    // The offsets measured by the control task; the offsets to apply now for an automatic calibration
+    pub fn finish(&mut self, result: Result<Vec<ChannelOffsets>, String>) -> Option<Vec<ChannelOffsets>> {
+        if !self.is_running() {
+            return None;
+        }
+        self.state = match result.and_then(|offsets| check(&offsets).map(|_| offsets)) {
+            Ok(offsets) => CalibrationState::Ready(offsets),
+            Err(e) => CalibrationState::Failed(e),
+        };
+        if self.auto_apply {
+            self.apply().ok()
+        }
+        else {
+            None
+        }
+    }
+
+    // The offsets measured, to be set in the control task
+    pub fn apply(&mut self) -> Result<Vec<ChannelOffsets>, String> {
+        match &self.state {
+            CalibrationState::Ready(offsets) => {
+                let offsets = offsets.clone();
+                self.state = CalibrationState::Applied(offsets.clone());
+                Ok(offsets)
+            },
+            state => Err(format!("no calibration to apply ({})", state.name())),
+        }
+    }
+
+    // Keep the offsets in effect
+    pub fn discard(&mut self) -> Result<(), String> {
+        match self.state {
+            CalibrationState::Ready(_) | CalibrationState::Failed(_) => {
+                self.state = CalibrationState::Idle;
+                Ok(())
+            },
+            ref state => Err(format!("no calibration to discard ({})", state.name())),
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        matches!(self.state, CalibrationState::Running { .. })
+    }
+
+    pub fn get_state(&self) -> &CalibrationState {
+        &self.state
+    }
+
+    pub fn status(&self) -> CalibrationStatus {
+        CalibrationStatus { state: self.state.clone(), percent: self.percent() }
+    }
+
+    // Progress of the running calibration (0 to 100%)
+    pub fn percent(&self) -> u32 {
+        match self.state {
+            CalibrationState::Idle | CalibrationState::Failed(_) => 0,
+            CalibrationState::Running { done, total } if total > 0 => (done * 100 / total).min(100),
+            CalibrationState::Running { .. } => 0,
+            _ => 100,
+        }
+    }
+}
+
+fn check(offsets: &[ChannelOffsets]) -> Result<(), String> {
+    for (index, offset) in offsets.iter().enumerate() {
+        // A NaN reading is not within the limits either
+        if offset.current_a.is_nan() || offset.current_a.abs() > MAX_CURRENT_OFFSET_A {
+            return Err(format!("CH{} current offset {:.4}A beyond {:.2}A, is a load connected?", index + 1, offset.current_a, MAX_CURRENT_OFFSET_A));
+        }
+        let sense = offset.sense_v.unwrap_or(0.0);
+        if [offset.voltage_v, sense].iter().any(|v| v.is_nan() || v.abs() > MAX_VOLTAGE_OFFSET_V) {
+            return Err(format!("CH{} voltage offset beyond {:.2}V, is the output off?", index + 1, MAX_VOLTAGE_OFFSET_V));
+        }
+    }
+    Ok(())
+}
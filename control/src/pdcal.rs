@@ -0,0 +1,212 @@
+// Two-point calibration of the USB PD rail voltage divider (GPIO9)
+// The rail is set to a low and a high fixed PDO in turn. At each point, after the rail has
+// settled, the ADC voltage (with the nominal divider ratio) and the voltage reported by the
+// AP33772S are averaged. The gain and offset map the ADC voltage onto the reported one.
+// The AP33772S reports in coarse steps, so the points are averaged over a few seconds.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use crate::hal::{Clock, SystemClock};
+
+// The two points must be this far apart for a usable gain (V)
+pub const MIN_SPAN: f32 = 4.0;
+// 1% divider resistors and the ADC error stay well within these
+const MAX_GAIN_ERROR: f32 = 0.1;
+const MAX_OFFSET: f32 = 1.0;
+const SETTLE_MS: u128 = 1000;
+const MEASURE_MS: u128 = 3000;
+const REQUEST_TIMEOUT_MS: u128 = 5000;
+// Readings of each source needed at a point
+const MIN_SAMPLES: usize = 5;
+
+// rail voltage = gain * ADC voltage + offset
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PdVoltageCorrection {
+    pub gain: f32,
+    pub offset: f32,
+}
+
+impl Default for PdVoltageCorrection {
+    fn default() -> Self {
+        PdVoltageCorrection { gain: 1.0, offset: 0.0 }
+    }
+}
+
+impl PdVoltageCorrection {
+    pub fn apply(&self, voltage: f32) -> f32 {
+        self.gain * voltage + self.offset
+    }
+}
+
+// Averages of a calibration point
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CalPoint {
+    pub requested: f32,
+    pub adc: f32,
+    pub reference: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CalState {
+    Request,
+    Requested,
+    Measuring(u128),
+}
+
+pub struct PdVoltageCalibration<C: Clock = SystemClock> {
+    voltages: [f32; 2],
+    points: Vec<CalPoint>,
+    state: CalState,
+    state_start: u128,
+    adc: Vec<f32>,
+    reference: Vec<f32>,
+    aborted: Option<String>,
+    done: bool,
+    clock: C,
+}
+
+impl PdVoltageCalibration<SystemClock> {
+    pub fn new(low: f32, high: f32) -> Result<PdVoltageCalibration, String> {
+        PdVoltageCalibration::with_clock(low, high, SystemClock)
+    }
+}
+
+impl<C: Clock> PdVoltageCalibration<C> {
+    pub fn with_clock(low: f32, high: f32, clock: C) -> Result<PdVoltageCalibration<C>, String> {
+        if high - low < MIN_SPAN {
+            return Err(format!("the points {:.2}V and {:.2}V are less than {:.1}V apart", low, high, MIN_SPAN));
+        }
+        Ok(PdVoltageCalibration {
+            voltages: [low, high],
+            points: Vec::new(),
+            state: CalState::Request,
+            state_start: clock.now_ns(),
+            adc: Vec::new(),
+            reference: Vec::new(),
+            aborted: None,
+            done: false,
+            clock: clock,
+        })
+    }
+
+    pub fn get_points(&self) -> &[CalPoint] {
+        &self.points
+    }
+
+    pub fn is_running(&self) -> bool {
+        !self.done
+    }
+
+    // The voltage to request now, once per point
+    pub fn next_request(&mut self) -> Option<f32> {
+        if self.done || self.state != CalState::Request {
+            return None;
+        }
+        let voltage = *self.voltages.get(self.points.len())?;
+        self.state = CalState::Requested;
+        self.state_start = self.clock.now_ns();
+        Some(voltage)
+    }
+
+    // Result of the request: the contract voltage, None if it failed
+    pub fn contract(&mut self, contract: Option<f32>) {
+        if self.state != CalState::Requested {
+            return;
+        }
+        match contract {
+            Some(_) => {
+                let now = self.clock.now_ns();
+                self.state = CalState::Measuring(now);
+                self.state_start = now;
+                self.adc.clear();
+                self.reference.clear();
+            },
+            None => self.abort("request rejected"),
+        }
+    }
+
+    fn measuring(&self) -> bool {
+        match self.state {
+            CalState::Measuring(start) => self.clock.now_ns() - start >= SETTLE_MS * 1_000_000,
+            _ => false,
+        }
+    }
+
+    // ADC voltage with the nominal divider ratio, ignored while settling
+    pub fn add_adc(&mut self, voltage: f32) {
+        if self.measuring() {
+            self.adc.push(voltage);
+        }
+    }
+
+    // Voltage reported by the AP33772S, ignored while settling
+    pub fn add_reference(&mut self, voltage: f32) {
+        if self.measuring() {
+            self.reference.push(voltage);
+        }
+    }
+
+    pub fn abort(&mut self, reason: &str) {
+        if !self.done && self.aborted.is_none() {
+            self.aborted = Some(reason.to_string());
+        }
+    }
+
+    // Advance the calibration. Returns the correction or the reason of the failure once.
+    pub fn poll(&mut self) -> Option<Result<PdVoltageCorrection, String>> {
+        if self.done {
+            return None;
+        }
+        if self.aborted.is_none() {
+            let elapsed = self.clock.now_ns() - self.state_start;
+            match self.state {
+                CalState::Requested if elapsed >= REQUEST_TIMEOUT_MS * 1_000_000 => {
+                    self.abort("no response to the request");
+                },
+                CalState::Measuring(_) if elapsed >= (SETTLE_MS + MEASURE_MS) * 1_000_000 => {
+                    if self.adc.len() < MIN_SAMPLES || self.reference.len() < MIN_SAMPLES {
+                        self.abort("too few readings");
+                    }
+                    else {
+                        self.points.push(CalPoint {
+                            requested: self.voltages[self.points.len()],
+                            adc: mean(&self.adc),
+                            reference: mean(&self.reference),
+                        });
+                        self.state = CalState::Request;
+                        self.state_start = self.clock.now_ns();
+                    }
+                },
+                _ => {},
+            }
+            if self.aborted.is_none() && self.points.len() < self.voltages.len() {
+                return None;
+            }
+        }
+        self.done = true;
+        if let Some(reason) = &self.aborted {
+            return Some(Err(reason.clone()));
+        }
+        Some(correction(&self.points[0], &self.points[1]))
+    }
+}
+
+fn mean(values: &[f32]) -> f32 {
+    values.iter().sum::<f32>() / values.len() as f32
+}
+
+// The correction through the two points, if it is plausible
+pub fn correction(low: &CalPoint, high: &CalPoint) -> Result<PdVoltageCorrection, String> {
+    if high.adc - low.adc < MIN_SPAN / 2.0 {
+        return Err(format!("the ADC voltage did not follow the rail ({:.3}V at {:.2}V, {:.3}V at {:.2}V)",
+            low.adc, low.requested, high.adc, high.requested));
+    }
+    let gain = (high.reference - low.reference) / (high.adc - low.adc);
+    let offset = low.reference - gain * low.adc;
+    if !gain.is_finite() || (gain - 1.0).abs() > MAX_GAIN_ERROR || offset.abs() > MAX_OFFSET {
+        return Err(format!("implausible correction: gain {:.4}, offset {:+.3}V", gain, offset));
+    }
+    Ok(PdVoltageCorrection { gain: gain, offset: offset })
+}
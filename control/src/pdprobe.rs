@@ -0,0 +1,290 @@
+// USB PD charger compatibility probe
+// Each advertised PDO is requested in turn: a fixed PDO at its voltage, a PPS APDO at 5V,
+// the middle and the maximum of its range. After the request, the rail voltage is left to
+// settle and then averaged, and compared with the requested voltage. The outputs are off
+// during the probe, so the rail is measured without load.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use crate::hal::{Clock, SystemClock};
+
+// The unit needs 5V, the lowest point of a PPS range
+const PPS_MIN_VOLTAGE: f32 = 5.0;
+// PPS voltage steps are 20mV, the probe points are rounded to 100mV
+const PPS_POINT_STEP: f32 = 0.1;
+// The rail is left to settle after the request, then measured
+const SETTLE_MS: u128 = 500;
+const MEASURE_MS: u128 = 1000;
+// Without the result of a request, the step is recorded as no response
+const REQUEST_TIMEOUT_MS: u128 = 5000;
+
+// An advertised PDO; the voltage of a PPS APDO is its maximum
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PdoPoint {
+    pub index: u8,
+    pub voltage: f32,
+    pub current: f32,
+    pub fixed: bool,
+}
+
+// A voltage requested by the probe
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProbeStep {
+    pub pdo: PdoPoint,
+    pub voltage: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProbeOutcome {
+    Pass,
+    // The rail voltage is out of the tolerance
+    OutOfTolerance,
+    // The request was not accepted
+    Rejected,
+    // No result of the request within the timeout
+    NoResponse,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProbeResult {
+    pub step: ProbeStep,
+    pub outcome: ProbeOutcome,
+    // Rail voltage during the measurement (0 without samples)
+    pub mean: f32,
+    pub min: f32,
+    pub max: f32,
+}
+
+impl ProbeResult {
+    // Deviation of the mean from the requested voltage (%)
+    pub fn error_percent(&self) -> f32 {
+        if self.step.voltage > 0.0 {
+            (self.mean - self.step.voltage) / self.step.voltage * 100.0
+        }
+        else {
+            0.0
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let kind = if self.step.pdo.fixed { "Fixed" } else { "PPS" };
+        let pdo = format!("PDO{} {} {:.2}V {:.2}A", self.step.pdo.index, kind, self.step.pdo.voltage, self.step.pdo.current);
+        match self.outcome {
+            ProbeOutcome::Rejected => format!("{}: {:.2}V rejected", pdo, self.step.voltage),
+            ProbeOutcome::NoResponse => format!("{}: {:.2}V no response", pdo, self.step.voltage),
+            _ => format!("{}: {:.2}V -> {:.3}V ({:.3}-{:.3}V, {:+.1}%) {}", pdo, self.step.voltage,
+                self.mean, self.min, self.max, self.error_percent(),
+                if self.outcome == ProbeOutcome::Pass { "OK" } else { "OUT OF TOLERANCE" }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PdProbeReport {
+    pub tolerance_percent: f32,
+    pub results: Vec<ProbeResult>,
+    // Reason of an aborted probe
+    pub aborted: Option<String>,
+}
+
+impl PdProbeReport {
+    pub fn passed(&self) -> usize {
+        self.results.iter().filter(|r| r.outcome == ProbeOutcome::Pass).count()
+    }
+
+    // All the steps were run and passed
+    pub fn is_compatible(&self) -> bool {
+        self.aborted.is_none() && !self.results.is_empty() && self.passed() == self.results.len()
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = format!("USB PD probe (tolerance {:.1}%)\n", self.tolerance_percent);
+        for result in &self.results {
+            text.push_str(&result.to_text());
+            text.push('\n');
+        }
+        if let Some(reason) = &self.aborted {
+            text.push_str(&format!("Aborted: {}\n", reason));
+        }
+        text.push_str(&format!("{}/{} points passed: {}", self.passed(), self.results.len(),
+            if self.is_compatible() { "compatible" } else { "not compatible" }));
+        text
+    }
+}
+
+// The probe points of the PDOs, in the order of the list
+pub fn probe_steps(pdos: &[PdoPoint]) -> Vec<ProbeStep> {
+    let mut steps = Vec::new();
+    for pdo in pdos {
+        if pdo.fixed || pdo.voltage <= PPS_MIN_VOLTAGE {
+            steps.push(ProbeStep { pdo: *pdo, voltage: pdo.voltage });
+            continue;
+        }
+        let middle = ((PPS_MIN_VOLTAGE + pdo.voltage) / 2.0 / PPS_POINT_STEP).round() * PPS_POINT_STEP;
+        for voltage in [PPS_MIN_VOLTAGE, middle, pdo.voltage] {
+            steps.push(ProbeStep { pdo: *pdo, voltage: voltage });
+        }
+    }
+    steps
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StepState {
+    // The request is to be sent
+    Request,
+    // Waiting for the result of the request
+    Requested,
+    // Accepted at the time, settling then measuring
+    Measuring(u128),
+}
+
+pub struct PdProbe<C: Clock = SystemClock> {
+    steps: Vec<ProbeStep>,
+    tolerance_percent: f32,
+    results: Vec<ProbeResult>,
+    state: StepState,
+    state_start: u128,
+    samples: Vec<f32>,
+    aborted: Option<String>,
+    done: bool,
+    clock: C,
+}
+
+impl PdProbe<SystemClock> {
+    pub fn new(pdos: &[PdoPoint], tolerance_percent: f32) -> PdProbe {
+        PdProbe::with_clock(pdos, tolerance_percent, SystemClock)
+    }
+}
+
+impl<C: Clock> PdProbe<C> {
+    pub fn with_clock(pdos: &[PdoPoint], tolerance_percent: f32, clock: C) -> PdProbe<C> {
+        PdProbe {
+            steps: probe_steps(pdos),
+            tolerance_percent: tolerance_percent,
+            results: Vec::new(),
+            state: StepState::Request,
+            state_start: clock.now_ns(),
+            samples: Vec::new(),
+            aborted: None,
+            done: false,
+            clock: clock,
+        }
+    }
+
+    // Completed and total steps
+    pub fn progress(&self) -> (usize, usize) {
+        (self.results.len(), self.steps.len())
+    }
+
+    pub fn is_running(&self) -> bool {
+        !self.done
+    }
+
+    // The step to request now, once per step
+    pub fn next_request(&mut self) -> Option<ProbeStep> {
+        if self.done || self.state != StepState::Request {
+            return None;
+        }
+        let step = *self.steps.get(self.results.len())?;
+        self.state = StepState::Requested;
+        self.state_start = self.clock.now_ns();
+        Some(step)
+    }
+
+    // Result of the request: the contract voltage, None if it failed
+    pub fn contract(&mut self, contract: Option<f32>) {
+        if self.state != StepState::Requested {
+            return;
+        }
+        match contract {
+            Some(_) => {
+                let now = self.clock.now_ns();
+                self.state = StepState::Measuring(now);
+                self.state_start = now;
+                self.samples.clear();
+            },
+            None => self.finish_step(ProbeOutcome::Rejected),
+        }
+    }
+
+    // Rail voltage, ignored while settling
+    pub fn add_sample(&mut self, voltage: f32) {
+        if let StepState::Measuring(start) = self.state {
+            if self.clock.now_ns() - start >= SETTLE_MS * 1_000_000 {
+                self.samples.push(voltage);
+            }
+        }
+    }
+
+    pub fn abort(&mut self, reason: &str) {
+        if !self.done {
+            self.aborted = Some(reason.to_string());
+        }
+    }
+
+    // Advance the probe. Returns the report once, when all the steps are done or aborted.
+    pub fn poll(&mut self) -> Option<PdProbeReport> {
+        if self.done {
+            return None;
+        }
+        if self.aborted.is_none() {
+            let elapsed = self.clock.now_ns() - self.state_start;
+            match self.state {
+                StepState::Requested if elapsed >= REQUEST_TIMEOUT_MS * 1_000_000 => {
+                    self.finish_step(ProbeOutcome::NoResponse);
+                },
+                StepState::Measuring(_) if elapsed >= (SETTLE_MS + MEASURE_MS) * 1_000_000 => {
+                    let outcome = if self.within_tolerance() { ProbeOutcome::Pass } else { ProbeOutcome::OutOfTolerance };
+                    self.finish_step(outcome);
+                },
+                _ => {},
+            }
+            if self.results.len() < self.steps.len() {
+                return None;
+            }
+        }
+        self.done = true;
+        Some(PdProbeReport {
+            tolerance_percent: self.tolerance_percent,
+            results: self.results.clone(),
+            aborted: self.aborted.clone(),
+        })
+    }
+
+    fn within_tolerance(&self) -> bool {
+        let step = &self.steps[self.results.len()];
+        let mean = self.mean();
+        !self.samples.is_empty() && (mean - step.voltage).abs() <= step.voltage * self.tolerance_percent / 100.0
+    }
+
+    fn mean(&self) -> f32 {
+        if self.samples.is_empty() {
+            0.0
+        }
+        else {
+            self.samples.iter().sum::<f32>() / self.samples.len() as f32
+        }
+    }
+
+    fn finish_step(&mut self, outcome: ProbeOutcome) {
+        let measured = matches!(outcome, ProbeOutcome::Pass | ProbeOutcome::OutOfTolerance) && !self.samples.is_empty();
+        let (min, max) = if measured {
+            (self.samples.iter().cloned().fold(f32::MAX, f32::min), self.samples.iter().cloned().fold(f32::MIN, f32::max))
+        }
+        else {
+            (0.0, 0.0)
+        };
+        self.results.push(ProbeResult {
+            step: self.steps[self.results.len()],
+            outcome: outcome,
+            mean: if measured { self.mean() } else { 0.0 },
+            min: min,
+            max: max,
+        });
+        self.samples.clear();
+        self.state = StepState::Request;
+        self.state_start = self.clock.now_ns();
+    }
+}
@@ -0,0 +1,79 @@
+// Live measurement frames for an ESP-NOW receiver (USB dongle on a PC)
+// Where the unit cannot join a WiFi network (e.g. an RF-shielded room), the measurements
+// are broadcast over ESP-NOW at a fixed interval instead. A frame is one line of text, so
+// the dongle can pass it to the PC serial port as is:
+// "DCPT1,<unit>,<sequence>,<temperature C>;<channel>,<0|1 output on>,<V>,<A>,<W>;..."
+// An ESP-NOW frame carries up to 250 bytes; the unit name is shortened to keep within it.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use crate::hal::{Clock, SystemClock};
+
+pub const FRAME_MAGIC: &str = "DCPT1";
+pub const MAX_FRAME_LEN: usize = 250;
+pub const MAX_UNIT_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeerChannel {
+    pub channel: u8,
+    pub output_on: bool,
+    pub voltage: f32,
+    pub current: f32,
+    pub power: f32,
+}
+
+pub struct PeerTelemetry<C: Clock = SystemClock> {
+    unit: String,
+    interval_ns: u128,
+    next: u128,
+    sequence: u32,
+    clock: C,
+}
+
+impl PeerTelemetry<SystemClock> {
+    pub fn new(unit: &str, interval_ms: u32) -> PeerTelemetry {
+        PeerTelemetry::with_clock(unit, interval_ms, SystemClock)
+    }
+}
+
+impl<C: Clock> PeerTelemetry<C> {
+    pub fn with_clock(unit: &str, interval_ms: u32, clock: C) -> PeerTelemetry<C> {
+        // The separators of the frame are not allowed in the name
+        let unit : String = unit.chars()
+            .map(|c| if c == ',' || c == ';' || c.is_control() { '_' } else { c })
+            .take(MAX_UNIT_LEN)
+            .collect();
+        PeerTelemetry {
+            unit: unit,
+            interval_ns: interval_ms.max(1) as u128 * 1_000_000,
+            next: 0,
+            sequence: 0,
+            clock: clock,
+        }
+    }
+
+    // The frame of the measurements if one is due
+    pub fn poll(&mut self, channels: &[PeerChannel], temperature: f32) -> Option<String> {
+        let now = self.clock.now_ns();
+        if now < self.next {
+            return None;
+        }
+        self.next = now + self.interval_ns;
+        self.sequence = self.sequence.wrapping_add(1);
+        Some(self.frame(channels, temperature))
+    }
+
+    pub fn frame(&self, channels: &[PeerChannel], temperature: f32) -> String {
+        let mut frame = format!("{},{},{},{:.1}", FRAME_MAGIC, self.unit, self.sequence, temperature);
+        for ch in channels {
+            let part = format!(";{},{},{:.4},{:.4},{:.3}", ch.channel, ch.output_on as u8, ch.voltage, ch.current, ch.power);
+            if frame.len() + part.len() > MAX_FRAME_LEN {
+                break;
+            }
+            frame.push_str(&part);
+        }
+        frame
+    }
+}
@@ -0,0 +1,60 @@
+// Loop performance counters
+// The control task, the display thread and the main loop count their work in free running
+// counters. The main loop reads them periodically and turns the difference to the last read
+// into rates per second, sent as the diagnostics measurement, so a firmware which does less
+// work per second (or spends longer on the I2C bus) than the last one shows it.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+// Free running counters (they wrap)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PerfCounters {
+    pub control_cycles: u32,
+    // Control timer ticks missed (deadlines)
+    pub overruns: u32,
+    pub i2c_transfers: u32,
+    // Time on the I2C bus of the measurement reads (us)
+    pub i2c_busy_us: u32,
+    // PWM duty changes of all the channels
+    pub pwm_updates: u32,
+    pub display_frames: u32,
+    pub main_loops: u32,
+}
+
+// Per second over the interval, and the mean time of an I2C transfer
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PerfRates {
+    pub control_cycles: f32,
+    pub overruns: f32,
+    pub i2c_transfers: f32,
+    pub i2c_busy_us: f32,
+    pub i2c_transfer_us: f32,
+    pub pwm_updates: f32,
+    pub display_frames: f32,
+    pub main_loops: f32,
+}
+
+impl PerfCounters {
+    // Rates from the previous read of the counters, elapsed_ms before
+    pub fn rates(&self, previous: &PerfCounters, elapsed_ms: u64) -> PerfRates {
+        if elapsed_ms == 0 {
+            return PerfRates::default();
+        }
+        let secs = elapsed_ms as f32 / 1000.0;
+        let per_sec = |now: u32, before: u32| now.wrapping_sub(before) as f32 / secs;
+        let transfers = self.i2c_transfers.wrapping_sub(previous.i2c_transfers);
+        let busy_us = self.i2c_busy_us.wrapping_sub(previous.i2c_busy_us);
+        PerfRates {
+            control_cycles: per_sec(self.control_cycles, previous.control_cycles),
+            overruns: per_sec(self.overruns, previous.overruns),
+            i2c_transfers: per_sec(self.i2c_transfers, previous.i2c_transfers),
+            i2c_busy_us: per_sec(self.i2c_busy_us, previous.i2c_busy_us),
+            i2c_transfer_us: if transfers > 0 { busy_us as f32 / transfers as f32 } else { 0.0 },
+            pwm_updates: per_sec(self.pwm_updates, previous.pwm_updates),
+            display_frames: per_sec(self.display_frames, previous.display_frames),
+            main_loops: per_sec(self.main_loops, previous.main_loops),
+        }
+    }
+}
@@ -2,13 +2,23 @@
 // SPDX-License-Identifier: MIT
 // Copyright (c) 2024 Hiroshi Nakajima
 
-#![allow(dead_code, unused_imports)]
+#![allow(dead_code)]
 
-use std::time::SystemTime;
-use std::time::UNIX_EPOCH;
 use log::info;
+use crate::hal::{Clock, SystemClock};
 
-pub struct PIDController {
+// Internals of the last update, for the PID telemetry (see pidtrace)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PidTerms {
+    pub error: f32,
+    pub proportional: f32,
+    pub integral: f32,
+    pub derivative: f32,
+    // Sum of the terms, before the clamping
+    pub output: f32,
+}
+
+pub struct PIDController<C: Clock = SystemClock> {
     kp: f32,
     ki: f32,
     kd: f32,
@@ -16,11 +26,19 @@ pub struct PIDController {
     integral: f32,
     prev_error: f32,
     prev_time: u128,
+    terms: PidTerms,
+    clock: C,
 }
 
-#[allow(dead_code)]
-impl PIDController {
+impl PIDController<SystemClock> {
     pub fn new(kp: f32, ki: f32, kd: f32, setpoint: f32) -> PIDController {
+        PIDController::with_clock(kp, ki, kd, setpoint, SystemClock)
+    }
+}
+
+#[allow(dead_code)]
+impl<C: Clock> PIDController<C> {
+    pub fn with_clock(kp: f32, ki: f32, kd: f32, setpoint: f32, clock: C) -> PIDController<C> {
         PIDController {
             kp: kp,
             ki: ki,
@@ -29,14 +47,45 @@ impl PIDController {
             integral: 0.0,
             prev_error: 0.0,
             prev_time: 0,
+            terms: PidTerms::default(),
+            clock: clock,
         }
     }
 
     pub fn reset(&mut self) {
         self.integral = 0.0;
         self.prev_error = 0.0;
-        let now = SystemTime::now();
-        self.prev_time = now.duration_since(UNIX_EPOCH).unwrap().as_nanos();
+        self.prev_time = self.clock.now_ns();
+        self.terms = PidTerms::default();
+    }
+
+    // Change the gains while running. The integral is rescaled so the integral term
+    // (and the output) does not jump.
+    pub fn set_gains(&mut self, kp: f32, ki: f32, kd: f32) {
+        if self.ki > 0.0 && ki > 0.0 {
+            self.integral *= self.ki / ki;
+        }
+        else {
+            self.integral = 0.0;
+        }
+        self.kp = kp;
+        self.ki = ki;
+        self.kd = kd;
+    }
+
+    // Scale the integral term (and so the output at a zero error)
+    pub fn scale_integral(&mut self, ratio: f32) {
+        if ratio.is_finite() {
+            self.integral *= ratio;
+        }
+    }
+
+    pub fn get_gains(&self) -> (f32, f32, f32) {
+        (self.kp, self.ki, self.kd)
+    }
+
+    pub fn get_terms(&self) -> PidTerms {
+        self.terms
     }
 
     pub fn set_setpoint(&mut self, setpoint: f32) {
@@ -44,8 +93,7 @@ impl PIDController {
     }
 
     pub fn update(&mut self, input: f32) -> f32 {
-        let now = SystemTime::now();
-        let nano = now.duration_since(UNIX_EPOCH).unwrap().as_nanos();
+        let nano = self.clock.now_ns();
         
         // Initial execution guard
         if self.prev_time == 0 {
@@ -57,8 +105,9 @@ impl PIDController {
         // Calculate dt in milliseconds (not converted to seconds)
         let dt_ms = (nano - self.prev_time) as f32 / 1000000.0; // Convert nanoseconds to milliseconds
         
-        // Guard against abnormal dt values (less than 1ms or more than 10000ms)
-        if dt_ms <= 1.0 || dt_ms > 10000.0 || !dt_ms.is_finite() {
+        // Guard against abnormal dt values (less than 0.1ms or more than 10000ms).
+        // The control period is 0.5ms to 10ms (control_rate_hz).
+        if !(0.1..=10000.0).contains(&dt_ms) || !dt_ms.is_finite() {
             info!("Abnormal dt_ms detected: {} nano: {} prev_time: {}", dt_ms, nano, self.prev_time);
             self.prev_time = nano;
             return 0.0;
@@ -87,6 +136,13 @@ impl PIDController {
         let derivative = if derivative.is_finite() { derivative } else { 0.0 };
         
         let output = self.kp * error + self.ki * self.integral + self.kd * derivative;
+        self.terms = PidTerms {
+            error: error,
+            proportional: self.kp * error,
+            integral: self.ki * self.integral,
+            derivative: self.kd * derivative,
+            output: output,
+        };
         
         // Limit output if it becomes infinite
         let output = if output.is_finite() { 
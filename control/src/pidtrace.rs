@@ -0,0 +1,111 @@
+// PID loop telemetry
+// The PID internals (error, P/I/D terms and the output before the clamping) of a channel
+// are sampled at the trace rate, below the control rate, together with the setpoint, the
+// voltage and the duty. The points are buffered in the control task and taken as a batch
+// (every second), so an oscillation of the loop can be analyzed offline from the terms
+// instead of from the voltage alone. The buffer is bounded; the oldest points are dropped.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+use crate::hal::{Clock, SystemClock};
+use crate::pidcont::PidTerms;
+
+pub const MAX_TRACE_RATE_HZ: u32 = 1000;
+// Points kept per channel between two batches
+pub const MAX_TRACE_POINTS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PidPoint {
+    // Time of the sample (ns)
+    pub clock: u128,
+    pub setpoint: f32,
+    pub voltage: f32,
+    pub terms: PidTerms,
+    pub duty: u32,
+}
+
+pub struct PidTrace<C: Clock = SystemClock> {
+    // 0 disables the trace
+    interval_ns: u128,
+    next: u128,
+    points: VecDeque<PidPoint>,
+    dropped: u32,
+    clock: C,
+}
+
+impl PidTrace<SystemClock> {
+    pub fn new(rate_hz: u32) -> PidTrace {
+        PidTrace::with_clock(rate_hz, SystemClock)
+    }
+}
+
+impl<C: Clock> PidTrace<C> {
+    pub fn with_clock(rate_hz: u32, clock: C) -> PidTrace<C> {
+        let mut trace = PidTrace {
+            interval_ns: 0,
+            next: 0,
+            points: VecDeque::new(),
+            dropped: 0,
+            clock: clock,
+        };
+        trace.set_rate(rate_hz);
+        trace
+    }
+
+    pub fn set_rate(&mut self, rate_hz: u32) {
+        self.interval_ns = match rate_hz.min(MAX_TRACE_RATE_HZ) {
+            0 => 0,
+            rate => 1_000_000_000 / rate as u128,
+        };
+        self.next = 0;
+        if self.interval_ns == 0 {
+            self.points.clear();
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.interval_ns > 0
+    }
+
+    // Points dropped on a full buffer since the start
+    pub fn get_dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    // PID internals of a control period, kept if a sample is due
+    pub fn record(&mut self, setpoint: f32, voltage: f32, terms: PidTerms, duty: u32) {
+        if self.interval_ns == 0 {
+            return;
+        }
+        let now = self.clock.now_ns();
+        if now < self.next {
+            return;
+        }
+        // On the grid of the interval, without catching up after a gap
+        self.next = if self.next > 0 && now - self.next < self.interval_ns {
+            self.next + self.interval_ns
+        }
+        else {
+            now + self.interval_ns
+        };
+        if self.points.len() >= MAX_TRACE_POINTS {
+            self.points.pop_front();
+            self.dropped += 1;
+        }
+        self.points.push_back(PidPoint {
+            clock: now,
+            setpoint: setpoint,
+            voltage: voltage,
+            terms: terms,
+            duty: duty,
+        });
+    }
+
+    // The points since the last batch
+    pub fn take(&mut self) -> Vec<PidPoint> {
+        self.points.drain(..).collect()
+    }
+}
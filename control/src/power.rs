@@ -0,0 +1,28 @@
+// Signed output power
+// The POWER register of the INA228 is unsigned: it holds |V x I| whatever the direction of
+// the current, so a reverse feed (a battery or another supply driving the output) read as
+// power delivered. When the current is negative, the power is computed as V x I instead,
+// which is negative. Within the zero band of the current the register is taken as it is, so
+// the offset noise around 0A does not flip the sign of the power. A record with a negative
+// power is a reverse feed (flagged as such in the log).
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+// Currents down to this are taken as 0A (A)
+pub const ZERO_BAND_A: f32 = 0.002;
+
+// Power from the voltage, the signed current and the POWER register
+pub fn signed_power(voltage: f32, current: f32, power_register: f32) -> f32 {
+    if current < -ZERO_BAND_A {
+        voltage * current
+    }
+    else {
+        power_register
+    }
+}
+
+pub fn is_reverse(power: f32) -> bool {
+    power < 0.0
+}
@@ -0,0 +1,146 @@
+// End-of-line production test sequence
+// The unit is started in the test mode by a strap on the fixture or the prodtest console
+// command. Each subsystem is checked in turn: the I2C devices, a PWM sweep of each channel,
+// a test pattern on the display, a touch of each key and a WiFi scan. A step which gives
+// no result within its time fails. The results form the pass/fail report sent over USB.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+// Time the test pattern is shown (ms)
+pub const DISPLAY_MS: u64 = 3000;
+// Time for the operator to touch all the keys (ms)
+pub const TOUCH_MS: u64 = 30_000;
+// Up, Down, Left, Right and Center
+pub const KEY_COUNT: usize = 5;
+const ALL_KEYS: u32 = (1 << KEY_COUNT) - 1;
+const KEY_NAMES: [&str; KEY_COUNT] = ["up", "down", "left", "right", "center"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TestStep {
+    I2c,
+    Pwm,
+    Display,
+    Touch,
+    Wifi,
+}
+
+pub const STEPS: [TestStep; 5] = [TestStep::I2c, TestStep::Pwm, TestStep::Display, TestStep::Touch, TestStep::Wifi];
+
+impl TestStep {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TestStep::I2c => "i2c",
+            TestStep::Pwm => "pwm",
+            TestStep::Display => "display",
+            TestStep::Touch => "touch",
+            TestStep::Wifi => "wifi",
+        }
+    }
+
+    // Time for the result of the step (ms)
+    pub fn timeout_ms(&self) -> u64 {
+        match self {
+            TestStep::I2c => 2000,
+            TestStep::Pwm => 5000,
+            TestStep::Display => DISPLAY_MS,
+            TestStep::Touch => TOUCH_MS,
+            TestStep::Wifi => 15_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepResult {
+    pub step: TestStep,
+    pub pass: bool,
+    pub detail: String,
+    pub duration_ms: u64,
+}
+
+pub struct ProductionTest {
+    // Index in STEPS of the step running, and when it was started (None until poll starts it)
+    step: usize,
+    started_ms: Option<u64>,
+    results: Vec<StepResult>,
+    // Keys touched in the touch step
+    keys: u32,
+}
+
+impl ProductionTest {
+    pub fn new() -> ProductionTest {
+        ProductionTest { step: 0, started_ms: None, results: Vec::new(), keys: 0 }
+    }
+
+    // The step to start now, once; a step which timed out is failed here
+    pub fn poll(&mut self, now_ms: u64) -> Option<TestStep> {
+        let step = *STEPS.get(self.step)?;
+        match self.started_ms {
+            None => {
+                self.started_ms = Some(now_ms);
+                Some(step)
+            },
+            Some(started) if now_ms.saturating_sub(started) >= step.timeout_ms() => {
+                match step {
+                    // Shown for its time, the picture is checked by the operator or a camera
+                    TestStep::Display => self.finish(now_ms, true, "pattern shown".to_string()),
+                    TestStep::Touch => {
+                        let missing = self.missing_keys().join(",");
+                        self.finish(now_ms, false, format!("not touched: {}", missing))
+                    },
+                    _ => self.finish(now_ms, false, "timed out".to_string()),
+                }
+                self.poll(now_ms)
+            },
+            Some(_) => None,
+        }
+    }
+
+    // Result of the step running; a late result of an earlier step is ignored
+    pub fn result(&mut self, step: TestStep, now_ms: u64, pass: bool, detail: &str) {
+        if self.current() == Some(step) && self.started_ms.is_some() {
+            self.finish(now_ms, pass, detail.to_string());
+        }
+    }
+
+    // A key touched (index in up, down, left, right, center)
+    pub fn key(&mut self, index: usize, now_ms: u64) {
+        if self.current() != Some(TestStep::Touch) || index >= KEY_COUNT {
+            return;
+        }
+        self.keys |= 1 << index;
+        if self.keys == ALL_KEYS {
+            self.finish(now_ms, true, "all keys touched".to_string());
+        }
+    }
+
+    fn finish(&mut self, now_ms: u64, pass: bool, detail: String) {
+        let step = STEPS[self.step];
+        let duration_ms = now_ms.saturating_sub(self.started_ms.unwrap_or(now_ms));
+        self.results.push(StepResult { step: step, pass: pass, detail: detail, duration_ms: duration_ms });
+        self.step += 1;
+        self.started_ms = None;
+    }
+
+    pub fn current(&self) -> Option<TestStep> {
+        STEPS.get(self.step).copied()
+    }
+
+    // Names of the keys not touched yet
+    pub fn missing_keys(&self) -> Vec<&'static str> {
+        KEY_NAMES.iter().enumerate().filter(|(i, _)| self.keys & (1 << i) == 0).map(|(_, name)| *name).collect()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.step >= STEPS.len()
+    }
+
+    pub fn passed(&self) -> bool {
+        self.is_done() && self.results.iter().all(|r| r.pass)
+    }
+
+    pub fn results(&self) -> &[StepResult] {
+        &self.results
+    }
+}
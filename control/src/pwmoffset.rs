@@ -0,0 +1,323 @@
+// Automatic PWM offset learning
+// The buck stage does not raise the output until the duty passes a threshold, which moves
+// with the board and the USB PD rail voltage. With the output off, the duty is ramped up
+// from zero until the output just begins to rise; the last duty before the rise is the
+// offset the regulator adds to the PID output. It is learned at each fixed PDO voltage,
+// and the offset for a contract voltage is interpolated between them.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use crate::hal::{Clock, SystemClock};
+use crate::regulator::PWM_OFFSET_REFERENCE_DUTY;
+
+// Output voltage at zero duty, averaged before the ramp
+const BASELINE_MS: u128 = 20;
+// Each duty step is held this long for the output filter to follow
+const DWELL_MS: u128 = 5;
+// Duty step in counts of the reference duty (as pwm_offset)
+const STEP: u32 = 16;
+// Rise over the baseline which ends the ramp (V)
+pub const RISE_V: f32 = 0.05;
+// No offset above half of the reference duty
+pub const MAX_OFFSET: u32 = PWM_OFFSET_REFERENCE_DUTY / 2;
+// Time for the rail to settle after a USB PD request
+const SETTLE_MS: u128 = 1000;
+const REQUEST_TIMEOUT_MS: u128 = 5000;
+// Worst case ramp, with margin for the reports
+const RAMP_TIMEOUT_MS: u128 = BASELINE_MS + (MAX_OFFSET / STEP) as u128 * DWELL_MS + 1000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RampStep {
+    // Duty to apply for this control period
+    Duty(u32),
+    // The offset in counts of the reference duty, or the reason of the failure
+    Done(Result<u32, String>),
+}
+
+// Open loop duty ramp of one channel, run at the control rate with the output off
+pub struct OffsetRamp<C: Clock = SystemClock> {
+    max_duty: u32,
+    start: u128,
+    baseline: Option<f32>,
+    baseline_sum: f32,
+    baseline_samples: u32,
+    // Offset being tried, in counts of the reference duty
+    offset: u32,
+    step_start: u128,
+    clock: C,
+}
+
+impl OffsetRamp<SystemClock> {
+    pub fn new(max_duty: u32) -> OffsetRamp {
+        OffsetRamp::with_clock(max_duty, SystemClock)
+    }
+}
+
+impl<C: Clock> OffsetRamp<C> {
+    pub fn with_clock(max_duty: u32, clock: C) -> OffsetRamp<C> {
+        let now = clock.now_ns();
+        OffsetRamp {
+            max_duty: max_duty,
+            start: now,
+            baseline: None,
+            baseline_sum: 0.0,
+            baseline_samples: 0,
+            offset: 0,
+            step_start: now,
+            clock: clock,
+        }
+    }
+
+    fn duty(&self) -> u32 {
+        (self.offset as f32 * self.max_duty as f32 / PWM_OFFSET_REFERENCE_DUTY as f32) as u32
+    }
+
+    // Output voltage of this control period, returns the duty to apply or the result
+    pub fn update(&mut self, voltage: f32) -> RampStep {
+        let now = self.clock.now_ns();
+        let baseline = match self.baseline {
+            Some(baseline) => baseline,
+            None => {
+                self.baseline_sum += voltage;
+                self.baseline_samples += 1;
+                if now - self.start < BASELINE_MS * 1_000_000 {
+                    return RampStep::Duty(0);
+                }
+                let baseline = self.baseline_sum / self.baseline_samples as f32;
+                self.baseline = Some(baseline);
+                self.step_start = now;
+                baseline
+            },
+        };
+        if now - self.step_start < DWELL_MS * 1_000_000 {
+            return RampStep::Duty(self.duty());
+        }
+        if voltage > baseline + RISE_V {
+            // The last step before the rise
+            return RampStep::Done(Ok(self.offset.saturating_sub(STEP)));
+        }
+        if self.offset + STEP > MAX_OFFSET {
+            return RampStep::Done(Err(format!("no rise up to offset {}", MAX_OFFSET)));
+        }
+        self.offset += STEP;
+        self.step_start = now;
+        RampStep::Duty(self.duty())
+    }
+}
+
+// Learned offsets of a channel by the rail voltage
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OffsetTable {
+    points: Vec<(f32, u32)>,
+}
+
+impl OffsetTable {
+    pub fn new() -> OffsetTable {
+        OffsetTable { points: Vec::new() }
+    }
+
+    pub fn get_points(&self) -> &[(f32, u32)] {
+        &self.points
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    pub fn insert(&mut self, voltage: f32, offset: u32) {
+        self.points.retain(|(v, _)| *v != voltage);
+        self.points.push((voltage, offset));
+        self.points.sort_by(|a, b| a.0.total_cmp(&b.0));
+    }
+
+    // Offset at the rail voltage, linear between the learned points and the nearest one
+    // outside them; None if nothing was learned
+    pub fn offset_at(&self, voltage: f32) -> Option<u32> {
+        let first = self.points.first()?;
+        let last = self.points.last()?;
+        if voltage <= first.0 {
+            return Some(first.1);
+        }
+        if voltage >= last.0 {
+            return Some(last.1);
+        }
+        let upper = self.points.iter().position(|(v, _)| *v >= voltage)?;
+        let (v0, o0) = self.points[upper - 1];
+        let (v1, o1) = self.points[upper];
+        let offset = o0 as f32 + (o1 as f32 - o0 as f32) * (voltage - v0) / (v1 - v0);
+        Some(offset.round() as u32)
+    }
+
+    pub fn to_text(&self) -> String {
+        if self.points.is_empty() {
+            return "not learned".to_string();
+        }
+        self.points.iter().map(|(v, o)| format!("{:.1}V:{}", v, o)).collect::<Vec<_>>().join(" ")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LearnState {
+    Request,
+    Requested,
+    Settling,
+    Ramping,
+}
+
+// Learning at each voltage in turn: request the voltage, let the rail settle, then ramp
+// all the channels together
+pub struct OffsetLearning<C: Clock = SystemClock> {
+    voltages: Vec<f32>,
+    index: usize,
+    state: LearnState,
+    state_start: u128,
+    tables: Vec<OffsetTable>,
+    // Channels still ramping at the voltage
+    pending: Vec<bool>,
+    errors: Vec<String>,
+    aborted: Option<String>,
+    done: bool,
+    clock: C,
+}
+
+impl OffsetLearning<SystemClock> {
+    pub fn new(voltages: &[f32], channels: usize) -> OffsetLearning {
+        OffsetLearning::with_clock(voltages, channels, SystemClock)
+    }
+}
+
+impl<C: Clock> OffsetLearning<C> {
+    pub fn with_clock(voltages: &[f32], channels: usize, clock: C) -> OffsetLearning<C> {
+        let mut voltages = voltages.to_vec();
+        voltages.sort_by(|a, b| a.total_cmp(b));
+        voltages.dedup();
+        OffsetLearning {
+            voltages: voltages,
+            index: 0,
+            state: LearnState::Request,
+            state_start: clock.now_ns(),
+            tables: vec![OffsetTable::new(); channels],
+            pending: vec![false; channels],
+            errors: Vec::new(),
+            aborted: None,
+            done: false,
+            clock: clock,
+        }
+    }
+
+    // Voltage in progress and the number of voltages
+    pub fn progress(&self) -> (usize, usize) {
+        (self.index.min(self.voltages.len()), self.voltages.len())
+    }
+
+    pub fn get_tables(&self) -> &[OffsetTable] {
+        &self.tables
+    }
+
+    fn set_state(&mut self, state: LearnState) {
+        self.state = state;
+        self.state_start = self.clock.now_ns();
+    }
+
+    // Move to the next voltage, recording why this one failed
+    fn skip(&mut self, reason: String) {
+        if let Some(voltage) = self.voltages.get(self.index) {
+            self.errors.push(format!("{:.1}V: {}", voltage, reason));
+        }
+        self.index += 1;
+        self.set_state(LearnState::Request);
+    }
+
+    // The voltage to request now, once per voltage
+    pub fn next_request(&mut self) -> Option<f32> {
+        if self.done || self.aborted.is_some() || self.state != LearnState::Request {
+            return None;
+        }
+        let voltage = *self.voltages.get(self.index)?;
+        self.set_state(LearnState::Requested);
+        Some(voltage)
+    }
+
+    // Result of the request: the contract voltage, None if it failed
+    pub fn contract(&mut self, contract: Option<f32>) {
+        if self.state != LearnState::Requested {
+            return;
+        }
+        match contract {
+            Some(_) => self.set_state(LearnState::Settling),
+            None => self.skip("request rejected".to_string()),
+        }
+    }
+
+    // True once the rail has settled: the ramp is to be started on all the channels
+    pub fn start_ramp(&mut self) -> bool {
+        if self.aborted.is_some() || self.state != LearnState::Settling
+            || self.clock.now_ns() - self.state_start < SETTLE_MS * 1_000_000 {
+            return false;
+        }
+        self.pending.iter_mut().for_each(|p| *p = true);
+        self.set_state(LearnState::Ramping);
+        true
+    }
+
+    // Result of the ramp of a channel
+    pub fn ramp_result(&mut self, channel: usize, result: Result<u32, String>) {
+        if self.state != LearnState::Ramping || !self.pending.get(channel).copied().unwrap_or(false) {
+            return;
+        }
+        self.pending[channel] = false;
+        let voltage = self.voltages[self.index];
+        match result {
+            Ok(offset) => self.tables[channel].insert(voltage, offset),
+            Err(e) => self.errors.push(format!("CH{} {:.1}V: {}", channel + 1, voltage, e)),
+        }
+        if self.pending.iter().all(|p| !p) {
+            self.index += 1;
+            self.set_state(LearnState::Request);
+        }
+    }
+
+    pub fn abort(&mut self, reason: &str) {
+        if !self.done && self.aborted.is_none() {
+            self.aborted = Some(reason.to_string());
+        }
+    }
+
+    // Failures of single voltages or channels so far
+    pub fn get_errors(&self) -> &[String] {
+        &self.errors
+    }
+
+    // Advance the learning. Returns the tables of all the channels, or the reason of the
+    // failure if a channel learned nothing, once.
+    pub fn poll(&mut self) -> Option<Result<Vec<OffsetTable>, String>> {
+        if self.done {
+            return None;
+        }
+        if self.aborted.is_none() {
+            let elapsed = self.clock.now_ns() - self.state_start;
+            match self.state {
+                LearnState::Requested if elapsed >= REQUEST_TIMEOUT_MS * 1_000_000 => {
+                    self.skip("no response to the request".to_string());
+                },
+                LearnState::Ramping if elapsed >= RAMP_TIMEOUT_MS * 1_000_000 => {
+                    self.skip("no result of the ramp".to_string());
+                },
+                _ => {},
+            }
+            if self.index < self.voltages.len() {
+                return None;
+            }
+        }
+        self.done = true;
+        if let Some(reason) = &self.aborted {
+            return Some(Err(reason.clone()));
+        }
+        if self.tables.iter().any(|t| t.is_empty()) {
+            return Some(Err(self.errors.last().cloned().unwrap_or_else(|| "no voltage to learn at".to_string())));
+        }
+        Some(Ok(self.tables.clone()))
+    }
+}
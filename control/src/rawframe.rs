@@ -0,0 +1,99 @@
+// Binary frames of the raw samples streamed over USB
+// In the raw streaming mode every sample of the control cycle (up to 2kHz per channel) is
+// written to the USB console as a fixed-size frame, little endian:
+//   0xA5 0x5A | sequence u32 | t_us u32 | channel u8 | flags u8 | voltage f32 | current f32 | CRC-16 u16
+// flags: bit 0 output on, bit 1 reused sample (failed read). The sequence counts the
+// samples of all the channels, including the ones dropped when the host does not read fast
+// enough, so a gap is a lost sample. The CRC (CRC-16/CCITT-FALSE) covers the bytes before
+// it. Text (log messages) may be mixed in the stream; the reader finds the frames by the
+// sync bytes and the CRC.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+pub const FRAME_SYNC: [u8; 2] = [0xA5, 0x5A];
+pub const FRAME_LEN: usize = 22;
+
+const FLAG_OUTPUT_ON: u8 = 0x01;
+const FLAG_STALE: u8 = 0x02;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RawSample {
+    pub sequence: u32,
+    // Monotonic time (us since boot, wraps in 71 minutes)
+    pub t_us: u32,
+    pub channel: u8,
+    pub output_on: bool,
+    // The last good sample reused after a failed read
+    pub stale: bool,
+    pub voltage: f32,
+    pub current: f32,
+}
+
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+impl RawSample {
+    pub fn encode(&self) -> [u8; FRAME_LEN] {
+        let mut frame = [0u8; FRAME_LEN];
+        frame[0..2].copy_from_slice(&FRAME_SYNC);
+        frame[2..6].copy_from_slice(&self.sequence.to_le_bytes());
+        frame[6..10].copy_from_slice(&self.t_us.to_le_bytes());
+        frame[10] = self.channel;
+        frame[11] = if self.output_on { FLAG_OUTPUT_ON } else { 0 } | if self.stale { FLAG_STALE } else { 0 };
+        frame[12..16].copy_from_slice(&self.voltage.to_le_bytes());
+        frame[16..20].copy_from_slice(&self.current.to_le_bytes());
+        let crc = crc16(&frame[..FRAME_LEN - 2]);
+        frame[20..22].copy_from_slice(&crc.to_le_bytes());
+        frame
+    }
+
+    // A frame at the start of the bytes, None if it is not one (sync bytes or CRC)
+    pub fn decode(bytes: &[u8]) -> Option<RawSample> {
+        if bytes.len() < FRAME_LEN || bytes[0..2] != FRAME_SYNC {
+            return None;
+        }
+        let crc = u16::from_le_bytes([bytes[20], bytes[21]]);
+        if crc16(&bytes[..FRAME_LEN - 2]) != crc {
+            return None;
+        }
+        let word = |i: usize| [bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]];
+        Some(RawSample {
+            sequence: u32::from_le_bytes(word(2)),
+            t_us: u32::from_le_bytes(word(6)),
+            channel: bytes[10],
+            output_on: bytes[11] & FLAG_OUTPUT_ON != 0,
+            stale: bytes[11] & FLAG_STALE != 0,
+            voltage: f32::from_le_bytes(word(12)),
+            current: f32::from_le_bytes(word(16)),
+        })
+    }
+}
+
+// The frames in a received block, skipping anything else. Returns them and the bytes
+// consumed; the rest (the start of a frame) is to be kept for the next block.
+pub fn scan(bytes: &[u8]) -> (Vec<RawSample>, usize) {
+    let mut samples = Vec::new();
+    let mut pos = 0;
+    while pos + FRAME_LEN <= bytes.len() {
+        match RawSample::decode(&bytes[pos..]) {
+            Some(sample) => {
+                samples.push(sample);
+                pos += FRAME_LEN;
+            },
+            None => pos += 1,
+        }
+    }
+    // Keep a possible partial frame
+    let rest = bytes[pos..].iter().position(|b| *b == FRAME_SYNC[0]).map_or(bytes.len(), |i| pos + i);
+    (samples, rest)
+}
@@ -0,0 +1,111 @@
+// Automatic restart-after-fault policy
+// After a non-critical trip, the output is restarted after a cooldown up to max_retries times.
+// Critical trips and repeated trips latch the output off until the operator restarts it.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use crate::hal::{Clock, SystemClock};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TripCause {
+    OverCurrent,
+    OverPower,
+    OverTemperature,
+    Interlock,
+    ShortCircuit,
+    // The measurement failed for too many control cycles
+    SensorFault,
+    // The output exceeded the absolute over-voltage limit
+    OverVoltage,
+}
+
+impl TripCause {
+    // Only transient electrical trips are recoverable
+    pub fn is_critical(&self) -> bool {
+        match self {
+            TripCause::OverCurrent | TripCause::OverPower => false,
+            TripCause::OverTemperature | TripCause::Interlock | TripCause::ShortCircuit | TripCause::SensorFault
+                | TripCause::OverVoltage => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecoveryAction {
+    Retry(u32),
+    Latch,
+}
+
+pub struct RecoveryPolicy<C: Clock = SystemClock> {
+    enable: bool,
+    cooldown_secs: u32,
+    max_retries: u32,
+    retries: u32,
+    pending: bool,
+    trip_time: u128,
+    clock: C,
+}
+
+impl RecoveryPolicy<SystemClock> {
+    pub fn new(enable: bool, cooldown_secs: u32, max_retries: u32) -> RecoveryPolicy {
+        RecoveryPolicy::with_clock(enable, cooldown_secs, max_retries, SystemClock)
+    }
+}
+
+impl<C: Clock> RecoveryPolicy<C> {
+    pub fn with_clock(enable: bool, cooldown_secs: u32, max_retries: u32, clock: C) -> RecoveryPolicy<C> {
+        RecoveryPolicy {
+            enable: enable,
+            cooldown_secs: cooldown_secs,
+            max_retries: max_retries,
+            retries: 0,
+            pending: false,
+            trip_time: clock.now_ns(),
+            clock: clock,
+        }
+    }
+
+    // Clear the retry count when the operator starts or stops the output
+    pub fn reset(&mut self) {
+        self.retries = 0;
+        self.pending = false;
+    }
+
+    pub fn on_trip(&mut self, cause: TripCause) -> RecoveryAction {
+        if !self.enable || cause.is_critical() || self.retries >= self.max_retries {
+            self.pending = false;
+            return RecoveryAction::Latch;
+        }
+        self.retries += 1;
+        self.pending = true;
+        self.trip_time = self.clock.now_ns();
+        RecoveryAction::Retry(self.retries)
+    }
+
+    // Returns true once when the cooldown of a pending retry has elapsed
+    pub fn poll(&mut self) -> bool {
+        if !self.pending {
+            return false;
+        }
+        let elapsed = self.clock.now_ns().saturating_sub(self.trip_time) / 1_000_000_000;
+        if elapsed >= self.cooldown_secs as u128 {
+            self.pending = false;
+            return true;
+        }
+        false
+    }
+
+    pub fn get_retries(&self) -> u32 {
+        self.retries
+    }
+
+    pub fn get_max_retries(&self) -> u32 {
+        self.max_retries
+    }
+
+    pub fn get_cooldown_secs(&self) -> u32 {
+        self.cooldown_secs
+    }
+}
@@ -0,0 +1,137 @@
+// Regulation quality statistics of an output session
+// The error (measured - setpoint) is evaluated once the output has settled within the band
+// after a start or a setpoint change. A current change of at least load_step between two
+// samples is a load step; its recovery time is until the error is back within the band
+// (and stays there for the settle time).
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use crate::hal::{Clock, SystemClock};
+
+// Defaults of the firmware
+pub const REGULATION_BAND_V: f32 = 0.05;
+pub const LOAD_STEP_A: f32 = 0.1;
+pub const SETTLE_MS: u32 = 100;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RegulationReport {
+    // Mean error while settled, outside the load step recoveries (V)
+    pub steady_state_error: f32,
+    // Largest absolute error while settled, including the load steps (V)
+    pub peak_deviation: f32,
+    pub load_steps: u32,
+    // Recovery time of the last load step and the longest one
+    pub recovery_time_ms: f32,
+    pub max_recovery_time_ms: f32,
+    // Samples of the steady-state error, 0 if the output has not settled
+    pub samples: u32,
+}
+
+pub struct RegulationStats<C: Clock = SystemClock> {
+    band: f32,
+    load_step: f32,
+    settle_ns: u128,
+    setpoint: f32,
+    settled: bool,
+    in_band_since: Option<u128>,
+    last_current: Option<f32>,
+    // Time of the load step being recovered and the last sample out of the band after it
+    step_time: Option<u128>,
+    step_out_of_band: Option<u128>,
+    error_sum: f64,
+    report: RegulationReport,
+    clock: C,
+}
+
+impl RegulationStats<SystemClock> {
+    pub fn new(band: f32, load_step: f32, settle_ms: u32) -> RegulationStats {
+        RegulationStats::with_clock(band, load_step, settle_ms, SystemClock)
+    }
+}
+
+impl<C: Clock> RegulationStats<C> {
+    pub fn with_clock(band: f32, load_step: f32, settle_ms: u32, clock: C) -> RegulationStats<C> {
+        RegulationStats {
+            band: band,
+            load_step: load_step,
+            settle_ns: settle_ms as u128 * 1_000_000,
+            setpoint: 0.0,
+            settled: false,
+            in_band_since: None,
+            last_current: None,
+            step_time: None,
+            step_out_of_band: None,
+            error_sum: 0.0,
+            report: RegulationReport::default(),
+            clock: clock,
+        }
+    }
+
+    // Start a new session (output started)
+    pub fn reset(&mut self) {
+        self.settled = false;
+        self.in_band_since = None;
+        self.last_current = None;
+        self.step_time = None;
+        self.step_out_of_band = None;
+        self.error_sum = 0.0;
+        self.report = RegulationReport::default();
+    }
+
+    // One sample of the running output
+    pub fn update(&mut self, setpoint: f32, voltage: f32, current: f32) {
+        let now = self.clock.now_ns();
+        let error = voltage - setpoint;
+        let in_band = error.abs() <= self.band;
+        let current_step = self.last_current.map(|last| (current - last).abs() >= self.load_step).unwrap_or(false);
+        self.last_current = Some(current);
+        if setpoint != self.setpoint {
+            // The transient of a setpoint change is not a regulation error
+            self.setpoint = setpoint;
+            self.settled = false;
+            self.in_band_since = None;
+            self.step_time = None;
+        }
+        if !self.settled {
+            if !in_band {
+                self.in_band_since = None;
+                return;
+            }
+            let since = *self.in_band_since.get_or_insert(now);
+            self.settled = now - since >= self.settle_ns;
+            return;
+        }
+        self.report.peak_deviation = self.report.peak_deviation.max(error.abs());
+        if current_step && self.step_time.is_none() {
+            self.step_time = Some(now);
+            self.step_out_of_band = None;
+            self.report.load_steps += 1;
+        }
+        if let Some(step_time) = self.step_time {
+            if !in_band {
+                self.step_out_of_band = Some(now);
+            }
+            let last_out = self.step_out_of_band.unwrap_or(step_time);
+            if in_band && now - last_out >= self.settle_ns {
+                let recovery_ms = (last_out - step_time) as f32 / 1_000_000.0;
+                self.report.recovery_time_ms = recovery_ms;
+                self.report.max_recovery_time_ms = self.report.max_recovery_time_ms.max(recovery_ms);
+                self.step_time = None;
+            }
+            return;
+        }
+        self.error_sum += error as f64;
+        self.report.samples += 1;
+        self.report.steady_state_error = (self.error_sum / self.report.samples as f64) as f32;
+    }
+
+    pub fn report(&self) -> RegulationReport {
+        self.report
+    }
+
+    pub fn is_settled(&self) -> bool {
+        self.settled
+    }
+}
@@ -0,0 +1,136 @@
+// Output voltage regulation
+// Converts the PID output into the PWM duty of the buck stage for each control period.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use log::info;
+use crate::hal::{Clock, SystemClock};
+use crate::pidcont::{PIDController, PidTerms};
+
+// PID is reset when the output exceeds the setpoint by this ratio
+const OVERSHOOT_RATIO: f32 = 1.10;
+// pwm_offset is given in counts of the 14-bit PWM (4kHz default) and scaled to max_duty
+pub const PWM_OFFSET_REFERENCE_DUTY: u32 = 16383;
+
+pub struct Regulator<C: Clock = SystemClock> {
+    pid: PIDController<C>,
+    max_duty: u32,
+    pwm_offset: u32,
+    // Temporal dithering between adjacent duty codes for sub-LSB resolution
+    dither: bool,
+    dither_residual: f32,
+    // Step-down of the setpoint in progress, and the setpoint of the last period
+    stepping_down: bool,
+    last_setpoint: f32,
+}
+
+impl Regulator<SystemClock> {
+    pub fn new(kp: f32, ki: f32, kd: f32, max_duty: u32, pwm_offset: u32) -> Regulator {
+        Regulator::with_clock(kp, ki, kd, max_duty, pwm_offset, SystemClock)
+    }
+}
+
+impl<C: Clock> Regulator<C> {
+    pub fn with_clock(kp: f32, ki: f32, kd: f32, max_duty: u32, pwm_offset: u32, clock: C) -> Regulator<C> {
+        Regulator {
+            pid: PIDController::with_clock(kp, ki, kd, 0.0, clock),
+            max_duty: max_duty,
+            pwm_offset: pwm_offset,
+            dither: false,
+            dither_residual: 0.0,
+            stepping_down: false,
+            last_setpoint: 0.0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.pid.reset();
+        self.dither_residual = 0.0;
+    }
+
+    pub fn set_dither(&mut self, dither: bool) {
+        self.dither = dither;
+        self.dither_residual = 0.0;
+    }
+
+    // During a step-down (see stepdown) the PID output is scaled with the setpoint, as the
+    // output of the buck stage follows the duty, and the output above the lowered setpoint is
+    // not taken as an overshoot
+    pub fn set_stepping_down(&mut self, stepping_down: bool) {
+        self.stepping_down = stepping_down;
+    }
+
+    // Runtime tuning, applied from the next control period
+    pub fn set_gains(&mut self, kp: f32, ki: f32, kd: f32, pwm_offset: u32) {
+        self.pid.set_gains(kp, ki, kd);
+        self.pwm_offset = pwm_offset;
+    }
+
+    // Offset learned for the rail voltage, applied from the next control period
+    pub fn set_pwm_offset(&mut self, pwm_offset: u32) {
+        self.pwm_offset = pwm_offset;
+    }
+
+    pub fn get_pwm_offset(&self) -> u32 {
+        self.pwm_offset
+    }
+
+    // PID internals of the last control period
+    pub fn get_terms(&self) -> PidTerms {
+        self.pid.get_terms()
+    }
+
+    pub fn get_max_duty(&self) -> u32 {
+        self.max_duty
+    }
+
+    // pwm_offset in counts of max_duty
+    fn duty_offset(&self) -> f32 {
+        self.pwm_offset as f32 * self.max_duty as f32 / PWM_OFFSET_REFERENCE_DUTY as f32
+    }
+
+    // Duty code for the fractional duty. With dithering the fraction is carried to the
+    // next period (first order sigma-delta), so the average over a few periods is exact.
+    fn duty_code(&mut self, duty: f32) -> u32 {
+        let duty = duty.clamp(0.0, self.max_duty as f32);
+        if !self.dither {
+            return duty as u32;
+        }
+        let target = duty + self.dither_residual;
+        let code = target.floor().clamp(0.0, self.max_duty as f32);
+        self.dither_residual = (target - code).clamp(-1.0, 1.0);
+        code as u32
+    }
+
+    // Output stopped: returns the duty to apply
+    pub fn stop(&mut self) -> u32 {
+        self.pid.reset();
+        0
+    }
+
+    // Returns the duty for this control period
+    pub fn update(&mut self, setpoint: f32, voltage: f32, current: f32, current_limit: f32) -> u32 {
+        self.pid.set_setpoint(setpoint);
+        if self.stepping_down && self.last_setpoint > 0.0 && setpoint < self.last_setpoint {
+            self.pid.scale_integral(setpoint / self.last_setpoint);
+        }
+        self.last_setpoint = setpoint;
+        if current > current_limit {
+            // no voltage, over current
+            info!("Voltage Off due to over current or load stop {}", current);
+            return self.stop();
+        }
+        // Check voltage overshoot (>110% of setpoint)
+        let voltage_overshoot_threshold = setpoint * OVERSHOOT_RATIO;
+        if voltage > voltage_overshoot_threshold && setpoint > 0.0 && !self.stepping_down {
+            info!("Voltage overshoot detected: {:.3}V > {:.3}V (110% of {:.3}V) - Resetting PID",
+                  voltage, voltage_overshoot_threshold, setpoint);
+            self.pid.reset();
+            // Continue with PID control after reset
+        }
+        let pid_out = self.pid.update(voltage);
+        // A negative PID output is a zero duty before the offset
+        let duty = (pid_out * (self.max_duty as f32)).max(0.0) + self.duty_offset();
+        self.duty_code(duty)
+    }
+}
@@ -0,0 +1,38 @@
+// Output ripple estimation from a burst of voltage readings
+// The readings are taken back to back at the fastest conversion without averaging, so the
+// sample rate is set by the bus transfers. Peak-to-peak and RMS (AC, around the mean) of the
+// burst estimate the ripple amplitude; the burst is too slow to resolve the waveform.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RippleReport {
+    pub samples: u32,
+    pub sample_rate_hz: f32,
+    pub mean: f32,
+    pub peak_to_peak: f32,
+    pub rms: f32,
+}
+
+impl RippleReport {
+    // Readings of a burst which took duration_us
+    pub fn from_samples(samples: &[f32], duration_us: u64) -> RippleReport {
+        if samples.is_empty() {
+            return RippleReport::default();
+        }
+        let n = samples.len() as f64;
+        let mean = samples.iter().map(|&v| v as f64).sum::<f64>() / n;
+        let variance = samples.iter().map(|&v| (v as f64 - mean).powi(2)).sum::<f64>() / n;
+        let max = samples.iter().cloned().fold(f32::MIN, f32::max);
+        let min = samples.iter().cloned().fold(f32::MAX, f32::min);
+        RippleReport {
+            samples: samples.len() as u32,
+            sample_rate_hz: if duration_us > 0 { (n * 1_000_000.0 / duration_us as f64) as f32 } else { 0.0 },
+            mean: mean as f32,
+            peak_to_peak: max - min,
+            rms: variance.sqrt() as f32,
+        }
+    }
+}
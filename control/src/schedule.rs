@@ -0,0 +1,173 @@
+// Scheduled operation at wall-clock times
+// A schedule is a list of entries separated by ';', each "[days] HH:MM [ch1|ch2] <action>":
+//   days   : daily (default), weekdays, weekends, a day (mon) or a range of days (mon-fri)
+//   action : on, off, a voltage setpoint (12.0V) or cycle (start the endurance test on ch1)
+// e.g. "weekdays 08:00 on; weekdays 18:00 off; sat 09:00 ch2 5.0V"
+// The caller passes the local day and time; an entry fires once when its minute begins.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const ALL_DAYS: u8 = 0x7f;
+const WEEKDAYS: u8 = 0x1f;
+const WEEKENDS: u8 = 0x60;
+const MAX_CHANNELS: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScheduleAction {
+    Output(bool),
+    Voltage(f32),
+    Cycle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScheduleEntry {
+    // Bit 0 is Monday
+    pub days: u8,
+    pub minute_of_day: u16,
+    // Channel index (0 is CH1)
+    pub channel: usize,
+    pub action: ScheduleAction,
+}
+
+impl ScheduleEntry {
+    pub fn to_text(&self) -> String {
+        let days = match self.days {
+            ALL_DAYS => "daily".to_string(),
+            WEEKDAYS => "weekdays".to_string(),
+            WEEKENDS => "weekends".to_string(),
+            _ => (0..7).filter(|d| self.days & (1 << d) != 0).map(|d| DAY_NAMES[d]).collect::<Vec<_>>().join("+"),
+        };
+        let action = match self.action {
+            ScheduleAction::Output(true) => "on".to_string(),
+            ScheduleAction::Output(false) => "off".to_string(),
+            ScheduleAction::Voltage(v) => format!("{:.3}V", v),
+            ScheduleAction::Cycle => "cycle".to_string(),
+        };
+        format!("{} {:02}:{:02} ch{} {}", days, self.minute_of_day / 60, self.minute_of_day % 60, self.channel + 1, action)
+    }
+}
+
+pub struct Schedule {
+    entries: Vec<ScheduleEntry>,
+    // Day and minute of the last poll
+    last: Option<(u8, u16)>,
+}
+
+impl Schedule {
+    pub fn new(entries: Vec<ScheduleEntry>) -> Schedule {
+        Schedule { entries: entries, last: None }
+    }
+
+    pub fn parse(text: &str) -> Result<Schedule, String> {
+        let mut entries = Vec::new();
+        for item in text.split(';').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            entries.push(parse_entry(item)?);
+        }
+        Ok(Schedule::new(entries))
+    }
+
+    pub fn get_entries(&self) -> &[ScheduleEntry] {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Entries to run now; weekday 0 is Monday. Each minute is run once.
+    pub fn poll(&mut self, weekday: u8, minute_of_day: u16) -> Vec<ScheduleEntry> {
+        let now = (weekday % 7, minute_of_day);
+        if self.last == Some(now) {
+            return Vec::new();
+        }
+        self.last = Some(now);
+        self.entries.iter()
+            .filter(|e| e.minute_of_day == now.1 && e.days & (1 << now.0) != 0)
+            .copied()
+            .collect()
+    }
+}
+
+fn parse_entry(item: &str) -> Result<ScheduleEntry, String> {
+    let tokens: Vec<&str> = item.split_whitespace().collect();
+    let mut rest = &tokens[..];
+    let mut days = ALL_DAYS;
+    if let Some(first) = rest.first() {
+        if !first.contains(':') {
+            days = parse_days(first).ok_or_else(|| format!("'{}': unknown days '{}'", item, first))?;
+            rest = &rest[1..];
+        }
+    }
+    let time = rest.first().ok_or_else(|| format!("'{}': missing time", item))?;
+    let minute_of_day = parse_time(time).ok_or_else(|| format!("'{}': time must be HH:MM", item))?;
+    rest = &rest[1..];
+    let mut channel = 0;
+    if let Some(ch) = rest.first().and_then(|t| t.strip_prefix("ch")) {
+        channel = match ch.parse::<usize>() {
+            Ok(n) if (1..=MAX_CHANNELS).contains(&n) => n - 1,
+            _ => return Err(format!("'{}': channel must be ch1 or ch2", item)),
+        };
+        rest = &rest[1..];
+    }
+    let action = match rest {
+        [a] if a.eq_ignore_ascii_case("on") => ScheduleAction::Output(true),
+        [a] if a.eq_ignore_ascii_case("off") => ScheduleAction::Output(false),
+        [a] if a.eq_ignore_ascii_case("cycle") => ScheduleAction::Cycle,
+        [a] => {
+            let value = a.strip_suffix(['V', 'v']).unwrap_or(a);
+            match value.parse::<f32>() {
+                Ok(v) if v.is_finite() && v >= 0.0 => ScheduleAction::Voltage(v),
+                _ => return Err(format!("'{}': action must be on, off, cycle or a voltage", item)),
+            }
+        },
+        _ => return Err(format!("'{}': one action of on, off, cycle or a voltage expected", item)),
+    };
+    if action == ScheduleAction::Cycle && channel != 0 {
+        return Err(format!("'{}': the cycle test runs on ch1", item));
+    }
+    Ok(ScheduleEntry { days: days, minute_of_day: minute_of_day, channel: channel, action: action })
+}
+
+fn parse_days(text: &str) -> Option<u8> {
+    let text = text.to_ascii_lowercase();
+    match text.as_str() {
+        "daily" => return Some(ALL_DAYS),
+        "weekdays" => return Some(WEEKDAYS),
+        "weekends" => return Some(WEEKENDS),
+        _ => {},
+    }
+    let mut days = 0u8;
+    for part in text.split('+') {
+        let day = |name: &str| DAY_NAMES.iter().position(|d| *d == name);
+        match part.split_once('-') {
+            Some((from, to)) => {
+                // A range may wrap over the week end (fri-mon)
+                let (from, to) = (day(from)?, day(to)?);
+                let mut d = from;
+                loop {
+                    days |= 1 << d;
+                    if d == to {
+                        break;
+                    }
+                    d = (d + 1) % 7;
+                }
+            },
+            None => days |= 1 << day(part)?,
+        }
+    }
+    Some(days)
+}
+
+fn parse_time(text: &str) -> Option<u16> {
+    let (h, m) = text.split_once(':')?;
+    let (h, m) = (h.parse::<u16>().ok()?, m.parse::<u16>().ok()?);
+    if h < 24 && m < 60 {
+        Some(h * 60 + m)
+    }
+    else {
+        None
+    }
+}
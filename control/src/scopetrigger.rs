@@ -0,0 +1,172 @@
+// Oscilloscope trigger output
+// A GPIO pulses when a fault or an alarm fires, so an oscilloscope on the output triggers on
+// the pulse and captures the analog event around it (pretrigger) without a trigger level on
+// the signal itself. The events routed to the output are a list separated by ',':
+//   trip   : a protection trip of a channel (the control task pulses at the trip)
+//   alarm  : an alarm rule raised (see alarms)
+//   marker : a marker of the operator (see marker)
+// e.g. "trip,alarm". The pulse is active high or low, and ends at the first control cycle
+// after the width. Events within HOLDOFF_MS of the start of a pulse do not pulse again, so a
+// trip seen by the control task and by the main loop, or several alarms raised together,
+// give one pulse.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+// Events after the start of a pulse which are not pulsed (ms)
+pub const HOLDOFF_MS: u64 = 100;
+pub const MAX_WIDTH_MS: u32 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TriggerSource {
+    Trip,
+    Alarm,
+    Marker,
+}
+
+impl TriggerSource {
+    pub fn parse(text: &str) -> Option<TriggerSource> {
+        match text {
+            "trip" => Some(TriggerSource::Trip),
+            "alarm" => Some(TriggerSource::Alarm),
+            "marker" => Some(TriggerSource::Marker),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TriggerSource::Trip => "trip",
+            TriggerSource::Alarm => "alarm",
+            TriggerSource::Marker => "marker",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TriggerPolarity {
+    // Idle low, the pulse is high
+    High,
+    Low,
+}
+
+impl TriggerPolarity {
+    pub fn parse(text: &str) -> Option<TriggerPolarity> {
+        match text {
+            "high" => Some(TriggerPolarity::High),
+            "low" => Some(TriggerPolarity::Low),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TriggerPolarity::High => "high",
+            TriggerPolarity::Low => "low",
+        }
+    }
+
+    // Level of the pin (true is high) in and out of a pulse
+    pub fn level(&self, active: bool) -> bool {
+        active == (*self == TriggerPolarity::High)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TriggerConfig {
+    pub polarity: TriggerPolarity,
+    pub width_ms: u32,
+    // The sources routed to the output
+    pub trip: bool,
+    pub alarm: bool,
+    pub marker: bool,
+}
+
+impl TriggerConfig {
+    pub fn new(polarity: TriggerPolarity, width_ms: u32, events: &str) -> Result<TriggerConfig, String> {
+        if width_ms == 0 || width_ms > MAX_WIDTH_MS {
+            return Err(format!("width must be 1 to {}ms", MAX_WIDTH_MS));
+        }
+        let mut config = TriggerConfig { polarity: polarity, width_ms: width_ms, trip: false, alarm: false, marker: false };
+        for item in events.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+            match TriggerSource::parse(item) {
+                Some(TriggerSource::Trip) => config.trip = true,
+                Some(TriggerSource::Alarm) => config.alarm = true,
+                Some(TriggerSource::Marker) => config.marker = true,
+                None => return Err(format!("'{}': events must be trip, alarm or marker", item)),
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn is_routed(&self, source: TriggerSource) -> bool {
+        match source {
+            TriggerSource::Trip => self.trip,
+            TriggerSource::Alarm => self.alarm,
+            TriggerSource::Marker => self.marker,
+        }
+    }
+}
+
+pub struct ScopeTrigger {
+    config: TriggerConfig,
+    // Start of the last pulse (us)
+    started_us: Option<u64>,
+    active: bool,
+    pulses: u32,
+}
+
+impl ScopeTrigger {
+    pub fn new(config: TriggerConfig) -> ScopeTrigger {
+        ScopeTrigger { config: config, started_us: None, active: false, pulses: 0 }
+    }
+
+    pub fn get_config(&self) -> &TriggerConfig {
+        &self.config
+    }
+
+    // A pulse in progress keeps its width
+    pub fn set_config(&mut self, config: TriggerConfig) {
+        self.config = config;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    // Pulses since boot
+    pub fn pulses(&self) -> u32 {
+        self.pulses
+    }
+
+    // Level of the pin now (true is high)
+    pub fn level(&self) -> bool {
+        self.config.polarity.level(self.active)
+    }
+
+    // An event; true if a pulse starts
+    pub fn fire(&mut self, source: TriggerSource, now_us: u64) -> bool {
+        if !self.config.is_routed(source) {
+            return false;
+        }
+        if self.started_us.is_some_and(|started| now_us.saturating_sub(started) < HOLDOFF_MS * 1000) {
+            return false;
+        }
+        self.started_us = Some(now_us);
+        self.active = true;
+        self.pulses += 1;
+        true
+    }
+
+    // Each control cycle; true if the pulse ends
+    pub fn poll(&mut self, now_us: u64) -> bool {
+        match self.started_us {
+            Some(started) if self.active && now_us.saturating_sub(started) >= self.config.width_ms as u64 * 1000 => {
+                self.active = false;
+                true
+            },
+            _ => false,
+        }
+    }
+}
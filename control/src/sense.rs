@@ -0,0 +1,52 @@
+// Remote (load point) voltage sense
+// The output is regulated on the voltage sensed at the DUT terminals, which compensates the
+// drop of the output wiring. If the remote pair appears disconnected (no reading, more than
+// max_drop below the local voltage, or above it) the local voltage is used until the output
+// is restarted, so a lost sense lead can raise the output by max_drop at most.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+// Remote voltage above the local voltage by more than this is not plausible
+pub const REMOTE_SENSE_MAX_REVERSE_V: f32 = 0.2;
+
+pub struct RemoteSense {
+    max_drop: f32,
+    lost: bool,
+}
+
+impl RemoteSense {
+    pub fn new(max_drop: f32) -> RemoteSense {
+        RemoteSense { max_drop: max_drop, lost: false }
+    }
+
+    // Output started: try the remote sense again
+    pub fn reset(&mut self) {
+        self.lost = false;
+    }
+
+    pub fn is_lost(&self) -> bool {
+        self.lost
+    }
+
+    // Voltage to regulate on, and the reason when the remote sense is lost in this sample
+    pub fn select(&mut self, local: f32, remote: Option<f32>) -> (f32, Option<&'static str>) {
+        if self.lost {
+            return (local, None);
+        }
+        let reason = match remote {
+            None => Some("no reading"),
+            Some(v) if local - v > self.max_drop => Some("too far below the local voltage"),
+            Some(v) if v - local > REMOTE_SENSE_MAX_REVERSE_V => Some("above the local voltage"),
+            Some(_) => None,
+        };
+        match (reason, remote) {
+            (None, Some(v)) => (v, None),
+            _ => {
+                self.lost = true;
+                (local, reason)
+            }
+        }
+    }
+}
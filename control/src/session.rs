@@ -0,0 +1,182 @@
+// Output session report
+// The records of a channel between output ON and OFF are integrated into the energy (Wh)
+// and the charge (Ah) by the clock of the records, with the minimum and maximum voltage
+// and current and the trips of the session. The run label (DUT identifier and note) set
+// before or during the session is kept with the report.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use std::fmt::Write;
+use crate::currentlogs::CurrentLog;
+
+// A gap longer than this between two records is not integrated (ns)
+const MAX_RECORD_GAP_NS: u128 = 1_000_000_000;
+pub const MAX_DUT_LEN: usize = 32;
+pub const MAX_NOTE_LEN: usize = 128;
+
+// Identifier of the device under test and a free note of the operator
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RunLabel {
+    pub dut: String,
+    pub note: String,
+}
+
+impl RunLabel {
+    pub fn new(dut: &str, note: &str) -> Result<RunLabel, String> {
+        let (dut, note) = (dut.trim(), note.trim());
+        if dut.chars().count() > MAX_DUT_LEN || note.chars().count() > MAX_NOTE_LEN {
+            return Err(format!("DUT up to {} and note up to {} characters", MAX_DUT_LEN, MAX_NOTE_LEN));
+        }
+        if dut.chars().chain(note.chars()).any(|c| c.is_control()) {
+            return Err("no control characters".to_string());
+        }
+        if !note.is_empty() && dut.is_empty() {
+            return Err("a note needs a DUT identifier".to_string());
+        }
+        Ok(RunLabel { dut: dut.to_string(), note: note.to_string() })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dut.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SessionReport {
+    pub channel: u8,
+    pub setpoint: f32,
+    pub label: RunLabel,
+    // Clock of the start and the end (ns)
+    pub start_clock: u128,
+    pub end_clock: u128,
+    pub samples: u32,
+    pub energy_wh: f64,
+    pub charge_ah: f64,
+    pub voltage_min: f32,
+    pub voltage_max: f32,
+    pub current_min: f32,
+    pub current_max: f32,
+    pub trips: Vec<String>,
+    // "stopped", or the trip which ended the session
+    pub end_reason: String,
+}
+
+impl SessionReport {
+    pub fn duration_secs(&self) -> f64 {
+        self.end_clock.saturating_sub(self.start_clock) as f64 / 1_000_000_000.0
+    }
+
+    // Human-readable report; start_time is the formatted start clock
+    pub fn to_text(&self, start_time: &str) -> String {
+        let duration = self.duration_secs() as u64;
+        let mut text = String::new();
+        let _ = writeln!(text, "DC Power Unit session report");
+        let _ = writeln!(text, "Channel:   {}", self.channel);
+        if !self.label.is_empty() {
+            let _ = writeln!(text, "DUT:       {}", self.label.dut);
+        }
+        if !self.label.note.is_empty() {
+            let _ = writeln!(text, "Note:      {}", self.label.note);
+        }
+        let _ = writeln!(text, "Start:     {}", start_time);
+        let _ = writeln!(text, "Duration:  {}h {:02}m {:02}s", duration / 3600, duration / 60 % 60, duration % 60);
+        let _ = writeln!(text, "Setpoint:  {:.3} V", self.setpoint);
+        if self.samples > 0 {
+            let _ = writeln!(text, "Voltage:   {:.3} V to {:.3} V", self.voltage_min, self.voltage_max);
+            let _ = writeln!(text, "Current:   {:.3} A to {:.3} A", self.current_min, self.current_max);
+        }
+        let _ = writeln!(text, "Energy:    {:.4} Wh", self.energy_wh);
+        let _ = writeln!(text, "Charge:    {:.4} Ah", self.charge_ah);
+        let _ = writeln!(text, "Samples:   {}", self.samples);
+        let trips = if self.trips.is_empty() { "none".to_string() } else { self.trips.join(", ") };
+        let _ = writeln!(text, "Trips:     {}", trips);
+        let _ = writeln!(text, "End:       {}", self.end_reason);
+        text
+    }
+}
+
+#[derive(Default)]
+pub struct SessionTracker {
+    report: Option<SessionReport>,
+    last: Option<(u128, f32, f32)>,
+    label: RunLabel,
+}
+
+impl SessionTracker {
+    pub fn new() -> SessionTracker {
+        SessionTracker::default()
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.report.is_some()
+    }
+
+    pub fn start(&mut self, channel: u8, setpoint: f32, clock: u128) {
+        self.report = Some(SessionReport {
+            channel: channel,
+            setpoint: setpoint,
+            label: self.label.clone(),
+            start_clock: clock,
+            end_clock: clock,
+            end_reason: String::new(),
+            ..Default::default()
+        });
+        self.last = None;
+    }
+
+    // One record of the running output (ignored without a session). The power and the
+    // current are integrated with the trapezoidal rule.
+    pub fn add(&mut self, sample: &CurrentLog) {
+        let Some(report) = self.report.as_mut() else {
+            return;
+        };
+        if report.samples == 0 {
+            report.voltage_min = sample.voltage;
+            report.voltage_max = sample.voltage;
+            report.current_min = sample.current;
+            report.current_max = sample.current;
+        }
+        report.samples += 1;
+        report.end_clock = report.end_clock.max(sample.clock);
+        report.voltage_min = report.voltage_min.min(sample.voltage);
+        report.voltage_max = report.voltage_max.max(sample.voltage);
+        report.current_min = report.current_min.min(sample.current);
+        report.current_max = report.current_max.max(sample.current);
+        if let Some((clock, power, current)) = self.last {
+            let dt_ns = sample.clock.saturating_sub(clock);
+            if dt_ns <= MAX_RECORD_GAP_NS {
+                let hours = dt_ns as f64 / 3_600_000_000_000.0;
+                report.energy_wh += (power + sample.power) as f64 / 2.0 * hours;
+                report.charge_ah += (current + sample.current) as f64 / 2.0 * hours;
+            }
+        }
+        self.last = Some((sample.clock, sample.power, sample.current));
+    }
+
+    // Label of the running session and the next ones
+    pub fn set_label(&mut self, label: &RunLabel) {
+        self.label = label.clone();
+        if let Some(report) = self.report.as_mut() {
+            report.label = label.clone();
+        }
+    }
+
+    pub fn trip(&mut self, cause: &str) {
+        if let Some(report) = self.report.as_mut() {
+            report.trips.push(cause.to_string());
+        }
+    }
+
+    // End the session; None without one
+    pub fn finish(&mut self, clock: u128) -> Option<SessionReport> {
+        let mut report = self.report.take()?;
+        report.end_clock = report.end_clock.max(clock);
+        report.end_reason = match report.trips.last() {
+            Some(cause) => format!("trip ({})", cause),
+            None => "stopped".to_string(),
+        };
+        Some(report)
+    }
+}
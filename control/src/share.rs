@@ -0,0 +1,190 @@
+// Current sharing of two units in parallel (master/slave)
+// Two outputs tied together do not share a load by themselves: the one with the slightly
+// higher voltage supplies nearly all of it. Both units lower their setpoint by the droop
+// (droop_ohm * own current), which spreads the load passively, and the master sends its
+// setpoint, output state and current to the slave over UDP. The slave follows the output
+// state and the setpoint, and trims its setpoint by an integral of the difference of the
+// currents, so each unit supplies half of the load. If the messages stop for the timeout,
+// the slave reports the share lost and stops (the master may have tripped, and the load
+// is more than one unit can supply).
+// Message: "DCPSHARE1 <sequence> <0|1 output on> <setpoint V> <current A>"
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use crate::hal::{Clock, SystemClock};
+
+pub const SHARE_MAGIC: &str = "DCPSHARE1";
+// The share is lost without a message from the master for this long
+pub const SHARE_TIMEOUT_MS: u128 = 500;
+// Trim of the slave setpoint per A of current difference per second (V/As)
+pub const SHARE_GAIN: f32 = 0.5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShareMode {
+    Off,
+    Master,
+    Slave,
+}
+
+impl ShareMode {
+    pub fn parse(text: &str) -> Option<ShareMode> {
+        match text {
+            "off" => Some(ShareMode::Off),
+            "master" => Some(ShareMode::Master),
+            "slave" => Some(ShareMode::Slave),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShareMessage {
+    pub sequence: u32,
+    pub output_on: bool,
+    pub setpoint: f32,
+    pub current: f32,
+}
+
+impl ShareMessage {
+    pub fn to_text(&self) -> String {
+        format!("{} {} {} {:.4} {:.4}", SHARE_MAGIC, self.sequence, self.output_on as u8, self.setpoint, self.current)
+    }
+
+    pub fn parse(text: &str) -> Result<ShareMessage, String> {
+        let fields : Vec<&str> = text.split_whitespace().collect();
+        match fields.as_slice() {
+            [magic, sequence, output_on, setpoint, current] if *magic == SHARE_MAGIC => {
+                let number = |v: &str| v.parse::<f32>().ok().filter(|v| v.is_finite()).ok_or(format!("invalid value: {}", v));
+                Ok(ShareMessage {
+                    sequence: sequence.parse::<u32>().map_err(|_| format!("invalid sequence: {}", sequence))?,
+                    output_on: match *output_on {
+                        "0" => false,
+                        "1" => true,
+                        _ => return Err(format!("invalid output state: {}", output_on)),
+                    },
+                    setpoint: number(setpoint)?,
+                    current: number(current)?,
+                })
+            },
+            _ => Err("not a share message".to_string()),
+        }
+    }
+}
+
+// Smoothing of the current for the droop. The droop is applied in the main loop, a period
+// behind the current, and its loop gain (droop over the cable resistance) can exceed 1.
+const DROOP_ALPHA: f32 = 0.2;
+
+// Setpoint lowered by the droop at the (smoothed) output current
+pub struct Droop {
+    droop_ohm: f32,
+    current: f32,
+}
+
+impl Droop {
+    pub fn new(droop_ohm: f32) -> Droop {
+        Droop { droop_ohm: droop_ohm, current: 0.0 }
+    }
+
+    pub fn reset(&mut self) {
+        self.current = 0.0;
+    }
+
+    pub fn update(&mut self, setpoint: f32, current: f32) -> f32 {
+        self.current += DROOP_ALPHA * (current.max(0.0) - self.current);
+        setpoint - self.droop_ohm * self.current
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShareUpdate {
+    // No message from the master yet
+    Waiting,
+    // Output state and the setpoint to regulate on
+    Follow { output_on: bool, setpoint: f32 },
+    // The messages stopped, once
+    Lost,
+}
+
+pub struct ShareSlave<C: Clock = SystemClock> {
+    droop: Droop,
+    max_trim: f32,
+    trim: f32,
+    last: Option<ShareMessage>,
+    received: u128,
+    updated: u128,
+    lost: bool,
+    clock: C,
+}
+
+impl ShareSlave<SystemClock> {
+    pub fn new(droop_ohm: f32, max_trim: f32) -> ShareSlave {
+        ShareSlave::with_clock(droop_ohm, max_trim, SystemClock)
+    }
+}
+
+impl<C: Clock> ShareSlave<C> {
+    pub fn with_clock(droop_ohm: f32, max_trim: f32, clock: C) -> ShareSlave<C> {
+        ShareSlave {
+            droop: Droop::new(droop_ohm),
+            max_trim: max_trim,
+            trim: 0.0,
+            last: None,
+            received: 0,
+            updated: 0,
+            lost: false,
+            clock: clock,
+        }
+    }
+
+    pub fn get_trim(&self) -> f32 {
+        self.trim
+    }
+
+    pub fn get_master(&self) -> Option<&ShareMessage> {
+        self.last.as_ref()
+    }
+
+    pub fn is_lost(&self) -> bool {
+        self.lost
+    }
+
+    // A message from the master; an older one (reordered) is ignored
+    pub fn receive(&mut self, message: ShareMessage) {
+        if let Some(last) = self.last {
+            let behind = last.sequence.wrapping_sub(message.sequence);
+            if !self.lost && behind > 0 && behind < u32::MAX / 2 {
+                return;
+            }
+        }
+        self.last = Some(message);
+        self.received = self.clock.now_ns();
+        self.lost = false;
+    }
+
+    // Own output current, returns what to do
+    pub fn update(&mut self, current: f32) -> ShareUpdate {
+        let now = self.clock.now_ns();
+        let dt_s = if self.updated == 0 { 0.0 } else { (now - self.updated) as f32 / 1e9 };
+        self.updated = now;
+        let master = match self.last {
+            Some(master) if !self.lost => master,
+            _ => return ShareUpdate::Waiting,
+        };
+        if now - self.received > SHARE_TIMEOUT_MS * 1_000_000 {
+            self.lost = true;
+            self.trim = 0.0;
+            self.droop.reset();
+            return ShareUpdate::Lost;
+        }
+        if !master.output_on {
+            self.trim = 0.0;
+            self.droop.reset();
+            return ShareUpdate::Follow { output_on: false, setpoint: master.setpoint };
+        }
+        self.trim = (self.trim + SHARE_GAIN * (master.current - current) * dt_s.min(0.1)).clamp(-self.max_trim, self.max_trim);
+        ShareUpdate::Follow { output_on: true, setpoint: self.droop.update(master.setpoint, current) + self.trim }
+    }
+}
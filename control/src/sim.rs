@@ -0,0 +1,218 @@
+// Simulated plant for host-side tests of the control logic
+// The buck stage is modelled as an ideal converter (Vin x duty) followed by the RC
+// low-pass of the output filter, loaded with a resistor. The source resistance (0 by
+// default) makes the output droop on a load step. The wiring resistance (0 by default) is
+// between the output and the load, where the remote sense is connected. Below the dead
+// duty (0 by default) the stage does not raise the output.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use std::cell::Cell;
+use std::rc::Rc;
+use crate::hal::{Clock, PowerStage, OutputSensor};
+use crate::regulator::Regulator;
+use crate::limits::{OverVoltageDetector, ProtectionLimits, ShortCircuitDetector};
+use crate::recovery::TripCause;
+use crate::currentlogs::{CurrentLog, CurrentRecord};
+use crate::regstats::{RegulationStats, REGULATION_BAND_V, LOAD_STEP_A, SETTLE_MS};
+use crate::sense::RemoteSense;
+
+// Clock advanced by the simulation. Clones share the same time.
+#[derive(Debug, Clone, Default)]
+pub struct SimClock {
+    now_ns: Rc<Cell<u128>>,
+}
+
+impl SimClock {
+    pub fn new() -> SimClock {
+        // Start at a non-zero time like the wall clock of the unit
+        SimClock { now_ns: Rc::new(Cell::new(1_000_000_000)) }
+    }
+
+    pub fn advance_ms(&self, ms: u32) {
+        self.now_ns.set(self.now_ns.get() + ms as u128 * 1_000_000);
+    }
+}
+
+impl Clock for SimClock {
+    fn now_ns(&self) -> u128 {
+        self.now_ns.get()
+    }
+}
+
+// Plant with dynamics, advanced by the simulation between the control periods
+pub trait Plant: PowerStage + OutputSensor {
+    fn advance(&mut self, dt_ms: f32);
+}
+
+pub struct BuckPlant {
+    pub input_voltage: f32,
+    pub load_resistance: f32,
+    pub tau_ms: f32,
+    pub source_resistance: f32,
+    pub wiring_resistance: f32,
+    // Remote sense connected at the load, None without it
+    pub remote_sense: Option<bool>,
+    pub dead_duty: u32,
+    max_duty: u32,
+    duty: u32,
+    voltage: f32,
+}
+
+impl BuckPlant {
+    pub fn new(input_voltage: f32, load_resistance: f32, tau_ms: f32, max_duty: u32) -> BuckPlant {
+        BuckPlant {
+            input_voltage: input_voltage,
+            load_resistance: load_resistance,
+            tau_ms: tau_ms,
+            source_resistance: 0.0,
+            wiring_resistance: 0.0,
+            remote_sense: None,
+            dead_duty: 0,
+            max_duty: max_duty,
+            duty: 0,
+            voltage: 0.0,
+        }
+    }
+
+    pub fn get_duty(&self) -> u32 {
+        self.duty
+    }
+
+    // Voltage at the load
+    pub fn load_voltage(&mut self) -> f32 {
+        self.read_current() * self.load_resistance
+    }
+}
+
+impl Plant for BuckPlant {
+    fn advance(&mut self, dt_ms: f32) {
+        let mut target = self.input_voltage * self.duty.saturating_sub(self.dead_duty) as f32 / self.max_duty as f32;
+        if self.load_resistance > 0.0 {
+            let load = self.load_resistance + self.wiring_resistance;
+            target *= load / (load + self.source_resistance);
+        }
+        let alpha = 1.0 - (-dt_ms / self.tau_ms).exp();
+        self.voltage += (target - self.voltage) * alpha;
+    }
+}
+
+impl PowerStage for BuckPlant {
+    fn max_duty(&self) -> u32 {
+        self.max_duty
+    }
+
+    fn set_duty(&mut self, duty: u32) {
+        self.duty = duty.min(self.max_duty);
+    }
+}
+
+impl OutputSensor for BuckPlant {
+    fn read_voltage(&mut self) -> f32 {
+        self.voltage
+    }
+
+    fn read_current(&mut self) -> f32 {
+        if self.load_resistance > 0.0 { self.voltage / (self.load_resistance + self.wiring_resistance) } else { 0.0 }
+    }
+
+    fn read_power(&mut self) -> f32 {
+        let current = self.read_current();
+        self.voltage * current
+    }
+
+    // A disconnected sense input reads 0V
+    fn read_remote_voltage(&mut self) -> Option<f32> {
+        match self.remote_sense {
+            Some(true) => Some(self.load_voltage()),
+            Some(false) => Some(0.0),
+            None => None,
+        }
+    }
+}
+
+// Closed loop of the regulator and the protection limits around a plant,
+// stepped with the 10ms control period of the firmware.
+pub struct Simulation<P: Plant> {
+    pub plant: P,
+    pub regulator: Regulator<SimClock>,
+    pub limits: ProtectionLimits,
+    pub short_circuit: ShortCircuitDetector,
+    pub over_voltage: OverVoltageDetector,
+    // Regulate on the remote sense input of the plant
+    pub remote_sense: Option<RemoteSense>,
+    pub clock: SimClock,
+    pub logs: CurrentRecord,
+    pub stats: RegulationStats<SimClock>,
+    pub period_ms: u32,
+    pub output_on: bool,
+    pub trip: Option<TripCause>,
+}
+
+impl<P: Plant> Simulation<P> {
+    pub fn new(plant: P, kp: f32, ki: f32, kd: f32, limits: ProtectionLimits) -> Simulation<P> {
+        let clock = SimClock::new();
+        let max_duty = plant.max_duty();
+        Simulation {
+            plant: plant,
+            regulator: Regulator::with_clock(kp, ki, kd, max_duty, 0, clock.clone()),
+            stats: RegulationStats::with_clock(REGULATION_BAND_V, LOAD_STEP_A, SETTLE_MS, clock.clone()),
+            limits: limits,
+            short_circuit: ShortCircuitDetector::disabled(),
+            over_voltage: OverVoltageDetector::disabled(),
+            remote_sense: None,
+            clock: clock,
+            logs: CurrentRecord::new(),
+            period_ms: 10,
+            output_on: true,
+            trip: None,
+        }
+    }
+
+    // Run one control period at the setpoint
+    pub fn step(&mut self, setpoint: f32) -> CurrentLog {
+        self.clock.advance_ms(self.period_ms);
+        let mut data = CurrentLog::default();
+        data.clock = self.clock.now_ns();
+        data.voltage = self.plant.read_voltage();
+        data.current = self.plant.read_current();
+        data.power = self.plant.read_power();
+        // The short circuit detector checks the output terminals
+        let local_voltage = data.voltage;
+        if let Some(sense) = self.remote_sense.as_mut() {
+            data.voltage = sense.select(data.voltage, self.plant.read_remote_voltage()).0;
+        }
+        if self.output_on {
+            let cause = self.short_circuit.check(local_voltage, data.current)
+                .or_else(|| self.over_voltage.check(local_voltage))
+                .or_else(|| self.limits.check_electrical(data.current, data.power));
+            if let Some(cause) = cause {
+                self.output_on = false;
+                self.trip = Some(cause);
+            }
+        }
+        if self.output_on {
+            self.stats.update(setpoint, data.voltage, data.current);
+        }
+        let duty = if self.output_on {
+            self.regulator.update(setpoint, data.voltage, data.current, self.limits.max_current)
+        }
+        else {
+            self.regulator.stop()
+        };
+        self.plant.set_duty(duty);
+        data.pwm = duty;
+        self.logs.record(data.clone());
+        data
+    }
+
+    // Run the control loop for a duration and return the last sample
+    pub fn run(&mut self, setpoint: f32, duration_ms: u32) -> CurrentLog {
+        let mut last = CurrentLog::default();
+        for _ in 0..(duration_ms / self.period_ms) {
+            last = self.step(setpoint);
+            self.plant.advance(self.period_ms as f32);
+        }
+        last
+    }
+}
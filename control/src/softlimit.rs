@@ -0,0 +1,160 @@
+// Soft current limiting by the USB PD operating current
+// By default the over-current check trips the output of channel 1 at the current limit. With
+// the soft limit, a current above the limit renegotiates the PPS contract at a lower operating
+// current instead, so the constant current mode of the source holds the DUT at a reduced
+// current and it stays powered. The control task then only trips at TRIP_FACTOR times the
+// limit. When the operating current reaches the PPS minimum and the current is still above
+// the limit (e.g. a fixed PDO without a current limit), the output is tripped after all.
+// The operating current is restored when the current stays well below the limit.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+// A PPS source does not limit its current below 1A
+pub const MIN_OPERATING_MA: u16 = 1000;
+// Resolution of the PPS operating current
+pub const STEP_MA: u16 = 50;
+// Time above the limit before the operating current is reduced (ms)
+pub const OVER_MS: u64 = 100;
+// Settling time of the source after a request (ms)
+pub const SETTLE_MS: u64 = 500;
+// Time above the limit at the minimum operating current before the output trips (ms)
+pub const HOLD_FAIL_MS: u64 = 2000;
+// Time below RESTORE_FRACTION of the limit before the operating current is restored (ms)
+pub const RESTORE_MS: u64 = 5000;
+pub const RESTORE_FRACTION: f32 = 0.8;
+// The control task trips the output above this multiple of the limit in the soft mode
+pub const TRIP_FACTOR: f32 = 1.5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CurrentLimitMode {
+    Trip,
+    Pps,
+}
+
+impl CurrentLimitMode {
+    pub fn parse(text: &str) -> Option<CurrentLimitMode> {
+        match text {
+            "trip" => Some(CurrentLimitMode::Trip),
+            "pps" => Some(CurrentLimitMode::Pps),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CurrentLimitMode::Trip => "trip",
+            CurrentLimitMode::Pps => "pps",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SoftLimitAction {
+    // Renegotiate at the lower operating current (mA)
+    Reduce(u16),
+    // Renegotiate at the operating current of the limit (mA)
+    Restore(u16),
+    // The source does not hold the current, trip the output
+    Trip,
+}
+
+pub struct SoftCurrentLimit {
+    limit: f32,
+    // Operating current of the limit, and the one requested now (mA)
+    nominal_ma: u16,
+    operating_ma: u16,
+    over_since_ms: Option<u64>,
+    under_since_ms: Option<u64>,
+    requested_ms: Option<u64>,
+}
+
+impl SoftCurrentLimit {
+    pub fn new(limit: f32, nominal_ma: u16) -> SoftCurrentLimit {
+        SoftCurrentLimit {
+            limit: limit,
+            nominal_ma: nominal_ma,
+            operating_ma: nominal_ma,
+            over_since_ms: None,
+            under_since_ms: None,
+            requested_ms: None,
+        }
+    }
+
+    // The limit and its operating current; a change starts again from it
+    pub fn set_limit(&mut self, limit: f32, nominal_ma: u16) {
+        if limit != self.limit || nominal_ma != self.nominal_ma {
+            *self = SoftCurrentLimit::new(limit, nominal_ma);
+        }
+    }
+
+    // The output stopped or the contract was requested again at the nominal current
+    pub fn reset(&mut self) {
+        *self = SoftCurrentLimit::new(self.limit, self.nominal_ma);
+    }
+
+    // A limit below the PPS minimum is only tripped
+    pub fn is_supported(&self) -> bool {
+        self.limit * 1000.0 >= MIN_OPERATING_MA as f32
+    }
+
+    // Current of the over-current trip of the control task
+    pub fn trip_current(&self) -> f32 {
+        if self.is_supported() { self.limit * TRIP_FACTOR } else { self.limit }
+    }
+
+    // Output current measured while the output is on
+    pub fn update(&mut self, now_ms: u64, current: f32) -> Option<SoftLimitAction> {
+        if !self.is_supported() {
+            return None;
+        }
+        if current > self.limit {
+            self.under_since_ms = None;
+            let over_since = *self.over_since_ms.get_or_insert(now_ms);
+            let settled = match self.requested_ms {
+                Some(t) => now_ms.saturating_sub(t) >= SETTLE_MS,
+                None => true,
+            };
+            if now_ms.saturating_sub(over_since) < OVER_MS || !settled {
+                return None;
+            }
+            if self.operating_ma <= MIN_OPERATING_MA {
+                let held_ms = now_ms.saturating_sub(self.requested_ms.unwrap_or(over_since));
+                if held_ms >= HOLD_FAIL_MS {
+                    self.over_since_ms = None;
+                    return Some(SoftLimitAction::Trip);
+                }
+                return None;
+            }
+            // The operating current scaled down by the excess, at least one step
+            let scaled = (self.operating_ma as f32 * self.limit / current) as u16 / STEP_MA * STEP_MA;
+            self.operating_ma = scaled.min(self.operating_ma - STEP_MA).max(MIN_OPERATING_MA);
+            self.requested_ms = Some(now_ms);
+            return Some(SoftLimitAction::Reduce(self.operating_ma));
+        }
+        self.over_since_ms = None;
+        if self.is_limiting() && current < self.limit * RESTORE_FRACTION {
+            let under_since = *self.under_since_ms.get_or_insert(now_ms);
+            if now_ms.saturating_sub(under_since) >= RESTORE_MS {
+                self.operating_ma = self.nominal_ma;
+                self.under_since_ms = None;
+                self.requested_ms = Some(now_ms);
+                return Some(SoftLimitAction::Restore(self.nominal_ma));
+            }
+        }
+        else {
+            self.under_since_ms = None;
+        }
+        None
+    }
+
+    // The operating current is reduced below the one of the limit
+    pub fn is_limiting(&self) -> bool {
+        self.operating_ma < self.nominal_ma
+    }
+
+    pub fn get_operating_ma(&self) -> u16 {
+        self.operating_ma
+    }
+}
@@ -0,0 +1,82 @@
+// Stale measurement policy
+// A failed INA228 read leaves no measurement for the control cycle; fed to the PID as 0V it
+// would drive the duty up. Instead the last good sample is reused, and the PID output is
+// held, for up to max_stale cycles in a row. After that the measurement is a fault and the
+// output is to be stopped.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Reading {
+    pub voltage: f32,
+    pub current: f32,
+    pub power: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleState {
+    Fresh,
+    // The last good sample, reused for the cycles in a row
+    Stale(u32),
+    // Reused too long
+    Fault,
+}
+
+pub struct StalePolicy {
+    max_stale: u32,
+    last: Reading,
+    stale: u32,
+    stale_total: u64,
+}
+
+impl StalePolicy {
+    pub fn new(max_stale: u32) -> StalePolicy {
+        StalePolicy {
+            max_stale: max_stale,
+            last: Reading::default(),
+            stale: 0,
+            stale_total: 0,
+        }
+    }
+
+    pub fn set_max_stale(&mut self, max_stale: u32) {
+        self.max_stale = max_stale;
+    }
+
+    pub fn get_max_stale(&self) -> u32 {
+        self.max_stale
+    }
+
+    // Cycles run on a reused sample since the start
+    pub fn get_stale_total(&self) -> u64 {
+        self.stale_total
+    }
+
+    // Start a new run of the output; the last good sample is kept
+    pub fn reset(&mut self) {
+        self.stale = 0;
+    }
+
+    // The reading of the cycle, None if a read failed. Returns the sample to use.
+    pub fn update(&mut self, reading: Option<Reading>) -> (Reading, SampleState) {
+        match reading {
+            Some(reading) => {
+                self.last = reading;
+                self.stale = 0;
+                (reading, SampleState::Fresh)
+            },
+            None => {
+                self.stale = self.stale.saturating_add(1);
+                self.stale_total += 1;
+                if self.stale > self.max_stale {
+                    (self.last, SampleState::Fault)
+                }
+                else {
+                    (self.last, SampleState::Stale(self.stale))
+                }
+            },
+        }
+    }
+}
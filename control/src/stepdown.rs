@@ -0,0 +1,111 @@
+// Step-down of the setpoint with an active ramp
+// The buck stage can only source current, so a lower setpoint used to wait for the load to
+// pull the output down. Now the setpoint regulated on ramps from the old to the new one over
+// half of the time limit: the bleed follows it down, and the PID output is scaled with it
+// (Regulator::set_stepping_down) instead of winding the integral down against an output
+// held up by the capacitor, which would undershoot. The step is complete once the output
+// is within the band of the new setpoint, or has timed out at the time limit, so a
+// sequence can rely on a bounded step time.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use crate::hal::{Clock, SystemClock};
+
+// Smaller steps down are applied at once
+pub const STEP_DOWN_MIN_V: f32 = 0.1;
+// Completed within this much of the new setpoint (V)
+pub const STEP_DOWN_BAND_V: f32 = 0.1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepDownEvent {
+    Started { from: f32, to: f32 },
+    // Time from the start (ms)
+    Completed(f32),
+    // Output voltage at the time limit
+    TimedOut(f32),
+}
+
+pub struct SetpointRamp<C: Clock = SystemClock> {
+    // 0 disables the ramp
+    time_limit_ms: u32,
+    setpoint: f32,
+    from: f32,
+    to: f32,
+    start: Option<u128>,
+    clock: C,
+}
+
+impl SetpointRamp<SystemClock> {
+    pub fn new(time_limit_ms: u32) -> SetpointRamp {
+        SetpointRamp::with_clock(time_limit_ms, SystemClock)
+    }
+}
+
+impl<C: Clock> SetpointRamp<C> {
+    pub fn with_clock(time_limit_ms: u32, clock: C) -> SetpointRamp<C> {
+        SetpointRamp {
+            time_limit_ms: time_limit_ms,
+            setpoint: 0.0,
+            from: 0.0,
+            to: 0.0,
+            start: None,
+            clock: clock,
+        }
+    }
+
+    pub fn set_time_limit(&mut self, time_limit_ms: u32) {
+        self.time_limit_ms = time_limit_ms;
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.start.is_some()
+    }
+
+    // Output started at the setpoint, without a ramp
+    pub fn reset(&mut self, setpoint: f32) {
+        self.setpoint = setpoint;
+        self.start = None;
+    }
+
+    // Setpoint and output voltage of the control period. Returns the setpoint to regulate
+    // on, and the start or the end of a step-down.
+    pub fn update(&mut self, target: f32, voltage: f32) -> (f32, Option<StepDownEvent>) {
+        let now = self.clock.now_ns();
+        let stepping = self.start.is_some() && target == self.to;
+        if self.time_limit_ms > 0 && !stepping && target < self.setpoint - STEP_DOWN_MIN_V {
+            // A new step down, from where the output is regulated now
+            self.from = self.setpoint;
+            self.to = target;
+            self.start = Some(now);
+            return (self.setpoint, Some(StepDownEvent::Started { from: self.from, to: self.to }));
+        }
+        let start = match self.start {
+            Some(start) if target == self.to => start,
+            _ => {
+                // No step in progress, or a step up (or a small step) which ends it
+                self.start = None;
+                self.setpoint = target;
+                return (target, None);
+            },
+        };
+        let elapsed_ms = (now - start) as f32 / 1_000_000.0;
+        let ramp_ms = self.time_limit_ms as f32 / 2.0;
+        self.setpoint = if elapsed_ms >= ramp_ms {
+            self.to
+        }
+        else {
+            self.from + (self.to - self.from) * elapsed_ms / ramp_ms
+        };
+        if elapsed_ms >= ramp_ms && (voltage - self.to).abs() <= STEP_DOWN_BAND_V {
+            self.start = None;
+            return (self.setpoint, Some(StepDownEvent::Completed(elapsed_ms)));
+        }
+        if elapsed_ms >= self.time_limit_ms as f32 {
+            self.start = None;
+            return (self.setpoint, Some(StepDownEvent::TimedOut(voltage)));
+        }
+        (self.setpoint, None)
+    }
+}
@@ -0,0 +1,96 @@
+// Periodic summary records for sparse logging
+// While nothing interesting happens the records of a channel are reduced to one summary
+// (mean, minimum and maximum) per interval, by the clock of the records.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use crate::currentlogs::CurrentLog;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SummaryStat {
+    pub mean: f32,
+    pub min: f32,
+    pub max: f32,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LogSummary {
+    pub channel: u8,
+    pub samples: u32,
+    // Clock of the first and the last record (ns)
+    pub start_clock: u128,
+    pub end_clock: u128,
+    pub voltage: SummaryStat,
+    pub current: SummaryStat,
+    pub power: SummaryStat,
+    pub temp: SummaryStat,
+}
+
+#[derive(Default)]
+struct Accumulator {
+    summary: LogSummary,
+    sums: [f64; 4],
+}
+
+impl Accumulator {
+    fn new(sample: &CurrentLog) -> Accumulator {
+        let stat = |v: f32| SummaryStat { mean: v, min: v, max: v };
+        Accumulator {
+            summary: LogSummary {
+                channel: sample.channel,
+                samples: 1,
+                start_clock: sample.clock,
+                end_clock: sample.clock,
+                voltage: stat(sample.voltage),
+                current: stat(sample.current),
+                power: stat(sample.power),
+                temp: stat(sample.temp),
+            },
+            sums: [sample.voltage as f64, sample.current as f64, sample.power as f64, sample.temp as f64],
+        }
+    }
+
+    fn add(&mut self, sample: &CurrentLog) {
+        let s = &mut self.summary;
+        s.samples += 1;
+        s.end_clock = sample.clock;
+        let values = [sample.voltage, sample.current, sample.power, sample.temp];
+        let stats = [&mut s.voltage, &mut s.current, &mut s.power, &mut s.temp];
+        for ((stat, sum), value) in stats.into_iter().zip(self.sums.iter_mut()).zip(values) {
+            *sum += value as f64;
+            stat.mean = (*sum / s.samples as f64) as f32;
+            stat.min = stat.min.min(value);
+            stat.max = stat.max.max(value);
+        }
+    }
+}
+
+pub struct SummaryLog {
+    interval_ns: u128,
+    accumulator: Option<Accumulator>,
+}
+
+impl SummaryLog {
+    pub fn new(interval_secs: u32) -> SummaryLog {
+        SummaryLog { interval_ns: interval_secs as u128 * 1_000_000_000, accumulator: None }
+    }
+
+    // Discard the partial interval (full-rate logging is on)
+    pub fn reset(&mut self) {
+        self.accumulator = None;
+    }
+
+    // One record of the channel. Returns the summary of an interval when a record is past it;
+    // the record starts the next interval.
+    pub fn add(&mut self, sample: &CurrentLog) -> Option<LogSummary> {
+        match self.accumulator.as_mut() {
+            Some(acc) if sample.clock.saturating_sub(acc.summary.start_clock) < self.interval_ns => {
+                acc.add(sample);
+                None
+            },
+            _ => self.accumulator.replace(Accumulator::new(sample)).map(|acc| acc.summary),
+        }
+    }
+}
@@ -0,0 +1,237 @@
+// Test scripts run on channel 1
+// A script is a named series of steps stored on the unit, so a routine test (e.g. the
+// incoming inspection of a batch of devices) runs the same way every time: set the voltage
+// or the current limit, turn the output on or off, wait, check that the mean voltage or
+// current over ASSERT_MS is in a range, and put a marker in the log. The runner takes one
+// step per poll and gives the main loop the commands of the steps; the first failed check
+// ends the run. The result of each check forms the pass/fail report.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+pub const MAX_STEPS: usize = 64;
+pub const MAX_NAME_LEN: usize = 16;
+// Longest wait of a step (ms)
+pub const MAX_WAIT_MS: u64 = 3_600_000;
+// Time a check averages the measurement (ms)
+pub const ASSERT_MS: u64 = 500;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptStep {
+    Voltage(f32),
+    CurrentLimit(f32),
+    Output(bool),
+    Wait(u64),
+    // Range (min, max) of the mean over ASSERT_MS
+    AssertVoltage(f32, f32),
+    AssertCurrent(f32, f32),
+    Marker(String),
+}
+
+impl ScriptStep {
+    pub fn to_text(&self) -> String {
+        match self {
+            ScriptStep::Voltage(v) => format!("voltage {:.3}V", v),
+            ScriptStep::CurrentLimit(a) => format!("current limit {:.3}A", a),
+            ScriptStep::Output(on) => format!("output {}", if *on { "on" } else { "off" }),
+            ScriptStep::Wait(ms) => format!("wait {}ms", ms),
+            ScriptStep::AssertVoltage(min, max) => format!("assert voltage {:.3}..{:.3}V", min, max),
+            ScriptStep::AssertCurrent(min, max) => format!("assert current {:.3}..{:.3}A", min, max),
+            ScriptStep::Marker(text) => format!("marker \"{}\"", text),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestScript {
+    name: String,
+    steps: Vec<ScriptStep>,
+}
+
+impl TestScript {
+    pub fn new(name: &str, steps: Vec<ScriptStep>) -> Result<TestScript, String> {
+        if !is_valid_name(name) {
+            return Err(format!("name must be 1 to {} characters of a-z, 0-9, - and _", MAX_NAME_LEN));
+        }
+        if steps.is_empty() || steps.len() > MAX_STEPS {
+            return Err(format!("a script has 1 to {} steps", MAX_STEPS));
+        }
+        for (index, step) in steps.iter().enumerate() {
+            let valid = match step {
+                ScriptStep::Voltage(v) | ScriptStep::CurrentLimit(v) => v.is_finite() && *v >= 0.0,
+                ScriptStep::Wait(ms) => *ms <= MAX_WAIT_MS,
+                ScriptStep::AssertVoltage(min, max) | ScriptStep::AssertCurrent(min, max) => {
+                    min.is_finite() && max.is_finite() && min <= max
+                },
+                ScriptStep::Output(_) | ScriptStep::Marker(_) => true,
+            };
+            if !valid {
+                return Err(format!("step {}: {} is out of range", index + 1, step.to_text()));
+            }
+        }
+        Ok(TestScript { name: name.to_string(), steps: steps })
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_steps(&self) -> &[ScriptStep] {
+        &self.steps
+    }
+}
+
+// Names are used in the file names on SPIFFS
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= MAX_NAME_LEN
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+// What the main loop does for a step
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptCommand {
+    Voltage(f32),
+    CurrentLimit(f32),
+    Output(bool),
+    Marker(String),
+    // A check ended: index of the step, its result and the mean measured
+    Checked(usize, bool, f32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptState {
+    Running,
+    Passed,
+    Failed,
+    Aborted(String),
+}
+
+impl ScriptState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScriptState::Running => "running",
+            ScriptState::Passed => "pass",
+            ScriptState::Failed => "fail",
+            ScriptState::Aborted(_) => "aborted",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckResult {
+    // Index of the step
+    pub step: usize,
+    pub text: String,
+    pub pass: bool,
+    pub value: f32,
+}
+
+// State, progress and checks reported to the console and the HTTP API
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptStatus {
+    pub name: String,
+    pub state: ScriptState,
+    // Steps done and the steps of the script
+    pub step: usize,
+    pub steps: usize,
+    pub checks: Vec<CheckResult>,
+}
+
+pub struct ScriptRun {
+    script: TestScript,
+    step: usize,
+    state: ScriptState,
+    // Start of the step running, None until poll starts it
+    step_started_ms: Option<u64>,
+    sum: f32,
+    count: u32,
+    checks: Vec<CheckResult>,
+}
+
+impl ScriptRun {
+    pub fn new(script: TestScript) -> ScriptRun {
+        ScriptRun {
+            script: script,
+            step: 0,
+            state: ScriptState::Running,
+            step_started_ms: None,
+            sum: 0.0,
+            count: 0,
+            checks: Vec::new(),
+        }
+    }
+
+    pub fn get_name(&self) -> &str {
+        self.script.get_name()
+    }
+
+    pub fn state(&self) -> &ScriptState {
+        &self.state
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state == ScriptState::Running
+    }
+
+    // Stop the run by a fault or by the operator
+    pub fn abort(&mut self, reason: &str) {
+        if self.is_running() {
+            self.state = ScriptState::Aborted(reason.to_string());
+        }
+    }
+
+    // Advance the run with the voltage and current of channel 1 now. Returns the command
+    // of the step started or the result of a check which ended.
+    pub fn poll(&mut self, now_ms: u64, voltage: f32, current: f32) -> Option<ScriptCommand> {
+        if !self.is_running() {
+            return None;
+        }
+        let Some(step) = self.script.steps.get(self.step).cloned() else {
+            self.state = ScriptState::Passed;
+            return None;
+        };
+        let started = *self.step_started_ms.get_or_insert(now_ms);
+        let elapsed = now_ms.saturating_sub(started);
+        let command = match step {
+            ScriptStep::Voltage(v) => Some(ScriptCommand::Voltage(v)),
+            ScriptStep::CurrentLimit(a) => Some(ScriptCommand::CurrentLimit(a)),
+            ScriptStep::Output(on) => Some(ScriptCommand::Output(on)),
+            ScriptStep::Marker(ref text) => Some(ScriptCommand::Marker(text.clone())),
+            ScriptStep::Wait(ms) if elapsed < ms => return None,
+            ScriptStep::Wait(_) => None,
+            ScriptStep::AssertVoltage(min, max) | ScriptStep::AssertCurrent(min, max) => {
+                self.sum += if matches!(step, ScriptStep::AssertVoltage(..)) { voltage } else { current };
+                self.count += 1;
+                if elapsed < ASSERT_MS {
+                    return None;
+                }
+                let mean = self.sum / self.count as f32;
+                let pass = mean >= min && mean <= max;
+                self.checks.push(CheckResult { step: self.step, text: step.to_text(), pass: pass, value: mean });
+                if !pass {
+                    self.state = ScriptState::Failed;
+                }
+                Some(ScriptCommand::Checked(self.step, pass, mean))
+            },
+        };
+        self.step += 1;
+        self.step_started_ms = None;
+        self.sum = 0.0;
+        self.count = 0;
+        if self.is_running() && self.step >= self.script.steps.len() {
+            self.state = ScriptState::Passed;
+        }
+        command
+    }
+
+    pub fn status(&self) -> ScriptStatus {
+        ScriptStatus {
+            name: self.script.get_name().to_string(),
+            state: self.state.clone(),
+            step: self.step,
+            steps: self.script.steps.len(),
+            checks: self.checks.clone(),
+        }
+    }
+}
@@ -0,0 +1,96 @@
+// Thermal model of the heatsink
+// The NTC on GPIO18 follows the heatsink with a delay of tens of seconds, so a high load
+// reaches the temperature limit before the sensor shows it. The model estimates the rise of
+// the heatsink above the ambient as a first order response to the output power: it settles
+// at thermal_resistance (°C/W) times the power with thermal_time_constant (s). When the
+// temperature predicted PREDICT_SECS ahead crosses the limit less DERATE_MARGIN_C, the power
+// is derated to the one which holds the heatsink there. The ambient is taken from the sensor
+// at rest, and a sensor reading above the model is taken as it is.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+// The derated power holds the heatsink this far below the temperature limit (°C)
+pub const DERATE_MARGIN_C: f32 = 5.0;
+// The derating is released when the prediction is this far below the derating point (°C)
+pub const RELEASE_HYSTERESIS_C: f32 = 2.0;
+// Time ahead of the prediction (s)
+pub const PREDICT_SECS: f32 = 30.0;
+// Below this modeled rise the sensor reads the ambient (°C)
+pub const REST_RISE_C: f32 = 1.0;
+
+pub struct ThermalModel {
+    resistance: f32,
+    time_constant: f32,
+    ambient: Option<f32>,
+    rise: f32,
+    sensor: f32,
+    derating: bool,
+}
+
+impl ThermalModel {
+    // Thermal resistance (°C/W, 0 disables the model) and time constant (s)
+    pub fn new(resistance: f32, time_constant: f32) -> ThermalModel {
+        ThermalModel { resistance: resistance, time_constant: time_constant, ambient: None, rise: 0.0, sensor: 0.0, derating: false }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.resistance > 0.0 && self.time_constant > 0.0
+    }
+
+    // Output power (W) over the last interval and the sensor temperature
+    pub fn update(&mut self, dt_secs: f32, power: f32, sensor: f32) {
+        if self.ambient.is_none() || self.rise < REST_RISE_C {
+            self.ambient = Some(sensor);
+        }
+        let alpha = 1.0 - (-dt_secs / self.time_constant).exp();
+        self.rise += (power.max(0.0) * self.resistance - self.rise) * alpha;
+        self.sensor = sensor;
+    }
+
+    // Heatsink temperature now: the model, or the sensor when it reads higher
+    pub fn estimate(&self) -> f32 {
+        (self.get_ambient() + self.rise).max(self.sensor)
+    }
+
+    // Heatsink temperature after the seconds at the power
+    pub fn predict(&self, power: f32, secs: f32) -> f32 {
+        let settled = power.max(0.0) * self.resistance;
+        let predicted = self.get_ambient() + settled + (self.rise - settled) * (-secs / self.time_constant).exp();
+        predicted.max(self.estimate())
+    }
+
+    // Power which holds the heatsink below the limit, None while no derating is needed
+    pub fn power_limit(&mut self, max_temperature: f32, power: f32) -> Option<f32> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let target = max_temperature - DERATE_MARGIN_C;
+        let predicted = self.predict(power, PREDICT_SECS);
+        if predicted > target {
+            self.derating = true;
+        }
+        else if predicted < target - RELEASE_HYSTERESIS_C {
+            self.derating = false;
+        }
+        if self.derating {
+            Some(((target - self.get_ambient()) / self.resistance).max(0.0))
+        }
+        else {
+            None
+        }
+    }
+
+    pub fn is_derating(&self) -> bool {
+        self.derating
+    }
+
+    pub fn get_ambient(&self) -> f32 {
+        self.ambient.unwrap_or(self.sensor)
+    }
+
+    pub fn get_rise(&self) -> f32 {
+        self.rise
+    }
+}
@@ -0,0 +1,89 @@
+// Touch baseline tracking
+// The touch thresholds are derived from the smooth values of the pads read at boot. When the
+// enclosure warms up at high power the smooth values drift and a press no longer crosses the
+// threshold. While no key is pressed the smooth values are read again periodically and the
+// threshold of a pad which drifted is derived again from its new value.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+// Period of the check (ms)
+pub const CHECK_PERIOD_MS: u64 = 10_000;
+// Time without a touch before a check (ms)
+pub const QUIET_MS: u64 = 5_000;
+// Drift of the smooth value which derives the threshold again (fraction of the baseline)
+pub const DRIFT_FRACTION: f32 = 0.003;
+// Larger changes are not a drift (a hand near the panel, a bad reading) and are ignored
+pub const MAX_DRIFT_FRACTION: f32 = 0.2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rebaseline {
+    pub pad: usize,
+    pub from: u32,
+    pub to: u32,
+    pub threshold: u32,
+}
+
+pub struct TouchBaseline {
+    baseline: Vec<u32>,
+    threshold_fraction: f32,
+    last_check_ms: u64,
+    last_touch_ms: u64,
+    count: u32,
+}
+
+impl TouchBaseline {
+    pub fn new(baseline: &[u32], threshold_fraction: f32, now_ms: u64) -> TouchBaseline {
+        TouchBaseline {
+            baseline: baseline.to_vec(),
+            threshold_fraction: threshold_fraction,
+            last_check_ms: now_ms,
+            last_touch_ms: now_ms,
+            count: 0,
+        }
+    }
+
+    // A key is pressed
+    pub fn touched(&mut self, now_ms: u64) {
+        self.last_touch_ms = now_ms;
+    }
+
+    // True when the smooth values should be read again
+    pub fn is_due(&self, now_ms: u64) -> bool {
+        now_ms.saturating_sub(self.last_check_ms) >= CHECK_PERIOD_MS
+            && now_ms.saturating_sub(self.last_touch_ms) >= QUIET_MS
+    }
+
+    // The smooth values read again; the pads whose threshold is derived again
+    pub fn update(&mut self, now_ms: u64, values: &[u32]) -> Vec<Rebaseline> {
+        self.last_check_ms = now_ms;
+        let mut changed = Vec::new();
+        for (pad, (base, value)) in self.baseline.iter_mut().zip(values.iter()).enumerate() {
+            if *base == 0 {
+                continue;
+            }
+            let drift = (*value as f32 - *base as f32).abs() / *base as f32;
+            if (DRIFT_FRACTION..=MAX_DRIFT_FRACTION).contains(&drift) {
+                let from = *base;
+                *base = *value;
+                changed.push(Rebaseline { pad: pad, from: from, to: *value, threshold: (*value as f32 * self.threshold_fraction) as u32 });
+            }
+        }
+        self.count += changed.len() as u32;
+        changed
+    }
+
+    pub fn threshold(&self, pad: usize) -> u32 {
+        (self.baseline[pad] as f32 * self.threshold_fraction) as u32
+    }
+
+    pub fn baseline(&self, pad: usize) -> u32 {
+        self.baseline[pad]
+    }
+
+    // Thresholds derived again since boot
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+}
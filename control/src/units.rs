@@ -0,0 +1,71 @@
+// Unit scaling and resolution of the displayed values
+// A value is shown with a fixed number of significant digits, in milli units below 1
+// (0.003A is "3.00mA", 0.4567A is "457mA", 1.234A is "1.23A"). The digits are set per
+// quantity, so e.g. the voltage can keep its mV resolution at low voltages. Up to 4 digits
+// fit the 7 characters of a value field on the display.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+pub const MIN_DIGITS: u32 = 2;
+pub const MAX_DIGITS: u32 = 4;
+
+// Significant digits of each quantity
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UnitFormat {
+    pub voltage_digits: u32,
+    pub current_digits: u32,
+    pub power_digits: u32,
+}
+
+impl Default for UnitFormat {
+    fn default() -> Self {
+        UnitFormat { voltage_digits: 4, current_digits: 3, power_digits: 3 }
+    }
+}
+
+impl UnitFormat {
+    pub fn voltage(&self, value: f32) -> String {
+        format_scaled(value, "V", self.voltage_digits)
+    }
+
+    pub fn current(&self, value: f32) -> String {
+        format_scaled(value, "A", self.current_digits)
+    }
+
+    pub fn power(&self, value: f32) -> String {
+        format_scaled(value, "W", self.power_digits)
+    }
+}
+
+// The value with the digits, in milli units below 1 (but not below 1 milli unit)
+pub fn format_scaled(value: f32, unit: &str, digits: u32) -> String {
+    let digits = digits.clamp(MIN_DIGITS, MAX_DIGITS);
+    if !value.is_finite() {
+        return format!("--{}", unit);
+    }
+    if value.abs() < 1.0 && value != 0.0 {
+        let milli = value * 1000.0;
+        let decimals = decimals_for(milli, digits);
+        // Rounding may carry over to the next unit (999.6mA is 1.00A)
+        if round_to(milli, decimals).abs() < 1000.0 {
+            return format!("{:.*}m{}", decimals, milli, unit);
+        }
+    }
+    let decimals = decimals_for(value, digits);
+    // Rounding may also add an integer digit (9.996V with 3 digits is 10.0V)
+    let decimals = decimals_for(round_to(value, decimals), digits).min(decimals);
+    format!("{:.*}{}", decimals, value, unit)
+}
+
+fn decimals_for(value: f32, digits: u32) -> usize {
+    let abs = value.abs();
+    let integer_digits = if abs < 1.0 { 1 } else { abs.log10().floor() as u32 + 1 };
+    digits.saturating_sub(integer_digits) as usize
+}
+
+fn round_to(value: f32, decimals: usize) -> f32 {
+    let scale = 10f32.powi(decimals as i32);
+    (value * scale).round() / scale
+}
@@ -0,0 +1,184 @@
+// Synchronized start of several units
+// A unit (the leader) broadcasts a start message over UDP to the units of its sync group:
+// the action, a run ID shared by all the units and the start time on the wall clock (SNTP),
+// a lead time ahead. Each unit of the group, the leader included, schedules the action at
+// that time, so the start does not depend on the delivery time of the datagram. The
+// message is sent a few times against a lost datagram; a run ID already accepted is ignored.
+// Message: "DCPSYNC1 <group> <run ID> <on|off|cycle> <start time in ms since the epoch> [MAC]"
+// With a sync key, the MAC (the HMAC-SHA256 of the message before it with the key, in hex) is
+// required, so a host of the network without the key cannot start the outputs; a replayed
+// message is stale after the late tolerance.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+pub const SYNC_MAGIC: &str = "DCPSYNC1";
+pub const MAX_ID_LEN: usize = 32;
+// A start further ahead is rejected (ms)
+pub const MAX_LEAD_MS: u64 = 60_000;
+// A start received up to this late is run at once, later ones are rejected (ms)
+pub const LATE_TOLERANCE_MS: u64 = 1000;
+// The wall clock is taken as set by SNTP after this time (ms since the epoch)
+pub const CLOCK_VALID_MS: u64 = 1_700_000_000_000;
+pub const MAX_KEY_LEN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncAction {
+    // Turn the outputs on (off) at their setpoints
+    On,
+    Off,
+    // Start the endurance test with the cycle settings of the unit
+    Cycle,
+}
+
+impl SyncAction {
+    pub fn parse(text: &str) -> Option<SyncAction> {
+        match text {
+            "on" => Some(SyncAction::On),
+            "off" => Some(SyncAction::Off),
+            "cycle" => Some(SyncAction::Cycle),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SyncAction::On => "on",
+            SyncAction::Off => "off",
+            SyncAction::Cycle => "cycle",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyncMessage {
+    pub group: String,
+    pub run_id: String,
+    pub action: SyncAction,
+    pub start_ms: u64,
+}
+
+// Group names and run IDs: letters, digits, '-', '_' and '.'
+pub fn valid_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_ID_LEN
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+impl SyncMessage {
+    pub fn new(group: &str, run_id: &str, action: SyncAction, start_ms: u64) -> Result<SyncMessage, String> {
+        if !valid_id(group) || !valid_id(run_id) {
+            return Err(format!("group and run ID must be 1 to {} letters, digits, '-', '_' or '.'", MAX_ID_LEN));
+        }
+        Ok(SyncMessage {
+            group: group.to_string(),
+            run_id: run_id.to_string(),
+            action: action,
+            start_ms: start_ms,
+        })
+    }
+
+    pub fn to_text(&self) -> String {
+        format!("{} {} {} {} {}", SYNC_MAGIC, self.group, self.run_id, self.action.as_str(), self.start_ms)
+    }
+
+    pub fn parse(text: &str) -> Result<SyncMessage, String> {
+        let fields : Vec<&str> = text.split_whitespace().collect();
+        match fields.as_slice() {
+            [magic, group, run_id, action, start_ms] if *magic == SYNC_MAGIC => {
+                let action = SyncAction::parse(action).ok_or(format!("unknown action: {}", action))?;
+                let start_ms = start_ms.parse::<u64>().map_err(|_| format!("invalid start time: {}", start_ms))?;
+                SyncMessage::new(group, run_id, action, start_ms)
+            },
+            _ => Err("not a sync message".to_string()),
+        }
+    }
+}
+
+// The message and its MAC field, if any
+pub fn split_mac(text: &str) -> (&str, Option<&str>) {
+    let text = text.trim();
+    match text.rsplit_once(char::is_whitespace) {
+        Some((message, mac)) if message.split_whitespace().count() == 5 => (message.trim_end(), Some(mac)),
+        _ => (text, None),
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(text: &str) -> Option<Vec<u8>> {
+    text.as_bytes().chunks(2)
+        .map(|pair| match pair {
+            [high, low] if high.is_ascii_hexdigit() && low.is_ascii_hexdigit() => u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+// Start of a message accepted, due in the main loop
+pub struct SyncScheduler {
+    group: String,
+    pending: Option<SyncMessage>,
+    // Run ID of the last accepted message
+    last_run_id: Option<String>,
+}
+
+impl SyncScheduler {
+    pub fn new(group: &str) -> SyncScheduler {
+        SyncScheduler {
+            group: group.to_string(),
+            pending: None,
+            last_run_id: None,
+        }
+    }
+
+    pub fn set_group(&mut self, group: &str) {
+        self.group = group.to_string();
+    }
+
+    pub fn get_group(&self) -> &str {
+        &self.group
+    }
+
+    pub fn get_pending(&self) -> Option<&SyncMessage> {
+        self.pending.as_ref()
+    }
+
+    pub fn get_last_run_id(&self) -> Option<&str> {
+        self.last_run_id.as_deref()
+    }
+
+    // A message at the wall clock time. Ok(false) if it is not for this unit: another group
+    // or a repeat of an accepted run. A new run replaces a pending one.
+    pub fn accept(&mut self, message: SyncMessage, now_ms: u64) -> Result<bool, String> {
+        if message.group != self.group || self.last_run_id.as_deref() == Some(message.run_id.as_str()) {
+            return Ok(false);
+        }
+        if now_ms < CLOCK_VALID_MS {
+            return Err("the clock is not set (waiting for NTP)".to_string());
+        }
+        if message.start_ms > now_ms + MAX_LEAD_MS {
+            return Err(format!("start {}ms ahead, more than {}ms", message.start_ms - now_ms, MAX_LEAD_MS));
+        }
+        if message.start_ms + LATE_TOLERANCE_MS < now_ms {
+            return Err(format!("start {}ms in the past", now_ms - message.start_ms));
+        }
+        self.last_run_id = Some(message.run_id.clone());
+        self.pending = Some(message);
+        Ok(true)
+    }
+
+    pub fn cancel(&mut self) -> Option<SyncMessage> {
+        self.pending.take()
+    }
+
+    // The message due at the wall clock time, once
+    pub fn poll(&mut self, now_ms: u64) -> Option<SyncMessage> {
+        match &self.pending {
+            Some(message) if now_ms >= message.start_ms => self.pending.take(),
+            _ => None,
+        }
+    }
+}
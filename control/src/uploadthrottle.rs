@@ -0,0 +1,106 @@
+// Upload throttling on a weak WiFi signal
+// Near the edge of the access point range a batch of 128 full-rate records often fails and
+// is sent again, and the transfer thread stays busy with the retries. Below the RSSI
+// threshold the uploads are throttled: a batch is sent at most once per
+// THROTTLED_BATCH_INTERVAL_MS, and the records of the outputs are summarized as while they
+// are off. The throttle is released when the signal is above the threshold by the
+// hysteresis for RECOVER_MS, so a signal around the threshold does not switch back and forth.
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+#![allow(dead_code)]
+
+use crate::hal::{Clock, SystemClock};
+
+// The signal must be this much above the threshold to recover (dB)
+pub const HYSTERESIS_DB: i32 = 5;
+// ... for this long (ms)
+pub const RECOVER_MS: u128 = 10_000;
+// Shortest interval of the batches while throttled (ms)
+pub const THROTTLED_BATCH_INTERVAL_MS: u128 = 5_000;
+
+pub struct UploadThrottle<C: Clock = SystemClock> {
+    // dBm, 0 to disable
+    threshold_dbm: i32,
+    throttled: bool,
+    // Since when the signal is good enough to recover
+    good_since: Option<u128>,
+    last_batch: Option<u128>,
+    clock: C,
+}
+
+impl UploadThrottle<SystemClock> {
+    pub fn new(threshold_dbm: i32) -> UploadThrottle {
+        UploadThrottle::with_clock(threshold_dbm, SystemClock)
+    }
+}
+
+impl<C: Clock> UploadThrottle<C> {
+    pub fn with_clock(threshold_dbm: i32, clock: C) -> UploadThrottle<C> {
+        UploadThrottle {
+            threshold_dbm: threshold_dbm,
+            throttled: false,
+            good_since: None,
+            last_batch: None,
+            clock: clock,
+        }
+    }
+
+    // A threshold of 0 releases the throttle at once
+    pub fn set_threshold(&mut self, threshold_dbm: i32) {
+        self.threshold_dbm = threshold_dbm;
+        if threshold_dbm == 0 {
+            self.throttled = false;
+            self.good_since = None;
+        }
+    }
+
+    pub fn get_threshold(&self) -> i32 {
+        self.threshold_dbm
+    }
+
+    pub fn is_throttled(&self) -> bool {
+        self.throttled
+    }
+
+    // The RSSI of the access point (dBm, 0 while not connected). Returns the new state
+    // when the throttle is applied or released.
+    pub fn update(&mut self, rssi: i32) -> Option<bool> {
+        if self.threshold_dbm == 0 || rssi == 0 {
+            // Not connected: no signal to judge, the state is kept
+            return None;
+        }
+        let now = self.clock.now_ns();
+        if !self.throttled {
+            if rssi < self.threshold_dbm {
+                self.throttled = true;
+                self.good_since = None;
+                return Some(true);
+            }
+            return None;
+        }
+        if rssi < self.threshold_dbm + HYSTERESIS_DB {
+            self.good_since = None;
+            return None;
+        }
+        let since = *self.good_since.get_or_insert(now);
+        if now - since >= RECOVER_MS * 1_000_000 {
+            self.throttled = false;
+            self.good_since = None;
+            return Some(false);
+        }
+        None
+    }
+
+    // A batch may be sent now
+    pub fn batch_due(&self) -> bool {
+        match self.last_batch {
+            Some(last) if self.throttled => self.clock.now_ns() - last >= THROTTLED_BATCH_INTERVAL_MS * 1_000_000,
+            _ => true,
+        }
+    }
+
+    pub fn batch_sent(&mut self) {
+        self.last_batch = Some(self.clock.now_ns());
+    }
+}
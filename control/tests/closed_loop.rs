@@ -0,0 +1,1893 @@
+// Regression tests of the control behavior against the simulated buck stage
+// SPDX-License-Identifier: MIT
+// Copyright (c) 2025 Hiroshi Nakajima
+
+use dcpower_control::limits::{LimitKind, LimitPreWarning, LimitWarning, OverVoltageDetector, ProtectionLimits, SafeMode, ShortCircuitDetector, SAFE_MODE_MAX_CURRENT, SAFE_MODE_MAX_VOLTAGE};
+use dcpower_control::recovery::{RecoveryAction, RecoveryPolicy, TripCause};
+use dcpower_control::regulator::Regulator;
+use dcpower_control::ripple::RippleReport;
+use dcpower_control::cable::{CableEstimate, CablePoint, CableTest};
+use dcpower_control::capture::{Capture, CaptureState, CaptureTrigger};
+use dcpower_control::sense::RemoteSense;
+use dcpower_control::adcfilter::AdcFilter;
+use dcpower_control::bleed::Bleed;
+use dcpower_control::pidtrace::{PidTrace, MAX_TRACE_POINTS};
+use dcpower_control::unitsync::{self, SyncAction, SyncMessage, SyncScheduler, CLOCK_VALID_MS};
+use dcpower_control::share::{Droop, ShareMessage, ShareSlave, ShareUpdate};
+use dcpower_control::peertelemetry::{PeerChannel, PeerTelemetry, MAX_FRAME_LEN};
+use dcpower_control::limitlog::{LimitChange, LimitLog, SETTLE_MS};
+use dcpower_control::power::{signed_power, ZERO_BAND_A};
+use dcpower_control::uploadthrottle::{UploadThrottle, HYSTERESIS_DB, RECOVER_MS};
+use dcpower_control::idlesleep::IdleSleep;
+use dcpower_control::rawframe::{scan, RawSample, FRAME_LEN};
+use dcpower_control::latency::{LatencyReport, LatencyStats, BUCKETS, BUCKET_US};
+use dcpower_control::framediff::{changed_regions, Region, TILE_HEIGHT, TILE_WIDTH};
+use dcpower_control::gesture::{ButtonDebounce, StartStopGesture, Tap, TapDetector, DEBOUNCE_MS, DOUBLE_TAP_MS};
+use dcpower_control::apiauth::{Access, AccessTokens, Role};
+use dcpower_control::touchbaseline::{TouchBaseline, CHECK_PERIOD_MS, QUIET_MS};
+use dcpower_control::offsetcal::{CalibrationAction, CalibrationState, ChannelOffsets, OffsetCalibration};
+use dcpower_control::prodtest::{ProductionTest, TestStep, DISPLAY_MS, TOUCH_MS};
+use dcpower_control::errorcounters::{Counter, ErrorCounters, SAVE_PERIOD_MS};
+use dcpower_control::softlimit::{CurrentLimitMode, SoftCurrentLimit, SoftLimitAction, HOLD_FAIL_MS, MIN_OPERATING_MA, OVER_MS, RESTORE_MS, SETTLE_MS as PPS_SETTLE_MS};
+use dcpower_control::thermal::{ThermalModel, DERATE_MARGIN_C};
+use dcpower_control::perfstats::PerfCounters;
+use dcpower_control::alarms::{AlarmEvent, AlarmValues, Alarms, CLEAR_MS};
+use dcpower_control::testscript::{ScriptCommand, ScriptRun, ScriptState, ScriptStep, TestScript, ASSERT_MS};
+use dcpower_control::consistency::{PowerCheck, PowerCheckEvent, CHECK_SAMPLES};
+use dcpower_control::marker::{Markers, MarkerSource, MAX_MARKER_TEXT_LEN};
+use dcpower_control::scopetrigger::{ScopeTrigger, TriggerConfig, TriggerPolarity, TriggerSource, HOLDOFF_MS};
+use dcpower_control::stepdown::{SetpointRamp, StepDownEvent, STEP_DOWN_BAND_V};
+use dcpower_control::stale::{Reading, SampleState, StalePolicy};
+use dcpower_control::summary::SummaryLog;
+use dcpower_control::session::{RunLabel, SessionTracker};
+use dcpower_control::schedule::{Schedule, ScheduleAction};
+use dcpower_control::cycle::{CyclePhase, CycleTest};
+use dcpower_control::pdprobe::{probe_steps, PdProbe, PdProbeReport, PdoPoint, ProbeOutcome};
+use dcpower_control::pdcal::PdVoltageCalibration;
+use dcpower_control::pwmoffset::{OffsetLearning, OffsetRamp, RampStep, MAX_OFFSET};
+use dcpower_control::efficiency::EfficiencyMeter;
+use dcpower_control::i2chealth::{DeviceEvent, I2cHealth, I2cOutcome, OFFLINE_FAILURES};
+use dcpower_control::units::{format_scaled, UnitFormat};
+use dcpower_control::currentlogs::{sparkline, BufferPolicy, CurrentLog, CurrentRecord, TrendHistory};
+use dcpower_control::hal::{OutputSensor, PowerStage};
+use dcpower_control::sim::{BuckPlant, Plant, SimClock, Simulation};
+
+// cfg.toml defaults
+const KP: f32 = 0.0000005;
+const KI: f32 = 0.00002;
+const KD: f32 = 0.1;
+const MAX_DUTY: u32 = 16383;
+
+fn simulation(input_voltage: f32, load_resistance: f32) -> Simulation<BuckPlant> {
+    let plant = BuckPlant::new(input_voltage, load_resistance, 20.0, MAX_DUTY);
+    Simulation::new(plant, KP, KI, KD, ProtectionLimits::new(5.0, 100.0, 80.0))
+}
+
+#[test]
+fn settles_to_setpoint() {
+    let mut sim = simulation(20.0, 10.0);
+    let last = sim.run(5.0, 30_000);
+    assert!((last.voltage - 5.0).abs() < 0.05, "voltage {}", last.voltage);
+    assert!(sim.trip.is_none());
+}
+
+#[test]
+fn settles_at_fast_control_rate() {
+    // 1kHz control rate (control_rate_hz = 1000)
+    let mut sim = simulation(20.0, 10.0);
+    sim.period_ms = 1;
+    let last = sim.run(5.0, 30_000);
+    assert!((last.voltage - 5.0).abs() < 0.05, "voltage {}", last.voltage);
+    assert!(sim.trip.is_none());
+}
+
+#[test]
+fn follows_setpoint_change() {
+    let mut sim = simulation(20.0, 10.0);
+    sim.run(5.0, 30_000);
+    let last = sim.run(12.0, 30_000);
+    assert!((last.voltage - 12.0).abs() < 0.05, "voltage {}", last.voltage);
+}
+
+#[test]
+fn gain_change_while_running_is_bumpless() {
+    let mut sim = simulation(20.0, 10.0);
+    sim.run(5.0, 30_000);
+    sim.regulator.set_gains(KP, KI * 2.0, KD, 0);
+    let next = sim.step(5.0);
+    assert!((next.voltage - 5.0).abs() < 0.05, "voltage {}", next.voltage);
+    let last = sim.run(5.0, 10_000);
+    assert!((last.voltage - 5.0).abs() < 0.05, "voltage {}", last.voltage);
+    let peak = sim.logs.get_all_data().iter().skip(3_000).map(|l| l.voltage).fold(0.0, f32::max);
+    assert!(peak < 5.0 * 1.10, "peak {}", peak);
+}
+
+#[test]
+fn settles_at_lower_pwm_resolution() {
+    // 20kHz PWM with 11-bit resolution
+    let plant = BuckPlant::new(20.0, 10.0, 20.0, 2048);
+    let mut sim = Simulation::new(plant, KP, KI, KD, ProtectionLimits::new(5.0, 100.0, 80.0));
+    let last = sim.run(5.0, 30_000);
+    assert!((last.voltage - 5.0).abs() < 0.05, "voltage {}", last.voltage);
+}
+
+#[test]
+fn pwm_offset_scales_with_resolution() {
+    let mut regulator = Regulator::with_clock(0.0, 0.0, 0.0, 2048, 8192, SimClock::new());
+    assert_eq!(regulator.update(5.0, 0.0, 0.0, 5.0), 1024);
+    let mut regulator = Regulator::with_clock(0.0, 0.0, 0.0, MAX_DUTY, 8192, SimClock::new());
+    assert_eq!(regulator.update(5.0, 0.0, 0.0, 5.0), 8192);
+}
+
+#[test]
+fn dithering_averages_to_sub_lsb_duty() {
+    // 8193 of 16383 is 1024.19 of 2048
+    let mut regulator = Regulator::with_clock(0.0, 0.0, 0.0, 2048, 8193, SimClock::new());
+    let duties: Vec<u32> = (0..100).map(|_| regulator.update(5.0, 0.0, 0.0, 5.0)).collect();
+    assert!(duties.iter().all(|d| *d == 1024));
+    regulator.set_dither(true);
+    let duties: Vec<u32> = (0..1000).map(|_| regulator.update(5.0, 0.0, 0.0, 5.0)).collect();
+    assert!(duties.iter().all(|d| *d == 1024 || *d == 1025));
+    let average = duties.iter().sum::<u32>() as f32 / duties.len() as f32;
+    assert!((average - 1024.19).abs() < 0.01, "average {}", average);
+}
+
+#[test]
+fn settles_with_dithering() {
+    let mut sim = simulation(20.0, 10.0);
+    sim.regulator.set_dither(true);
+    let last = sim.run(5.0, 30_000);
+    assert!((last.voltage - 5.0).abs() < 0.05, "voltage {}", last.voltage);
+}
+
+#[test]
+fn no_excessive_overshoot() {
+    let mut sim = simulation(20.0, 10.0);
+    sim.run(5.0, 30_000);
+    let peak = sim.logs.get_all_data().iter().map(|l| l.voltage).fold(0.0, f32::max);
+    assert!(peak < 5.0 * 1.10, "peak {}", peak);
+}
+
+#[test]
+fn over_current_trips_and_cuts_output() {
+    // 12V into 2 ohm exceeds the 5A limit
+    let mut sim = simulation(20.0, 2.0);
+    sim.run(12.0, 30_000);
+    assert_eq!(sim.trip, Some(TripCause::OverCurrent));
+    assert!(!sim.output_on);
+    assert_eq!(sim.plant.get_duty(), 0);
+    let last = sim.logs.get_all_data().back().unwrap();
+    assert!(last.voltage < 1.0, "voltage {}", last.voltage);
+}
+
+#[test]
+fn over_power_trips() {
+    let plant = BuckPlant::new(20.0, 10.0, 20.0, MAX_DUTY);
+    let mut sim = Simulation::new(plant, KP, KI, KD, ProtectionLimits::new(5.0, 10.0, 80.0));
+    sim.run(15.0, 30_000);
+    assert_eq!(sim.trip, Some(TripCause::OverPower));
+}
+
+#[test]
+fn logs_every_period() {
+    let mut sim = simulation(20.0, 10.0);
+    sim.run(5.0, 1_000);
+    let logs = sim.logs.get_all_data();
+    assert_eq!(logs.len(), 100);
+    assert_eq!(logs[1].clock - logs[0].clock, 10_000_000);
+    sim.logs.remove_data(40);
+    assert_eq!(sim.logs.get_size(), 60);
+}
+
+#[test]
+fn recovery_retries_then_latches() {
+    let clock = SimClock::new();
+    let mut policy = RecoveryPolicy::with_clock(true, 5, 2, clock.clone());
+    assert_eq!(policy.on_trip(TripCause::OverCurrent), RecoveryAction::Retry(1));
+    clock.advance_ms(4_000);
+    assert!(!policy.poll());
+    clock.advance_ms(1_000);
+    assert!(policy.poll());
+    assert!(!policy.poll());
+    assert_eq!(policy.on_trip(TripCause::OverPower), RecoveryAction::Retry(2));
+    assert_eq!(policy.on_trip(TripCause::OverCurrent), RecoveryAction::Latch);
+}
+
+#[test]
+fn critical_trips_latch() {
+    let mut policy = RecoveryPolicy::with_clock(true, 5, 3, SimClock::new());
+    assert_eq!(policy.on_trip(TripCause::OverTemperature), RecoveryAction::Latch);
+    assert_eq!(policy.on_trip(TripCause::Interlock), RecoveryAction::Latch);
+}
+
+#[test]
+fn regulation_stats_at_steady_state() {
+    let mut sim = simulation(20.0, 10.0);
+    sim.run(5.0, 30_000);
+    let report = sim.stats.report();
+    assert!(report.samples > 0);
+    assert!(report.steady_state_error.abs() < 0.01, "error {}", report.steady_state_error);
+    assert!(report.peak_deviation < 0.05, "peak {}", report.peak_deviation);
+    assert_eq!(report.load_steps, 0);
+}
+
+#[test]
+fn load_step_recovery_is_measured() {
+    let mut sim = simulation(20.0, 10.0);
+    sim.plant.source_resistance = 0.5;
+    sim.run(5.0, 30_000);
+    // 0.5A to 2A
+    sim.plant.load_resistance = 2.5;
+    sim.run(5.0, 30_000);
+    let report = sim.stats.report();
+    assert_eq!(report.load_steps, 1);
+    assert!(report.peak_deviation > 0.05, "peak {}", report.peak_deviation);
+    assert!(report.recovery_time_ms > 0.0 && report.recovery_time_ms < 30_000.0, "recovery {}", report.recovery_time_ms);
+    assert_eq!(report.recovery_time_ms, report.max_recovery_time_ms);
+}
+
+#[test]
+fn ripple_of_a_square_wave() {
+    // 5V with +-10mV alternating, 200 samples in 40ms
+    let samples: Vec<f32> = (0..200).map(|i| if i % 2 == 0 { 5.01 } else { 4.99 }).collect();
+    let report = RippleReport::from_samples(&samples, 40_000);
+    assert_eq!(report.samples, 200);
+    assert!((report.sample_rate_hz - 5000.0).abs() < 1.0, "rate {}", report.sample_rate_hz);
+    assert!((report.mean - 5.0).abs() < 1e-4, "mean {}", report.mean);
+    assert!((report.peak_to_peak - 0.02).abs() < 1e-4, "p-p {}", report.peak_to_peak);
+    assert!((report.rms - 0.01).abs() < 1e-4, "rms {}", report.rms);
+}
+
+#[test]
+fn ripple_of_a_settled_output_is_small() {
+    let mut sim = simulation(20.0, 10.0);
+    sim.run(5.0, 30_000);
+    let samples: Vec<f32> = sim.logs.get_all_data().iter().skip(2_900).map(|l| l.voltage).collect();
+    let report = RippleReport::from_samples(&samples, 1_000_000);
+    assert!(report.peak_to_peak < 0.02, "p-p {}", report.peak_to_peak);
+    assert_eq!(RippleReport::from_samples(&[], 0), RippleReport::default());
+}
+
+#[test]
+fn short_circuit_trips_and_latches() {
+    // Current and power limits above the short circuit current to check the detector alone
+    let plant = BuckPlant::new(20.0, 10.0, 20.0, MAX_DUTY);
+    let mut sim = Simulation::new(plant, KP, KI, KD, ProtectionLimits::new(1000.0, 10000.0, 80.0));
+    sim.short_circuit = ShortCircuitDetector::new(0.5, 3.0);
+    sim.plant.source_resistance = 0.5;
+    sim.run(5.0, 30_000);
+    assert!(sim.trip.is_none());
+    sim.plant.load_resistance = 0.01;
+    sim.run(5.0, 1_000);
+    assert_eq!(sim.trip, Some(TripCause::ShortCircuit));
+    assert_eq!(sim.plant.get_duty(), 0);
+    // The PWM is cut in the sample which sees the collapse
+    let logs = sim.logs.get_all_data();
+    let collapsed = logs.iter().position(|l| l.voltage < 0.5).unwrap();
+    assert_eq!(logs[collapsed].pwm, 0);
+    let mut policy = RecoveryPolicy::with_clock(true, 5, 2, SimClock::new());
+    assert_eq!(policy.on_trip(TripCause::ShortCircuit), RecoveryAction::Latch);
+}
+
+#[test]
+fn over_voltage_trips_above_the_absolute_limit() {
+    // A 3.3V DUT with the setpoint fat-fingered to 12V
+    let mut sim = simulation(20.0, 10.0);
+    sim.over_voltage = OverVoltageDetector::new(3.6, 3);
+    sim.run(3.3, 30_000);
+    assert!(sim.trip.is_none());
+    sim.run(12.0, 2_000);
+    assert_eq!(sim.trip, Some(TripCause::OverVoltage));
+    assert_eq!(sim.plant.get_duty(), 0);
+    // Tripped on the third sample above the limit
+    let logs = sim.logs.get_all_data();
+    let above = logs.iter().position(|l| l.voltage > 3.6).unwrap();
+    assert!(logs[above + 1].voltage > 3.6);
+    assert_eq!(logs[above + 2].pwm, 0);
+    assert!(logs[above + 1].pwm > 0);
+    let mut policy = RecoveryPolicy::with_clock(true, 5, 2, SimClock::new());
+    assert_eq!(policy.on_trip(TripCause::OverVoltage), RecoveryAction::Latch);
+    // A single sample above the limit does not trip it
+    let mut detector = OverVoltageDetector::new(3.6, 3);
+    assert_eq!(detector.check(3.7), None);
+    assert_eq!(detector.check(3.5), None);
+    assert_eq!(detector.check(3.7), None);
+    assert_eq!(detector.check(3.7), None);
+    assert_eq!(detector.check(3.7), Some(TripCause::OverVoltage));
+}
+
+#[test]
+fn start_into_a_heavy_load_is_not_a_short_circuit() {
+    // The inrush while the output rises and a setpoint below the threshold (0.3V into 0.05 ohm, 6A)
+    let plant = BuckPlant::new(20.0, 0.05, 20.0, MAX_DUTY);
+    let mut sim = Simulation::new(plant, KP, KI, KD, ProtectionLimits::new(10.0, 100.0, 80.0));
+    sim.short_circuit = ShortCircuitDetector::new(0.5, 3.0);
+    let last = sim.run(0.3, 30_000);
+    assert!(sim.trip.is_none(), "trip {:?}", sim.trip);
+    assert!(last.current > 3.0, "current {}", last.current);
+}
+
+#[test]
+fn cable_resistance_from_two_setpoints() {
+    // 20V contract through 0.15 ohm, 0.5A and 2.5A input current
+    let rail = |current: f32| 20.0 - 0.15 * current;
+    let clock = SimClock::new();
+    let mut test = CableTest::with_clock(6.0, 12.0, 500, 1_000, clock.clone());
+    let mut result = None;
+    while result.is_none() {
+        let current = if test.setpoint() < 10.0 { 0.5 } else { 2.5 };
+        test.add_sample(rail(current), current);
+        clock.advance_ms(100);
+        result = test.poll();
+    }
+    let estimate = result.unwrap().unwrap();
+    assert!((estimate.resistance - 0.15).abs() < 1e-3, "resistance {}", estimate.resistance);
+    assert!((estimate.low.current - 0.5).abs() < 1e-6);
+    assert!((estimate.high.current - 2.5).abs() < 1e-6);
+}
+
+#[test]
+fn cable_estimate_needs_a_current_step() {
+    let point = CablePoint { voltage: 20.0, current: 0.02 };
+    assert!(CableEstimate::from_points(point, point).is_err());
+}
+
+#[test]
+fn remote_sense_compensates_wiring_drop() {
+    // 0.5 ohm of wiring to a 10 ohm load
+    let mut sim = simulation(20.0, 10.0);
+    sim.plant.wiring_resistance = 0.5;
+    sim.run(5.0, 30_000);
+    let local = sim.plant.load_voltage();
+    assert!(local < 4.8, "load voltage {}", local);
+
+    let mut sim = simulation(20.0, 10.0);
+    sim.plant.wiring_resistance = 0.5;
+    sim.plant.remote_sense = Some(true);
+    sim.remote_sense = Some(RemoteSense::new(1.0));
+    sim.run(5.0, 30_000);
+    let remote = sim.plant.load_voltage();
+    assert!((remote - 5.0).abs() < 0.05, "load voltage {}", remote);
+    assert!(!sim.remote_sense.as_ref().unwrap().is_lost());
+}
+
+#[test]
+fn disconnected_remote_sense_falls_back_to_local() {
+    let mut sim = simulation(20.0, 10.0);
+    sim.plant.wiring_resistance = 0.5;
+    sim.plant.remote_sense = Some(true);
+    sim.remote_sense = Some(RemoteSense::new(1.0));
+    sim.run(5.0, 30_000);
+    sim.plant.remote_sense = Some(false);
+    let last = sim.run(5.0, 30_000);
+    assert!(sim.remote_sense.as_ref().unwrap().is_lost());
+    assert!((last.voltage - 5.0).abs() < 0.05, "voltage {}", last.voltage);
+    let peak = sim.logs.get_all_data().iter().skip(3_000).map(|l| l.voltage).fold(0.0, f32::max);
+    assert!(peak < 5.0 + 1.0, "peak {}", peak);
+    // A missing reading is lost at once
+    let mut sense = RemoteSense::new(1.0);
+    assert_eq!(sense.select(5.0, None), (5.0, Some("no reading")));
+    assert_eq!(sense.select(5.0, Some(5.0)), (5.0, None));
+    assert!(sense.is_lost());
+}
+
+#[test]
+fn records_are_acknowledged_by_sequence() {
+    let mut clogs = CurrentRecord::new();
+    for _ in 0..10 {
+        clogs.record(CurrentLog::default());
+    }
+    let sequences: Vec<u64> = clogs.get_all_data().iter().map(|it| it.sequence).collect();
+    assert_eq!(sequences, (0..10).collect::<Vec<u64>>());
+    // A batch of 0..=3 is stored by the server
+    assert_eq!(clogs.acknowledge(3), 4);
+    assert_eq!(clogs.get_all_data()[0].sequence, 4);
+    // A late acknowledgement after clear does not remove the new records
+    clogs.clear();
+    clogs.record(CurrentLog::default());
+    assert_eq!(clogs.acknowledge(9), 0);
+    assert_eq!(clogs.get_size(), 1);
+    assert_eq!(clogs.get_lost(), 0);
+    // A batch which cannot be stored is dropped up to its last record
+    clogs.record(CurrentLog::default());
+    assert_eq!(clogs.discard(10), 1);
+    assert_eq!(clogs.get_lost(), 1);
+    assert_eq!(clogs.get_all_data()[0].sequence, 11);
+}
+
+#[test]
+fn full_buffer_stops_or_overwrites() {
+    let mut clogs = CurrentRecord::with_capacity(4, BufferPolicy::Stop);
+    for _ in 0..6 {
+        clogs.record(CurrentLog::default());
+    }
+    assert!(clogs.is_full());
+    assert_eq!(clogs.get_all_data().back().unwrap().sequence, 3);
+    assert_eq!(clogs.get_lost(), 0);
+
+    let mut clogs = CurrentRecord::with_capacity(4, BufferPolicy::Overwrite);
+    let dropped: usize = (0..6).map(|_| clogs.record(CurrentLog::default())).sum();
+    assert_eq!(dropped, 2);
+    assert_eq!(clogs.get_lost(), 2);
+    let sequences: Vec<u64> = clogs.get_all_data().iter().map(|it| it.sequence).collect();
+    assert_eq!(sequences, vec![2, 3, 4, 5]);
+    // An acknowledgement of overwritten records removes only the ones still held
+    assert_eq!(clogs.acknowledge(2), 1);
+}
+
+fn log(voltage: f32, current: f32) -> CurrentLog {
+    let mut sample = CurrentLog::default();
+    sample.voltage = voltage;
+    sample.current = current;
+    sample
+}
+
+#[test]
+fn capture_keeps_pre_and_post_trigger_samples() {
+    let mut capture = Capture::new(3, 2);
+    capture.arm(CaptureTrigger::CurrentAbove(1.0));
+    let currents = [0.1, 0.2, 0.3, 0.4, 0.5, 2.0, 2.1, 2.2, 0.1];
+    let frozen: Vec<bool> = currents.iter().map(|&c| capture.push(&log(5.0, c))).collect();
+    assert_eq!(frozen.iter().position(|&f| f), Some(6));
+    assert_eq!(capture.state(), CaptureState::Frozen);
+    let window = capture.take().unwrap();
+    let samples: Vec<f32> = window.samples.iter().map(|s| s.current).collect();
+    assert_eq!(samples, vec![0.3, 0.4, 0.5, 2.0, 2.1]);
+    assert_eq!(window.trigger_index, 3);
+    assert_eq!(capture.state(), CaptureState::Idle);
+}
+
+#[test]
+fn capture_fires_on_an_edge_only() {
+    // The condition is already true when armed: no trigger until it goes false and true again
+    let mut capture = Capture::new(2, 1);
+    capture.arm(CaptureTrigger::VoltageBelow(1.0));
+    for v in [0.5, 0.5, 0.5, 5.0, 5.0] {
+        assert!(!capture.push(&log(v, 0.0)));
+    }
+    assert!(capture.push(&log(0.2, 0.0)));
+    assert_eq!(capture.take().unwrap().trigger_index, 2);
+    // A fault fires at once with the pre-trigger records so far
+    let mut capture = Capture::new(5, 2);
+    capture.arm(CaptureTrigger::Fault);
+    capture.push(&log(5.0, 1.0));
+    capture.fault();
+    assert_eq!(capture.state(), CaptureState::Triggered);
+    capture.push(&log(0.0, 0.0));
+    assert!(capture.push(&log(0.0, 0.0)));
+    let window = capture.take().unwrap();
+    assert_eq!((window.trigger_index, window.samples.len()), (1, 3));
+}
+
+#[test]
+fn summary_of_each_interval() {
+    let mut summary = SummaryLog::new(10);
+    let mut summaries = Vec::new();
+    // 25s of records every 100ms, the voltage ramps 0..25V
+    for i in 0..250u32 {
+        let mut sample = log(i as f32 * 0.1, 1.0);
+        sample.clock = i as u128 * 100_000_000;
+        summaries.extend(summary.add(&sample));
+    }
+    assert_eq!(summaries.len(), 2);
+    let first = summaries[0];
+    assert_eq!(first.samples, 100);
+    assert_eq!((first.start_clock, first.end_clock), (0, 9_900_000_000));
+    assert!((first.voltage.min - 0.0).abs() < 1e-6 && (first.voltage.max - 9.9).abs() < 1e-4);
+    assert!((first.voltage.mean - 4.95).abs() < 1e-4, "mean {}", first.voltage.mean);
+    assert_eq!(first.current.mean, 1.0);
+    assert!((summaries[1].voltage.min - 10.0).abs() < 1e-4);
+    // A reset discards the partial interval
+    summary.reset();
+    assert!(summary.add(&log(0.0, 0.0)).is_none());
+}
+
+#[test]
+fn session_report_integrates_energy_and_charge() {
+    // 5V 2A (10W) for 1 hour, records every 10ms
+    let mut session = SessionTracker::new();
+    session.start(1, 5.0, 0);
+    let mut sample = log(5.0, 2.0);
+    sample.power = 10.0;
+    for i in 0..=360_000u32 {
+        sample.clock = i as u128 * 10_000_000;
+        session.add(&sample);
+    }
+    session.trip("OverCurrent");
+    let report = session.finish(3_600_000_000_000).unwrap();
+    assert_eq!(report.end_reason, "trip (OverCurrent)");
+    assert!(!session.is_active());
+    assert!((report.energy_wh - 10.0).abs() < 1e-3, "energy {}", report.energy_wh);
+    assert!((report.charge_ah - 2.0).abs() < 1e-3, "charge {}", report.charge_ah);
+    assert_eq!(report.duration_secs(), 3600.0);
+    let text = report.to_text("2025-01-01 00:00:00");
+    assert!(text.contains("Duration:  1h 00m 00s"), "{}", text);
+    assert!(text.contains("Trips:     OverCurrent"), "{}", text);
+    // Records without a session are ignored
+    session.add(&sample);
+    assert!(session.finish(0).is_none());
+}
+
+#[test]
+fn schedule_runs_each_entry_once_at_its_time() {
+    let mut schedule = Schedule::parse("weekdays 08:00 on; weekdays 18:00 off; sat 09:30 ch2 5.0V").unwrap();
+    assert_eq!(schedule.get_entries().len(), 3);
+    // Monday 08:00, polled every loop of the minute
+    let fired = schedule.poll(0, 8 * 60);
+    assert_eq!(fired.len(), 1);
+    assert_eq!((fired[0].channel, fired[0].action), (0, ScheduleAction::Output(true)));
+    assert!(schedule.poll(0, 8 * 60).is_empty());
+    assert_eq!(schedule.poll(4, 18 * 60)[0].action, ScheduleAction::Output(false));
+    // Not on Sunday
+    assert!(schedule.poll(6, 8 * 60).is_empty());
+    let fired = schedule.poll(5, 9 * 60 + 30);
+    assert_eq!((fired[0].channel, fired[0].action), (1, ScheduleAction::Voltage(5.0)));
+    assert_eq!(fired[0].to_text(), "sat 09:30 ch2 5.000V");
+    // A range may wrap over the week end
+    let mut wrap = Schedule::parse("fri-mon 12:00 off").unwrap();
+    assert_eq!(wrap.get_entries()[0].days, 0x71);
+    assert_eq!(wrap.poll(6, 12 * 60).len(), 1);
+    assert!(Schedule::parse("").unwrap().is_empty());
+    assert!(Schedule::parse("25:00 on").is_err());
+    assert!(Schedule::parse("08:00 ch3 on").is_err());
+    assert!(Schedule::parse("someday 08:00 on").is_err());
+    assert!(Schedule::parse("08:00 start").is_err());
+    assert_eq!(Schedule::parse("08:00 cycle").unwrap().get_entries()[0].action, ScheduleAction::Cycle);
+    assert!(Schedule::parse("08:00 ch2 cycle").is_err());
+}
+
+#[test]
+fn cycle_test_counts_cycles_and_stops() {
+    let clock = SimClock::new();
+    let mut test = CycleTest::with_clock(12.0, 2_000, 1_000, 2, clock.clone());
+    assert!(test.output());
+    clock.advance_ms(1_990);
+    assert_eq!(test.poll(), None);
+    clock.advance_ms(10);
+    assert_eq!(test.poll(), Some(CyclePhase::Off));
+    assert_eq!(test.get_completed(), 1);
+    assert!(!test.output() && test.is_running());
+    clock.advance_ms(1_000);
+    assert_eq!(test.poll(), Some(CyclePhase::On));
+    clock.advance_ms(2_000);
+    // The last cycle ends with the output off
+    assert_eq!(test.poll(), Some(CyclePhase::Done));
+    assert_eq!(test.get_completed(), 2);
+    assert!(!test.output() && !test.is_running());
+    clock.advance_ms(10_000);
+    assert_eq!(test.poll(), None);
+}
+
+#[test]
+fn cycle_test_aborts_on_fault() {
+    let clock = SimClock::new();
+    // 0 cycles repeats until stopped
+    let mut test = CycleTest::with_clock(5.0, 1_000, 1_000, 0, clock.clone());
+    for _ in 0..10 {
+        clock.advance_ms(1_000);
+        test.poll();
+    }
+    assert_eq!(test.get_completed(), 5);
+    test.abort("OverCurrent trip");
+    assert_eq!(test.phase(), &CyclePhase::Aborted("OverCurrent trip".to_string()));
+    assert!(!test.output());
+    clock.advance_ms(1_000);
+    assert_eq!(test.poll(), None);
+    assert_eq!(test.get_completed(), 5);
+}
+
+#[test]
+fn run_label_is_attached_to_the_session() {
+    let mut session = SessionTracker::new();
+    session.start(1, 5.0, 0);
+    // Set during the session
+    let label = RunLabel::new(" proto-B3 ", "rev2 board, 10uF output cap").unwrap();
+    assert_eq!(label.dut, "proto-B3");
+    session.set_label(&label);
+    let report = session.finish(1_000_000_000).unwrap();
+    assert_eq!(report.label, label);
+    let text = report.to_text("2025-01-01 00:00:00");
+    assert!(text.contains("DUT:       proto-B3"), "{}", text);
+    assert!(text.contains("Note:      rev2 board, 10uF output cap"), "{}", text);
+    // Kept for the next sessions until cleared
+    session.start(1, 5.0, 0);
+    assert_eq!(session.finish(0).unwrap().label.dut, "proto-B3");
+    session.set_label(&RunLabel::default());
+    session.start(1, 5.0, 0);
+    assert!(!session.finish(0).unwrap().to_text("").contains("DUT:"));
+    assert!(RunLabel::new(&"x".repeat(33), "").is_err());
+    assert!(RunLabel::new("a\nb", "").is_err());
+    assert!(RunLabel::new("", "note only").is_err());
+}
+
+#[test]
+fn display_values_are_scaled_to_milli_units() {
+    let format = UnitFormat::default();
+    // A small current is not lost as 0.0A
+    assert_eq!(format.current(0.003), "3.00mA");
+    assert_eq!(format.current(0.4567), "457mA");
+    assert_eq!(format.current(1.234), "1.23A");
+    assert_eq!(format.current(0.0), "0.00A");
+    // Rounding carries over to the next unit and integer digit
+    assert_eq!(format.current(0.9996), "1.00A");
+    assert_eq!(format_scaled(9.996, "V", 3), "10.0V");
+    // mV resolution at low voltages
+    assert_eq!(format.voltage(0.05), "50.00mV");
+    assert_eq!(format.voltage(5.0), "5.000V");
+    assert_eq!(format.voltage(12.345), "12.35V");
+    assert_eq!(format.power(0.0123), "12.3mW");
+    assert_eq!(format.power(-25.0), "-25.0W");
+    assert_eq!(format_scaled(0.0000004, "A", 3), "0.00mA");
+    assert_eq!(format_scaled(f32::NAN, "A", 3), "--A");
+}
+
+// Request, settle, then measure a step at the rail voltage; the report after the last step
+fn probe_step(probe: &mut PdProbe<SimClock>, clock: &SimClock, contract: Option<f32>, rail: f32) -> Option<PdProbeReport> {
+    assert!(probe.next_request().is_some());
+    assert!(probe.next_request().is_none());
+    probe.contract(contract);
+    let mut report = None;
+    for _ in 0..150 {
+        probe.add_sample(rail);
+        clock.advance_ms(10);
+        report = report.or(probe.poll());
+    }
+    report
+}
+
+#[test]
+fn pd_probe_checks_each_pdo() {
+    let pdos = [
+        PdoPoint { index: 1, voltage: 5.0, current: 3.0, fixed: true },
+        PdoPoint { index: 2, voltage: 9.0, current: 3.0, fixed: true },
+        PdoPoint { index: 3, voltage: 21.0, current: 3.0, fixed: false },
+    ];
+    let steps = probe_steps(&pdos);
+    let voltages: Vec<f32> = steps.iter().map(|s| s.voltage).collect();
+    assert_eq!(voltages, vec![5.0, 9.0, 5.0, 13.0, 21.0]);
+    let clock = SimClock::new();
+    let mut probe = PdProbe::with_clock(&pdos, 5.0, clock.clone());
+    assert!(probe_step(&mut probe, &clock, Some(5.0), 5.05).is_none());
+    // A sagging 9V point
+    assert!(probe_step(&mut probe, &clock, Some(9.0), 8.3).is_none());
+    assert!(probe_step(&mut probe, &clock, Some(5.0), 5.0).is_none());
+    assert!(probe_step(&mut probe, &clock, None, 0.0).is_none());
+    let report = probe_step(&mut probe, &clock, Some(21.0), 20.9).unwrap();
+    let outcomes: Vec<ProbeOutcome> = report.results.iter().map(|r| r.outcome).collect();
+    assert_eq!(outcomes, vec![ProbeOutcome::Pass, ProbeOutcome::OutOfTolerance, ProbeOutcome::Pass,
+        ProbeOutcome::Rejected, ProbeOutcome::Pass]);
+    assert!((report.results[1].mean - 8.3).abs() < 1e-4);
+    assert_eq!(report.passed(), 3);
+    assert!(!report.is_compatible());
+    let text = report.to_text();
+    assert!(text.contains("PDO2 Fixed 9.00V 3.00A: 9.00V -> 8.300V"), "{}", text);
+    assert!(text.contains("PDO3 PPS 21.00V 3.00A: 13.00V rejected"), "{}", text);
+    assert!(text.ends_with("3/5 points passed: not compatible"), "{}", text);
+    assert!(!probe.is_running());
+    assert!(probe.poll().is_none());
+}
+
+#[test]
+fn pd_probe_times_out_and_aborts() {
+    let pdos = [
+        PdoPoint { index: 1, voltage: 5.0, current: 3.0, fixed: true },
+        PdoPoint { index: 2, voltage: 12.0, current: 2.0, fixed: true },
+    ];
+    let clock = SimClock::new();
+    let mut probe = PdProbe::with_clock(&pdos, 5.0, clock.clone());
+    assert!(probe.next_request().is_some());
+    clock.advance_ms(5_000);
+    assert!(probe.poll().is_none());
+    assert_eq!(probe.progress(), (1, 2));
+    // A late result is ignored
+    probe.contract(Some(5.0));
+    assert!(probe.next_request().is_some());
+    probe.abort("output started");
+    let report = probe.poll().unwrap();
+    assert_eq!(report.results.len(), 1);
+    assert_eq!(report.results[0].outcome, ProbeOutcome::NoResponse);
+    assert_eq!(report.aborted.as_deref(), Some("output started"));
+    assert!(!report.is_compatible());
+}
+
+#[test]
+fn efficiency_of_each_interval_and_input_energy() {
+    let mut meter = EfficiencyMeter::new(10, 70.0);
+    let mut report = None;
+    // 10W in, 8W out for 10s at one sample per second
+    for sec in 0..=10u128 {
+        report = meter.add(sec * 1_000_000_000, 10.0, 8.0);
+    }
+    let report = report.unwrap();
+    assert!((report.input_power - 10.0).abs() < 1e-4);
+    assert!((report.efficiency.unwrap() - 80.0).abs() < 1e-3);
+    assert!((report.input_energy_wh - 100.0 / 3600.0).abs() < 1e-9);
+    assert_eq!(report.warning, None);
+    // Drops to 60%, then has to recover above the level with the margin
+    let mut warnings = Vec::new();
+    for (sec, output) in (11..=40u128).zip([6.0; 10].into_iter().chain([7.1; 10]).chain([7.3; 10])) {
+        if let Some(r) = meter.add(sec * 1_000_000_000, 10.0, output) {
+            warnings.push(r.warning);
+        }
+    }
+    assert_eq!(warnings, vec![Some(true), None, Some(false)]);
+    assert!(!meter.is_low());
+    // No efficiency at a low output power, and no integration over a gap
+    meter.reset();
+    let reports: Vec<_> = (100..=110u128).filter_map(|sec| meter.add(sec * 1_000_000_000, 1.0, 0.1)).collect();
+    assert_eq!(reports.len(), 1);
+    assert_eq!(reports[0].efficiency, None);
+    assert!((meter.get_input_energy_wh() - 410.0 / 3600.0).abs() < 1e-6);
+}
+
+#[test]
+fn records_keep_each_temperature_source() {
+    let mut record = CurrentLog::default();
+    assert_eq!(record.get_source_temps(), [None, None, None]);
+    record.set_source_temps(Some(41.26), Some(38.0), None);
+    assert_eq!(record.get_source_temps(), [Some(41.3), Some(38.0), None]);
+    record.set_source_temps(Some(f32::NAN), Some(-12.34), Some(55.0));
+    assert_eq!(record.get_source_temps(), [None, Some(-12.3), Some(55.0)]);
+    record.set_pd_request(20.0004, 3000);
+    assert_eq!((record.pd_voltage_mv, record.pd_current_ma), (20000, 3000));
+    // The log buffer size in PSRAM is 64 bytes per record
+    assert!(std::mem::size_of::<CurrentLog>() <= 64);
+}
+
+#[test]
+fn reverse_current_gives_negative_power() {
+    // The POWER register reads |V x I|
+    assert_eq!(signed_power(12.0, 0.5, 6.0), 6.0);
+    assert_eq!(signed_power(12.0, -0.5, 6.0), -6.0);
+    // Offset noise around 0A keeps the register value
+    assert_eq!(signed_power(12.0, -ZERO_BAND_A / 2.0, 0.012), 0.012);
+    let mut record = CurrentLog::default();
+    record.power = signed_power(12.0, -0.5, 6.0);
+    assert!(record.is_reverse());
+    record.power = signed_power(12.0, 0.0, 0.0);
+    assert!(!record.is_reverse());
+}
+
+#[test]
+fn i2c_health_reports_changes_and_a_hung_bus() {
+    let mut health = I2cHealth::new(&[("INA228 CH1", 0x40), ("AP33772S", 0x52)]);
+    assert_eq!(health.record(0x40, I2cOutcome::Ok), None);
+    // A flaky device goes offline once, not at every failed read
+    let mut events = Vec::new();
+    for _ in 0..OFFLINE_FAILURES * 2 {
+        events.extend(health.record(0x40, I2cOutcome::Nack));
+    }
+    assert_eq!(events, vec![DeviceEvent::Offline("INA228 CH1", 0x40, I2cOutcome::Nack)]);
+    assert!(!health.is_hung());
+    assert_eq!(health.record(0x40, I2cOutcome::Ok), Some(DeviceEvent::Online("INA228 CH1", 0x40)));
+    let device = &health.get_devices()[0];
+    assert_eq!((device.transfers, device.nacks), (OFFLINE_FAILURES as u64 * 2 + 2, OFFLINE_FAILURES as u64 * 2));
+    // Unknown addresses are not counted
+    assert_eq!(health.record(0x10, I2cOutcome::Timeout), None);
+    // Repeated timeouts mean SDA is held, until the bus is recovered
+    for _ in 0..3 {
+        health.record(0x52, I2cOutcome::Timeout);
+    }
+    assert!(health.is_hung());
+    health.recovered(true);
+    assert!(!health.is_hung());
+    assert_eq!(health.get_recoveries(), 1);
+    // With all the devices failing, a recovery is tried again after a new run of failures
+    for _ in 0..OFFLINE_FAILURES {
+        health.record(0x40, I2cOutcome::Nack);
+        health.record(0x52, I2cOutcome::Nack);
+    }
+    assert!(health.is_hung());
+    health.recovered(false);
+    health.record(0x40, I2cOutcome::Nack);
+    health.record(0x52, I2cOutcome::Nack);
+    assert!(!health.is_hung());
+    assert_eq!(health.get_failed_recoveries(), 1);
+}
+
+#[test]
+fn failed_reads_reuse_the_last_sample_then_fault() {
+    let mut policy = StalePolicy::new(3);
+    let good = Reading { voltage: 5.0, current: 1.0, power: 5.0 };
+    assert_eq!(policy.update(Some(good)), (good, SampleState::Fresh));
+    // Not 0V to the PID
+    assert_eq!(policy.update(None), (good, SampleState::Stale(1)));
+    assert_eq!(policy.update(None), (good, SampleState::Stale(2)));
+    assert_eq!(policy.update(None), (good, SampleState::Stale(3)));
+    assert_eq!(policy.update(None).1, SampleState::Fault);
+    // A good read ends the run
+    let next = Reading { voltage: 4.9, current: 1.1, power: 5.4 };
+    assert_eq!(policy.update(Some(next)), (next, SampleState::Fresh));
+    assert_eq!(policy.update(None), (next, SampleState::Stale(1)));
+    policy.reset();
+    assert_eq!(policy.update(None).1, SampleState::Stale(1));
+    assert_eq!(policy.get_stale_total(), 6);
+    assert!(TripCause::SensorFault.is_critical());
+}
+
+#[test]
+fn adc_filter_rejects_spikes_and_follows_steps() {
+    let mut filter = AdcFilter::new(5, 0.5, 5.0);
+    for _ in 0..20 {
+        filter.update(30.0);
+    }
+    assert!((filter.get_value().unwrap() - 30.0).abs() < 1e-4);
+    // A single spike (an over-temperature trip before) is rejected
+    assert!((filter.update(95.0) - 30.0).abs() < 1e-4);
+    assert_eq!(filter.get_rejected(), 1);
+    // Noise within the limit is smoothed by the median and the IIR filter
+    for reading in [31.0, 29.0, 30.5, 29.5, 30.0] {
+        assert!((filter.update(reading) - 30.0).abs() < 0.5);
+    }
+    // A real step is taken after a full window of readings at the new level
+    let mut value = 0.0;
+    for _ in 0..30 {
+        value = filter.update(20.0);
+    }
+    assert!((value - 20.0).abs() < 0.01, "value {}", value);
+    // Invalid readings are ignored
+    assert!((filter.update(f32::NAN) - value).abs() < 1e-6);
+}
+
+#[test]
+fn pd_voltage_calibration_through_two_points() {
+    let clock = SimClock::new();
+    assert!(PdVoltageCalibration::with_clock(5.0, 6.0, clock.clone()).is_err());
+    let mut cal = PdVoltageCalibration::with_clock(5.0, 20.0, clock.clone()).unwrap();
+    // The divider reads 2% low with a 50mV offset
+    let adc = |rail: f32| (rail - 0.05) / 1.02;
+    let mut result = None;
+    for rail in [5.0, 20.0] {
+        assert_eq!(cal.next_request(), Some(rail));
+        cal.contract(Some(rail));
+        for _ in 0..40 {
+            clock.advance_ms(100);
+            cal.add_adc(adc(rail));
+            cal.add_reference(rail);
+            result = result.or(cal.poll());
+        }
+    }
+    let correction = result.unwrap().unwrap();
+    assert!((correction.gain - 1.02).abs() < 1e-4, "{:?}", correction);
+    assert!((correction.offset - 0.05).abs() < 1e-3, "{:?}", correction);
+    assert!((correction.apply(adc(12.0)) - 12.0).abs() < 1e-3);
+    assert_eq!(cal.get_points().len(), 2);
+    assert!(!cal.is_running());
+    // A rejected request or an implausible gain fails the calibration
+    let mut cal = PdVoltageCalibration::with_clock(5.0, 20.0, clock.clone()).unwrap();
+    cal.next_request();
+    cal.contract(None);
+    assert!(cal.poll().unwrap().is_err());
+    let mut cal = PdVoltageCalibration::with_clock(5.0, 20.0, clock.clone()).unwrap();
+    let mut result = None;
+    for rail in [5.0, 20.0] {
+        cal.next_request();
+        cal.contract(Some(rail));
+        for _ in 0..40 {
+            clock.advance_ms(100);
+            cal.add_adc(rail * 0.7);
+            cal.add_reference(rail);
+            result = result.or(cal.poll());
+        }
+    }
+    assert!(result.unwrap().unwrap_err().contains("implausible"));
+}
+
+// Ramp the duty of the plant with the output off until the ramp ends
+fn ramp_offset(plant: &mut BuckPlant, clock: &SimClock) -> Result<u32, String> {
+    let mut ramp = OffsetRamp::with_clock(MAX_DUTY, clock.clone());
+    for _ in 0..10_000 {
+        clock.advance_ms(1);
+        match ramp.update(plant.read_voltage()) {
+            RampStep::Duty(duty) => plant.set_duty(duty),
+            RampStep::Done(result) => return result,
+        }
+        plant.advance(1.0);
+    }
+    Err("ramp did not end".to_string())
+}
+
+#[test]
+fn pwm_offset_is_learned_below_the_rise_of_the_output() {
+    let clock = SimClock::new();
+    let mut plant = BuckPlant::new(20.0, 100.0, 2.0, MAX_DUTY);
+    plant.dead_duty = 2000;
+    let offset = ramp_offset(&mut plant, &clock).unwrap();
+    assert!((2000..2100).contains(&offset), "{}", offset);
+    // The output stays close to 0V
+    assert!(plant.read_voltage() < 0.2);
+    // No rise up to the maximum offset
+    let mut plant = BuckPlant::new(20.0, 100.0, 2.0, MAX_DUTY);
+    plant.dead_duty = MAX_DUTY;
+    assert!(ramp_offset(&mut plant, &clock).is_err());
+    // The regulator starts from the offset
+    let mut sim = simulation(20.0, 10.0);
+    sim.regulator.set_pwm_offset(offset);
+    assert_eq!(sim.regulator.get_pwm_offset(), offset);
+    assert!(offset < MAX_OFFSET);
+}
+
+#[test]
+fn pwm_offset_learning_at_each_voltage() {
+    let clock = SimClock::new();
+    let mut learning = OffsetLearning::with_clock(&[20.0, 5.0, 9.0, 5.0], 2, clock.clone());
+    assert_eq!(learning.progress(), (0, 3));
+    let mut result = None;
+    for (voltage, offsets) in [(5.0, [Ok(1000), Err("no rise".to_string())]), (9.0, [Ok(1400), Ok(900)]), (20.0, [Ok(2000), Ok(1200)])] {
+        assert_eq!(learning.next_request(), Some(voltage));
+        assert_eq!(learning.next_request(), None);
+        learning.contract(Some(voltage));
+        assert!(!learning.start_ramp());
+        clock.advance_ms(1000);
+        assert!(learning.start_ramp());
+        for (channel, offset) in offsets.into_iter().enumerate() {
+            learning.ramp_result(channel, offset);
+        }
+        result = learning.poll();
+    }
+    let tables = result.unwrap().unwrap();
+    assert_eq!(tables[0].get_points(), &[(5.0, 1000), (9.0, 1400), (20.0, 2000)]);
+    assert_eq!(tables[1].get_points().len(), 2);
+    assert_eq!(learning.get_errors().len(), 1);
+    // Between the points, and the nearest one outside them
+    assert_eq!(tables[0].offset_at(7.0), Some(1200));
+    assert_eq!(tables[0].offset_at(3.3), Some(1000));
+    assert_eq!(tables[1].offset_at(28.0), Some(1200));
+    assert_eq!(tables[1].offset_at(5.0), Some(900));
+    // A channel which learned nothing fails the learning, as does an abort
+    let mut learning = OffsetLearning::with_clock(&[5.0], 1, clock.clone());
+    learning.next_request();
+    learning.contract(None);
+    assert!(learning.poll().unwrap().unwrap_err().contains("rejected"));
+    let mut learning = OffsetLearning::with_clock(&[5.0, 9.0], 1, clock.clone());
+    learning.next_request();
+    learning.abort("output started");
+    assert_eq!(learning.poll(), Some(Err("output started".to_string())));
+    assert_eq!(learning.poll(), None);
+}
+
+#[test]
+fn bleed_discharges_to_the_target_and_rests() {
+    let clock = SimClock::new();
+    let mut bleed = Bleed::with_clock(2000, clock.clone());
+    // Stepping down from 20V to 5V, released near the new setpoint
+    assert!(bleed.update(true, 5.0, 20.0));
+    clock.advance_ms(10);
+    assert!(bleed.update(true, 5.0, 5.15));
+    assert!(!bleed.update(true, 5.0, 5.05));
+    // Regulating at the setpoint
+    assert!(!bleed.update(true, 5.0, 5.1));
+    // Turned off, discharged to 0V
+    assert!(bleed.update(false, 5.0, 5.0));
+    assert!(!bleed.update(false, 5.0, 0.05));
+    // A discharge which does not end is cut after max_on and rests as long
+    assert!(bleed.update(false, 0.0, 12.0));
+    clock.advance_ms(2000);
+    assert!(!bleed.update(false, 0.0, 12.0));
+    clock.advance_ms(1000);
+    assert!(!bleed.update(false, 0.0, 12.0));
+    clock.advance_ms(1000);
+    assert!(bleed.update(false, 0.0, 12.0));
+    assert!(bleed.is_engaged());
+}
+
+// Run the loop on the ramped setpoint, returns the events and the lowest output voltage
+fn run_step_down(sim: &mut Simulation<BuckPlant>, ramp: &mut SetpointRamp<SimClock>, target: f32, duration_ms: u32) -> (Vec<StepDownEvent>, f32) {
+    let mut events = Vec::new();
+    let mut lowest = f32::MAX;
+    for _ in 0..(duration_ms / sim.period_ms) {
+        let (setpoint, event) = ramp.update(target, sim.plant.read_voltage());
+        match event {
+            Some(StepDownEvent::Started { .. }) => sim.regulator.set_stepping_down(true),
+            Some(_) => sim.regulator.set_stepping_down(false),
+            None => {},
+        }
+        events.extend(event);
+        lowest = lowest.min(sim.step(setpoint).voltage);
+        sim.plant.advance(sim.period_ms as f32);
+    }
+    (events, lowest)
+}
+
+#[test]
+fn step_down_ramps_and_completes_within_the_time_limit() {
+    let mut sim = simulation(20.0, 10.0);
+    let mut ramp = SetpointRamp::with_clock(2000, sim.clock.clone());
+    ramp.reset(12.0);
+    sim.run(12.0, 30_000);
+    let (events, lowest) = run_step_down(&mut sim, &mut ramp, 5.0, 3000);
+    assert_eq!(events[0], StepDownEvent::Started { from: 12.0, to: 5.0 });
+    match events[1] {
+        StepDownEvent::Completed(ms) => assert!((1000.0..=2000.0).contains(&ms), "{}", ms),
+        event => panic!("{:?}", event),
+    }
+    assert_eq!(events.len(), 2);
+    assert!(lowest > 5.0 - STEP_DOWN_BAND_V, "{}", lowest);
+    assert!(!ramp.is_active());
+    // A step up is applied at once and the next step down ramps again
+    assert_eq!(ramp.update(12.0, 5.0), (12.0, None));
+    assert!(matches!(ramp.update(5.0, 12.0).1, Some(StepDownEvent::Started { .. })));
+    // An output which does not follow times out at the limit
+    let clock = SimClock::new();
+    let mut ramp = SetpointRamp::with_clock(500, clock.clone());
+    ramp.reset(20.0);
+    ramp.update(5.0, 20.0);
+    clock.advance_ms(300);
+    assert_eq!(ramp.update(5.0, 15.0), (5.0, None));
+    clock.advance_ms(200);
+    assert_eq!(ramp.update(5.0, 12.0), (5.0, Some(StepDownEvent::TimedOut(12.0))));
+    // Disabled, or a small step, is applied at once
+    ramp.set_time_limit(0);
+    assert_eq!(ramp.update(3.3, 5.0), (3.3, None));
+    ramp.set_time_limit(500);
+    assert_eq!(ramp.update(3.25, 3.3), (3.25, None));
+}
+
+#[test]
+fn pid_trace_samples_the_terms_at_the_trace_rate() {
+    let mut sim = simulation(20.0, 10.0);
+    sim.period_ms = 1;
+    let mut trace = PidTrace::with_clock(100, sim.clock.clone());
+    // 1s at the 1kHz control rate
+    for _ in 0..1000 {
+        let log = sim.run(5.0, 1);
+        trace.record(5.0, log.voltage, sim.regulator.get_terms(), log.pwm);
+    }
+    let points = trace.take();
+    assert_eq!(points.len(), 100);
+    assert!(points.windows(2).all(|p| p[1].clock - p[0].clock == 10_000_000));
+    for p in &points[1..] {
+        let t = p.terms;
+        assert!((t.proportional + t.integral + t.derivative - t.output).abs() <= 1e-6 * t.output.abs().max(1.0), "{:?}", t);
+        assert!((t.error - (p.setpoint - p.voltage)).abs() < 1e-4, "{:?}", p);
+    }
+    assert!(trace.take().is_empty());
+    // Bounded without a batch taken, the oldest points dropped
+    trace.set_rate(1000);
+    for _ in 0..(MAX_TRACE_POINTS + 50) {
+        let log = sim.run(5.0, 1);
+        trace.record(5.0, log.voltage, sim.regulator.get_terms(), log.pwm);
+    }
+    assert_eq!(trace.take().len(), MAX_TRACE_POINTS);
+    assert_eq!(trace.get_dropped(), 50);
+    // Disabled
+    trace.set_rate(0);
+    sim.run(5.0, 1);
+    trace.record(5.0, 5.0, sim.regulator.get_terms(), 0);
+    assert!(!trace.is_enabled() && trace.take().is_empty());
+}
+
+#[test]
+fn sync_start_is_scheduled_once_per_run() {
+    let now = CLOCK_VALID_MS + 1_000_000;
+    let message = SyncMessage::new("bench1", "run-42", SyncAction::On, now + 2000).unwrap();
+    let text = message.to_text();
+    assert_eq!(text, format!("DCPSYNC1 bench1 run-42 on {}", now + 2000));
+    assert_eq!(SyncMessage::parse(&text), Ok(message.clone()));
+    assert!(SyncMessage::parse("DCPSYNC1 bench1 run-42 reboot 0").is_err());
+    assert!(SyncMessage::parse("hello").is_err());
+    assert!(SyncMessage::new("bench 1", "run", SyncAction::On, 0).is_err());
+
+    let mut scheduler = SyncScheduler::new("bench1");
+    // Another group, then a clock not set by NTP
+    let other = SyncMessage::new("bench2", "run-42", SyncAction::On, now + 2000).unwrap();
+    assert_eq!(scheduler.accept(other, now), Ok(false));
+    assert!(scheduler.accept(message.clone(), 1000).is_err());
+    assert_eq!(scheduler.accept(message.clone(), now), Ok(true));
+    // A repeat of the datagram is ignored
+    assert_eq!(scheduler.accept(message.clone(), now + 10), Ok(false));
+    assert_eq!(scheduler.poll(now + 1999), None);
+    assert_eq!(scheduler.poll(now + 2003), Some(message));
+    assert_eq!(scheduler.poll(now + 2010), None);
+    assert_eq!(scheduler.get_last_run_id(), Some("run-42"));
+    // Too far ahead, or too late
+    let ahead = SyncMessage::new("bench1", "run-43", SyncAction::Cycle, now + 120_000).unwrap();
+    assert!(scheduler.accept(ahead, now).is_err());
+    let late = SyncMessage::new("bench1", "run-43", SyncAction::Cycle, now - 5000).unwrap();
+    assert!(scheduler.accept(late, now).is_err());
+    assert!(scheduler.get_pending().is_none());
+}
+
+#[test]
+fn sync_message_mac_is_split_off() {
+    let text = "DCPSYNC1 bench1 run-42 on 1700000002000";
+    assert_eq!(unitsync::split_mac(text), (text, None));
+    assert_eq!(unitsync::split_mac(&format!("{} 0aff\n", text)), (text, Some("0aff")));
+    assert_eq!(unitsync::split_mac("DCPSYNC1 bench1 on 1700000002000 0aff").1, None);
+    assert_eq!(unitsync::to_hex(&[0x0a, 0xff]), "0aff");
+    assert_eq!(unitsync::from_hex("0aFF"), Some(vec![0x0a, 0xff]));
+    assert_eq!(unitsync::from_hex("0af"), None);
+    assert_eq!(unitsync::from_hex("zz"), None);
+    assert_eq!(unitsync::from_hex("+f"), None);
+}
+
+// Currents of two sources behind the cable resistance into a common load
+fn parallel_currents(master: f32, slave: f32, cable: f32, load: f32) -> (f32, f32) {
+    let bus = (master / cable + slave / cable) / (2.0 / cable + 1.0 / load);
+    ((master - bus) / cable, (slave - bus) / cable)
+}
+
+#[test]
+fn slave_shares_half_of_the_load_current() {
+    let message = ShareMessage { sequence: 7, output_on: true, setpoint: 12.0, current: 4.5 };
+    assert_eq!(ShareMessage::parse(&message.to_text()), Ok(message));
+    assert!(ShareMessage::parse("DCPSHARE1 7 2 12 4.5").is_err());
+
+    let clock = SimClock::new();
+    let mut slave = ShareSlave::with_clock(0.05, 0.5, clock.clone());
+    assert_eq!(slave.update(0.0), ShareUpdate::Waiting);
+    // 12V into 1.2ohm (120W), the slave set 0.1V low at first
+    let (cable, load) = (0.02, 1.2);
+    let (mut master_current, mut slave_current) = (0.0, 0.0);
+    let mut slave_setpoint = 11.9;
+    let mut master_droop = Droop::new(0.05);
+    for i in 0..500u32 {
+        let master_setpoint = master_droop.update(12.0, master_current);
+        (master_current, slave_current) = parallel_currents(master_setpoint, slave_setpoint, cable, load);
+        slave.receive(ShareMessage { sequence: i, output_on: true, setpoint: 12.0, current: master_current });
+        clock.advance_ms(20);
+        match slave.update(slave_current) {
+            ShareUpdate::Follow { output_on: true, setpoint } => slave_setpoint = setpoint,
+            update => panic!("{:?}", update),
+        }
+    }
+    let total = master_current + slave_current;
+    assert!((total - 10.0).abs() < 0.5, "{}", total);
+    assert!((master_current - slave_current).abs() < 0.05 * total, "{} {}", master_current, slave_current);
+    assert!(slave.get_trim().abs() < 0.5);
+    // A reordered message is ignored, the master turning off is followed
+    slave.receive(ShareMessage { sequence: 3, output_on: false, setpoint: 12.0, current: 0.0 });
+    assert!(matches!(slave.update(slave_current), ShareUpdate::Follow { output_on: true, .. }));
+    slave.receive(ShareMessage { sequence: 500, output_on: false, setpoint: 12.0, current: 0.0 });
+    assert_eq!(slave.update(0.0), ShareUpdate::Follow { output_on: false, setpoint: 12.0 });
+    // The messages stop
+    clock.advance_ms(600);
+    assert_eq!(slave.update(0.0), ShareUpdate::Lost);
+    assert!(slave.is_lost());
+    assert_eq!(slave.update(0.0), ShareUpdate::Waiting);
+}
+
+#[test]
+fn peer_telemetry_frames_at_the_interval() {
+    let clock = SimClock::new();
+    let mut telemetry = PeerTelemetry::with_clock("bench;1", 100, clock.clone());
+    let channels = [
+        PeerChannel { channel: 1, output_on: true, voltage: 5.0012, current: 0.25, power: 1.25 },
+        PeerChannel { channel: 2, output_on: false, voltage: 0.0, current: 0.0, power: 0.0 },
+    ];
+    assert_eq!(telemetry.poll(&channels, 31.25).as_deref(),
+        Some("DCPT1,bench_1,1,31.2;1,1,5.0012,0.2500,1.250;2,0,0.0000,0.0000,0.000"));
+    clock.advance_ms(50);
+    assert_eq!(telemetry.poll(&channels, 31.25), None);
+    clock.advance_ms(50);
+    assert!(telemetry.poll(&channels, 31.25).unwrap().starts_with("DCPT1,bench_1,2,"));
+    // Channels which do not fit in an ESP-NOW frame are left out
+    let many = vec![channels[0]; 20];
+    let frame = PeerTelemetry::with_clock(&"x".repeat(64), 100, clock.clone()).frame(&many, 25.0);
+    assert!(frame.len() <= MAX_FRAME_LEN, "{}", frame.len());
+    assert!(frame.starts_with(&format!("DCPT1,{},0,", "x".repeat(32))));
+}
+
+#[test]
+fn limit_log_reports_settled_changes_once() {
+    let clock = SimClock::new();
+    let mut log = LimitLog::with_clock(clock.clone());
+    log.watch("ch1_setpoint", 5.0, "auto");
+    log.watch("ch1_current_limit", 1.0, "auto");
+    assert!(log.take().is_empty());
+    // A key held down steps the setpoint up
+    for i in 1..=5 {
+        clock.advance_ms(100);
+        log.watch("ch1_setpoint", 5.0 + i as f32 * 0.1, "panel");
+        log.watch("ch1_current_limit", 1.0, "auto");
+        assert!(log.take().is_empty());
+    }
+    clock.advance_ms(SETTLE_MS as u32);
+    let changes = log.take();
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].name, "ch1_setpoint");
+    assert_eq!((changes[0].old, changes[0].source), (5.0, "panel"));
+    assert!((changes[0].new - 5.5).abs() < 1e-4);
+    assert!(log.take().is_empty());
+    // Changed and set back before it settled
+    log.watch("ch1_current_limit", 2.0, "api");
+    clock.advance_ms(10);
+    log.watch("ch1_current_limit", 1.0, "api");
+    clock.advance_ms(SETTLE_MS as u32);
+    assert!(log.take().is_empty());
+    log.watch("ch1_current_limit", 1.5, "console");
+    clock.advance_ms(SETTLE_MS as u32);
+    assert_eq!(log.take(), vec![LimitChange { name: "ch1_current_limit", old: 1.0, new: 1.5, source: "console" }]);
+}
+
+#[test]
+fn upload_throttle_follows_the_signal_with_hysteresis() {
+    let clock = SimClock::new();
+    let mut throttle = UploadThrottle::with_clock(-80, clock.clone());
+    assert_eq!(throttle.update(-70), None);
+    assert!(throttle.batch_due());
+    // Not connected: the state is kept
+    assert_eq!(throttle.update(0), None);
+    assert_eq!(throttle.update(-85), Some(true));
+    assert!(throttle.is_throttled());
+    throttle.batch_sent();
+    clock.advance_ms(1000);
+    assert!(!throttle.batch_due());
+    clock.advance_ms(4000);
+    assert!(throttle.batch_due());
+    // Above the threshold but within the hysteresis
+    clock.advance_ms(RECOVER_MS as u32 * 2);
+    assert_eq!(throttle.update(-80 + HYSTERESIS_DB - 1), None);
+    assert!(throttle.is_throttled());
+    // Good long enough, with a dip in between which restarts the wait
+    assert_eq!(throttle.update(-70), None);
+    clock.advance_ms(RECOVER_MS as u32 / 2);
+    assert_eq!(throttle.update(-90), None);
+    assert_eq!(throttle.update(-70), None);
+    clock.advance_ms(RECOVER_MS as u32 - 1);
+    assert_eq!(throttle.update(-70), None);
+    clock.advance_ms(1);
+    assert_eq!(throttle.update(-70), Some(false));
+    throttle.batch_sent();
+    assert!(throttle.batch_due());
+    // Disabled
+    assert_eq!(throttle.update(-90), Some(true));
+    throttle.set_threshold(0);
+    assert!(!throttle.is_throttled());
+    assert_eq!(throttle.update(-95), None);
+}
+
+#[test]
+fn idle_sleep_after_the_timeout_and_wakes_on_activity() {
+    let clock = SimClock::new();
+    let mut idle = IdleSleep::with_clock(60, clock.clone());
+    clock.advance_ms(59_999);
+    assert_eq!(idle.update(false), None);
+    // Not while an output is on
+    clock.advance_ms(10_000);
+    assert_eq!(idle.update(true), None);
+    clock.advance_ms(59_999);
+    assert_eq!(idle.update(false), None);
+    clock.advance_ms(1);
+    assert_eq!(idle.update(false), Some(true));
+    assert!(idle.is_sleeping());
+    assert_eq!(idle.update(false), None);
+    // A key wakes the unit, and counts the timeout again
+    assert!(idle.activity());
+    assert!(!idle.activity());
+    assert_eq!(idle.update(false), Some(false));
+    clock.advance_ms(30_000);
+    assert_eq!(idle.update(false), None);
+    clock.advance_ms(30_000);
+    assert_eq!(idle.update(false), Some(true));
+    // An output started by the schedule or the sync
+    assert_eq!(idle.update(true), Some(false));
+    assert_eq!(idle.update(true), None);
+    // Disabled
+    clock.advance_ms(60_000);
+    assert_eq!(idle.update(false), Some(true));
+    idle.set_timeout(0);
+    assert_eq!(idle.update(false), Some(false));
+    clock.advance_ms(3_600_000);
+    assert_eq!(idle.update(false), None);
+}
+
+#[test]
+fn raw_frames_are_found_among_text_and_checked() {
+    let samples : Vec<RawSample> = (0..3).map(|i| RawSample {
+        sequence: 100 + i,
+        t_us: 500 * i,
+        channel: 1 + (i % 2) as u8,
+        output_on: i != 1,
+        stale: i == 2,
+        voltage: 5.0 + i as f32 * 0.001,
+        current: -0.25,
+    }).collect();
+    let mut stream = Vec::new();
+    stream.extend_from_slice(&samples[0].encode());
+    stream.extend_from_slice(b"I (1234) dcpower: log line\n");
+    stream.extend_from_slice(&samples[1].encode());
+    // A corrupted frame is skipped
+    let mut broken = samples[1].encode();
+    broken[13] ^= 0x40;
+    stream.extend_from_slice(&broken);
+    stream.extend_from_slice(&samples[2].encode());
+    // The start of the next frame stays for the next block
+    stream.extend_from_slice(&samples[0].encode()[..10]);
+    let (found, consumed) = scan(&stream);
+    assert_eq!(found, samples);
+    assert_eq!(consumed, stream.len() - 10);
+    assert_eq!(RawSample::decode(&stream[consumed..]), None);
+    assert_eq!(RawSample::decode(&samples[2].encode()[..FRAME_LEN - 1]), None);
+}
+
+#[test]
+fn latency_percentiles_and_budget() {
+    let mut stats = LatencyStats::new(500);
+    assert_eq!(stats.report(), LatencyReport { budget_us: 500, ..Default::default() });
+    // 98 fast cycles, one slow and one stalled
+    for _ in 0..98 {
+        stats.record(123);
+    }
+    stats.record(480);
+    stats.record(2345);
+    let report = stats.report();
+    assert_eq!(report.samples, 100);
+    assert_eq!(report.p50_us, 130);
+    assert_eq!(report.p99_us, 490);
+    assert_eq!(report.max_us, 2345);
+    assert_eq!(report.over_budget, 1);
+    assert_eq!(stats.take_over_budget(), (1, 2345));
+    assert_eq!(stats.take_over_budget(), (0, 0));
+    // Beyond the histogram the maximum is reported
+    let beyond = BUCKETS as u32 * BUCKET_US + 1000;
+    for _ in 0..100 {
+        stats.record(beyond);
+    }
+    assert_eq!(stats.percentile(0.99), beyond);
+    assert_eq!(stats.report().over_budget, 101);
+    // No budget
+    stats.reset();
+    stats.set_budget(0);
+    stats.record(beyond);
+    assert_eq!(stats.report().over_budget, 0);
+    assert_eq!(stats.take_over_budget(), (0, beyond));
+}
+
+#[test]
+fn only_the_changed_tiles_of_a_frame_are_sent() {
+    let (width, height) = (96, 64);
+    let previous = vec![0u16; width * height];
+    assert_eq!(changed_regions(&previous, &previous, width, height), vec![]);
+    // A digit across two tiles, and a pixel in a tile of the next row of tiles
+    let mut frame = previous.clone();
+    for y in 2..6 {
+        for x in 14..20 {
+            frame[y * width + x] = 0xFFFF;
+        }
+    }
+    frame[(TILE_HEIGHT + 1) * width + 50] = 0xF800;
+    assert_eq!(changed_regions(&previous, &frame, width, height), vec![
+        Region { x: 0, y: 0, width: 2 * TILE_WIDTH, height: TILE_HEIGHT },
+        Region { x: 48, y: TILE_HEIGHT, width: TILE_WIDTH, height: TILE_HEIGHT },
+    ]);
+    // Most of the frame changed: the whole frame
+    let full = vec![0x001Fu16; width * height];
+    assert_eq!(changed_regions(&previous, &full, width, height), vec![Region { x: 0, y: 0, width, height }]);
+    // Tiles cut at the edges of a frame which is not a multiple of the tile size
+    let (width, height) = (20, 10);
+    let previous = vec![0u16; width * height];
+    let mut frame = previous.clone();
+    frame[width * height - 1] = 1;
+    assert_eq!(changed_regions(&previous, &frame, width, height),
+        vec![Region { x: TILE_WIDTH, y: TILE_HEIGHT, width: width - TILE_WIDTH, height: height - TILE_HEIGHT }]);
+}
+
+#[test]
+fn trend_history_is_decimated_and_drawn_right_aligned() {
+    let mut trend = TrendHistory::new(3, 2);
+    assert!(!trend.add(5.0, 0.1));
+    assert!(trend.add(5.2, 0.3));
+    assert_eq!(trend.get_voltages(), vec![5.1]);
+    assert!((trend.get_currents()[0] - 0.2).abs() < 1e-6);
+    for i in 0..6 {
+        trend.add(i as f32, 0.0);
+    }
+    // The oldest point dropped
+    assert_eq!(trend.get_voltages(), vec![0.5, 2.5, 4.5]);
+    // Right aligned, the highest at the top
+    assert_eq!(sparkline(&trend.get_voltages(), 5, 9, 0.1), vec![(2, 8), (3, 4), (4, 0)]);
+    // A steady output is a flat line in the middle, not the noise scaled up
+    assert_eq!(sparkline(&[5.0, 5.001, 4.999], 3, 9, 0.1), vec![(0, 4), (1, 4), (2, 4)]);
+    assert_eq!(sparkline(&[1.0], 3, 9, 0.0), vec![(2, 4)]);
+    // Longer than the width: the newest points
+    assert_eq!(sparkline(&[0.0, 0.0, 1.0, 2.0], 2, 3, 0.0), vec![(0, 2), (1, 0)]);
+    assert!(sparkline(&[], 10, 8, 0.1).is_empty());
+    trend.clear();
+    assert!(trend.get_voltages().is_empty());
+}
+
+#[test]
+fn limit_pre_warning_is_reported_once_per_excursion() {
+    let limits = ProtectionLimits::new(2.0, 20.0, 80.0);
+    let mut warning = LimitPreWarning::new(90.0);
+    assert!(warning.check(&limits, 1.7, 10.0).is_empty());
+    assert_eq!(warning.check(&limits, 1.85, 10.0), vec![LimitWarning::Above(LimitKind::Current, 1.85, 2.0)]);
+    assert!(warning.is_warning(LimitKind::Current));
+    // Around the share: not repeated
+    assert!(warning.check(&limits, 1.79, 10.0).is_empty());
+    assert!(warning.check(&limits, 1.9, 10.0).is_empty());
+    // The power on its own
+    assert_eq!(warning.check(&limits, 1.9, 18.5), vec![LimitWarning::Above(LimitKind::Power, 18.5, 20.0)]);
+    // Back below 85%
+    assert_eq!(warning.check(&limits, 1.6, 16.0), vec![LimitWarning::Cleared(LimitKind::Current), LimitWarning::Cleared(LimitKind::Power)]);
+    assert_eq!(warning.check(&limits, 1.81, 10.0), vec![LimitWarning::Above(LimitKind::Current, 1.81, 2.0)]);
+    // Output off
+    warning.reset();
+    assert!(!warning.is_warning(LimitKind::Current));
+    // Disabled
+    let mut disabled = LimitPreWarning::new(0.0);
+    assert!(disabled.check(&limits, 1.99, 19.9).is_empty());
+}
+
+#[test]
+fn double_tap_holds_back_the_single_tap_until_the_window_passed() {
+    assert_eq!(StartStopGesture::parse("center_double"), Some(StartStopGesture::CenterDouble));
+    assert_eq!(StartStopGesture::parse("input").map(|g| g.as_str()), Some("input"));
+    assert_eq!(StartStopGesture::parse("right_long"), None);
+    let mut taps = TapDetector::new(DOUBLE_TAP_MS);
+    assert_eq!(taps.press(1000), None);
+    assert_eq!(taps.poll(1000 + DOUBLE_TAP_MS), None);
+    assert_eq!(taps.press(1000 + DOUBLE_TAP_MS), Some(Tap::Double));
+    assert!(!taps.is_pending());
+    assert_eq!(taps.poll(5000), None);
+    // A tap on its own is taken once the window passed
+    assert_eq!(taps.press(5000), None);
+    assert_eq!(taps.poll(5000 + DOUBLE_TAP_MS + 1), Some(Tap::Single));
+    assert_eq!(taps.poll(6000), None);
+    // A late second tap starts a new one
+    assert_eq!(taps.press(7000), None);
+    assert_eq!(taps.press(7000 + DOUBLE_TAP_MS + 100), None);
+    assert_eq!(taps.poll(8000), Some(Tap::Single));
+    taps.press(9000);
+    taps.clear();
+    assert_eq!(taps.poll(10000), None);
+}
+
+#[test]
+fn button_press_is_taken_once_when_stable() {
+    let mut button = ButtonDebounce::new();
+    // Bounce on the press
+    assert!(!button.update(true, 0));
+    assert!(!button.update(false, 5));
+    assert!(!button.update(true, 10));
+    assert!(!button.update(true, 10 + DEBOUNCE_MS - 1));
+    assert!(button.update(true, 10 + DEBOUNCE_MS));
+    assert!(button.is_pressed());
+    // Held down: once
+    assert!(!button.update(true, 1000));
+    // Released, then pressed again
+    assert!(!button.update(false, 1100));
+    assert!(!button.update(false, 1100 + DEBOUNCE_MS));
+    assert!(!button.is_pressed());
+    assert!(!button.update(true, 1200));
+    assert!(button.update(true, 1200 + DEBOUNCE_MS));
+}
+
+#[test]
+fn input_latency_uses_wider_buckets() {
+    // 1ms buckets for the touch to the action of a key
+    let mut stats = LatencyStats::with_bucket(0, 1000);
+    for _ in 0..9 {
+        stats.record(23_400);
+    }
+    stats.record(131_000);
+    let report = stats.report();
+    assert_eq!(report.p50_us, 24_000);
+    assert_eq!(report.p99_us, 131_000);
+    assert_eq!(report.over_budget, 0);
+    // Beyond 500ms the maximum
+    stats.record(900_000);
+    assert_eq!(stats.percentile(1.0), 900_000);
+}
+
+#[test]
+fn api_tokens_separate_telemetry_from_control() {
+    // No tokens: open as before
+    let open = AccessTokens::default();
+    assert_eq!(open.check(None, Role::Control), Access::Granted);
+    // Control token only: the telemetry stays open
+    let control_only = AccessTokens::new("", "s3cret");
+    assert_eq!(control_only.check(None, Role::Read), Access::Granted);
+    assert_eq!(control_only.check(None, Role::Control), Access::Unauthorized);
+    assert_eq!(control_only.check(Some("Bearer s3cret"), Role::Control), Access::Granted);
+    assert_eq!(control_only.check(Some("Bearer s3cre"), Role::Control), Access::Unauthorized);
+    assert_eq!(control_only.check(Some("s3cret"), Role::Control), Access::Unauthorized);
+    // Both
+    let tokens = AccessTokens::new("dash", "s3cret");
+    assert_eq!(tokens.role_of(Some("Bearer dash")), Some(Role::Read));
+    assert_eq!(tokens.check(None, Role::Read), Access::Unauthorized);
+    assert_eq!(tokens.check(Some("Bearer dash"), Role::Read), Access::Granted);
+    assert_eq!(tokens.check(Some("Bearer dash"), Role::Control), Access::Forbidden);
+    assert_eq!(tokens.check(Some(" Bearer s3cret "), Role::Read), Access::Granted);
+    assert_eq!(tokens.check(Some("Bearer "), Role::Read), Access::Unauthorized);
+}
+
+#[test]
+fn touch_thresholds_follow_a_slow_drift() {
+    let mut baseline = TouchBaseline::new(&[100_000, 80_000], 0.011, 0);
+    assert_eq!(baseline.threshold(0), 1100);
+    assert!(!baseline.is_due(CHECK_PERIOD_MS - 1));
+    // Not while a key was pressed recently
+    baseline.touched(CHECK_PERIOD_MS - 1000);
+    assert!(!baseline.is_due(CHECK_PERIOD_MS));
+    let now = CHECK_PERIOD_MS - 1000 + QUIET_MS;
+    assert!(baseline.is_due(now));
+    // Pad 0 warmed up by 1%, pad 1 within the noise
+    let changed = baseline.update(now, &[101_000, 80_100]);
+    assert_eq!(changed.len(), 1);
+    assert_eq!(changed[0].pad, 0);
+    assert_eq!(changed[0].from, 100_000);
+    assert_eq!(changed[0].threshold, 1111);
+    assert_eq!(baseline.baseline(1), 80_000);
+    assert!(!baseline.is_due(now + 1));
+    // A jump is not a drift
+    let changed = baseline.update(now + CHECK_PERIOD_MS, &[150_000, 80_000]);
+    assert!(changed.is_empty());
+    assert_eq!(baseline.baseline(0), 101_000);
+    assert_eq!(baseline.count(), 1);
+}
+
+#[test]
+fn offset_calibration_waits_for_apply_or_discard() {
+    let offsets = vec![ChannelOffsets { current_a: 0.0012, voltage_v: -0.004, sense_v: None }];
+    let mut cal = OffsetCalibration::new();
+    assert!(cal.apply().is_err());
+    cal.start(false).unwrap();
+    assert!(cal.start(false).is_err());
+    cal.progress(150, 600);
+    assert_eq!(cal.percent(), 25);
+    // Held until the fixture decides
+    assert_eq!(cal.finish(Ok(offsets.clone())), None);
+    assert_eq!(cal.get_state(), &CalibrationState::Ready(offsets.clone()));
+    assert_eq!(cal.apply(), Ok(offsets.clone()));
+    assert_eq!(cal.get_state().name(), "applied");
+    assert_eq!(cal.status().offsets(), &offsets[..]);
+    assert_eq!(cal.status().percent, 100);
+    assert!(cal.discard().is_err());
+    // A load left on the output
+    cal.start(false).unwrap();
+    cal.finish(Ok(vec![ChannelOffsets { current_a: 0.2, voltage_v: 0.0, sense_v: None }]));
+    assert_eq!(cal.get_state().name(), "failed");
+    assert!(cal.status().error().unwrap().contains("CH1 current offset"));
+    assert!(cal.apply().is_err());
+    cal.discard().unwrap();
+    assert_eq!(cal.get_state(), &CalibrationState::Idle);
+    // The front panel applies at once
+    cal.start(true).unwrap();
+    assert_eq!(cal.finish(Ok(offsets.clone())), Some(offsets));
+    assert_eq!(CalibrationAction::parse("apply"), Some(CalibrationAction::Apply));
+    assert_eq!(CalibrationAction::parse("go"), None);
+}
+
+#[test]
+fn production_test_runs_each_step_in_turn() {
+    let mut test = ProductionTest::new();
+    assert_eq!(test.poll(0), Some(TestStep::I2c));
+    // Started once
+    assert_eq!(test.poll(10), None);
+    test.result(TestStep::I2c, 100, true, "ina228 0x40, ap33772s 0x52");
+    assert_eq!(test.poll(100), Some(TestStep::Pwm));
+    // A late result of another step is ignored
+    test.result(TestStep::I2c, 150, false, "late");
+    test.result(TestStep::Pwm, 1300, true, "ch1 offset 410");
+    // The pattern passes once shown for its time
+    assert_eq!(test.poll(1300), Some(TestStep::Display));
+    assert_eq!(test.poll(1300 + DISPLAY_MS - 1), None);
+    assert_eq!(test.poll(1300 + DISPLAY_MS), Some(TestStep::Touch));
+    let touch_start = 1300 + DISPLAY_MS;
+    test.key(0, touch_start + 100);
+    test.key(4, touch_start + 200);
+    assert_eq!(test.missing_keys(), vec!["down", "left", "right"]);
+    // The keys not touched fail the step
+    assert_eq!(test.poll(touch_start + TOUCH_MS), Some(TestStep::Wifi));
+    assert!(!test.is_done());
+    test.result(TestStep::Wifi, touch_start + TOUCH_MS + 2500, true, "6 networks");
+    assert!(test.is_done());
+    assert_eq!(test.poll(touch_start + TOUCH_MS + 3000), None);
+    let results = test.results();
+    assert_eq!(results.len(), 5);
+    assert!(results[2].pass);
+    assert_eq!(results[3].detail, "not touched: down,left,right");
+    assert_eq!(results[4].duration_ms, 2500);
+    assert!(!test.passed());
+}
+
+#[test]
+fn error_counters_survive_a_reboot() {
+    let mut counters = ErrorCounters::new();
+    counters.add(Counter::Boot, 1);
+    counters.add(Counter::OverCurrentTrip, 2);
+    counters.add(Counter::I2cError, 0);
+    assert!(counters.is_save_due(0));
+    counters.saved(0);
+    // A later change waits for the period
+    counters.add(Counter::I2cError, 5);
+    assert!(!counters.is_save_due(SAVE_PERIOD_MS - 1));
+    assert!(counters.is_save_due(SAVE_PERIOD_MS));
+    let blob = counters.encode();
+    counters.saved(SAVE_PERIOD_MS);
+    assert!(!counters.is_dirty());
+
+    let stored = ErrorCounters::decode(&blob).unwrap();
+    assert_eq!(stored.get(Counter::Boot), 1);
+    assert_eq!(stored.get(Counter::OverCurrentTrip), 2);
+    assert_eq!(stored.get(Counter::I2cError), 5);
+    assert_eq!(stored.get(Counter::PdFailure), 0);
+    assert_eq!(stored.entries()[0], ("boots", 1));
+    // A blob of an earlier version with fewer counters leaves the new ones at 0
+    let short = ErrorCounters::decode(&blob[..12]).unwrap();
+    assert_eq!(short.get(Counter::WatchdogReset), 0);
+    assert_eq!(short.get(Counter::I2cError), 0);
+    // An unknown version is not read
+    let mut other = blob.clone();
+    other[0] = 9;
+    assert!(ErrorCounters::decode(&other).is_none());
+    assert!(ErrorCounters::decode(&[]).is_none());
+}
+
+#[test]
+fn safe_mode_caps_voltage_and_current() {
+    let safe = SafeMode::new(true);
+    assert_eq!(safe.voltage(12.0), SAFE_MODE_MAX_VOLTAGE);
+    assert_eq!(safe.voltage(3.3), 3.3);
+    assert_eq!(safe.current(5.0), SAFE_MODE_MAX_CURRENT);
+    assert_eq!(safe.current(0.5), 0.5);
+    // Off, the values are left as set
+    let off = SafeMode::new(false);
+    assert_eq!(off.voltage(12.0), 12.0);
+    assert_eq!(off.current(5.0), 5.0);
+}
+
+#[test]
+fn soft_limit_lowers_the_operating_current() {
+    assert_eq!(CurrentLimitMode::parse("pps"), Some(CurrentLimitMode::Pps));
+    assert_eq!(CurrentLimitMode::parse("cc"), None);
+    let mut soft = SoftCurrentLimit::new(2.0, 2000);
+    assert!((soft.trip_current() - 3.0).abs() < 1e-6);
+    // A short excursion is let through
+    assert_eq!(soft.update(0, 2.5), None);
+    assert_eq!(soft.update(OVER_MS - 10, 2.5), None);
+    // Scaled by the excess: 2000mA * 2.0 / 2.5
+    assert_eq!(soft.update(OVER_MS, 2.5), Some(SoftLimitAction::Reduce(1600)));
+    assert!(soft.is_limiting());
+    // The source settles before the next step, which is at least 50mA
+    assert_eq!(soft.update(OVER_MS + 100, 2.01), None);
+    let t = OVER_MS + PPS_SETTLE_MS;
+    assert_eq!(soft.update(t, 2.01), Some(SoftLimitAction::Reduce(1550)));
+    // Held below the limit: restored after a while well below it
+    assert_eq!(soft.update(t + 100, 1.9), None);
+    assert_eq!(soft.update(t + 200, 1.5), None);
+    assert_eq!(soft.update(t + 200 + RESTORE_MS, 1.5), Some(SoftLimitAction::Restore(2000)));
+    assert!(!soft.is_limiting());
+
+    // A source which does not limit: stepped down to the minimum, then tripped
+    let mut fixed = SoftCurrentLimit::new(1.2, 1200);
+    let mut t = 0;
+    let mut last = None;
+    while t < 10_000 {
+        last = fixed.update(t, 1.5);
+        if last == Some(SoftLimitAction::Trip) {
+            break;
+        }
+        t += 10;
+    }
+    assert_eq!(last, Some(SoftLimitAction::Trip));
+    assert_eq!(fixed.get_operating_ma(), MIN_OPERATING_MA);
+    assert!(t >= HOLD_FAIL_MS);
+    // A limit below the PPS minimum only trips in the control task
+    let mut low = SoftCurrentLimit::new(0.5, 1000);
+    assert!(!low.is_supported());
+    assert_eq!(low.trip_current(), 0.5);
+    assert_eq!(low.update(1000, 0.8), None);
+}
+
+#[test]
+fn thermal_model_derates_before_the_sensor() {
+    // 0.5°C/W, 60s: 80W settles 40°C above the ambient
+    let mut model = ThermalModel::new(0.5, 60.0);
+    assert!(model.is_enabled());
+    model.update(1.0, 0.0, 25.0);
+    assert_eq!(model.power_limit(75.0, 0.0), None);
+    // 40W settles at 45°C, no derating
+    for _ in 0..10 {
+        model.update(1.0, 40.0, 25.0);
+    }
+    assert_eq!(model.power_limit(75.0, 40.0), None);
+    // 120W heads for 85°C: derated while the slow sensor still reads the ambient
+    let mut derated = None;
+    for _ in 0..60 {
+        model.update(1.0, 120.0, 26.0);
+        derated = derated.or(model.power_limit(75.0, 120.0));
+    }
+    let limit = derated.unwrap();
+    // The power which holds the heatsink at the limit less the margin
+    assert!((limit - (75.0 - DERATE_MARGIN_C - 25.0) / 0.5).abs() < 1e-3);
+    assert!(model.estimate() < 75.0);
+    // Released once the load drops well below it
+    for _ in 0..300 {
+        model.update(1.0, 20.0, 30.0);
+    }
+    assert_eq!(model.power_limit(75.0, 20.0), None);
+    // A sensor above the model is taken as it is
+    assert!(model.estimate() >= 30.0);
+    assert!(!ThermalModel::new(0.0, 60.0).is_enabled());
+}
+
+#[test]
+fn perf_counters_give_rates_per_second() {
+    let before = PerfCounters { control_cycles: u32::MAX - 999, i2c_transfers: 100, i2c_busy_us: 5000, main_loops: 10, ..Default::default() };
+    let now = PerfCounters {
+        control_cycles: 9_000,
+        overruns: 2,
+        i2c_transfers: 30_100,
+        i2c_busy_us: 5000 + 30_000 * 120,
+        pwm_updates: 500,
+        display_frames: 100,
+        main_loops: 1010,
+    };
+    let rates = now.rates(&before, 10_000);
+    // Wrapped counter
+    assert_eq!(rates.control_cycles, 1000.0);
+    assert_eq!(rates.overruns, 0.2);
+    assert_eq!(rates.i2c_transfers, 3000.0);
+    assert_eq!(rates.i2c_transfer_us, 120.0);
+    assert_eq!(rates.display_frames, 10.0);
+    assert_eq!(rates.main_loops, 100.0);
+    assert_eq!(now.rates(&before, 0).control_cycles, 0.0);
+}
+
+#[test]
+fn alarm_is_raised_after_its_hold_time() {
+    let mut alarms = Alarms::parse("current > 2A for 5s; ch2 voltage < 4.5V; temperature >= 60C").unwrap();
+    assert_eq!(alarms.get_rules().len(), 3);
+    assert_eq!(alarms.get_rules()[0].to_text(), "ch1 current > 2A for 5s");
+    let mut values = AlarmValues { temperature: 25.0, pd_voltage: 20.0, ..Default::default() };
+    // The outputs are off, nothing is watched but the temperature
+    assert!(alarms.update(0, &values).is_empty());
+    values.current[0] = Some(2.5);
+    values.voltage[1] = Some(5.0);
+    assert!(alarms.update(1000, &values).is_empty());
+    assert!(alarms.update(5999, &values).is_empty());
+    assert_eq!(alarms.update(6000, &values), vec![AlarmEvent::Raised(0, 2.5)]);
+    // Raised once
+    assert!(alarms.update(7000, &values).is_empty());
+    // A short dip below the threshold does not clear it
+    values.current[0] = Some(1.0);
+    assert!(alarms.update(7100, &values).is_empty());
+    values.current[0] = Some(2.5);
+    assert!(alarms.update(7200, &values).is_empty());
+    assert!(alarms.is_raised(0));
+    values.current[0] = Some(1.0);
+    alarms.update(8000, &values);
+    assert_eq!(alarms.update(8000 + CLEAR_MS, &values), vec![AlarmEvent::Cleared(0)]);
+    // Without a hold time at once
+    values.voltage[1] = Some(4.0);
+    values.temperature = 60.0;
+    assert_eq!(alarms.update(10_000, &values), vec![AlarmEvent::Raised(1, 4.0), AlarmEvent::Raised(2, 60.0)]);
+    assert_eq!(alarms.raised_count(), 2);
+    for bad in ["current 2A", "ch3 current > 1A", "ch2 temperature > 50", "current > 2V", "current > 2A after 5s", "voltage ~ 5"] {
+        assert!(Alarms::parse(bad).is_err(), "{}", bad);
+    }
+    assert!(Alarms::parse("").unwrap().is_empty());
+}
+
+#[test]
+fn test_script_runs_its_steps_and_stops_at_a_failed_check() {
+    let steps = vec![
+        ScriptStep::Voltage(5.0),
+        ScriptStep::Output(true),
+        ScriptStep::Wait(1000),
+        ScriptStep::AssertCurrent(0.1, 0.3),
+        ScriptStep::Marker("fan at 5V".to_string()),
+        ScriptStep::AssertVoltage(4.9, 5.1),
+        ScriptStep::Output(false),
+    ];
+    let script = TestScript::new("usb-fan", steps.clone()).unwrap();
+    let mut run = ScriptRun::new(script);
+    assert_eq!(run.poll(0, 0.0, 0.0), Some(ScriptCommand::Voltage(5.0)));
+    assert_eq!(run.poll(10, 0.0, 0.0), Some(ScriptCommand::Output(true)));
+    assert_eq!(run.poll(20, 5.0, 0.2), None);
+    assert_eq!(run.poll(1019, 5.0, 0.2), None);
+    assert_eq!(run.poll(1020, 5.0, 0.2), None);
+    // The check averages over ASSERT_MS
+    let mut t = 1030;
+    let mut checked = None;
+    while checked.is_none() {
+        checked = run.poll(t, 5.0, if t % 20 == 0 { 0.1 } else { 0.3 });
+        t += 10;
+    }
+    assert!(t - 1030 > ASSERT_MS);
+    match checked {
+        Some(ScriptCommand::Checked(3, true, mean)) => assert!((mean - 0.2).abs() < 0.01),
+        other => panic!("{:?}", other),
+    }
+    assert_eq!(run.poll(t, 5.0, 0.2), Some(ScriptCommand::Marker("fan at 5V".to_string())));
+    // Voltage out of range: the run ends failed, the output off step is not run
+    let mut end = None;
+    for _ in 0..100 {
+        t += 10;
+        if let Some(command) = run.poll(t, 4.5, 0.2) {
+            end = Some(command);
+            break;
+        }
+    }
+    assert!(matches!(end, Some(ScriptCommand::Checked(5, false, _))));
+    assert_eq!(*run.state(), ScriptState::Failed);
+    assert_eq!(run.poll(t + 10, 5.0, 0.2), None);
+    let status = run.status();
+    assert_eq!((status.step, status.steps, status.checks.len()), (6, 7, 2));
+    // Passed when the last step is done, aborted by a fault
+    let mut run = ScriptRun::new(TestScript::new("short", vec![ScriptStep::Output(false)]).unwrap());
+    assert_eq!(run.poll(0, 0.0, 0.0), Some(ScriptCommand::Output(false)));
+    assert_eq!(*run.state(), ScriptState::Passed);
+    let mut run = ScriptRun::new(TestScript::new("abort", steps).unwrap());
+    run.abort("OverCurrent trip");
+    assert!(!run.is_running());
+    assert!(TestScript::new("Bad Name", vec![ScriptStep::Output(true)]).is_err());
+    assert!(TestScript::new("empty", Vec::new()).is_err());
+    assert!(TestScript::new("range", vec![ScriptStep::AssertCurrent(0.3, 0.1)]).is_err());
+}
+
+#[test]
+fn power_check_flags_a_power_read_off_v_times_i() {
+    let mut check = PowerCheck::new(5.0);
+    // 5V 1A read as 5.1W is within 5%
+    for _ in 0..CHECK_SAMPLES * 2 {
+        assert_eq!(check.check(5.0, 1.0, 5.1), None);
+    }
+    // Read as 4W: 20% off, reported after CHECK_SAMPLES in a row
+    for _ in 0..CHECK_SAMPLES - 1 {
+        assert_eq!(check.check(5.0, 1.0, 4.0), None);
+    }
+    match check.check(5.0, 1.0, 4.0) {
+        Some(PowerCheckEvent::Inconsistent { power, expected, error }) => {
+            assert_eq!((power, expected), (4.0, 5.0));
+            assert!((error - 20.0).abs() < 1e-3);
+        },
+        other => panic!("{:?}", other),
+    }
+    assert!(check.is_inconsistent());
+    // A single good sample does not clear it
+    assert_eq!(check.check(5.0, 1.0, 5.0), None);
+    assert_eq!(check.check(5.0, 1.0, 4.0), None);
+    let mut event = None;
+    for _ in 0..CHECK_SAMPLES {
+        event = event.or(check.check(5.0, -1.0, -5.0));
+    }
+    assert_eq!(event, Some(PowerCheckEvent::Consistent));
+    // Light loads and a disabled check are not checked
+    let mut light = PowerCheck::new(5.0);
+    let mut disabled = PowerCheck::new(0.0);
+    for _ in 0..CHECK_SAMPLES * 2 {
+        assert_eq!(light.check(1.0, 0.1, 0.3), None);
+        assert_eq!(disabled.check(5.0, 1.0, 1.0), None);
+    }
+}
+
+#[test]
+fn markers_are_numbered_and_their_text_checked() {
+    let mut markers = Markers::new();
+    let first = markers.add(MarkerSource::Key, "", 1_000).unwrap();
+    assert_eq!((first.number, first.to_text()), (1, "marker 1 (key)".to_string()));
+    let second = markers.add(MarkerSource::Api, "  DUT started whining here ", 2_000).unwrap();
+    assert_eq!(second.number, 2);
+    assert_eq!(second.to_text(), "marker 2 (api): DUT started whining here");
+    // A refused text does not take a number
+    assert!(markers.add(MarkerSource::Console, &"x".repeat(MAX_MARKER_TEXT_LEN + 1), 3_000).is_err());
+    assert!(markers.add(MarkerSource::Console, "line\nbreak", 3_000).is_err());
+    assert_eq!(markers.count(), 2);
+    assert_eq!(markers.last().map(|m| m.clock_ms), Some(2_000));
+}
+
+#[test]
+fn scope_trigger_pulses_the_routed_events_once_per_holdoff() {
+    let config = TriggerConfig::new(TriggerPolarity::Low, 2, "trip, alarm").unwrap();
+    let mut trigger = ScopeTrigger::new(config);
+    // Idle high with an active low pulse
+    assert!(trigger.level());
+    assert!(!trigger.fire(TriggerSource::Marker, 0));
+    assert!(trigger.fire(TriggerSource::Trip, 1_000));
+    assert!(!trigger.level());
+    // The same trip from the main loop 10ms later is held off
+    assert!(!trigger.poll(2_999));
+    assert!(trigger.poll(3_000));
+    assert!(trigger.level());
+    assert!(!trigger.fire(TriggerSource::Trip, 11_000));
+    assert!(!trigger.poll(12_000));
+    assert!(trigger.fire(TriggerSource::Alarm, 1_000 + HOLDOFF_MS * 1000));
+    assert_eq!(trigger.pulses(), 2);
+    assert!(TriggerConfig::new(TriggerPolarity::High, 0, "trip").is_err());
+    assert!(TriggerConfig::new(TriggerPolarity::High, 1, "trip,fault").is_err());
+    assert!(TriggerPolarity::High.level(true) && !TriggerPolarity::High.level(false));
+}